@@ -0,0 +1,85 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use ire::data::TunnelId;
+use ire::i2np::{Message, MessageIdGenerator, MessagePayload};
+
+/// Parses, re-encrypts (here, flips a byte of) and re-serializes a TunnelData
+/// message, as a participating hop does.
+fn forward(msg: Message, out: &mut BytesMut) {
+    let mut td = match msg.payload {
+        MessagePayload::TunnelData(td) => td,
+        _ => unreachable!(),
+    };
+    td.update_data(|data| data[0] ^= 0xff);
+    td.tid = TunnelId(td.tid.0 + 1);
+
+    out.clear();
+    Message::from_payload(
+        &MessageIdGenerator::default(),
+        MessagePayload::TunnelData(td),
+    )
+    .write_to(out)
+    .unwrap();
+}
+
+fn tunnel_data(c: &mut Criterion) {
+    let ids = MessageIdGenerator::default();
+    let msg = Message::tunnel_data(&ids, TunnelId(1), [7; 1024]);
+    let mut buf = BytesMut::new();
+    msg.write_to(&mut buf).unwrap();
+    let buf = buf.freeze();
+
+    let mut group = c.benchmark_group("TunnelData");
+    group.throughput(Throughput::Bytes(buf.len() as u64));
+    group.bench_function("parse/copied", |b| {
+        b.iter(|| Message::from_bytes(&buf).unwrap())
+    });
+    group.bench_function("parse/shared", |b| {
+        b.iter(|| Message::from_shared(&buf).unwrap())
+    });
+    group.bench_function("write_to", |b| {
+        let mut out = BytesMut::with_capacity(buf.len());
+        b.iter(|| {
+            out.clear();
+            msg.write_to(&mut out).unwrap();
+        })
+    });
+    group.bench_function("forward/copied", |b| {
+        let mut out = BytesMut::with_capacity(buf.len());
+        b.iter(|| forward(Message::from_bytes(&buf).unwrap(), &mut out))
+    });
+    group.bench_function("forward/shared", |b| {
+        let mut out = BytesMut::with_capacity(buf.len());
+        b.iter(|| forward(Message::from_shared(&buf).unwrap(), &mut out))
+    });
+    group.finish();
+}
+
+fn data(c: &mut Criterion) {
+    let ids = MessageIdGenerator::default();
+    let msg = Message::data(&ids, vec![7; 60 * 1024]);
+    let mut buf = BytesMut::new();
+    msg.write_to(&mut buf).unwrap();
+    let buf = buf.freeze();
+
+    let mut group = c.benchmark_group("Data");
+    group.throughput(Throughput::Bytes(buf.len() as u64));
+    group.bench_function("parse/copied", |b| {
+        b.iter(|| Message::from_bytes(&buf).unwrap())
+    });
+    group.bench_function("parse/shared", |b| {
+        b.iter(|| Message::from_shared(&buf).unwrap())
+    });
+    group.bench_function("write_to", |b| {
+        let mut out = BytesMut::with_capacity(buf.len());
+        b.iter(|| {
+            out.clear();
+            msg.write_to(&mut out).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tunnel_data, data);
+criterion_main!(benches);
@@ -0,0 +1,165 @@
+//! Forwards TunnelData messages through two NTCP2 managers on the loopback
+//! interface, and reports the heap allocations made per message.
+//!
+//! Alice sends each message to Bob, and Bob forwards it back to Alice, as a
+//! participating hop would. Copying a message body into a new buffer allocates at
+//! least its 1024 bytes, so the bytes allocated per message show how many such
+//! copies are made.
+//!
+//! Run with `cargo bench --bench loopback`. The number of messages can be given as
+//! an argument, and defaults to 100 000.
+
+use futures::{
+    future,
+    stream::{self, Stream},
+    sync::mpsc,
+    Future, Sink,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+use ire::data::{Hash, RouterInfo, TunnelId};
+use ire::i2np::{Message, MessagePayload};
+use ire::router::{
+    mock::mock_context,
+    types::{Distributor, DistributorResult},
+    Context,
+};
+use ire::transport::ntcp2::Manager;
+
+/// Counts the allocations made through the system allocator.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Passes the TunnelData messages that a manager receives to a channel.
+#[derive(Clone)]
+struct TunnelDataTx(mpsc::UnboundedSender<Message>);
+
+impl Distributor for TunnelDataTx {
+    fn handle(&self, _from: Hash, msg: Message) -> DistributorResult {
+        if let MessagePayload::TunnelData(_) = msg.payload {
+            // The receiver is dropped once every message has arrived
+            let _ = self.0.unbounded_send(msg);
+        }
+        Box::new(future::ok(()))
+    }
+}
+
+/// Returns a loopback address that nothing is listening on.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Starts an NTCP2 manager for a new router, returning the router's context and the
+/// TunnelData messages that it receives.
+fn start(
+    rt: &mut Runtime,
+) -> (
+    Manager<TunnelDataTx>,
+    Arc<Context>,
+    mpsc::UnboundedReceiver<Message>,
+) {
+    let (tx, rx) = mpsc::unbounded();
+    let mut manager = Manager::new(free_addr(), TunnelDataTx(tx));
+    let ctx = mock_context();
+    {
+        let mut ri = ctx.ri.write().unwrap();
+        ri.set_addresses(vec![manager.address()]);
+        ri.sign(&ctx.keys.signing_private_key);
+    }
+    manager.set_context(ctx.clone());
+
+    let listener = manager.listen(&ctx.keys.rid);
+    rt.spawn(future::lazy(|| {
+        listener.map_err(|e| eprintln!("Listener failed: {}", e))
+    }));
+    (manager, ctx, rx)
+}
+
+fn main() {
+    let count: usize = env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with('-'))
+        .map(|arg| arg.parse().expect("Message count must be a number"))
+        .unwrap_or(100_000);
+
+    let mut rt = Runtime::new().unwrap();
+    let (alice, alice_ctx, alice_rx) = start(&mut rt);
+    let (bob, bob_ctx, bob_rx) = start(&mut rt);
+    let alice_ri: RouterInfo = alice_ctx.ri.read().unwrap().clone();
+    let bob_ri: RouterInfo = bob_ctx.ri.read().unwrap().clone();
+
+    // Bob forwards everything he receives back to Alice
+    rt.spawn(
+        bob_rx
+            .map(move |msg| (alice_ri.clone(), msg))
+            .forward(
+                bob.sink()
+                    .sink_map_err(|e| eprintln!("Bob failed to send: {}", e)),
+            )
+            .map(|_| ()),
+    );
+
+    let msgs: Vec<_> = (0..count)
+        .map(|i| Message::tunnel_data(&alice_ctx.msg_ids, TunnelId(i as u32), [7; 1024]))
+        .collect();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    rt.spawn(
+        alice
+            .sink()
+            .sink_map_err(|e| eprintln!("Alice failed to send: {}", e))
+            .send_all(stream::iter_ok::<_, ()>(
+                msgs.into_iter().map(move |msg| (bob_ri.clone(), msg)),
+            ))
+            .map(|_| ()),
+    );
+    rt.block_on(alice_rx.take(count as u64).for_each(|_| Ok(())))
+        .unwrap();
+
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated_bytes;
+    println!("Forwarded {} TunnelData messages in {:?}", count, elapsed);
+    println!(
+        "{:.1} allocations and {:.0} bytes allocated per message",
+        allocations as f64 / count as f64,
+        allocated_bytes as f64 / count as f64
+    );
+}
@@ -0,0 +1,13 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ire::data::{LeaseSet, LeaseSet2, RouterInfo};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ri) = RouterInfo::from_bytes(data) {
+        // Anything we accept must serialize back to something we accept
+        assert_eq!(RouterInfo::from_bytes(&ri.to_bytes()).unwrap(), ri);
+    }
+    let _ = LeaseSet::from_bytes(data);
+    let _ = LeaseSet2::from_bytes(data);
+});
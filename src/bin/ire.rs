@@ -5,22 +5,26 @@ extern crate clap;
 extern crate env_logger;
 extern crate futures;
 extern crate ire;
+extern crate serde_json;
 extern crate tokio;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use futures::{Future, Sink};
 use ire::{
-    data, i2np,
-    netdb::reseed::HttpsReseeder,
+    crypto, data, i2np,
+    netdb::{export, persist, reseed::HttpsReseeder},
     router::{
+        config,
         mock::{mock_context, MockDistributor},
+        profiles::Profiles,
         Builder,
     },
     transport,
 };
+use std::fs::{self, File};
+use std::path::Path;
 
 fn main() {
-    env_logger::init();
     let exit_code = inner_main();
     std::process::exit(exit_code);
 }
@@ -30,12 +34,113 @@ fn inner_main() -> i32 {
         .version("0.0.1")
         .author("Jack Grigg <str4d@i2pmail.org>")
         .about("The I2P Rust engine")
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help("Runs the router with the given TOML config file")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Sets a router config option, overriding the config file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("router")
+                .arg(
+                    Arg::with_name("cfgFile")
+                        .help("Path to the router's TOML config file, if --config is not given"),
+                )
+                .arg(
+                    Arg::with_name("reseedFrom")
+                        .long("reseed-from")
+                        .help("Reseed from a local i2pseeds.su3 file or routerInfo directory")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
-            SubCommand::with_name("router").arg(
-                Arg::with_name("cfgFile")
-                    .help("Path to the router's TOML config file")
-                    .required(true),
-            ),
+            SubCommand::with_name("keygen")
+                .about("Generates the private keys for a new Destination")
+                .arg(
+                    Arg::with_name("sigType")
+                        .long("sig-type")
+                        .help("Signature type of the Destination")
+                        .takes_value(true)
+                        .possible_values(&["ed25519"])
+                        .default_value("ed25519"),
+                )
+                .arg(
+                    Arg::with_name("keyFile")
+                        .help("Path to write the private key file to")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("netdb")
+                .about("Exports, imports, and inspects an on-disk network database")
+                .arg(
+                    Arg::with_name("netDbDir")
+                        .long("netdb")
+                        .help("Path to the netDb directory")
+                        .takes_value(true)
+                        .default_value("netDb"),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Exports the RouterInfos in the netDb")
+                        .arg(
+                            Arg::with_name("format")
+                                .long("format")
+                                .help("Format to export to")
+                                .takes_value(true)
+                                .possible_values(&["json", "dir"])
+                                .default_value("json"),
+                        )
+                        .arg(
+                            Arg::with_name("caps")
+                                .long("caps")
+                                .help("Only export routers with all of these capabilities")
+                                .takes_value(true)
+                                .validator(validate_caps),
+                        )
+                        .arg(
+                            Arg::with_name("out")
+                                .help("Path of the JSON file or directory to export to")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Validates exported RouterInfos and adds them to the netDb")
+                        .arg(
+                            Arg::with_name("format")
+                                .long("format")
+                                .help("Format to import from")
+                                .takes_value(true)
+                                .possible_values(&["json", "dir"])
+                                .default_value("json"),
+                        )
+                        .arg(
+                            Arg::with_name("in")
+                                .help("Path of the JSON file or directory to import from")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("inspect")
+                        .about("Describes a RouterInfo in the netDb")
+                        .arg(
+                            Arg::with_name("hash")
+                                .help("Base64 hash of the router")
+                                .required(true),
+                        ),
+                ),
         )
         .subcommand(
             SubCommand::with_name("cli")
@@ -69,8 +174,24 @@ fn inner_main() -> i32 {
         )
         .get_matches();
 
+    // The router sets up logging once it has loaded its config
     match matches.subcommand() {
-        ("router", Some(matches)) => cli_router(matches),
+        ("router", Some(matches)) => return cli_router(matches),
+        ("", None) if matches.is_present("config") => return cli_router(&matches),
+        _ => env_logger::init(),
+    }
+
+    match matches.subcommand() {
+        ("keygen", Some(matches)) => cli_keygen(matches),
+        ("netdb", Some(matches)) => {
+            let netdb_dir = Path::new(matches.value_of("netDbDir").unwrap());
+            match matches.subcommand() {
+                ("export", Some(matches)) => cli_netdb_export(netdb_dir, matches),
+                ("import", Some(matches)) => cli_netdb_import(netdb_dir, matches),
+                ("inspect", Some(matches)) => cli_netdb_inspect(netdb_dir, matches),
+                (&_, _) => panic!("Invalid matches for netdb subcommand"),
+            }
+        }
         ("cli", Some(matches)) => match matches.subcommand() {
             ("gen", Some(matches)) => cli_gen(matches),
             ("client", Some(matches)) => cli_client(matches),
@@ -87,11 +208,171 @@ fn cli_gen(args: &ArgMatches) -> i32 {
     0
 }
 
+fn cli_keygen(args: &ArgMatches) -> i32 {
+    let sig_type = match args.value_of("sigType") {
+        Some("ed25519") => crypto::SigType::Ed25519,
+        _ => panic!("Unknown signature type"),
+    };
+    let dsk = data::DestinationSecretKeys::new(sig_type, crypto::EncType::ElGamal2048);
+    match dsk.to_file(args.value_of("keyFile").unwrap()) {
+        Ok(()) => {
+            println!("{}", dsk.dest.to_base32());
+            0
+        }
+        Err(e) => {
+            error!("Failed to write private key file: {}", e);
+            1
+        }
+    }
+}
+
+/// Checks that a `--caps` value only contains known capability codes.
+fn validate_caps(caps: String) -> Result<(), String> {
+    match caps.parse::<data::RouterCaps>() {
+        Ok(parsed) if parsed.unknown().is_empty() => Ok(()),
+        Ok(parsed) => Err(format!("unknown capabilities: {}", parsed.unknown())),
+        Err(e) => match e {},
+    }
+}
+
+fn cli_netdb_export(netdb_dir: &Path, args: &ArgMatches) -> i32 {
+    let ris = match persist::load_router_infos(netdb_dir, false, true, &Profiles::default()) {
+        Ok(ris) => ris,
+        Err(e) => {
+            error!("Failed to read netDb: {}", e);
+            return 1;
+        }
+    };
+    let filter = export::Filter {
+        caps: args.value_of("caps").and_then(|caps| caps.parse().ok()),
+        max_age: None,
+    };
+    let ris = filter.apply(ris);
+
+    let out = Path::new(args.value_of("out").unwrap());
+    let res = match args.value_of("format") {
+        Some("dir") => export::export_dir(&ris, out).map_err(|e| e.to_string()),
+        _ => File::create(out)
+            .map_err(|e| e.to_string())
+            .and_then(|file| {
+                let count = ris.len();
+                serde_json::to_writer_pretty(file, &export::Export { router_infos: ris })
+                    .map(|()| count)
+                    .map_err(|e| e.to_string())
+            }),
+    };
+    match res {
+        Ok(count) => {
+            println!("Exported {} RouterInfos", count);
+            0
+        }
+        Err(e) => {
+            error!("Failed to export netDb: {}", e);
+            1
+        }
+    }
+}
+
+fn cli_netdb_import(netdb_dir: &Path, args: &ArgMatches) -> i32 {
+    let input = Path::new(args.value_of("in").unwrap());
+    let profiles = Profiles::default();
+    let imported = match args.value_of("format") {
+        Some("dir") => export::import_dir(input, &profiles).map_err(|e| e.to_string()),
+        _ => File::open(input)
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::from_reader(file).map_err(|e| e.to_string()))
+            .map(|exported: export::Export| {
+                export::import_router_infos(exported.router_infos, &profiles)
+            }),
+    };
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(e) => {
+            error!("Failed to read import: {}", e);
+            return 1;
+        }
+    };
+
+    let mut stored = 0;
+    for entry in &imported {
+        match &entry.result {
+            Ok(ri) => match persist::write_router_info(netdb_dir, ri) {
+                Ok(()) => {
+                    println!("Imported {}", entry.source);
+                    stored += 1;
+                }
+                Err(e) => println!("Failed to store {}: {}", entry.source, e),
+            },
+            Err(e) => println!("Rejected {}: {}", entry.source, e),
+        }
+    }
+    println!("Imported {} of {} RouterInfos", stored, imported.len());
+    if stored == 0 {
+        error!("No RouterInfos were imported");
+        1
+    } else {
+        0
+    }
+}
+
+fn cli_netdb_inspect(netdb_dir: &Path, args: &ArgMatches) -> i32 {
+    let hash: data::Hash = match args.value_of("hash").unwrap().parse() {
+        Ok(hash) => hash,
+        Err(_) => {
+            error!("Invalid router hash");
+            return 1;
+        }
+    };
+    let ri = fs::read(persist::router_info_path(netdb_dir, &hash))
+        .map_err(|e| e.to_string())
+        .and_then(|data| data::RouterInfo::from_bytes(&data).map_err(|e| e.to_string()));
+    match ri {
+        Ok(ri) => {
+            print!("{}", export::inspect(&ri));
+            0
+        }
+        Err(e) => {
+            error!("Failed to read RouterInfo {}: {}", hash, e);
+            1
+        }
+    }
+}
+
 fn cli_router(args: &ArgMatches) -> i32 {
-    let builder = Builder::new();
+    let mut loader = config::Loader::new();
+    let loaded = args
+        .value_of("config")
+        .or_else(|| args.value_of("cfgFile"))
+        .map_or(Ok(()), |cfg_file| loader.file(Path::new(cfg_file)))
+        .and_then(|()| {
+            args.values_of("set")
+                .into_iter()
+                .flatten()
+                .try_for_each(|arg| loader.set(arg))
+        });
+    if let Err(e) = loaded {
+        env_logger::init();
+        error!("Failed to load config: {}", e);
+        return 1;
+    }
+    let unknown = loader.unknown().to_vec();
+    let settings = loader.finish();
+
+    // RUST_LOG takes precedence over the configured filter
+    let env = env_logger::Env::default();
+    let env = match settings.get_str(config::LOGGING_FILTER) {
+        Ok(filter) => env.default_filter_or(filter),
+        Err(_) => env,
+    };
+    env_logger::Builder::from_env(env).init();
+    for option in unknown {
+        warn!("{}", option);
+    }
+
+    let builder = Builder::new().config(settings);
 
-    let builder = if let Some(cfg_file) = args.value_of("cfgFile") {
-        builder.config_file(cfg_file.to_string())
+    let builder = if let Some(reseed_from) = args.value_of("reseedFrom") {
+        builder.reseed_from(reseed_from.to_string())
     } else {
         builder
     };
@@ -118,13 +399,15 @@ fn cli_client(args: &ArgMatches) -> i32 {
         Some("NTCP") => {
             let mut ntcp =
                 transport::ntcp::Manager::new("127.0.0.1:0".parse().unwrap(), distributor);
-            ntcp.set_context(mock_context());
+            let ctx = mock_context();
+            ntcp.set_context(ctx.clone());
             let conn = ntcp
                 .connect(rsk.rid, rsk.signing_private_key, peer_ri.clone())
                 .unwrap()
                 .and_then(move |_| {
                     info!("Connection established!");
-                    ntcp.sink().send((peer_ri, i2np::Message::dummy_data()))
+                    let msg = i2np::Message::data(&ctx.msg_ids, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+                    ntcp.sink().send((peer_ri, msg))
                 })
                 .and_then(|_| {
                     info!("Dummy data sent!");
@@ -136,13 +419,15 @@ fn cli_client(args: &ArgMatches) -> i32 {
         Some("NTCP2") => {
             let mut ntcp2 =
                 transport::ntcp2::Manager::new("127.0.0.1:0".parse().unwrap(), distributor);
-            ntcp2.set_context(mock_context());
+            let ctx = mock_context();
+            ntcp2.set_context(ctx.clone());
             let conn = ntcp2
                 .connect(&ri, peer_ri.clone())
                 .unwrap()
                 .and_then(move |_| {
                     info!("Connection established!");
-                    ntcp2.sink().send((peer_ri, i2np::Message::dummy_data()))
+                    let msg = i2np::Message::data(&ctx.msg_ids, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+                    ntcp2.sink().send((peer_ri, msg))
                 })
                 .and_then(|_| {
                     info!("Dummy data sent!");
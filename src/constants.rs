@@ -1,18 +1,7 @@
-use data_encoding::{Encoding, Specification};
 use num_bigint::BigUint;
 use num_traits::{Num, One};
 use std::ops::Sub;
 
-lazy_static! {
-    pub static ref I2P_BASE64: Encoding = {
-        let mut spec = Specification::new();
-        spec.symbols
-            .push_str("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~");
-        spec.padding = Some('=');
-        spec.encoding().unwrap()
-    };
-}
-
 // Sig types
 pub const DSA_SHA1: u16 = 0;
 pub const ECDSA_SHA256_P256: u16 = 1;
@@ -22,9 +11,11 @@ pub const RSA_SHA256_2048: u16 = 4;
 pub const RSA_SHA384_3072: u16 = 5;
 pub const RSA_SHA512_4096: u16 = 6;
 pub const ED25519: u16 = 7;
+pub const REDDSA_SHA512_ED25519: u16 = 11;
 
 // Enc types
 pub const ELGAMAL2048: u16 = 0;
+pub const X25519: u16 = 4;
 
 // Key material constants
 pub const KEYCERT_SIGKEY_BYTES: usize = 128;
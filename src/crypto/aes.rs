@@ -0,0 +1,297 @@
+//! AES-256-CBC as used by NTCP and ElGamal/AES+SessionTag.
+//!
+//! I2P never uses the block-mode padding schemes; callers are responsible for
+//! laying out their data in whole blocks.
+
+use aes::cipher::generic_array::{ArrayLength, GenericArray};
+use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
+use std::slice;
+
+use super::{
+    rand::{CryptoRng, OsRng},
+    Error, SessionKey, AES_BLOCK_SIZE,
+};
+
+fn to_blocks<N>(data: &mut [u8]) -> &mut [GenericArray<u8, N>]
+where
+    N: ArrayLength<u8>,
+{
+    let n = N::to_usize();
+    assert!(data.len() % n == 0);
+
+    #[allow(unsafe_code)]
+    unsafe {
+        slice::from_raw_parts_mut(
+            data.as_mut_ptr() as *mut GenericArray<u8, N>,
+            data.len() / n,
+        )
+    }
+}
+
+/// Returns the number of padding bytes needed to extend `len` bytes to a whole
+/// number of AES blocks.
+pub fn padding_len(len: usize) -> usize {
+    (AES_BLOCK_SIZE - len % AES_BLOCK_SIZE) % AES_BLOCK_SIZE
+}
+
+/// Extends `buf` with random bytes up to a whole number of AES blocks.
+pub fn pad_random(buf: &mut Vec<u8>) {
+    pad_random_with_rng(buf, &mut OsRng)
+}
+
+/// As [`pad_random`], but drawing the padding from `rng`.
+pub fn pad_random_with_rng<R: CryptoRng>(buf: &mut Vec<u8>, rng: &mut R) {
+    let start = buf.len();
+    buf.resize(start + padding_len(start), 0);
+    rng.fill_bytes(&mut buf[start..]);
+}
+
+/// AES-256-CBC state for a bidirectional session.
+///
+/// Each direction has its own CBC chain, which continues across calls: the last
+/// ciphertext block of one call is the IV for the next.
+pub struct SessionCipher {
+    cbc_enc: Cbc<aes::Aes256, NoPadding>,
+    cbc_dec: Cbc<aes::Aes256, NoPadding>,
+}
+
+impl SessionCipher {
+    pub fn new(
+        key: &SessionKey,
+        iv_enc: &[u8; AES_BLOCK_SIZE],
+        iv_dec: &[u8; AES_BLOCK_SIZE],
+    ) -> Self {
+        SessionCipher {
+            cbc_enc: Cbc::new_from_slices(&key.0, iv_enc).expect("key and iv are correct length"),
+            cbc_dec: Cbc::new_from_slices(&key.0, iv_dec).expect("key and iv are correct length"),
+        }
+    }
+
+    /// Encrypts `buf` in place. Returns an error if it isn't a whole number of blocks.
+    pub fn encrypt_blocks(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        if buf.len() % AES_BLOCK_SIZE != 0 {
+            return Err(Error::InvalidMessage);
+        }
+        self.cbc_enc.encrypt_blocks(to_blocks(buf));
+        Ok(())
+    }
+
+    /// Decrypts `buf` in place. Returns an error if it isn't a whole number of blocks.
+    pub fn decrypt_blocks(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        if buf.len() % AES_BLOCK_SIZE != 0 {
+            return Err(Error::InvalidCiphertext);
+        }
+        self.cbc_dec.decrypt_blocks(to_blocks(buf));
+        Ok(())
+    }
+}
+
+/// A [`SessionCipher`] for streams, which processes as many whole blocks as are
+/// available and leaves any trailing partial block for a later call.
+pub(crate) struct Aes256(SessionCipher);
+
+impl Aes256 {
+    pub fn new(key: &SessionKey, iv_enc: &[u8], iv_dec: &[u8]) -> Self {
+        let mut enc = [0; AES_BLOCK_SIZE];
+        let mut dec = [0; AES_BLOCK_SIZE];
+        enc.copy_from_slice(iv_enc);
+        dec.copy_from_slice(iv_dec);
+        Aes256(SessionCipher::new(key, &enc, &dec))
+    }
+
+    pub fn encrypt_blocks(&mut self, buf: &mut [u8]) -> Option<usize> {
+        // Wait until we have at least a block to encrypt
+        if buf.len() < AES_BLOCK_SIZE {
+            return None;
+        }
+
+        // Integer division, leaves extra bytes unencrypted at the end
+        let end = (buf.len() / AES_BLOCK_SIZE) * AES_BLOCK_SIZE;
+        self.0
+            .encrypt_blocks(&mut buf[..end])
+            .expect("whole number of blocks");
+        Some(end)
+    }
+
+    pub fn decrypt_blocks(&mut self, buf: &mut [u8]) -> Option<usize> {
+        // Wait until we have at least a block to decrypt
+        if buf.len() < AES_BLOCK_SIZE {
+            return None;
+        }
+
+        // Integer division, leaves extra bytes undecrypted at the end
+        let end = (buf.len() / AES_BLOCK_SIZE) * AES_BLOCK_SIZE;
+        self.0
+            .decrypt_blocks(&mut buf[..end])
+            .expect("whole number of blocks");
+        Some(end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pad_random, padding_len, SessionCipher};
+    use crate::crypto::{Error, SessionKey, AES_BLOCK_SIZE};
+    use crate::data::encoding::hex_decode;
+
+    fn block(s: &str) -> [u8; AES_BLOCK_SIZE] {
+        let mut buf = [0; AES_BLOCK_SIZE];
+        buf.copy_from_slice(&hex_decode(s).unwrap());
+        buf
+    }
+
+    /// NIST SP 800-38A, F.2.5 and F.2.6 (CBC-AES256)
+    fn nist_vector() -> (SessionKey, [u8; AES_BLOCK_SIZE], Vec<u8>, Vec<u8>) {
+        let mut key = [0; 32];
+        key.copy_from_slice(
+            &hex_decode("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4")
+                .unwrap(),
+        );
+        let iv = block("000102030405060708090a0b0c0d0e0f");
+        let plaintext = hex_decode(
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+             30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+        )
+        .unwrap();
+        let ciphertext = hex_decode(
+            "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d\
+             39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b",
+        )
+        .unwrap();
+        (SessionKey(key), iv, plaintext, ciphertext)
+    }
+
+    #[test]
+    fn nist_known_answer() {
+        let (key, iv, plaintext, ciphertext) = nist_vector();
+        let mut cipher = SessionCipher::new(&key, &iv, &iv);
+
+        let mut buf = plaintext.clone();
+        cipher.encrypt_blocks(&mut buf).unwrap();
+        assert_eq!(buf, ciphertext);
+        cipher.decrypt_blocks(&mut buf).unwrap();
+        assert_eq!(buf, plaintext);
+    }
+
+    #[test]
+    fn chain_continues_across_calls() {
+        let (key, iv, plaintext, ciphertext) = nist_vector();
+
+        // Encrypting and decrypting one block at a time matches the single-call result
+        let mut cipher = SessionCipher::new(&key, &iv, &iv);
+        let mut buf = plaintext.clone();
+        for chunk in buf.chunks_mut(AES_BLOCK_SIZE) {
+            cipher.encrypt_blocks(chunk).unwrap();
+        }
+        assert_eq!(buf, ciphertext);
+        for chunk in buf.chunks_mut(AES_BLOCK_SIZE) {
+            cipher.decrypt_blocks(chunk).unwrap();
+        }
+        assert_eq!(buf, plaintext);
+
+        // NTCP derives the next IV from the last ciphertext block, so a fresh cipher
+        // set up that way picks up the chain where the first one left off
+        let mut buf = plaintext[32..].to_vec();
+        let next_iv = block("9cfc4e967edb808d679f777bc6702c7d");
+        let mut cipher = SessionCipher::new(&key, &next_iv, &next_iv);
+        cipher.encrypt_blocks(&mut buf).unwrap();
+        assert_eq!(buf, &ciphertext[32..]);
+    }
+
+    #[test]
+    fn partial_blocks() {
+        let (key, iv, plaintext, _) = nist_vector();
+        let mut cipher = SessionCipher::new(&key, &iv, &iv);
+
+        let mut buf = plaintext[..17].to_vec();
+        assert_eq!(cipher.encrypt_blocks(&mut buf), Err(Error::InvalidMessage));
+        assert_eq!(
+            cipher.decrypt_blocks(&mut buf),
+            Err(Error::InvalidCiphertext)
+        );
+        assert_eq!(buf, &plaintext[..17]);
+
+        // Empty input is trivially aligned
+        assert_eq!(cipher.encrypt_blocks(&mut []), Ok(()));
+    }
+
+    fn pattern(len: usize, start: u8) -> Vec<u8> {
+        (0..len).map(|i| start.wrapping_add(i as u8)).collect()
+    }
+
+    /// An NTCP handshake and the start of its data phase, with the IVs set up as the
+    /// NTCP spec describes: Bob encrypts from the last 16 bytes of Y, and Alice from
+    /// the second half of HXxorHI. The ciphertext was generated with Python's
+    /// `cryptography` package, and checked with OpenSSL.
+    #[test]
+    fn ntcp_transcript() {
+        let key =
+            hex_decode("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap();
+        let key = SessionKey(*array_ref![key, 0, 32]);
+        let y_iv = block("e0e1e2e3e4e5e6e7e8e9eaebecedeeef");
+        let hx_iv = block("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let mut bob = SessionCipher::new(&key, &y_iv, &hx_iv);
+        let mut alice = SessionCipher::new(&key, &hx_iv, &y_iv);
+
+        // (sent by Bob, plaintext, ciphertext)
+        let messages = [
+            // SessionCreated
+            (
+                true,
+                pattern(48, 0x40),
+                "224c27f4ba378b27d3d6888adced64420466b5ee10ea6f3eb4bd8c304c567564\
+                 7005a59c6da66e2c36598f294708754c",
+            ),
+            // SessionConfirmA
+            (
+                false,
+                pattern(48, 0x10),
+                "f97d2631b54672f9e1e9e52f5c516485cf07e798677c739e5ce217cb691cb800\
+                 800a8c2e19bb548bd5dd7ad9a3da3748",
+            ),
+            // SessionConfirmB
+            (
+                true,
+                pattern(32, 0x80),
+                "badea748388e280bc17c753f0716da4ba6d700582a614ec284324b0c26c2168a",
+            ),
+            // A data frame in each direction continues each chain
+            (true, pattern(16, 0xa0), "cad1570c67c467d3ca1010a84b0aa398"),
+            (
+                false,
+                pattern(32, 0xc0),
+                "6158c6b6314caeebd754355ff50132f71fdf806dc64f29db0305156896d53ad1",
+            ),
+        ];
+
+        for (from_bob, plaintext, ciphertext) in messages.iter() {
+            let (sender, receiver) = if *from_bob {
+                (&mut bob, &mut alice)
+            } else {
+                (&mut alice, &mut bob)
+            };
+            let mut buf = plaintext.clone();
+            sender.encrypt_blocks(&mut buf).unwrap();
+            assert_eq!(buf, hex_decode(ciphertext).unwrap());
+            receiver.decrypt_blocks(&mut buf).unwrap();
+            assert_eq!(&buf, plaintext);
+        }
+    }
+
+    #[test]
+    fn padding() {
+        assert_eq!(padding_len(0), 0);
+        assert_eq!(padding_len(1), 15);
+        assert_eq!(padding_len(15), 1);
+        assert_eq!(padding_len(16), 0);
+        assert_eq!(padding_len(33), 15);
+
+        for len in 0..40 {
+            let mut buf = vec![0xaa; len];
+            pad_random(&mut buf);
+            assert_eq!(buf.len() % AES_BLOCK_SIZE, 0);
+            assert_eq!(buf.len() - len, padding_len(len));
+            assert!(buf[..len].iter().all(|b| *b == 0xaa));
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! Key blinding and layer encryption for encrypted LeaseSets.
+//!
+//! [Specification](https://geti2p.net/spec/encryptedleaseset)
+
+use chrono::{DateTime, Utc};
+use curve25519_dalek::{
+    constants::ED25519_BASEPOINT_TABLE, edwards::CompressedEdwardsY, scalar::Scalar,
+};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+use std::time::{Duration, SystemTime};
+
+use super::{chachapoly::chacha20, hkdf, Error, SigType, SigningPrivateKey, SigningPublicKey};
+use crate::constants;
+use crate::data::Hash;
+
+/// How long after UTC midnight lookups also try the previous day's blinded key,
+/// to allow for clock skew between us and the publishing router.
+pub(crate) const ROLLOVER_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// H(p, d) from the specification.
+pub(crate) fn hash(personalization: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(personalization);
+    for d in data {
+        hasher.update(d);
+    }
+    let mut out = [0; 32];
+    out.copy_from_slice(&hasher.finalize());
+    out
+}
+
+/// Returns the UTC date used to derive the blinding factor at the given time, in the
+/// form "YYYYMMDD".
+pub(crate) fn date_string(t: SystemTime) -> String {
+    DateTime::<Utc>::from(t).format("%Y%m%d").to_string()
+}
+
+/// keydata = A || stA || stA'
+fn key_data(key: &SigningPublicKey) -> Vec<u8> {
+    let mut data = key.as_bytes().to_vec();
+    data.extend_from_slice(&key.sig_type().code().to_be_bytes());
+    data.extend_from_slice(&constants::REDDSA_SHA512_ED25519.to_be_bytes());
+    data
+}
+
+/// Derives the blinding factor for the given Destination signing key and date.
+pub(crate) fn generate_alpha(key: &SigningPublicKey, date: &str, secret: Option<&[u8]>) -> Scalar {
+    let salt = hash(b"I2PGenerateAlpha", &[&key_data(key)[..]]);
+    let mut ikm = date.as_bytes().to_vec();
+    if let Some(secret) = secret {
+        ikm.extend_from_slice(secret);
+    }
+
+    let mut seed = [0; 64];
+    hkdf::derive(&salt, &ikm, b"i2pblinding1", &mut seed);
+    Scalar::from_bytes_mod_order_wide(&seed)
+}
+
+/// A' = A + [alpha]B
+pub(crate) fn blind_public_key(key: &SigningPublicKey, alpha: &Scalar) -> Result<[u8; 32], Error> {
+    match *key {
+        SigningPublicKey::Ed25519(ref pk) | SigningPublicKey::RedDsaSha512Ed25519(ref pk) => {
+            let a = CompressedEdwardsY::from_slice(pk.as_bytes())
+                .decompress()
+                .ok_or(Error::InvalidKey)?;
+            Ok((a + alpha * &ED25519_BASEPOINT_TABLE).compress().to_bytes())
+        }
+        _ => Err(Error::TypeMismatch),
+    }
+}
+
+/// Returns the blinded signing key for the given Destination signing key and date,
+/// for use without a secret.
+pub(crate) fn blinded_key(
+    dest_key: &SigningPublicKey,
+    date: &str,
+) -> Result<SigningPublicKey, Error> {
+    let alpha = generate_alpha(dest_key, date, None);
+    let blinded = blind_public_key(dest_key, &alpha)?;
+    SigningPublicKey::from_bytes(SigType::RedDsaSha512Ed25519, &blinded)
+}
+
+/// Returns the network database key for a blinded signing key: H(stA' || A').
+pub(crate) fn store_key(blinded_key: &SigningPublicKey) -> Hash {
+    let mut data = constants::REDDSA_SHA512_ED25519.to_be_bytes().to_vec();
+    data.extend_from_slice(blinded_key.as_bytes());
+    Hash::digest(&data)
+}
+
+/// Returns the dates whose blinded keys should be used to look up an encrypted
+/// LeaseSet at the given time. The current UTC date always comes first; within
+/// [`ROLLOVER_WINDOW`] after midnight, the previous date is also included.
+pub(crate) fn lookup_dates(now: SystemTime) -> Vec<String> {
+    let mut dates = vec![date_string(now)];
+    if let Some(earlier) = now.checked_sub(ROLLOVER_WINDOW) {
+        let yesterday = date_string(earlier);
+        if yesterday != dates[0] {
+            dates.push(yesterday);
+        }
+    }
+    dates
+}
+
+/// Returns the network database keys under which an encrypted LeaseSet for the
+/// given Destination signing key may be stored at the given time, in the order
+/// they should be looked up.
+pub(crate) fn lookup_store_keys(
+    dest_key: &SigningPublicKey,
+    now: SystemTime,
+) -> Result<Vec<Hash>, Error> {
+    lookup_dates(now)
+        .iter()
+        .map(|date| blinded_key(dest_key, date).map(|key| store_key(&key)))
+        .collect()
+}
+
+/// a' = a + alpha (mod L)
+///
+/// The blinded key is always a RedDSA key, whether the Destination uses Ed25519
+/// or RedDSA.
+pub(crate) fn blind_private_key(
+    key: &SigningPrivateKey,
+    alpha: &Scalar,
+) -> Result<SigningPrivateKey, Error> {
+    let a = match *key {
+        SigningPrivateKey::Ed25519(ref seed) => {
+            // Ed25519 derives the private scalar from the seed
+            let h = Sha512::digest(seed.as_secret_slice());
+            let mut a = [0; 32];
+            a.copy_from_slice(&h[..32]);
+            a[0] &= 248;
+            a[31] &= 127;
+            a[31] |= 64;
+            Scalar::from_bytes_mod_order(a)
+        }
+        SigningPrivateKey::RedDsaSha512Ed25519(a) => a,
+        _ => return Err(Error::TypeMismatch),
+    };
+    Ok(SigningPrivateKey::RedDsaSha512Ed25519(a + alpha))
+}
+
+fn hash_to_scalar(data: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for d in data {
+        hasher.update(d);
+    }
+    let mut h = [0; 64];
+    h.copy_from_slice(&hasher.finalize());
+    Scalar::from_bytes_mod_order_wide(&h)
+}
+
+/// Generates a new RedDSA private scalar.
+pub(crate) fn red25519_generate() -> Scalar {
+    let mut buf = [0; 64];
+    OsRng.fill_bytes(&mut buf);
+    Scalar::from_bytes_mod_order_wide(&buf)
+}
+
+/// A = [a]B
+pub(crate) fn red25519_public_key(key: &Scalar) -> [u8; 32] {
+    (key * &ED25519_BASEPOINT_TABLE).compress().to_bytes()
+}
+
+/// Signs a message with a RedDSA private key, such as a blinded key.
+///
+/// RedDSA signatures are verified in the same way as Ed25519 signatures; only the
+/// derivation of the nonce differs.
+pub(crate) fn red25519_sign(key: &Scalar, pub_key: &[u8; 32], msg: &[u8]) -> [u8; 64] {
+    let mut t = [0; 80];
+    OsRng.fill_bytes(&mut t);
+    red25519_sign_with_randomness(key, pub_key, msg, &t)
+}
+
+/// r = H*(T || A || M), with T supplied by the caller.
+fn red25519_sign_with_randomness(
+    key: &Scalar,
+    pub_key: &[u8; 32],
+    msg: &[u8],
+    t: &[u8; 80],
+) -> [u8; 64] {
+    let r = hash_to_scalar(&[&t[..], &pub_key[..], msg]);
+    let big_r = (&r * &ED25519_BASEPOINT_TABLE).compress();
+    let s = r + hash_to_scalar(&[&big_r.as_bytes()[..], &pub_key[..], msg]) * key;
+
+    let mut sig = [0; 64];
+    sig[..32].copy_from_slice(big_r.as_bytes());
+    sig[32..].copy_from_slice(s.as_bytes());
+    sig
+}
+
+/// credential = H("credential", A || stA || stA')
+pub(crate) fn credential(key: &SigningPublicKey) -> [u8; 32] {
+    hash(b"credential", &[&key_data(key)[..]])
+}
+
+/// subcredential = H("subcredential", credential || A')
+pub(crate) fn subcredential(credential: &[u8; 32], blinded_key: &[u8]) -> [u8; 32] {
+    hash(b"subcredential", &[&credential[..], blinded_key])
+}
+
+/// keys = HKDF(salt, input, label, 44); key = keys[0:31], iv = keys[32:43]
+fn layer_keys(salt: &[u8], input: &[u8], label: &[u8]) -> ([u8; 32], [u8; 12]) {
+    let mut keys = [0; 44];
+    hkdf::derive(salt, input, label, &mut keys);
+    (*array_ref![keys, 0, 32], *array_ref![keys, 32, 12])
+}
+
+fn apply_layer_keystream(salt: &[u8], input: &[u8], label: &[u8], buf: &mut [u8]) {
+    let (key, iv) = layer_keys(salt, input, label);
+    chacha20(&key, &iv, buf);
+}
+
+/// Encrypts one layer of an encrypted LeaseSet, returning salt || ciphertext.
+pub(crate) fn encrypt_layer(plaintext: &[u8], input: &[u8], label: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 32];
+    OsRng.fill_bytes(&mut buf);
+    buf.extend_from_slice(plaintext);
+
+    let (salt, ct) = buf.split_at_mut(32);
+    apply_layer_keystream(salt, input, label, ct);
+    buf
+}
+
+/// Decrypts one layer of an encrypted LeaseSet, given salt || ciphertext.
+pub(crate) fn decrypt_layer(data: &[u8], input: &[u8], label: &[u8]) -> Result<Vec<u8>, Error> {
+    if data.len() < 32 {
+        return Err(Error::InvalidCiphertext);
+    }
+    let (salt, ct) = data.split_at(32);
+    let mut pt = ct.to_vec();
+    apply_layer_keystream(salt, input, label, &mut pt);
+    Ok(pt)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::crypto::Signature;
+    use crate::data::encoding::hex_decode;
+
+    #[test]
+    fn date() {
+        assert_eq!(date_string(UNIX_EPOCH), "19700101");
+        assert_eq!(
+            date_string(UNIX_EPOCH + Duration::from_secs(1_524_874_654)),
+            "20180428"
+        );
+    }
+
+    #[test]
+    fn blinding_is_consistent() {
+        let sk = SigningPrivateKey::new();
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+
+        let alpha = generate_alpha(&pk, "20180428", None);
+        assert_eq!(alpha, generate_alpha(&pk, "20180428", None));
+        assert_ne!(alpha, generate_alpha(&pk, "20180429", None));
+        assert_ne!(alpha, generate_alpha(&pk, "20180428", Some(b"secret")));
+
+        // [a']B = A'
+        let blinded_pk = blind_public_key(&pk, &alpha).unwrap();
+        let blinded_sk = blind_private_key(&sk, &alpha).unwrap();
+        assert_eq!(
+            SigningPublicKey::from_secret(&blinded_sk).unwrap(),
+            SigningPublicKey::from_bytes(SigType::RedDsaSha512Ed25519, &blinded_pk).unwrap()
+        );
+        assert_ne!(&blinded_pk[..], pk.as_bytes());
+
+        // RedDSA keys can be blinded again
+        let beta = generate_alpha(&pk, "20180429", None);
+        let reblinded_sk = blind_private_key(&blinded_sk, &beta).unwrap();
+        assert_eq!(
+            SigningPublicKey::from_secret(&reblinded_sk)
+                .unwrap()
+                .as_bytes(),
+            blind_public_key(
+                &SigningPublicKey::from_bytes(SigType::RedDsaSha512Ed25519, &blinded_pk).unwrap(),
+                &beta
+            )
+            .unwrap()
+        );
+
+        // Alpha of zero is the identity
+        assert_eq!(
+            &blind_public_key(&pk, &Scalar::zero()).unwrap()[..],
+            pk.as_bytes()
+        );
+    }
+
+    #[test]
+    fn store_keys() {
+        let sk = SigningPrivateKey::new();
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+
+        let key = blinded_key(&pk, "20190205").unwrap();
+        assert_eq!(key.sig_type(), SigType::RedDsaSha512Ed25519);
+        assert_eq!(
+            key.as_bytes(),
+            &blind_public_key(&pk, &generate_alpha(&pk, "20190205", None)).unwrap()[..]
+        );
+
+        // SHA-256 of the blinded key's signature type (11) and the key itself
+        let mut data = vec![0x00, 0x0b];
+        data.extend_from_slice(key.as_bytes());
+        assert_eq!(store_key(&key), Hash::digest(&data));
+
+        // 2019-02-05 12:00:00 UTC
+        let noon = UNIX_EPOCH + Duration::from_secs(1_549_368_000);
+        assert_eq!(lookup_store_keys(&pk, noon).unwrap(), vec![store_key(&key)]);
+    }
+
+    #[test]
+    fn date_rollover() {
+        // 2019-02-05 00:00:00 UTC
+        let midnight = UNIX_EPOCH + Duration::from_secs(1_549_324_800);
+        let secs = Duration::from_secs;
+
+        // Just before midnight, only today's date is used
+        assert_eq!(lookup_dates(midnight - secs(1)), vec!["20190204"]);
+
+        // Within the window after midnight, yesterday's date is also used
+        assert_eq!(lookup_dates(midnight), vec!["20190205", "20190204"]);
+        assert_eq!(
+            lookup_dates(midnight + ROLLOVER_WINDOW - secs(1)),
+            vec!["20190205", "20190204"]
+        );
+
+        // After the window, only today's date is used again
+        assert_eq!(lookup_dates(midnight + ROLLOVER_WINDOW), vec!["20190205"]);
+
+        let sk = SigningPrivateKey::new();
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+        let today = store_key(&blinded_key(&pk, "20190205").unwrap());
+        let yesterday = store_key(&blinded_key(&pk, "20190204").unwrap());
+        assert_ne!(today, yesterday);
+        assert_eq!(
+            lookup_store_keys(&pk, midnight + secs(60)).unwrap(),
+            vec![today, yesterday]
+        );
+    }
+
+    #[test]
+    fn red25519_signatures_verify_as_ed25519() {
+        let sk = SigningPrivateKey::new();
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+        let alpha = generate_alpha(&pk, "20180428", None);
+        let blinded_pk = blind_public_key(&pk, &alpha).unwrap();
+        let blinded_sk = blind_private_key(&sk, &alpha).unwrap();
+
+        let sig = blinded_sk.sign(b"hello").unwrap();
+        assert_eq!(sig.sig_type(), Some(SigType::RedDsaSha512Ed25519));
+        let verifier = SigningPublicKey::from_bytes(SigType::Ed25519, &blinded_pk).unwrap();
+        let sig = Signature::from_bytes(SigType::Ed25519, &sig.to_bytes()).unwrap();
+        assert_eq!(verifier.verify(b"hello", &sig), Ok(()));
+        assert_eq!(
+            verifier.verify(b"world", &sig),
+            Err(Error::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn red25519_keys() {
+        let sk = SigningPrivateKey::with_type(SigType::RedDsaSha512Ed25519);
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+        assert_eq!(pk.sig_type(), SigType::RedDsaSha512Ed25519);
+
+        // Private keys round-trip, and must be canonical scalars
+        let sk2 =
+            SigningPrivateKey::from_bytes(SigType::RedDsaSha512Ed25519, sk.as_bytes()).unwrap();
+        assert_eq!(sk2.as_bytes(), sk.as_bytes());
+        assert!(SigningPrivateKey::from_bytes(SigType::RedDsaSha512Ed25519, &[0xff; 32]).is_err());
+        assert!(SigningPrivateKey::from_bytes(SigType::RedDsaSha512Ed25519, &[0; 31]).is_err());
+
+        // Signatures are randomized, and all verify
+        let sig1 = sk.sign(b"hello").unwrap();
+        let sig2 = sk2.sign(b"hello").unwrap();
+        assert_ne!(sig1.to_bytes(), sig2.to_bytes());
+        assert_eq!(pk.verify(b"hello", &sig1), Ok(()));
+        assert_eq!(pk.verify(b"hello", &sig2), Ok(()));
+        assert_eq!(pk.verify(b"world", &sig1), Err(Error::InvalidSignature));
+    }
+
+    /// Uses the RFC 8032 section 7.1 TEST 1 key, with the layer input for a LeaseSet
+    /// published at 2019-02-05 12:00:00 UTC and a salt of 0x00..0x1f. Generated with
+    /// an independent Python implementation of the specification.
+    #[test]
+    fn layer_key_vectors() {
+        let seed =
+            hex_decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60").unwrap();
+        let sk = SigningPrivateKey::from_bytes(SigType::Ed25519, &seed).unwrap();
+        let pk = SigningPublicKey::from_secret(&sk).unwrap();
+        let blinded = blinded_key(&pk, "20190205").unwrap();
+
+        let credential = credential(&pk);
+        assert_eq!(
+            &credential[..],
+            &hex_decode("5f0296354332c87414e9625fdd7acd3fb6acdb83094444c90079ce0409c3435a")
+                .unwrap()[..]
+        );
+
+        let subcredential = subcredential(&credential, blinded.as_bytes());
+        assert_eq!(
+            &subcredential[..],
+            &hex_decode("c44f09a756737c2a40e1601e2ed18618adaa29ffedb4dc416e703983bd17b250")
+                .unwrap()[..]
+        );
+
+        let mut input = subcredential.to_vec();
+        input.extend_from_slice(&1_549_368_000u32.to_be_bytes());
+        let mut salt = [0; 32];
+        for (i, b) in salt.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let (key, iv) = layer_keys(&salt, &input, b"ELS2_L1K");
+        assert_eq!(
+            &key[..],
+            &hex_decode("0fd45879fcc85ecae8ace8d90a2cabea6e8275581ff73039396573800834a90c")
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &iv[..],
+            &hex_decode("848529c3bf63619761292caa").unwrap()[..]
+        );
+
+        let (key, iv) = layer_keys(&salt, &input, b"ELS2_L2K");
+        assert_eq!(
+            &key[..],
+            &hex_decode("5585136a3df7771c2d99fdfe5e479277c603aedcea819341d655ebf483ce8f52")
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &iv[..],
+            &hex_decode("1c84b25995456cd47193f523").unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn layer_round_trip() {
+        let ct = encrypt_layer(b"plaintext", b"input", b"ELS2_L1K");
+        assert_eq!(ct.len(), 32 + 9);
+        assert_ne!(&ct[32..], b"plaintext");
+        assert_eq!(
+            decrypt_layer(&ct, b"input", b"ELS2_L1K").unwrap(),
+            b"plaintext"
+        );
+        assert_ne!(
+            decrypt_layer(&ct, b"other", b"ELS2_L1K").unwrap(),
+            b"plaintext"
+        );
+        assert_eq!(
+            decrypt_layer(&ct[..31], b"input", b"ELS2_L1K"),
+            Err(Error::InvalidCiphertext)
+        );
+    }
+}
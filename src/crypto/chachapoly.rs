@@ -0,0 +1,180 @@
+//! ChaCha20-Poly1305 AEAD as specified in RFC 8439, and the bare ChaCha20 stream
+//! cipher for the places where I2P uses it without authentication.
+//!
+//! All operations work in place, with the authentication tag detached from the
+//! ciphertext.
+
+use chacha20::{
+    cipher::{NewCipher, StreamCipher},
+    ChaCha20,
+};
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+
+use super::Error;
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+/// Returns the nonce for the given message counter, in the form used by Noise (and
+/// therefore NTCP2): four zero bytes followed by the little-endian counter.
+pub fn noise_nonce(counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Encrypts `buf` in place, returning the authentication tag over the ciphertext
+/// and `ad`.
+pub fn seal_in_place(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    ad: &[u8],
+    buf: &mut [u8],
+) -> [u8; TAG_LEN] {
+    let tag = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), ad, buf)
+        .expect("buffer is shorter than the ChaCha20 keystream");
+    let mut out = [0; TAG_LEN];
+    out.copy_from_slice(&tag);
+    out
+}
+
+/// Checks the tag over `buf` and `ad` in constant time, and if it is valid decrypts
+/// `buf` in place. `buf` is left unmodified if the tag is invalid.
+pub fn open_in_place(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    ad: &[u8],
+    buf: &mut [u8],
+    tag: &[u8; TAG_LEN],
+) -> Result<(), Error> {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt_in_place_detached(Nonce::from_slice(nonce), ad, buf, Tag::from_slice(tag))
+        .map_err(|_| Error::InvalidCiphertext)
+}
+
+/// XORs `buf` with the ChaCha20 keystream for the given key and nonce, starting
+/// from block 0.
+pub fn chacha20(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], buf: &mut [u8]) {
+    ChaCha20::new(
+        chacha20::Key::from_slice(key),
+        chacha20::Nonce::from_slice(nonce),
+    )
+    .apply_keystream(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chacha20, noise_nonce, open_in_place, seal_in_place, KEY_LEN, NONCE_LEN};
+    use crate::crypto::Error;
+    use crate::data::encoding::hex_decode;
+
+    #[test]
+    fn rfc8439_aead() {
+        // RFC 8439, section 2.8.2
+        let mut key = [0; KEY_LEN];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = 0x80 + i as u8;
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&hex_decode("070000004041424344454647").unwrap());
+        let ad = hex_decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer \
+                                 you only one tip for the future, sunscreen would be it.";
+        let ciphertext = hex_decode(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116",
+        )
+        .unwrap();
+        let mut tag = [0; 16];
+        tag.copy_from_slice(&hex_decode("1ae10b594f09e26a7e902ecbd0600691").unwrap());
+
+        let mut buf = plaintext.to_vec();
+        assert_eq!(seal_in_place(&key, &nonce, &ad, &mut buf), tag);
+        assert_eq!(buf, ciphertext);
+
+        // Flipped tag bit
+        let mut bad_tag = tag;
+        bad_tag[15] ^= 0x01;
+        assert_eq!(
+            open_in_place(&key, &nonce, &ad, &mut buf, &bad_tag),
+            Err(Error::InvalidCiphertext)
+        );
+        assert_eq!(buf, ciphertext);
+
+        // Wrong associated data
+        assert_eq!(
+            open_in_place(&key, &nonce, &ad[1..], &mut buf, &tag),
+            Err(Error::InvalidCiphertext)
+        );
+        assert_eq!(buf, ciphertext);
+
+        // Flipped ciphertext bit
+        buf[0] ^= 0x01;
+        assert_eq!(
+            open_in_place(&key, &nonce, &ad, &mut buf, &tag),
+            Err(Error::InvalidCiphertext)
+        );
+        buf[0] ^= 0x01;
+
+        assert_eq!(open_in_place(&key, &nonce, &ad, &mut buf, &tag), Ok(()));
+        assert_eq!(buf, plaintext);
+    }
+
+    #[test]
+    fn rfc8439_keystream() {
+        // RFC 8439, appendix A.1, test vector #1
+        let mut buf = [0; 64];
+        chacha20(&[0; KEY_LEN], &[0; NONCE_LEN], &mut buf);
+        assert_eq!(
+            &buf[..],
+            &hex_decode(
+                "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+                 da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+            )
+            .unwrap()[..]
+        );
+
+        // Applying the keystream again decrypts
+        chacha20(&[0; KEY_LEN], &[0; NONCE_LEN], &mut buf);
+        assert_eq!(buf, [0; 64]);
+    }
+
+    #[test]
+    fn noise_nonces() {
+        assert_eq!(noise_nonce(0), [0; NONCE_LEN]);
+        assert_eq!(
+            noise_nonce(0x0102_0304_0506_0708),
+            [0, 0, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1]
+        );
+
+        // Generated with Python's cryptography library
+        let mut key = [0; KEY_LEN];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let nonce = noise_nonce(0x0102_0304_0506_0708);
+        let mut buf = b"I2P NTCP2 frame".to_vec();
+        let tag = seal_in_place(&key, &nonce, &[], &mut buf);
+        assert_eq!(
+            [&buf[..], &tag[..]].concat(),
+            hex_decode("b97bfdc4e56da948568b4cbe1215049b80279c3ec7a1055838d8c2c6c8c971").unwrap()
+        );
+        assert_eq!(open_in_place(&key, &nonce, &[], &mut buf, &tag), Ok(()));
+        assert_eq!(buf, b"I2P NTCP2 frame");
+
+        // A different counter doesn't authenticate
+        let mut buf = b"I2P NTCP2 frame".to_vec();
+        let tag = seal_in_place(&key, &noise_nonce(1), &[], &mut buf);
+        assert_eq!(
+            open_in_place(&key, &noise_nonce(2), &[], &mut buf, &tag),
+            Err(Error::InvalidCiphertext)
+        );
+    }
+}
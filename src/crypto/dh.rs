@@ -1,10 +1,12 @@
+use curve25519_dalek::{constants::X25519_BASEPOINT, montgomery::MontgomeryPoint, scalar::Scalar};
 use num_bigint::BigUint;
 use rand::{rngs::OsRng, Rng};
 use std::iter::repeat;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::constants::{ELGAMAL_G, ELGAMAL_P};
 use crate::crypto::math::rectify;
-use crate::crypto::SessionKey;
+use crate::crypto::{Error, SessionKey};
 
 pub struct DHSessionKeyBuilder {
     dh_priv: BigUint,
@@ -17,6 +19,7 @@ impl DHSessionKeyBuilder {
         let mut buf = vec![0; 256];
         rng.fill(&mut buf[..]);
         let dh_priv = BigUint::from_bytes_be(&buf);
+        buf.zeroize();
         let dh_pub = ELGAMAL_G.modpow(&dh_priv, &ELGAMAL_P);
         DHSessionKeyBuilder { dh_priv, dh_pub }
     }
@@ -42,9 +45,64 @@ impl DHSessionKeyBuilder {
         if length < 32 {
             buf.extend(repeat(0).take(32 - length));
         }
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&buf[0..32]);
-        SessionKey(key)
+        let mut key = SessionKey([0u8; 32]);
+        key.0.copy_from_slice(&buf[0..32]);
+        buf.zeroize();
+        key
+    }
+}
+
+/// Clamps an X25519 secret key as described in RFC 7748, section 5.
+fn clamp(secret: &[u8; 32]) -> Scalar {
+    let mut k = *secret;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+    Scalar::from_bits(k)
+}
+
+/// Computes the X25519 public key corresponding to the given secret key.
+pub fn x25519_base(secret: &[u8; 32]) -> [u8; 32] {
+    (X25519_BASEPOINT * clamp(secret)).to_bytes()
+}
+
+/// Computes the X25519 function from RFC 7748 on the given secret key and peer public
+/// key, in constant time.
+///
+/// Returns an error if the result is all-zero, which happens when the peer public key
+/// is a point of small order.
+pub fn x25519(secret: &[u8; 32], public: &[u8; 32]) -> Result<[u8; 32], Error> {
+    let shared = (MontgomeryPoint(*public) * clamp(secret)).to_bytes();
+
+    // Check for the all-zero value without branching on individual bytes
+    if shared.iter().fold(0, |acc, b| acc | b) == 0 {
+        Err(Error::InvalidKey)
+    } else {
+        Ok(shared)
+    }
+}
+
+pub struct X25519SessionKeyBuilder {
+    dh_priv: Zeroizing<[u8; 32]>,
+    dh_pub: [u8; 32],
+}
+
+impl X25519SessionKeyBuilder {
+    pub fn new() -> Self {
+        let mut rng = OsRng;
+        let mut dh_priv = Zeroizing::new([0; 32]);
+        rng.fill(&mut dh_priv[..]);
+        let dh_pub = x25519_base(&dh_priv);
+        X25519SessionKeyBuilder { dh_priv, dh_pub }
+    }
+
+    pub fn get_pub(&self) -> [u8; 32] {
+        self.dh_pub
+    }
+
+    /// Returns the raw shared secret, for use as input to a KDF.
+    pub fn build_shared_secret(&self, peer_pub: &[u8; 32]) -> Result<[u8; 32], Error> {
+        x25519(&self.dh_priv, peer_pub)
     }
 }
 
@@ -52,9 +110,10 @@ impl DHSessionKeyBuilder {
 mod tests {
     use num_bigint::BigUint;
     use num_traits::Num;
+    use zeroize::Zeroizing;
 
-    use super::DHSessionKeyBuilder;
-    use crate::crypto::SessionKey;
+    use super::{x25519, x25519_base, DHSessionKeyBuilder, X25519SessionKeyBuilder};
+    use crate::crypto::{Error, SessionKey};
 
     #[test]
     fn build_session_key() {
@@ -201,4 +260,108 @@ mod tests {
             assert_eq!(session_key.0, tv.session_key.0);
         }
     }
+
+    #[test]
+    fn x25519_rfc7748_vectors() {
+        // RFC 7748, section 5.2
+        let test_vectors = [
+            (
+                "a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4",
+                "e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c",
+                "c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552",
+            ),
+            (
+                "4b66e9d4d1b4673c5ad22691957d6af5c11b6421e0ea01d42ca4169e7918ba0d",
+                "e5210f12786811d3f4b7959d0538ae2c31dbe7106fc03c3efc4cd549c715a493",
+                "95cbde9476e8907d7ade45cb4b873f88b595a68799fa152f6d64ab92dab4d5c8",
+            ),
+        ];
+
+        for (scalar, u, expected) in test_vectors.iter() {
+            assert_eq!(x25519(&hex32(scalar), &hex32(u)), Ok(hex32(expected)));
+        }
+    }
+
+    #[test]
+    fn x25519_rfc7748_iterated() {
+        // RFC 7748, section 5.2
+        let mut k = [0; 32];
+        k[0] = 9;
+        let mut u = k;
+        for i in 1..=1000 {
+            let next = x25519(&k, &u).unwrap();
+            u = k;
+            k = next;
+            if i == 1 {
+                assert_eq!(
+                    k,
+                    hex32("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079")
+                );
+            }
+        }
+        assert_eq!(
+            k,
+            hex32("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51")
+        );
+    }
+
+    #[test]
+    fn x25519_rfc7748_key_agreement() {
+        // RFC 7748, section 6.1
+        let alice_priv = hex32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let alice_pub = hex32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+        let bob_priv = hex32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let bob_pub = hex32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+        let shared = hex32("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+
+        assert_eq!(x25519_base(&alice_priv), alice_pub);
+        assert_eq!(x25519_base(&bob_priv), bob_pub);
+        assert_eq!(x25519(&alice_priv, &bob_pub), Ok(shared));
+        assert_eq!(x25519(&bob_priv, &alice_pub), Ok(shared));
+
+        let alice = X25519SessionKeyBuilder {
+            dh_priv: Zeroizing::new(alice_priv),
+            dh_pub: alice_pub,
+        };
+        assert_eq!(alice.get_pub(), alice_pub);
+        assert_eq!(alice.build_shared_secret(&bob_pub), Ok(shared));
+    }
+
+    #[test]
+    fn x25519_session_key_builder() {
+        let alice = X25519SessionKeyBuilder::new();
+        let bob = X25519SessionKeyBuilder::new();
+        assert_ne!(alice.get_pub(), bob.get_pub());
+        assert_eq!(
+            alice.build_shared_secret(&bob.get_pub()),
+            bob.build_shared_secret(&alice.get_pub())
+        );
+    }
+
+    #[test]
+    fn x25519_rejects_small_order_points() {
+        let secret = X25519SessionKeyBuilder::new().dh_priv;
+
+        // Points of small order, and a non-canonical encoding of zero
+        let mut small_order = vec![[0; 32], [0; 32]];
+        small_order[1][0] = 1;
+        small_order.push(hex32(
+            "ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        ));
+        small_order.push(hex32(
+            "edffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f",
+        ));
+
+        for public in small_order {
+            assert_eq!(x25519(&secret, &public), Err(Error::InvalidKey));
+        }
+    }
+
+    fn hex32(s: &str) -> [u8; 32] {
+        let mut buf = [0; 32];
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap();
+        }
+        buf
+    }
 }
@@ -0,0 +1,155 @@
+//! One-shot public-key encryption to an X25519 key, as used by ECIES-X25519
+//! tunnel build records.
+//!
+//! A message is encrypted with the Noise N pattern: the sender generates an
+//! ephemeral key, performs a DH with the recipient's static key, and encrypts
+//! the message with ChaChaPoly under the resulting key. The ciphertext is the
+//! ephemeral public key followed by the encrypted message and its tag.
+//!
+//! Tunnel build records derive further keys from the Noise state after the
+//! message, so it is returned along with the ciphertext or plaintext.
+//!
+//! This is not ECIES-X25519-AEAD-Ratchet, which garlic messages to X25519 keys
+//! would need.
+//!
+//! [Noise specification](https://noiseprotocol.org/noise.html#one-way-handshake-patterns)
+
+use super::{
+    chachapoly::TAG_LEN,
+    dh::{x25519, x25519_base},
+    noise::NoiseSymmetricState,
+    rand::CryptoRng,
+    Error,
+};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_N_25519_ChaChaPoly_SHA256";
+
+pub const KEY_LEN: usize = 32;
+
+/// The number of bytes that encryption adds to a message.
+pub const OVERHEAD: usize = KEY_LEN + TAG_LEN;
+
+fn initialize(remote_static_key: &[u8; KEY_LEN]) -> NoiseSymmetricState {
+    let mut state = NoiseSymmetricState::new(PROTOCOL_NAME);
+    // Empty prologue, then the pre-message <- s
+    state.mix_hash(&[]);
+    state.mix_hash(remote_static_key);
+    state
+}
+
+/// Encrypts `msg` to the given public key, with an ephemeral key drawn from
+/// `rng`, and returns the Noise state after the message.
+pub fn encrypt_with_state<R: CryptoRng>(
+    public_key: &[u8; KEY_LEN],
+    msg: &[u8],
+    rng: &mut R,
+) -> Result<(Vec<u8>, NoiseSymmetricState), Error> {
+    let mut e = [0; KEY_LEN];
+    rng.fill_bytes(&mut e);
+    encrypt_with_ephemeral(public_key, msg, &e)
+}
+
+pub(crate) fn encrypt_with_ephemeral(
+    public_key: &[u8; KEY_LEN],
+    msg: &[u8],
+    e: &[u8; KEY_LEN],
+) -> Result<(Vec<u8>, NoiseSymmetricState), Error> {
+    let mut state = initialize(public_key);
+
+    // -> e, es
+    let mut ct = x25519_base(e).to_vec();
+    state.mix_hash(&ct);
+    state.mix_key(&x25519(e, public_key)?);
+    ct.extend(state.encrypt_and_hash(msg));
+    Ok((ct, state))
+}
+
+/// Decrypts a message that was encrypted to the public key for `secret_key`, and
+/// returns the Noise state after the message.
+pub fn decrypt_with_state(
+    secret_key: &[u8; KEY_LEN],
+    ct: &[u8],
+) -> Result<(Vec<u8>, NoiseSymmetricState), Error> {
+    if ct.len() < OVERHEAD {
+        return Err(Error::InvalidCiphertext);
+    }
+    let mut state = initialize(&x25519_base(secret_key));
+
+    let e = array_ref![ct, 0, KEY_LEN];
+    state.mix_hash(e);
+    state.mix_key(&x25519(secret_key, e)?);
+    let msg = state.decrypt_and_hash(&ct[KEY_LEN..])?;
+    Ok((msg, state))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decrypt_with_state, encrypt_with_ephemeral, encrypt_with_state, KEY_LEN, OVERHEAD,
+    };
+    use crate::crypto::{dh::x25519_base, rand::OsRng, Error};
+    use crate::data::encoding::hex_decode;
+
+    fn encrypt(public_key: &[u8; KEY_LEN], msg: &[u8]) -> Vec<u8> {
+        encrypt_with_state(public_key, msg, &mut OsRng).unwrap().0
+    }
+
+    fn decrypt(secret_key: &[u8; KEY_LEN], ct: &[u8]) -> Result<Vec<u8>, Error> {
+        decrypt_with_state(secret_key, ct).map(|(msg, _)| msg)
+    }
+
+    fn key(start: u8) -> [u8; 32] {
+        let mut k = [0; 32];
+        for (i, b) in k.iter_mut().enumerate() {
+            *b = start + i as u8;
+        }
+        k
+    }
+
+    /// Checked against an independent implementation of the Noise N pattern.
+    #[test]
+    fn noise_n_vector() {
+        let public_key = x25519_base(&key(64));
+        let (ct, _) =
+            encrypt_with_ephemeral(&public_key, b"ECIES-X25519 session block", &key(32)).unwrap();
+        assert_eq!(
+            ct,
+            hex_decode(
+                "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd166254\
+                 06415b86635145acc41606ecd65ac7d7c2cbc74637361f93af05d365e85166f7\
+                 d53ab81a5b0de08468c5"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            decrypt(&key(64), &ct).unwrap(),
+            b"ECIES-X25519 session block"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let public_key = x25519_base(&key(64));
+        for &len in &[0, 1, 64, 1000] {
+            let msg = vec![0x42; len];
+            let ct = encrypt(&public_key, &msg);
+            assert_eq!(ct.len(), len + OVERHEAD);
+            assert_eq!(decrypt(&key(64), &ct).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn wrong_key_or_tampering() {
+        let public_key = x25519_base(&key(64));
+        let mut ct = encrypt(&public_key, b"hello");
+        assert_eq!(decrypt(&key(65), &ct), Err(Error::InvalidCiphertext));
+
+        let last = ct.len() - 1;
+        ct[last] ^= 1;
+        assert_eq!(decrypt(&key(64), &ct), Err(Error::InvalidCiphertext));
+        assert_eq!(
+            decrypt(&key(64), &ct[..OVERHEAD - 1]),
+            Err(Error::InvalidCiphertext)
+        );
+    }
+}
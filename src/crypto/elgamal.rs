@@ -1,23 +1,26 @@
 //! Implementation of I2P's ElGamal public-key encryption scheme over the
 //! 2048-bit MODP DH group.
 //!
-//! This implementation is not constant-time.
+//! The modular exponentiation is not constant-time, but the integrity check
+//! performed during decryption is.
 //!
 //! Original implementation in Java I2P was based on algorithms 8.17 and 8.18
 //! specified in section 8.4.1 of the Handbook of Applied Cryptography.
 
 use num_bigint::BigUint;
 use num_traits::Zero;
-use rand::{rngs::OsRng, Rng};
+use rand::Rng;
 use sha2::{Digest, Sha256};
 use std::ops::{Mul, Rem, Sub};
 
-use super::{math::rectify, Error, PrivateKey, PublicKey};
+use super::{
+    math::{ct_eq, rectify},
+    rand::{CryptoRng, OsRng},
+    Error, PrivateKey, PublicKey,
+};
 use crate::constants::{ELGAMAL_G, ELGAMAL_P, ELGAMAL_PM1, ELGAMAL_PM2};
 
-fn gen_gamma_k() -> (BigUint, BigUint) {
-    let mut rng = OsRng;
-
+fn gen_gamma_k<R: CryptoRng>(rng: &mut R) -> (BigUint, BigUint) {
     // Select a random integer k, 1 <= k <= p - 2
     let mut buf = vec![0; 256];
     let k = loop {
@@ -34,15 +37,43 @@ fn gen_gamma_k() -> (BigUint, BigUint) {
     (k, gamma)
 }
 
+/// The message-independent half of an ElGamal encryption: a random exponent k
+/// and γ = α^k mod p.
+///
+/// Neither value depends on the message or the recipient, so ephemerals can be
+/// generated ahead of time (see [`crate::crypto::pool`]). Each one must only be
+/// used for a single encryption.
+#[cfg_attr(all(test, feature = "nightly"), derive(Clone))]
+pub struct Ephemeral {
+    k: BigUint,
+    gamma: BigUint,
+}
+
+impl Ephemeral {
+    pub fn generate() -> Self {
+        Self::generate_with_rng(&mut OsRng)
+    }
+
+    pub fn generate_with_rng<R: CryptoRng>(rng: &mut R) -> Self {
+        let (k, gamma) = gen_gamma_k(rng);
+        Ephemeral { k, gamma }
+    }
+}
+
 /// Generates ElGamal keypairs.
 pub struct KeyPairGenerator;
 
 impl KeyPairGenerator {
     /// ElGamal key generation, following algorithm 8.17.
     pub fn generate() -> (PrivateKey, PublicKey) {
+        Self::generate_with_rng(&mut OsRng)
+    }
+
+    /// As [`KeyPairGenerator::generate`], but drawing randomness from `rng`.
+    pub fn generate_with_rng<R: CryptoRng>(rng: &mut R) -> (PrivateKey, PublicKey) {
         // Select a random integer a, 1 <= a <= p - 2
         // Public key is α^a mod p
-        let (a, alpha_a) = gen_gamma_k();
+        let (a, alpha_a) = gen_gamma_k(rng);
 
         let priv_key = {
             let buf = rectify(&a, 256);
@@ -60,6 +91,15 @@ impl KeyPairGenerator {
 
         (priv_key, pub_key)
     }
+
+    /// Recomputes the public key α^a mod p for an existing private key.
+    pub fn public_key(priv_key: &PrivateKey) -> PublicKey {
+        let a = BigUint::from_bytes_be(&priv_key.0[..]);
+        let buf = rectify(&ELGAMAL_G.modpow(&a, &ELGAMAL_P), 256);
+        let mut x = [0u8; 256];
+        x.copy_from_slice(&buf[..]);
+        PublicKey(x)
+    }
 }
 
 pub struct Encryptor(BigUint);
@@ -72,16 +112,16 @@ impl<'a> From<&'a PublicKey> for Encryptor {
 
 impl Encryptor {
     /// Basic ElGamal encryption, following algorithm 8.18 1).
-    fn encrypt_basic(&self, msg: &[u8]) -> Result<(BigUint, BigUint), Error> {
+    fn encrypt_basic(&self, msg: &[u8], ephemeral: Ephemeral) -> Result<(BigUint, BigUint), Error> {
         // Represent the message as an integer m in the range {0, 1, ..., p - 1}
         let m = BigUint::from_bytes_be(msg);
         if m > *ELGAMAL_PM1 {
             return Err(Error::InvalidMessage);
         }
 
-        // Select a random integer k, 1 <= k <= p - 2
-        // γ = α^k mod p
-        let (k, gamma) = gen_gamma_k();
+        // The ephemeral holds a random integer k, 1 <= k <= p - 2,
+        // and γ = α^k mod p
+        let Ephemeral { k, gamma } = ephemeral;
 
         // δ = m * (α^a)^k mod p
         let s = self.0.modpow(&k, &ELGAMAL_P);
@@ -92,6 +132,16 @@ impl Encryptor {
 
     /// ElGamal encryption using I2P's message and ciphertext encoding schemes.
     pub fn encrypt(&self, msg: &[u8], include_zeroes: bool) -> Result<Vec<u8>, Error> {
+        self.encrypt_with(msg, include_zeroes, Ephemeral::generate())
+    }
+
+    /// As [`Encryptor::encrypt`], but using a previously-generated ephemeral.
+    pub fn encrypt_with(
+        &self,
+        msg: &[u8],
+        include_zeroes: bool,
+        ephemeral: Ephemeral,
+    ) -> Result<Vec<u8>, Error> {
         // Message must be no more than 222 bytes
         if msg.len() > 222 {
             return Err(Error::InvalidMessage);
@@ -113,7 +163,7 @@ impl Encryptor {
         data.extend_from_slice(hash.as_slice());
         data.extend_from_slice(msg);
 
-        self.encrypt_basic(&data).map(|(gamma, delta)| {
+        self.encrypt_basic(&data, ephemeral).map(|(gamma, delta)| {
             if include_zeroes {
                 // ElGamal ciphertext:
                 // 0   1                       257 258                      514
@@ -188,9 +238,14 @@ impl Decryptor {
             (gamma, delta)
         };
 
+        // γ and δ must both be in the range {1, ..., p - 1}
+        if gamma.is_zero() || delta.is_zero() || gamma > *ELGAMAL_PM1 || delta > *ELGAMAL_PM1 {
+            return Err(Error::InvalidCiphertext);
+        }
+
         let data = self.decrypt_basic((gamma, delta));
-        if data.len() < 33 {
-            // Decrypted data is too small
+        if data.len() < 33 || data.len() > 33 + 222 {
+            // Decrypted data is the wrong size
             return Err(Error::InvalidCiphertext);
         }
 
@@ -199,7 +254,7 @@ impl Decryptor {
         // | nonzero byte | SHA256(msg) | msg |
         let msg = data[33..].to_vec();
         let hash = Sha256::digest(&msg);
-        if hash.as_slice() == &data[1..33] {
+        if ct_eq(hash.as_slice(), &data[1..33]) {
             Ok(msg)
         } else {
             Err(Error::InvalidCiphertext)
@@ -207,11 +262,36 @@ impl Decryptor {
     }
 }
 
+/// Encrypts a message of at most 222 bytes to the given public key, returning the
+/// 514-byte ciphertext used by garlic messages and tunnel build records.
+pub fn encrypt(pub_key: &PublicKey, msg: &[u8]) -> Result<Vec<u8>, Error> {
+    Encryptor::from(pub_key).encrypt(msg, true)
+}
+
+/// As [`encrypt`], but using a previously-generated ephemeral.
+pub fn encrypt_with(
+    pub_key: &PublicKey,
+    msg: &[u8],
+    ephemeral: Ephemeral,
+) -> Result<Vec<u8>, Error> {
+    Encryptor::from(pub_key).encrypt_with(msg, true, ephemeral)
+}
+
+/// Decrypts a 514-byte ciphertext produced by [`encrypt`].
+pub fn decrypt(priv_key: &PrivateKey, ct: &[u8]) -> Result<Vec<u8>, Error> {
+    Decryptor::from(priv_key).decrypt(ct, true)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Decryptor, Encryptor, KeyPairGenerator};
-    use crate::constants::I2P_BASE64;
-    use crate::crypto::{PrivateKey, PublicKey};
+    use num_bigint::BigUint;
+
+    use super::{
+        decrypt, encrypt, encrypt_with, Decryptor, Encryptor, Ephemeral, KeyPairGenerator,
+    };
+    use crate::constants::ELGAMAL_P;
+    use crate::crypto::{math::rectify, Error, PrivateKey, PublicKey};
+    use crate::data::encoding::b64_decode;
 
     #[test]
     fn round_trip_basic() {
@@ -221,13 +301,13 @@ mod tests {
 
         // All-zeroes message is returned as a single byte
         let msg = [0u8; 256];
-        let ct = enc.encrypt_basic(&msg[..]).unwrap();
+        let ct = enc.encrypt_basic(&msg[..], Ephemeral::generate()).unwrap();
         let pt = dec.decrypt_basic(ct);
         assert_eq!(&pt, &[0]);
 
         // All-ones message is returned as-is
         let msg = [1u8; 256];
-        let ct = enc.encrypt_basic(&msg[..]).unwrap();
+        let ct = enc.encrypt_basic(&msg[..], Ephemeral::generate()).unwrap();
         let pt = dec.decrypt_basic(ct);
         assert_eq!(&pt[..], &msg[..]);
     }
@@ -266,6 +346,56 @@ mod tests {
         assert_eq!(&pt[..], &msg[..]);
     }
 
+    #[test]
+    fn block_format() {
+        let (priv_key, pub_key) = KeyPairGenerator::generate();
+        assert_eq!(KeyPairGenerator::public_key(&priv_key).0[..], pub_key.0[..]);
+
+        let msg = b"hello world";
+        let ct = encrypt(&pub_key, msg).unwrap();
+        assert_eq!(ct.len(), 514);
+        assert_eq!(ct[0], 0);
+        assert_eq!(ct[257], 0);
+        assert_eq!(decrypt(&priv_key, &ct).unwrap(), msg);
+
+        // Wrong lengths
+        assert_eq!(decrypt(&priv_key, &ct[1..]), Err(Error::InvalidCiphertext));
+        assert_eq!(
+            decrypt(&priv_key, &[&ct[..], &[0][..]].concat()),
+            Err(Error::InvalidCiphertext)
+        );
+
+        // Tampered ciphertext
+        let mut tampered = ct.clone();
+        tampered[513] ^= 0x01;
+        assert_eq!(decrypt(&priv_key, &tampered), Err(Error::InvalidCiphertext));
+
+        // Decrypting with the wrong key
+        let (other_priv, _) = KeyPairGenerator::generate();
+        assert_eq!(decrypt(&other_priv, &ct), Err(Error::InvalidCiphertext));
+
+        // γ and δ out of range
+        let p = rectify(&ELGAMAL_P, 256);
+        let mut zero_gamma = ct.clone();
+        zero_gamma[1..257].copy_from_slice(&[0; 256]);
+        assert_eq!(
+            decrypt(&priv_key, &zero_gamma),
+            Err(Error::InvalidCiphertext)
+        );
+        let mut large_gamma = ct.clone();
+        large_gamma[1..257].copy_from_slice(&p);
+        assert_eq!(
+            decrypt(&priv_key, &large_gamma),
+            Err(Error::InvalidCiphertext)
+        );
+        let mut large_delta = ct;
+        large_delta[258..514].copy_from_slice(&p);
+        assert_eq!(
+            decrypt(&priv_key, &large_delta),
+            Err(Error::InvalidCiphertext)
+        );
+    }
+
     /// From `core/java/test/junit/net/i2p/crypto/ElGamalTest.java` in Java I2P.
     #[test]
     fn test_vectors() {
@@ -370,18 +500,19 @@ mod tests {
 
         let enc = {
             let mut data = [0u8; 256];
-            data.copy_from_slice(&I2P_BASE64.decode(pub_key.as_bytes()).unwrap());
+            data.copy_from_slice(&b64_decode(pub_key).unwrap());
             Encryptor::from(&PublicKey(data))
         };
-        let dec = {
+        let priv_key = {
             let mut data = [0u8; 256];
-            data.copy_from_slice(&I2P_BASE64.decode(priv_key.as_bytes()).unwrap());
-            Decryptor::from(&PrivateKey(data))
+            data.copy_from_slice(&b64_decode(priv_key).unwrap());
+            PrivateKey(data)
         };
+        let dec = Decryptor::from(&priv_key);
 
         for tv in test_vectors {
             let msg = tv.msg.as_bytes();
-            let ct = I2P_BASE64.decode(tv.ct.as_bytes()).unwrap();
+            let ct = b64_decode(tv.ct).unwrap();
 
             // Check round-trip
             assert_eq!(
@@ -396,6 +527,40 @@ mod tests {
 
             // Check test vector
             assert_eq!(dec.decrypt(&ct, true).unwrap(), msg);
+            assert_eq!(decrypt(&priv_key, &ct).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn precomputed_ephemeral() {
+        let (priv_key, pub_key) = KeyPairGenerator::generate();
+        let msg = b"hello world";
+        let ct = encrypt_with(&pub_key, msg, Ephemeral::generate()).unwrap();
+        assert_eq!(ct.len(), 514);
+        assert_eq!(decrypt(&priv_key, &ct).unwrap(), msg);
+    }
+
+    #[cfg(all(test, feature = "nightly"))]
+    mod bench {
+        use test::{black_box, Bencher};
+
+        use super::super::{Encryptor, Ephemeral, KeyPairGenerator};
+
+        #[bench]
+        fn encrypt_cold(b: &mut Bencher) {
+            let (_, pub_key) = KeyPairGenerator::generate();
+            let enc = Encryptor::from(&pub_key);
+            b.iter(|| black_box(enc.encrypt(black_box(b"hello world"), true)));
+        }
+
+        #[bench]
+        fn encrypt_warm(b: &mut Bencher) {
+            let (_, pub_key) = KeyPairGenerator::generate();
+            let enc = Encryptor::from(&pub_key);
+            let ephemeral = Ephemeral::generate();
+            b.iter(|| {
+                black_box(enc.encrypt_with(black_box(b"hello world"), true, ephemeral.clone()))
+            });
         }
     }
 }
@@ -11,7 +11,7 @@ use nom::{
 
 use crate::constants;
 use crate::crypto::{
-    EncType, PrivateKey, PublicKey, SessionKey, SigType, Signature, SigningPrivateKey,
+    DecryptionKey, EncType, PublicKey, SessionKey, SigType, Signature, SigningPrivateKey,
     SigningPublicKey,
 };
 
@@ -25,6 +25,7 @@ pub fn sig_type(i: &[u8]) -> IResult<&[u8], SigType> {
         constants::RSA_SHA384_3072 => Some(SigType::Rsa3072Sha384),
         constants::RSA_SHA512_4096 => Some(SigType::Rsa4096Sha512),
         constants::ED25519 => Some(SigType::Ed25519),
+        constants::REDDSA_SHA512_ED25519 => Some(SigType::RedDsaSha512Ed25519),
         _ => None,
     })(i)
 }
@@ -39,6 +40,7 @@ pub fn gen_sig_type(
 pub fn enc_type(i: &[u8]) -> IResult<&[u8], EncType> {
     map_opt(be_u16, |enc_type| match enc_type {
         constants::ELGAMAL2048 => Some(EncType::ElGamal2048),
+        constants::X25519 => Some(EncType::X25519),
         _ => None,
     })(i)
 }
@@ -82,19 +84,21 @@ pub fn gen_public_key<'a>(
     gen_slice!(input, key.0)
 }
 
-// PrivateKey
+// DecryptionKey
 
-pub fn private_key(i: &[u8]) -> IResult<&[u8], PrivateKey> {
-    map(take(256usize), |k: &[u8]| {
-        PrivateKey::from_bytes(k.try_into().unwrap())
-    })(i)
+pub fn decryption_key(enc_type: EncType) -> impl Fn(&[u8]) -> IResult<&[u8], DecryptionKey> {
+    move |input: &[u8]| {
+        map_res(take(enc_type.privkey_len()), |key| {
+            DecryptionKey::from_bytes(enc_type, key)
+        })(input)
+    }
 }
 
-pub fn gen_private_key<'a>(
+pub fn gen_decryption_key<'a>(
     input: (&'a mut [u8], usize),
-    key: &PrivateKey,
+    key: &DecryptionKey,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    gen_slice!(input, key.0)
+    gen_slice!(input, key.as_bytes())
 }
 
 // SigningPublicKey
@@ -120,7 +124,7 @@ pub fn signing_private_key(
     sig_type: SigType,
 ) -> impl Fn(&[u8]) -> IResult<&[u8], SigningPrivateKey> {
     move |input: &[u8]| {
-        map_res(take(sig_type.pubkey_len()), |sig_key| {
+        map_res(take(sig_type.privkey_len()), |sig_key| {
             SigningPrivateKey::from_bytes(sig_type, sig_key)
         })(input)
     }
@@ -0,0 +1,570 @@
+//! ElGamal/AES+SessionTags, the end-to-end encryption layer for garlic messages.
+//!
+//! The first message to a destination is encrypted to its public key, and
+//! delivers a session key along with a set of single-use session tags. Later
+//! messages are prefixed with one of those tags instead, which the recipient
+//! looks up to find the session key without performing a public-key decryption.
+//!
+//! The first message is encrypted to the recipient's ElGamal key, with the session
+//! key and pre-IV padded to 222 bytes. Recipients with X25519 keys need
+//! ECIES-X25519-AEAD-Ratchet, which is not implemented, so they are rejected with
+//! [`Error::UnsupportedEncType`].
+//!
+//! Session tags are random values chosen by the sender, exactly as in Java I2P;
+//! they are not derived from the session key.
+//!
+//! [Specification](https://geti2p.net/spec/elgamal-aes)
+
+use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::{
+    aes::{pad_random, SessionCipher},
+    elgamal::{self, Ephemeral},
+    math::ct_eq,
+    pool::Precomputed,
+    DecryptionKey, EncType, EncryptionKey, Error, SessionKey, AES_BLOCK_SIZE,
+};
+
+pub const TAG_LEN: usize = 32;
+
+/// The most tags that can be delivered in a single message.
+pub const MAX_TAGS: usize = 200;
+
+/// How long a delivered tag can be used for.
+pub const TAG_LIFETIME: Duration = Duration::from_secs(12 * 60);
+
+const ELGAMAL_BLOCK_LEN: usize = 514;
+const ELGAMAL_PLAINTEXT_LEN: usize = 222;
+
+/// The session key and pre-IV.
+const SESSION_BLOCK_LEN: usize = 64;
+
+/// The length of the public-key encrypted block at the start of a new session
+/// message.
+fn new_session_block_len(enc_type: EncType) -> Result<usize, Error> {
+    match enc_type {
+        EncType::ElGamal2048 => Ok(ELGAMAL_BLOCK_LEN),
+        EncType::X25519 => Err(Error::UnsupportedEncType),
+    }
+}
+
+/// A single-use tag identifying an existing session.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionTag(pub [u8; TAG_LEN]);
+
+impl SessionTag {
+    pub fn random() -> Self {
+        let mut tag = [0; TAG_LEN];
+        OsRng.fill(&mut tag);
+        SessionTag(tag)
+    }
+}
+
+impl fmt::Debug for SessionTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionTag(")?;
+        for b in &self.0[..4] {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, "…)")
+    }
+}
+
+/// Generates `count` fresh tags to deliver to a peer.
+pub fn generate_tags(count: usize) -> Vec<SessionTag> {
+    (0..count).map(|_| SessionTag::random()).collect()
+}
+
+/// The AES IV for a message is the first 16 bytes of SHA-256 over either the
+/// session tag, or the pre-IV from the new session block.
+fn derive_iv(data: &[u8]) -> [u8; AES_BLOCK_SIZE] {
+    let mut iv = [0; AES_BLOCK_SIZE];
+    iv.copy_from_slice(&Sha256::digest(data)[..AES_BLOCK_SIZE]);
+    iv
+}
+
+/// The decrypted contents of an AES block.
+#[derive(Debug)]
+pub struct AesBlock {
+    /// Tags delivered for future messages.
+    pub tags: Vec<SessionTag>,
+    /// A replacement session key, which the delivered tags belong to.
+    pub new_key: Option<SessionKey>,
+    pub payload: Vec<u8>,
+}
+
+fn encrypt_aes_block(
+    key: &SessionKey,
+    iv: &[u8; AES_BLOCK_SIZE],
+    tags: &[SessionTag],
+    new_key: Option<&SessionKey>,
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if tags.len() > MAX_TAGS {
+        return Err(Error::InvalidMessage);
+    }
+
+    let mut buf = Vec::with_capacity(2 + tags.len() * TAG_LEN + 4 + 32 + 1 + 32 + payload.len());
+    buf.extend_from_slice(&(tags.len() as u16).to_be_bytes());
+    for tag in tags {
+        buf.extend_from_slice(&tag.0);
+    }
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&Sha256::digest(payload));
+    match new_key {
+        Some(new_key) => {
+            buf.push(1);
+            buf.extend_from_slice(&new_key.0);
+        }
+        None => buf.push(0),
+    }
+    buf.extend_from_slice(payload);
+    pad_random(&mut buf);
+
+    SessionCipher::new(key, iv, iv).encrypt_blocks(&mut buf)?;
+    Ok(buf)
+}
+
+fn decrypt_aes_block(
+    key: &SessionKey,
+    iv: &[u8; AES_BLOCK_SIZE],
+    ct: &[u8],
+) -> Result<AesBlock, Error> {
+    let mut buf = ct.to_vec();
+    SessionCipher::new(key, iv, iv).decrypt_blocks(&mut buf)?;
+
+    let take = |buf: &mut &[u8], n: usize| {
+        if buf.len() < n {
+            return Err(Error::InvalidMessage);
+        }
+        let (head, tail) = buf.split_at(n);
+        *buf = tail;
+        Ok(head)
+    };
+    let mut rest = &buf[..];
+
+    let tag_count = take(&mut rest, 2)?;
+    let tag_count = u16::from_be_bytes([tag_count[0], tag_count[1]]) as usize;
+    if tag_count > MAX_TAGS {
+        return Err(Error::InvalidMessage);
+    }
+    let tags = take(&mut rest, tag_count * TAG_LEN)?
+        .chunks(TAG_LEN)
+        .map(|c| {
+            let mut tag = [0; TAG_LEN];
+            tag.copy_from_slice(c);
+            SessionTag(tag)
+        })
+        .collect();
+
+    let size = take(&mut rest, 4)?;
+    let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+    let hash = take(&mut rest, 32)?;
+    let new_key = match take(&mut rest, 1)?[0] {
+        0 => None,
+        1 => {
+            let mut key = [0; 32];
+            key.copy_from_slice(take(&mut rest, 32)?);
+            Some(SessionKey(key))
+        }
+        _ => return Err(Error::InvalidMessage),
+    };
+    let payload = take(&mut rest, size)?;
+
+    // Everything after the payload is padding
+    if rest.len() >= AES_BLOCK_SIZE || !ct_eq(&Sha256::digest(payload), hash) {
+        return Err(Error::InvalidMessage);
+    }
+
+    Ok(AesBlock {
+        tags,
+        new_key,
+        payload: payload.to_vec(),
+    })
+}
+
+/// Encrypts a message for a new session: a block carrying `session_key`
+/// encrypted to `pub_key`, followed by an AES block delivering `tags`.
+///
+/// The ElGamal ephemeral is taken from `ephemerals`. X25519 keys are rejected.
+pub fn encrypt_new_session(
+    pub_key: &EncryptionKey,
+    session_key: &SessionKey,
+    tags: &[SessionTag],
+    payload: &[u8],
+    ephemerals: &Precomputed<Ephemeral>,
+) -> Result<Vec<u8>, Error> {
+    let mut pre_iv = [0; 32];
+    OsRng.fill(&mut pre_iv);
+
+    let mut block = [0; ELGAMAL_PLAINTEXT_LEN];
+    block[..32].copy_from_slice(&session_key.0);
+    block[32..SESSION_BLOCK_LEN].copy_from_slice(&pre_iv);
+
+    let mut msg = match pub_key {
+        EncryptionKey::ElGamal(pub_key) => {
+            OsRng.fill(&mut block[SESSION_BLOCK_LEN..]);
+            elgamal::encrypt_with(pub_key, &block, ephemerals.take())?
+        }
+        EncryptionKey::X25519(_) => return Err(Error::UnsupportedEncType),
+    };
+    msg.extend(encrypt_aes_block(
+        session_key,
+        &derive_iv(&pre_iv),
+        tags,
+        None,
+        payload,
+    )?);
+    Ok(msg)
+}
+
+/// Encrypts a message for an existing session, identified by a previously
+/// delivered `tag`.
+pub fn encrypt_existing_session(
+    tag: &SessionTag,
+    session_key: &SessionKey,
+    tags: &[SessionTag],
+    new_key: Option<&SessionKey>,
+    payload: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut msg = tag.0.to_vec();
+    msg.extend(encrypt_aes_block(
+        session_key,
+        &derive_iv(&tag.0),
+        tags,
+        new_key,
+        payload,
+    )?);
+    Ok(msg)
+}
+
+/// The tags that have been delivered to us, and the session keys they map to.
+#[derive(Default)]
+pub struct TagStore {
+    tags: HashMap<SessionTag, (SessionKey, Instant)>,
+}
+
+impl TagStore {
+    pub fn new() -> Self {
+        TagStore::default()
+    }
+
+    /// Stores `tags` for `key`, expiring [`TAG_LIFETIME`] after `now`.
+    pub fn add_tags(&mut self, key: &SessionKey, tags: &[SessionTag], now: Instant) {
+        let expiry = now + TAG_LIFETIME;
+        for tag in tags {
+            self.tags.insert(*tag, (key.clone(), expiry));
+        }
+    }
+
+    /// Removes `tag` from the store, returning its session key if it has not
+    /// expired. Each tag can only be consumed once.
+    pub fn consume(&mut self, tag: &SessionTag, now: Instant) -> Option<SessionKey> {
+        match self.tags.remove(tag) {
+            Some((key, expiry)) if now < expiry => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Removes all expired tags, returning the number removed.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.tags.len();
+        self.tags.retain(|_, (_, expiry)| now < *expiry);
+        before - self.tags.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
+/// Decrypts incoming garlic messages, tracking the tags delivered by them.
+pub struct GarlicDecryptor {
+    priv_key: DecryptionKey,
+    tags: TagStore,
+}
+
+impl GarlicDecryptor {
+    pub fn new(priv_key: &DecryptionKey) -> Self {
+        GarlicDecryptor {
+            priv_key: priv_key.clone(),
+            tags: TagStore::new(),
+        }
+    }
+
+    pub fn tags(&self) -> &TagStore {
+        &self.tags
+    }
+
+    pub fn tags_mut(&mut self) -> &mut TagStore {
+        &mut self.tags
+    }
+
+    /// Decrypts a message, using a known session tag if it has one and falling
+    /// back to public-key decryption otherwise. Any tags delivered by the message are stored.
+    pub fn decrypt(&mut self, msg: &[u8], now: Instant) -> Result<Vec<u8>, Error> {
+        if msg.len() < TAG_LEN + AES_BLOCK_SIZE {
+            return Err(Error::InvalidCiphertext);
+        }
+
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(&msg[..TAG_LEN]);
+        let tag = SessionTag(tag);
+
+        let (key, block) = if let Some(key) = self.tags.consume(&tag, now) {
+            let block = decrypt_aes_block(&key, &derive_iv(&tag.0), &msg[TAG_LEN..])?;
+            (key, block)
+        } else {
+            let block_len = new_session_block_len(self.priv_key.enc_type())?;
+            if msg.len() < block_len + AES_BLOCK_SIZE {
+                return Err(Error::InvalidCiphertext);
+            }
+            let session_block = self.priv_key.decrypt_session(&msg[..block_len])?;
+            if session_block.len() < SESSION_BLOCK_LEN {
+                return Err(Error::InvalidMessage);
+            }
+            let mut key = [0; 32];
+            key.copy_from_slice(&session_block[..32]);
+            let key = SessionKey(key);
+            let block = decrypt_aes_block(
+                &key,
+                &derive_iv(&session_block[32..SESSION_BLOCK_LEN]),
+                &msg[block_len..],
+            )?;
+            (key, block)
+        };
+
+        // Delivered tags belong to the replacement key, if there is one
+        self.tags
+            .add_tags(block.new_key.as_ref().unwrap_or(&key), &block.tags, now);
+
+        Ok(block.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::OsRng;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::crypto::pool::{PoolStats, Pools};
+    use crate::data::encoding::hex_decode;
+
+    #[test]
+    fn aes_block_round_trip() {
+        let key = SessionKey::generate(&mut OsRng);
+        let new_key = SessionKey::generate(&mut OsRng);
+        let iv = [7; AES_BLOCK_SIZE];
+        let tags = generate_tags(3);
+
+        let ct = encrypt_aes_block(&key, &iv, &tags, Some(&new_key), b"payload").unwrap();
+        assert_eq!(ct.len() % AES_BLOCK_SIZE, 0);
+
+        let block = decrypt_aes_block(&key, &iv, &ct).unwrap();
+        assert_eq!(block.tags, tags);
+        assert_eq!(block.new_key, Some(new_key));
+        assert_eq!(block.payload, b"payload");
+
+        // Too many tags
+        assert_eq!(
+            encrypt_aes_block(&key, &iv, &generate_tags(MAX_TAGS + 1), None, b"").unwrap_err(),
+            Error::InvalidMessage
+        );
+    }
+
+    #[test]
+    fn aes_block_payload_hash_mismatch() {
+        let key = SessionKey::generate(&mut OsRng);
+        let iv = [0; AES_BLOCK_SIZE];
+
+        // A well-formed block whose payload hash has been corrupted
+        let mut pt = vec![0, 0, 0, 0, 0, 1];
+        pt.extend_from_slice(&Sha256::digest(b"x"));
+        pt.push(0);
+        pt.push(b'x');
+        pt[37] ^= 1;
+        pad_random(&mut pt);
+        SessionCipher::new(&key, &iv, &iv)
+            .encrypt_blocks(&mut pt)
+            .unwrap();
+
+        assert_eq!(
+            decrypt_aes_block(&key, &iv, &pt).unwrap_err(),
+            Error::InvalidMessage
+        );
+    }
+
+    #[test]
+    fn existing_session_vectors() {
+        // Generated independently from the spec: tag 0x11.., key 0x22..,
+        // delivering tags 0x33.. and 0x44.., payload "hello garlic", zero padding.
+        let key = SessionKey([0x22; 32]);
+        let tag = SessionTag([0x11; 32]);
+        let tags = [SessionTag([0x33; 32]), SessionTag([0x44; 32])];
+
+        let vectors: [(&str, Option<SessionKey>); 2] = [
+            (
+                "1111111111111111111111111111111111111111111111111111111111111111\
+                 699142ed315b3c3062fc7d45d1111914d4e9d2793429600c1eb6bc22dbeade93\
+                 7781d630873d0688cc396b7b7819dffe09f5f463aefe0622763f82bf3055dbbe\
+                 4f4a9808b9066e5a4090859e46d74dc9987c8ac5a07d6d9eb4446dd3147c3f57\
+                 dcd96efbbdcd0d420a08787d208223ac44623127ea24455e173fe59a768c0b3f",
+                None,
+            ),
+            (
+                "1111111111111111111111111111111111111111111111111111111111111111\
+                 699142ed315b3c3062fc7d45d1111914d4e9d2793429600c1eb6bc22dbeade93\
+                 7781d630873d0688cc396b7b7819dffe09f5f463aefe0622763f82bf3055dbbe\
+                 4f4a9808b9066e5a4090859e46d74dc9987c8ac5a07d6d9eb4446dd3147c3f57\
+                 2c839eeb2838a94e9518b44417d6ab419c63a8b919c9c8e23b88d89c5c065317\
+                 0527faf42bbe8220dd87c30e16d3d8e1aed14885484b758c9dbfcf713a5ea34a",
+                Some(SessionKey([0x55; 32])),
+            ),
+        ];
+
+        for (msg, new_key) in vectors.iter() {
+            let msg = hex_decode(msg).unwrap();
+            let mut decryptor = GarlicDecryptor::new(&DecryptionKey::new());
+            let now = Instant::now();
+            decryptor.tags_mut().add_tags(&key, &[tag], now);
+
+            assert_eq!(decryptor.decrypt(&msg, now).unwrap(), b"hello garlic");
+
+            // The used tag is gone, and the delivered tags map to the right key
+            assert_eq!(decryptor.tags().len(), 2);
+            let expected = new_key.as_ref().unwrap_or(&key);
+            for t in &tags {
+                assert_eq!(
+                    decryptor.tags_mut().consume(t, now).as_ref(),
+                    Some(expected)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn second_message_uses_fast_path() {
+        let pools = Pools::new();
+        let priv_key = DecryptionKey::new();
+        let pub_key = priv_key.public_key();
+        let mut decryptor = GarlicDecryptor::new(&priv_key);
+        let now = Instant::now();
+
+        let key = SessionKey::generate(&mut OsRng);
+        let tags = generate_tags(4);
+        let msg = encrypt_new_session(&pub_key, &key, &tags, b"first", &pools.elgamal).unwrap();
+        assert_eq!(decryptor.decrypt(&msg, now).unwrap(), b"first");
+        assert_eq!(decryptor.tags().len(), 4);
+
+        // Swap in a decryptor for an unrelated key, so that the second message
+        // can only be decrypted via the tag store.
+        let mut fast = GarlicDecryptor::new(&DecryptionKey::new());
+        fast.tags = decryptor.tags;
+
+        let msg = encrypt_existing_session(&tags[0], &key, &[], None, b"second").unwrap();
+        assert_eq!(fast.decrypt(&msg, now).unwrap(), b"second");
+        assert_eq!(fast.tags().len(), 3);
+
+        // Tags are single-use
+        assert!(fast.decrypt(&msg, now).is_err());
+
+        // Existing sessions don't depend on the key type
+        let mut x25519 = GarlicDecryptor::new(&DecryptionKey::with_type(EncType::X25519));
+        x25519.tags = fast.tags;
+        let msg = encrypt_existing_session(&tags[1], &key, &[], None, b"third").unwrap();
+        assert_eq!(x25519.decrypt(&msg, now).unwrap(), b"third");
+    }
+
+    #[test]
+    fn new_session_round_trip() {
+        let pools = Pools::new();
+        let now = Instant::now();
+        let priv_key = DecryptionKey::new();
+        let pub_key = priv_key.public_key();
+        let key = SessionKey::generate(&mut OsRng);
+        let tags = generate_tags(2);
+
+        let msg = encrypt_new_session(&pub_key, &key, &tags, b"payload", &pools.elgamal).unwrap();
+        assert_eq!(new_session_block_len(EncType::ElGamal2048), Ok(514));
+        assert_eq!((msg.len() - 514) % AES_BLOCK_SIZE, 0);
+
+        let mut decryptor = GarlicDecryptor::new(&priv_key);
+        assert_eq!(decryptor.decrypt(&msg, now).unwrap(), b"payload");
+        for t in &tags {
+            assert_eq!(decryptor.tags_mut().consume(t, now), Some(key.clone()));
+        }
+
+        // Only the intended recipient can decrypt it
+        let mut other = GarlicDecryptor::new(&DecryptionKey::new());
+        assert!(other.decrypt(&msg, now).is_err());
+
+        // The ephemeral came from the pool, which was empty
+        assert_eq!(pools.elgamal.stats(), PoolStats { hits: 0, misses: 1 });
+
+        // Precomputed ephemerals are used once the pool has been filled
+        let priv_key = DecryptionKey::new();
+        let key = SessionKey::generate(&mut OsRng);
+        let ephemerals = Precomputed::new("test", 2, 0, Ephemeral::generate);
+        ephemerals.fill();
+        let msg = encrypt_new_session(&priv_key.public_key(), &key, &[], b"payload", &ephemerals)
+            .unwrap();
+        assert_eq!(ephemerals.stats(), PoolStats { hits: 1, misses: 0 });
+        let mut decryptor = GarlicDecryptor::new(&priv_key);
+        assert_eq!(decryptor.decrypt(&msg, now).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn x25519_new_sessions_unsupported() {
+        let pools = Pools::new();
+        let priv_key = DecryptionKey::with_type(EncType::X25519);
+        let pub_key = priv_key.public_key();
+        let key = SessionKey::generate(&mut OsRng);
+        assert_eq!(
+            encrypt_new_session(&pub_key, &key, &[], b"payload", &pools.elgamal),
+            Err(Error::UnsupportedEncType)
+        );
+        assert_eq!(pools.elgamal.stats(), PoolStats { hits: 0, misses: 0 });
+
+        // An ElGamal new session message can't be read with an X25519 key
+        let msg = encrypt_new_session(
+            &DecryptionKey::new().public_key(),
+            &key,
+            &[],
+            b"payload",
+            &pools.elgamal,
+        )
+        .unwrap();
+        let mut decryptor = GarlicDecryptor::new(&priv_key);
+        assert_eq!(
+            decryptor.decrypt(&msg, Instant::now()),
+            Err(Error::UnsupportedEncType)
+        );
+    }
+
+    #[test]
+    fn tags_expire() {
+        let key = SessionKey::generate(&mut OsRng);
+        let tags = generate_tags(2);
+        let now = Instant::now();
+
+        let mut store = TagStore::new();
+        store.add_tags(&key, &tags, now);
+        assert_eq!(store.len(), 2);
+
+        let later = now + TAG_LIFETIME;
+        assert_eq!(store.consume(&tags[0], later), None);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.expire(now), 0);
+        assert_eq!(store.expire(later), 1);
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,337 @@
+//! Hashcash version 1 stamps, as carried by HashCash certificates.
+//!
+//! A stamp has the form `ver:bits:date:resource:ext:rand:counter`, and is valid
+//! if the SHA-1 hash of the whole stamp starts with at least `bits` zero bits.
+//! Checking a stamp costs a single hash, while minting one takes around 2^bits
+//! attempts.
+//!
+//! [Hashcash specification](http://hashcash.org/docs/hashcash.html#stamp_format__version_1_)
+
+use data_encoding::BASE64;
+use rand::{rngs::OsRng, Rng};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// The largest number of collision bits that a SHA-1 hash can have.
+pub const MAX_BITS: u32 = 160;
+
+/// How often the miner checks whether it has been cancelled.
+const CANCEL_CHECK_INTERVAL: u64 = 1 << 12;
+
+/// Reasons that a hashcash stamp can be rejected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashcashError {
+    /// The certificate is not a HashCash certificate.
+    NotHashCash,
+    /// The stamp is not valid UTF-8, or does not have the expected fields.
+    Malformed,
+    UnsupportedVersion(String),
+    /// The stamp claims fewer collision bits than were required.
+    TooFewBits {
+        claimed: u32,
+        required: u32,
+    },
+    /// The SHA-1 hash of the stamp has fewer leading zero bits than it claims.
+    InvalidCollision {
+        claimed: u32,
+        actual: u32,
+    },
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for HashcashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashcashError::NotHashCash => "Not a HashCash certificate".fmt(f),
+            HashcashError::Malformed => "Malformed hashcash stamp".fmt(f),
+            HashcashError::UnsupportedVersion(ver) => {
+                format!("Unsupported hashcash stamp version {}", ver).fmt(f)
+            }
+            HashcashError::TooFewBits { claimed, required } => format!(
+                "Hashcash stamp claims {} bits, but {} are required",
+                claimed, required
+            )
+            .fmt(f),
+            HashcashError::InvalidCollision { claimed, actual } => format!(
+                "Hashcash stamp claims {} bits, but only has {}",
+                claimed, actual
+            )
+            .fmt(f),
+        }
+    }
+}
+
+/// Reasons that minting a stamp can stop without finding one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MintError {
+    /// More collision bits were requested than SHA-1 can provide.
+    TooManyBits(u32),
+    /// The [`CancelHandle`] was triggered.
+    Cancelled,
+    /// No stamp was found within the iteration limit.
+    Exhausted(u64),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for MintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MintError::TooManyBits(bits) => {
+                format!("Cannot mint a hashcash stamp with {} bits", bits).fmt(f)
+            }
+            MintError::Cancelled => "Hashcash minting was cancelled".fmt(f),
+            MintError::Exhausted(n) => {
+                format!("No hashcash stamp found in {} iterations", n).fmt(f)
+            }
+        }
+    }
+}
+
+/// A parsed version 1 hashcash stamp.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stamp<'a> {
+    raw: &'a str,
+    pub bits: u32,
+    pub date: &'a str,
+    pub resource: &'a str,
+    pub ext: &'a str,
+    pub rand: &'a str,
+    pub counter: &'a str,
+}
+
+impl<'a> Stamp<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, HashcashError> {
+        let raw = std::str::from_utf8(data).map_err(|_| HashcashError::Malformed)?;
+        let fields: Vec<_> = raw.split(':').collect();
+        if fields[0] != "1" {
+            return Err(HashcashError::UnsupportedVersion(fields[0].to_owned()));
+        }
+        if fields.len() != 7 {
+            return Err(HashcashError::Malformed);
+        }
+
+        let bits = fields[1]
+            .parse::<u32>()
+            .ok()
+            .filter(|bits| *bits <= MAX_BITS)
+            .ok_or(HashcashError::Malformed)?;
+
+        // YYMMDD[hhmm[ss]]
+        let date = fields[2];
+        if ![6, 10, 12].contains(&date.len()) || !date.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(HashcashError::Malformed);
+        }
+
+        if fields[3].is_empty() || fields[5].is_empty() || fields[6].is_empty() {
+            return Err(HashcashError::Malformed);
+        }
+
+        Ok(Stamp {
+            raw,
+            bits,
+            date,
+            resource: fields[3],
+            ext: fields[4],
+            rand: fields[5],
+            counter: fields[6],
+        })
+    }
+
+    /// Returns the number of leading zero bits in the SHA-1 hash of the stamp.
+    pub fn value(&self) -> u32 {
+        leading_zero_bits(&Sha1::digest(self.raw.as_bytes()))
+    }
+
+    /// Checks that the stamp claims at least `min_bits` collision bits, and that
+    /// its hash has as many as it claims.
+    pub fn check(&self, min_bits: u32) -> Result<(), HashcashError> {
+        if self.bits < min_bits {
+            return Err(HashcashError::TooFewBits {
+                claimed: self.bits,
+                required: min_bits,
+            });
+        }
+        let actual = self.value();
+        if actual < self.bits {
+            return Err(HashcashError::InvalidCollision {
+                claimed: self.bits,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for b in hash {
+        bits += b.leading_zeros();
+        if *b != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// Allows a running [`mint`] to be stopped from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        CancelHandle::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Mints a stamp for `resource` with at least `bits` collision bits, trying at
+/// most `max_iterations` counter values.
+///
+/// `date` is in the stamp format `YYMMDD[hhmm[ss]]`, and is not validated.
+pub fn mint(
+    resource: &str,
+    bits: u32,
+    date: &str,
+    max_iterations: u64,
+    cancel: &CancelHandle,
+) -> Result<String, MintError> {
+    if bits > MAX_BITS {
+        return Err(MintError::TooManyBits(bits));
+    }
+
+    let rand: [u8; 12] = OsRng.gen();
+    let prefix = format!(
+        "1:{}:{}:{}::{}:",
+        bits,
+        date,
+        resource,
+        BASE64.encode(&rand)
+    );
+
+    for counter in 0..max_iterations {
+        if counter % CANCEL_CHECK_INTERVAL == 0 && cancel.is_cancelled() {
+            return Err(MintError::Cancelled);
+        }
+        let stamp = format!("{}{:x}", prefix, counter);
+        if leading_zero_bits(&Sha1::digest(stamp.as_bytes())) >= bits {
+            return Ok(stamp);
+        }
+    }
+    Err(MintError::Exhausted(max_iterations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leading_zero_bits, mint, CancelHandle, HashcashError, MintError, Stamp};
+
+    const STAMP_10: &[u8] = b"1:10:200101:example.i2p::aXJlLXRlc3Q=:9";
+    const STAMP_16: &[u8] = b"1:16:200101:routeridentity::aXJlLXRlc3Q=:95b5";
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x40]), 9);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x01]), 23);
+        assert_eq!(leading_zero_bits(&[0x00; 20]), 160);
+    }
+
+    #[test]
+    fn parse() {
+        let stamp = Stamp::parse(STAMP_10).unwrap();
+        assert_eq!(stamp.bits, 10);
+        assert_eq!(stamp.date, "200101");
+        assert_eq!(stamp.resource, "example.i2p");
+        assert_eq!(stamp.ext, "");
+        assert_eq!(stamp.rand, "aXJlLXRlc3Q=");
+        assert_eq!(stamp.counter, "9");
+
+        assert_eq!(
+            Stamp::parse(b"0:10:200101:example.i2p:aXJlLXRlc3Q=:9"),
+            Err(HashcashError::UnsupportedVersion("0".to_owned()))
+        );
+        for &stamp in &[
+            &b"1:10:200101:example.i2p:aXJlLXRlc3Q=:9"[..],
+            b"1:10:200101:example.i2p::aXJlLXRlc3Q=:9:",
+            b"1:ten:200101:example.i2p::aXJlLXRlc3Q=:9",
+            b"1:161:200101:example.i2p::aXJlLXRlc3Q=:9",
+            b"1:10:2001:example.i2p::aXJlLXRlc3Q=:9",
+            b"1:10:20010a:example.i2p::aXJlLXRlc3Q=:9",
+            b"1:10:200101:::aXJlLXRlc3Q=:9",
+            b"1:10:200101:example.i2p::aXJlLXRlc3Q=:",
+            b"1:10:200101:example.i2p::\xff:9",
+        ] {
+            assert_eq!(Stamp::parse(stamp), Err(HashcashError::Malformed));
+        }
+    }
+
+    #[test]
+    fn known_stamps() {
+        // SHA-1 is 0019d5a2...
+        let stamp = Stamp::parse(STAMP_10).unwrap();
+        assert_eq!(stamp.value(), 11);
+        assert_eq!(stamp.check(0), Ok(()));
+        assert_eq!(stamp.check(10), Ok(()));
+        assert_eq!(
+            stamp.check(16),
+            Err(HashcashError::TooFewBits {
+                claimed: 10,
+                required: 16
+            })
+        );
+
+        // SHA-1 is 00008604...
+        let stamp = Stamp::parse(STAMP_16).unwrap();
+        assert_eq!(stamp.value(), 16);
+        assert_eq!(stamp.check(16), Ok(()));
+
+        // Claiming more bits than the hash has (SHA-1 is 16bb0861...)
+        let stamp = Stamp::parse(b"1:16:200101:example.i2p::aXJlLXRlc3Q=:9").unwrap();
+        assert_eq!(
+            stamp.check(10),
+            Err(HashcashError::InvalidCollision {
+                claimed: 16,
+                actual: 3
+            })
+        );
+    }
+
+    #[test]
+    fn mint_and_check() {
+        let stamp = mint("example.i2p", 12, "200101", 1 << 20, &CancelHandle::new()).unwrap();
+        let stamp = Stamp::parse(stamp.as_bytes()).unwrap();
+        assert_eq!(stamp.resource, "example.i2p");
+        assert_eq!(stamp.check(12), Ok(()));
+    }
+
+    #[test]
+    fn mint_limits() {
+        let cancel = CancelHandle::new();
+        assert_eq!(
+            mint("example.i2p", 161, "200101", 1, &cancel),
+            Err(MintError::TooManyBits(161))
+        );
+        // Finding 160 bits would take far longer than the iteration limit
+        assert_eq!(
+            mint("example.i2p", 160, "200101", 100, &cancel),
+            Err(MintError::Exhausted(100))
+        );
+
+        // Cancelling any clone of the handle stops the miner
+        cancel.clone().cancel();
+        assert_eq!(
+            mint("example.i2p", 160, "200101", u64::max_value(), &cancel),
+            Err(MintError::Cancelled)
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! HKDF-SHA256 as specified in RFC 5869, with the salt and info conventions used
+//! by the Noise-based protocols.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub const PRK_LEN: usize = 32;
+
+/// HKDF-Extract: derives a pseudorandom key from `ikm`, keyed by `salt`.
+pub fn extract(salt: &[u8], ikm: &[u8]) -> [u8; PRK_LEN] {
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), ikm);
+    let mut out = [0; PRK_LEN];
+    out.copy_from_slice(&prk);
+    out
+}
+
+/// HKDF-Expand: fills `okm` with key material derived from `prk` and `info`.
+///
+/// Panics if `okm` is longer than 255 * 32 bytes.
+pub fn expand(prk: &[u8; PRK_LEN], info: &[u8], okm: &mut [u8]) {
+    Hkdf::<Sha256>::from_prk(prk)
+        .expect("PRK is the correct length")
+        .expand(info, okm)
+        .expect("okm is a valid HKDF output length");
+}
+
+/// HKDF-Extract followed by HKDF-Expand.
+pub fn derive(salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) {
+    expand(&extract(salt, ikm), info, okm)
+}
+
+/// The two-output HKDF from the Noise specification, which is HKDF with the
+/// chaining key as salt and empty info.
+pub fn noise_hkdf2(ck: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0; 64];
+    derive(ck, ikm, &[], &mut okm);
+    (*array_ref![okm, 0, 32], *array_ref![okm, 32, 32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive, expand, extract, noise_hkdf2};
+    use crate::data::encoding::hex_decode;
+
+    #[test]
+    fn rfc5869_vectors() {
+        struct Vector {
+            ikm: Vec<u8>,
+            salt: Vec<u8>,
+            info: Vec<u8>,
+            prk: &'static str,
+            okm: &'static str,
+        }
+
+        let vectors = vec![
+            // Test Case 1
+            Vector {
+                ikm: vec![0x0b; 22],
+                salt: (0x00..=0x0c).collect(),
+                info: (0xf0..=0xf9).collect(),
+                prk: "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5",
+                okm: "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+                      34007208d5b887185865",
+            },
+            // Test Case 3
+            Vector {
+                ikm: vec![0x0b; 22],
+                salt: vec![],
+                info: vec![],
+                prk: "19ef24a32c717b167f33a91d6f648bdf96596776afdb6377ac434c1c293ccb04",
+                okm: "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d\
+                      9d201395faa4b61a96c8",
+            },
+        ];
+
+        for v in vectors {
+            let prk = extract(&v.salt, &v.ikm);
+            assert_eq!(&prk[..], &hex_decode(v.prk).unwrap()[..]);
+
+            let expected = hex_decode(v.okm).unwrap();
+            let mut okm = vec![0; expected.len()];
+            expand(&prk, &v.info, &mut okm);
+            assert_eq!(okm, expected);
+
+            let mut okm = vec![0; expected.len()];
+            derive(&v.salt, &v.ikm, &v.info, &mut okm);
+            assert_eq!(okm, expected);
+        }
+    }
+
+    #[test]
+    fn noise_hkdf2_outputs() {
+        let ck = [7; 32];
+        let ikm = b"input key material";
+
+        let mut okm = [0; 64];
+        derive(&ck, ikm, &[], &mut okm);
+        let (k1, k2) = noise_hkdf2(&ck, ikm);
+        assert_eq!(&k1[..], &okm[..32]);
+        assert_eq!(&k2[..], &okm[32..]);
+    }
+}
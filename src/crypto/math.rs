@@ -25,12 +25,29 @@ pub fn rectify(bi: &BigUint, len: usize) -> Vec<u8> {
     }
 }
 
+/// Compares two byte slices in time that depends only on their lengths.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use num_bigint::BigUint;
     use num_traits::{One, Zero};
 
-    use super::rectify;
+    use super::{ct_eq, rectify};
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(&[], &[]));
+        assert!(ct_eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!ct_eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!ct_eq(&[0x80, 2, 3], &[0, 2, 3]));
+        assert!(!ct_eq(&[1, 2, 3], &[1, 2]));
+    }
 
     #[test]
     fn rectify_zero() {
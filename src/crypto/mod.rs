@@ -1,9 +1,8 @@
 //! Cryptographic types and operations.
 
-use aes::cipher::generic_array::{ArrayLength, GenericArray as AesGenericArray};
-use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
+use curve25519_dalek::scalar::Scalar;
 use nom::Err;
-use rand::Rng;
+use ::rand::Rng;
 use ring::signature::{
     UnparsedPublicKey, RSA_PKCS1_2048_8192_SHA256_RAW, RSA_PKCS1_3072_8192_SHA384_RAW,
     RSA_PKCS1_4096_8192_SHA512_RAW,
@@ -21,18 +20,37 @@ use signatory::{
 };
 use signatory_dalek::{Ed25519Signer, Ed25519Verifier};
 use signatory_ring::ecdsa::{p256, p384};
-use std::{fmt, slice};
+use std::fmt;
+use zeroize::{Zeroize, Zeroizing};
 
+use self::math::ct_eq;
 use crate::constants;
 use crate::util::fmt_colon_delimited_hex;
 
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) mod frame;
 
+pub(crate) mod aes;
+pub(crate) mod blinding;
+pub(crate) mod chachapoly;
 pub(crate) mod dh;
 mod dsa;
+pub(crate) mod ecies;
 pub(crate) mod elgamal;
+pub(crate) mod garlic;
+pub mod hashcash;
+pub(crate) mod hkdf;
 pub(crate) mod math;
+pub(crate) mod noise;
+mod p521;
+pub(crate) mod pool;
+pub mod rand;
+mod selftest;
+pub(crate) mod siphash;
+pub(crate) mod x509;
+
+pub(crate) use self::aes::Aes256;
+pub use self::selftest::{self_test, Primitive, SelfTestError};
 
 pub(crate) const AES_BLOCK_SIZE: usize = 16;
 
@@ -46,6 +64,7 @@ pub enum Error {
     NoSignature,
     SigningFailed,
     TypeMismatch,
+    UnsupportedEncType,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -59,12 +78,13 @@ impl fmt::Display for Error {
             Error::NoSignature => "No signature".fmt(f),
             Error::SigningFailed => "Failed to create a signature".fmt(f),
             Error::TypeMismatch => "Signature type doesn't match key type".fmt(f),
+            Error::UnsupportedEncType => "Unsupported encryption type".fmt(f),
         }
     }
 }
 
 /// Various signature algorithms present on the network.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SigType {
     DsaSha1,
     EcdsaSha256P256,
@@ -74,6 +94,7 @@ pub enum SigType {
     Rsa3072Sha384,
     Rsa4096Sha512,
     Ed25519,
+    RedDsaSha512Ed25519,
 }
 
 impl SigType {
@@ -95,6 +116,7 @@ impl SigType {
             SigType::Rsa3072Sha384 => constants::RSA_SHA384_3072,
             SigType::Rsa4096Sha512 => constants::RSA_SHA512_4096,
             SigType::Ed25519 => constants::ED25519,
+            SigType::RedDsaSha512Ed25519 => constants::REDDSA_SHA512_ED25519,
         }
     }
 
@@ -107,7 +129,7 @@ impl SigType {
             SigType::Rsa2048Sha256 => 256,
             SigType::Rsa3072Sha384 => 384,
             SigType::Rsa4096Sha512 => 512,
-            SigType::Ed25519 => ed25519::PUBLIC_KEY_SIZE as u32,
+            SigType::Ed25519 | SigType::RedDsaSha512Ed25519 => ed25519::PUBLIC_KEY_SIZE as u32,
         }
     }
 
@@ -120,7 +142,7 @@ impl SigType {
             SigType::Rsa2048Sha256 => 512,
             SigType::Rsa3072Sha384 => 768,
             SigType::Rsa4096Sha512 => 1024,
-            SigType::Ed25519 => ed25519::SEED_SIZE as u32,
+            SigType::Ed25519 | SigType::RedDsaSha512Ed25519 => ed25519::SEED_SIZE as u32,
         }
     }
 
@@ -133,14 +155,14 @@ impl SigType {
             SigType::Rsa2048Sha256 => 256,
             SigType::Rsa3072Sha384 => 384,
             SigType::Rsa4096Sha512 => 512,
-            SigType::Ed25519 => ed25519::SIGNATURE_SIZE as u32,
+            SigType::Ed25519 | SigType::RedDsaSha512Ed25519 => ed25519::SIGNATURE_SIZE as u32,
         }
     }
 
     // Returns a number between 0 and 128
     pub fn pad_len(self, enc_type: EncType) -> usize {
         match enc_type {
-            EncType::ElGamal2048 => {
+            EncType::ElGamal2048 | EncType::X25519 => {
                 constants::KEYCERT_SIGKEY_BYTES.saturating_sub(self.pubkey_len() as usize)
             }
         }
@@ -148,7 +170,7 @@ impl SigType {
 
     pub fn extra_data_len(self, enc_type: EncType) -> usize {
         match enc_type {
-            EncType::ElGamal2048 => {
+            EncType::ElGamal2048 | EncType::X25519 => {
                 (self.pubkey_len() as usize).saturating_sub(constants::KEYCERT_SIGKEY_BYTES)
             }
         }
@@ -156,9 +178,13 @@ impl SigType {
 }
 
 /// Various encryption algorithms present on the network.
-#[derive(Clone, Copy, Debug, PartialEq)]
+///
+/// Keys shorter than 256 bytes are stored at the start of the public key field,
+/// followed by padding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EncType {
     ElGamal2048,
+    X25519,
 }
 
 impl EncType {
@@ -173,12 +199,27 @@ impl EncType {
     pub fn code(self) -> u16 {
         match self {
             EncType::ElGamal2048 => constants::ELGAMAL2048,
+            EncType::X25519 => constants::X25519,
+        }
+    }
+
+    pub fn pubkey_len(self) -> u32 {
+        match self {
+            EncType::ElGamal2048 => 256,
+            EncType::X25519 => ecies::KEY_LEN as u32,
+        }
+    }
+
+    pub fn privkey_len(self) -> u32 {
+        match self {
+            EncType::ElGamal2048 => 256,
+            EncType::X25519 => ecies::KEY_LEN as u32,
         }
     }
 
     pub fn extra_data_len(self, _sig_type: SigType) -> usize {
         match self {
-            EncType::ElGamal2048 => 0,
+            EncType::ElGamal2048 | EncType::X25519 => 0,
         }
     }
 }
@@ -231,6 +272,10 @@ impl PrivateKey {
         elgamal::KeyPairGenerator::generate()
     }
 
+    pub fn public_key(&self) -> PublicKey {
+        elgamal::KeyPairGenerator::public_key(self)
+    }
+
     fn from_bytes(buf: &[u8; 256]) -> Self {
         let mut x = [0u8; 256];
         x.copy_from_slice(buf);
@@ -247,9 +292,176 @@ impl Clone for PrivateKey {
 #[cfg_attr(tarpaulin, skip)]
 impl fmt::Debug for PrivateKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        write!(f, "PrivateKey(")?;
-        fmt_colon_delimited_hex(f, &self.0[..])?;
-        write!(f, ")")
+        write!(f, "PrivateKey([REDACTED])")
+    }
+}
+
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        ct_eq(&self.0, &other.0)
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.0[..].zeroize();
+    }
+}
+
+/// The public component of an encryption keypair of any supported type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncryptionKey {
+    ElGamal(PublicKey),
+    X25519([u8; 32]),
+}
+
+impl EncryptionKey {
+    /// Parses a key of the given type, which must take up all of `data`.
+    pub fn from_bytes(enc_type: EncType, data: &[u8]) -> Result<Self, Error> {
+        if data.len() != enc_type.pubkey_len() as usize {
+            return Err(Error::InvalidKey);
+        }
+        Ok(match enc_type {
+            EncType::ElGamal2048 => EncryptionKey::ElGamal(PublicKey(*array_ref![data, 0, 256])),
+            EncType::X25519 => EncryptionKey::X25519(*array_ref![data, 0, 32]),
+        })
+    }
+
+    pub fn enc_type(&self) -> EncType {
+        match self {
+            EncryptionKey::ElGamal(_) => EncType::ElGamal2048,
+            EncryptionKey::X25519(_) => EncType::X25519,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            EncryptionKey::ElGamal(key) => &key.0,
+            EncryptionKey::X25519(key) => key,
+        }
+    }
+
+    /// Encrypts a session establishment message to this key.
+    ///
+    /// ElGamal messages are limited to 222 bytes, and always encrypt to 514 bytes.
+    /// X25519 keys are rejected with [`Error::UnsupportedEncType`] until
+    /// ECIES-X25519-AEAD-Ratchet is implemented.
+    pub fn encrypt_session(&self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            EncryptionKey::ElGamal(key) => elgamal::encrypt(key, msg),
+            EncryptionKey::X25519(_) => Err(Error::UnsupportedEncType),
+        }
+    }
+}
+
+impl From<PublicKey> for EncryptionKey {
+    fn from(key: PublicKey) -> Self {
+        EncryptionKey::ElGamal(key)
+    }
+}
+
+/// The private component of an encryption keypair of any supported type.
+#[derive(Clone, PartialEq)]
+pub enum DecryptionKey {
+    ElGamal(PrivateKey),
+    X25519(X25519PrivateKey),
+}
+
+/// An X25519 private key.
+#[derive(Clone)]
+pub struct X25519PrivateKey(Zeroizing<[u8; 32]>);
+
+impl PartialEq for X25519PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        ct_eq(&self.0[..], &other.0[..])
+    }
+}
+
+impl DecryptionKey {
+    pub fn new() -> Self {
+        DecryptionKey::with_type(EncType::ElGamal2048)
+    }
+
+    pub fn with_type(enc_type: EncType) -> Self {
+        match enc_type {
+            EncType::ElGamal2048 => DecryptionKey::ElGamal(PrivateKey::new_keypair().0),
+            EncType::X25519 => {
+                let mut key = Zeroizing::new([0; 32]);
+                self::rand::OsRng.fill(&mut key[..]);
+                DecryptionKey::X25519(X25519PrivateKey(key))
+            }
+        }
+    }
+
+    /// Parses a key of the given type, which must take up all of `data`.
+    pub fn from_bytes(enc_type: EncType, data: &[u8]) -> Result<Self, Error> {
+        if data.len() != enc_type.privkey_len() as usize {
+            return Err(Error::InvalidKey);
+        }
+        Ok(match enc_type {
+            EncType::ElGamal2048 => {
+                DecryptionKey::ElGamal(PrivateKey::from_bytes(array_ref![data, 0, 256]))
+            }
+            EncType::X25519 => {
+                DecryptionKey::X25519(X25519PrivateKey(Zeroizing::new(*array_ref![data, 0, 32])))
+            }
+        })
+    }
+
+    pub fn enc_type(&self) -> EncType {
+        match self {
+            DecryptionKey::ElGamal(_) => EncType::ElGamal2048,
+            DecryptionKey::X25519(_) => EncType::X25519,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            DecryptionKey::ElGamal(key) => &key.0,
+            DecryptionKey::X25519(key) => &key.0[..],
+        }
+    }
+
+    /// Returns the ElGamal private key, if this is one.
+    pub fn as_elgamal(&self) -> Option<&PrivateKey> {
+        match self {
+            DecryptionKey::ElGamal(key) => Some(key),
+            DecryptionKey::X25519(_) => None,
+        }
+    }
+
+    pub fn public_key(&self) -> EncryptionKey {
+        match self {
+            DecryptionKey::ElGamal(key) => EncryptionKey::ElGamal(key.public_key()),
+            DecryptionKey::X25519(key) => EncryptionKey::X25519(dh::x25519_base(&key.0)),
+        }
+    }
+
+    /// Decrypts a message produced by [`EncryptionKey::encrypt_session`].
+    pub fn decrypt_session(&self, ct: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            DecryptionKey::ElGamal(key) => elgamal::decrypt(key, ct),
+            DecryptionKey::X25519(_) => Err(Error::UnsupportedEncType),
+        }
+    }
+}
+
+impl Default for DecryptionKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for DecryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "DecryptionKey({:?}, [REDACTED])", self.enc_type())
+    }
+}
+
+impl From<PrivateKey> for DecryptionKey {
+    fn from(key: PrivateKey) -> Self {
+        DecryptionKey::ElGamal(key)
     }
 }
 
@@ -259,8 +471,9 @@ pub enum SigningPublicKey {
     DsaSha1(dsa::DsaPublicKey),
     EcdsaSha256P256(ecdsa::PublicKey<NistP256>),
     EcdsaSha384P384(ecdsa::PublicKey<NistP384>),
-    EcdsaSha512P521,
+    EcdsaSha512P521(p521::P521PublicKey),
     Ed25519(ed25519::PublicKey),
+    RedDsaSha512Ed25519(ed25519::PublicKey),
 }
 
 impl SigningPublicKey {
@@ -269,8 +482,9 @@ impl SigningPublicKey {
             SigningPublicKey::DsaSha1(_) => SigType::DsaSha1,
             SigningPublicKey::EcdsaSha256P256(_) => SigType::EcdsaSha256P256,
             SigningPublicKey::EcdsaSha384P384(_) => SigType::EcdsaSha384P384,
-            SigningPublicKey::EcdsaSha512P521 => SigType::EcdsaSha512P521,
+            SigningPublicKey::EcdsaSha512P521(_) => SigType::EcdsaSha512P521,
             SigningPublicKey::Ed25519(_) => SigType::Ed25519,
+            SigningPublicKey::RedDsaSha512Ed25519(_) => SigType::RedDsaSha512Ed25519,
         }
     }
 }
@@ -287,13 +501,18 @@ impl SigningPublicKey {
             SigType::EcdsaSha384P384 => Ok(SigningPublicKey::EcdsaSha384P384(
                 ecdsa::PublicKey::from_untagged_point(SignatoryGenericArray::from_slice(data)),
             )),
-            SigType::EcdsaSha512P521 => unimplemented!(),
+            SigType::EcdsaSha512P521 => {
+                p521::P521PublicKey::from_bytes(data).map(SigningPublicKey::EcdsaSha512P521)
+            }
             SigType::Rsa2048Sha256 | SigType::Rsa3072Sha384 | SigType::Rsa4096Sha512 => {
                 panic!("Online verifying not supported")
             }
             SigType::Ed25519 => ed25519::PublicKey::from_bytes(data)
                 .map(SigningPublicKey::Ed25519)
                 .ok_or(Error::InvalidKey),
+            SigType::RedDsaSha512Ed25519 => ed25519::PublicKey::from_bytes(data)
+                .map(SigningPublicKey::RedDsaSha512Ed25519)
+                .ok_or(Error::InvalidKey),
         }
     }
 
@@ -307,6 +526,11 @@ impl SigningPublicKey {
                 .public_key()
                 .map(SigningPublicKey::Ed25519)
                 .map_err(|_| Error::InvalidKey),
+            SigningPrivateKey::RedDsaSha512Ed25519(ref key) => {
+                ed25519::PublicKey::from_bytes(blinding::red25519_public_key(key))
+                    .map(SigningPublicKey::RedDsaSha512Ed25519)
+                    .ok_or(Error::InvalidKey)
+            }
         }
     }
 
@@ -315,8 +539,9 @@ impl SigningPublicKey {
             SigningPublicKey::DsaSha1(ref pk) => pk.as_bytes(),
             SigningPublicKey::EcdsaSha256P256(ref pk) => &pk.as_bytes()[1..],
             SigningPublicKey::EcdsaSha384P384(ref pk) => &pk.as_bytes()[1..],
-            SigningPublicKey::EcdsaSha512P521 => unimplemented!(),
+            SigningPublicKey::EcdsaSha512P521(ref pk) => pk.as_bytes(),
             SigningPublicKey::Ed25519(ref pk) => pk.as_bytes(),
+            SigningPublicKey::RedDsaSha512Ed25519(ref pk) => pk.as_bytes(),
         }
     }
 
@@ -339,8 +564,19 @@ impl SigningPublicKey {
                     .verify(message, s)
                     .map_err(|_| Error::InvalidSignature)
             }
-            (&SigningPublicKey::EcdsaSha512P521, &Signature::EcdsaSha512P521) => unimplemented!(),
-            (&SigningPublicKey::Ed25519(ref pk), &Signature::Ed25519(ref s)) => {
+            (&SigningPublicKey::EcdsaSha512P521(ref pk), &Signature::EcdsaSha512P521(ref s)) => {
+                if pk.verify(message, s) {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidSignature)
+                }
+            }
+            (&SigningPublicKey::Ed25519(ref pk), &Signature::Ed25519(ref s))
+            | (
+                &SigningPublicKey::RedDsaSha512Ed25519(ref pk),
+                &Signature::RedDsaSha512Ed25519(ref s),
+            ) => {
+                // RedDSA signatures are verified in the same way as Ed25519
                 Ed25519Verifier::from(pk)
                     .verify(message, s)
                     .map_err(|_| Error::InvalidSignature)
@@ -357,6 +593,9 @@ pub enum SigningPrivateKey {
     EcdsaSha384P384,
     EcdsaSha512P521,
     Ed25519(ed25519::Seed),
+    /// A RedDSA private scalar, encoded as 32 little-endian bytes. Unlike Ed25519
+    /// keys this is not a seed, so blinded keys can be represented directly.
+    RedDsaSha512Ed25519(Scalar),
 }
 
 impl SigningPrivateKey {
@@ -373,7 +612,10 @@ impl SigningPrivateKey {
             SigType::Rsa2048Sha256 | SigType::Rsa3072Sha384 | SigType::Rsa4096Sha512 => {
                 panic!("Online signing not supported")
             }
-            SigType::Ed25519 => SigningPrivateKey::Ed25519(ed25519::Seed::generate()),
+            SigType::Ed25519 => Ed25519SigningKey::generate().into(),
+            SigType::RedDsaSha512Ed25519 => {
+                SigningPrivateKey::RedDsaSha512Ed25519(blinding::red25519_generate())
+            }
         }
     }
 
@@ -389,6 +631,12 @@ impl SigningPrivateKey {
             SigType::Ed25519 => ed25519::Seed::from_bytes(data)
                 .map(SigningPrivateKey::Ed25519)
                 .ok_or(Error::InvalidKey),
+            SigType::RedDsaSha512Ed25519 if data.len() == 32 => {
+                Scalar::from_canonical_bytes(*array_ref![data, 0, 32])
+                    .map(SigningPrivateKey::RedDsaSha512Ed25519)
+                    .ok_or(Error::InvalidKey)
+            }
+            SigType::RedDsaSha512Ed25519 => Err(Error::InvalidKey),
         }
     }
 
@@ -399,6 +647,7 @@ impl SigningPrivateKey {
             SigningPrivateKey::EcdsaSha384P384 => unimplemented!(),
             SigningPrivateKey::EcdsaSha512P521 => unimplemented!(),
             SigningPrivateKey::Ed25519(ref seed) => seed.as_secret_slice(),
+            SigningPrivateKey::RedDsaSha512Ed25519(ref key) => &key.as_bytes()[..],
         }
     }
 
@@ -411,6 +660,13 @@ impl SigningPrivateKey {
             SigningPrivateKey::Ed25519(ref seed) => {
                 Ok(Signature::Ed25519(Ed25519Signer::from(seed).sign(msg)))
             }
+            SigningPrivateKey::RedDsaSha512Ed25519(ref key) => {
+                let pub_key = blinding::red25519_public_key(key);
+                Signature::from_bytes(
+                    SigType::RedDsaSha512Ed25519,
+                    &blinding::red25519_sign(key, &pub_key, msg),
+                )
+            }
         }
     }
 }
@@ -426,16 +682,91 @@ impl Clone for SigningPrivateKey {
             SigningPrivateKey::Ed25519(ref seed) => SigningPrivateKey::Ed25519(
                 ed25519::Seed::from_bytes(seed.as_secret_slice()).unwrap(),
             ),
+            SigningPrivateKey::RedDsaSha512Ed25519(key) => {
+                SigningPrivateKey::RedDsaSha512Ed25519(key)
+            }
+        }
+    }
+}
+
+/// An Ed25519 signing key.
+///
+/// Ed25519 signatures are deterministic: signing the same message with the same key
+/// always produces the same signature.
+pub struct Ed25519SigningKey(ed25519::Seed);
+
+impl Ed25519SigningKey {
+    pub fn generate() -> Self {
+        Ed25519SigningKey(ed25519::Seed::generate())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        ed25519::Seed::from_bytes(data)
+            .map(Ed25519SigningKey)
+            .ok_or(Error::InvalidKey)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_secret_slice()
+    }
+
+    pub fn verifying_key(&self) -> Ed25519VerifyingKey {
+        Ed25519VerifyingKey(
+            Ed25519Signer::from(&self.0)
+                .public_key()
+                .expect("seed is a valid Ed25519 key"),
+        )
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Signature {
+        Signature::Ed25519(Ed25519Signer::from(&self.0).sign(msg))
+    }
+}
+
+impl From<Ed25519SigningKey> for SigningPrivateKey {
+    fn from(key: Ed25519SigningKey) -> Self {
+        SigningPrivateKey::Ed25519(key.0)
+    }
+}
+
+/// An Ed25519 verifying key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ed25519VerifyingKey(ed25519::PublicKey);
+
+impl Ed25519VerifyingKey {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        ed25519::PublicKey::from_bytes(data)
+            .map(Ed25519VerifyingKey)
+            .ok_or(Error::InvalidKey)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> Result<(), Error> {
+        match signature {
+            Signature::Ed25519(s) => Ed25519Verifier::from(&self.0)
+                .verify(message, s)
+                .map_err(|_| Error::InvalidSignature),
+            _ => Err(Error::TypeMismatch),
         }
     }
 }
 
+impl From<Ed25519VerifyingKey> for SigningPublicKey {
+    fn from(key: Ed25519VerifyingKey) -> Self {
+        SigningPublicKey::Ed25519(key.0)
+    }
+}
+
 /// The public component of an offline signature keypair.
 #[derive(Clone, PartialEq)]
 pub enum OfflineSigningPublicKey {
     Rsa2048Sha256(Vec<u8>),
     Rsa3072Sha384(Vec<u8>),
     Rsa4096Sha512(Vec<u8>),
+    Ed25519(ed25519::PublicKey),
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -458,6 +789,11 @@ impl fmt::Debug for OfflineSigningPublicKey {
                 fmt_colon_delimited_hex(f, key)?;
                 write!(f, ")")?;
             }
+            OfflineSigningPublicKey::Ed25519(key) => {
+                write!(f, "Ed25519(")?;
+                fmt_colon_delimited_hex(f, key.as_bytes())?;
+                write!(f, ")")?;
+            }
         };
         write!(f, ")")
     }
@@ -476,10 +812,43 @@ impl OfflineSigningPublicKey {
                     _ => unreachable!(),
                 })
             }
+            SigType::Ed25519 => ed25519::PublicKey::from_bytes(data)
+                .map(OfflineSigningPublicKey::Ed25519)
+                .ok_or(Error::InvalidKey),
             _ => panic!("Invalid offline SigType"),
         }
     }
 
+    /// Extracts the public key of the given type from a DER-encoded X.509
+    /// certificate.
+    ///
+    /// The certificate itself is not validated; it must come from a trusted
+    /// source, such as the signer certificates bundled with the router.
+    pub fn from_certificate(sig_type: SigType, cert: &[u8]) -> Result<Self, Error> {
+        let (algorithm, key) = x509::subject_public_key(cert).ok_or(Error::InvalidKey)?;
+        let expected = match sig_type {
+            SigType::Rsa2048Sha256 | SigType::Rsa3072Sha384 | SigType::Rsa4096Sha512 => {
+                x509::KeyAlgorithm::Rsa
+            }
+            SigType::Ed25519 => x509::KeyAlgorithm::Ed25519,
+            _ => return Err(Error::TypeMismatch),
+        };
+        if algorithm == expected {
+            OfflineSigningPublicKey::from_bytes(sig_type, key)
+        } else {
+            Err(Error::TypeMismatch)
+        }
+    }
+
+    pub fn sig_type(&self) -> SigType {
+        match self {
+            OfflineSigningPublicKey::Rsa2048Sha256(_) => SigType::Rsa2048Sha256,
+            OfflineSigningPublicKey::Rsa3072Sha384(_) => SigType::Rsa3072Sha384,
+            OfflineSigningPublicKey::Rsa4096Sha512(_) => SigType::Rsa4096Sha512,
+            OfflineSigningPublicKey::Ed25519(_) => SigType::Ed25519,
+        }
+    }
+
     pub fn verify(&self, message: &[u8], signature: &Signature) -> Result<(), Error> {
         match (self, signature) {
             (OfflineSigningPublicKey::Rsa2048Sha256(pk), Signature::Rsa2048Sha256(s)) => {
@@ -497,6 +866,11 @@ impl OfflineSigningPublicKey {
                     .verify(message, &s)
                     .map_err(|_| Error::InvalidSignature)
             }
+            (OfflineSigningPublicKey::Ed25519(pk), Signature::Ed25519(s)) => {
+                Ed25519Verifier::from(pk)
+                    .verify(message, s)
+                    .map_err(|_| Error::InvalidSignature)
+            }
             _ => Err(Error::TypeMismatch),
         }
     }
@@ -508,16 +882,23 @@ pub enum Signature {
     DsaSha1(dsa::DsaSignature),
     EcdsaSha256P256(FixedSignature<NistP256>),
     EcdsaSha384P384(FixedSignature<NistP384>),
-    EcdsaSha512P521,
+    EcdsaSha512P521(p521::P521Signature),
     Rsa2048Sha256(Vec<u8>),
     Rsa3072Sha384(Vec<u8>),
     Rsa4096Sha512(Vec<u8>),
     Ed25519(ed25519::Signature),
+    RedDsaSha512Ed25519(ed25519::Signature),
     Unsupported(Vec<u8>),
 }
 
 impl Signature {
+    /// Parses a signature of the given type, which must be exactly
+    /// `sig_type.sig_len()` bytes.
     pub fn from_bytes(sig_type: SigType, data: &[u8]) -> Result<Self, Error> {
+        if data.len() != sig_type.sig_len() as usize {
+            return Err(Error::InvalidSignature);
+        }
+
         match sig_type {
             SigType::DsaSha1 => Ok(Signature::DsaSha1(dsa::DsaSignature::from_bytes(data)?)),
             SigType::EcdsaSha256P256 => Ok(Signature::EcdsaSha256P256(
@@ -526,15 +907,17 @@ impl Signature {
             SigType::EcdsaSha384P384 => Ok(Signature::EcdsaSha384P384(
                 FixedSignature::from_bytes(data).map_err(|_| Error::InvalidSignature)?,
             )),
+            SigType::EcdsaSha512P521 => Ok(Signature::EcdsaSha512P521(
+                p521::P521Signature::from_bytes(data)?,
+            )),
             SigType::Ed25519 => Ok(Signature::Ed25519(
                 ed25519::Signature::from_bytes(data).map_err(|_| Error::InvalidSignature)?,
             )),
-            SigType::EcdsaSha512P521
-            | SigType::Rsa2048Sha256
-            | SigType::Rsa3072Sha384
-            | SigType::Rsa4096Sha512 => {
-                let mut sig = Vec::with_capacity(sig_type.sig_len() as usize);
-                sig.extend_from_slice(&data[..sig_type.sig_len() as usize]);
+            SigType::RedDsaSha512Ed25519 => Ok(Signature::RedDsaSha512Ed25519(
+                ed25519::Signature::from_bytes(data).map_err(|_| Error::InvalidSignature)?,
+            )),
+            SigType::Rsa2048Sha256 | SigType::Rsa3072Sha384 | SigType::Rsa4096Sha512 => {
+                let sig = data.to_vec();
                 Ok(match sig_type {
                     SigType::Rsa2048Sha256 => Signature::Rsa2048Sha256(sig),
                     SigType::Rsa3072Sha384 => Signature::Rsa3072Sha384(sig),
@@ -550,18 +933,55 @@ impl Signature {
             Signature::DsaSha1(ref s) => s.to_bytes(),
             Signature::EcdsaSha256P256(ref s) => Vec::from(s.as_ref()),
             Signature::EcdsaSha384P384(ref s) => Vec::from(s.as_ref()),
-            Signature::EcdsaSha512P521 => unimplemented!(),
+            Signature::EcdsaSha512P521(ref s) => s.to_bytes(),
             Signature::Rsa2048Sha256(ref s) => s.clone(),
             Signature::Rsa3072Sha384(ref s) => s.clone(),
             Signature::Rsa4096Sha512(ref s) => s.clone(),
-            Signature::Ed25519(ref s) => s.to_bytes().to_vec(),
+            Signature::Ed25519(ref s) | Signature::RedDsaSha512Ed25519(ref s) => {
+                s.to_bytes().to_vec()
+            }
             Signature::Unsupported(ref s) => s.clone(),
         }
     }
+
+    /// Returns the type of this signature, or `None` if it is unsupported.
+    pub fn sig_type(&self) -> Option<SigType> {
+        match *self {
+            Signature::DsaSha1(_) => Some(SigType::DsaSha1),
+            Signature::EcdsaSha256P256(_) => Some(SigType::EcdsaSha256P256),
+            Signature::EcdsaSha384P384(_) => Some(SigType::EcdsaSha384P384),
+            Signature::EcdsaSha512P521(_) => Some(SigType::EcdsaSha512P521),
+            Signature::Rsa2048Sha256(_) => Some(SigType::Rsa2048Sha256),
+            Signature::Rsa3072Sha384(_) => Some(SigType::Rsa3072Sha384),
+            Signature::Rsa4096Sha512(_) => Some(SigType::Rsa4096Sha512),
+            Signature::Ed25519(_) => Some(SigType::Ed25519),
+            Signature::RedDsaSha512Ed25519(_) => Some(SigType::RedDsaSha512Ed25519),
+            Signature::Unsupported(_) => None,
+        }
+    }
+
+    /// Returns the length of this signature in bytes.
+    pub fn len(&self) -> usize {
+        self.sig_type()
+            .map_or_else(|| self.to_bytes().len(), |t| t.sig_len() as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Verifies this signature over the given message, dispatching on the type of
+    /// the key.
+    pub fn verify(&self, key: &SigningPublicKey, message: &[u8]) -> Result<(), Error> {
+        key.verify(message, self)
+    }
 }
 
 /// A symmetric key used for AES-256 encryption.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// Keys are compared in constant time, are never printed, and are zeroized when
+/// dropped.
+#[derive(Clone)]
 pub struct SessionKey(pub [u8; 32]);
 
 impl SessionKey {
@@ -578,64 +998,98 @@ impl SessionKey {
     }
 }
 
-//
-// Algorithm implementations
-//
-
-fn to_blocks<N>(data: &mut [u8]) -> &mut [AesGenericArray<u8, N>]
-where
-    N: ArrayLength<u8>,
-{
-    let n = N::to_usize();
-    debug_assert!(data.len() % n == 0);
-
-    #[allow(unsafe_code)]
-    unsafe {
-        slice::from_raw_parts_mut(data.as_ptr() as *mut AesGenericArray<u8, N>, data.len() / n)
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "SessionKey([REDACTED])")
     }
 }
 
-pub(crate) struct Aes256 {
-    cbc_enc: Cbc<aes::Aes256, NoPadding>,
-    cbc_dec: Cbc<aes::Aes256, NoPadding>,
+impl PartialEq for SessionKey {
+    fn eq(&self, other: &Self) -> bool {
+        ct_eq(&self.0, &other.0)
+    }
 }
 
-impl Aes256 {
-    pub fn new(key: &SessionKey, iv_enc: &[u8], iv_dec: &[u8]) -> Self {
-        Aes256 {
-            cbc_enc: Cbc::new_from_slices(&key.0, iv_enc).expect("key and iv are correct length"),
-            cbc_dec: Cbc::new_from_slices(&key.0, iv_dec).expect("key and iv are correct length"),
-        }
+impl Drop for SessionKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
     }
+}
 
-    pub fn encrypt_blocks(&mut self, buf: &mut [u8]) -> Option<usize> {
-        // Wait until we have at least a block to encrypt
-        if buf.len() < AES_BLOCK_SIZE {
-            return None;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::encoding::hex_decode;
 
-        // Integer division, leaves extra bytes unencrypted at the end
-        let end = (buf.len() / AES_BLOCK_SIZE) * AES_BLOCK_SIZE;
-        self.cbc_enc.encrypt_blocks(to_blocks(&mut buf[..end]));
-        Some(end)
-    }
+    #[test]
+    fn ed25519_rfc8032_vectors() {
+        // RFC 8032, section 7.1, tests 1-3
+        let test_vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+            (
+                "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                "af82",
+                "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+                 18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+            ),
+        ];
 
-    pub fn decrypt_blocks(&mut self, buf: &mut [u8]) -> Option<usize> {
-        // Wait until we have at least a block to decrypt
-        if buf.len() < AES_BLOCK_SIZE {
-            return None;
+        for (secret, public, msg, sig) in test_vectors.iter() {
+            let secret = hex_decode(secret).unwrap();
+            let public = hex_decode(public).unwrap();
+            let msg = hex_decode(msg).unwrap();
+            let sig = hex_decode(sig).unwrap();
+
+            let sk = Ed25519SigningKey::from_bytes(&secret).unwrap();
+            assert_eq!(sk.as_bytes(), &secret[..]);
+            let vk = sk.verifying_key();
+            assert_eq!(vk.as_bytes(), &public[..]);
+            assert_eq!(Ed25519VerifyingKey::from_bytes(&public), Ok(vk.clone()));
+
+            // Signing is deterministic
+            let signature = sk.sign(&msg);
+            assert_eq!(signature.to_bytes(), sig);
+            assert_eq!(sk.sign(&msg).to_bytes(), sig);
+            assert_eq!(vk.verify(&msg, &signature), Ok(()));
+
+            // The generic key types dispatch to the same implementation
+            let spk = SigningPrivateKey::from(sk);
+            let pk = SigningPublicKey::from(vk.clone());
+            assert_eq!(SigningPublicKey::from_secret(&spk).unwrap(), pk);
+            assert_eq!(spk.sign(&msg).unwrap().to_bytes(), sig);
+            assert_eq!(pk.verify(&msg, &signature), Ok(()));
+
+            // Tampered messages and mismatched signature types are rejected
+            let mut tampered = msg.clone();
+            tampered.push(0);
+            assert_eq!(
+                vk.verify(&tampered, &signature),
+                Err(Error::InvalidSignature)
+            );
+            let reddsa = Signature::from_bytes(SigType::RedDsaSha512Ed25519, &sig).unwrap();
+            assert_eq!(vk.verify(&msg, &reddsa), Err(Error::TypeMismatch));
         }
 
-        // Integer division, leaves extra bytes undecrypted at the end
-        let end = (buf.len() / AES_BLOCK_SIZE) * AES_BLOCK_SIZE;
-        self.cbc_dec.decrypt_blocks(to_blocks(&mut buf[..end]));
-        Some(end)
+        assert_eq!(
+            Ed25519SigningKey::from_bytes(&[0; 31]).err(),
+            Some(Error::InvalidKey)
+        );
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
     fn test_sig_type_pad_len() {
@@ -664,6 +1118,198 @@ mod tests {
         assert_eq!(SigType::Ed25519.extra_data_len(EncType::ElGamal2048), 0);
     }
 
+    #[test]
+    fn signature_test_vectors() {
+        struct TestVector {
+            sig_type: SigType,
+            public_key: &'static str,
+            message: &'static [u8],
+            signature: &'static str,
+        }
+        // The DSA and ECDSA vectors were generated with Python's cryptography
+        // library. The Ed25519 vector is TEST 1 from RFC 8032, and RedDSA signatures
+        // are verified in the same way.
+        let test_vectors = vec![
+            TestVector {
+                sig_type: SigType::DsaSha1,
+                public_key: "7120ee5552112fd1ddd34d1648382c405fdb56a23f97477a82d31d25fcdf10b4\
+                             5c796ea5a688e8d889c973867be3dbf57c3aace8d09e3120302d0df516997046\
+                             044ed8271b151d8aca41559e5d74c0a2b99ffb05cb7fb5a9b53b5a2a11a32aa9\
+                             564650c1020b659dc3f1aed91ad542a50b2399c38fa711f5438490fc08bca350",
+                message: b"I2P signature test",
+                signature: "18355ff964fac05f2262b31676a06afcc046a7fe96c1db95f067465cf82e974f\
+                            1d67e12482f5072d",
+            },
+            TestVector {
+                sig_type: SigType::EcdsaSha256P256,
+                public_key: "f633935f4dc1a1f75a69d96888f9b2903399b79d0aae776f58f57494787fb5b6\
+                             8b0ff2e9914bd741f8f33cdffc6e992e07c56ef91b2f12211b23471626b5b108",
+                message: b"I2P signature test",
+                signature: "7c04aa559b2f88bbe930a45c4d29b21329f5cfac5d6bca5f817c12820d49b9b0\
+                            47bf87167076b632d6ff46e5176d81d53316943e1781b36c812fb6350755e79b",
+            },
+            TestVector {
+                sig_type: SigType::EcdsaSha384P384,
+                public_key: "8cb29956642901b22eadbf32d651906f1d0e1edf0720a6770dd4fae3f61707fe\
+                             ab5a4613feea8ce7cc953865038916fadb0c73ae49737c8148a433a70f7f8f63\
+                             f63116fc26ddde1fafd2cc6fdcb8f08942847cbe35a3a28745ba9ecc85d93890",
+                message: b"I2P signature test",
+                signature: "377da5c4cc415d8d054f211b32c58be4c99ffac261103b146a24f24f74dfb489\
+                            5e319b4c6e84f1c0a79e46b4e9386dc77dd589c7a07febf20ff3d056517c6e9a\
+                            955af5a56b7840af4f4909740b5fd9ceaf3599ceeb9e3966a9b928995c79189b",
+            },
+            TestVector {
+                sig_type: SigType::EcdsaSha512P521,
+                public_key: "01e49a577bea7c30484adfefb6d4659a83e27485ba05bdfbca8015a820158eac\
+                             c89fc24c1e9b0ed9bc966c814dfa35225b153c4293172f0969a2a5a6ca09ea28\
+                             922f01a6e858203a669840edb8682e2d69489c34780121dc86f878b4fa24ba16\
+                             258a4dfee34eafa356b77f49bad265a2b68d9afe6f578c84e5bdb18971b64841\
+                             1d2b2206",
+                message: b"I2P signature test",
+                signature: "0088ebfeaa472b0a1f11ce9f3b43302179b8be1c367d047b31cd811b6b58200b\
+                            9d888c72fae278c02e2fa263dbd90c384a4752d8c926ee711796fa2167c3dbf0\
+                            174b010a07a37b70ecc7d370e6a4894ec5764c95aac7071216d1a239ce0735a2\
+                            75967cfc6f8d82d70d08315a85e06d9b27f26811e0dbbb9b4abc05fa1e25bc96\
+                            4a16761f",
+            },
+            TestVector {
+                sig_type: SigType::Ed25519,
+                public_key: "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                message: b"",
+                signature: "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                            5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            },
+            TestVector {
+                sig_type: SigType::RedDsaSha512Ed25519,
+                public_key: "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                message: b"",
+                signature: "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                            5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            },
+        ];
+
+        let dsa_sig = Signature::from_bytes(SigType::DsaSha1, &[1; 40]).unwrap();
+        for tv in test_vectors {
+            let key = SigningPublicKey::from_bytes(
+                tv.sig_type,
+                &hex_decode(tv.public_key).unwrap(),
+            )
+            .unwrap();
+            let data = hex_decode(tv.signature).unwrap();
+            let sig = Signature::from_bytes(tv.sig_type, &data).unwrap();
+            assert_eq!(sig.sig_type(), Some(tv.sig_type));
+            assert_eq!(sig.len(), tv.sig_type.sig_len() as usize);
+            assert_eq!(sig.to_bytes(), data);
+
+            assert_eq!(sig.verify(&key, tv.message), Ok(()));
+            assert_eq!(
+                sig.verify(&key, b"Not the signed message"),
+                Err(Error::InvalidSignature)
+            );
+
+            // Signatures must be exactly the right length
+            let mut longer = data.clone();
+            longer.push(0);
+            assert_eq!(
+                Signature::from_bytes(tv.sig_type, &longer),
+                Err(Error::InvalidSignature)
+            );
+            assert_eq!(
+                Signature::from_bytes(tv.sig_type, &data[1..]),
+                Err(Error::InvalidSignature)
+            );
+
+            // Signatures are only checked against keys of the same type
+            if tv.sig_type != SigType::DsaSha1 {
+                assert_eq!(dsa_sig.verify(&key, tv.message), Err(Error::TypeMismatch));
+            }
+        }
+    }
+
+    #[test]
+    fn ecdsa_rfc6979_vectors() {
+        struct TestVector {
+            sig_type: SigType,
+            public_key: &'static str,
+            signatures: [(&'static [u8], &'static str); 2],
+        }
+        // RFC 6979, sections A.2.5 and A.2.6. I2P encodes public keys as the raw
+        // coordinates x || y, and signatures as the raw integers r || s, each padded
+        // to the field size.
+        let test_vectors = vec![
+            TestVector {
+                sig_type: SigType::EcdsaSha256P256,
+                public_key: "60FED4BA255A9D31C961EB74C6356D68C049B8923B61FA6CE669622E60F29FB6\
+                             7903FE1008B8BC99A41AE9E95628BC64F2F1B20C2D7E9F5177A3C294D4462299",
+                signatures: [
+                    (
+                        b"sample",
+                        "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716\
+                         F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8",
+                    ),
+                    (
+                        b"test",
+                        "F1ABB023518351CD71D881567B1EA663ED3EFCF6C5132B354F28D3B0B7D38367\
+                         019F4113742A2B14BD25926B49C649155F267E60D3814B4C0CC84250E46F0083",
+                    ),
+                ],
+            },
+            TestVector {
+                sig_type: SigType::EcdsaSha384P384,
+                public_key: "EC3A4E415B4E19A4568618029F427FA5DA9A8BC4AE92E02E06AAE5286B300C64\
+                             DEF8F0EA9055866064A254515480BC138015D9B72D7D57244EA8EF9AC0C62189\
+                             6708A59367F9DFB9F54CA84B3F1C9DB1288B231C3AE0D4FE7344FD2533264720",
+                signatures: [
+                    (
+                        b"sample",
+                        "94EDBB92A5ECB8AAD4736E56C691916B3F88140666CE9FA73D64C4EA95AD133C\
+                         81A648152E44ACF96E36DD1E80FABE4699EF4AEB15F178CEA1FE40DB2603138F\
+                         130E740A19624526203B6351D0A3A94FA329C145786E679E7B82C71A38628AC8",
+                    ),
+                    (
+                        b"test",
+                        "8203B63D3C853E8D77227FB377BCF7B7B772E97892A80F36AB775D509D7A5FEB\
+                         0542A7F0812998DA8F1DD3CA3CF023DBDDD0760448D42D8A43AF45AF836FCE4D\
+                         E8BE06B485E9B61B827C2F13173923E06A739F040649A667BF3B828246BAA5A5",
+                    ),
+                ],
+            },
+        ];
+
+        for tv in test_vectors {
+            let public_key = hex_decode(tv.public_key).unwrap();
+            assert_eq!(public_key.len(), tv.sig_type.pubkey_len() as usize);
+            let key = SigningPublicKey::from_bytes(tv.sig_type, &public_key).unwrap();
+            assert_eq!(key.as_bytes(), &public_key[..]);
+
+            for (message, signature) in tv.signatures.iter() {
+                let data = hex_decode(signature).unwrap();
+                let sig = Signature::from_bytes(tv.sig_type, &data).unwrap();
+                assert_eq!(sig.to_bytes(), data);
+                assert_eq!(key.verify(message, &sig), Ok(()));
+
+                // Swapping r and s produces an invalid signature
+                let half = data.len() / 2;
+                let swapped = [&data[half..], &data[..half]].concat();
+                let sig = Signature::from_bytes(tv.sig_type, &swapped).unwrap();
+                assert_eq!(key.verify(message, &sig), Err(Error::InvalidSignature));
+            }
+
+            // DER-encoded signatures are not accepted
+            let (r, s) = tv.signatures[0].1.split_at(tv.signatures[0].1.len() / 2);
+            let (r, s) = (hex_decode(r).unwrap(), hex_decode(s).unwrap());
+            let mut der = vec![0x30, 0, 0x02, r.len() as u8 + 1, 0];
+            der.extend_from_slice(&r);
+            der.extend_from_slice(&[0x02, s.len() as u8 + 1, 0]);
+            der.extend_from_slice(&s);
+            der[1] = (der.len() - 2) as u8;
+            assert_eq!(
+                Signature::from_bytes(tv.sig_type, &der),
+                Err(Error::InvalidSignature)
+            );
+        }
+    }
+
     #[test]
     fn aes_256_cbc_test_vectors() {
         struct TestVector {
@@ -870,4 +1516,97 @@ mod tests {
             assert_eq!(blocks, tv.plaintext);
         }
     }
+
+    #[test]
+    fn key_debug_is_redacted() {
+        let key = SessionKey([0xab; 32]);
+        assert_eq!(format!("{:?}", key), "SessionKey([REDACTED])");
+
+        let (private_key, _) = PrivateKey::new_keypair();
+        assert_eq!(format!("{:?}", private_key), "PrivateKey([REDACTED])");
+
+        let key = DecryptionKey::with_type(EncType::X25519);
+        assert_eq!(format!("{:?}", key), "DecryptionKey(X25519, [REDACTED])");
+    }
+
+    #[test]
+    fn encryption_key_types() {
+        for &(enc_type, len) in &[(EncType::ElGamal2048, 256), (EncType::X25519, 32)] {
+            let private_key = DecryptionKey::with_type(enc_type);
+            assert_eq!(private_key.enc_type(), enc_type);
+            assert_eq!(private_key.as_bytes().len(), len);
+            assert_eq!(
+                DecryptionKey::from_bytes(enc_type, private_key.as_bytes()).unwrap(),
+                private_key
+            );
+
+            let public_key = private_key.public_key();
+            assert_eq!(public_key.enc_type(), enc_type);
+            assert_eq!(public_key.as_bytes().len(), len);
+            assert_eq!(
+                EncryptionKey::from_bytes(enc_type, public_key.as_bytes()).unwrap(),
+                public_key
+            );
+            assert_eq!(
+                EncryptionKey::from_bytes(enc_type, &[0; 31]),
+                Err(Error::InvalidKey)
+            );
+        }
+    }
+
+    #[test]
+    fn session_encryption() {
+        let private_key = DecryptionKey::with_type(EncType::ElGamal2048);
+        let public_key = private_key.public_key();
+        let ct = public_key.encrypt_session(&[0x42; 64]).unwrap();
+        assert_eq!(ct.len(), 514);
+        assert_eq!(private_key.decrypt_session(&ct).unwrap(), &[0x42; 64][..]);
+        assert!(DecryptionKey::new().decrypt_session(&ct).is_err());
+
+        // ECIES-X25519-AEAD-Ratchet is not implemented
+        let private_key = DecryptionKey::with_type(EncType::X25519);
+        let public_key = private_key.public_key();
+        assert_eq!(
+            public_key.encrypt_session(&[0x42; 64]),
+            Err(Error::UnsupportedEncType)
+        );
+        assert_eq!(
+            private_key.decrypt_session(&[0; 96]),
+            Err(Error::UnsupportedEncType)
+        );
+    }
+
+    #[test]
+    fn key_equality() {
+        let mut other = [0x42; 32];
+        assert_eq!(SessionKey([0x42; 32]), SessionKey(other));
+        other[31] ^= 1;
+        assert_ne!(SessionKey([0x42; 32]), SessionKey(other));
+
+        let (private_key, _) = PrivateKey::new_keypair();
+        let mut other = private_key.clone();
+        assert_eq!(private_key, other);
+        other.0[0] ^= 1;
+        assert_ne!(private_key, other);
+    }
+
+    #[test]
+    fn keys_zeroized_on_drop() {
+        use std::mem::ManuallyDrop;
+        use std::ptr;
+
+        let mut key = ManuallyDrop::new(SessionKey([0xab; 32]));
+        #[allow(unsafe_code)]
+        unsafe {
+            ptr::drop_in_place(&mut *key);
+        }
+        assert_eq!(key.0, [0; 32]);
+
+        let mut key = ManuallyDrop::new(PrivateKey([0xab; 256]));
+        #[allow(unsafe_code)]
+        unsafe {
+            ptr::drop_in_place(&mut *key);
+        }
+        assert!(key.0.iter().all(|b| *b == 0));
+    }
 }
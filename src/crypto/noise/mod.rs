@@ -0,0 +1,390 @@
+//! The Noise Protocol Framework, instantiated with ChaChaPoly and SHA256 as used
+//! by NTCP2, SSU2 and ECIES-X25519.
+//!
+//! [`NoiseSymmetricState`] is the symmetric half of Noise, for callers that drive
+//! a handshake pattern themselves. The [`xk`] module builds the I2P variant of
+//! the XK pattern on top of it.
+//!
+//! [Noise specification](https://noiseprotocol.org/noise.html)
+
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+use super::{
+    chachapoly::{noise_nonce, open_in_place, seal_in_place, KEY_LEN, TAG_LEN},
+    hkdf::{self, noise_hkdf2},
+    siphash::SipState,
+    Error, SessionKey,
+};
+
+pub mod xk;
+
+pub const HASH_LEN: usize = 32;
+
+/// A ChaChaPoly key and its message counter.
+pub struct CipherState {
+    k: SessionKey,
+    n: u64,
+}
+
+impl CipherState {
+    pub fn new(k: [u8; KEY_LEN]) -> Self {
+        CipherState {
+            k: SessionKey(k),
+            n: 0,
+        }
+    }
+
+    /// Encrypts `buf` in place, returning the authentication tag.
+    pub fn seal(&mut self, ad: &[u8], buf: &mut [u8]) -> [u8; TAG_LEN] {
+        let tag = seal_in_place(&self.k.0, &noise_nonce(self.n), ad, buf);
+        self.n += 1;
+        tag
+    }
+
+    /// Decrypts `buf` in place. The counter only advances if the tag is valid.
+    pub fn open(&mut self, ad: &[u8], buf: &mut [u8], tag: &[u8; TAG_LEN]) -> Result<(), Error> {
+        open_in_place(&self.k.0, &noise_nonce(self.n), ad, buf, tag)?;
+        self.n += 1;
+        Ok(())
+    }
+}
+
+pub struct NoiseSymmetricState {
+    ck: Zeroizing<[u8; HASH_LEN]>,
+    h: [u8; HASH_LEN],
+    cipher: Option<CipherState>,
+}
+
+impl NoiseSymmetricState {
+    /// Initializes the state for the given protocol name, which is used directly
+    /// if it fits in a hash and is hashed otherwise.
+    pub fn new(protocol_name: &[u8]) -> Self {
+        let mut h = [0; HASH_LEN];
+        if protocol_name.len() <= HASH_LEN {
+            h[..protocol_name.len()].copy_from_slice(protocol_name);
+        } else {
+            h.copy_from_slice(&Sha256::digest(protocol_name));
+        }
+        NoiseSymmetricState {
+            ck: Zeroizing::new(h),
+            h,
+            cipher: None,
+        }
+    }
+
+    pub fn chaining_key(&self) -> &[u8; HASH_LEN] {
+        &self.ck
+    }
+
+    pub fn handshake_hash(&self) -> &[u8; HASH_LEN] {
+        &self.h
+    }
+
+    /// h = SHA256(h || data)
+    pub fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.h);
+        hasher.update(data);
+        self.h.copy_from_slice(&hasher.finalize());
+    }
+
+    /// ck, k = HKDF(ck, ikm), and resets the nonce.
+    pub fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, k) = noise_hkdf2(&self.ck, ikm);
+        *self.ck = ck;
+        self.cipher = Some(CipherState::new(k));
+    }
+
+    /// Encrypts `plaintext` with the current key and h as associated data, then
+    /// mixes the ciphertext into h. Before the first [`mix_key`] the plaintext is
+    /// passed through unencrypted.
+    ///
+    /// [`mix_key`]: NoiseSymmetricState::mix_key
+    pub fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut buf = plaintext.to_vec();
+        if let Some(ref mut cipher) = self.cipher {
+            let tag = cipher.seal(&self.h, &mut buf);
+            buf.extend_from_slice(&tag);
+        }
+        self.mix_hash(&buf);
+        buf
+    }
+
+    /// The inverse of [`encrypt_and_hash`]. The state is left unchanged if the
+    /// ciphertext fails to authenticate.
+    ///
+    /// [`encrypt_and_hash`]: NoiseSymmetricState::encrypt_and_hash
+    pub fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = match self.cipher {
+            Some(ref mut cipher) => {
+                if ciphertext.len() < TAG_LEN {
+                    return Err(Error::InvalidCiphertext);
+                }
+                let (ct, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+                let mut buf = ct.to_vec();
+                cipher.open(&self.h, &mut buf, array_ref![tag, 0, TAG_LEN])?;
+                buf
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Returns the pair of transport keys: the first for messages from the
+    /// initiator, the second for messages from the responder.
+    pub fn split(&self) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+        noise_hkdf2(&self.ck, &[])
+    }
+}
+
+/// The outcome of a completed handshake: a cipher for each direction, and what
+/// is needed to derive Additional Symmetric Keys.
+pub struct Transport {
+    tx: CipherState,
+    rx: CipherState,
+    ck: Zeroizing<[u8; HASH_LEN]>,
+    h: [u8; HASH_LEN],
+    initiator: bool,
+    remote_static_key: [u8; 32],
+}
+
+impl Transport {
+    fn new(state: &NoiseSymmetricState, initiator: bool, remote_static_key: [u8; 32]) -> Self {
+        let (k_ab, k_ba) = state.split();
+        let (tx, rx) = if initiator {
+            (k_ab, k_ba)
+        } else {
+            (k_ba, k_ab)
+        };
+        Transport {
+            tx: CipherState::new(tx),
+            rx: CipherState::new(rx),
+            ck: state.ck.clone(),
+            h: state.h,
+            initiator,
+            remote_static_key,
+        }
+    }
+
+    pub fn handshake_hash(&self) -> &[u8; HASH_LEN] {
+        &self.h
+    }
+
+    /// The static key that the peer proved possession of during the handshake.
+    pub fn remote_static_key(&self) -> &[u8; 32] {
+        &self.remote_static_key
+    }
+
+    /// Derives the SipHash states that NTCP2 uses to obfuscate frame lengths,
+    /// from the Additional Symmetric Keys with the label "siphash". Returns the
+    /// states for (sending, receiving).
+    pub fn sip_states(&self) -> (SipState, SipState) {
+        let mut ask_master = Zeroizing::new([0; 32]);
+        hkdf::derive(&self.ck[..], &[], b"ask", &mut ask_master[..]);
+
+        let mut sip_master = Zeroizing::new([0; 32]);
+        let mut ikm = self.h.to_vec();
+        ikm.extend_from_slice(b"siphash");
+        hkdf::derive(&ask_master[..], &ikm, &[], &mut sip_master[..]);
+
+        let mut sipkeys = Zeroizing::new([0; 64]);
+        hkdf::derive(&sip_master[..], &[], &[], &mut sipkeys[..]);
+        let sip = |keys: &[u8; 32]| {
+            SipState::new(
+                u64::from_le_bytes(*array_ref![keys, 0, 8]),
+                u64::from_le_bytes(*array_ref![keys, 8, 8]),
+                u64::from_le_bytes(*array_ref![keys, 16, 8]),
+            )
+        };
+        let ab = sip(array_ref![sipkeys, 0, 32]);
+        let ba = sip(array_ref![sipkeys, 32, 32]);
+
+        if self.initiator {
+            (ab, ba)
+        } else {
+            (ba, ab)
+        }
+    }
+
+    /// Returns the ciphers for (sending, receiving).
+    pub fn into_ciphers(self) -> (CipherState, CipherState) {
+        (self.tx, self.rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NoiseSymmetricState;
+    use crate::crypto::{
+        chachapoly::{noise_nonce, seal_in_place},
+        dh::{x25519, x25519_base},
+        Error,
+    };
+    use crate::data::encoding::hex_decode;
+
+    fn key(start: u8) -> [u8; 32] {
+        let mut k = [0; 32];
+        for (i, b) in k.iter_mut().enumerate() {
+            *b = start + i as u8;
+        }
+        k
+    }
+
+    #[test]
+    fn protocol_name() {
+        // Names up to 32 bytes are zero-padded
+        let state = NoiseSymmetricState::new(b"Noise_NN_25519_ChaChaPoly_SHA256");
+        assert_eq!(state.handshake_hash(), b"Noise_NN_25519_ChaChaPoly_SHA256");
+        assert_eq!(state.chaining_key(), state.handshake_hash());
+
+        let state = NoiseSymmetricState::new(b"Noise_N");
+        assert_eq!(&state.handshake_hash()[..7], b"Noise_N");
+        assert!(state.handshake_hash()[7..].iter().all(|b| *b == 0));
+
+        // Longer names are hashed
+        let state = NoiseSymmetricState::new(b"Noise_XKaesobfse+hs2+hs3_25519_ChaChaPoly_SHA256");
+        assert_eq!(
+            &state.handshake_hash()[..],
+            &hex_decode("72e842c545e18080d39c4493bb91d7edf228981771218c1f624e206f28d32f71")
+                .unwrap()[..]
+        );
+    }
+
+    /// A Noise_XK_25519_ChaChaPoly_SHA256 handshake between two instances, checked
+    /// against a transcript generated with an independent implementation.
+    #[test]
+    fn xk_transcript() {
+        let name = b"Noise_XK_25519_ChaChaPoly_SHA256";
+        let (s_i, e_i, s_r, e_r) = (key(0), key(32), key(64), key(96));
+        let s_r_pub = x25519_base(&s_r);
+
+        let mut alice = NoiseSymmetricState::new(name);
+        let mut bob = NoiseSymmetricState::new(name);
+        for state in [&mut alice, &mut bob].iter_mut() {
+            // Empty prologue, then the pre-message <- s
+            state.mix_hash(&[]);
+            state.mix_hash(&s_r_pub);
+        }
+
+        // -> e, es
+        let mut msg1 = x25519_base(&e_i).to_vec();
+        alice.mix_hash(&msg1);
+        alice.mix_key(&x25519(&e_i, &s_r_pub).unwrap());
+        msg1.extend(alice.encrypt_and_hash(b"msg1"));
+        assert_eq!(
+            msg1,
+            hex_decode(
+                "358072d6365880d1aeea329adf9121383851ed21a28e3b75e965d0d2cd166254\
+                 38510c460dad56ca8ef0544ccbb7d224d12d7840"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            &alice.handshake_hash()[..],
+            &hex_decode("10e11d8e877cf7772bb809b2ac827d0e116b26cd345fc83932232ad3e50fee17")
+                .unwrap()[..]
+        );
+
+        let e_i_pub = *array_ref![msg1, 0, 32];
+        bob.mix_hash(&e_i_pub);
+        bob.mix_key(&x25519(&s_r, &e_i_pub).unwrap());
+        assert_eq!(bob.decrypt_and_hash(&msg1[32..]).unwrap(), b"msg1");
+
+        // <- e, ee
+        let mut msg2 = x25519_base(&e_r).to_vec();
+        bob.mix_hash(&msg2);
+        bob.mix_key(&x25519(&e_r, &e_i_pub).unwrap());
+        msg2.extend(bob.encrypt_and_hash(b"msg2"));
+        assert_eq!(
+            msg2,
+            hex_decode(
+                "675dd574ed7789310b3d2e7681f3790b466c773b1521fecf36577958371ea52f\
+                 c7591b7e751a539d6042cc50f9e02a86782d9c72"
+            )
+            .unwrap()
+        );
+
+        let e_r_pub = *array_ref![msg2, 0, 32];
+        alice.mix_hash(&e_r_pub);
+        alice.mix_key(&x25519(&e_i, &e_r_pub).unwrap());
+        assert_eq!(alice.decrypt_and_hash(&msg2[32..]).unwrap(), b"msg2");
+
+        // -> s, se
+        let mut msg3 = alice.encrypt_and_hash(&x25519_base(&s_i));
+        alice.mix_key(&x25519(&s_i, &e_r_pub).unwrap());
+        msg3.extend(alice.encrypt_and_hash(b"msg3"));
+        assert_eq!(
+            msg3,
+            hex_decode(
+                "ec2eb504958636c3c6ea92f55847f9bcae889163f9e2ae94c28c78761bc96a48\
+                 b22fc6c6c888f903f263a1904a5a4aed66fe54c980ed221ac240d34ddd24833d\
+                 5455c634"
+            )
+            .unwrap()
+        );
+
+        let s_i_pub = bob.decrypt_and_hash(&msg3[..48]).unwrap();
+        assert_eq!(s_i_pub, x25519_base(&s_i));
+        bob.mix_key(&x25519(&e_r, array_ref![s_i_pub, 0, 32]).unwrap());
+        assert_eq!(bob.decrypt_and_hash(&msg3[48..]).unwrap(), b"msg3");
+
+        // Both sides end with the same handshake hash and transport keys
+        assert_eq!(alice.handshake_hash(), bob.handshake_hash());
+        assert_eq!(
+            &alice.handshake_hash()[..],
+            &hex_decode("bf071995a34df5da19049a98015b75adc0573c31b9b1ae10918e65b0086d8404")
+                .unwrap()[..]
+        );
+
+        let (k1, k2) = alice.split();
+        assert_eq!((k1, k2), bob.split());
+        assert_eq!(
+            &k1[..],
+            &hex_decode("949e0739616d15237061da63191baf66c463b67b40d574147cfef627d9a1c58b")
+                .unwrap()[..]
+        );
+        assert_eq!(
+            &k2[..],
+            &hex_decode("4e5bfb9a2e5e952ad8d0fcb552c3422b991ac7f89243cec02e81d488c48b85d7")
+                .unwrap()[..]
+        );
+
+        let mut transport = b"hello".to_vec();
+        let tag = seal_in_place(&k1, &noise_nonce(0), &[], &mut transport);
+        transport.extend_from_slice(&tag);
+        assert_eq!(
+            transport,
+            hex_decode("b1fd3a1572aa642ce2d0afc093a042d4863d257475").unwrap()
+        );
+    }
+
+    #[test]
+    fn decrypt_failure_leaves_state() {
+        let mut alice = NoiseSymmetricState::new(b"Noise_NN_25519_ChaChaPoly_SHA256");
+        let mut bob = NoiseSymmetricState::new(b"Noise_NN_25519_ChaChaPoly_SHA256");
+
+        // Before a key is mixed in, payloads are only hashed
+        let ct = alice.encrypt_and_hash(b"plaintext");
+        assert_eq!(ct, b"plaintext");
+        assert_eq!(bob.decrypt_and_hash(&ct).unwrap(), b"plaintext");
+
+        alice.mix_key(b"shared secret");
+        bob.mix_key(b"shared secret");
+        let mut ct = alice.encrypt_and_hash(b"payload");
+
+        let h = *bob.handshake_hash();
+        ct[0] ^= 0x01;
+        assert_eq!(bob.decrypt_and_hash(&ct), Err(Error::InvalidCiphertext));
+        assert_eq!(
+            bob.decrypt_and_hash(&ct[..15]),
+            Err(Error::InvalidCiphertext)
+        );
+        assert_eq!(bob.handshake_hash(), &h);
+
+        ct[0] ^= 0x01;
+        assert_eq!(bob.decrypt_and_hash(&ct).unwrap(), b"payload");
+        assert_eq!(alice.handshake_hash(), bob.handshake_hash());
+    }
+}
@@ -0,0 +1,780 @@
+//! The Noise XK handshake, with the extensions I2P uses for NTCP2:
+//!
+//! - The ephemeral keys in messages 1 and 2 are obfuscated with AES-256-CBC,
+//!   keyed by the responder's router hash and published IV. The CBC chain
+//!   continues from message 1 into message 2.
+//!
+//! - Messages 1 and 2 may be followed by cleartext padding, which is mixed into
+//!   the handshake hash at the start of the next message.
+//!
+//! Each step consumes the current state and returns the next, so a handshake can
+//! only be driven in order. Framing is left to the caller: it reads the right
+//! number of bytes for each message, and parses the payloads.
+//!
+//! ```text
+//! <- s
+//! ...
+//! -> e, es     Initiator::write_session_request
+//!              ExpectSessionRequest::read_session_request
+//! <- e, ee     SendSessionCreated::write_session_created
+//!              ExpectSessionCreated::read_session_created
+//! -> s, se     SendSessionConfirmed::write_session_confirmed
+//!              ExpectSessionConfirmed::read_session_confirmed
+//! ```
+
+use zeroize::Zeroizing;
+
+use super::{NoiseSymmetricState, Transport};
+use crate::crypto::{
+    aes::SessionCipher,
+    chachapoly::TAG_LEN,
+    dh::{x25519, x25519_base},
+    rand::CryptoRng,
+    Error, SessionKey, AES_BLOCK_SIZE,
+};
+
+pub const KEY_LEN: usize = 32;
+
+/// The length of messages 1 and 2 excluding their payloads and padding.
+pub const EPHEMERAL_MSG_OVERHEAD: usize = KEY_LEN + TAG_LEN;
+
+/// The length of the encrypted static key at the start of message 3.
+pub const STATIC_KEY_CT_LEN: usize = KEY_LEN + TAG_LEN;
+
+fn generate_key<R: CryptoRng>(rng: &mut R) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    rng.fill_bytes(&mut key);
+    key
+}
+
+fn initialize(protocol_name: &[u8], responder_static_key: &[u8; KEY_LEN]) -> NoiseSymmetricState {
+    let mut state = NoiseSymmetricState::new(protocol_name);
+    // Empty prologue, then the pre-message "<- s"
+    state.mix_hash(&[]);
+    state.mix_hash(responder_static_key);
+    state
+}
+
+fn mix_padding(state: &mut NoiseSymmetricState, padding: &[u8]) {
+    if !padding.is_empty() {
+        state.mix_hash(padding);
+    }
+}
+
+/// Encrypts an ephemeral key, returning it along with the IV for the next message.
+fn obfuscate(
+    key: &SessionKey,
+    iv: &[u8; AES_BLOCK_SIZE],
+    ephemeral: &[u8; KEY_LEN],
+) -> ([u8; KEY_LEN], [u8; AES_BLOCK_SIZE]) {
+    let mut buf = *ephemeral;
+    SessionCipher::new(key, iv, iv)
+        .encrypt_blocks(&mut buf)
+        .expect("whole number of blocks");
+    (
+        buf,
+        *array_ref![buf, KEY_LEN - AES_BLOCK_SIZE, AES_BLOCK_SIZE],
+    )
+}
+
+/// Decrypts an ephemeral key, returning it along with the IV for the next message.
+fn deobfuscate(
+    key: &SessionKey,
+    iv: &[u8; AES_BLOCK_SIZE],
+    obfuscated: &[u8; KEY_LEN],
+) -> ([u8; KEY_LEN], [u8; AES_BLOCK_SIZE]) {
+    let mut buf = *obfuscated;
+    SessionCipher::new(key, iv, iv)
+        .decrypt_blocks(&mut buf)
+        .expect("whole number of blocks");
+    (
+        buf,
+        *array_ref![obfuscated, KEY_LEN - AES_BLOCK_SIZE, AES_BLOCK_SIZE],
+    )
+}
+
+//
+// Initiator
+//
+
+pub struct Initiator {
+    state: NoiseSymmetricState,
+    s: Zeroizing<[u8; KEY_LEN]>,
+    e: Zeroizing<[u8; KEY_LEN]>,
+    rs: [u8; KEY_LEN],
+    aes_key: SessionKey,
+    aes_iv: [u8; AES_BLOCK_SIZE],
+}
+
+impl Initiator {
+    /// Starts a handshake with the responder that published the given static key,
+    /// obfuscation key and IV, generating an ephemeral key with `rng`.
+    pub fn new<R: CryptoRng>(
+        protocol_name: &[u8],
+        static_key: &[u8; KEY_LEN],
+        remote_static_key: &[u8; KEY_LEN],
+        aes_key: &[u8; 32],
+        aes_iv: &[u8; AES_BLOCK_SIZE],
+        rng: &mut R,
+    ) -> Self {
+        Self::with_ephemeral(
+            protocol_name,
+            static_key,
+            remote_static_key,
+            aes_key,
+            aes_iv,
+            generate_key(rng),
+        )
+    }
+
+    fn with_ephemeral(
+        protocol_name: &[u8],
+        static_key: &[u8; KEY_LEN],
+        remote_static_key: &[u8; KEY_LEN],
+        aes_key: &[u8; 32],
+        aes_iv: &[u8; AES_BLOCK_SIZE],
+        ephemeral_key: [u8; KEY_LEN],
+    ) -> Self {
+        Initiator {
+            state: initialize(protocol_name, remote_static_key),
+            s: Zeroizing::new(*static_key),
+            e: Zeroizing::new(ephemeral_key),
+            rs: *remote_static_key,
+            aes_key: SessionKey(*aes_key),
+            aes_iv: *aes_iv,
+        }
+    }
+
+    /// Writes message 1 (`-> e, es`) with the given payload, followed by the
+    /// given cleartext padding.
+    pub fn write_session_request(
+        mut self,
+        payload: &[u8],
+        padding: &[u8],
+    ) -> Result<(ExpectSessionCreated, Vec<u8>), Error> {
+        let e_pub = x25519_base(&self.e);
+        let (obfuscated, aes_iv) = obfuscate(&self.aes_key, &self.aes_iv, &e_pub);
+        self.state.mix_hash(&e_pub);
+        self.state.mix_key(&x25519(&self.e, &self.rs)?);
+
+        let mut msg = obfuscated.to_vec();
+        msg.extend(self.state.encrypt_and_hash(payload));
+        msg.extend_from_slice(padding);
+        mix_padding(&mut self.state, padding);
+
+        Ok((
+            ExpectSessionCreated {
+                state: self.state,
+                s: self.s,
+                e: self.e,
+                rs: self.rs,
+                aes_key: self.aes_key,
+                aes_iv,
+            },
+            msg,
+        ))
+    }
+}
+
+pub struct ExpectSessionCreated {
+    state: NoiseSymmetricState,
+    s: Zeroizing<[u8; KEY_LEN]>,
+    e: Zeroizing<[u8; KEY_LEN]>,
+    rs: [u8; KEY_LEN],
+    aes_key: SessionKey,
+    aes_iv: [u8; AES_BLOCK_SIZE],
+}
+
+impl ExpectSessionCreated {
+    /// Reads message 2 (`<- e, ee`), excluding its padding, and returns the
+    /// decrypted payload.
+    pub fn read_session_created(
+        mut self,
+        msg: &[u8],
+    ) -> Result<(SendSessionConfirmed, Vec<u8>), Error> {
+        if msg.len() < EPHEMERAL_MSG_OVERHEAD {
+            return Err(Error::InvalidMessage);
+        }
+
+        let (re, _) = deobfuscate(&self.aes_key, &self.aes_iv, array_ref![msg, 0, KEY_LEN]);
+        self.state.mix_hash(&re);
+        self.state.mix_key(&x25519(&self.e, &re)?);
+        let payload = self.state.decrypt_and_hash(&msg[KEY_LEN..])?;
+
+        Ok((
+            SendSessionConfirmed {
+                state: self.state,
+                s: self.s,
+                re,
+                rs: self.rs,
+            },
+            payload,
+        ))
+    }
+}
+
+pub struct SendSessionConfirmed {
+    state: NoiseSymmetricState,
+    s: Zeroizing<[u8; KEY_LEN]>,
+    re: [u8; KEY_LEN],
+    rs: [u8; KEY_LEN],
+}
+
+impl SendSessionConfirmed {
+    /// Writes message 3 (`-> s, se`) with the given payload, after mixing in the
+    /// padding that followed message 2. This completes the handshake.
+    pub fn write_session_confirmed(
+        mut self,
+        created_padding: &[u8],
+        payload: &[u8],
+    ) -> Result<(Vec<u8>, Transport), Error> {
+        mix_padding(&mut self.state, created_padding);
+
+        let mut msg = self.state.encrypt_and_hash(&x25519_base(&self.s));
+        self.state.mix_key(&x25519(&self.s, &self.re)?);
+        msg.extend(self.state.encrypt_and_hash(payload));
+
+        Ok((msg, Transport::new(&self.state, true, self.rs)))
+    }
+}
+
+//
+// Responder
+//
+
+pub struct ExpectSessionRequest {
+    state: NoiseSymmetricState,
+    s: Zeroizing<[u8; KEY_LEN]>,
+    e: Zeroizing<[u8; KEY_LEN]>,
+    aes_key: SessionKey,
+    aes_iv: [u8; AES_BLOCK_SIZE],
+}
+
+impl ExpectSessionRequest {
+    /// Waits for a handshake using our static key, obfuscation key and IV,
+    /// generating an ephemeral key with `rng`.
+    pub fn new<R: CryptoRng>(
+        protocol_name: &[u8],
+        static_key: &[u8; KEY_LEN],
+        aes_key: &[u8; 32],
+        aes_iv: &[u8; AES_BLOCK_SIZE],
+        rng: &mut R,
+    ) -> Self {
+        Self::with_ephemeral(
+            protocol_name,
+            static_key,
+            aes_key,
+            aes_iv,
+            generate_key(rng),
+        )
+    }
+
+    fn with_ephemeral(
+        protocol_name: &[u8],
+        static_key: &[u8; KEY_LEN],
+        aes_key: &[u8; 32],
+        aes_iv: &[u8; AES_BLOCK_SIZE],
+        ephemeral_key: [u8; KEY_LEN],
+    ) -> Self {
+        ExpectSessionRequest {
+            state: initialize(protocol_name, &x25519_base(static_key)),
+            s: Zeroizing::new(*static_key),
+            e: Zeroizing::new(ephemeral_key),
+            aes_key: SessionKey(*aes_key),
+            aes_iv: *aes_iv,
+        }
+    }
+
+    /// Reads message 1 (`-> e, es`), excluding its padding, and returns the
+    /// decrypted payload.
+    pub fn read_session_request(
+        mut self,
+        msg: &[u8],
+    ) -> Result<(SendSessionCreated, Vec<u8>), Error> {
+        if msg.len() < EPHEMERAL_MSG_OVERHEAD {
+            return Err(Error::InvalidMessage);
+        }
+
+        let (re, aes_iv) = deobfuscate(&self.aes_key, &self.aes_iv, array_ref![msg, 0, KEY_LEN]);
+        self.state.mix_hash(&re);
+        self.state.mix_key(&x25519(&self.s, &re)?);
+        let payload = self.state.decrypt_and_hash(&msg[KEY_LEN..])?;
+
+        Ok((
+            SendSessionCreated {
+                state: self.state,
+                e: self.e,
+                re,
+                aes_key: self.aes_key,
+                aes_iv,
+            },
+            payload,
+        ))
+    }
+}
+
+pub struct SendSessionCreated {
+    state: NoiseSymmetricState,
+    e: Zeroizing<[u8; KEY_LEN]>,
+    re: [u8; KEY_LEN],
+    aes_key: SessionKey,
+    aes_iv: [u8; AES_BLOCK_SIZE],
+}
+
+impl SendSessionCreated {
+    /// Writes message 2 (`<- e, ee`) with the given payload, followed by the given
+    /// cleartext padding. The padding that followed message 1 is mixed in first.
+    pub fn write_session_created(
+        mut self,
+        request_padding: &[u8],
+        payload: &[u8],
+        padding: &[u8],
+    ) -> Result<(ExpectSessionConfirmed, Vec<u8>), Error> {
+        mix_padding(&mut self.state, request_padding);
+
+        let e_pub = x25519_base(&self.e);
+        let (obfuscated, _) = obfuscate(&self.aes_key, &self.aes_iv, &e_pub);
+        self.state.mix_hash(&e_pub);
+        self.state.mix_key(&x25519(&self.e, &self.re)?);
+
+        let mut msg = obfuscated.to_vec();
+        msg.extend(self.state.encrypt_and_hash(payload));
+        msg.extend_from_slice(padding);
+        mix_padding(&mut self.state, padding);
+
+        Ok((
+            ExpectSessionConfirmed {
+                state: self.state,
+                e: self.e,
+            },
+            msg,
+        ))
+    }
+}
+
+pub struct ExpectSessionConfirmed {
+    state: NoiseSymmetricState,
+    e: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl ExpectSessionConfirmed {
+    /// Reads message 3 (`-> s, se`) and returns the decrypted payload. This
+    /// completes the handshake; the initiator's static key is available from the
+    /// returned [`Transport`].
+    pub fn read_session_confirmed(mut self, msg: &[u8]) -> Result<(Transport, Vec<u8>), Error> {
+        if msg.len() < STATIC_KEY_CT_LEN + TAG_LEN {
+            return Err(Error::InvalidMessage);
+        }
+
+        let rs = self.state.decrypt_and_hash(&msg[..STATIC_KEY_CT_LEN])?;
+        let rs = *array_ref![rs, 0, KEY_LEN];
+        self.state.mix_key(&x25519(&self.e, &rs)?);
+        let payload = self.state.decrypt_and_hash(&msg[STATIC_KEY_CT_LEN..])?;
+
+        Ok((Transport::new(&self.state, false, rs), payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use i2p_snow::Builder;
+    use std::str;
+
+    use super::{ExpectSessionRequest, Initiator, EPHEMERAL_MSG_OVERHEAD};
+    use crate::crypto::{
+        dh::x25519_base,
+        rand::{OsRng, TestRng},
+        siphash::SipState,
+        Error,
+    };
+    use crate::data::encoding::hex_decode;
+
+    const PROTOCOL_NAME: &[u8] = b"Noise_XKaesobfse+hs2+hs3_25519_ChaChaPoly_SHA256";
+
+    fn key(start: u8) -> [u8; 32] {
+        let mut k = [0; 32];
+        for (i, b) in k.iter_mut().enumerate() {
+            *b = start + i as u8;
+        }
+        k
+    }
+
+    fn parties() -> (Initiator, ExpectSessionRequest) {
+        let (s_i, e_i, s_r, e_r) = (key(0), key(32), key(64), key(96));
+        let aes_key = key(128);
+        let aes_iv = *array_ref![key(160), 0, 16];
+
+        let alice = Initiator::with_ephemeral(
+            PROTOCOL_NAME,
+            &s_i,
+            &x25519_base(&s_r),
+            &aes_key,
+            &aes_iv,
+            e_i,
+        );
+        let bob = ExpectSessionRequest::with_ephemeral(PROTOCOL_NAME, &s_r, &aes_key, &aes_iv, e_r);
+        (alice, bob)
+    }
+
+    fn assert_same_masks(mut a: SipState, mut b: SipState) {
+        for _ in 0..4 {
+            assert_eq!(a.next_mask(), b.next_mask());
+        }
+    }
+
+    /// A full handshake with fixed keys, checked against a transcript generated with
+    /// an independent implementation of the NTCP2 specification.
+    #[test]
+    fn ntcp2_transcript() {
+        let (alice, bob) = parties();
+        let (pad_1, pad_2) = ([0xaa; 5], [0xbb; 3]);
+
+        // -> e, es
+        let (alice, msg_1) = alice
+            .write_session_request(b"session request!", &pad_1)
+            .unwrap();
+        assert_eq!(
+            msg_1,
+            hex_decode(
+                "ea057278133fe110aff47fad7e2ba8ffab11350308f894d45cf95cb051bc02fa\
+                 9f97bfb417289c1c1f9b1e7fec27d48197b7a838629890312e36d71694191441\
+                 aaaaaaaaaa"
+            )
+            .unwrap()
+        );
+        let (bob, payload) = bob
+            .read_session_request(&msg_1[..EPHEMERAL_MSG_OVERHEAD + 16])
+            .unwrap();
+        assert_eq!(payload, b"session request!");
+
+        // <- e, ee
+        let (bob, msg_2) = bob
+            .write_session_created(&pad_1, b"session created!", &pad_2)
+            .unwrap();
+        assert_eq!(
+            msg_2,
+            hex_decode(
+                "766e5349212b68cc7178d1c9dd6cc138702ba928e4a44de121d51fb734e35a96\
+                 552554cfcdc6deaaf633a5c08694f96f179845c18ebf1426b58621f146d5e204\
+                 bbbbbb"
+            )
+            .unwrap()
+        );
+        let (alice, payload) = alice
+            .read_session_created(&msg_2[..EPHEMERAL_MSG_OVERHEAD + 16])
+            .unwrap();
+        assert_eq!(payload, b"session created!");
+
+        // -> s, se
+        let (msg_3, alice) = alice
+            .write_session_confirmed(&pad_2, b"session confirmed")
+            .unwrap();
+        assert_eq!(
+            msg_3,
+            hex_decode(
+                "c4c2eaa9e250a810943ac70f411d9fc94fdc1bc1f0420e3ee35baf02ba5244ff\
+                 e336a694eb4df6be4533524ae44d647ece169091993e6e8c44ccc108d4612397\
+                 898c120c190f0fdd2da0a28bb33ae0f186"
+            )
+            .unwrap()
+        );
+        let (bob, payload) = bob.read_session_confirmed(&msg_3).unwrap();
+        assert_eq!(payload, b"session confirmed");
+
+        // Both sides agree on the handshake, and on each other's static keys
+        let h =
+            hex_decode("8624a75d400c4cbd132bbe31bee5d5c0c76ac9543c0e64c5ed1c43d1012e753c").unwrap();
+        assert_eq!(&alice.handshake_hash()[..], &h[..]);
+        assert_eq!(&bob.handshake_hash()[..], &h[..]);
+        assert_eq!(alice.remote_static_key(), &x25519_base(&key(64)));
+        assert_eq!(bob.remote_static_key(), &x25519_base(&key(0)));
+
+        // SipHash keys for each direction
+        let sip_ab = || {
+            SipState::new(
+                0xb27a_452e_7e68_9f26,
+                0xe991_2968_25bb_ce1f,
+                0x1d61_77bc_5ba4_ec06,
+            )
+        };
+        let sip_ba = || {
+            SipState::new(
+                0x87f1_a71e_d8b4_d585,
+                0x3f81_bbfe_fe26_97a6,
+                0x1c9a_f6cd_05ea_893b,
+            )
+        };
+        let (alice_tx, alice_rx) = alice.sip_states();
+        let (bob_tx, bob_rx) = bob.sip_states();
+        assert_same_masks(alice_tx, sip_ab());
+        assert_same_masks(bob_rx, sip_ab());
+        assert_same_masks(bob_tx, sip_ba());
+        assert_same_masks(alice_rx, sip_ba());
+
+        // Data phase keys
+        let (mut alice_tx, mut alice_rx) = alice.into_ciphers();
+        let (mut bob_tx, mut bob_rx) = bob.into_ciphers();
+
+        let mut buf = *b"hello";
+        let tag = alice_tx.seal(&[], &mut buf);
+        assert_eq!(
+            [&buf[..], &tag[..]].concat(),
+            hex_decode("0f9482de249ad3a5517e355378e09d6ed0dc128ece").unwrap()
+        );
+        bob_rx.open(&[], &mut buf, &tag).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut buf = *b"world";
+        let tag = bob_tx.seal(&[], &mut buf);
+        alice_rx.open(&[], &mut buf, &tag).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    /// Ephemeral keys drawn from a seeded RNG give a fixed transcript, which was
+    /// generated with an independent implementation of the NTCP2 specification.
+    #[test]
+    fn seeded_rng_transcript() {
+        let aes_key = key(128);
+        let aes_iv = *array_ref![key(160), 0, 16];
+        let alice = Initiator::new(
+            PROTOCOL_NAME,
+            &key(0),
+            &x25519_base(&key(64)),
+            &aes_key,
+            &aes_iv,
+            &mut TestRng::from_seed([1; 32]),
+        );
+        let bob = ExpectSessionRequest::new(
+            PROTOCOL_NAME,
+            &key(64),
+            &aes_key,
+            &aes_iv,
+            &mut TestRng::from_seed([2; 32]),
+        );
+
+        let (alice, msg_1) = alice
+            .write_session_request(b"session request!", &[])
+            .unwrap();
+        assert_eq!(
+            msg_1,
+            hex_decode(
+                "68d6323fbfe82edd580f25baf74e21c8d6aab738063380c74652fc326b506423\
+                 bd7f3d2d8b43a178b348e24b92e84a1b58af3e47bb26f8fe084fc7b23374cd25"
+            )
+            .unwrap()
+        );
+        let (bob, _) = bob.read_session_request(&msg_1).unwrap();
+
+        let (bob, msg_2) = bob
+            .write_session_created(&[], b"session created!", &[])
+            .unwrap();
+        assert_eq!(
+            msg_2,
+            hex_decode(
+                "196817b79496fc7b29dce1555c5af1ab5ebc3a5e7fb411fe320b1d3d2005c4b7\
+                 6624db44c876b0262d069ddf0ce6c2da130c719810baabaae3208e0639a769e5"
+            )
+            .unwrap()
+        );
+        let (alice, _) = alice.read_session_created(&msg_2).unwrap();
+
+        let (msg_3, alice) = alice
+            .write_session_confirmed(&[], b"session confirmed")
+            .unwrap();
+        assert_eq!(
+            msg_3,
+            hex_decode(
+                "2cbbf4350c19f9759fcd97f245eeaf587ff49bad1e2c81574da12729b4e3bc01\
+                 65b372bc70486c83cd5c9122011e9c95b3f49fcde8df9213c989673f1f4902e4\
+                 c27547903d7d0bd148e99fd853ced23c6d"
+            )
+            .unwrap()
+        );
+        let (bob, payload) = bob.read_session_confirmed(&msg_3).unwrap();
+        assert_eq!(payload, b"session confirmed");
+
+        let h =
+            hex_decode("ebe7a45a43675d32595efd70c4b05bf71f1c5d731cd8f5de1d0290ec563a6e30").unwrap();
+        assert_eq!(&alice.handshake_hash()[..], &h[..]);
+        assert_eq!(&bob.handshake_hash()[..], &h[..]);
+    }
+
+    /// Handshakes in both directions with i2p_snow, which NTCP2 previously used.
+    #[test]
+    fn i2p_snow_interop() {
+        let name = str::from_utf8(PROTOCOL_NAME).unwrap();
+        let (s_i, s_r) = (key(0), key(64));
+        let aes_key = key(128);
+        let aes_iv = *array_ref![key(160), 0, 16];
+        let sip = |ask: &[u8]| {
+            SipState::new(
+                u64::from_le_bytes(*array_ref![ask, 0, 8]),
+                u64::from_le_bytes(*array_ref![ask, 8, 8]),
+                u64::from_le_bytes(*array_ref![ask, 16, 8]),
+            )
+        };
+        let label = String::from("siphash");
+
+        // i2p_snow initiator, our responder
+        let mut alice = Builder::new(name.parse().unwrap())
+            .local_private_key(&s_i)
+            .remote_public_key(&x25519_base(&s_r))
+            .aesobfse(&aes_key, &aes_iv)
+            .enable_ask()
+            .build_initiator()
+            .unwrap();
+        let bob = ExpectSessionRequest::new(PROTOCOL_NAME, &s_r, &aes_key, &aes_iv, &mut OsRng);
+
+        let mut msg_1 = [0; EPHEMERAL_MSG_OVERHEAD + 16];
+        alice.write_message(&[1; 16], &mut msg_1).unwrap();
+        alice.set_h_data(2, &[0xaa; 5]).unwrap();
+        let (bob, payload) = bob.read_session_request(&msg_1).unwrap();
+        assert_eq!(payload, [1; 16]);
+
+        let (bob, msg_2) = bob
+            .write_session_created(&[0xaa; 5], &[2; 16], &[0xbb; 3])
+            .unwrap();
+        let mut payload = [0; 16];
+        alice
+            .read_message(&msg_2[..EPHEMERAL_MSG_OVERHEAD + 16], &mut payload)
+            .unwrap();
+        assert_eq!(payload, [2; 16]);
+        alice.set_h_data(3, &[0xbb; 3]).unwrap();
+
+        let mut msg_3 = [0; 128];
+        let len = alice
+            .write_message(b"session confirmed", &mut msg_3)
+            .unwrap();
+        let (bob, payload) = bob.read_session_confirmed(&msg_3[..len]).unwrap();
+        assert_eq!(payload, b"session confirmed");
+        assert_eq!(bob.remote_static_key(), &x25519_base(&s_i));
+
+        alice.initialize_ask(vec![label.clone()]).unwrap();
+        let (ask0, ask1) = alice.finalize_ask(&label).unwrap();
+        let (bob_tx, bob_rx) = bob.sip_states();
+        assert_same_masks(bob_rx, sip(&ask0));
+        assert_same_masks(bob_tx, sip(&ask1));
+
+        let mut alice = alice.into_transport_mode().unwrap();
+        let (_, mut bob_rx) = bob.into_ciphers();
+        let mut msg = [0; 5 + 16];
+        alice.write_message(b"hello", &mut msg).unwrap();
+        let mut buf = *array_ref![msg, 0, 5];
+        bob_rx.open(&[], &mut buf, array_ref![msg, 5, 16]).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Our initiator, i2p_snow responder
+        let alice = Initiator::new(
+            PROTOCOL_NAME,
+            &s_i,
+            &x25519_base(&s_r),
+            &aes_key,
+            &aes_iv,
+            &mut OsRng,
+        );
+        let mut bob = Builder::new(name.parse().unwrap())
+            .local_private_key(&s_r)
+            .aesobfse(&aes_key, &aes_iv)
+            .enable_ask()
+            .build_responder()
+            .unwrap();
+
+        let (alice, msg_1) = alice.write_session_request(&[1; 16], &[0xaa; 5]).unwrap();
+        let mut payload = [0; 16];
+        bob.read_message(&msg_1[..EPHEMERAL_MSG_OVERHEAD + 16], &mut payload)
+            .unwrap();
+        assert_eq!(payload, [1; 16]);
+        bob.set_h_data(2, &[0xaa; 5]).unwrap();
+
+        let mut msg_2 = [0; EPHEMERAL_MSG_OVERHEAD + 16];
+        bob.write_message(&[2; 16], &mut msg_2).unwrap();
+        bob.set_h_data(3, &[0xbb; 3]).unwrap();
+        let (alice, payload) = alice.read_session_created(&msg_2).unwrap();
+        assert_eq!(payload, [2; 16]);
+
+        let (msg_3, alice) = alice
+            .write_session_confirmed(&[0xbb; 3], b"session confirmed")
+            .unwrap();
+        let mut payload = [0; 128];
+        let len = bob.read_message(&msg_3, &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"session confirmed");
+
+        bob.initialize_ask(vec![label.clone()]).unwrap();
+        let (ask0, ask1) = bob.finalize_ask(&label).unwrap();
+        let (alice_tx, alice_rx) = alice.sip_states();
+        assert_same_masks(alice_tx, sip(&ask0));
+        assert_same_masks(alice_rx, sip(&ask1));
+
+        let mut bob = bob.into_transport_mode().unwrap();
+        let (mut alice_tx, _) = alice.into_ciphers();
+        let mut buf = *b"hello";
+        let tag = alice_tx.seal(&[], &mut buf);
+        let mut payload = [0; 5];
+        bob.read_message(&[&buf[..], &tag[..]].concat(), &mut payload)
+            .unwrap();
+        assert_eq!(&payload, b"hello");
+    }
+
+    #[test]
+    fn empty_padding() {
+        let (alice, bob) = parties();
+
+        let (alice, msg_1) = alice.write_session_request(&[0; 16], &[]).unwrap();
+        let (bob, _) = bob.read_session_request(&msg_1).unwrap();
+        let (bob, msg_2) = bob.write_session_created(&[], &[0; 16], &[]).unwrap();
+        let (alice, _) = alice.read_session_created(&msg_2).unwrap();
+        let (msg_3, alice) = alice.write_session_confirmed(&[], &[]).unwrap();
+        let (bob, payload) = bob.read_session_confirmed(&msg_3).unwrap();
+
+        assert!(payload.is_empty());
+        assert_eq!(alice.handshake_hash(), bob.handshake_hash());
+    }
+
+    #[test]
+    fn padding_is_authenticated() {
+        let (alice, bob) = parties();
+
+        let (alice, msg_1) = alice.write_session_request(&[0; 16], &[1, 2, 3]).unwrap();
+        let (bob, _) = bob
+            .read_session_request(&msg_1[..EPHEMERAL_MSG_OVERHEAD + 16])
+            .unwrap();
+
+        // Bob sees different padding, so Alice can't decrypt his reply
+        let (_, msg_2) = bob
+            .write_session_created(&[1, 2, 4], &[0; 16], &[])
+            .unwrap();
+        assert!(matches!(
+            alice.read_session_created(&msg_2),
+            Err(Error::InvalidCiphertext)
+        ));
+    }
+
+    #[test]
+    fn wrong_obfuscation_key() {
+        let (alice, _) = parties();
+        let bob = ExpectSessionRequest::new(
+            PROTOCOL_NAME,
+            &key(64),
+            &key(129),
+            array_ref![key(160), 0, 16],
+            &mut OsRng,
+        );
+
+        let (_, msg_1) = alice.write_session_request(&[0; 16], &[]).unwrap();
+        assert!(matches!(
+            bob.read_session_request(&msg_1),
+            Err(Error::InvalidCiphertext) | Err(Error::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn truncated_messages() {
+        let (alice, bob) = parties();
+
+        let (_, msg_1) = alice.write_session_request(&[0; 16], &[]).unwrap();
+        assert!(matches!(
+            bob.read_session_request(&msg_1[..EPHEMERAL_MSG_OVERHEAD - 1]),
+            Err(Error::InvalidMessage)
+        ));
+    }
+}
@@ -0,0 +1,288 @@
+//! Implementation of ECDSA-SHA512-P521 signature verification.
+//!
+//! None of our ECDSA backends support P-521, so this implements the curve
+//! arithmetic directly, using Jacobian coordinates. It is not constant-time, and
+//! is only used for verification (which operates on public data).
+
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+use sha2::{Digest, Sha512};
+
+use super::math::rectify;
+
+const FIELD_BYTES: usize = 66;
+
+fn from_hex(hex: &str) -> BigUint {
+    BigUint::parse_bytes(hex.as_bytes(), 16).unwrap()
+}
+
+lazy_static! {
+    /// p = 2^521 - 1
+    static ref P: BigUint = (BigUint::one() << 521) - 1u32;
+    static ref B: BigUint = from_hex(
+        "0051953eb9618e1c9a1f929a21a0b68540eea2da725b99b315f3b8b489918ef109e156193951ec7e9\
+         37b1652c0bd3bb1bf073573df883d2c34f1ef451fd46b503f00",
+    );
+    /// The order of the base point.
+    static ref N: BigUint = from_hex(
+        "01fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffa51868783bf2f9\
+         66b7fcc0148f709a5d03bb5c9b8899c47aebb6fb71e91386409",
+    );
+    static ref NM2: BigUint = &(*N) - 2u32;
+    static ref PM2: BigUint = &(*P) - 2u32;
+    static ref G: JacobianPoint = JacobianPoint::from_affine(
+        from_hex(
+            "00c6858e06b70404e9cd9e3ecb662395b4429c648139053fb521f828af606b4d3dbaa14b5e77efe\
+             75928fe1dc127a2ffa8de3348b3c1856a429bf97e7e31c2e5bd66",
+        ),
+        from_hex(
+            "011839296a789a3bc0045c8a5fb42c7d1bd998f54449579b446817afbd17273e662c97ee72995ef\
+             42640c550b9013fad0761353c7086a272c24088be94769fd16650",
+        ),
+    );
+}
+
+fn add_p(a: &BigUint, b: &BigUint) -> BigUint {
+    (a + b) % &(*P)
+}
+
+fn sub_p(a: &BigUint, b: &BigUint) -> BigUint {
+    // a and b are always reduced
+    (a + &(*P) - b) % &(*P)
+}
+
+fn mul_p(a: &BigUint, b: &BigUint) -> BigUint {
+    (a * b) % &(*P)
+}
+
+/// A point (X : Y : Z) representing the affine point (X/Z^2, Y/Z^3). The point at
+/// infinity has Z = 0.
+#[derive(Clone)]
+struct JacobianPoint {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+}
+
+impl JacobianPoint {
+    fn identity() -> Self {
+        JacobianPoint {
+            x: BigUint::one(),
+            y: BigUint::one(),
+            z: BigUint::zero(),
+        }
+    }
+
+    fn from_affine(x: BigUint, y: BigUint) -> Self {
+        JacobianPoint {
+            x,
+            y,
+            z: BigUint::one(),
+        }
+    }
+
+    fn is_identity(&self) -> bool {
+        self.z.is_zero()
+    }
+
+    /// Returns the affine x-coordinate, or None for the point at infinity.
+    fn affine_x(&self) -> Option<BigUint> {
+        if self.is_identity() {
+            return None;
+        }
+        let z_inv = self.z.modpow(&PM2, &P);
+        Some(mul_p(&self.x, &mul_p(&z_inv, &z_inv)))
+    }
+
+    /// dbl-2001-b, for a = -3.
+    fn double(&self) -> Self {
+        if self.is_identity() || self.y.is_zero() {
+            return JacobianPoint::identity();
+        }
+
+        let delta = mul_p(&self.z, &self.z);
+        let gamma = mul_p(&self.y, &self.y);
+        let beta = mul_p(&self.x, &gamma);
+        let alpha = mul_p(&(sub_p(&self.x, &delta) * 3u32), &add_p(&self.x, &delta));
+
+        let x = sub_p(&mul_p(&alpha, &alpha), &((&beta * 8u32) % &(*P)));
+        let y_plus_z = add_p(&self.y, &self.z);
+        let z = sub_p(&sub_p(&mul_p(&y_plus_z, &y_plus_z), &gamma), &delta);
+        let y = sub_p(
+            &mul_p(&alpha, &sub_p(&((&beta * 4u32) % &(*P)), &x)),
+            &((mul_p(&gamma, &gamma) * 8u32) % &(*P)),
+        );
+        JacobianPoint { x, y, z }
+    }
+
+    /// add-2007-bl
+    fn add(&self, other: &Self) -> Self {
+        if self.is_identity() {
+            return other.clone();
+        }
+        if other.is_identity() {
+            return self.clone();
+        }
+
+        let z1z1 = mul_p(&self.z, &self.z);
+        let z2z2 = mul_p(&other.z, &other.z);
+        let u1 = mul_p(&self.x, &z2z2);
+        let u2 = mul_p(&other.x, &z1z1);
+        let s1 = mul_p(&self.y, &mul_p(&other.z, &z2z2));
+        let s2 = mul_p(&other.y, &mul_p(&self.z, &z1z1));
+
+        let h = sub_p(&u2, &u1);
+        let r = (sub_p(&s2, &s1) * 2u32) % &(*P);
+        if h.is_zero() {
+            return if r.is_zero() {
+                self.double()
+            } else {
+                JacobianPoint::identity()
+            };
+        }
+
+        let h2 = (&h * 2u32) % &(*P);
+        let i = mul_p(&h2, &h2);
+        let j = mul_p(&h, &i);
+        let v = mul_p(&u1, &i);
+
+        let x = sub_p(&sub_p(&mul_p(&r, &r), &j), &((&v * 2u32) % &(*P)));
+        let y = sub_p(&mul_p(&r, &sub_p(&v, &x)), &((mul_p(&s1, &j) * 2u32) % &(*P)));
+        let z1_plus_z2 = add_p(&self.z, &other.z);
+        let z = mul_p(&sub_p(&sub_p(&mul_p(&z1_plus_z2, &z1_plus_z2), &z1z1), &z2z2), &h);
+        JacobianPoint { x, y, z }
+    }
+}
+
+/// Computes [a]P + [b]Q using Shamir's trick.
+fn double_mul(a: &BigUint, p: &JacobianPoint, b: &BigUint, q: &JacobianPoint) -> JacobianPoint {
+    let pq = p.add(q);
+    let bits = a.bits().max(b.bits());
+    let mut acc = JacobianPoint::identity();
+    for i in (0..bits).rev() {
+        acc = acc.double();
+        match (a.bit(i), b.bit(i)) {
+            (true, true) => acc = acc.add(&pq),
+            (true, false) => acc = acc.add(p),
+            (false, true) => acc = acc.add(q),
+            (false, false) => (),
+        }
+    }
+    acc
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct P521Signature {
+    r: [u8; FIELD_BYTES],
+    s: [u8; FIELD_BYTES],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct P521PublicKey {
+    x: BigUint,
+    y: BigUint,
+    bytes: Vec<u8>,
+}
+
+impl P521Signature {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, super::Error> {
+        if data.len() != 2 * FIELD_BYTES {
+            return Err(super::Error::InvalidSignature);
+        }
+
+        let mut r = [0; FIELD_BYTES];
+        let mut s = [0; FIELD_BYTES];
+        r.copy_from_slice(&data[..FIELD_BYTES]);
+        s.copy_from_slice(&data[FIELD_BYTES..]);
+
+        Ok(P521Signature { r, s })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(2 * FIELD_BYTES);
+        data.extend_from_slice(&self.r);
+        data.extend_from_slice(&self.s);
+        data
+    }
+}
+
+impl P521PublicKey {
+    /// Parses an untagged point (x || y), checking that it is on the curve.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, super::Error> {
+        if data.len() != 2 * FIELD_BYTES {
+            return Err(super::Error::InvalidKey);
+        }
+
+        let x = BigUint::from_bytes_be(&data[..FIELD_BYTES]);
+        let y = BigUint::from_bytes_be(&data[FIELD_BYTES..]);
+        if x >= *P || y >= *P {
+            return Err(super::Error::InvalidKey);
+        }
+
+        // y^2 = x^3 - 3x + b
+        let rhs = sub_p(&add_p(&mul_p(&mul_p(&x, &x), &x), &B), &((&x * 3u32) % &(*P)));
+        if mul_p(&y, &y) != rhs {
+            return Err(super::Error::InvalidKey);
+        }
+
+        let mut bytes = rectify(&x, FIELD_BYTES);
+        bytes.extend(rectify(&y, FIELD_BYTES));
+        Ok(P521PublicKey { x, y, bytes })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    #[allow(clippy::many_single_char_names)]
+    pub fn verify(&self, msg: &[u8], sig: &P521Signature) -> bool {
+        let n = &(*N);
+
+        let r = BigUint::from_bytes_be(&sig.r);
+        let s = BigUint::from_bytes_be(&sig.s);
+
+        // Verify that 0 < r < n and 0 < s < n
+        if r.is_zero() || r >= *n || s.is_zero() || s >= *n {
+            return false;
+        }
+
+        // SHA-512 is shorter than n, so the hash is used without truncation
+        let e = BigUint::from_bytes_be(&Sha512::digest(msg));
+
+        // w = s^{-1} mod n = s^{n-2} mod n
+        let w = s.modpow(&NM2, n);
+        let u1 = e * &w % n;
+        let u2 = &r * &w % n;
+
+        let q = JacobianPoint::from_affine(self.x.clone(), self.y.clone());
+        match double_mul(&u1, &G, &u2, &q).affine_x() {
+            Some(x) => x % n == r,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_traits::Zero;
+
+    use super::{JacobianPoint, P521PublicKey, P521Signature, G, N};
+    use crate::crypto::Error;
+
+    #[test]
+    fn base_point_order() {
+        let n_g = super::double_mul(&N, &G, &Zero::zero(), &JacobianPoint::identity());
+        assert!(n_g.is_identity());
+    }
+
+    #[test]
+    fn invalid_encodings() {
+        assert_eq!(P521PublicKey::from_bytes(&[0; 131]), Err(Error::InvalidKey));
+        // (0, 0) is not on the curve
+        assert_eq!(P521PublicKey::from_bytes(&[0; 132]), Err(Error::InvalidKey));
+        assert_eq!(
+            P521Signature::from_bytes(&[0; 131]),
+            Err(Error::InvalidSignature)
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! Pools of precomputed values for asymmetric operations.
+//!
+//! Classic DH and ElGamal encryption both start with a 2048-bit modular
+//! exponentiation that does not depend on the peer. Java I2P keeps pools of
+//! these values filled in the background, and [`Precomputed`] does the same.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex, Weak,
+};
+use std::thread;
+use std::time::Duration;
+
+use super::{dh::DHSessionKeyBuilder, elgamal::Ephemeral};
+
+/// How often an idle refill thread checks whether its pool has been dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const DH_POOL_SIZE: usize = 40;
+const DH_REFILL_THRESHOLD: usize = 15;
+const ELGAMAL_POOL_SIZE: usize = 50;
+const ELGAMAL_REFILL_THRESHOLD: usize = 20;
+
+/// Hit and miss counters for a [`Precomputed`] pool.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Values that were taken from the pool.
+    pub hits: usize,
+    /// Values that had to be generated on demand because the pool was empty.
+    pub misses: usize,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)
+    }
+}
+
+struct Inner<T> {
+    name: &'static str,
+    items: Mutex<VecDeque<T>>,
+    drained: Condvar,
+    capacity: usize,
+    refill_threshold: usize,
+    generate: Box<dyn Fn() -> T + Send + Sync>,
+    started: AtomicBool,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<T> Inner<T> {
+    /// Generates values until the pool is at capacity. Values are generated
+    /// without holding the lock, so consumers are never blocked on a refill.
+    fn fill(&self) {
+        while self.items.lock().unwrap().len() < self.capacity {
+            let item = (self.generate)();
+            let mut items = self.items.lock().unwrap();
+            if items.len() < self.capacity {
+                items.push_back(item);
+            }
+            drop(items);
+            // Stay out of the way of more urgent work
+            thread::yield_now();
+        }
+    }
+}
+
+/// A bounded pool of precomputed values, refilled by a background thread
+/// whenever it drops to the refill threshold.
+///
+/// Handles are cheap to clone, and all clones share the same pool. The
+/// background thread exits once every handle has been dropped.
+pub struct Precomputed<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for Precomputed<T> {
+    fn clone(&self) -> Self {
+        Precomputed {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Precomputed<T> {
+    /// Creates an empty pool. Call [`Precomputed::start`] to begin filling it.
+    pub fn new<F>(name: &'static str, capacity: usize, refill_threshold: usize, generate: F) -> Self
+    where
+        F: Fn() -> T + Send + Sync + 'static,
+    {
+        assert!(refill_threshold < capacity);
+        Precomputed {
+            inner: Arc::new(Inner {
+                name,
+                items: Mutex::new(VecDeque::with_capacity(capacity)),
+                drained: Condvar::new(),
+                capacity,
+                refill_threshold,
+                generate: Box::new(generate),
+                started: AtomicBool::new(false),
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Spawns the background thread that keeps the pool filled. Subsequent
+    /// calls do nothing.
+    pub fn start(&self) -> std::io::Result<()> {
+        if self.inner.started.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let pool = Arc::downgrade(&self.inner);
+        thread::Builder::new()
+            .name(format!("{} precompute", self.inner.name))
+            .spawn(move || refill(pool))
+            .map(|_| ())
+    }
+
+    /// Fills the pool to capacity on the current thread.
+    pub fn fill(&self) {
+        self.inner.fill()
+    }
+
+    /// Takes a value from the pool, or generates one if the pool is empty.
+    pub fn take(&self) -> T {
+        let (item, remaining) = {
+            let mut items = self.inner.items.lock().unwrap();
+            let item = items.pop_front();
+            (item, items.len())
+        };
+
+        if remaining <= self.inner.refill_threshold {
+            self.inner.drained.notify_one();
+        }
+
+        match item {
+            Some(item) => {
+                self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                item
+            }
+            None => {
+                self.inner.misses.fetch_add(1, Ordering::Relaxed);
+                debug!("{} pool is empty, generating on demand", self.inner.name);
+                (self.inner.generate)()
+            }
+        }
+    }
+
+    /// Returns the number of values currently in the pool.
+    pub fn len(&self) -> usize {
+        self.inner.items.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn refill<T>(pool: Weak<Inner<T>>) {
+    while let Some(inner) = pool.upgrade() {
+        {
+            let items = inner.items.lock().unwrap();
+            if items.len() > inner.refill_threshold {
+                // Sleep until a consumer drains the pool, waking periodically
+                // to check whether the pool has been dropped.
+                let _ = inner.drained.wait_timeout(items, IDLE_TIMEOUT).unwrap();
+                continue;
+            }
+        }
+        inner.fill();
+        debug!("Refilled {} pool", inner.name);
+    }
+}
+
+/// The precomputation pools owned by the router.
+pub struct Pools {
+    /// DH keypairs for NTCP handshakes.
+    pub dh: Precomputed<DHSessionKeyBuilder>,
+    /// Ephemerals for ElGamal encryption of garlic messages and tunnel build
+    /// records.
+    pub elgamal: Precomputed<Ephemeral>,
+}
+
+impl Pools {
+    pub fn new() -> Self {
+        Pools {
+            dh: Precomputed::new(
+                "DH",
+                DH_POOL_SIZE,
+                DH_REFILL_THRESHOLD,
+                DHSessionKeyBuilder::new,
+            ),
+            elgamal: Precomputed::new(
+                "ElGamal",
+                ELGAMAL_POOL_SIZE,
+                ELGAMAL_REFILL_THRESHOLD,
+                Ephemeral::generate,
+            ),
+        }
+    }
+
+    /// Starts the background threads that fill the pools.
+    pub fn start(&self) -> std::io::Result<()> {
+        self.dh.start()?;
+        self.elgamal.start()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{PoolStats, Precomputed};
+
+    fn counting_pool(capacity: usize, threshold: usize) -> Precomputed<usize> {
+        let counter = Arc::new(AtomicUsize::new(0));
+        Precomputed::new("test", capacity, threshold, move || {
+            counter.fetch_add(1, Ordering::SeqCst)
+        })
+    }
+
+    fn wait_for_len(pool: &Precomputed<usize>, len: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.len() != len {
+            assert!(Instant::now() < deadline, "pool was not refilled");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn take_from_empty_pool() {
+        let pool = counting_pool(4, 1);
+        assert!(pool.is_empty());
+        assert_eq!(pool.take(), 0);
+        assert_eq!(pool.take(), 1);
+        assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 2 });
+    }
+
+    #[test]
+    fn fill_and_take() {
+        let pool = counting_pool(4, 1);
+        pool.fill();
+        assert_eq!(pool.len(), 4);
+
+        // Values are handed out in the order they were generated
+        for i in 0..4 {
+            assert_eq!(pool.take(), i);
+        }
+        assert_eq!(pool.stats(), PoolStats { hits: 4, misses: 0 });
+
+        assert_eq!(pool.take(), 4);
+        assert_eq!(pool.stats(), PoolStats { hits: 4, misses: 1 });
+    }
+
+    #[test]
+    fn background_refill() {
+        let pool = counting_pool(4, 1);
+        pool.start().unwrap();
+        wait_for_len(&pool, 4);
+
+        // Staying above the threshold does not trigger a refill
+        pool.take();
+        pool.take();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.len(), 2);
+
+        // Dropping to the threshold does
+        pool.take();
+        wait_for_len(&pool, 4);
+        assert_eq!(pool.stats().hits, 3);
+
+        // Starting twice is harmless
+        pool.start().unwrap();
+    }
+}
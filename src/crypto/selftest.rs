@@ -0,0 +1,396 @@
+//! Known-answer tests for the cryptographic primitives, run at router startup.
+//!
+//! A miscompiled or broken crypto backend rarely crashes; it silently corrupts
+//! data on the network instead. The router therefore checks every primitive it
+//! depends on against fixed vectors before binding any listeners. All of the
+//! vectors live in this module so that they can be audited in one place.
+
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use super::{
+    aes::SessionCipher,
+    chachapoly::{open_in_place, seal_in_place},
+    dh::{x25519, x25519_base},
+    elgamal::{Decryptor, Encryptor, KeyPairGenerator},
+    siphash::SipState,
+    Ed25519SigningKey, PrivateKey, PublicKey, SessionKey, AES_BLOCK_SIZE,
+};
+use crate::data::encoding::{b64_decode, hex_decode};
+
+/// The primitives covered by the self-test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    Sha256,
+    AesCbc,
+    ChaCha20Poly1305,
+    X25519,
+    Ed25519,
+    ElGamal,
+    SipHash,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for Primitive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Primitive::Sha256 => "SHA-256",
+            Primitive::AesCbc => "AES-256-CBC",
+            Primitive::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+            Primitive::X25519 => "X25519",
+            Primitive::Ed25519 => "Ed25519",
+            Primitive::ElGamal => "ElGamal",
+            Primitive::SipHash => "SipHash-2-4",
+        }
+        .fmt(f)
+    }
+}
+
+/// A failed self-test check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelfTestError {
+    pub primitive: Primitive,
+    pub check: &'static str,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} self-test failed: {}", self.primitive, self.check)
+    }
+}
+
+fn ensure(primitive: Primitive, check: &'static str, ok: bool) -> Result<(), SelfTestError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(SelfTestError { primitive, check })
+    }
+}
+
+fn hex(data: &str) -> Vec<u8> {
+    hex_decode(data).expect("self-test vectors are valid hex")
+}
+
+fn b64(data: &str) -> Vec<u8> {
+    b64_decode(data).expect("self-test vectors are valid base64")
+}
+
+#[derive(Clone, Copy)]
+struct Vectors {
+    /// FIPS 180-2, appendix B.1
+    sha256_msg: &'static [u8],
+    sha256_digest: &'static str,
+
+    /// NIST SP 800-38A, F.2.5 CBC-AES256.Encrypt
+    aes_key: &'static str,
+    aes_iv: &'static str,
+    aes_pt: &'static str,
+    aes_ct: &'static str,
+
+    /// RFC 8439, section 2.8.2
+    chacha_key: &'static str,
+    chacha_nonce: &'static str,
+    chacha_ad: &'static str,
+    chacha_pt: &'static [u8],
+    chacha_ct: &'static str,
+    chacha_tag: &'static str,
+
+    /// RFC 7748, section 6.1
+    x25519_alice_priv: &'static str,
+    x25519_alice_pub: &'static str,
+    x25519_bob_pub: &'static str,
+    x25519_shared: &'static str,
+
+    /// RFC 8032, section 7.1, TEST 2
+    ed25519_seed: &'static str,
+    ed25519_pub: &'static str,
+    ed25519_msg: &'static str,
+    ed25519_sig: &'static str,
+
+    /// From `core/java/test/junit/net/i2p/crypto/ElGamalTest.java` in Java I2P
+    elgamal_pub: &'static str,
+    elgamal_priv: &'static str,
+    elgamal_msg: &'static [u8],
+    elgamal_ct: &'static str,
+
+    /// SipHash reference vectors: the keys are the bytes 00..0f, and the first IV
+    /// is SipHash-2-4 of the 8-byte message 00..07. The later IVs chain from it.
+    siphash_k0: u64,
+    siphash_k1: u64,
+    siphash_masks: [u16; 4],
+}
+
+const VECTORS: Vectors = Vectors {
+    sha256_msg: b"abc",
+    sha256_digest: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+
+    aes_key: "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+    aes_iv: "000102030405060708090a0b0c0d0e0f",
+    aes_pt: "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+             30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710",
+    aes_ct: "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d\
+             39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b",
+
+    chacha_key: "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+    chacha_nonce: "070000004041424344454647",
+    chacha_ad: "50515253c0c1c2c3c4c5c6c7",
+    chacha_pt: b"Ladies and Gentlemen of the class of '99: If I could offer you \
+                 only one tip for the future, sunscreen would be it.",
+    chacha_ct: "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+                3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+                92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+                3ff4def08e4b7a9de576d26586cec64b6116",
+    chacha_tag: "1ae10b594f09e26a7e902ecbd0600691",
+
+    x25519_alice_priv: "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+    x25519_alice_pub: "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+    x25519_bob_pub: "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f",
+    x25519_shared: "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742",
+
+    ed25519_seed: "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+    ed25519_pub: "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+    ed25519_msg: "72",
+    ed25519_sig: "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                  085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+
+    elgamal_pub: "pOvBUMrSUUeN5awynzbPbCAwe3MqWprhSpp3OR7pvdfm9PhWaNbPoKRLeEmDoUwyNDoHE0\
+                  E6mcZSG8qPQ8XUZFlczpilOl0MJBvsI9u9SMyi~bEqzSgzh9FNfS-NcGji3q2wI~Ux~q5B\
+                  KOjGlyMLgd1nxl5R5wIYL4uHKZNaYuArsRYmtV~MgMQPGvDtIbdGTV6aL6UbOYryzQSUMY\
+                  OuO3S~YoBjA6Nmi0SeJM3tyTxlI6U1EYjR6oQcI4SOFUW4L~8pfYWijcncCODAqpXVN6ZI\
+                  AJ3a6vjxGu56IDp4xCcKlOEHgdXvqmEC67dR5qf2btH6dtWoB3-Z6QPsS6tPTQ==",
+    elgamal_priv: "gMlIhURVXU8uPube20Xr8E1K11g-3qZxOj1riThHqt-rBx72MPq5ivT1rr28cE9mzOmsXi\
+                   bbsuBuQKYDvF7hGICRB3ROSPePYhcupV3j7XiXUIYjWNw9hvylHXK~nTT7jkpIBazBJZfr\
+                   LJPcDZTDB0YnCOHOL-KFn4N1R5B22g0iYRABN~O10AUjQmf1epklAXPqYlzmOYeJSfTPBI\
+                   E44nEccWJp0M0KynhKVbDI0v9VYm6sPFK7WrzRyWwHL~r735wiRkwywuMmKJtA7-PuJjcW\
+                   NLkJwx6WScH2msMzhzYPi8JSZJBl~PosX934l-L0T-KNV4jg1Ih6yoCnm1748A==",
+    elgamal_msg: b"hello world",
+    elgamal_ct: "AIrd65mG1FJ~9J-DDSyhryVejJBSIjYOqV3GYmHDWgwLchTwq-bJS7dub3ENk9MZ-C6FIN\
+                 gjUFRaLBtfwJnySmNf8pIf1srmgdfqGV2h77ufG5Gs0jggKPmPV~7Z1kTcgsqpL8MyrfXr\
+                 Gi86X5ey-T0SZSFc0X1EhaE-47WlyWaGf-~xth6VOR~KG7clOxaOBpks-7WKZNQf7mpQRE\
+                 4IsPJyj5p1Rf-MeDbVKbK~52IfXSuUZQ8uZr34KMoy4chjn6e-jBhM4XuaQWhsM~a3Q-zE\
+                 pV-ea6t0bQTYfsbG9ch7pJuDPHM64o5mF9FS5-JGr7MOtfP7KDNHiYM2~-uC6BIAbiqBN8\
+                 WSLX1mrHVuhiM-hiJ7U4oq~HYB6N~U980sCIW0dgFBbhalzzQhJQSrC1DFDqGfL5-L25mj\
+                 ArP8dtvN0JY3LSnbcsm-pT9ttFHCPGomLfaAuP7ohknBoXK0j9e6~splg5sUA9TfLeBfqc\
+                 Lr0Sf8b3l~PvmrVkbVcaE8yUqSS6JFdt3pavjyyAQSmSlb2jVNKGPlrov5QLzlbH7G~AUv\
+                 IehsbGQX5ptRROtSojN~iYx3WQTOa-JLEC-AL7RbRu6B62p9I0pD0JgbUfCc4C4l9E9W~s\
+                 MuaJLAXxh0b2miF7C5bzZHxbt~MtZ7Ho5qpZMitXyoE3icb43B6Y1sbA==",
+
+    siphash_k0: 0x0706_0504_0302_0100,
+    siphash_k1: 0x0f0e_0d0c_0b0a_0908,
+    siphash_masks: [0x2462, 0x8f5e, 0xd8f2, 0x3756],
+};
+
+fn check_sha256(v: &Vectors) -> Result<(), SelfTestError> {
+    ensure(
+        Primitive::Sha256,
+        "digest mismatch",
+        Sha256::digest(v.sha256_msg).as_slice() == &hex(v.sha256_digest)[..],
+    )
+}
+
+fn check_aes_cbc(v: &Vectors) -> Result<(), SelfTestError> {
+    let mut key = [0; 32];
+    key.copy_from_slice(&hex(v.aes_key));
+    let mut iv = [0; AES_BLOCK_SIZE];
+    iv.copy_from_slice(&hex(v.aes_iv));
+    let pt = hex(v.aes_pt);
+    let ct = hex(v.aes_ct);
+    let mut cipher = SessionCipher::new(&SessionKey(key), &iv, &iv);
+
+    let mut buf = pt.clone();
+    let encrypted = cipher.encrypt_blocks(&mut buf).is_ok() && buf == ct;
+    ensure(Primitive::AesCbc, "encryption mismatch", encrypted)?;
+
+    let mut buf = ct;
+    let decrypted = cipher.decrypt_blocks(&mut buf).is_ok() && buf == pt;
+    ensure(Primitive::AesCbc, "decryption mismatch", decrypted)
+}
+
+fn check_chachapoly(v: &Vectors) -> Result<(), SelfTestError> {
+    let key = hex(v.chacha_key);
+    let nonce = hex(v.chacha_nonce);
+    let (key, nonce) = (array_ref![key, 0, 32], array_ref![nonce, 0, 12]);
+    let ad = hex(v.chacha_ad);
+    let ct = hex(v.chacha_ct);
+
+    let mut buf = v.chacha_pt.to_vec();
+    let tag = seal_in_place(key, nonce, &ad, &mut buf);
+    ensure(
+        Primitive::ChaCha20Poly1305,
+        "ciphertext mismatch",
+        buf == ct,
+    )?;
+    ensure(
+        Primitive::ChaCha20Poly1305,
+        "tag mismatch",
+        tag[..] == hex(v.chacha_tag)[..],
+    )?;
+
+    let opened = open_in_place(key, nonce, &ad, &mut buf, &tag).is_ok() && buf == v.chacha_pt;
+    ensure(Primitive::ChaCha20Poly1305, "decryption mismatch", opened)?;
+
+    let mut forged = tag;
+    forged[0] ^= 1;
+    ensure(
+        Primitive::ChaCha20Poly1305,
+        "forged tag accepted",
+        open_in_place(key, nonce, &ad, &mut buf, &forged).is_err(),
+    )
+}
+
+fn check_x25519(v: &Vectors) -> Result<(), SelfTestError> {
+    let alice_priv = hex(v.x25519_alice_priv);
+    let bob_pub = hex(v.x25519_bob_pub);
+    let (alice_priv, bob_pub) = (array_ref![alice_priv, 0, 32], array_ref![bob_pub, 0, 32]);
+
+    ensure(
+        Primitive::X25519,
+        "public key mismatch",
+        x25519_base(alice_priv)[..] == hex(v.x25519_alice_pub)[..],
+    )?;
+    ensure(
+        Primitive::X25519,
+        "shared secret mismatch",
+        x25519(alice_priv, bob_pub).map(|s| s[..] == hex(v.x25519_shared)[..]) == Ok(true),
+    )?;
+    ensure(
+        Primitive::X25519,
+        "small-order point accepted",
+        x25519(alice_priv, &[0; 32]).is_err(),
+    )
+}
+
+fn check_ed25519(v: &Vectors) -> Result<(), SelfTestError> {
+    let sk = Ed25519SigningKey::from_bytes(&hex(v.ed25519_seed)).map_err(|_| SelfTestError {
+        primitive: Primitive::Ed25519,
+        check: "invalid signing key",
+    })?;
+    let vk = sk.verifying_key();
+    ensure(
+        Primitive::Ed25519,
+        "public key mismatch",
+        vk.as_bytes() == &hex(v.ed25519_pub)[..],
+    )?;
+
+    let msg = hex(v.ed25519_msg);
+    let sig = sk.sign(&msg);
+    ensure(
+        Primitive::Ed25519,
+        "signature mismatch",
+        sig.to_bytes() == hex(v.ed25519_sig),
+    )?;
+    ensure(
+        Primitive::Ed25519,
+        "valid signature rejected",
+        vk.verify(&msg, &sig).is_ok(),
+    )?;
+    ensure(
+        Primitive::Ed25519,
+        "invalid signature accepted",
+        vk.verify(b"Not the signed message", &sig).is_err(),
+    )
+}
+
+fn check_elgamal(v: &Vectors) -> Result<(), SelfTestError> {
+    let mut priv_key = [0; 256];
+    priv_key.copy_from_slice(&b64(v.elgamal_priv));
+    let priv_key = PrivateKey(priv_key);
+    let mut pub_key = [0; 256];
+    pub_key.copy_from_slice(&b64(v.elgamal_pub));
+    let pub_key = PublicKey(pub_key);
+
+    ensure(
+        Primitive::ElGamal,
+        "public key mismatch",
+        KeyPairGenerator::public_key(&priv_key).0[..] == pub_key.0[..],
+    )?;
+
+    let dec = Decryptor::from(&priv_key);
+    ensure(
+        Primitive::ElGamal,
+        "decryption mismatch",
+        dec.decrypt(&b64(v.elgamal_ct), true).ok().as_deref() == Some(v.elgamal_msg),
+    )?;
+
+    let round_trip = Encryptor::from(&pub_key)
+        .encrypt(v.elgamal_msg, true)
+        .and_then(|ct| dec.decrypt(&ct, true));
+    ensure(
+        Primitive::ElGamal,
+        "round trip failed",
+        round_trip.ok().as_deref() == Some(v.elgamal_msg),
+    )
+}
+
+fn check_siphash(v: &Vectors) -> Result<(), SelfTestError> {
+    let mut state = SipState::new(v.siphash_k0, v.siphash_k1, v.siphash_k0);
+    ensure(
+        Primitive::SipHash,
+        "mask mismatch",
+        v.siphash_masks
+            .iter()
+            .all(|&mask| state.next_mask() == mask),
+    )
+}
+
+fn run(v: &Vectors) -> Result<(), SelfTestError> {
+    check_sha256(v)?;
+    check_aes_cbc(v)?;
+    check_chachapoly(v)?;
+    check_x25519(v)?;
+    check_ed25519(v)?;
+    check_elgamal(v)?;
+    check_siphash(v)
+}
+
+/// Checks every cryptographic primitive the router depends on against known
+/// answers, returning the first failure.
+pub fn self_test() -> Result<(), SelfTestError> {
+    run(&VECTORS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, self_test, Primitive, SelfTestError, VECTORS};
+
+    #[test]
+    fn vectors_pass() {
+        assert_eq!(self_test(), Ok(()));
+    }
+
+    #[test]
+    fn corrupted_vectors_are_detected() {
+        let fails = |v, primitive, check| {
+            assert_eq!(run(&v), Err(SelfTestError { primitive, check }));
+        };
+
+        let mut v = VECTORS;
+        v.sha256_msg = b"abd";
+        fails(v, Primitive::Sha256, "digest mismatch");
+
+        let mut v = VECTORS;
+        v.aes_iv = "000102030405060708090a0b0c0d0e0e";
+        fails(v, Primitive::AesCbc, "encryption mismatch");
+
+        let mut v = VECTORS;
+        v.chacha_tag = "1ae10b594f09e26a7e902ecbd0600690";
+        fails(v, Primitive::ChaCha20Poly1305, "tag mismatch");
+
+        let mut v = VECTORS;
+        v.x25519_shared = "4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161743";
+        fails(v, Primitive::X25519, "shared secret mismatch");
+
+        let mut v = VECTORS;
+        v.ed25519_msg = "73";
+        fails(v, Primitive::Ed25519, "signature mismatch");
+
+        let mut v = VECTORS;
+        v.elgamal_msg = b"hello world!";
+        fails(v, Primitive::ElGamal, "decryption mismatch");
+
+        let mut v = VECTORS;
+        v.siphash_masks[3] ^= 1;
+        fails(v, Primitive::SipHash, "mask mismatch");
+    }
+}
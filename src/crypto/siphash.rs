@@ -0,0 +1,72 @@
+//! SipHash-2-4 in the IV-chaining mode used for NTCP2 frame length obfuscation.
+//!
+//! Each direction of a session starts from keys and an IV derived from the final
+//! Noise chaining key. Before every frame, the IV is replaced by the SipHash of
+//! its little-endian encoding, and the low 16 bits of the new IV are XORed with
+//! the frame length.
+
+use siphasher::sip::SipHasher;
+use std::hash::Hasher;
+
+pub struct SipState {
+    hasher: SipHasher,
+    iv: u64,
+}
+
+impl SipState {
+    pub fn new(k0: u64, k1: u64, initial_iv: u64) -> Self {
+        SipState {
+            hasher: SipHasher::new_with_keys(k0, k1),
+            iv: initial_iv,
+        }
+    }
+
+    /// Advances the IV, and returns the mask for the next frame length.
+    pub fn next_mask(&mut self) -> u16 {
+        let mut hasher = self.hasher;
+        hasher.write_u64(self.iv);
+        self.iv = hasher.finish();
+        (self.iv & 0xffff) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SipState;
+
+    #[test]
+    fn mask_chain() {
+        // Keys and IV are the bytes 00..0f and 00..07, read as little-endian
+        // integers. The first IV is SipHash-2-4 of the 8-byte message 00..07 from the
+        // SipHash reference test vectors; the rest were generated with an
+        // independent implementation.
+        let k0 = 0x0706_0504_0302_0100;
+        let k1 = 0x0f0e_0d0c_0b0a_0908;
+        let mut state = SipState::new(k0, k1, k0);
+
+        let ivs = [
+            0x93f5_f579_9a93_2462,
+            0x3aed_95d6_90d0_8f5e,
+            0x5a38_bed4_acba_d8f2,
+            0x5f9d_a75f_82a1_3756,
+        ];
+        for &iv in ivs.iter() {
+            assert_eq!(state.next_mask(), (iv & 0xffff) as u16);
+            assert_eq!(state.iv, iv);
+        }
+    }
+
+    #[test]
+    fn directions_are_independent() {
+        let mut a = SipState::new(1, 2, 3);
+        let mut b = SipState::new(1, 2, 3);
+        let masks: Vec<_> = (0..10).map(|_| a.next_mask()).collect();
+        assert_eq!(masks, (0..10).map(|_| b.next_mask()).collect::<Vec<_>>());
+
+        // Different keys or IVs give different chains
+        let mut c = SipState::new(1, 2, 4);
+        let mut d = SipState::new(2, 1, 3);
+        assert_ne!(masks, (0..10).map(|_| c.next_mask()).collect::<Vec<_>>());
+        assert_ne!(masks, (0..10).map(|_| d.next_mask()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,166 @@
+//! Minimal X.509 parsing, for extracting the public keys of trusted signers.
+//!
+//! Only the SubjectPublicKeyInfo is read. The certificate's own signature and
+//! validity period are not checked, because signer certificates are bundled with
+//! the router and trusted explicitly.
+
+use data_encoding::BASE64;
+use nom::{
+    bytes::complete::take,
+    combinator::opt,
+    error::{Error as NomError, ErrorKind},
+    multi::count,
+    number::complete::be_u8,
+    Err, IResult,
+};
+
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_EXPLICIT_VERSION: u8 = 0xa0;
+
+/// rsaEncryption (1.2.840.113549.1.1.1)
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+/// id-Ed25519 (1.3.101.112)
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+#[derive(Debug, PartialEq)]
+pub(super) enum KeyAlgorithm {
+    Rsa,
+    Ed25519,
+}
+
+fn der_length(i: &[u8]) -> IResult<&[u8], usize> {
+    let (i, first) = be_u8(i)?;
+    if first < 0x80 {
+        return Ok((i, first as usize));
+    }
+
+    // Long form; we never need more than four length bytes
+    let len_len = (first & 0x7f) as usize;
+    if len_len == 0 || len_len > 4 {
+        return Err(Err::Error(NomError::new(i, ErrorKind::LengthValue)));
+    }
+    let (i, len) = take(len_len)(i)?;
+    Ok((i, len.iter().fold(0, |acc, &b| (acc << 8) | b as usize)))
+}
+
+/// Parses any DER element, returning its tag and contents.
+fn der_any(i: &[u8]) -> IResult<&[u8], (u8, &[u8])> {
+    let (i, tag) = be_u8(i)?;
+    let (i, len) = der_length(i)?;
+    let (i, contents) = take(len)(i)?;
+    Ok((i, (tag, contents)))
+}
+
+/// Parses a DER element with the given tag, returning its contents.
+fn der_tagged(tag: u8) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> {
+    move |i: &[u8]| {
+        let (rest, (t, contents)) = der_any(i)?;
+        if t == tag {
+            Ok((rest, contents))
+        } else {
+            Err(Err::Error(NomError::new(i, ErrorKind::Tag)))
+        }
+    }
+}
+
+fn subject_public_key_info(cert: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (_, cert) = der_tagged(TAG_SEQUENCE)(cert)?;
+    let (_, tbs_cert) = der_tagged(TAG_SEQUENCE)(cert)?;
+
+    // Skip the optional version, and then the serial number, signature algorithm,
+    // issuer, validity, and subject
+    let (i, _) = opt(der_tagged(TAG_EXPLICIT_VERSION))(tbs_cert)?;
+    let (i, _) = count(der_any, 5)(i)?;
+
+    let (_, spki) = der_tagged(TAG_SEQUENCE)(i)?;
+    let (i, algorithm) = der_tagged(TAG_SEQUENCE)(spki)?;
+    let (_, key) = der_tagged(TAG_BIT_STRING)(i)?;
+    let (_, oid) = der_tagged(TAG_OID)(algorithm)?;
+    Ok((i, (oid, key)))
+}
+
+/// Returns the algorithm and encoded public key of a DER-encoded certificate. RSA
+/// keys are returned in the RSAPublicKey format.
+pub(super) fn subject_public_key(cert: &[u8]) -> Option<(KeyAlgorithm, &[u8])> {
+    let (_, (oid, key)) = subject_public_key_info(cert).ok()?;
+
+    let algorithm = if oid == OID_RSA_ENCRYPTION {
+        KeyAlgorithm::Rsa
+    } else if oid == OID_ED25519 {
+        KeyAlgorithm::Ed25519
+    } else {
+        return None;
+    };
+
+    // The first byte of a BIT STRING is the number of unused bits in the last byte
+    match key.split_first() {
+        Some((0, key)) => Some((algorithm, key)),
+        _ => None,
+    }
+}
+
+/// Decodes a PEM-encoded certificate into DER.
+pub(crate) fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let b64: String = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with("-----BEGIN "))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END "))
+        .collect();
+    BASE64.decode(b64.as_bytes()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pem_to_der, subject_public_key, KeyAlgorithm};
+
+    #[test]
+    fn rsa_certificate() {
+        let cert = pem_to_der(include_str!(
+            "../../assets/certificates/reseed/meeh_at_mail.i2p.crt"
+        ))
+        .unwrap();
+        let (algorithm, key) = subject_public_key(&cert).unwrap();
+        assert_eq!(algorithm, KeyAlgorithm::Rsa);
+        assert_eq!(
+            key,
+            &include_bytes!("../../assets/certificates/reseed/meeh_at_mail.i2p.der")[..]
+        );
+    }
+
+    #[test]
+    fn ed25519_certificate() {
+        let cert = include_bytes!("../../assets/test-ed25519-signer.der");
+        let (algorithm, key) = subject_public_key(cert).unwrap();
+        assert_eq!(algorithm, KeyAlgorithm::Ed25519);
+        assert_eq!(
+            key,
+            &[
+                0xea, 0x4a, 0x6c, 0x63, 0xe2, 0x9c, 0x52, 0x0a, 0xbe, 0xf5, 0x50, 0x7b, 0x13, 0x2e,
+                0xc5, 0xf9, 0x95, 0x47, 0x76, 0xae, 0xbe, 0xbe, 0x7b, 0x92, 0x42, 0x1e, 0xea, 0x69,
+                0x14, 0x46, 0xd2, 0x2c,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn invalid_certificates() {
+        let cert = include_bytes!("../../assets/test-ed25519-signer.der");
+        assert_eq!(subject_public_key(&[]), None);
+        assert_eq!(subject_public_key(&cert[..cert.len() - 1]), None);
+        assert_eq!(subject_public_key(&cert[1..]), None);
+
+        // Unknown key algorithm (the OID also appears in the signature algorithms
+        // before and after the SubjectPublicKeyInfo)
+        let mut unknown = cert.to_vec();
+        let oids: Vec<_> = (0..unknown.len() - 4)
+            .filter(|&i| unknown[i..i + 5] == [0x06, 0x03, 0x2b, 0x65, 0x70])
+            .collect();
+        assert_eq!(oids.len(), 3);
+        unknown[oids[1] + 4] = 0x71;
+        assert_eq!(subject_public_key(&unknown), None);
+    }
+}
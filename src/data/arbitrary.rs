@@ -0,0 +1,535 @@
+//! Property-testing strategies for the common data structures.
+//!
+//! The structural strategies cover everything that we can parse and serialize,
+//! including legacy certificates, non-UTF-8 strings, and boundary dates, but
+//! make no attempt to look like real network data. [`peer`] instead generates
+//! RouterInfos for reachable routers that pass netDb validation, for use in the
+//! transport and netDb tests.
+
+use proptest::{
+    collection::{btree_map, vec},
+    prelude::*,
+    sample::select,
+};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use super::{
+    dest::{Destination, Lease, LeaseSet, MAX_LEASES},
+    Certificate, Hash, I2PDate, I2PString, KeyCertificate, Mapping, Padding, RouterAddress,
+    RouterAddressBuilder, RouterIdentity, RouterInfo, RouterInfoBuilder, TunnelId,
+    I2PSTRING_MAX_LEN,
+};
+use crate::crypto::{EncType, PublicKey, SigType, Signature, SigningPrivateKey, SigningPublicKey};
+
+/// The largest number of addresses and peers in a generated RouterInfo. This is
+/// below the parser limits, to keep the generated RouterInfos small.
+const MAX_GENERATED_ADDRESSES: usize = 4;
+const MAX_GENERATED_PEERS: usize = 2;
+
+/// The largest number of entries in a generated Mapping.
+const MAX_GENERATED_MAPPING_LEN: usize = 8;
+
+fn bytes(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=max_len)
+}
+
+fn ed25519_key(seed: &[u8; 32]) -> SigningPrivateKey {
+    SigningPrivateKey::from_bytes(SigType::Ed25519, seed).unwrap()
+}
+
+pub fn hash() -> impl Strategy<Value = Hash> {
+    any::<[u8; 32]>().prop_map(Hash)
+}
+
+/// Any valid date, with the unset and latest dates weighted up.
+pub fn i2p_date() -> impl Strategy<Value = I2PDate> {
+    prop_oneof![
+        Just(I2PDate::UNSET),
+        Just(I2PDate::MAX),
+        (0..=I2PDate::MAX.0).prop_map(I2PDate),
+    ]
+}
+
+/// Any string of valid length, which need not be UTF-8.
+pub fn i2p_string() -> impl Strategy<Value = I2PString> {
+    bytes(I2PSTRING_MAX_LEN).prop_map(I2PString)
+}
+
+/// A string that can be used as a key or value in a Mapping.
+fn mapping_string() -> impl Strategy<Value = I2PString> {
+    vec(
+        any::<u8>().prop_filter("Mapping separator", |&c| c != b'=' && c != b';'),
+        0..=I2PSTRING_MAX_LEN,
+    )
+    .prop_map(I2PString)
+}
+
+pub fn mapping() -> impl Strategy<Value = Mapping> {
+    btree_map(
+        mapping_string(),
+        mapping_string(),
+        0..=MAX_GENERATED_MAPPING_LEN,
+    )
+    .prop_map(Mapping)
+}
+
+pub fn sig_type() -> impl Strategy<Value = SigType> {
+    select(vec![
+        SigType::DsaSha1,
+        SigType::EcdsaSha256P256,
+        SigType::EcdsaSha384P384,
+        SigType::EcdsaSha512P521,
+        SigType::Rsa2048Sha256,
+        SigType::Rsa3072Sha384,
+        SigType::Rsa4096Sha512,
+        SigType::Ed25519,
+        SigType::RedDsaSha512Ed25519,
+    ])
+}
+
+pub fn enc_type() -> impl Strategy<Value = EncType> {
+    select(vec![EncType::ElGamal2048, EncType::X25519])
+}
+
+/// A KeyCertificate with the right amount of key data for its key types, and
+/// possibly some excess data.
+pub fn key_certificate() -> impl Strategy<Value = KeyCertificate> {
+    (sig_type(), enc_type(), bytes(16)).prop_flat_map(|(sig_type, enc_type, excess)| {
+        (
+            vec(any::<u8>(), sig_type.extra_data_len(enc_type)),
+            vec(any::<u8>(), enc_type.extra_data_len(sig_type)),
+        )
+            .prop_map(move |(sig_data, enc_data)| KeyCertificate {
+                sig_type,
+                enc_type,
+                sig_data,
+                enc_data,
+                excess: excess.clone(),
+            })
+    })
+}
+
+/// The certificates that don't affect the key layout of a KeysAndCert.
+fn legacy_certificate() -> impl Strategy<Value = Certificate> {
+    prop_oneof![
+        Just(Certificate::Null),
+        bytes(64).prop_map(Certificate::HashCash),
+        Just(Certificate::Hidden),
+        bytes(64).prop_map(Certificate::Signed),
+        bytes(64).prop_map(Certificate::Multiple),
+    ]
+}
+
+/// Any certificate, including those of unknown types.
+pub fn certificate() -> impl Strategy<Value = Certificate> {
+    prop_oneof![
+        legacy_certificate(),
+        key_certificate().prop_map(Certificate::Key),
+        (6u8..=255, bytes(64)).prop_map(|(code, payload)| Certificate::Unknown(code, payload)),
+    ]
+}
+
+fn elgamal_public_key() -> impl Strategy<Value = PublicKey> {
+    vec(any::<u8>(), 256).prop_map(|key| PublicKey(*array_ref![key, 0, 256]))
+}
+
+fn router_identity_from_parts(
+    public_key: PublicKey,
+    padding: Option<Padding>,
+    signing_key: SigningPublicKey,
+    certificate: Certificate,
+) -> RouterIdentity {
+    let mut rid = RouterIdentity {
+        public_key,
+        padding,
+        signing_key,
+        certificate,
+        hash: Hash([0; 32]),
+    };
+    rid.hash = Hash::digest(&rid.to_bytes());
+    rid
+}
+
+/// A RouterIdentity with an Ed25519 signing key, along with the seed for its
+/// private signing key.
+pub fn ed25519_router_identity() -> impl Strategy<Value = ([u8; 32], RouterIdentity)> {
+    let padding_len = SigType::Ed25519.pad_len(EncType::ElGamal2048);
+    (
+        any::<[u8; 32]>(),
+        elgamal_public_key(),
+        vec(any::<u8>(), padding_len),
+    )
+        .prop_map(|(seed, public_key, padding)| {
+            let signing_key = SigningPublicKey::from_secret(&ed25519_key(&seed)).unwrap();
+            let certificate = Certificate::Key(KeyCertificate {
+                sig_type: SigType::Ed25519,
+                enc_type: EncType::ElGamal2048,
+                sig_data: vec![],
+                enc_data: vec![],
+                excess: vec![],
+            });
+            let rid = router_identity_from_parts(
+                public_key,
+                Some(Padding(padding)),
+                signing_key,
+                certificate,
+            );
+            (seed, rid)
+        })
+}
+
+/// A RouterIdentity with a DSA signing key and a legacy certificate. We can't
+/// sign with DSA keys, so the key is arbitrary.
+fn dsa_router_identity() -> impl Strategy<Value = RouterIdentity> {
+    (
+        elgamal_public_key(),
+        vec(any::<u8>(), 128),
+        legacy_certificate(),
+    )
+        .prop_map(|(public_key, signing_key, certificate)| {
+            let signing_key = SigningPublicKey::from_bytes(SigType::DsaSha1, &signing_key).unwrap();
+            router_identity_from_parts(public_key, None, signing_key, certificate)
+        })
+}
+
+pub fn router_identity() -> impl Strategy<Value = RouterIdentity> {
+    prop_oneof![
+        ed25519_router_identity().prop_map(|(_, rid)| rid),
+        dsa_router_identity(),
+    ]
+}
+
+pub fn router_address() -> impl Strategy<Value = RouterAddress> {
+    (any::<u8>(), i2p_date(), i2p_string(), mapping()).prop_map(
+        |(cost, expiration, transport_style, options)| RouterAddress {
+            cost,
+            expiration,
+            transport_style,
+            options,
+        },
+    )
+}
+
+/// The published date, addresses, peers, and options of a RouterInfo.
+type RouterInfoContents = (I2PDate, Vec<RouterAddress>, Vec<Hash>, Mapping);
+
+fn router_info_contents() -> impl Strategy<Value = RouterInfoContents> {
+    (
+        i2p_date(),
+        vec(router_address(), 0..=MAX_GENERATED_ADDRESSES),
+        vec(hash(), 0..=MAX_GENERATED_PEERS),
+        mapping(),
+    )
+}
+
+/// A RouterInfo with arbitrary contents. RouterInfos with Ed25519 identities
+/// have valid signatures, and those with DSA identities have arbitrary ones.
+pub fn router_info() -> impl Strategy<Value = RouterInfo> {
+    prop_oneof![
+        (ed25519_router_identity(), router_info_contents()).prop_map(
+            |((seed, router_id), (published, addresses, peers, options))| {
+                let mut ri = RouterInfo {
+                    router_id,
+                    published,
+                    addresses,
+                    peers,
+                    options,
+                    signature: None,
+                };
+                ri.sign(&ed25519_key(&seed));
+                ri
+            }
+        ),
+        (
+            dsa_router_identity(),
+            router_info_contents(),
+            vec(any::<u8>(), SigType::DsaSha1.sig_len() as usize),
+        )
+            .prop_map(
+                |(router_id, (published, addresses, peers, options), signature)| RouterInfo {
+                    router_id,
+                    published,
+                    addresses,
+                    peers,
+                    options,
+                    signature: Some(Signature::from_bytes(SigType::DsaSha1, &signature).unwrap()),
+                }
+            ),
+    ]
+}
+
+/// A RouterInfo for a router with a single NTCP2 address on a public IPv4
+/// address, published now and signed with its Ed25519 key.
+///
+/// These pass netDb validation, and can be used wherever a realistic peer is
+/// needed.
+pub fn peer() -> impl Strategy<Value = RouterInfo> {
+    (
+        ed25519_router_identity(),
+        any::<[u8; 4]>().prop_filter("Public IPv4 address", |ip| {
+            let ip = Ipv4Addr::from(*ip);
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_unspecified()
+                || ip.is_multicast())
+        }),
+        1024u16..=u16::MAX,
+        any::<[u8; 32]>(),
+        any::<[u8; 16]>(),
+    )
+        .prop_map(|((seed, rid), ip, port, static_key, iv)| {
+            let addr = RouterAddressBuilder::new(&I2PString::new("NTCP2"))
+                .addr(SocketAddr::new(IpAddr::V4(ip.into()), port))
+                .ntcp2_static_key(&static_key)
+                .ntcp2_iv(&iv)
+                .version(&[2])
+                .build();
+            RouterInfoBuilder::new(rid)
+                .addresses(vec![addr])
+                .sign(&ed25519_key(&seed))
+        })
+}
+
+pub fn lease() -> impl Strategy<Value = Lease> {
+    (hash(), any::<u32>(), i2p_date()).prop_map(|(tunnel_gw, tid, end_date)| Lease {
+        tunnel_gw,
+        tid: TunnelId(tid),
+        end_date,
+    })
+}
+
+/// A LeaseSet for an Ed25519 Destination, signed with the Destination's key.
+pub fn lease_set() -> impl Strategy<Value = LeaseSet> {
+    (
+        ed25519_router_identity(),
+        elgamal_public_key(),
+        any::<[u8; 32]>(),
+        vec(lease(), 1..=MAX_LEASES),
+    )
+        .prop_map(|((seed, rid), enc_key, sig_seed, leases)| {
+            let dest = Destination {
+                public_key: rid.public_key,
+                padding: rid.padding,
+                signing_key: rid.signing_key,
+                certificate: rid.certificate,
+            };
+            let sig_key = SigningPublicKey::from_secret(&ed25519_key(&sig_seed)).unwrap();
+            let mut ls = LeaseSet {
+                dest,
+                enc_key,
+                sig_key,
+                leases,
+                signature: None,
+            };
+            ls.sign(&ed25519_key(&seed)).unwrap();
+            ls
+        })
+}
+
+macro_rules! impl_arbitrary {
+    ($($ty:ty => $strategy:expr,)+) => {
+        $(
+            impl Arbitrary for $ty {
+                type Parameters = ();
+                type Strategy = BoxedStrategy<Self>;
+
+                fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+                    $strategy.boxed()
+                }
+            }
+        )+
+    };
+}
+
+impl_arbitrary! {
+    Hash => hash(),
+    I2PDate => i2p_date(),
+    I2PString => i2p_string(),
+    Mapping => mapping(),
+    Certificate => certificate(),
+    RouterIdentity => router_identity(),
+    RouterAddress => router_address(),
+    RouterInfo => router_info(),
+    Lease => lease(),
+    LeaseSet => lease_set(),
+}
+
+#[cfg(test)]
+mod tests {
+    use cookie_factory::GenError;
+    use nom::IResult;
+    use proptest::{prelude::*, sample::Index};
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::data::{dest::frame as dest_frame, frame};
+    use crate::util::serialize;
+
+    /// Checks that `value` serializes to bytes that parse back to `value`, and
+    /// then serialize to the same bytes.
+    fn round_trip<T, G, P>(value: &T, gen: G, parse: P) -> Result<(), TestCaseError>
+    where
+        T: Debug + PartialEq,
+        G: for<'a> Fn((&'a mut [u8], usize), &T) -> Result<(&'a mut [u8], usize), GenError>,
+        P: for<'a> Fn(&'a [u8]) -> IResult<&'a [u8], T>,
+    {
+        let buf = serialize(|input| gen(input, value));
+        let (rest, parsed) = match parse(&buf) {
+            Ok(res) => res,
+            Err(e) => return Err(TestCaseError::fail(format!("{:?}", e))),
+        };
+        prop_assert!(rest.is_empty());
+        prop_assert_eq!(&parsed, value);
+        prop_assert_eq!(serialize(|input| gen(input, &parsed)), buf);
+        Ok(())
+    }
+
+    /// Checks that if `data` parses, the result serializes to the same number of
+    /// bytes that were consumed, and parses back to the same value.
+    ///
+    /// The bytes themselves may differ, because Mappings are re-serialized in
+    /// sorted order.
+    fn parses_cleanly<T, G, P>(data: &[u8], gen: G, parse: P) -> Result<(), TestCaseError>
+    where
+        T: Debug + PartialEq,
+        G: for<'a> Fn((&'a mut [u8], usize), &T) -> Result<(&'a mut [u8], usize), GenError>,
+        P: for<'a> Fn(&'a [u8]) -> IResult<&'a [u8], T>,
+    {
+        if let Ok((rest, parsed)) = parse(data) {
+            let buf = serialize(|input| gen(input, &parsed));
+            prop_assert_eq!(buf.len(), data.len() - rest.len());
+            match parse(&buf) {
+                Ok((rest, reparsed)) => {
+                    prop_assert!(rest.is_empty());
+                    prop_assert_eq!(reparsed, parsed);
+                }
+                Err(e) => return Err(TestCaseError::fail(format!("{:?}", e))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Either flips some bits of the byte at `index`, or truncates the data there.
+    fn mutate(mut data: Vec<u8>, index: Index, mask: u8, truncate: bool) -> Vec<u8> {
+        let i = index.index(data.len());
+        if truncate {
+            data.truncate(i);
+        } else {
+            data[i] ^= mask;
+        }
+        data
+    }
+
+    proptest! {
+        #[test]
+        fn hash_round_trip(h in hash()) {
+            round_trip(&h, frame::gen_hash, frame::hash)?;
+        }
+
+        #[test]
+        fn i2p_date_round_trip(date in i2p_date()) {
+            round_trip(&date, frame::gen_i2p_date, frame::i2p_date)?;
+        }
+
+        #[test]
+        fn i2p_string_round_trip(s in i2p_string()) {
+            round_trip(&s, frame::gen_i2p_string, frame::i2p_string)?;
+        }
+
+        #[test]
+        fn mapping_round_trip(m in mapping()) {
+            round_trip(&m, frame::gen_mapping, frame::mapping)?;
+        }
+
+        #[test]
+        fn certificate_round_trip(cert in certificate()) {
+            round_trip(&cert, frame::gen_certificate, frame::certificate)?;
+            prop_assert_eq!(Certificate::from_bytes(&cert.to_bytes()), Ok(cert));
+        }
+
+        #[test]
+        fn router_address_round_trip(addr in router_address()) {
+            round_trip(&addr, frame::gen_router_address, frame::router_address)?;
+        }
+
+        #[test]
+        fn mapping_mutations(
+            m in mapping(),
+            index in any::<Index>(),
+            mask in 1u8..=255,
+            truncate in any::<bool>(),
+        ) {
+            let data = serialize(|input| frame::gen_mapping(input, &m));
+            let data = mutate(data, index, mask, truncate);
+            parses_cleanly(&data, frame::gen_mapping, frame::mapping)?;
+        }
+
+        #[test]
+        fn certificate_mutations(
+            cert in certificate(),
+            index in any::<Index>(),
+            mask in 1u8..=255,
+            truncate in any::<bool>(),
+        ) {
+            let data = mutate(cert.to_bytes(), index, mask, truncate);
+            parses_cleanly(&data, frame::gen_certificate, frame::certificate)?;
+        }
+    }
+
+    proptest! {
+        // Each case signs a structure, so run fewer of them.
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn router_identity_round_trip(rid in router_identity()) {
+            round_trip(&rid, frame::gen_router_identity, frame::router_identity)?;
+            prop_assert_eq!(rid.hash(), Hash::digest(&rid.to_bytes()));
+        }
+
+        #[test]
+        fn router_info_round_trip(ri in router_info()) {
+            round_trip(&ri, frame::gen_router_info, frame::router_info)?;
+            if ri.router_id.signing_key().sig_type() == SigType::Ed25519 {
+                prop_assert_eq!(ri.verify(), Ok(()));
+            }
+        }
+
+        #[test]
+        fn lease_set_round_trip(ls in lease_set()) {
+            round_trip(&ls, dest_frame::gen_lease_set, dest_frame::lease_set)?;
+            prop_assert_eq!(ls.verify(), Ok(()));
+        }
+
+        #[test]
+        fn peer_is_valid(ri in peer()) {
+            prop_assert_eq!(ri.verify(), Ok(()));
+            prop_assert!(ri.addresses[0].addr().is_some());
+        }
+
+        #[test]
+        fn router_info_mutations(
+            ri in router_info(),
+            index in any::<Index>(),
+            mask in 1u8..=255,
+            truncate in any::<bool>(),
+        ) {
+            let data = mutate(ri.to_bytes(), index, mask, truncate);
+            parses_cleanly(&data, frame::gen_router_info, frame::router_info)?;
+        }
+
+        #[test]
+        fn lease_set_mutations(
+            ls in lease_set(),
+            index in any::<Index>(),
+            mask in 1u8..=255,
+            truncate in any::<bool>(),
+        ) {
+            let data = serialize(|input| dest_frame::gen_lease_set(input, &ls));
+            let data = mutate(data, index, mask, truncate);
+            parses_cleanly(&data, dest_frame::gen_lease_set, dest_frame::lease_set)?;
+        }
+    }
+}
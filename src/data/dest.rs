@@ -1,17 +1,46 @@
-use std::time::SystemTime;
+use rand::{rngs::OsRng, seq::SliceRandom};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
-use super::{cert_and_padding_from_keys, Certificate, Padding};
+use super::{cert_and_padding_from_keys, encryption_key_from_field, Certificate, Padding};
 use crate::crypto::{
-    self, elgamal, PrivateKey, PublicKey, Signature, SigningPrivateKey, SigningPublicKey,
+    self, blinding, DecryptionKey, EncType, PublicKey, SigType, Signature, SigningPrivateKey,
+    SigningPublicKey,
 };
-use crate::data::{Hash, I2PDate, TunnelId};
-use crate::util::serialize;
+use crate::data::{encoding, Hash, I2PDate, I2PString, Mapping, ReadError, TunnelId};
+use crate::util::{serialize, write_private_file};
 
 pub(crate) mod frame;
 
+const B32_SUFFIX: &str = ".b32.i2p";
+
+/// Errors that can occur while parsing a Destination address.
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+    /// The address is a valid b32 address, which only contains the hash of the
+    /// Destination. The full Destination needs to be looked up.
+    Base32(Hash),
+    InvalidEncoding,
+    InvalidDestination,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Base32(hash) => write!(f, "b32 address only contains a hash ({})", hash),
+            AddressError::InvalidEncoding => "Invalid address encoding".fmt(f),
+            AddressError::InvalidDestination => "Invalid Destination".fmt(f),
+        }
+    }
+}
+
 /// A Destination defines a particular endpoint to which messages can be
 /// directed for secure delivery.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Destination {
     pub(super) public_key: PublicKey,
     pub(super) padding: Option<Padding>,
@@ -20,8 +49,8 @@ pub struct Destination {
 }
 
 impl Destination {
-    pub fn from_keys(public_key: PublicKey, signing_key: SigningPublicKey) -> Self {
-        let (certificate, padding) = cert_and_padding_from_keys(&public_key, &signing_key);
+    pub fn from_keys(enc_key: crypto::EncryptionKey, signing_key: SigningPublicKey) -> Self {
+        let (public_key, certificate, padding) = cert_and_padding_from_keys(&enc_key, &signing_key);
         Destination {
             public_key,
             padding,
@@ -30,6 +59,11 @@ impl Destination {
         }
     }
 
+    /// Returns the encryption key, of the type given by the key certificate.
+    pub fn encryption_key(&self) -> crypto::EncryptionKey {
+        encryption_key_from_field(&self.public_key, &self.certificate)
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         serialize(|input| frame::gen_destination(input, self))
     }
@@ -37,31 +71,121 @@ impl Destination {
     pub fn hash(&self) -> Hash {
         Hash::digest(&self.to_bytes()[..])
     }
+
+    /// Returns the full form of this Destination, as used in address books.
+    pub fn to_base64(&self) -> String {
+        encoding::b64_encode(&self.to_bytes())
+    }
+
+    /// Returns the short form of this Destination, in the form `<hash>.b32.i2p`.
+    pub fn to_base32(&self) -> String {
+        format!("{}{}", encoding::b32_encode(&self.hash().0), B32_SUFFIX)
+    }
+
+    /// Returns the network database key under which an EncryptedLeaseSet2 for this
+    /// Destination is stored as of `now`.
+    pub fn blinded_hash(&self, now: SystemTime) -> Result<Hash, crypto::Error> {
+        let date = blinding::date_string(now);
+        let blinded_key = blinding::blinded_key(&self.signing_key, &date)?;
+        Ok(blinding::store_key(&blinded_key))
+    }
+
+    /// Returns the network database keys to try when looking up an
+    /// EncryptedLeaseSet2 for this Destination as of `now`. Shortly after UTC
+    /// midnight this includes the previous day's key as well as the current one.
+    pub fn blinded_lookup_hashes(&self, now: SystemTime) -> Result<Vec<Hash>, crypto::Error> {
+        blinding::lookup_store_keys(&self.signing_key, now)
+    }
+}
+
+impl FromStr for Destination {
+    type Err = AddressError;
+
+    /// Parses the base64 form of a Destination.
+    ///
+    /// b32 addresses are also accepted, but as they can't be turned into a full
+    /// Destination, their hash is returned in [`AddressError::Base32`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.to_lowercase().ends_with(B32_SUFFIX) {
+            return match encoding::b32_decode(&s[..s.len() - B32_SUFFIX.len()]) {
+                Ok(ref hash) if hash.len() == 32 => {
+                    Err(AddressError::Base32(Hash::from_bytes(array_ref![
+                        hash, 0, 32
+                    ])))
+                }
+                _ => Err(AddressError::InvalidEncoding),
+            };
+        }
+
+        let data = encoding::b64_decode(s).map_err(|_| AddressError::InvalidEncoding)?;
+        match frame::destination(&data) {
+            Ok((rest, dest)) if rest.is_empty() => Ok(dest),
+            _ => Err(AddressError::InvalidDestination),
+        }
+    }
 }
 
 /// Key material for a Destination.
 pub struct DestinationSecretKeys {
     pub dest: Destination,
-    private_key: PrivateKey,
+    pub(super) private_key: DecryptionKey,
     pub signing_private_key: SigningPrivateKey,
 }
 
 impl DestinationSecretKeys {
-    pub fn new() -> Self {
-        let (private_key, public_key) = elgamal::KeyPairGenerator::generate();
-        let signing_private_key = SigningPrivateKey::new();
+    pub fn new(sig_type: SigType, enc_type: EncType) -> Self {
+        let private_key = DecryptionKey::with_type(enc_type);
+        let signing_private_key = SigningPrivateKey::with_type(sig_type);
         let signing_key = SigningPublicKey::from_secret(&signing_private_key).unwrap();
         DestinationSecretKeys {
-            dest: Destination::from_keys(public_key, signing_key),
+            dest: Destination::from_keys(private_key.public_key(), signing_key),
             private_key,
             signing_private_key,
         }
     }
+
+    /// Parses keys in the private key file format used by Java I2P and i2pd: the
+    /// Destination, followed by the private encryption key and the private
+    /// signing key.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, dsk) = frame::destination_secret_keys(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        if !dsk.keys_match() {
+            return Err(ReadError::KeyMismatch);
+        }
+        Ok(dsk)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ReadError> {
+        let mut dsk = File::open(path)?;
+        let mut data: Vec<u8> = Vec::new();
+        dsk.read_to_end(&mut data)?;
+        DestinationSecretKeys::from_bytes(&data)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_destination_secret_keys(input, self))
+    }
+
+    /// Atomically writes these keys to `path`, readable only by the current user.
+    pub fn to_file(&self, path: &str) -> io::Result<()> {
+        write_private_file(path, &self.to_bytes())
+    }
+
+    fn keys_match(&self) -> bool {
+        self.private_key.public_key() == self.dest.encryption_key()
+            && SigningPublicKey::from_secret(&self.signing_private_key)
+                .map(|key| key == self.dest.signing_key)
+                .unwrap_or(false)
+    }
 }
 
 /// Defines the authorization for a particular tunnel to receive messages
 /// targeting a Destination.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Lease {
     pub(super) tunnel_gw: Hash,
     pub(super) tid: TunnelId,
@@ -76,6 +200,122 @@ impl Lease {
             end_date,
         }
     }
+
+    pub fn gateway(&self) -> &Hash {
+        &self.tunnel_gw
+    }
+
+    pub fn tunnel_id(&self) -> TunnelId {
+        self.tid
+    }
+
+    pub fn end_date(&self) -> I2PDate {
+        self.end_date
+    }
+}
+
+/// How long a Lease must remain valid after it is picked, so that a message sent
+/// through it has time to reach the tunnel gateway.
+pub const LEASE_SAFETY_MARGIN: Duration = Duration::from_secs(15);
+
+/// How far our clock may be ahead of the clock of the router that published a
+/// LeaseSet before we consider the LeaseSet to have expired.
+pub const CLOCK_SKEW_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// Lease selection that works across LeaseSet and LeaseSet2.
+///
+/// All dates are compared in milliseconds. LeaseSet2 stores its Lease end dates to
+/// the second, so they are always whole seconds once parsed.
+pub trait Leases {
+    /// Returns every Lease, including any that have expired.
+    fn leases(&self) -> &[Lease];
+
+    /// Returns the date after which none of the Leases can be used, or `None` if
+    /// there are no Leases.
+    fn expires_at(&self) -> Option<I2PDate>;
+
+    /// Returns true if this has not expired as of `now`, allowing for clock skew.
+    fn is_current(&self, now: I2PDate) -> bool {
+        match self.expires_at() {
+            Some(expires) => expires.saturating_add(CLOCK_SKEW_TOLERANCE) > now,
+            None => false,
+        }
+    }
+
+    /// Returns the Leases that will remain valid for at least
+    /// `LEASE_SAFETY_MARGIN` after `now`, and whose gateways are not banned.
+    fn usable_leases<F>(&self, now: I2PDate, is_banned: F) -> Vec<&Lease>
+    where
+        F: Fn(&Hash) -> bool,
+    {
+        let cutoff = now.saturating_add(LEASE_SAFETY_MARGIN);
+        match self.expires_at() {
+            Some(expires) if expires > cutoff => self
+                .leases()
+                .iter()
+                .filter(|lease| lease.end_date > cutoff && !is_banned(&lease.tunnel_gw))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Returns a random usable Lease, or `None` if every Lease has expired or is
+    /// about to.
+    fn pick_lease(&self, now: I2PDate) -> Option<&Lease> {
+        self.pick_lease_excluding(now, |_| false)
+    }
+
+    /// Returns a random usable Lease whose gateway is not banned, or `None` if
+    /// there are none.
+    fn pick_lease_excluding<F>(&self, now: I2PDate, is_banned: F) -> Option<&Lease>
+    where
+        F: Fn(&Hash) -> bool,
+    {
+        self.usable_leases(now, is_banned)
+            .choose(&mut OsRng)
+            .cloned()
+    }
+}
+
+/// The largest number of Leases that a LeaseSet can contain.
+pub const MAX_LEASES: usize = 16;
+
+/// The largest number of encryption keys that a LeaseSet2 can contain.
+pub const MAX_ENC_KEYS: usize = 8;
+
+/// LeaseSet construction errors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaseSetError {
+    NoLeases,
+    TooManyLeases(usize),
+    Crypto(crypto::Error),
+    /// The offline signature authorizing the transient signing key has expired.
+    OfflineSignatureExpired,
+    /// The private key does not match the transient key in the offline signature.
+    TransientKeyMismatch,
+}
+
+impl From<crypto::Error> for LeaseSetError {
+    fn from(e: crypto::Error) -> Self {
+        LeaseSetError::Crypto(e)
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for LeaseSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseSetError::NoLeases => "No leases".fmt(f),
+            LeaseSetError::TooManyLeases(n) => {
+                write!(f, "Too many leases ({}, max {})", n, MAX_LEASES)
+            }
+            LeaseSetError::Crypto(e) => e.fmt(f),
+            LeaseSetError::OfflineSignatureExpired => "Offline signature has expired".fmt(f),
+            LeaseSetError::TransientKeyMismatch => {
+                "Private key does not match the transient key".fmt(f)
+            }
+        }
+    }
 }
 
 /// Contains all of the currently authorized Leases for a particular Destination,
@@ -86,7 +326,7 @@ impl Lease {
 /// The LeaseSet is one of the two structures stored in the network database
 /// (the other being RouterInfo), and is keyed under the SHA-256 of the contained
 /// Destination.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct LeaseSet {
     pub dest: Destination,
     pub(super) enc_key: PublicKey,
@@ -106,6 +346,42 @@ impl LeaseSet {
         }
     }
 
+    /// Creates a LeaseSet containing the given leases, signed with the Destination's
+    /// private signing key.
+    pub fn signed(
+        dest: Destination,
+        enc_key: PublicKey,
+        sig_key: SigningPublicKey,
+        leases: Vec<Lease>,
+        sk: &SigningPrivateKey,
+    ) -> Result<Self, LeaseSetError> {
+        if leases.is_empty() {
+            return Err(LeaseSetError::NoLeases);
+        }
+        if leases.len() > MAX_LEASES {
+            return Err(LeaseSetError::TooManyLeases(leases.len()));
+        }
+
+        let mut ls = LeaseSet {
+            dest,
+            enc_key,
+            sig_key,
+            leases,
+            signature: None,
+        };
+        ls.sign(sk)?;
+        Ok(ls)
+    }
+
+    /// Parses a LeaseSet, which must take up all of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, ls) = frame::lease_set(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(ls)
+    }
+
     pub fn add_lease(&mut self, lease: Lease) {
         self.leases.push(lease);
     }
@@ -126,29 +402,516 @@ impl LeaseSet {
         }
     }
 
-    pub fn is_current(&self) -> bool {
-        let expiry = self.leases.iter().fold(I2PDate(1), |expiry, lease| {
-            if lease.end_date > expiry {
-                lease.end_date
-            } else {
-                expiry
-            }
+    /// Returns the end date of the last Lease to expire, or `None` if there are no
+    /// Leases.
+    pub fn latest_expiry(&self) -> Option<I2PDate> {
+        self.leases
+            .iter()
+            .map(|lease| lease.end_date)
+            .fold(None, |latest, end| match latest {
+                Some(latest) if latest >= end => Some(latest),
+                _ => Some(end),
+            })
+    }
+
+    /// Returns true if every Lease in this LeaseSet has expired.
+    pub fn is_expired(&self) -> bool {
+        match self.latest_expiry() {
+            Some(expiry) => expiry <= I2PDate::from_system_time(SystemTime::now()),
+            None => true,
+        }
+    }
+}
+
+impl Leases for LeaseSet {
+    fn leases(&self) -> &[Lease] {
+        &self.leases
+    }
+
+    fn expires_at(&self) -> Option<I2PDate> {
+        self.latest_expiry()
+    }
+}
+
+/// The DatabaseStore type of a LeaseSet2, which is also prepended to its signed data.
+pub(crate) const LS2_DS_TYPE: u8 = 3;
+
+const LS2_FLAG_OFFLINE_KEYS: u16 = 1;
+const LS2_FLAG_UNPUBLISHED: u16 = 1 << 1;
+
+const ENC_KEY_TYPE_ELGAMAL: u16 = 0;
+const ENC_KEY_TYPE_X25519: u16 = 4;
+
+/// A public key to which messages for a Destination can be encrypted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EncryptionKey {
+    ElGamal(PublicKey),
+    X25519([u8; 32]),
+    Unsupported(u16, Vec<u8>),
+}
+
+impl EncryptionKey {
+    pub fn key_type(&self) -> u16 {
+        match *self {
+            EncryptionKey::ElGamal(_) => ENC_KEY_TYPE_ELGAMAL,
+            EncryptionKey::X25519(_) => ENC_KEY_TYPE_X25519,
+            EncryptionKey::Unsupported(key_type, _) => key_type,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            EncryptionKey::ElGamal(ref pk) => &pk.0,
+            EncryptionKey::X25519(ref pk) => pk,
+            EncryptionKey::Unsupported(_, ref data) => data,
+        }
+    }
+}
+
+impl From<crypto::EncryptionKey> for EncryptionKey {
+    fn from(key: crypto::EncryptionKey) -> Self {
+        match key {
+            crypto::EncryptionKey::ElGamal(pk) => EncryptionKey::ElGamal(pk),
+            crypto::EncryptionKey::X25519(pk) => EncryptionKey::X25519(pk),
+        }
+    }
+}
+
+/// Authorizes a transient key to sign on behalf of a Destination, so that the
+/// Destination's own signing key can be kept offline.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OfflineSignature {
+    pub(super) expires: I2PDate,
+    pub(super) transient_key: SigningPublicKey,
+    pub(super) signature: Signature,
+}
+
+impl OfflineSignature {
+    /// Creates an offline signature for the given transient key, signed with the
+    /// Destination's private signing key.
+    pub fn new(
+        expires: I2PDate,
+        transient_key: SigningPublicKey,
+        dest_sk: &SigningPrivateKey,
+    ) -> Result<Self, crypto::Error> {
+        let sig_bytes = serialize(|input| {
+            frame::gen_offline_signature_minus_sig(input, &expires, &transient_key)
+        });
+        Ok(OfflineSignature {
+            expires,
+            transient_key,
+            signature: dest_sk.sign(&sig_bytes)?,
+        })
+    }
+
+    /// Checks that this offline signature was created by the given signing key.
+    pub fn verify(&self, signer: &SigningPublicKey) -> Result<(), crypto::Error> {
+        let sig_bytes = serialize(|input| {
+            frame::gen_offline_signature_minus_sig(input, &self.expires, &self.transient_key)
         });
-        expiry < I2PDate::from_system_time(SystemTime::now())
+        signer.verify(&sig_bytes, &self.signature)
+    }
+
+    /// Parses an offline signature block created by a Destination with the given
+    /// signature type, such as one made ahead of time with an offline key tool.
+    pub fn from_bytes(dest_sig_type: SigType, data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, offline_sig) = frame::offline_signature(dest_sig_type)(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(offline_sig)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_offline_signature(input, self))
+    }
+
+    pub fn expires(&self) -> I2PDate {
+        self.expires
+    }
+
+    pub fn transient_key(&self) -> &SigningPublicKey {
+        &self.transient_key
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(I2PDate::from_system_time(SystemTime::now()))
+    }
+
+    pub fn is_expired_at(&self, now: I2PDate) -> bool {
+        self.expires <= now
+    }
+}
+
+/// The key that signed a LeaseSet2.
+#[derive(Debug, PartialEq)]
+pub enum LeaseSetSigner<'a> {
+    /// The Destination's own signing key.
+    Destination,
+    /// A transient key, authorized by the Destination with this offline signature.
+    Transient(&'a OfflineSignature),
+}
+
+/// The second-generation LeaseSet, which can contain multiple encryption keys of
+/// different types, and can be signed by a transient key.
+#[derive(Clone)]
+pub struct LeaseSet2 {
+    pub dest: Destination,
+    pub(super) published: I2PDate,
+    pub(super) expires: I2PDate,
+    pub(super) unpublished: bool,
+    pub(super) offline_sig: Option<OfflineSignature>,
+    pub(super) options: Mapping,
+    pub(super) enc_keys: Vec<EncryptionKey>,
+    pub(super) leases: Vec<Lease>,
+    pub(super) signature: Option<Signature>,
+}
+
+impl LeaseSet2 {
+    /// Creates an unsigned LeaseSet2.
+    ///
+    /// The dates are only stored to the second, and `expires` must be less than 18
+    /// hours after `published`.
+    pub fn new(
+        dest: Destination,
+        published: I2PDate,
+        expires: I2PDate,
+        enc_keys: Vec<EncryptionKey>,
+        leases: Vec<Lease>,
+    ) -> Self {
+        LeaseSet2 {
+            dest,
+            published,
+            expires,
+            unpublished: false,
+            offline_sig: None,
+            options: Mapping::new(),
+            enc_keys,
+            leases,
+            signature: None,
+        }
+    }
+
+    /// Parses a LeaseSet2, which must take up all of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, ls) = frame::lease_set2(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(ls)
+    }
+
+    /// Marks this LeaseSet2 as signed by a transient key.
+    pub fn offline_signature(mut self, offline_sig: OfflineSignature) -> Self {
+        self.offline_sig = Some(offline_sig);
+        self.signature = None;
+        self
+    }
+
+    /// Sets an option, replacing any existing value.
+    ///
+    /// Panics if the key or value is not valid in a Mapping.
+    pub fn option<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<I2PString>,
+        V: Into<I2PString>,
+    {
+        self.options.insert(key.into(), value.into()).unwrap();
+        self.signature = None;
+        self
+    }
+
+    pub(super) fn flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.offline_sig.is_some() {
+            flags |= LS2_FLAG_OFFLINE_KEYS;
+        }
+        if self.unpublished {
+            flags |= LS2_FLAG_UNPUBLISHED;
+        }
+        flags
+    }
+
+    pub fn enc_keys(&self) -> &[EncryptionKey] {
+        &self.enc_keys
+    }
+
+    pub fn published(&self) -> I2PDate {
+        self.published
+    }
+
+    /// Returns the key that signs this LeaseSet2: the transient key if there is an
+    /// offline signature, and otherwise the Destination's signing key.
+    fn signing_key(&self) -> &SigningPublicKey {
+        match self.offline_sig {
+            Some(ref offline_sig) => &offline_sig.transient_key,
+            None => &self.dest.signing_key,
+        }
+    }
+
+    fn signature_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_lease_set2_signed_data(input, self))
+    }
+
+    /// Signs this LeaseSet2 with either the transient private key (if it has an
+    /// offline signature) or the Destination's private signing key.
+    pub fn sign(&mut self, sk: &SigningPrivateKey) -> Result<(), crypto::Error> {
+        self.signature = Some(sk.sign(&self.signature_bytes())?);
+        Ok(())
+    }
+
+    /// Attaches a pre-made offline signature, and signs this LeaseSet2 with the
+    /// private key for the transient key that it authorizes.
+    pub fn sign_with_transient_key(
+        &mut self,
+        offline_sig: OfflineSignature,
+        transient_sk: &SigningPrivateKey,
+    ) -> Result<(), LeaseSetError> {
+        if SigningPublicKey::from_secret(transient_sk)? != offline_sig.transient_key {
+            return Err(LeaseSetError::TransientKeyMismatch);
+        }
+        self.offline_sig = Some(offline_sig);
+        self.sign(transient_sk)?;
+        Ok(())
+    }
+
+    /// Verifies the signature on this LeaseSet2, along with the offline signature
+    /// authorizing the transient key if there is one.
+    ///
+    /// This does not check whether the offline signature has expired; use
+    /// [`LeaseSet2::verify_at`] for that.
+    pub fn verify(&self) -> Result<(), crypto::Error> {
+        if let Some(ref offline_sig) = self.offline_sig {
+            offline_sig.verify(&self.dest.signing_key)?;
+        }
+        match self.signature.as_ref() {
+            Some(s) => self.signing_key().verify(&self.signature_bytes(), s),
+            None => Err(crypto::Error::NoSignature),
+        }
+    }
+
+    /// Verifies this LeaseSet2 as of `now`, rejecting it if it was signed by a
+    /// transient key whose offline signature has expired.
+    ///
+    /// Returns the key that signed it, so that callers can apply their own policy
+    /// to transient keys.
+    pub fn verify_at(&self, now: I2PDate) -> Result<LeaseSetSigner<'_>, LeaseSetError> {
+        if let Some(ref offline_sig) = self.offline_sig {
+            if offline_sig.is_expired_at(now) {
+                return Err(LeaseSetError::OfflineSignatureExpired);
+            }
+        }
+        self.verify()?;
+        Ok(match self.offline_sig {
+            Some(ref offline_sig) => LeaseSetSigner::Transient(offline_sig),
+            None => LeaseSetSigner::Destination,
+        })
+    }
+
+    /// Returns the end date of the last Lease to expire, or `None` if there are no
+    /// Leases.
+    pub fn latest_expiry(&self) -> Option<I2PDate> {
+        self.leases
+            .iter()
+            .map(|lease| lease.end_date)
+            .fold(None, |latest, end| match latest {
+                Some(latest) if latest >= end => Some(latest),
+                _ => Some(end),
+            })
+    }
+
+    /// Returns true if this LeaseSet2 or its offline signature has expired.
+    pub fn is_expired(&self) -> bool {
+        self.expires <= I2PDate::from_system_time(SystemTime::now())
+            || self
+                .offline_sig
+                .as_ref()
+                .map(|offline_sig| offline_sig.is_expired())
+                .unwrap_or(false)
+    }
+}
+
+impl Leases for LeaseSet2 {
+    fn leases(&self) -> &[Lease] {
+        &self.leases
+    }
+
+    /// A LeaseSet2 expires at the earliest of its own expiry, the expiry of its
+    /// offline signature, and the end date of its last Lease.
+    fn expires_at(&self) -> Option<I2PDate> {
+        let mut expires = self.latest_expiry()?;
+        if self.expires < expires {
+            expires = self.expires;
+        }
+        if let Some(ref offline_sig) = self.offline_sig {
+            if offline_sig.expires < expires {
+                expires = offline_sig.expires;
+            }
+        }
+        Some(expires)
+    }
+}
+
+/// The DatabaseStore type of an EncryptedLeaseSet2, which is also prepended to its
+/// signed data.
+pub(crate) const ELS2_DS_TYPE: u8 = 5;
+
+const ELS2_LAYER1_LABEL: &[u8] = b"ELS2_L1K";
+const ELS2_LAYER2_LABEL: &[u8] = b"ELS2_L2K";
+
+/// A LeaseSet2 that is encrypted to clients that know the Destination, and signed
+/// by a key blinded with the current date so that it can't be linked to the
+/// Destination by anyone else.
+#[derive(Clone)]
+pub struct EncryptedLeaseSet2 {
+    pub(super) blinded_key: SigningPublicKey,
+    pub(super) published: I2PDate,
+    pub(super) expires: I2PDate,
+    pub(super) offline_sig: Option<OfflineSignature>,
+    pub(super) encrypted: Vec<u8>,
+    pub(super) signature: Option<Signature>,
+}
+
+impl EncryptedLeaseSet2 {
+    /// Encrypts and signs the given LeaseSet2, blinding the Destination's signing key
+    /// for the UTC date of `now`.
+    ///
+    /// Per-client authorization is not supported.
+    pub fn encrypt(
+        ls: &LeaseSet2,
+        dest_sk: &SigningPrivateKey,
+        now: SystemTime,
+    ) -> Result<Self, crypto::Error> {
+        let dest_key = &ls.dest.signing_key;
+        let alpha = blinding::generate_alpha(dest_key, &blinding::date_string(now), None);
+        let blinded_key = blinding::blind_public_key(dest_key, &alpha)?;
+        let blinded_sk = blinding::blind_private_key(dest_sk, &alpha)?;
+
+        let mut els = EncryptedLeaseSet2 {
+            blinded_key: SigningPublicKey::from_bytes(SigType::RedDsaSha512Ed25519, &blinded_key)?,
+            published: ls.published,
+            expires: ls.expires,
+            offline_sig: None,
+            encrypted: vec![],
+            signature: None,
+        };
+        let input = els.layer_input(dest_key);
+
+        let mut inner = vec![LS2_DS_TYPE];
+        inner.extend(serialize(|input| frame::gen_lease_set2(input, ls)));
+        let mut layer1 = vec![0];
+        layer1.extend(blinding::encrypt_layer(&inner, &input, ELS2_LAYER2_LABEL));
+        els.encrypted = blinding::encrypt_layer(&layer1, &input, ELS2_LAYER1_LABEL);
+
+        els.signature = Some(blinded_sk.sign(&els.signature_bytes())?);
+        Ok(els)
+    }
+
+    /// Returns the network database key for this EncryptedLeaseSet2.
+    pub fn store_key(&self) -> Hash {
+        blinding::store_key(&self.blinded_key)
+    }
+
+    pub fn published(&self) -> I2PDate {
+        self.published
+    }
+
+    /// Returns true if neither this EncryptedLeaseSet2 nor its offline signature has
+    /// expired as of `now`, allowing for clock skew.
+    ///
+    /// The Leases are encrypted, so their end dates can't be checked.
+    pub fn is_current(&self, now: I2PDate) -> bool {
+        let mut expires = self.expires;
+        if let Some(ref offline_sig) = self.offline_sig {
+            if offline_sig.expires < expires {
+                expires = offline_sig.expires;
+            }
+        }
+        expires.saturating_add(CLOCK_SKEW_TOLERANCE) > now
+    }
+
+    fn signature_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_encrypted_lease_set2_signed_data(input, self))
+    }
+
+    /// Verifies the outer signature on this EncryptedLeaseSet2, which is made by the
+    /// blinded key or a transient key that it has authorized.
+    pub fn verify(&self) -> Result<(), crypto::Error> {
+        let signing_key = match self.offline_sig {
+            Some(ref offline_sig) => {
+                offline_sig.verify(&self.blinded_key)?;
+                &offline_sig.transient_key
+            }
+            None => &self.blinded_key,
+        };
+        match self.signature.as_ref() {
+            Some(s) => signing_key.verify(&self.signature_bytes(), s),
+            None => Err(crypto::Error::NoSignature),
+        }
+    }
+
+    /// subcredential || publishedTimestamp
+    fn layer_input(&self, dest_key: &SigningPublicKey) -> Vec<u8> {
+        let credential = blinding::credential(dest_key);
+        let mut input = blinding::subcredential(&credential, self.blinded_key.as_bytes()).to_vec();
+        input.extend_from_slice(&self.published.to_short_expiry().to_be_bytes());
+        input
+    }
+
+    /// Decrypts the inner LeaseSet2, given the Destination it was published for.
+    ///
+    /// The inner LeaseSet2 is not verified.
+    pub fn decrypt(&self, dest: &Destination) -> Result<LeaseSet2, crypto::Error> {
+        let input = self.layer_input(&dest.signing_key);
+
+        let layer1 = blinding::decrypt_layer(&self.encrypted, &input, ELS2_LAYER1_LABEL)?;
+        let layer2 = match layer1.split_first() {
+            Some((&0, layer2)) => layer2,
+            // Either per-client authorization, or the wrong Destination
+            _ => return Err(crypto::Error::InvalidMessage),
+        };
+
+        let inner = blinding::decrypt_layer(layer2, &input, ELS2_LAYER2_LABEL)?;
+        match inner.split_first() {
+            Some((&LS2_DS_TYPE, data)) => match frame::lease_set2(data) {
+                Ok((rest, ls)) if rest.is_empty() && ls.dest.signing_key == dest.signing_key => {
+                    Ok(ls)
+                }
+                _ => Err(crypto::Error::InvalidMessage),
+            },
+            _ => Err(crypto::Error::InvalidMessage),
+        }
+    }
+
+    /// Returns true if this EncryptedLeaseSet2 or its offline signature has expired.
+    pub fn is_expired(&self) -> bool {
+        self.expires <= I2PDate::from_system_time(SystemTime::now())
+            || self
+                .offline_sig
+                .as_ref()
+                .map(|offline_sig| offline_sig.is_expired())
+                .unwrap_or(false)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
+    use nom::Needed;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-    use super::{Destination, DestinationSecretKeys, Lease, LeaseSet};
+    use super::{
+        frame, AddressError, Destination, DestinationSecretKeys, EncryptedLeaseSet2, EncryptionKey,
+        Lease, LeaseSet, LeaseSet2, LeaseSetError, LeaseSetSigner, Leases, OfflineSignature,
+        CLOCK_SKEW_TOLERANCE, LEASE_SAFETY_MARGIN, MAX_ENC_KEYS, MAX_LEASES,
+    };
     use crate::{
         crypto::{
-            self, elgamal::KeyPairGenerator, PublicKey, SigType, SigningPrivateKey,
-            SigningPublicKey,
+            self, blinding::ROLLOVER_WINDOW, elgamal::KeyPairGenerator, EncType, PublicKey,
+            SigType, SigningPrivateKey, SigningPublicKey,
         },
-        data::{Certificate, Hash, I2PDate, TunnelId},
+        data::{Certificate, Hash, I2PDate, ReadError, TunnelId},
+        tests::ROUTER_KEYS,
+        util::serialize,
     };
 
     #[test]
@@ -169,9 +932,59 @@ mod tests {
         );
     }
 
+    const DEST_B64: &str = concat!(
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB",
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB",
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB",
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB",
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQICAgICAgICAgICAgIC",
+        "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgIC",
+        "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgIC",
+        "AgICAgICAAAA",
+    );
+    const DEST_B32: &str = "wf4seww4eph3vdnf376qwjgkyh6xu2paea2ul5r43dqu2eezytma.b32.i2p";
+
+    #[test]
+    fn dest_addresses() {
+        let dest: Destination = DEST_B64.parse().unwrap();
+        assert_eq!(dest.signing_key.sig_type(), SigType::DsaSha1);
+        assert_eq!(dest.certificate, Certificate::Null);
+        assert_eq!(dest.to_base64(), DEST_B64);
+        assert_eq!(dest.to_base32(), DEST_B32);
+
+        // b32 addresses only resolve to the hash
+        assert_eq!(
+            DEST_B32.parse::<Destination>(),
+            Err(AddressError::Base32(dest.hash()))
+        );
+        assert_eq!(
+            DEST_B32.to_uppercase().parse::<Destination>(),
+            Err(AddressError::Base32(dest.hash()))
+        );
+
+        assert_eq!(
+            "not a destination".parse::<Destination>(),
+            Err(AddressError::InvalidEncoding)
+        );
+        assert_eq!(
+            "abcd.b32.i2p".parse::<Destination>(),
+            Err(AddressError::InvalidEncoding)
+        );
+        assert_eq!(
+            DEST_B64[..DEST_B64.len() - 4].parse::<Destination>(),
+            Err(AddressError::InvalidDestination)
+        );
+
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let b64 = dsk.dest.to_base64();
+        assert_eq!(b64.len(), 524);
+        assert_eq!(b64.parse(), Ok(dsk.dest.clone()));
+        assert_eq!(dsk.dest.to_base32().len(), 60);
+    }
+
     #[test]
     fn ls_sign() {
-        let dsk = DestinationSecretKeys::new();
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
         let dest = dsk.dest;
         let key = dest.hash();
 
@@ -193,4 +1006,638 @@ mod tests {
         ls.sign(&dsk.signing_private_key).unwrap();
         assert_eq!(ls.verify(), Ok(()));
     }
+
+    #[test]
+    fn dest_secret_keys_round_trip() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let data = dsk.to_bytes();
+
+        // The Destination, followed by the private keys
+        let mut expected = dsk.dest.to_bytes();
+        expected.extend_from_slice(dsk.private_key.as_bytes());
+        expected.extend_from_slice(dsk.signing_private_key.as_bytes());
+        assert_eq!(data, expected);
+
+        let parsed = DestinationSecretKeys::from_bytes(&data).unwrap();
+        assert_eq!(parsed.dest.to_bytes(), dsk.dest.to_bytes());
+        assert_eq!(parsed.to_bytes(), data);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("eepsite.keys");
+        let path = path.to_str().unwrap();
+        dsk.to_file(path).unwrap();
+        let loaded = DestinationSecretKeys::from_file(path).unwrap();
+        assert_eq!(loaded.dest.hash(), dsk.dest.hash());
+    }
+
+    #[test]
+    fn dest_secret_keys_x25519() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::X25519);
+        let enc_key = dsk.dest.encryption_key();
+        assert_eq!(enc_key.enc_type(), EncType::X25519);
+        assert_eq!(enc_key, dsk.private_key.public_key());
+        assert_eq!(
+            EncryptionKey::from(enc_key.clone()),
+            EncryptionKey::X25519(*array_ref![enc_key.as_bytes(), 0, 32])
+        );
+
+        let data = dsk.to_bytes();
+        assert_eq!(data.len(), dsk.dest.to_bytes().len() + 32 + 32);
+        let parsed = DestinationSecretKeys::from_bytes(&data).unwrap();
+        assert_eq!(parsed.dest, dsk.dest);
+        assert_eq!(parsed.private_key, dsk.private_key);
+    }
+
+    #[test]
+    fn dest_secret_keys_import() {
+        // The router.keys.dat fixture uses the same layout as a Destination's private
+        // key file in Java I2P and i2pd, so it can be imported as one. It was not
+        // written by either of them, so this only checks our reading of the layout.
+        let dsk = DestinationSecretKeys::from_bytes(&ROUTER_KEYS[..]).unwrap();
+        assert_eq!(dsk.dest.signing_key.sig_type(), SigType::Ed25519);
+        assert_eq!(dsk.dest.encryption_key(), dsk.private_key.public_key());
+        assert_eq!(&dsk.to_bytes()[..], &ROUTER_KEYS[..]);
+
+        // The imported keys can sign for the Destination
+        let sig = dsk.signing_private_key.sign(b"foo").unwrap();
+        assert_eq!(dsk.dest.signing_key.verify(b"foo", &sig), Ok(()));
+    }
+
+    #[test]
+    fn dest_secret_keys_corrupt() {
+        let data = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048).to_bytes();
+
+        assert_eq!(
+            DestinationSecretKeys::from_bytes(&data[..data.len() - 1]).err(),
+            Some(ReadError::Incomplete(Needed::new(1)))
+        );
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert_eq!(
+            DestinationSecretKeys::from_bytes(&trailing).err(),
+            Some(ReadError::TrailingData(1))
+        );
+
+        // Private keys that don't match the Destination
+        for &i in [data.len() - 32 - 256, data.len() - 1].iter() {
+            let mut mismatched = data.clone();
+            mismatched[i] ^= 1;
+            assert_eq!(
+                DestinationSecretKeys::from_bytes(&mismatched).err(),
+                Some(ReadError::KeyMismatch)
+            );
+        }
+    }
+
+    fn leases(n: u8, end_date: I2PDate) -> Vec<Lease> {
+        (0..n)
+            .map(|i| Lease::new(Hash([i; 32]), TunnelId(i.into()), end_date))
+            .collect()
+    }
+
+    fn signed_ls(n: u8, end_date: I2PDate) -> Result<LeaseSet, LeaseSetError> {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let (_, enc_key) = KeyPairGenerator::generate();
+        let sig_key = SigningPublicKey::from_secret(&SigningPrivateKey::new()).unwrap();
+        LeaseSet::signed(
+            dsk.dest,
+            enc_key,
+            sig_key,
+            leases(n, end_date),
+            &dsk.signing_private_key,
+        )
+    }
+
+    #[test]
+    fn ls_round_trip() {
+        let end_date = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(600));
+        let ls = signed_ls(3, end_date).unwrap();
+        assert_eq!(ls.verify(), Ok(()));
+
+        let buf = serialize(|input| frame::gen_lease_set(input, &ls));
+        let (rest, parsed) = frame::lease_set(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.verify(), Ok(()));
+        assert_eq!(parsed.leases.len(), 3);
+        assert_eq!(serialize(|input| frame::gen_lease_set(input, &parsed)), buf);
+
+        // Tampering with a lease invalidates the signature
+        let mut tampered = parsed.clone();
+        tampered.leases[0].tid = TunnelId(42);
+        assert_eq!(tampered.verify(), Err(crypto::Error::InvalidSignature));
+    }
+
+    #[test]
+    fn ls_lease_count() {
+        let end_date = I2PDate::from_system_time(SystemTime::now());
+        assert_eq!(signed_ls(0, end_date).err(), Some(LeaseSetError::NoLeases));
+        assert!(signed_ls(MAX_LEASES as u8, end_date).is_ok());
+        assert_eq!(
+            signed_ls(MAX_LEASES as u8 + 1, end_date).err(),
+            Some(LeaseSetError::TooManyLeases(MAX_LEASES + 1))
+        );
+
+        // The parser rejects LeaseSets with an invalid number of leases
+        let ls = signed_ls(1, end_date).unwrap();
+        for &n in [0, MAX_LEASES as u8 + 1].iter() {
+            let mut invalid = ls.clone();
+            invalid.leases = leases(n, end_date);
+            let buf = serialize(|input| frame::gen_lease_set(input, &invalid));
+            assert!(frame::lease_set(&buf).is_err());
+        }
+    }
+
+    #[test]
+    fn ls_expiry() {
+        let now = SystemTime::now();
+        let past = I2PDate::from_system_time(now - Duration::from_secs(120));
+        let future = I2PDate::from_system_time(now + Duration::from_secs(60));
+        let now = I2PDate::from_system_time(now);
+
+        let mut ls = signed_ls(1, past).unwrap();
+        assert_eq!(ls.latest_expiry(), Some(past));
+        assert!(ls.is_expired());
+        assert!(!ls.is_current(now));
+
+        ls.add_lease(Lease::new(Hash([9; 32]), TunnelId(9), future));
+        assert_eq!(ls.latest_expiry(), Some(future));
+        assert!(!ls.is_expired());
+        assert!(ls.is_current(now));
+
+        ls.leases.clear();
+        assert_eq!(ls.latest_expiry(), None);
+        assert!(ls.is_expired());
+    }
+
+    #[test]
+    fn ls_is_current_tolerates_skew() {
+        let now = I2PDate(1_600_000_000_000);
+        let ls = signed_ls(2, now).unwrap();
+        assert_eq!(ls.expires_at(), Some(now));
+
+        assert!(ls.is_current(now));
+        assert!(ls.is_current(now.saturating_add(CLOCK_SKEW_TOLERANCE - Duration::from_millis(1))));
+        assert!(!ls.is_current(now.saturating_add(CLOCK_SKEW_TOLERANCE)));
+
+        let mut empty = ls;
+        empty.leases.clear();
+        assert_eq!(empty.expires_at(), None);
+        assert!(!empty.is_current(I2PDate(0)));
+    }
+
+    #[test]
+    fn ls_pick_lease() {
+        let now = I2PDate(1_600_000_000_000);
+        let cutoff = now.saturating_add(LEASE_SAFETY_MARGIN);
+        let expiring = |ms: u32| {
+            Lease::new(
+                Hash([ms as u8; 32]),
+                TunnelId(ms),
+                I2PDate(cutoff.0 + u64::from(ms)),
+            )
+        };
+        let tids = |leases: Vec<&Lease>| -> Vec<TunnelId> {
+            leases.iter().map(|lease| lease.tunnel_id()).collect()
+        };
+
+        // Leases ending within the safety margin are never picked
+        let mut ls = signed_ls(1, cutoff).unwrap();
+        assert!(ls.is_current(now));
+        assert!(ls.usable_leases(now, |_| false).is_empty());
+        assert!(ls.pick_lease(now).is_none());
+
+        ls.add_lease(expiring(1));
+        ls.add_lease(expiring(2));
+        ls.add_lease(expiring(3));
+        assert_eq!(
+            tids(ls.usable_leases(now, |_| false)),
+            vec![TunnelId(1), TunnelId(2), TunnelId(3)]
+        );
+        for _ in 0..20 {
+            let lease = ls.pick_lease(now).unwrap();
+            assert!(lease.end_date() > cutoff);
+        }
+
+        // A millisecond later, the earliest usable Lease is no longer usable
+        let later = I2PDate(now.0 + 1);
+        assert_eq!(
+            tids(ls.usable_leases(later, |_| false)),
+            vec![TunnelId(2), TunnelId(3)]
+        );
+
+        // Banned gateways are excluded
+        let banned = |gw: &Hash| *gw == Hash([2; 32]) || *gw == Hash([3; 32]);
+        assert_eq!(tids(ls.usable_leases(now, banned)), vec![TunnelId(1)]);
+        for _ in 0..20 {
+            assert_eq!(
+                ls.pick_lease_excluding(now, banned).unwrap().tunnel_id(),
+                TunnelId(1)
+            );
+        }
+        assert!(ls.pick_lease_excluding(later, banned).is_none());
+
+        // Once every Lease has expired, none are picked
+        let all_expired = I2PDate(now.0 + 3);
+        assert!(ls.pick_lease(all_expired).is_none());
+        assert!(ls.is_current(all_expired));
+    }
+
+    #[test]
+    fn ls2_pick_lease() {
+        // LeaseSet2 end dates are stored to the second
+        let now = I2PDate(1_600_000_000_500);
+        let cutoff = now.saturating_add(LEASE_SAFETY_MARGIN);
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let mut ls = LeaseSet2::new(
+            dsk.dest,
+            I2PDate(1_600_000_000_000),
+            I2PDate(1_600_000_600_000),
+            vec![EncryptionKey::X25519([7; 32])],
+            vec![
+                Lease::new(Hash([1; 32]), TunnelId(1), I2PDate(cutoff.0 - 1)),
+                Lease::new(Hash([2; 32]), TunnelId(2), I2PDate(cutoff.0 + 1)),
+                Lease::new(Hash([3; 32]), TunnelId(3), I2PDate(1_600_000_300_000)),
+            ],
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+        let parsed = ls2_round_trip(&ls);
+
+        // Sub-second end dates are rounded up, so the first Lease becomes usable
+        assert_eq!(parsed.leases()[0].end_date(), I2PDate(1_600_000_016_000));
+        assert_eq!(parsed.leases()[1].end_date(), I2PDate(1_600_000_016_000));
+        let usable = |ls: &LeaseSet2| -> Vec<TunnelId> {
+            ls.usable_leases(now, |_| false)
+                .iter()
+                .map(|lease| lease.tunnel_id())
+                .collect()
+        };
+        assert_eq!(usable(&ls), vec![TunnelId(2), TunnelId(3)]);
+        assert_eq!(usable(&parsed), vec![TunnelId(1), TunnelId(2), TunnelId(3)]);
+
+        // The LeaseSet2 expires with its last Lease
+        assert_eq!(parsed.expires_at(), Some(I2PDate(1_600_000_300_000)));
+        let all_expired = I2PDate(1_600_000_300_000);
+        assert!(parsed.pick_lease(all_expired).is_none());
+        assert!(parsed.is_current(all_expired));
+        assert!(!parsed.is_current(all_expired.saturating_add(CLOCK_SKEW_TOLERANCE)));
+
+        // Or earlier, if its own expiry is before the last Lease ends
+        let mut short = parsed.clone();
+        short.expires = I2PDate(1_600_000_010_000);
+        assert_eq!(short.expires_at(), Some(short.expires));
+        assert!(short.usable_leases(now, |_| false).is_empty());
+        assert!(short.pick_lease(now).is_none());
+    }
+
+    fn in_secs(secs: u64) -> I2PDate {
+        // LeaseSet2 dates are stored to the second
+        let date = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(secs));
+        I2PDate(date.0 - date.0 % 1_000)
+    }
+
+    fn ls2_round_trip(ls: &LeaseSet2) -> LeaseSet2 {
+        let buf = serialize(|input| frame::gen_lease_set2(input, ls));
+        let (rest, parsed) = frame::lease_set2(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            serialize(|input| frame::gen_lease_set2(input, &parsed)),
+            buf
+        );
+        parsed
+    }
+
+    #[test]
+    fn ls2_two_encryption_keys() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let (_, elgamal_key) = KeyPairGenerator::generate();
+        let mut ls = LeaseSet2::new(
+            dsk.dest,
+            in_secs(0),
+            in_secs(600),
+            vec![
+                EncryptionKey::X25519([7; 32]),
+                EncryptionKey::ElGamal(elgamal_key.clone()),
+            ],
+            leases(2, in_secs(600)),
+        )
+        .option("foo", "bar");
+        assert_eq!(ls.verify(), Err(crypto::Error::NoSignature));
+        ls.sign(&dsk.signing_private_key).unwrap();
+        assert_eq!(ls.verify(), Ok(()));
+        assert!(!ls.is_expired());
+
+        let parsed = ls2_round_trip(&ls);
+        assert_eq!(parsed.verify(), Ok(()));
+        assert_eq!(
+            parsed.enc_keys(),
+            &[
+                EncryptionKey::X25519([7; 32]),
+                EncryptionKey::ElGamal(elgamal_key)
+            ][..]
+        );
+        assert_eq!(parsed.published, ls.published);
+        assert_eq!(parsed.expires, ls.expires);
+        assert_eq!(parsed.latest_expiry(), Some(in_secs(600)));
+        assert!(parsed.offline_sig.is_none());
+
+        // Signing with the wrong key fails verification
+        let mut wrong = parsed.clone();
+        wrong.sign(&SigningPrivateKey::new()).unwrap();
+        assert_eq!(wrong.verify(), Err(crypto::Error::InvalidSignature));
+    }
+
+    #[test]
+    fn ls2_offline_signature() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let transient_sk = SigningPrivateKey::new();
+        let transient_key = SigningPublicKey::from_secret(&transient_sk).unwrap();
+        let offline_sig =
+            OfflineSignature::new(in_secs(3600), transient_key, &dsk.signing_private_key).unwrap();
+        assert_eq!(offline_sig.verify(&dsk.dest.signing_key), Ok(()));
+
+        let mut ls = LeaseSet2::new(
+            dsk.dest.clone(),
+            in_secs(0),
+            in_secs(600),
+            vec![EncryptionKey::X25519([7; 32])],
+            leases(1, in_secs(600)),
+        )
+        .offline_signature(offline_sig.clone());
+
+        // The LeaseSet2 must be signed by the transient key
+        ls.sign(&dsk.signing_private_key).unwrap();
+        assert_eq!(ls.verify(), Err(crypto::Error::InvalidSignature));
+        ls.sign(&transient_sk).unwrap();
+        assert_eq!(ls.verify(), Ok(()));
+
+        let parsed = ls2_round_trip(&ls);
+        assert_eq!(parsed.verify(), Ok(()));
+        assert!(parsed.offline_sig.is_some());
+
+        // The offline signature must be by the Destination
+        let other = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let mut forged = parsed.clone();
+        forged.offline_sig = Some(
+            OfflineSignature::new(
+                in_secs(3600),
+                offline_sig.transient_key.clone(),
+                &other.signing_private_key,
+            )
+            .unwrap(),
+        );
+        forged.sign(&transient_sk).unwrap();
+        assert_eq!(forged.verify(), Err(crypto::Error::InvalidSignature));
+
+        // An expired offline signature expires the LeaseSet2
+        let mut expired = parsed;
+        expired.offline_sig = Some(
+            OfflineSignature::new(
+                in_secs(0),
+                offline_sig.transient_key,
+                &dsk.signing_private_key,
+            )
+            .unwrap(),
+        );
+        assert!(expired.is_expired());
+    }
+
+    #[test]
+    fn ls2_offline_signature_policy() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let transient_sk = SigningPrivateKey::new();
+        let transient_key = SigningPublicKey::from_secret(&transient_sk).unwrap();
+        let expires = in_secs(3600);
+        let offline_sig =
+            OfflineSignature::new(expires, transient_key, &dsk.signing_private_key).unwrap();
+
+        // Offline signature blocks can be made ahead of time and stored
+        let block = offline_sig.to_bytes();
+        assert_eq!(block.len(), 4 + 2 + 32 + 64);
+        let stored = OfflineSignature::from_bytes(SigType::Ed25519, &block).unwrap();
+        assert_eq!(stored, offline_sig);
+        assert_eq!(stored.verify(&dsk.dest.signing_key), Ok(()));
+        let mut trailing = block.clone();
+        trailing.push(0);
+        assert_eq!(
+            OfflineSignature::from_bytes(SigType::Ed25519, &trailing),
+            Err(ReadError::TrailingData(1))
+        );
+
+        let mut ls = LeaseSet2::new(
+            dsk.dest.clone(),
+            in_secs(0),
+            in_secs(600),
+            vec![EncryptionKey::X25519([7; 32])],
+            leases(1, in_secs(600)),
+        );
+        let now = in_secs(0);
+
+        // Signed by the Destination
+        ls.sign(&dsk.signing_private_key).unwrap();
+        assert_eq!(ls.verify_at(now), Ok(LeaseSetSigner::Destination));
+
+        // Signing with a pre-made block requires the matching transient key
+        assert_eq!(
+            ls.sign_with_transient_key(stored.clone(), &dsk.signing_private_key),
+            Err(LeaseSetError::TransientKeyMismatch)
+        );
+        ls.sign_with_transient_key(stored, &transient_sk).unwrap();
+        let parsed = ls2_round_trip(&ls);
+        assert_eq!(
+            parsed.verify_at(now),
+            Ok(LeaseSetSigner::Transient(&offline_sig))
+        );
+
+        // The transient key is only valid until the offline signature expires
+        assert_eq!(
+            parsed.verify_at(I2PDate(expires.0 - 1)),
+            Ok(LeaseSetSigner::Transient(&offline_sig))
+        );
+        assert_eq!(
+            parsed.verify_at(expires),
+            Err(LeaseSetError::OfflineSignatureExpired)
+        );
+
+        // Signature failures are still reported
+        let mut tampered = parsed;
+        tampered.leases[0].tid = TunnelId(42);
+        assert_eq!(
+            tampered.verify_at(now),
+            Err(LeaseSetError::Crypto(crypto::Error::InvalidSignature))
+        );
+    }
+
+    #[test]
+    fn ls2_invalid_encryption_key() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let mut ls = LeaseSet2::new(
+            dsk.dest,
+            in_secs(0),
+            in_secs(600),
+            vec![EncryptionKey::Unsupported(4, vec![0; 16])],
+            vec![],
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+        let buf = serialize(|input| frame::gen_lease_set2(input, &ls));
+        assert!(frame::lease_set2(&buf).is_err());
+
+        // Unknown key types are preserved
+        ls.enc_keys = vec![EncryptionKey::Unsupported(42, vec![0; 16])];
+        ls.sign(&dsk.signing_private_key).unwrap();
+        let parsed = ls2_round_trip(&ls);
+        assert_eq!(parsed.enc_keys(), &ls.enc_keys[..]);
+        assert_eq!(parsed.verify(), Ok(()));
+    }
+
+    #[test]
+    fn ls2_counts() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let signed = |n_keys: usize, n_leases: u8| {
+            let mut ls = LeaseSet2::new(
+                dsk.dest.clone(),
+                in_secs(0),
+                in_secs(600),
+                vec![EncryptionKey::X25519([7; 32]); n_keys],
+                leases(n_leases, in_secs(600)),
+            );
+            ls.sign(&dsk.signing_private_key).unwrap();
+            serialize(|input| frame::gen_lease_set2(input, &ls))
+        };
+
+        let buf = signed(MAX_ENC_KEYS, MAX_LEASES as u8);
+        let parsed = LeaseSet2::from_bytes(&buf).unwrap();
+        assert_eq!(parsed.enc_keys().len(), MAX_ENC_KEYS);
+        assert_eq!(parsed.leases().len(), MAX_LEASES);
+        assert_eq!(parsed.verify(), Ok(()));
+
+        assert!(frame::lease_set2(&signed(MAX_ENC_KEYS + 1, 1)).is_err());
+        assert!(frame::lease_set2(&signed(1, MAX_LEASES as u8 + 1)).is_err());
+
+        // A lease count is checked against the remaining input before any leases
+        // are parsed
+        let buf = signed(1, MAX_LEASES as u8);
+        let count = buf.len() - 64 - MAX_LEASES * 40 - 1;
+        assert_eq!(usize::from(buf[count]), MAX_LEASES);
+        assert_eq!(
+            frame::lease_set2(&buf[..count + 11]).err(),
+            Some(nom::Err::Incomplete(Needed::new(MAX_LEASES * 40 - 10)))
+        );
+    }
+
+    #[test]
+    fn els2_round_trip() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let mut ls = LeaseSet2::new(
+            dsk.dest.clone(),
+            in_secs(0),
+            in_secs(600),
+            vec![EncryptionKey::X25519([7; 32])],
+            leases(2, in_secs(600)),
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+
+        let now = SystemTime::now();
+        let els = EncryptedLeaseSet2::encrypt(&ls, &dsk.signing_private_key, now).unwrap();
+        assert_eq!(els.verify(), Ok(()));
+        assert_eq!(els.store_key(), dsk.dest.blinded_hash(now).unwrap());
+        assert_eq!(
+            dsk.dest.blinded_lookup_hashes(now).unwrap()[0],
+            els.store_key()
+        );
+        assert_ne!(els.store_key(), dsk.dest.hash());
+        assert_ne!(
+            els.store_key(),
+            dsk.dest
+                .blinded_hash(now + Duration::from_secs(86_400))
+                .unwrap()
+        );
+        assert!(!els.is_expired());
+        assert!(els.is_current(in_secs(0)));
+        assert!(!els.is_current(in_secs(600).saturating_add(CLOCK_SKEW_TOLERANCE)));
+
+        let buf = serialize(|input| frame::gen_encrypted_lease_set2(input, &els));
+        let parsed = match frame::encrypted_lease_set2(&buf) {
+            Ok((rest, parsed)) => {
+                assert!(rest.is_empty());
+                parsed
+            }
+            Err(e) => panic!("Failed to parse EncryptedLeaseSet2: {:?}", e),
+        };
+        assert_eq!(
+            serialize(|input| frame::gen_encrypted_lease_set2(input, &parsed)),
+            buf
+        );
+        assert_eq!(parsed.verify(), Ok(()));
+        assert_eq!(parsed.published, ls.published);
+        assert_eq!(parsed.expires, ls.expires);
+
+        // Only clients that know the Destination can decrypt the inner LeaseSet2
+        let inner = parsed.decrypt(&dsk.dest).unwrap();
+        assert_eq!(inner.verify(), Ok(()));
+        assert_eq!(inner.enc_keys(), ls.enc_keys());
+        assert_eq!(inner.latest_expiry(), Some(in_secs(600)));
+        let other = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        assert!(parsed.decrypt(&other.dest).is_err());
+
+        // Tampering with the encrypted data breaks the outer signature
+        let mut tampered = parsed;
+        tampered.encrypted[40] ^= 0xff;
+        assert_eq!(tampered.verify(), Err(crypto::Error::InvalidSignature));
+    }
+
+    #[test]
+    fn els2_blinding_across_midnight() {
+        // 2019-02-05 00:00:00 UTC
+        let midnight = UNIX_EPOCH + Duration::from_secs(1_549_324_800);
+        let at = |secs_after: i64| {
+            if secs_after < 0 {
+                midnight - Duration::from_secs(-secs_after as u64)
+            } else {
+                midnight + Duration::from_secs(secs_after as u64)
+            }
+        };
+
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let mut ls = LeaseSet2::new(
+            dsk.dest.clone(),
+            in_secs(0),
+            in_secs(600),
+            vec![EncryptionKey::X25519([7; 32])],
+            leases(2, in_secs(600)),
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+
+        // Each side of midnight blinds with its own date
+        let before = EncryptedLeaseSet2::encrypt(&ls, &dsk.signing_private_key, at(-1)).unwrap();
+        let after = EncryptedLeaseSet2::encrypt(&ls, &dsk.signing_private_key, at(0)).unwrap();
+        assert_ne!(before.store_key(), after.store_key());
+        assert_eq!(before.store_key(), dsk.dest.blinded_hash(at(-1)).unwrap());
+        assert_eq!(after.store_key(), dsk.dest.blinded_hash(at(0)).unwrap());
+
+        // Before midnight, lookups only use the current date
+        assert_eq!(
+            dsk.dest.blinded_lookup_hashes(at(-1)).unwrap(),
+            vec![before.store_key()]
+        );
+
+        // Just after midnight, lookups also find the LeaseSet published yesterday
+        assert_eq!(
+            dsk.dest.blinded_lookup_hashes(at(60)).unwrap(),
+            vec![after.store_key(), before.store_key()]
+        );
+
+        // Once the rollover window has passed, only today's LeaseSet is found
+        let window = ROLLOVER_WINDOW.as_secs() as i64;
+        assert_eq!(
+            dsk.dest.blinded_lookup_hashes(at(window)).unwrap(),
+            vec![after.store_key()]
+        );
+
+        // Both blindings verify and decrypt
+        for els in &[before, after] {
+            assert_eq!(els.verify(), Ok(()));
+            assert_eq!(els.decrypt(&dsk.dest).unwrap().enc_keys(), ls.enc_keys());
+        }
+    }
 }
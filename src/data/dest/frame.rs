@@ -1,47 +1,45 @@
 use cookie_factory::*;
 use nom::*;
 use nom::{
-    bytes::streaming::take,
-    combinator::{map, map_res},
-    multi::length_count,
-    number::streaming::be_u8,
-    sequence::{pair, tuple},
+    combinator::{cond, map, verify},
+    error::{Error as NomError, ErrorKind},
+    multi::length_data,
+    number::streaming::{be_u16, be_u8},
+    sequence::{pair, preceded, tuple},
 };
+use std::convert::TryInto;
 
-use super::{Destination, Lease, LeaseSet};
-use crate::constants;
-use crate::crypto::frame::{
-    gen_public_key, gen_signature, gen_signing_key, public_key, signature, signing_key,
+use super::{
+    Destination, DestinationSecretKeys, EncryptedLeaseSet2, EncryptionKey, Lease, LeaseSet,
+    LeaseSet2, OfflineSignature, ELS2_DS_TYPE, ENC_KEY_TYPE_ELGAMAL, ENC_KEY_TYPE_X25519,
+    LS2_DS_TYPE, LS2_FLAG_OFFLINE_KEYS, LS2_FLAG_UNPUBLISHED, MAX_ENC_KEYS, MAX_LEASES,
 };
-use crate::data::frame::{
-    certificate, gen_certificate, gen_hash, gen_i2p_date, gen_truncated_signing_key, gen_tunnel_id,
-    hash, i2p_date, keycert_padding, split_signing_key, tunnel_id,
+use crate::crypto::{
+    frame::{
+        decryption_key, gen_decryption_key, gen_public_key, gen_sig_type, gen_signature,
+        gen_signing_key, gen_signing_private_key, public_key, sig_type, signature, signing_key,
+        signing_private_key,
+    },
+    PublicKey, SigType, SigningPublicKey,
+};
+use crate::data::{
+    frame::{
+        bounded_count, gen_hash, gen_i2p_date, gen_keys_and_cert, gen_mapping, gen_short_expiry,
+        gen_tunnel_id, hash, i2p_date, keys_and_cert, mapping, short_expiry, tunnel_id,
+    },
+    I2PDate,
 };
 
 // Destination
 
-fn destination(i: &[u8]) -> IResult<&[u8], Destination> {
-    map_res(
-        tuple((
+pub fn destination(i: &[u8]) -> IResult<&[u8], Destination> {
+    map(
+        keys_and_cert,
+        |(public_key, padding, signing_key, certificate)| Destination {
             public_key,
-            take(constants::KEYCERT_SIGKEY_BYTES),
+            padding,
+            signing_key,
             certificate,
-        )),
-        |(public_key, signing_data, certificate)| {
-            let padding = keycert_padding(
-                array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
-                &certificate,
-            );
-            split_signing_key(
-                array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
-                &certificate,
-            )
-            .map(|signing_key| Destination {
-                public_key,
-                padding,
-                signing_key,
-                certificate,
-            })
         },
     )(i)
 }
@@ -50,20 +48,20 @@ pub fn gen_destination<'a>(
     input: (&'a mut [u8], usize),
     dest: &Destination,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(
+    gen_keys_and_cert(
         input,
-        gen_public_key(&dest.public_key)
-            >> gen_cond!(
-                dest.padding.is_some(),
-                gen_slice!(dest.padding.as_ref().unwrap().0)
-            )
-            >> gen_truncated_signing_key(&dest.signing_key)
-            >> gen_certificate(&dest.certificate)
+        &dest.public_key,
+        &dest.padding,
+        &dest.signing_key,
+        &dest.certificate,
     )
 }
 
 // Lease
 
+/// Gateway, tunnel ID, and end date.
+const LEASE_SIZE: usize = 32 + 4 + 8;
+
 fn lease(i: &[u8]) -> IResult<&[u8], Lease> {
     map(
         tuple((hash, tunnel_id, i2p_date)),
@@ -85,6 +83,34 @@ fn gen_lease<'a>(
     )
 }
 
+// DestinationSecretKeys
+
+pub fn destination_secret_keys(i: &[u8]) -> IResult<&[u8], DestinationSecretKeys> {
+    let (i, dest) = destination(i)?;
+    let (i, private_key) = decryption_key(dest.encryption_key().enc_type())(i)?;
+    let (i, signing_private_key) = signing_private_key(dest.signing_key.sig_type())(i)?;
+    Ok((
+        i,
+        DestinationSecretKeys {
+            dest,
+            private_key,
+            signing_private_key,
+        },
+    ))
+}
+
+pub fn gen_destination_secret_keys<'a>(
+    input: (&'a mut [u8], usize),
+    dsk: &DestinationSecretKeys,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_destination(&dsk.dest)
+            >> gen_decryption_key(&dsk.private_key)
+            >> gen_signing_private_key(&dsk.signing_private_key)
+    )
+}
+
 // LeaseSet
 
 pub fn lease_set(i: &[u8]) -> IResult<&[u8], LeaseSet> {
@@ -92,7 +118,7 @@ pub fn lease_set(i: &[u8]) -> IResult<&[u8], LeaseSet> {
     let (i, (enc_key, sig_key, leases, sig)) = tuple((
         public_key,
         signing_key(dest.signing_key.sig_type()),
-        length_count(be_u8, lease),
+        bounded_count(verify(be_u8, |n| *n > 0), MAX_LEASES, LEASE_SIZE, lease),
         signature(dest.signing_key.sig_type()),
     ))(i)?;
     Ok((
@@ -130,3 +156,290 @@ pub fn gen_lease_set<'a>(
         gen_lease_set_minus_sig(ls) >> gen_signature(ls.signature.as_ref().unwrap())
     )
 }
+
+// OfflineSignature
+
+pub(crate) fn offline_signature(
+    dest_sig_type: SigType,
+) -> impl Fn(&[u8]) -> IResult<&[u8], OfflineSignature> {
+    move |i: &[u8]| {
+        let (i, (expires, transient_type)) = pair(short_expiry, sig_type)(i)?;
+        let (i, (transient_key, signature)) =
+            pair(signing_key(transient_type), signature(dest_sig_type))(i)?;
+        Ok((
+            i,
+            OfflineSignature {
+                expires,
+                transient_key,
+                signature,
+            },
+        ))
+    }
+}
+
+pub fn gen_offline_signature_minus_sig<'a>(
+    input: (&'a mut [u8], usize),
+    expires: &I2PDate,
+    transient_key: &SigningPublicKey,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_short_expiry(expires)
+            >> gen_sig_type(transient_key.sig_type())
+            >> gen_signing_key(transient_key)
+    )
+}
+
+pub(crate) fn gen_offline_signature<'a>(
+    input: (&'a mut [u8], usize),
+    offline_sig: &OfflineSignature,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_offline_signature_minus_sig(&offline_sig.expires, &offline_sig.transient_key)
+            >> gen_signature(&offline_sig.signature)
+    )
+}
+
+// EncryptionKey
+
+/// Key type and an empty key.
+const ENCRYPTION_KEY_MIN_SIZE: usize = 2 + 2;
+
+fn encryption_key(input: &[u8]) -> IResult<&[u8], EncryptionKey> {
+    let (i, (key_type, data)) = pair(be_u16, length_data(be_u16))(input)?;
+    let key = match (key_type, data.len()) {
+        (ENC_KEY_TYPE_ELGAMAL, 256) => EncryptionKey::ElGamal(PublicKey(data.try_into().unwrap())),
+        (ENC_KEY_TYPE_X25519, 32) => EncryptionKey::X25519(data.try_into().unwrap()),
+        (ENC_KEY_TYPE_ELGAMAL, _) | (ENC_KEY_TYPE_X25519, _) => {
+            return Err(Err::Error(NomError::new(input, ErrorKind::LengthValue)));
+        }
+        (key_type, _) => EncryptionKey::Unsupported(key_type, data.to_vec()),
+    };
+    Ok((i, key))
+}
+
+fn gen_encryption_key<'a>(
+    input: (&'a mut [u8], usize),
+    key: &EncryptionKey,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u16!(key.key_type())
+            >> gen_be_u16!(key.as_bytes().len() as u16)
+            >> gen_slice!(key.as_bytes())
+    )
+}
+
+// Lease2
+
+/// Gateway, tunnel ID, and short end date.
+const LEASE2_SIZE: usize = 32 + 4 + 4;
+
+fn lease2(i: &[u8]) -> IResult<&[u8], Lease> {
+    map(
+        tuple((hash, tunnel_id, short_expiry)),
+        |(tunnel_gw, tid, end_date)| Lease {
+            tunnel_gw,
+            tid,
+            end_date,
+        },
+    )(i)
+}
+
+fn gen_lease2<'a>(
+    input: (&'a mut [u8], usize),
+    lease: &Lease,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_hash(&lease.tunnel_gw)
+            >> gen_tunnel_id(&lease.tid)
+            >> gen_short_expiry(&lease.end_date)
+    )
+}
+
+// LeaseSet2
+
+/// Expiration is stored as an offset from the published date, in seconds.
+fn expires_offset(published: &I2PDate, expires: &I2PDate) -> Result<u16, GenError> {
+    let offset = expires
+        .to_short_expiry()
+        .saturating_sub(published.to_short_expiry());
+    if offset > u32::from(u16::max_value()) {
+        return Err(GenError::CustomError(1));
+    }
+    Ok(offset as u16)
+}
+
+pub fn lease_set2(i: &[u8]) -> IResult<&[u8], LeaseSet2> {
+    let (i, dest) = destination(i)?;
+    let (i, (published, expires, flags)) = tuple((short_expiry, be_u16, be_u16))(i)?;
+    let (i, offline_sig) = cond(
+        flags & LS2_FLAG_OFFLINE_KEYS != 0,
+        offline_signature(dest.signing_key.sig_type()),
+    )(i)?;
+    let (i, (options, enc_keys, leases)) = tuple((
+        mapping,
+        bounded_count(
+            verify(be_u8, |n| *n > 0),
+            MAX_ENC_KEYS,
+            ENCRYPTION_KEY_MIN_SIZE,
+            encryption_key,
+        ),
+        bounded_count(be_u8, MAX_LEASES, LEASE2_SIZE, lease2),
+    ))(i)?;
+    let sig_type = match offline_sig {
+        Some(ref offline_sig) => offline_sig.transient_key.sig_type(),
+        None => dest.signing_key.sig_type(),
+    };
+    let (i, signature) = signature(sig_type)(i)?;
+    Ok((
+        i,
+        LeaseSet2 {
+            dest,
+            published,
+            expires: I2PDate(published.0 + u64::from(expires) * 1_000),
+            unpublished: flags & LS2_FLAG_UNPUBLISHED != 0,
+            offline_sig,
+            options,
+            enc_keys,
+            leases,
+            signature: Some(signature),
+        },
+    ))
+}
+
+fn gen_lease_set2_minus_sig<'a>(
+    input: (&'a mut [u8], usize),
+    ls: &LeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let expires = expires_offset(&ls.published, &ls.expires)?;
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    do_gen!(
+        input,
+        gen_destination(&ls.dest) >>
+        gen_short_expiry(&ls.published) >>
+        gen_be_u16!(expires) >>
+        gen_be_u16!(ls.flags()) >>
+        gen_cond!(
+            ls.offline_sig.is_some(),
+            do_gen!(gen_offline_signature(ls.offline_sig.as_ref().unwrap()))
+        ) >>
+        gen_mapping(&ls.options) >>
+        gen_be_u8!(ls.enc_keys.len() as u8) >>
+        gen_many!(&ls.enc_keys, gen_encryption_key) >>
+        gen_be_u8!(ls.leases.len() as u8) >>
+        gen_many!(&ls.leases, gen_lease2)
+    )
+}
+
+/// The data covered by a LeaseSet2 signature, which is prefixed with the
+/// DatabaseStore type.
+pub fn gen_lease_set2_signed_data<'a>(
+    input: (&'a mut [u8], usize),
+    ls: &LeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u8!(LS2_DS_TYPE) >> gen_lease_set2_minus_sig(ls)
+    )
+}
+
+pub fn gen_lease_set2<'a>(
+    input: (&'a mut [u8], usize),
+    ls: &LeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_lease_set2_minus_sig(ls) >> gen_signature(ls.signature.as_ref().unwrap())
+    )
+}
+
+// EncryptedLeaseSet2
+
+fn blinded_signing_key(i: &[u8]) -> IResult<&[u8], SigningPublicKey> {
+    preceded(
+        verify(sig_type, |sig_type| {
+            *sig_type == SigType::RedDsaSha512Ed25519
+        }),
+        signing_key(SigType::RedDsaSha512Ed25519),
+    )(i)
+}
+
+pub fn encrypted_lease_set2(i: &[u8]) -> IResult<&[u8], EncryptedLeaseSet2> {
+    let (i, (blinded_key, published, expires, flags)) =
+        tuple((blinded_signing_key, short_expiry, be_u16, be_u16))(i)?;
+    let (i, offline_sig) = cond(
+        flags & LS2_FLAG_OFFLINE_KEYS != 0,
+        offline_signature(SigType::RedDsaSha512Ed25519),
+    )(i)?;
+    let (i, encrypted) = length_data(be_u16)(i)?;
+    let sig_type = match offline_sig {
+        Some(ref offline_sig) => offline_sig.transient_key.sig_type(),
+        None => SigType::RedDsaSha512Ed25519,
+    };
+    let (i, signature) = signature(sig_type)(i)?;
+    Ok((
+        i,
+        EncryptedLeaseSet2 {
+            blinded_key,
+            published,
+            expires: I2PDate(published.0 + u64::from(expires) * 1_000),
+            offline_sig,
+            encrypted: encrypted.to_vec(),
+            signature: Some(signature),
+        },
+    ))
+}
+
+fn gen_encrypted_lease_set2_minus_sig<'a>(
+    input: (&'a mut [u8], usize),
+    els: &EncryptedLeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let expires = expires_offset(&els.published, &els.expires)?;
+    let flags = if els.offline_sig.is_some() {
+        LS2_FLAG_OFFLINE_KEYS
+    } else {
+        0
+    };
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    do_gen!(
+        input,
+        gen_sig_type(els.blinded_key.sig_type()) >>
+        gen_signing_key(&els.blinded_key) >>
+        gen_short_expiry(&els.published) >>
+        gen_be_u16!(expires) >>
+        gen_be_u16!(flags) >>
+        gen_cond!(
+            els.offline_sig.is_some(),
+            do_gen!(gen_offline_signature(els.offline_sig.as_ref().unwrap()))
+        ) >>
+        gen_be_u16!(els.encrypted.len() as u16) >>
+        gen_slice!(&els.encrypted)
+    )
+}
+
+/// The data covered by an EncryptedLeaseSet2 signature, which is prefixed with the
+/// DatabaseStore type.
+pub fn gen_encrypted_lease_set2_signed_data<'a>(
+    input: (&'a mut [u8], usize),
+    els: &EncryptedLeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u8!(ELS2_DS_TYPE) >> gen_encrypted_lease_set2_minus_sig(els)
+    )
+}
+
+pub fn gen_encrypted_lease_set2<'a>(
+    input: (&'a mut [u8], usize),
+    els: &EncryptedLeaseSet2,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_encrypted_lease_set2_minus_sig(els) >> gen_signature(els.signature.as_ref().unwrap())
+    )
+}
@@ -0,0 +1,170 @@
+//! The base64 and base32 encodings used by I2P.
+//!
+//! I2P's base64 alphabet replaces `+/` with `-~`, so that encoded data can be
+//! used in URLs and filenames. Base32 is only used for hashes, and is always
+//! lowercase and unpadded.
+
+use data_encoding::{DecodeError, Encoding, Specification, HEXLOWER_PERMISSIVE};
+
+const BASE64_SYMBOLS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~";
+const BASE32_SYMBOLS: &str = "abcdefghijklmnopqrstuvwxyz234567";
+
+lazy_static! {
+    static ref BASE64: Encoding = {
+        let mut spec = Specification::new();
+        spec.symbols.push_str(BASE64_SYMBOLS);
+        spec.padding = Some('=');
+        spec.encoding().unwrap()
+    };
+    static ref BASE64_NOPAD: Encoding = {
+        let mut spec = Specification::new();
+        spec.symbols.push_str(BASE64_SYMBOLS);
+        spec.encoding().unwrap()
+    };
+    static ref BASE32: Encoding = {
+        let mut spec = Specification::new();
+        spec.symbols.push_str(BASE32_SYMBOLS);
+        spec.translate.from.push_str(&BASE32_SYMBOLS.to_uppercase());
+        spec.translate.to.push_str(BASE32_SYMBOLS);
+        spec.encoding().unwrap()
+    };
+}
+
+/// Encodes data in padded I2P base64.
+pub fn b64_encode(data: &[u8]) -> String {
+    BASE64.encode(data)
+}
+
+/// Encodes data in unpadded I2P base64.
+pub fn b64_encode_nopad(data: &[u8]) -> String {
+    BASE64_NOPAD.encode(data)
+}
+
+/// Decodes I2P base64, with or without padding.
+pub fn b64_decode(data: &str) -> Result<Vec<u8>, DecodeError> {
+    if data.len() % 4 == 0 {
+        BASE64.decode(data.as_bytes())
+    } else {
+        BASE64_NOPAD.decode(data.as_bytes())
+    }
+}
+
+/// Encodes data in unpadded lowercase base32.
+pub fn b32_encode(data: &[u8]) -> String {
+    BASE32.encode(data)
+}
+
+/// Decodes unpadded base32, in either case.
+pub fn b32_decode(data: &str) -> Result<Vec<u8>, DecodeError> {
+    BASE32.decode(data.as_bytes())
+}
+
+/// Decodes hex, in either case.
+pub fn hex_decode(data: &str) -> Result<Vec<u8>, DecodeError> {
+    HEXLOWER_PERMISSIVE.decode(data.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestVector {
+        data: &'static [u8],
+        b64: &'static str,
+        b32: &'static str,
+    }
+
+    const TEST_VECTORS: &[TestVector] = &[
+        TestVector {
+            data: b"",
+            b64: "",
+            b32: "",
+        },
+        TestVector {
+            data: b"f",
+            b64: "Zg==",
+            b32: "my",
+        },
+        TestVector {
+            data: b"fo",
+            b64: "Zm8=",
+            b32: "mzxq",
+        },
+        TestVector {
+            data: b"foo",
+            b64: "Zm9v",
+            b32: "mzxw6",
+        },
+        TestVector {
+            data: b"foob",
+            b64: "Zm9vYg==",
+            b32: "mzxw6yq",
+        },
+        TestVector {
+            data: b"fooba",
+            b64: "Zm9vYmE=",
+            b32: "mzxw6ytb",
+        },
+        TestVector {
+            data: b"foobar",
+            b64: "Zm9vYmFy",
+            b32: "mzxw6ytboi",
+        },
+        // These differ from the standard alphabet
+        TestVector {
+            data: &[0xfb, 0xef, 0xbe],
+            b64: "----",
+            b32: "7px34",
+        },
+        TestVector {
+            data: &[0xff, 0xff, 0xff],
+            b64: "~~~~",
+            b32: "77776",
+        },
+        TestVector {
+            data: &[0xfb, 0xff],
+            b64: "-~8=",
+            b32: "7p7q",
+        },
+        // Every base64 symbol, in order
+        TestVector {
+            data: &[
+                0x00, 0x10, 0x83, 0x10, 0x51, 0x87, 0x20, 0x92, 0x8b, 0x30, 0xd3, 0x8f, 0x41, 0x14,
+                0x93, 0x51, 0x55, 0x97, 0x61, 0x96, 0x9b, 0x71, 0xd7, 0x9f, 0x82, 0x18, 0xa3, 0x92,
+                0x59, 0xa7, 0xa2, 0x9a, 0xab, 0xb2, 0xdb, 0xaf, 0xc3, 0x1c, 0xb3, 0xd3, 0x5d, 0xb7,
+                0xe3, 0x9e, 0xbb, 0xf3, 0xdf, 0xbf,
+            ],
+            b64: "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~",
+            b32: "aaiigecrq4qjfczq2ohucfetkfkzoymwtny5ph4cdcrzewnhuknkxmw3v7brzm6tlw36hhv36pp36",
+        },
+    ];
+
+    #[test]
+    fn base64() {
+        for tv in TEST_VECTORS {
+            assert_eq!(b64_encode(tv.data), tv.b64);
+            assert_eq!(b64_decode(tv.b64).unwrap(), tv.data);
+
+            let unpadded = tv.b64.trim_end_matches('=');
+            assert_eq!(b64_encode_nopad(tv.data), unpadded);
+            assert_eq!(b64_decode(unpadded).unwrap(), tv.data);
+        }
+
+        // The standard alphabet is rejected
+        assert!(b64_decode("++++").is_err());
+        assert!(b64_decode("////").is_err());
+        assert!(b64_decode("+~8=").is_err());
+    }
+
+    #[test]
+    fn base32() {
+        for tv in TEST_VECTORS {
+            assert_eq!(b32_encode(tv.data), tv.b32);
+            assert_eq!(b32_decode(tv.b32).unwrap(), tv.data);
+            assert_eq!(b32_decode(&tv.b32.to_uppercase()).unwrap(), tv.data);
+        }
+
+        assert!(b32_decode("my======").is_err());
+        assert!(b32_decode("m0").is_err());
+    }
+}
@@ -1,24 +1,65 @@
 use std::convert::TryInto;
 
 use cookie_factory::*;
-use itertools::Itertools;
 use nom::{
     bytes::streaming::{tag, take},
-    combinator::{complete, map, map_res},
+    combinator::{all_consuming, complete, consumed, map, map_opt, map_res},
     error::{Error as NomError, ErrorKind},
-    multi::{length_count, length_data, length_value, many0},
+    multi::{length_data, length_value, many0},
     number::streaming::{be_u16, be_u32, be_u64, be_u8},
     sequence::{pair, separated_pair, terminated, tuple},
-    IResult,
+    IResult, Parser,
 };
 
 use super::*;
 use crate::constants;
 use crate::crypto::frame::{
-    enc_type, gen_enc_type, gen_private_key, gen_public_key, gen_sig_type, gen_signature,
-    gen_signing_private_key, private_key, public_key, sig_type, signature, signing_private_key,
+    decryption_key, gen_decryption_key, gen_enc_type, gen_public_key, gen_sig_type, gen_signature,
+    gen_signing_private_key, signature, signing_private_key,
 };
 
+//
+// Bounded repetition
+//
+
+/// Parses a count with `count`, followed by that many items parsed with `item`.
+///
+/// Counts larger than `max` are rejected. Each item takes up at least
+/// `min_item_size` bytes, so the remaining input is checked against the count
+/// before anything is allocated; a large count at the end of a short buffer is
+/// reported as incomplete instead of reserving space for items that aren't there.
+pub(crate) fn bounded_count<'a, C, N, F, O>(
+    mut count: C,
+    max: usize,
+    min_item_size: usize,
+    mut item: F,
+) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Vec<O>>
+where
+    C: Parser<&'a [u8], N, NomError<&'a [u8]>>,
+    N: Into<usize>,
+    F: Parser<&'a [u8], O, NomError<&'a [u8]>>,
+{
+    move |input: &'a [u8]| {
+        let (mut i, n) = count.parse(input)?;
+        let n = n.into();
+        if n > max {
+            return Err(nom::Err::Error(NomError::new(input, ErrorKind::Verify)));
+        }
+        let needed = n * min_item_size;
+        if i.len() < needed {
+            return Err(nom::Err::Incomplete(Needed::new(needed - i.len())));
+        }
+
+        let mut items = Vec::with_capacity(n);
+        for _ in 0..n {
+            let (rest, o) = item.parse(i)?;
+            items.push(o);
+            i = rest;
+        }
+        Ok((i, items))
+    }
+}
+
 //
 // Simple data types
 //
@@ -36,7 +77,7 @@ pub fn gen_hash<'a>(
 }
 
 pub fn i2p_date(i: &[u8]) -> IResult<&[u8], I2PDate> {
-    map(be_u64, I2PDate)(i)
+    map_opt(be_u64, I2PDate::from_millis)(i)
 }
 pub fn gen_i2p_date<'a>(
     input: (&'a mut [u8], usize),
@@ -45,38 +86,37 @@ pub fn gen_i2p_date<'a>(
     gen_be_u64!(input, date.0)
 }
 pub fn short_expiry(i: &[u8]) -> IResult<&[u8], I2PDate> {
-    map(be_u32, |seconds| I2PDate(u64::from(seconds) * 1_000))(i)
+    map(be_u32, I2PDate::from_short_expiry)(i)
 }
 pub fn gen_short_expiry<'a>(
     input: (&'a mut [u8], usize),
     date: &I2PDate,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    gen_be_u32!(input, date.0 / 1_000)
+    gen_be_u32!(input, date.to_short_expiry())
 }
 
 pub fn i2p_string(i: &[u8]) -> IResult<&[u8], I2PString> {
-    map_res(length_data(be_u8), |s: &[u8]| {
-        String::from_utf8(s.to_vec()).map(I2PString)
-    })(i)
+    // The length prefix means this can never exceed I2PSTRING_MAX_LEN
+    map(length_data(be_u8), |s: &[u8]| I2PString(s.to_vec()))(i)
 }
 pub fn gen_i2p_string<'a>(
     input: (&'a mut [u8], usize),
     s: &I2PString,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    let buf = s.0.as_bytes();
+    let buf = s.as_bytes();
     do_gen!(input, gen_be_u8!(buf.len() as u8) >> gen_slice!(buf))
 }
 
 pub fn mapping(i: &[u8]) -> IResult<&[u8], Mapping> {
-    map(
+    map_res(
         length_value(
             be_u16,
-            many0(complete(terminated(
+            all_consuming(many0(complete(terminated(
                 separated_pair(i2p_string, tag("="), i2p_string),
                 tag(";"),
-            ))),
+            )))),
         ),
-        |pairs| Mapping(pairs.into_iter().collect()),
+        Mapping::from_pairs,
     )(i)
 }
 pub fn gen_mapping_pair<'a>(
@@ -92,12 +132,18 @@ pub fn gen_mapping<'a>(
     input: (&'a mut [u8], usize),
     m: &Mapping,
 ) -> Result<(&'a mut [u8], usize), GenError> {
+    // Each pair is two length-prefixed strings plus the '=' and ';' separators
+    let len: usize = m.0.iter().map(|(k, v)| k.len() + v.len() + 4).sum();
+    if len > usize::from(u16::MAX) {
+        return Err(GenError::CustomError(1));
+    }
+
     #[cfg_attr(rustfmt, rustfmt_skip)]
     do_gen!(
         input,
         size:  gen_skip!(2) >>
-        // Some structures require the Mapping be sorted, so just sort them all
-        start: gen_many!(m.0.iter().sorted(), gen_mapping_pair) >>
+        // BTreeMap iterates in key order, which signed structures require
+        start: gen_many!(m.0.iter(), gen_mapping_pair) >>
         end:   gen_at_offset!(size, gen_be_u16!(end - start))
     )
 }
@@ -124,67 +170,8 @@ pub fn gen_tunnel_id<'a>(
     gen_be_u32!(input, tid.0)
 }
 
-// SigningPublicKey
-
-pub(crate) fn split_signing_key(
-    base_data: &[u8; constants::KEYCERT_SIGKEY_BYTES],
-    cert: &Certificate,
-) -> Result<SigningPublicKey, crypto::Error> {
-    match cert {
-        Certificate::Key(kc) => {
-            if kc.sig_type.extra_data_len(kc.enc_type) > 0 {
-                let mut data = Vec::from(&base_data[..]);
-                data.extend(&kc.sig_data);
-                SigningPublicKey::from_bytes(kc.sig_type, &data)
-            } else {
-                let pad = kc.sig_type.pad_len(kc.enc_type);
-                SigningPublicKey::from_bytes(kc.sig_type, &base_data[pad..])
-            }
-        }
-        _ => SigningPublicKey::from_bytes(SigType::DsaSha1, &base_data[..]),
-    }
-}
-
-pub(crate) fn gen_truncated_signing_key<'a>(
-    input: (&'a mut [u8], usize),
-    key: &SigningPublicKey,
-) -> Result<(&'a mut [u8], usize), GenError> {
-    if key.as_bytes().len() > constants::KEYCERT_SIGKEY_BYTES {
-        gen_slice!(input, key.as_bytes()[0..constants::KEYCERT_SIGKEY_BYTES])
-    } else {
-        gen_slice!(input, key.as_bytes())
-    }
-}
-
 // KeyCertificate
 
-pub(crate) fn keycert_padding(base_data: &[u8; 128], cert: &Certificate) -> Option<Padding> {
-    let pad_len = match cert {
-        Certificate::Key(kc) => Some(kc.sig_type.pad_len(kc.enc_type)),
-        _ => None,
-    };
-    match pad_len {
-        Some(pad_len) if pad_len > 0 => Some(Padding(Vec::from(&base_data[0..pad_len]))),
-        _ => None,
-    }
-}
-
-fn key_certificate(i: &[u8]) -> IResult<&[u8], KeyCertificate> {
-    let (i, (sig_type, enc_type)) = pair(sig_type, enc_type)(i)?;
-    map(
-        pair(
-            take(sig_type.extra_data_len(enc_type)),
-            take(enc_type.extra_data_len(sig_type)),
-        ),
-        move |(sig_data, enc_data)| KeyCertificate {
-            sig_type,
-            enc_type,
-            sig_data: Vec::from(sig_data),
-            enc_data: Vec::from(enc_data),
-        },
-    )(i)
-}
-
 fn gen_key_certificate<'a>(
     input: (&'a mut [u8], usize),
     kc: &KeyCertificate,
@@ -195,27 +182,17 @@ fn gen_key_certificate<'a>(
             >> gen_enc_type(kc.enc_type)
             >> gen_slice!(&kc.sig_data)
             >> gen_slice!(&kc.enc_data)
+            >> gen_slice!(&kc.excess)
     )
 }
 
 // Certificate
 
 pub fn certificate(i: &[u8]) -> IResult<&[u8], Certificate> {
-    let (i, cert_type) = be_u8(i)?;
-    match cert_type {
-        constants::NULL_CERT => map(tag(b"\x00\x00"), |_| Certificate::Null)(i),
-        constants::HASH_CERT => map(length_data(be_u16), |payload| {
-            Certificate::HashCash(Vec::from(payload))
-        })(i),
-        constants::HIDDEN_CERT => map(tag(b"\x00\x00"), |_| Certificate::Hidden)(i),
-        constants::SIGNED_CERT => map(length_data(be_u16), |payload| {
-            Certificate::Signed(Vec::from(payload))
-        })(i),
-        constants::MULTI_CERT => map(length_data(be_u16), |payload| {
-            Certificate::Multiple(Vec::from(payload))
-        })(i),
-        constants::KEY_CERT => map(length_value(be_u16, key_certificate), Certificate::Key)(i),
-        _ => Err(nom::Err::Error(NomError::new(i, ErrorKind::Switch))),
+    let (rest, (code, payload)) = pair(be_u8, length_data(be_u16))(i)?;
+    match Certificate::from_payload(code, payload) {
+        Ok(cert) => Ok((rest, cert)),
+        Err(_) => Err(nom::Err::Error(NomError::new(i, ErrorKind::Verify))),
     }
 }
 
@@ -260,33 +237,195 @@ pub fn gen_certificate<'a>(
             start: gen_key_certificate(&kc) >>
             end:   gen_at_offset!(size, gen_be_u16!(end - start))
         ),
+        Certificate::Unknown(code, ref payload) => do_gen!(
+            input,
+            gen_be_u8!(code) >>
+            gen_be_u16!(payload.len() as u16) >>
+            gen_slice!(&payload)
+        ),
     }
 }
 
+// KeysAndCert
+
+/// The layout of the signing key field of a KeysAndCert, as determined by its
+/// certificate.
+///
+/// Signing keys shorter than the field are right-aligned, and the bytes before
+/// them are padding (which must be preserved exactly, as it is covered by
+/// signatures). Signing keys longer than the field continue in the key
+/// certificate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct KeysLayout {
+    pub(crate) sig_type: SigType,
+    /// Bytes of padding at the start of the signing key field.
+    pub(crate) padding: usize,
+    /// Bytes of the signing key that are stored in the key certificate.
+    pub(crate) excess: usize,
+}
+
+impl KeysLayout {
+    pub(crate) fn new(cert: &Certificate) -> Self {
+        match cert {
+            Certificate::Key(kc) => KeysLayout {
+                sig_type: kc.sig_type,
+                padding: kc.sig_type.pad_len(kc.enc_type),
+                excess: kc.sig_type.extra_data_len(kc.enc_type),
+            },
+            _ => KeysLayout {
+                sig_type: SigType::DsaSha1,
+                padding: 0,
+                excess: 0,
+            },
+        }
+    }
+
+    /// Splits the signing key field into its padding and the signing key.
+    fn split(
+        &self,
+        field: &[u8],
+        cert: &Certificate,
+    ) -> Result<(Option<Padding>, SigningPublicKey), crypto::Error> {
+        let (padding, key_start) = field.split_at(self.padding);
+        let mut key_data = key_start.to_vec();
+        if let Certificate::Key(kc) = cert {
+            key_data.extend(&kc.sig_data);
+        }
+
+        let padding = if padding.is_empty() {
+            None
+        } else {
+            Some(Padding(padding.to_vec()))
+        };
+        SigningPublicKey::from_bytes(self.sig_type, &key_data).map(|key| (padding, key))
+    }
+
+    /// Returns the part of the signing key that is stored in the signing key field,
+    /// after checking that the given padding and signing key match this layout.
+    fn field_key<'a>(
+        &self,
+        padding: &Option<Padding>,
+        signing_key: &'a SigningPublicKey,
+        cert: &Certificate,
+    ) -> Option<&'a [u8]> {
+        let padding_len = padding.as_ref().map(|p| p.0.len()).unwrap_or(0);
+        let key = signing_key.as_bytes();
+        if signing_key.sig_type() != self.sig_type
+            || padding_len != self.padding
+            || key.len() + self.padding != constants::KEYCERT_SIGKEY_BYTES + self.excess
+        {
+            return None;
+        }
+
+        let (field_key, excess) = key.split_at(key.len() - self.excess);
+        match cert {
+            Certificate::Key(kc) if kc.sig_data != excess => None,
+            _ => Some(field_key),
+        }
+    }
+}
+
+pub(crate) type KeysAndCert = (PublicKey, Option<Padding>, SigningPublicKey, Certificate);
+
+/// Parses a KeysAndCert, checking that it takes up exactly the number of bytes
+/// that its certificate dictates.
+pub(crate) fn checked_keys_and_cert(i: &[u8]) -> Result<(&[u8], KeysAndCert), KeysAndCertError> {
+    let truncated = |needed| KeysAndCertError::Truncated {
+        needed,
+        available: i.len(),
+    };
+
+    // Public key, signing key field, and certificate header
+    let cert_start = 256 + constants::KEYCERT_SIGKEY_BYTES;
+    let header_len = cert_start + 3;
+    if i.len() < header_len {
+        return Err(truncated(header_len));
+    }
+    let payload_len = usize::from(u16::from_be_bytes([i[cert_start + 1], i[cert_start + 2]]));
+    let len = header_len + payload_len;
+    if i.len() < len {
+        return Err(truncated(len));
+    }
+    let (data, rest) = i.split_at(len);
+
+    let certificate = Certificate::from_payload(data[cert_start], &data[header_len..])?;
+    certificate.check_for_keys()?;
+    let layout = KeysLayout::new(&certificate);
+    if let Certificate::Key(ref kc) = certificate {
+        let expected = 4 + layout.excess + kc.enc_type.extra_data_len(kc.sig_type);
+        if payload_len != expected {
+            return Err(KeysAndCertError::KeyCertificateLength {
+                expected,
+                actual: payload_len,
+            });
+        }
+    }
+
+    let public_key = PublicKey(*array_ref![data, 0, 256]);
+    let (padding, signing_key) = layout
+        .split(&data[256..cert_start], &certificate)
+        .map_err(|_| KeysAndCertError::InvalidSigningKey(layout.sig_type))?;
+
+    debug_assert_eq!(
+        serialize(|input| gen_keys_and_cert(
+            input,
+            &public_key,
+            &padding,
+            &signing_key,
+            &certificate
+        )),
+        data
+    );
+    Ok((rest, (public_key, padding, signing_key, certificate)))
+}
+
+pub(crate) fn keys_and_cert(i: &[u8]) -> IResult<&[u8], KeysAndCert> {
+    match checked_keys_and_cert(i) {
+        Ok(res) => Ok(res),
+        Err(KeysAndCertError::Truncated { needed, available }) => {
+            Err(nom::Err::Incomplete(Needed::new(needed - available)))
+        }
+        Err(_) => Err(nom::Err::Error(NomError::new(i, ErrorKind::Verify))),
+    }
+}
+
+pub(crate) fn gen_keys_and_cert<'a>(
+    input: (&'a mut [u8], usize),
+    public_key: &PublicKey,
+    padding: &Option<Padding>,
+    signing_key: &SigningPublicKey,
+    certificate: &Certificate,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let field_key = match KeysLayout::new(certificate).field_key(padding, signing_key, certificate)
+    {
+        Some(field_key) => field_key,
+        None => return Err(GenError::CustomError(1)),
+    };
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    do_gen!(
+        input,
+        gen_public_key(public_key) >>
+        gen_cond!(
+            padding.is_some(),
+            gen_slice!(padding.as_ref().unwrap().0)
+        ) >>
+        gen_slice!(field_key) >>
+        gen_certificate(certificate)
+    )
+}
+
 // RouterIdentity
 
 pub fn router_identity(i: &[u8]) -> IResult<&[u8], RouterIdentity> {
-    map_res(
-        tuple((
+    map(
+        consumed(keys_and_cert),
+        |(data, (public_key, padding, signing_key, certificate))| RouterIdentity {
             public_key,
-            take(constants::KEYCERT_SIGKEY_BYTES),
+            padding,
+            signing_key,
             certificate,
-        )),
-        |(public_key, signing_data, certificate)| {
-            let padding = keycert_padding(
-                array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
-                &certificate,
-            );
-            split_signing_key(
-                array_ref![signing_data, 0, constants::KEYCERT_SIGKEY_BYTES],
-                &certificate,
-            )
-            .map(|signing_key| RouterIdentity {
-                public_key,
-                padding,
-                signing_key,
-                certificate,
-            })
+            hash: Hash::digest(data),
         },
     )(i)
 }
@@ -295,23 +434,20 @@ pub fn gen_router_identity<'a>(
     input: (&'a mut [u8], usize),
     rid: &RouterIdentity,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    do_gen!(
+    gen_keys_and_cert(
         input,
-        gen_public_key(&rid.public_key) >>
-        gen_cond!(
-            rid.padding.is_some(),
-            gen_slice!(rid.padding.as_ref().unwrap().0)
-        ) >>
-        gen_truncated_signing_key(&rid.signing_key) >>
-        gen_certificate(&rid.certificate)
+        &rid.public_key,
+        &rid.padding,
+        &rid.signing_key,
+        &rid.certificate,
     )
 }
 
 // RouterSecretKeys
 
 pub fn router_secret_keys(i: &[u8]) -> IResult<&[u8], RouterSecretKeys> {
-    let (i, (rid, private_key)) = pair(router_identity, private_key)(i)?;
+    let (i, rid) = router_identity(i)?;
+    let (i, private_key) = decryption_key(rid.encryption_key().enc_type())(i)?;
     let (i, signing_private_key) = signing_private_key(rid.signing_key.sig_type())(i)?;
     Ok((
         i,
@@ -330,14 +466,14 @@ pub fn gen_router_secret_keys<'a>(
     do_gen!(
         input,
         gen_router_identity(&rsk.rid)
-            >> gen_private_key(&rsk.private_key)
+            >> gen_decryption_key(&rsk.private_key)
             >> gen_signing_private_key(&rsk.signing_private_key)
     )
 }
 
 // RouterAddress
 
-fn router_address(i: &[u8]) -> IResult<&[u8], RouterAddress> {
+pub fn router_address(i: &[u8]) -> IResult<&[u8], RouterAddress> {
     map(
         tuple((be_u8, i2p_date, i2p_string, mapping)),
         |(cost, expiration, transport_style, options)| RouterAddress {
@@ -349,7 +485,7 @@ fn router_address(i: &[u8]) -> IResult<&[u8], RouterAddress> {
     )(i)
 }
 
-fn gen_router_address<'a>(
+pub fn gen_router_address<'a>(
     input: (&'a mut [u8], usize),
     addr: &RouterAddress,
 ) -> Result<(&'a mut [u8], usize), GenError> {
@@ -365,12 +501,20 @@ fn gen_router_address<'a>(
 
 // RouterInfo
 
+/// Cost, expiration, and empty transport style and options.
+const ROUTER_ADDRESS_MIN_SIZE: usize = 1 + 8 + 1 + 2;
+
 pub fn router_info(i: &[u8]) -> IResult<&[u8], RouterInfo> {
     let (i, router_id) = router_identity(i)?;
     let (i, (published, addresses, peers, options, signature)) = tuple((
         i2p_date,
-        length_count(be_u8, router_address),
-        length_count(be_u8, hash),
+        bounded_count(
+            be_u8,
+            MAX_ROUTER_ADDRESSES,
+            ROUTER_ADDRESS_MIN_SIZE,
+            router_address,
+        ),
+        bounded_count(be_u8, MAX_ROUTER_PEERS, 32, hash),
         mapping,
         signature(router_id.signing_key.sig_type()),
     ))(i)?;
@@ -417,9 +561,161 @@ pub fn gen_router_info<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::ROUTER_INFO;
+    use crate::data::dest::frame::{destination, gen_destination};
+    use crate::tests::{RI_SIGTYPE_1, RI_SIGTYPE_11, RI_SIGTYPE_2, RI_SIGTYPE_3, ROUTER_INFO};
 
     use nom::{Err, HexDisplay};
+    use rand::{rngs::OsRng, Rng};
+
+    #[test]
+    fn test_i2p_date() {
+        for &millis in [0, 1, 1_524_874_654_321, i64::MAX as u64].iter() {
+            let data = millis.to_be_bytes();
+            let date = I2PDate::from_millis(millis).unwrap();
+            assert_eq!(i2p_date(&data), Ok((&[][..], date)));
+            assert_eq!(serialize(|input| gen_i2p_date(input, &date)), data);
+        }
+
+        // Random dates round-trip
+        let mut rng = OsRng;
+        for _ in 0..64 {
+            let date = I2PDate(rng.gen_range(0..=I2PDate::MAX.0));
+            let data = serialize(|input| gen_i2p_date(input, &date));
+            assert_eq!(i2p_date(&data), Ok((&[][..], date)));
+        }
+
+        // Dates beyond the range of a signed long are rejected
+        for &millis in [i64::MAX as u64 + 1, u64::MAX].iter() {
+            let data = millis.to_be_bytes();
+            assert_eq!(
+                i2p_date(&data),
+                Err(Err::Error(NomError::new(&data[..], ErrorKind::MapOpt)))
+            );
+        }
+
+        // Truncated
+        assert_eq!(i2p_date(&[0; 7]), Err(Err::Incomplete(Needed::new(1))));
+        assert_eq!(i2p_date(&[]), Err(Err::Incomplete(Needed::new(8))));
+    }
+
+    #[test]
+    fn test_i2p_string() {
+        for &len in [0, 1, 254, 255].iter() {
+            let s = I2PString::new(&"a".repeat(len));
+            let data = serialize(|input| gen_i2p_string(input, &s));
+            assert_eq!(data.len(), len + 1);
+            assert_eq!(data[0] as usize, len);
+            assert_eq!(i2p_string(&data), Ok((&[][..], s)));
+        }
+
+        // Arbitrary bytes (usually not UTF-8) round-trip
+        let mut rng = OsRng;
+        for _ in 0..64 {
+            let mut bytes = vec![0; rng.gen_range(0..=I2PSTRING_MAX_LEN)];
+            rng.fill(&mut bytes[..]);
+            let s = I2PString::from_bytes(&bytes).unwrap();
+            let data = serialize(|input| gen_i2p_string(input, &s));
+            assert_eq!(&data[1..], &bytes[..]);
+            assert_eq!(i2p_string(&data), Ok((&[][..], s)));
+        }
+
+        // Truncated
+        assert_eq!(i2p_string(&[]), Err(Err::Incomplete(Needed::new(1))));
+        assert_eq!(
+            i2p_string(&[3, b'a', b'b']),
+            Err(Err::Incomplete(Needed::new(1)))
+        );
+        assert_eq!(i2p_string(&[255]), Err(Err::Incomplete(Needed::new(255))));
+    }
+
+    #[test]
+    fn test_mapping() {
+        fn pair(key: &str, value: &str) -> Vec<u8> {
+            let mut buf = vec![key.len() as u8];
+            buf.extend_from_slice(key.as_bytes());
+            buf.push(b'=');
+            buf.push(value.len() as u8);
+            buf.extend_from_slice(value.as_bytes());
+            buf.push(b';');
+            buf
+        }
+        fn encode(pairs: &[Vec<u8>]) -> Vec<u8> {
+            let body = pairs.concat();
+            let mut buf = (body.len() as u16).to_be_bytes().to_vec();
+            buf.extend(body);
+            buf
+        }
+
+        // Unsorted input is accepted, and re-serialized in sorted order
+        let unsorted = encode(&[pair("zzz", "3"), pair("a", "1"), pair("mm", "2")]);
+        let sorted = encode(&[pair("a", "1"), pair("mm", "2"), pair("zzz", "3")]);
+        let (rest, m) = mapping(&unsorted).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(m.get(&"mm".into()), Some(&"2".into()));
+        let mut buf = vec![0; sorted.len()];
+        gen_mapping((&mut buf, 0), &m).unwrap();
+        assert_eq!(buf, sorted);
+
+        // Parsed values containing separators are preserved
+        let separators = encode(&[pair("k", "a=b;c")]);
+        let (_, m) = mapping(&separators).unwrap();
+        assert_eq!(m.get(&"k".into()), Some(&"a=b;c".into()));
+
+        // Duplicate keys are rejected
+        let duplicates = encode(&[pair("a", "1"), pair("b", "2"), pair("a", "3")]);
+        assert_eq!(
+            mapping(&duplicates),
+            Err(Err::Error(NomError::new(&duplicates[..], ErrorKind::MapRes)))
+        );
+        assert_eq!(
+            Mapping::from_pairs(vec![("a".into(), "1".into()), ("a".into(), "1".into())]),
+            Err(MappingError::DuplicateKey("a".into()))
+        );
+
+        // Entries must fill the declared length, instead of anything after the last
+        // complete entry being dropped
+        let partial = encode(&[pair("a", "1"), vec![1, b'b']]);
+        assert_eq!(
+            mapping(&partial),
+            Err(Err::Error(NomError::new(&[1, b'b'][..], ErrorKind::Eof)))
+        );
+
+        // Empty
+        let (_, m) = mapping(&[0, 0]).unwrap();
+        assert_eq!(m, Mapping::new());
+    }
+
+    #[test]
+    fn test_mapping_limits() {
+        let max = "x".repeat(I2PSTRING_MAX_LEN);
+
+        let mut m = Mapping::new();
+        assert_eq!(m.insert(max.as_str().into(), "v".into()), Ok(None));
+        assert_eq!(m.insert("k".into(), max.as_str().into()), Ok(None));
+        for s in &["a=b", "a;b", "=", ";"] {
+            assert_eq!(
+                m.insert((*s).into(), "v".into()),
+                Err(MappingError::InvalidCharacter((*s).into()))
+            );
+            assert_eq!(
+                m.insert("k".into(), (*s).into()),
+                Err(MappingError::InvalidCharacter((*s).into()))
+            );
+        }
+        assert_eq!(m.insert("k".into(), "v".into()), Ok(Some(max.as_str().into())));
+
+        // Boundary-length strings round-trip
+        let buf = serialize(|input| gen_mapping(input, &m));
+        assert_eq!(buf.len(), 2 + (1 + 255 + 1 + 1 + 1 + 1) + (1 + 1 + 1 + 1 + 1 + 1));
+        assert_eq!(mapping(&buf), Ok((&[][..], m)));
+
+        // The Mapping must fit in a u16 length
+        let mut m = Mapping::new();
+        for i in 0..256 {
+            m.insert(format!("{:03}", i).as_str().into(), max.as_str().into()).unwrap();
+        }
+        assert!(gen_mapping((&mut vec![0; 70_000], 0), &m).is_err());
+    }
 
     #[test]
     fn test_router_info() {
@@ -435,8 +731,8 @@ mod tests {
                 assert_eq!(ri.addresses.len(), 2);
                 assert_eq!(ri.peers.len(), 0);
                 assert_eq!(
-                    ri.options.0[&I2PString(String::from("caps"))],
-                    I2PString(String::from("L"))
+                    ri.options.0[&I2PString::new("caps")],
+                    I2PString::new("L")
                 );
 
                 // Test generation
@@ -461,4 +757,287 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_bounded_count() {
+        let mut parser = bounded_count(be_u8, 3, 2, be_u16);
+        assert_eq!(parser(&[0, 7]), Ok((&[7][..], vec![])));
+        assert_eq!(
+            parser(&[3, 0, 1, 0, 2, 0, 3, 7]),
+            Ok((&[7][..], vec![1, 2, 3]))
+        );
+
+        // Counts above the maximum are rejected, even if the items are present
+        let too_many = [4, 0, 1, 0, 2, 0, 3, 0, 4];
+        assert_eq!(
+            parser(&too_many),
+            Err(Err::Error(NomError::new(&too_many[..], ErrorKind::Verify)))
+        );
+
+        // The whole count is requested up front
+        assert_eq!(parser(&[3, 0]), Err(Err::Incomplete(Needed::new(5))));
+        assert_eq!(
+            bounded_count(be_u16, 65535, 32, hash)(&[0xff, 0xff]),
+            Err(Err::Incomplete(Needed::new(65535 * 32)))
+        );
+    }
+
+    #[test]
+    fn router_info_counts() {
+        let (rest, _) = router_identity(ROUTER_INFO).unwrap();
+        let count = ROUTER_INFO.len() - rest.len() + 8;
+        assert_eq!(ROUTER_INFO[count], 2);
+
+        // Too many addresses
+        let mut data = ROUTER_INFO.to_vec();
+        data[count] = MAX_ROUTER_ADDRESSES as u8 + 1;
+        match router_info(&data) {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::Verify),
+            v => panic!("Unexpected returned value: {:?}", v),
+        }
+
+        // An address count that can't fit in the remaining input
+        data[count] = MAX_ROUTER_ADDRESSES as u8;
+        assert_eq!(
+            router_info(&data[..count + 21]),
+            Err(Err::Incomplete(Needed::new(
+                MAX_ROUTER_ADDRESSES * ROUTER_ADDRESS_MIN_SIZE - 20
+            )))
+        );
+    }
+
+    #[test]
+    fn keys_layout() {
+        let layout = |sig_type, enc_type| {
+            KeysLayout::new(&Certificate::Key(KeyCertificate {
+                sig_type,
+                enc_type,
+                sig_data: vec![],
+                enc_data: vec![],
+                excess: vec![],
+            }))
+        };
+        let expected = |sig_type, padding, excess| KeysLayout {
+            sig_type,
+            padding,
+            excess,
+        };
+
+        assert_eq!(
+            KeysLayout::new(&Certificate::Null),
+            expected(SigType::DsaSha1, 0, 0)
+        );
+        for &enc_type in [EncType::ElGamal2048, EncType::X25519].iter() {
+            for &(sig_type, padding, excess) in [
+                (SigType::DsaSha1, 0, 0),
+                (SigType::EcdsaSha256P256, 64, 0),
+                (SigType::EcdsaSha384P384, 32, 0),
+                (SigType::EcdsaSha512P521, 0, 4),
+                (SigType::Rsa2048Sha256, 0, 128),
+                (SigType::Rsa3072Sha384, 0, 256),
+                (SigType::Rsa4096Sha512, 0, 384),
+                (SigType::Ed25519, 96, 0),
+                (SigType::RedDsaSha512Ed25519, 96, 0),
+            ]
+            .iter()
+            {
+                assert_eq!(
+                    layout(sig_type, enc_type),
+                    expected(sig_type, padding, excess)
+                );
+            }
+        }
+    }
+
+    /// Builds the wire encoding of a KeysAndCert independently of the parser.
+    fn keys_and_cert_bytes(
+        public_key: &[u8],
+        padding: &[u8],
+        signing_key: &[u8],
+        key_cert: Option<(SigType, EncType)>,
+    ) -> Vec<u8> {
+        let field_len = constants::KEYCERT_SIGKEY_BYTES - padding.len();
+        let mut data = public_key.to_vec();
+        data.extend_from_slice(padding);
+        data.extend_from_slice(&signing_key[..field_len]);
+        match key_cert {
+            Some((sig_type, enc_type)) => {
+                let excess = &signing_key[field_len..];
+                data.push(constants::KEY_CERT);
+                data.extend_from_slice(&(4 + excess.len() as u16).to_be_bytes());
+                data.extend_from_slice(&sig_type.code().to_be_bytes());
+                data.extend_from_slice(&enc_type.code().to_be_bytes());
+                data.extend_from_slice(excess);
+            }
+            None => data.extend_from_slice(&[constants::NULL_CERT, 0, 0]),
+        }
+        data
+    }
+
+    #[test]
+    fn keys_and_cert_round_trip() {
+        let mut rng = OsRng;
+
+        // Signing keys of every supported type
+        let mut signing_keys = vec![];
+        for &data in [
+            &RI_SIGTYPE_1[..],
+            &RI_SIGTYPE_2[..],
+            &RI_SIGTYPE_3[..],
+            &ROUTER_INFO[..],
+            &RI_SIGTYPE_11[..],
+        ]
+        .iter()
+        {
+            let (_, rid) = router_identity(data).unwrap();
+            signing_keys.push(rid.signing_key);
+        }
+
+        for _ in 0..16 {
+            let mut public_key = [0; 256];
+            rng.fill(&mut public_key[..]);
+
+            // DSA identities have no key certificate
+            let mut dsa_key = [0; 128];
+            rng.fill(&mut dsa_key[..]);
+            let mut cases = vec![keys_and_cert_bytes(&public_key, &[], &dsa_key, None)];
+
+            for &enc_type in [EncType::ElGamal2048, EncType::X25519].iter() {
+                cases.push(keys_and_cert_bytes(
+                    &public_key,
+                    &[],
+                    &dsa_key,
+                    Some((SigType::DsaSha1, enc_type)),
+                ));
+                for signing_key in &signing_keys {
+                    let sig_type = signing_key.sig_type();
+                    let mut padding = vec![0; sig_type.pad_len(enc_type)];
+                    rng.fill(&mut padding[..]);
+                    cases.push(keys_and_cert_bytes(
+                        &public_key,
+                        &padding,
+                        signing_key.as_bytes(),
+                        Some((sig_type, enc_type)),
+                    ));
+                }
+            }
+
+            for data in cases {
+                let (rest, rid) = router_identity(&data).unwrap();
+                assert!(rest.is_empty());
+                assert_eq!(rid.to_bytes(), data);
+
+                let (rest, dest) = destination(&data).unwrap();
+                assert!(rest.is_empty());
+                assert_eq!(serialize(|input| gen_destination(input, &dest)), data);
+                assert_eq!(dest.signing_key, rid.signing_key);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_and_cert_strict() {
+        let (_, p521) = router_identity(&RI_SIGTYPE_3[..]).unwrap();
+        let data = p521.to_bytes();
+        assert_eq!(data.len(), 256 + 128 + 3 + 8);
+        assert_eq!(RouterIdentity::from_bytes(&data), Ok(p521.clone()));
+
+        let err = |e| Err(ReadError::KeysAndCert(e));
+
+        // Extra trailing bytes
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(&[0xab, 0xcd]);
+        assert_eq!(
+            RouterIdentity::from_bytes(&trailing),
+            Err(ReadError::TrailingData(2))
+        );
+        let (rest, rid) = router_identity(&trailing).unwrap();
+        assert_eq!(rest, &[0xab, 0xcd]);
+        assert_eq!(rid, p521);
+
+        // Truncated data
+        assert_eq!(
+            checked_keys_and_cert(&data[..300]).err(),
+            Some(KeysAndCertError::Truncated {
+                needed: 387,
+                available: 300
+            })
+        );
+        assert_eq!(
+            RouterIdentity::from_bytes(&data[..390]),
+            err(KeysAndCertError::Truncated {
+                needed: 395,
+                available: 390
+            })
+        );
+        assert_eq!(
+            keys_and_cert(&data[..390]).err(),
+            Some(Err::Incomplete(Needed::new(5)))
+        );
+
+        // A signing key too long for its field, without the rest of it in the key
+        // certificate
+        let mut short = data[..385].to_vec();
+        short.extend_from_slice(&[0, 4]);
+        short.extend_from_slice(&data[387..391]);
+        assert_eq!(
+            RouterIdentity::from_bytes(&short),
+            err(KeysAndCertError::Certificate(
+                CertificateError::KeyCertificateTooShort(4)
+            ))
+        );
+
+        // A key certificate with more data than its key types require
+        let mut long = data[..385].to_vec();
+        long.extend_from_slice(&[0, 10]);
+        long.extend_from_slice(&data[387..]);
+        long.extend_from_slice(&[1, 2]);
+        assert_eq!(
+            RouterIdentity::from_bytes(&long),
+            err(KeysAndCertError::KeyCertificateLength {
+                expected: 8,
+                actual: 10
+            })
+        );
+        assert!(router_identity(&long).is_err());
+
+        // Key data in the certificate of a key type that doesn't need any
+        let mut mismatched = data.clone();
+        mismatched[387..389].copy_from_slice(&constants::ED25519.to_be_bytes());
+        assert_eq!(
+            RouterIdentity::from_bytes(&mismatched),
+            err(KeysAndCertError::KeyCertificateLength {
+                expected: 4,
+                actual: 8
+            })
+        );
+
+        // Certificates of unknown types
+        let mut unknown = data[..384].to_vec();
+        unknown.extend_from_slice(&[0x07, 0x00, 0x00]);
+        assert_eq!(
+            RouterIdentity::from_bytes(&unknown),
+            err(KeysAndCertError::Certificate(
+                CertificateError::UnsupportedForKeys(7)
+            ))
+        );
+    }
+
+    #[test]
+    fn keys_and_cert_layout_mismatch() {
+        let (_, mut rid) = router_identity(&ROUTER_INFO[..]).unwrap();
+        let mut buf = vec![0; 1024];
+
+        // Padding must match the certificate
+        rid.padding = Some(Padding(vec![0; 95]));
+        assert!(gen_router_identity((&mut buf, 0), &rid).is_err());
+        rid.padding = None;
+        assert!(gen_router_identity((&mut buf, 0), &rid).is_err());
+
+        // As must the signing key type
+        let (_, p256) = router_identity(&RI_SIGTYPE_1[..]).unwrap();
+        rid.padding = Some(Padding(vec![0; 96]));
+        rid.signing_key = p256.signing_key;
+        assert!(gen_router_identity((&mut buf, 0), &rid).is_err());
+    }
 }
@@ -2,41 +2,64 @@
 //!
 //! [Common structures specification](https://geti2p.net/spec/common-structures)
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use nom::{self, Needed};
 use rand::{rngs::OsRng, Rng};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::btree_map::{self, BTreeMap, Entry};
+use std::convert::{Infallible, TryFrom};
+use std::error;
 use std::fmt;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::str::{self, FromStr};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::constants;
 use crate::crypto::{
-    self, elgamal, EncType, PrivateKey, PublicKey, SigType, Signature, SigningPrivateKey,
+    self,
+    hashcash::{HashcashError, Stamp},
+    DecryptionKey, Ed25519SigningKey, EncType, PublicKey, SigType, Signature, SigningPrivateKey,
     SigningPublicKey,
 };
-use crate::util::{fmt_colon_delimited_hex, serialize};
+use crate::util::{fmt_colon_delimited_hex, serialize, write_private_file};
 
 pub mod dest;
+pub mod encoding;
+pub mod su3;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod arbitrary;
+
+#[cfg(feature = "serde")]
+mod serde_impls;
 
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) mod frame;
 
-pub use self::dest::{Destination, Lease, LeaseSet};
+pub use self::dest::{
+    AddressError, Destination, DestinationSecretKeys, EncryptedLeaseSet2, EncryptionKey, Lease,
+    LeaseSet, LeaseSet2, LeaseSetError, LeaseSetSigner, Leases, OfflineSignature,
+    CLOCK_SKEW_TOLERANCE, LEASE_SAFETY_MARGIN,
+};
 
 lazy_static! {
     pub(crate) static ref OPT_NET_ID: I2PString = "netId".into();
     static ref OPT_ROUTER_VERSION: I2PString = "router.version".into();
     static ref OPT_CAPS: I2PString = "caps".into();
+    static ref OPT_HOST: I2PString = "host".into();
+    static ref OPT_PORT: I2PString = "port".into();
+    static ref OPT_NTCP2_STATIC_KEY: I2PString = "s".into();
+    static ref OPT_NTCP2_IV: I2PString = "i".into();
+    static ref OPT_VERSION: I2PString = "v".into();
 }
 
 lazy_static! {
     pub static ref NET_ID: I2PString = "2".into();
     static ref ROUTER_VERSION: I2PString = "0.9.37".into();
-    static ref CAPS: I2PString = "KU".into();
 }
 
 /// Data read errors
@@ -45,6 +68,11 @@ pub enum ReadError {
     FileIo(String),
     Incomplete(Needed),
     Parser,
+    /// The data parsed successfully, but was followed by this many extra bytes.
+    TrailingData(usize),
+    /// The stored secret keys do not correspond to the stored public keys.
+    KeyMismatch,
+    KeysAndCert(KeysAndCertError),
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -54,6 +82,9 @@ impl fmt::Display for ReadError {
             ReadError::FileIo(e) => format!("File IO error: {}", e).fmt(f),
             ReadError::Incomplete(n) => format!("Data is incomplete (needed: {:?})", n).fmt(f),
             ReadError::Parser => "Parser error".fmt(f),
+            ReadError::TrailingData(n) => format!("{} bytes of trailing data", n).fmt(f),
+            ReadError::KeyMismatch => "Secret keys do not match public keys".fmt(f),
+            ReadError::KeysAndCert(e) => e.fmt(f),
         }
     }
 }
@@ -64,6 +95,12 @@ impl From<io::Error> for ReadError {
     }
 }
 
+impl From<KeysAndCertError> for ReadError {
+    fn from(e: KeysAndCertError) -> Self {
+        ReadError::KeysAndCert(e)
+    }
+}
+
 impl<T> From<nom::Err<T>> for ReadError {
     fn from(e: nom::Err<T>) -> Self {
         match e {
@@ -78,7 +115,9 @@ impl<T> From<nom::Err<T>> for ReadError {
 //
 
 /// The SHA-256 hash of some data.
-#[derive(Clone, Eq, Hash, PartialEq)]
+///
+/// Hashes are ordered by big-endian byte comparison.
+#[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Hash(pub [u8; 32]);
 
 impl Hash {
@@ -98,6 +137,64 @@ impl Hash {
             self.0[i] ^= other.0[i];
         }
     }
+
+    /// Returns the Kademlia distance between two hashes.
+    pub fn xor_distance(&self, other: &Hash) -> [u8; 32] {
+        let mut distance = self.0;
+        for (d, o) in distance.iter_mut().zip(other.0.iter()) {
+            *d ^= o;
+        }
+        distance
+    }
+
+    /// Compares the distances of a and b from this hash. Use with e.g.
+    /// `sort_by(|a, b| target.cmp_distance(a, b))` to order hashes from closest to
+    /// furthest.
+    pub fn cmp_distance(&self, a: &Hash, b: &Hash) -> Ordering {
+        self.xor_distance(a).cmp(&self.xor_distance(b))
+    }
+
+    /// Returns the key under which this hash is stored in the network database on
+    /// the UTC date at the given time: SHA-256(hash || "yyyyMMdd").
+    pub fn routing_key(&self, at: SystemTime) -> Hash {
+        let mut data = self.0.to_vec();
+        data.extend_from_slice(DateTime::<Utc>::from(at).format("%Y%m%d").to_string().as_bytes());
+        Hash::digest(&data)
+    }
+
+    pub fn to_base64(&self) -> String {
+        encoding::b64_encode(&self.0)
+    }
+}
+
+/// The error returned when a Hash can't be parsed from a string.
+#[derive(Debug, PartialEq)]
+pub struct ParseHashError;
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for ParseHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "Invalid hash: expected 32 bytes in base64 or hex".fmt(f)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = ParseHashError;
+
+    /// Parses a Hash from either I2P base64 (44 characters, or 43 without padding)
+    /// or hex (64 characters).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let decoded = match s.len() {
+            43 | 44 => encoding::b64_decode(s),
+            64 => encoding::hex_decode(s),
+            _ => return Err(ParseHashError),
+        }
+        .map_err(|_| ParseHashError)?;
+        if decoded.len() != 32 {
+            return Err(ParseHashError);
+        }
+        Ok(Hash::from_bytes(array_ref![decoded, 0, 32]))
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -112,46 +209,166 @@ impl fmt::Debug for Hash {
 #[cfg_attr(tarpaulin, skip)]
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", constants::I2P_BASE64.encode(&self.0))
+        write!(f, "{}", self.to_base64())
     }
 }
 
 /// The number of milliseconds since midnight on January 1, 1970 in the GMT
 /// timezone. If the number is 0, the date is undefined or null.
+///
+/// Java I2P treats dates as signed, so dates after `I2PDate::MAX` are invalid.
 #[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
 pub struct I2PDate(pub(crate) u64);
 
 impl I2PDate {
+    /// The undefined date, which some structures use to mean "never".
+    pub const UNSET: I2PDate = I2PDate(0);
+
+    /// The latest representable date.
+    pub const MAX: I2PDate = I2PDate(i64::MAX as u64);
+
+    /// Returns the date `millis` milliseconds after the epoch, or `None` if that is
+    /// after `I2PDate::MAX`.
+    pub fn from_millis(millis: u64) -> Option<Self> {
+        if millis <= I2PDate::MAX.0 {
+            Some(I2PDate(millis))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.0
+    }
+
+    pub fn is_unset(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Converts a SystemTime, saturating times before the epoch to the epoch (which
+    /// is indistinguishable from `I2PDate::UNSET`), and times after `I2PDate::MAX`
+    /// to `I2PDate::MAX`.
     pub fn from_system_time(t: SystemTime) -> Self {
         let d = t
             .duration_since(UNIX_EPOCH)
             .unwrap_or_else(|_| Duration::new(0, 0));
-        I2PDate(d.as_secs() * 1_000 + u64::from(d.subsec_millis()))
+        let millis = d.as_millis();
+        if millis > u128::from(I2PDate::MAX.0) {
+            I2PDate::MAX
+        } else {
+            I2PDate(millis as u64)
+        }
     }
 
+    /// Converts this date into a SystemTime, saturating at the latest time that the
+    /// platform can represent.
     pub fn to_system_time(&self) -> SystemTime {
-        UNIX_EPOCH + Duration::from_millis(self.0)
+        let mut millis = self.0.min(I2PDate::MAX.0);
+        loop {
+            if let Some(t) = UNIX_EPOCH.checked_add(Duration::from_millis(millis)) {
+                return t;
+            }
+            millis /= 2;
+        }
+    }
+
+    /// Converts this date into a SystemTime, or `None` if it is unset.
+    pub fn to_system_time_opt(&self) -> Option<SystemTime> {
+        if self.is_unset() {
+            None
+        } else {
+            Some(self.to_system_time())
+        }
+    }
+
+    /// Returns the date `d` after this one, saturating at `I2PDate::MAX`.
+    pub(crate) fn saturating_add(&self, d: Duration) -> Self {
+        let millis = u64::try_from(d.as_millis()).unwrap_or(u64::MAX);
+        I2PDate(self.0.saturating_add(millis).min(I2PDate::MAX.0))
+    }
+
+    /// Creates a date from the 4-byte seconds-since-epoch format used by NTCP2's
+    /// short I2NP header.
+    pub(crate) fn from_short_expiry(seconds: u32) -> Self {
+        I2PDate(u64::from(seconds) * 1_000)
+    }
+
+    /// Converts this date into the 4-byte seconds-since-epoch format used by NTCP2's
+    /// short I2NP header.
+    ///
+    /// Partial seconds are rounded up, so that a relayed message never appears to
+    /// expire earlier than it originally did. The field is unsigned and therefore
+    /// wraps in 2106; dates beyond that saturate at the maximum value instead of
+    /// wrapping around into the past.
+    pub(crate) fn to_short_expiry(&self) -> u32 {
+        let seconds = self.0 / 1_000 + if self.0 % 1_000 > 0 { 1 } else { 0 };
+        if seconds > u64::from(u32::MAX) {
+            u32::MAX
+        } else {
+            seconds as u32
+        }
     }
 }
 
 #[cfg_attr(tarpaulin, skip)]
 impl fmt::Display for I2PDate {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        DateTime::<Utc>::from(self.to_system_time()).fmt(f)
+        // chrono can't represent the full range of dates
+        match Utc.timestamp_millis_opt(self.0 as i64).single() {
+            Some(dt) => dt.fmt(f),
+            None => write!(f, "{}ms after the epoch", self.0),
+        }
     }
 }
 
-/// A UTF-8-encoded string.
+/// The maximum length in bytes of an I2PString.
+pub const I2PSTRING_MAX_LEN: usize = 255;
+
+/// A string of up to 255 bytes.
+///
+/// I2PStrings are almost always UTF-8, but this is not guaranteed, so the raw
+/// bytes are preserved (and re-serialized exactly).
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct I2PString(pub String);
+pub struct I2PString(Vec<u8>);
 
 impl I2PString {
+    /// Panics if the string is longer than `I2PSTRING_MAX_LEN` bytes.
     pub fn new(string: &str) -> Self {
-        I2PString(String::from(string))
+        I2PString::from_bytes(string.as_bytes()).expect("I2PString is too long")
+    }
+
+    /// Returns `None` if the data is longer than `I2PSTRING_MAX_LEN` bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() <= I2PSTRING_MAX_LEN {
+            Some(I2PString(data.to_vec()))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the string, or `None` if it is not valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 
     pub fn to_csv(&self) -> Vec<Self> {
-        self.0.split(',').map(|s| Self::new(s)).collect()
+        self.0.split(|&c| c == b',').map(|s| I2PString(s.to_vec())).collect()
     }
 }
 
@@ -161,9 +378,96 @@ impl<'a> From<&'a str> for I2PString {
     }
 }
 
-/// A set of key/value mappings or properties.
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for I2PString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_string_lossy().fmt(f)
+    }
+}
+
+/// Errors that can occur when constructing a Mapping.
 #[derive(Clone, Debug, PartialEq)]
-pub struct Mapping(pub HashMap<I2PString, I2PString>);
+pub enum MappingError {
+    DuplicateKey(I2PString),
+    InvalidCharacter(I2PString),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::DuplicateKey(key) => format!("Duplicate key: {}", key).fmt(f),
+            MappingError::InvalidCharacter(s) => {
+                format!("Mapping strings may not contain '=' or ';': {}", s).fmt(f)
+            }
+        }
+    }
+}
+
+/// A set of key/value mappings or properties.
+///
+/// Entries are kept sorted by key, which is the canonical order that signed
+/// structures require.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mapping(BTreeMap<I2PString, I2PString>);
+
+impl Mapping {
+    pub fn new() -> Self {
+        Mapping(BTreeMap::new())
+    }
+
+    /// Builds a Mapping from parsed pairs, rejecting duplicate keys.
+    pub fn from_pairs<I>(pairs: I) -> Result<Self, MappingError>
+    where
+        I: IntoIterator<Item = (I2PString, I2PString)>,
+    {
+        let mut map = BTreeMap::new();
+        for (key, value) in pairs {
+            match map.entry(key) {
+                Entry::Occupied(e) => return Err(MappingError::DuplicateKey(e.key().clone())),
+                Entry::Vacant(e) => {
+                    e.insert(value);
+                }
+            }
+        }
+        Ok(Mapping(map))
+    }
+
+    pub fn get(&self, key: &I2PString) -> Option<&I2PString> {
+        self.0.get(key)
+    }
+
+    /// Sets an entry, returning the previous value for the key if there was one.
+    ///
+    /// Keys and values must not contain `=` or `;`.
+    pub fn insert(
+        &mut self,
+        key: I2PString,
+        value: I2PString,
+    ) -> Result<Option<I2PString>, MappingError> {
+        check_mapping_string(&key)?;
+        check_mapping_string(&value)?;
+        Ok(self.0.insert(key, value))
+    }
+
+    /// Removes an entry, returning its value if there was one.
+    pub fn remove(&mut self, key: &I2PString) -> Option<I2PString> {
+        self.0.remove(key)
+    }
+
+    /// Iterates over the entries in key order.
+    pub fn iter(&self) -> btree_map::Iter<'_, I2PString, I2PString> {
+        self.0.iter()
+    }
+}
+
+fn check_mapping_string(s: &I2PString) -> Result<(), MappingError> {
+    if s.0.iter().any(|&c| c == b'=' || c == b';') {
+        Err(MappingError::InvalidCharacter(s.clone()))
+    } else {
+        Ok(())
+    }
+}
 
 /// A random number.
 pub struct SessionTag(pub [u8; 32]);
@@ -189,6 +493,104 @@ impl fmt::Display for TunnelId {
     }
 }
 
+/// Errors that can occur when parsing a Certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CertificateError {
+    /// The data ended before the end of the certificate.
+    Truncated {
+        needed: usize,
+        available: usize,
+    },
+    /// The certificate was followed by this many extra bytes.
+    TrailingData(usize),
+    /// A certificate of this type must have an empty payload.
+    UnexpectedPayload(u8),
+    /// A key certificate's payload is too short for its key types.
+    KeyCertificateTooShort(usize),
+    UnknownSigType(u16),
+    UnknownEncType(u16),
+    /// A certificate of this unknown type can't be used with keys, because its
+    /// effect on the key layout is unknown.
+    UnsupportedForKeys(u8),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateError::Truncated { needed, available } => format!(
+                "Certificate is truncated (needed {} bytes, got {})",
+                needed, available
+            )
+            .fmt(f),
+            CertificateError::TrailingData(n) => {
+                format!("Certificate has {} bytes of trailing data", n).fmt(f)
+            }
+            CertificateError::UnexpectedPayload(code) => {
+                format!("Certificate of type {} must have an empty payload", code).fmt(f)
+            }
+            CertificateError::KeyCertificateTooShort(len) => {
+                format!("Key certificate payload is too short ({} bytes)", len).fmt(f)
+            }
+            CertificateError::UnknownSigType(code) => {
+                format!("Unknown signature type {}", code).fmt(f)
+            }
+            CertificateError::UnknownEncType(code) => {
+                format!("Unknown encryption type {}", code).fmt(f)
+            }
+            CertificateError::UnsupportedForKeys(code) => {
+                format!("Certificate of type {} can't be used with keys", code).fmt(f)
+            }
+        }
+    }
+}
+
+/// Reasons that a KeysAndCert structure (a RouterIdentity or Destination) can be
+/// rejected.
+///
+/// Parsing is strict: the structure must take up exactly the number of bytes that
+/// its certificate dictates, so that it re-serializes to the bytes that were
+/// signed or hashed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeysAndCertError {
+    /// The data ended before the end of the structure.
+    Truncated { needed: usize, available: usize },
+    Certificate(CertificateError),
+    /// The key certificate's payload is not the length that its key types
+    /// require.
+    KeyCertificateLength { expected: usize, actual: usize },
+    /// The signing key field does not contain a valid key of this type.
+    InvalidSigningKey(SigType),
+}
+
+impl From<CertificateError> for KeysAndCertError {
+    fn from(e: CertificateError) -> Self {
+        KeysAndCertError::Certificate(e)
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for KeysAndCertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeysAndCertError::Truncated { needed, available } => format!(
+                "Keys and certificate are truncated (needed {} bytes, got {})",
+                needed, available
+            )
+            .fmt(f),
+            KeysAndCertError::Certificate(e) => e.fmt(f),
+            KeysAndCertError::KeyCertificateLength { expected, actual } => format!(
+                "Key certificate payload is {} bytes, but its key types require {}",
+                actual, expected
+            )
+            .fmt(f),
+            KeysAndCertError::InvalidSigningKey(sig_type) => {
+                format!("Invalid {:?} signing key", sig_type).fmt(f)
+            }
+        }
+    }
+}
+
 /// A key certificate provides a mechanism to indicate the type of the PublicKey
 /// and SigningPublicKey in the Destination or RouterIdentity, and to package
 /// any key data in excess of the standard lengths.
@@ -202,10 +604,70 @@ pub struct KeyCertificate {
     enc_type: EncType,
     sig_data: Vec<u8>,
     enc_data: Vec<u8>,
+    /// Any bytes after the key data, which are preserved so that the certificate
+    /// round-trips exactly.
+    excess: Vec<u8>,
+}
+
+impl KeyCertificate {
+    fn from_payload(payload: &[u8]) -> Result<Self, CertificateError> {
+        if payload.len() < 4 {
+            return Err(CertificateError::KeyCertificateTooShort(payload.len()));
+        }
+
+        let sig_type = match crypto::frame::sig_type(&payload[0..2]) {
+            Ok((_, sig_type)) => sig_type,
+            Err(_) => {
+                return Err(CertificateError::UnknownSigType(u16::from_be_bytes([
+                    payload[0], payload[1],
+                ])))
+            }
+        };
+        let enc_type = match crypto::frame::enc_type(&payload[2..4]) {
+            Ok((_, enc_type)) => enc_type,
+            Err(_) => {
+                return Err(CertificateError::UnknownEncType(u16::from_be_bytes([
+                    payload[2], payload[3],
+                ])))
+            }
+        };
+
+        let sig_len = sig_type.extra_data_len(enc_type);
+        let enc_len = enc_type.extra_data_len(sig_type);
+        if payload.len() < 4 + sig_len + enc_len {
+            return Err(CertificateError::KeyCertificateTooShort(payload.len()));
+        }
+        let (sig_data, rest) = payload[4..].split_at(sig_len);
+        let (enc_data, excess) = rest.split_at(enc_len);
+
+        Ok(KeyCertificate {
+            sig_type,
+            enc_type,
+            sig_data: sig_data.to_vec(),
+            enc_data: enc_data.to_vec(),
+            excess: excess.to_vec(),
+        })
+    }
+
+    pub fn sig_type(&self) -> SigType {
+        self.sig_type
+    }
+
+    pub fn enc_type(&self) -> EncType {
+        self.enc_type
+    }
+
+    /// Returns any bytes in the payload after the key data.
+    pub fn excess(&self) -> &[u8] {
+        &self.excess
+    }
 }
 
 /// A container for various receipts or proof of works used throughout the I2P
 /// network.
+///
+/// Certificates are covered by the signatures of the structures containing them,
+/// so every certificate round-trips exactly, including those of unknown types.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Certificate {
     Null,
@@ -214,9 +676,53 @@ pub enum Certificate {
     Signed(Vec<u8>),
     Multiple(Vec<u8>),
     Key(KeyCertificate),
+    Unknown(u8, Vec<u8>),
 }
 
 impl Certificate {
+    /// Parses a Certificate, which must take up all of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, CertificateError> {
+        if data.len() < 3 {
+            return Err(CertificateError::Truncated {
+                needed: 3,
+                available: data.len(),
+            });
+        }
+
+        let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+        let payload = &data[3..];
+        if payload.len() < len {
+            return Err(CertificateError::Truncated {
+                needed: 3 + len,
+                available: data.len(),
+            });
+        }
+        if payload.len() > len {
+            return Err(CertificateError::TrailingData(payload.len() - len));
+        }
+
+        Certificate::from_payload(data[0], payload)
+    }
+
+    pub(crate) fn from_payload(code: u8, payload: &[u8]) -> Result<Self, CertificateError> {
+        match code {
+            constants::NULL_CERT | constants::HIDDEN_CERT if !payload.is_empty() => {
+                Err(CertificateError::UnexpectedPayload(code))
+            }
+            constants::NULL_CERT => Ok(Certificate::Null),
+            constants::HASH_CERT => Ok(Certificate::HashCash(payload.to_vec())),
+            constants::HIDDEN_CERT => Ok(Certificate::Hidden),
+            constants::SIGNED_CERT => Ok(Certificate::Signed(payload.to_vec())),
+            constants::MULTI_CERT => Ok(Certificate::Multiple(payload.to_vec())),
+            constants::KEY_CERT => KeyCertificate::from_payload(payload).map(Certificate::Key),
+            _ => Ok(Certificate::Unknown(code, payload.to_vec())),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize(|input| frame::gen_certificate(input, self))
+    }
+
     pub fn code(&self) -> u8 {
         match *self {
             Certificate::Null => constants::NULL_CERT,
@@ -225,6 +731,35 @@ impl Certificate {
             Certificate::Signed(_) => constants::SIGNED_CERT,
             Certificate::Multiple(_) => constants::MULTI_CERT,
             Certificate::Key(_) => constants::KEY_CERT,
+            Certificate::Unknown(code, _) => code,
+        }
+    }
+
+    pub fn as_key_cert(&self) -> Option<&KeyCertificate> {
+        match self {
+            Certificate::Key(kc) => Some(kc),
+            _ => None,
+        }
+    }
+
+    /// Checks that this is a HashCash certificate carrying a valid version 1
+    /// stamp with at least `min_bits` collision bits.
+    pub fn validate_hashcash(&self, min_bits: u32) -> Result<(), HashcashError> {
+        match self {
+            Certificate::HashCash(payload) => Stamp::parse(payload)?.check(min_bits),
+            _ => Err(HashcashError::NotHashCash),
+        }
+    }
+
+    /// Checks that this certificate can be used in a KeysAndCert (a Destination or
+    /// RouterIdentity).
+    ///
+    /// Legacy certificates don't affect the key layout, but we can't know how a
+    /// certificate of an unknown type would, so those are rejected.
+    pub(crate) fn check_for_keys(&self) -> Result<(), CertificateError> {
+        match self {
+            Certificate::Unknown(code, _) => Err(CertificateError::UnsupportedForKeys(*code)),
+            _ => Ok(()),
         }
     }
 }
@@ -241,21 +776,36 @@ impl fmt::Debug for Padding {
     }
 }
 
+/// Returns the encryption key field, certificate and padding for a KeysAndCert
+/// containing the given keys.
 fn cert_and_padding_from_keys(
-    _public_key: &PublicKey,
+    enc_key: &crypto::EncryptionKey,
     signing_key: &SigningPublicKey,
-) -> (Certificate, Option<Padding>) {
-    let certificate = match signing_key.sig_type() {
-        SigType::DsaSha1 => Certificate::Null,
-        SigType::Ed25519 => Certificate::Key(KeyCertificate {
-            sig_type: SigType::Ed25519,
-            enc_type: EncType::ElGamal2048,
-            sig_data: vec![],
+) -> (PublicKey, Certificate, Option<Padding>) {
+    let enc_type = enc_key.enc_type();
+
+    // Keys shorter than the field are followed by random padding
+    let mut public_key = PublicKey([0; 256]);
+    let key_bytes = enc_key.as_bytes();
+    public_key.0[..key_bytes.len()].copy_from_slice(key_bytes);
+    OsRng.fill(&mut public_key.0[key_bytes.len()..]);
+
+    let certificate = match (signing_key.sig_type(), enc_type) {
+        (SigType::DsaSha1, EncType::ElGamal2048) => Certificate::Null,
+        (sig_type, enc_type) => Certificate::Key(KeyCertificate {
+            sig_type,
+            enc_type,
+            // Key material that doesn't fit in the signing key field
+            sig_data: signing_key
+                .as_bytes()
+                .get(constants::KEYCERT_SIGKEY_BYTES..)
+                .unwrap_or_default()
+                .to_vec(),
             enc_data: vec![],
+            excess: vec![],
         }),
-        _ => panic!("Not implemented!"),
     };
-    let padding = match signing_key.sig_type().pad_len(EncType::ElGamal2048) {
+    let padding = match signing_key.sig_type().pad_len(enc_type) {
         0 => None,
         sz => {
             let mut rng = OsRng;
@@ -265,35 +815,89 @@ fn cert_and_padding_from_keys(
             Some(Padding(padding))
         }
     };
-    (certificate, padding)
+    (public_key, certificate, padding)
+}
+
+/// Extracts the encryption key from the encryption key field of a KeysAndCert.
+fn encryption_key_from_field(
+    public_key: &PublicKey,
+    certificate: &Certificate,
+) -> crypto::EncryptionKey {
+    let enc_type = match certificate {
+        Certificate::Key(kc) => kc.enc_type,
+        _ => EncType::ElGamal2048,
+    };
+    crypto::EncryptionKey::from_bytes(enc_type, &public_key.0[..enc_type.pubkey_len() as usize])
+        .expect("key is the correct length")
 }
 
 /// Defines the way to uniquely identify a particular router.
+///
+/// RouterIdentities are immutable once constructed, which allows their hash to
+/// be computed once up-front instead of on every lookup.
 #[derive(Clone, Debug, PartialEq)]
 pub struct RouterIdentity {
-    pub public_key: PublicKey,
+    public_key: PublicKey,
     padding: Option<Padding>,
-    pub signing_key: SigningPublicKey,
-    pub certificate: Certificate,
+    signing_key: SigningPublicKey,
+    certificate: Certificate,
+    hash: Hash,
 }
 
 impl RouterIdentity {
+    /// Parses a RouterIdentity, which must take up all of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, (public_key, padding, signing_key, certificate)) =
+            frame::checked_keys_and_cert(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(RouterIdentity {
+            public_key,
+            padding,
+            signing_key,
+            certificate,
+            hash: Hash::digest(data),
+        })
+    }
+
     pub fn from_file(path: &str) -> Result<Self, ReadError> {
         let mut rid = File::open(path)?;
         let mut data: Vec<u8> = Vec::new();
         rid.read_to_end(&mut data)?;
-        let (_, res) = frame::router_identity(&data[..])?;
-        Ok(res)
+        RouterIdentity::from_bytes(&data)
     }
 
-    fn from_keys(public_key: PublicKey, signing_key: SigningPublicKey) -> Self {
-        let (certificate, padding) = cert_and_padding_from_keys(&public_key, &signing_key);
-        RouterIdentity {
+    fn from_keys(enc_key: crypto::EncryptionKey, signing_key: SigningPublicKey) -> Self {
+        let (public_key, certificate, padding) = cert_and_padding_from_keys(&enc_key, &signing_key);
+        let mut rid = RouterIdentity {
             public_key,
             padding,
             signing_key,
             certificate,
-        }
+            hash: Hash([0; 32]),
+        };
+        rid.hash = Hash::digest(&rid.to_bytes()[..]);
+        rid
+    }
+
+    /// Returns the raw 256-byte encryption key field. For key types other than
+    /// ElGamal, use [`RouterIdentity::encryption_key`].
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Returns the encryption key, of the type given by the key certificate.
+    pub fn encryption_key(&self) -> crypto::EncryptionKey {
+        encryption_key_from_field(&self.public_key, &self.certificate)
+    }
+
+    pub fn signing_key(&self) -> &SigningPublicKey {
+        &self.signing_key
+    }
+
+    pub fn certificate(&self) -> &Certificate {
+        &self.certificate
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -305,8 +909,10 @@ impl RouterIdentity {
         rid.write(&self.to_bytes()).map(|_| ())
     }
 
+    /// Returns the SHA-256 hash of this RouterIdentity, which is computed when it
+    /// is constructed.
     pub fn hash(&self) -> Hash {
-        Hash::digest(&self.to_bytes()[..])
+        self.hash.clone()
     }
 }
 
@@ -314,37 +920,63 @@ impl RouterIdentity {
 #[derive(Clone)]
 pub struct RouterSecretKeys {
     pub rid: RouterIdentity,
-    pub private_key: PrivateKey,
+    pub private_key: DecryptionKey,
     pub signing_private_key: SigningPrivateKey,
 }
 
 impl RouterSecretKeys {
     pub fn new() -> Self {
-        let (private_key, public_key) = elgamal::KeyPairGenerator::generate();
-        let signing_private_key = SigningPrivateKey::new();
-        let signing_key = SigningPublicKey::from_secret(&signing_private_key).unwrap();
+        RouterSecretKeys::with_enc_type(EncType::ElGamal2048)
+    }
+
+    /// Generates keys for a RouterIdentity with an Ed25519 signing key and an
+    /// encryption key of the given type.
+    pub fn with_enc_type(enc_type: EncType) -> Self {
+        let private_key = DecryptionKey::with_type(enc_type);
+        let signing_private_key = Ed25519SigningKey::generate();
+        let signing_key = signing_private_key.verifying_key().into();
         RouterSecretKeys {
-            rid: RouterIdentity::from_keys(public_key, signing_key),
+            rid: RouterIdentity::from_keys(private_key.public_key(), signing_key),
             private_key,
-            signing_private_key,
+            signing_private_key: signing_private_key.into(),
+        }
+    }
+
+    /// Parses keys in the `router.keys.dat` format used by Java I2P: the
+    /// RouterIdentity, followed by the private encryption key and the private
+    /// signing key.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, rsk) = frame::router_secret_keys(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        if !rsk.keys_match() {
+            return Err(ReadError::KeyMismatch);
         }
+        Ok(rsk)
     }
 
     pub fn from_file(path: &str) -> Result<Self, ReadError> {
         let mut rsk = File::open(path)?;
         let mut data: Vec<u8> = Vec::new();
         rsk.read_to_end(&mut data)?;
-        let (_, res) = frame::router_secret_keys(&data[..])?;
-        Ok(res)
+        RouterSecretKeys::from_bytes(&data)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         serialize(|input| frame::gen_router_secret_keys(input, self))
     }
 
+    /// Atomically writes these keys to `path`, readable only by the current user.
     pub fn to_file(&self, path: &str) -> io::Result<()> {
-        let mut rid = File::create(path)?;
-        rid.write(&self.to_bytes()).map(|_| ())
+        write_private_file(path, &self.to_bytes())
+    }
+
+    fn keys_match(&self) -> bool {
+        self.private_key.public_key() == self.rid.encryption_key()
+            && SigningPublicKey::from_secret(&self.signing_private_key)
+                .map(|key| key == self.rid.signing_key)
+                .unwrap_or(false)
     }
 }
 
@@ -359,75 +991,480 @@ pub struct RouterAddress {
 
 impl RouterAddress {
     pub fn new(transport_style: &I2PString, addr: SocketAddr) -> Self {
-        let mut options = HashMap::new();
-        options.insert(
-            I2PString(String::from("host")),
-            I2PString(addr.ip().to_string()),
-        );
-        options.insert(
-            I2PString(String::from("port")),
-            I2PString(addr.port().to_string()),
-        );
-        RouterAddress {
-            cost: 0,
-            expiration: I2PDate(0),
-            transport_style: transport_style.clone(),
-            options: Mapping(options),
-        }
+        RouterAddressBuilder::new(transport_style).addr(addr).build()
+    }
+
+    /// Returns the transport protocol through which this address is reached.
+    pub fn transport_style(&self) -> &I2PString {
+        &self.transport_style
+    }
+
+    /// Returns the cost of this address; routers prefer lower-cost addresses.
+    pub fn cost(&self) -> u8 {
+        self.cost
     }
 
     pub fn option(&self, key: &I2PString) -> Option<&I2PString> {
         self.options.0.get(key)
     }
 
-    pub fn set_option(&mut self, key: I2PString, value: I2PString) {
-        self.options.0.insert(key, value);
+    /// Sets an option, returning the previous value if there was one.
+    pub fn set_option(
+        &mut self,
+        key: I2PString,
+        value: I2PString,
+    ) -> Result<Option<I2PString>, MappingError> {
+        self.options.insert(key, value)
     }
 
-    pub fn addr(&self) -> Option<SocketAddr> {
-        let host = self.options.0.get(&I2PString(String::from("host")));
-        let port = self.options.0.get(&I2PString(String::from("port")));
-        match (host, port) {
-            (Some(host), Some(port)) => match (host.0.parse(), port.0.parse()) {
-                (Ok(ip), Ok(port)) => Some(SocketAddr::new(ip, port)),
-                _ => None,
-            },
-            _ => None,
-        }
+    /// Returns the IP address of this RouterAddress.
+    ///
+    /// Returns `None` if the host is missing, or is a hostname rather than an IP
+    /// address literal. IPv6 addresses may optionally be enclosed in brackets.
+    pub fn host(&self) -> Option<IpAddr> {
+        let host = self.option(&OPT_HOST)?.as_str()?;
+        let host = if host.starts_with('[') && host.ends_with(']') {
+            &host[1..host.len() - 1]
+        } else {
+            host
+        };
+        host.parse().ok()
     }
-}
 
-/// Defines all of the data that a router wants to publish for the network to
-/// see.
-///
-/// The RouterInfo is one of two structures stored in the network database (the
-/// other being LeaseSet), and is keyed under the SHA-256 of the contained
-/// RouterIdentity.
-#[derive(Clone, Debug, PartialEq)]
-pub struct RouterInfo {
-    pub router_id: RouterIdentity,
-    pub published: I2PDate,
-    addresses: Vec<RouterAddress>,
-    peers: Vec<Hash>,
-    pub(crate) options: Mapping,
-    signature: Option<Signature>,
-}
+    pub fn port(&self) -> Option<u16> {
+        self.option(&OPT_PORT)?.as_str()?.parse().ok()
+    }
 
-impl RouterInfo {
-    pub fn new(rid: RouterIdentity) -> Self {
-        let mut options: HashMap<I2PString, I2PString> = HashMap::new();
-        options.insert(OPT_NET_ID.clone(), NET_ID.clone());
-        options.insert(OPT_ROUTER_VERSION.clone(), ROUTER_VERSION.clone());
-        options.insert(OPT_CAPS.clone(), CAPS.clone());
+    pub fn addr(&self) -> Option<SocketAddr> {
+        Some(SocketAddr::new(self.host()?, self.port()?))
+    }
 
-        RouterInfo {
-            router_id: rid,
-            published: I2PDate::from_system_time(SystemTime::now()),
-            addresses: Vec::new(),
-            peers: Vec::new(),
-            options: Mapping(options),
-            signature: None,
-        }
+    /// Returns the NTCP2 static public key of this RouterAddress, if it has a valid
+    /// one.
+    pub fn ntcp2_static_key(&self) -> Option<[u8; 32]> {
+        decode_option(self.option(&OPT_NTCP2_STATIC_KEY)?)
+    }
+
+    /// Returns the NTCP2 AES obfuscation IV of this RouterAddress, if it has a valid
+    /// one.
+    pub fn ntcp2_iv(&self) -> Option<[u8; 16]> {
+        decode_option(self.option(&OPT_NTCP2_IV)?)
+    }
+
+    /// Returns the transport protocol versions supported by this RouterAddress.
+    ///
+    /// Returns `None` if the option is missing or any version is invalid.
+    pub fn version(&self) -> Option<Vec<u8>> {
+        self.option(&OPT_VERSION)?
+            .to_csv()
+            .iter()
+            .map(|v| v.as_str()?.parse().ok())
+            .collect()
+    }
+
+    pub fn caps(&self) -> Option<&str> {
+        self.option(&OPT_CAPS)?.as_str()
+    }
+}
+
+/// Decodes an I2P base64-encoded option that must have a specific length.
+fn decode_option<T: AsMut<[u8]> + Default>(value: &I2PString) -> Option<T> {
+    let decoded = encoding::b64_decode(value.as_str()?).ok()?;
+    let mut buf = T::default();
+    if decoded.len() != buf.as_mut().len() {
+        return None;
+    }
+    buf.as_mut().copy_from_slice(&decoded);
+    Some(buf)
+}
+
+/// Constructs a RouterAddress.
+pub struct RouterAddressBuilder {
+    cost: u8,
+    transport_style: I2PString,
+    options: Mapping,
+}
+
+impl RouterAddressBuilder {
+    pub fn new(transport_style: &I2PString) -> Self {
+        RouterAddressBuilder {
+            cost: 0,
+            transport_style: transport_style.clone(),
+            options: Mapping::new(),
+        }
+    }
+
+    pub fn cost(mut self, cost: u8) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    /// Sets the host and port options.
+    pub fn addr(self, addr: SocketAddr) -> Self {
+        self.option(OPT_HOST.clone(), addr.ip().to_string().as_str())
+            .option(OPT_PORT.clone(), addr.port().to_string().as_str())
+    }
+
+    /// Sets an option, replacing any existing value.
+    ///
+    /// Panics if the key or value is not valid in a Mapping.
+    pub fn option<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<I2PString>,
+        V: Into<I2PString>,
+    {
+        self.options.insert(key.into(), value.into()).unwrap();
+        self
+    }
+
+    pub fn ntcp2_static_key(self, key: &[u8; 32]) -> Self {
+        let key = encoding::b64_encode(key);
+        self.option(OPT_NTCP2_STATIC_KEY.clone(), key.as_str())
+    }
+
+    pub fn ntcp2_iv(self, iv: &[u8; 16]) -> Self {
+        let iv = encoding::b64_encode(iv);
+        self.option(OPT_NTCP2_IV.clone(), iv.as_str())
+    }
+
+    /// Sets the supported transport protocol versions.
+    pub fn version(self, versions: &[u8]) -> Self {
+        let versions: Vec<_> = versions.iter().map(|v| v.to_string()).collect();
+        self.option(OPT_VERSION.clone(), versions.join(",").as_str())
+    }
+
+    pub fn build(self) -> RouterAddress {
+        RouterAddress {
+            cost: self.cost,
+            expiration: I2PDate(0),
+            transport_style: self.transport_style,
+            options: self.options,
+        }
+    }
+}
+
+/// The bandwidth class that a router advertises in its capabilities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Bandwidth {
+    /// Under 12 KBps
+    K,
+    /// 12 - 48 KBps
+    L,
+    /// 48 - 64 KBps
+    M,
+    /// 64 - 128 KBps
+    N,
+    /// 128 - 256 KBps
+    O,
+    /// 256 - 2000 KBps
+    P,
+    /// Over 2000 KBps
+    X,
+}
+
+impl Bandwidth {
+    /// Returns the bandwidth class with the given capability code.
+    pub fn from_code(code: char) -> Option<Self> {
+        match code {
+            'K' => Some(Bandwidth::K),
+            'L' => Some(Bandwidth::L),
+            'M' => Some(Bandwidth::M),
+            'N' => Some(Bandwidth::N),
+            'O' => Some(Bandwidth::O),
+            'P' => Some(Bandwidth::P),
+            'X' => Some(Bandwidth::X),
+            _ => None,
+        }
+    }
+
+    fn code(self) -> char {
+        match self {
+            Bandwidth::K => 'K',
+            Bandwidth::L => 'L',
+            Bandwidth::M => 'M',
+            Bandwidth::N => 'N',
+            Bandwidth::O => 'O',
+            Bandwidth::P => 'P',
+            Bandwidth::X => 'X',
+        }
+    }
+
+    fn flag(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// The congestion level that a router advertises in its capabilities.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Congestion {
+    /// Moderately congested, or a low-performance router
+    Moderate,
+    /// Severely congested, or a very low-performance router
+    Severe,
+    /// Rejecting all transit tunnels
+    NoTransit,
+}
+
+impl Congestion {
+    fn flag(self) -> u16 {
+        match self {
+            Congestion::Moderate => CAP_CONGESTION_MODERATE,
+            Congestion::Severe => CAP_CONGESTION_SEVERE,
+            Congestion::NoTransit => CAP_CONGESTION_NO_TRANSIT,
+        }
+    }
+}
+
+const CAP_BANDWIDTH_MASK: u16 = 0x7f;
+const CAP_FLOODFILL: u16 = 1 << 7;
+const CAP_HIDDEN: u16 = 1 << 8;
+const CAP_REACHABLE: u16 = 1 << 9;
+const CAP_UNREACHABLE: u16 = 1 << 10;
+const CAP_CONGESTION_MODERATE: u16 = 1 << 11;
+const CAP_CONGESTION_SEVERE: u16 = 1 << 12;
+const CAP_CONGESTION_NO_TRANSIT: u16 = 1 << 13;
+const CAP_CONGESTION_MASK: u16 =
+    CAP_CONGESTION_MODERATE | CAP_CONGESTION_SEVERE | CAP_CONGESTION_NO_TRANSIT;
+
+/// The known capability codes, in the order they are written.
+const CAP_CODES: [(char, u16); 14] = [
+    ('K', 1 << 0),
+    ('L', 1 << 1),
+    ('M', 1 << 2),
+    ('N', 1 << 3),
+    ('O', 1 << 4),
+    ('P', 1 << 5),
+    ('X', 1 << 6),
+    ('f', CAP_FLOODFILL),
+    ('H', CAP_HIDDEN),
+    ('R', CAP_REACHABLE),
+    ('U', CAP_UNREACHABLE),
+    ('D', CAP_CONGESTION_MODERATE),
+    ('E', CAP_CONGESTION_SEVERE),
+    ('G', CAP_CONGESTION_NO_TRANSIT),
+];
+
+/// The capabilities that a router publishes in the `caps` option of its
+/// RouterInfo.
+///
+/// Known capabilities are written in a canonical order, followed by any unknown
+/// characters in the order they were parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouterCaps {
+    flags: u16,
+    unknown: String,
+}
+
+impl Default for RouterCaps {
+    fn default() -> Self {
+        RouterCaps {
+            flags: Bandwidth::K.flag() | CAP_UNREACHABLE,
+            unknown: String::new(),
+        }
+    }
+}
+
+impl RouterCaps {
+    fn set(&mut self, flag: u16, set: bool) {
+        if set {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    /// Sets the bandwidth class, replacing any existing ones.
+    pub fn bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.flags = (self.flags & !CAP_BANDWIDTH_MASK) | bandwidth.flag();
+        self
+    }
+
+    pub fn floodfill(mut self, floodfill: bool) -> Self {
+        self.set(CAP_FLOODFILL, floodfill);
+        self
+    }
+
+    pub fn hidden(mut self, hidden: bool) -> Self {
+        self.set(CAP_HIDDEN, hidden);
+        self
+    }
+
+    pub fn reachable(mut self, reachable: bool) -> Self {
+        self.set(CAP_REACHABLE, reachable);
+        self.set(CAP_UNREACHABLE, !reachable);
+        self
+    }
+
+    /// Sets the congestion level, replacing any existing one.
+    pub fn congestion(mut self, congestion: Option<Congestion>) -> Self {
+        self.flags &= !CAP_CONGESTION_MASK;
+        if let Some(congestion) = congestion {
+            self.flags |= congestion.flag();
+        }
+        self
+    }
+
+    /// Returns the highest bandwidth class advertised.
+    ///
+    /// Routers in the P and X classes may also advertise O, for compatibility with
+    /// older routers.
+    pub fn bandwidth_class(&self) -> Option<Bandwidth> {
+        [
+            Bandwidth::X,
+            Bandwidth::P,
+            Bandwidth::O,
+            Bandwidth::N,
+            Bandwidth::M,
+            Bandwidth::L,
+            Bandwidth::K,
+        ]
+        .iter()
+        .find(|bw| self.flags & bw.flag() != 0)
+        .cloned()
+    }
+
+    pub fn is_floodfill(&self) -> bool {
+        self.flags & CAP_FLOODFILL != 0
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.flags & CAP_HIDDEN != 0
+    }
+
+    pub fn is_reachable(&self) -> bool {
+        self.flags & CAP_REACHABLE != 0
+    }
+
+    pub fn is_unreachable(&self) -> bool {
+        self.flags & CAP_UNREACHABLE != 0
+    }
+
+    /// Returns the most severe congestion level advertised.
+    pub fn congestion_level(&self) -> Option<Congestion> {
+        [
+            Congestion::NoTransit,
+            Congestion::Severe,
+            Congestion::Moderate,
+        ]
+        .iter()
+        .find(|c| self.flags & c.flag() != 0)
+        .cloned()
+    }
+
+    /// Returns any capability characters that were not recognised.
+    pub fn unknown(&self) -> &str {
+        &self.unknown
+    }
+
+    /// Returns true if every capability in `other` is also advertised here.
+    /// Unrecognised capability characters are not compared.
+    pub fn contains(&self, other: &RouterCaps) -> bool {
+        self.flags & other.flags == other.flags
+    }
+}
+
+impl FromStr for RouterCaps {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut caps = RouterCaps {
+            flags: 0,
+            unknown: String::new(),
+        };
+        for c in s.chars() {
+            match CAP_CODES.iter().find(|(code, _)| *code == c) {
+                Some((_, flag)) => caps.flags |= flag,
+                None => caps.unknown.push(c),
+            }
+        }
+        Ok(caps)
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for RouterCaps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (code, flag) in CAP_CODES.iter() {
+            if self.flags & flag != 0 {
+                write!(f, "{}", code)?;
+            }
+        }
+        self.unknown.fmt(f)
+    }
+}
+
+/// Reasons for rejecting a RouterInfo's signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The RouterInfo is not signed.
+    Unsigned,
+    /// The RouterInfo's signature does not match its contents.
+    InvalidSignature,
+    /// The RouterInfo's signature could not be checked.
+    Crypto(crypto::Error),
+}
+
+impl From<crypto::Error> for VerifyError {
+    fn from(e: crypto::Error) -> Self {
+        match e {
+            crypto::Error::NoSignature => VerifyError::Unsigned,
+            crypto::Error::InvalidSignature => VerifyError::InvalidSignature,
+            e => VerifyError::Crypto(e),
+        }
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Unsigned => "RouterInfo is not signed".fmt(f),
+            VerifyError::InvalidSignature => "RouterInfo has an invalid signature".fmt(f),
+            VerifyError::Crypto(e) => write!(f, "Could not verify RouterInfo: {}", e),
+        }
+    }
+}
+
+impl error::Error for VerifyError {}
+
+/// The largest number of RouterAddresses that a RouterInfo can contain.
+pub const MAX_ROUTER_ADDRESSES: usize = 16;
+
+/// The largest number of peers that a RouterInfo can list. This field is unused,
+/// and always empty in practice.
+pub const MAX_ROUTER_PEERS: usize = 16;
+
+/// Defines all of the data that a router wants to publish for the network to
+/// see.
+///
+/// The RouterInfo is one of two structures stored in the network database (the
+/// other being LeaseSet), and is keyed under the SHA-256 of the contained
+/// RouterIdentity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouterInfo {
+    pub router_id: RouterIdentity,
+    pub published: I2PDate,
+    addresses: Vec<RouterAddress>,
+    peers: Vec<Hash>,
+    pub(crate) options: Mapping,
+    signature: Option<Signature>,
+}
+
+impl RouterInfo {
+    /// Returns an unsigned RouterInfo with the standard options and no addresses.
+    pub fn new(rid: RouterIdentity) -> Self {
+        RouterInfoBuilder::new(rid).build()
+    }
+
+    /// Returns the hash of the contained RouterIdentity, under which this
+    /// RouterInfo is stored in the network database.
+    pub fn hash(&self) -> Hash {
+        self.router_id.hash()
+    }
+
+    /// Returns the addresses at which the router can be contacted.
+    pub fn addresses(&self) -> &[RouterAddress] {
+        &self.addresses
     }
 
     /// Set the addresses in this RouterInfo.
@@ -453,16 +1490,45 @@ impl RouterInfo {
             .map(|a| (*a).clone())
     }
 
+    /// Returns true if the router publishes an address we can connect to
+    /// directly. Routers with no addresses, or only ones reached through
+    /// introducers, return false.
+    pub fn is_reachable(&self) -> bool {
+        self.addresses.iter().any(|a| a.host().is_some())
+    }
+
+    /// Returns the IP addresses published by the router.
+    pub fn hosts(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        self.addresses.iter().filter_map(|a| a.host())
+    }
+
     pub fn network_id(&self) -> Option<&I2PString> {
         self.options.0.get(&OPT_NET_ID)
     }
 
+    /// Returns the capabilities published by this router, or `None` if it
+    /// publishes none.
+    pub fn caps(&self) -> Option<RouterCaps> {
+        self.options.0.get(&OPT_CAPS)?.as_str()?.parse().ok()
+    }
+
     pub fn is_floodfill(&self) -> bool {
-        self.options
-            .0
-            .get(&OPT_CAPS)
-            .map(|caps| caps.0.contains('f'))
-            .unwrap_or(false)
+        self.caps().map(|caps| caps.is_floodfill()).unwrap_or(false)
+    }
+
+    /// Returns the version of the router software that published this
+    /// RouterInfo, if it says.
+    pub fn router_version(&self) -> Option<&str> {
+        self.options.0.get(&OPT_ROUTER_VERSION)?.as_str()
+    }
+
+    /// Parses a RouterInfo, which must take up all of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, ri) = frame::router_info(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(ri)
     }
 
     pub fn from_file(path: &str) -> Result<Self, ReadError> {
@@ -491,21 +1557,160 @@ impl RouterInfo {
         self.signature = Some(spk.sign(&sig_msg).unwrap());
     }
 
-    pub fn verify(&self) -> Result<(), crypto::Error> {
+    pub fn verify(&self) -> Result<(), VerifyError> {
         match self.signature.as_ref() {
             Some(s) => {
                 let sig_msg = self.signature_bytes();
-                self.router_id.signing_key.verify(&sig_msg, s)
+                self.router_id
+                    .signing_key
+                    .verify(&sig_msg, s)
+                    .map_err(VerifyError::from)
             }
-            None => Err(crypto::Error::NoSignature),
+            None => Err(VerifyError::Unsigned),
         }
     }
+
+    /// Re-signs this RouterInfo with a published date that is strictly newer than
+    /// the previous one, so that peers will replace their copy of it.
+    pub fn resign(&mut self, spk: &SigningPrivateKey) {
+        *self = RouterInfoBuilder::from_router_info(self)
+            .published_after(self.published)
+            .sign(spk);
+    }
+}
+
+/// Constructs a signed RouterInfo.
+///
+/// The standard options (`netId`, `router.version`, and `caps`) are set by default,
+/// and can be overridden.
+pub struct RouterInfoBuilder {
+    router_id: RouterIdentity,
+    addresses: Vec<RouterAddress>,
+    options: Mapping,
+    published_after: Option<I2PDate>,
+}
+
+impl RouterInfoBuilder {
+    pub fn new(rid: RouterIdentity) -> Self {
+        let mut options = Mapping::new();
+        options.0.insert(OPT_NET_ID.clone(), NET_ID.clone());
+        options.0.insert(OPT_ROUTER_VERSION.clone(), ROUTER_VERSION.clone());
+        let caps = RouterCaps::default().to_string();
+        options.0.insert(OPT_CAPS.clone(), I2PString::new(&caps));
+
+        RouterInfoBuilder {
+            router_id: rid,
+            addresses: Vec::new(),
+            options,
+            published_after: None,
+        }
+    }
+
+    /// Starts from the identity, addresses, and options of an existing RouterInfo.
+    pub fn from_router_info(ri: &RouterInfo) -> Self {
+        RouterInfoBuilder {
+            router_id: ri.router_id.clone(),
+            addresses: ri.addresses.clone(),
+            options: ri.options.clone(),
+            published_after: None,
+        }
+    }
+
+    /// Sets an option, replacing any existing value.
+    ///
+    /// Panics if the key or value is not valid in a Mapping.
+    pub fn option<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<I2PString>,
+        V: Into<I2PString>,
+    {
+        self.options.insert(key.into(), value.into()).unwrap();
+        self
+    }
+
+    pub fn caps(self, caps: RouterCaps) -> Self {
+        let caps = caps.to_string();
+        self.option(OPT_CAPS.clone(), I2PString::new(&caps))
+    }
+
+    pub fn addresses(mut self, addrs: Vec<RouterAddress>) -> Self {
+        self.addresses = addrs;
+        self
+    }
+
+    /// Ensures that the RouterInfo is published strictly after the given date, even
+    /// if it is ahead of our clock.
+    pub fn published_after(mut self, date: I2PDate) -> Self {
+        self.published_after = Some(date);
+        self
+    }
+
+    /// Returns an unsigned RouterInfo published now.
+    pub fn build(self) -> RouterInfo {
+        let now = I2PDate::from_system_time(SystemTime::now());
+        let published = match self.published_after {
+            Some(prev) if now <= prev => I2PDate(prev.0 + 1),
+            _ => now,
+        };
+
+        RouterInfo {
+            router_id: self.router_id,
+            published,
+            addresses: self.addresses,
+            peers: Vec::new(),
+            options: self.options,
+            signature: None,
+        }
+    }
+
+    /// Returns a RouterInfo published now, and signed with the given key.
+    pub fn sign(self, spk: &SigningPrivateKey) -> RouterInfo {
+        let mut ri = self.build();
+        ri.sign(spk);
+        ri
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::{RI_SIGTYPE_1, RI_SIGTYPE_2, ROUTER_INFO};
+    use crate::crypto::Ed25519VerifyingKey;
+    use crate::tests::{
+        RI_SIGTYPE_0, RI_SIGTYPE_1, RI_SIGTYPE_11, RI_SIGTYPE_2, RI_SIGTYPE_3, ROUTER_INFO,
+        ROUTER_KEYS,
+    };
+
+    #[test]
+    fn i2pdate_short_expiry() {
+        // Exactly at a second boundary
+        assert_eq!(I2PDate(0).to_short_expiry(), 0);
+        assert_eq!(I2PDate(1_524_874_654_000).to_short_expiry(), 1_524_874_654);
+
+        // Within a second, rounded up
+        assert_eq!(I2PDate(1).to_short_expiry(), 1);
+        assert_eq!(I2PDate(1_524_874_654_001).to_short_expiry(), 1_524_874_655);
+        assert_eq!(I2PDate(1_524_874_654_999).to_short_expiry(), 1_524_874_655);
+
+        // Round trips never expire earlier
+        for &ms in &[1_524_874_654_000, 1_524_874_654_001, 1_524_874_654_999] {
+            let date = I2PDate(ms);
+            assert!(I2PDate::from_short_expiry(date.to_short_expiry()) >= date);
+        }
+
+        // Near the 4-byte limit
+        let max_ms = u64::from(u32::MAX) * 1_000;
+        assert_eq!(I2PDate(max_ms - 1).to_short_expiry(), u32::MAX);
+        assert_eq!(I2PDate(max_ms).to_short_expiry(), u32::MAX);
+        assert_eq!(I2PDate(max_ms + 1).to_short_expiry(), u32::MAX);
+        assert_eq!(I2PDate(u64::MAX).to_short_expiry(), u32::MAX);
+        assert_eq!(I2PDate::from_short_expiry(u32::MAX), I2PDate(max_ms));
+
+        // Encoded as a big-endian count of seconds
+        let mut buf = [0; 4];
+        frame::gen_short_expiry((&mut buf[..], 0), &I2PDate(1_001)).unwrap();
+        assert_eq!(buf, [0, 0, 0, 2]);
+        assert_eq!(frame::short_expiry(&buf), Ok((&[][..], I2PDate(2_000))));
+    }
 
     #[test]
     fn hash_xor() {
@@ -525,20 +1730,166 @@ mod tests {
         assert_eq!(h, h0);
     }
 
+    #[test]
+    fn hash_ordering_and_distance() {
+        let h0 = Hash([0; 32]);
+        let mut h1 = Hash([0; 32]);
+        h1.0[31] = 1;
+        let mut h1be = Hash([0; 32]);
+        h1be.0[0] = 1;
+        let hmax = Hash([0xff; 32]);
+
+        // Big-endian byte comparison
+        assert!(h0 < h1);
+        assert!(h1 < h1be);
+        assert!(h1be < hmax);
+
+        assert_eq!(h0.xor_distance(&h0), [0; 32]);
+        assert_eq!(h1.xor_distance(&hmax), h1.xor_distance(&hmax));
+        assert_eq!(hmax.xor_distance(&h1be)[0], 0xfe);
+
+        assert_eq!(h0.cmp_distance(&h1, &h1be), Ordering::Less);
+        assert_eq!(hmax.cmp_distance(&h1, &h1be), Ordering::Greater);
+        assert_eq!(h1.cmp_distance(&h1be, &h1be), Ordering::Equal);
+
+        let mut hashes = vec![hmax.clone(), h1be.clone(), h0.clone(), h1.clone()];
+        hashes.sort_by(|a, b| h1.cmp_distance(a, b));
+        assert_eq!(hashes, vec![h1, h0, h1be, hmax]);
+    }
+
+    #[test]
+    fn hash_strings() {
+        let mut bytes = [0; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let h = Hash(bytes);
+
+        let b64 = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        assert_eq!(h.to_base64(), b64);
+        assert_eq!(format!("{}", h), b64);
+        assert_eq!(b64.parse(), Ok(h.clone()));
+        assert_eq!(b64.trim_end_matches('=').parse(), Ok(h.clone()));
+        assert_eq!(hex.parse(), Ok(h.clone()));
+        assert_eq!(hex.to_uppercase().parse(), Ok(h));
+
+        assert_eq!("".parse::<Hash>(), Err(ParseHashError));
+        assert_eq!(b64[..40].parse::<Hash>(), Err(ParseHashError));
+        assert_eq!(
+            b64.replace('A', "+").parse::<Hash>(),
+            Err(ParseHashError)
+        );
+        assert_eq!(hex.replace('0', "g").parse::<Hash>(), Err(ParseHashError));
+    }
+
+    #[test]
+    fn hash_routing_key() {
+        let mut bytes = [0; 32];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let h = Hash(bytes);
+
+        // SHA-256(hash || "20180428"), as computed by RoutingKeyGenerator in Java I2P
+        let at = UNIX_EPOCH + Duration::from_secs(1_524_874_654);
+        assert_eq!(
+            h.routing_key(at),
+            "8f8e9c76b9b28d0183a54517c83f8f64deb5f0842032b4773b99372aab58bd55"
+                .parse()
+                .unwrap()
+        );
+
+        // The key only changes at midnight UTC
+        let midnight = UNIX_EPOCH + Duration::from_secs(1_524_873_600);
+        assert_eq!(h.routing_key(midnight), h.routing_key(at));
+        assert_ne!(
+            h.routing_key(midnight - Duration::from_secs(1)),
+            h.routing_key(at)
+        );
+    }
+
+    #[test]
+    fn i2pdate_system_time() {
+        assert!(I2PDate::UNSET.is_unset());
+        assert_eq!(I2PDate::UNSET.to_system_time(), UNIX_EPOCH);
+        assert_eq!(I2PDate::UNSET.to_system_time_opt(), None);
+
+        let t = UNIX_EPOCH + Duration::from_millis(1_524_874_654_321);
+        let date = I2PDate::from_system_time(t);
+        assert_eq!(date.as_millis(), 1_524_874_654_321);
+        assert!(!date.is_unset());
+        assert_eq!(date.to_system_time(), t);
+        assert_eq!(date.to_system_time_opt(), Some(t));
+
+        // Sub-millisecond precision is truncated
+        let date = I2PDate::from_system_time(t + Duration::from_micros(999));
+        assert_eq!(date.to_system_time(), t);
+
+        // Times before the epoch saturate
+        assert_eq!(
+            I2PDate::from_system_time(UNIX_EPOCH - Duration::from_secs(1)),
+            I2PDate::UNSET
+        );
+
+        // The maximum date converts without overflowing, and back without exceeding
+        // the maximum
+        let max = I2PDate::MAX.to_system_time();
+        assert!(max > t);
+        assert!(I2PDate::from_system_time(max) <= I2PDate::MAX);
+        if let Some(later) = max.checked_add(Duration::from_secs(1)) {
+            assert_eq!(I2PDate::from_system_time(later), I2PDate::MAX);
+        }
+
+        assert_eq!(I2PDate::from_millis(0), Some(I2PDate::UNSET));
+        assert_eq!(I2PDate::from_millis(i64::MAX as u64), Some(I2PDate::MAX));
+        assert_eq!(I2PDate::from_millis(i64::MAX as u64 + 1), None);
+        assert_eq!(I2PDate::from_millis(u64::MAX), None);
+    }
+
+    #[test]
+    fn i2pstring_bytes() {
+        let s = I2PString::new("caps");
+        assert_eq!(s.as_bytes(), b"caps");
+        assert_eq!(s.as_str(), Some("caps"));
+        assert_eq!(s.len(), 4);
+        assert_eq!(format!("{}", s), "caps");
+
+        let empty = I2PString::new("");
+        assert!(empty.is_empty());
+        assert_eq!(empty.as_str(), Some(""));
+
+        let invalid = I2PString::from_bytes(&[b'a', 0xff, b'b']).unwrap();
+        assert_eq!(invalid.as_bytes(), &[b'a', 0xff, b'b']);
+        assert_eq!(invalid.as_str(), None);
+        assert_eq!(invalid.to_string_lossy(), "a\u{fffd}b");
+        assert_eq!(format!("{}", invalid), "a\u{fffd}b");
+
+        let max = vec![b'x'; I2PSTRING_MAX_LEN];
+        assert_eq!(I2PString::from_bytes(&max).unwrap().len(), I2PSTRING_MAX_LEN);
+        assert_eq!(I2PString::from_bytes(&[b'x'; I2PSTRING_MAX_LEN + 1]), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "I2PString is too long")]
+    fn i2pstring_too_long() {
+        I2PString::new(&"x".repeat(I2PSTRING_MAX_LEN + 1));
+    }
+
     #[test]
     fn i2pstring_to_csv() {
-        let s1 = I2PString(String::from("a-b,c/d,1,2"));
+        let s1 = I2PString::new("a-b,c/d,1,2");
         assert_eq!(
             s1.to_csv(),
             vec![
-                I2PString(String::from("a-b")),
-                I2PString(String::from("c/d")),
-                I2PString(String::from("1")),
-                I2PString(String::from("2")),
+                I2PString::new("a-b"),
+                I2PString::new("c/d"),
+                I2PString::new("1"),
+                I2PString::new("2"),
             ]
         );
 
-        let s2 = I2PString(String::from("asdf"));
+        let s2 = I2PString::new("asdf");
         assert_eq!(s2.to_csv(), vec![s2]);
     }
 
@@ -557,6 +1908,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn router_identity_cached_hash() {
+        for data in [
+            &ROUTER_INFO[..],
+            &RI_SIGTYPE_0[..],
+            &RI_SIGTYPE_1[..],
+            &RI_SIGTYPE_2[..],
+            &RI_SIGTYPE_3[..],
+            &RI_SIGTYPE_11[..],
+        ]
+        .iter()
+        {
+            let ri = RouterInfo::from_bytes(data).unwrap();
+            let fresh = Hash::digest(&ri.router_id.to_bytes()[..]);
+            assert_eq!(ri.router_id.hash(), fresh);
+            assert_eq!(ri.hash(), fresh);
+
+            // Clones and re-parsed identities carry the same hash
+            assert_eq!(ri.router_id.clone().hash(), fresh);
+            let (_, rid) = frame::router_identity(&ri.router_id.to_bytes()).unwrap();
+            assert_eq!(rid.hash(), fresh);
+        }
+
+        let rsk = RouterSecretKeys::new();
+        assert_eq!(rsk.rid.hash(), Hash::digest(&rsk.rid.to_bytes()[..]));
+    }
+
+    #[test]
+    fn mapping_accessors() {
+        let mut m = Mapping::new();
+        m.insert("b".into(), "2".into()).unwrap();
+        m.insert("a".into(), "1".into()).unwrap();
+        assert_eq!(m.get(&"a".into()), Some(&"1".into()));
+
+        // Entries are iterated in key order
+        let keys: Vec<_> = m.iter().map(|(k, _)| k.clone()).collect();
+        assert_eq!(keys, vec![I2PString::new("a"), I2PString::new("b")]);
+
+        assert_eq!(m.remove(&"a".into()), Some("1".into()));
+        assert_eq!(m.remove(&"a".into()), None);
+        assert_eq!(m.get(&"a".into()), None);
+    }
+
     #[test]
     fn router_address_options() {
         let style = I2PString::new("test");
@@ -566,10 +1960,90 @@ mod tests {
         let value = I2PString::new("value");
         assert!(ra.option(&key).is_none());
 
-        ra.set_option(key.clone(), value.clone());
+        assert_eq!(ra.set_option(key.clone(), value.clone()), Ok(None));
+        assert_eq!(ra.option(&key).unwrap(), &value);
+
+        assert_eq!(
+            ra.set_option(key.clone(), I2PString::new("a=b")),
+            Err(MappingError::InvalidCharacter(I2PString::new("a=b")))
+        );
         assert_eq!(ra.option(&key).unwrap(), &value);
     }
 
+    #[test]
+    fn router_address_accessors() {
+        let style = I2PString::new("NTCP2");
+        let with = |key: &str, value: &str| {
+            RouterAddressBuilder::new(&style)
+                .option(key, value)
+                .build()
+        };
+
+        // Missing keys
+        let ra = RouterAddressBuilder::new(&style).build();
+        assert_eq!(ra.host(), None);
+        assert_eq!(ra.port(), None);
+        assert_eq!(ra.addr(), None);
+        assert_eq!(ra.ntcp2_static_key(), None);
+        assert_eq!(ra.ntcp2_iv(), None);
+        assert_eq!(ra.version(), None);
+        assert_eq!(ra.caps(), None);
+
+        // Hosts
+        assert_eq!(
+            with("host", "127.0.0.1").host(),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert_eq!(with("host", "::1").host(), Some("::1".parse().unwrap()));
+        assert_eq!(with("host", "[::1]").host(), Some("::1".parse().unwrap()));
+        assert_eq!(with("host", "[::1").host(), None);
+        assert_eq!(with("host", "example.i2p").host(), None);
+        assert_eq!(with("host", "").host(), None);
+
+        // Ports
+        assert_eq!(with("port", "12345").port(), Some(12345));
+        assert_eq!(with("port", "65536").port(), None);
+        assert_eq!(with("port", "-1").port(), None);
+
+        // Keys
+        let ra = RouterAddressBuilder::new(&style)
+            .ntcp2_static_key(&[7; 32])
+            .ntcp2_iv(&[8; 16])
+            .build();
+        assert_eq!(ra.ntcp2_static_key(), Some([7; 32]));
+        assert_eq!(ra.ntcp2_iv(), Some([8; 16]));
+        assert_eq!(with("s", "not base64!").ntcp2_static_key(), None);
+        assert_eq!(with("i", "AAAA").ntcp2_iv(), None);
+        assert_eq!(
+            with("s", &encoding::b64_encode(&[0; 16])).ntcp2_static_key(),
+            None
+        );
+
+        // Versions and caps
+        assert_eq!(with("v", "2").version(), Some(vec![2]));
+        assert_eq!(with("v", "1,2").version(), Some(vec![1, 2]));
+        assert_eq!(with("v", "1,x").version(), None);
+        assert_eq!(with("caps", "4B").caps(), Some("4B"));
+    }
+
+    #[test]
+    fn router_address_builder() {
+        let style = I2PString::new("NTCP2");
+        let addr = "[::1]:12345".parse().unwrap();
+        let ra = RouterAddressBuilder::new(&style)
+            .cost(10)
+            .addr(addr)
+            .version(&[1, 2])
+            .option("caps", "4")
+            .build();
+        assert_eq!(ra.cost, 10);
+        assert_eq!(ra.transport_style, style);
+        assert_eq!(ra.addr(), Some(addr));
+        assert_eq!(ra.option(&I2PString::new("v")), Some(&I2PString::new("1,2")));
+        assert_eq!(ra.caps(), Some("4"));
+        assert_eq!(RouterAddress::new(&style, addr).addr(), Some(addr));
+    }
+
     #[test]
     fn router_info_address() {
         let rsk = RouterSecretKeys::new();
@@ -583,7 +2057,7 @@ mod tests {
                 cost: 0,
                 expiration: I2PDate(0),
                 transport_style: style.clone(),
-                options: Mapping(HashMap::new()),
+                options: Mapping::new(),
             },
             RouterAddress::new(&style, "127.0.0.1:23456".parse().unwrap()),
             RouterAddress::new(&style, "127.0.0.1:34567".parse().unwrap()),
@@ -600,6 +2074,30 @@ mod tests {
         assert_eq!(ra.addr().unwrap(), "127.0.0.1:34567".parse().unwrap());
     }
 
+    #[test]
+    fn router_info_reachable() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        let style = I2PString::new("test");
+        assert!(!ri.is_reachable());
+
+        // An address without a host is only reachable through introducers
+        let introduced = RouterAddress {
+            cost: 0,
+            expiration: I2PDate(0),
+            transport_style: style.clone(),
+            options: Mapping::new(),
+        };
+        ri.set_addresses(vec![introduced.clone()]);
+        assert!(!ri.is_reachable());
+
+        ri.set_addresses(vec![
+            introduced,
+            RouterAddress::new(&style, "127.0.0.1:23456".parse().unwrap()),
+        ]);
+        assert!(ri.is_reachable());
+    }
+
     #[test]
     fn router_info_sign() {
         let rsk = RouterSecretKeys::new();
@@ -612,13 +2110,55 @@ mod tests {
 
     fn router_info_verify(data: &[u8]) {
         match frame::router_info(data) {
-            Ok((_, ri)) => {
+            Ok((rest, ri)) => {
+                assert!(rest.is_empty());
                 assert!(ri.verify().is_ok());
+
+                // The signature is exactly the trailing sig_len() bytes, and covers
+                // everything before it
+                let sig_type = ri.router_id.signing_key.sig_type();
+                let sig = ri.signature.as_ref().unwrap();
+                let (signed, sig_bytes) = data.split_at(data.len() - sig_type.sig_len() as usize);
+                assert_eq!(sig.sig_type(), Some(sig_type));
+                assert_eq!(sig.len(), sig_bytes.len());
+                assert_eq!(sig.to_bytes(), sig_bytes);
+                assert!(sig.verify(&ri.router_id.signing_key, signed).is_ok());
             }
             Err(e) => panic!("RouterInfo parsing failed: {}", e),
         }
     }
 
+    #[test]
+    fn router_info_verify_sigtype_0() {
+        router_info_verify(RI_SIGTYPE_0)
+    }
+
+    #[test]
+    fn router_info_corrupt_dsa_signature() {
+        let ri = RouterInfo::from_bytes(RI_SIGTYPE_0).unwrap();
+        assert_eq!(ri.router_id.signing_key.sig_type(), SigType::DsaSha1);
+        assert_eq!(ri.router_id.certificate, Certificate::Null);
+
+        let sig_start = RI_SIGTYPE_0.len() - 40;
+        // Flip bits in the identity, the published date, and the signature
+        for &i in [0, 390, sig_start, sig_start + 19, sig_start + 20].iter() {
+            let mut data = RI_SIGTYPE_0.to_vec();
+            data[i] ^= 0x01;
+            let ri = RouterInfo::from_bytes(&data).unwrap();
+            assert_eq!(ri.verify(), Err(VerifyError::InvalidSignature));
+        }
+
+        // r and s must be in the range (0, q)
+        for range in [sig_start..sig_start + 20, sig_start + 20..sig_start + 40].iter() {
+            let mut data = RI_SIGTYPE_0.to_vec();
+            for b in &mut data[range.clone()] {
+                *b = 0;
+            }
+            let ri = RouterInfo::from_bytes(&data).unwrap();
+            assert_eq!(ri.verify(), Err(VerifyError::InvalidSignature));
+        }
+    }
+
     #[test]
     fn router_info_verify_sigtype_1() {
         router_info_verify(RI_SIGTYPE_1)
@@ -629,8 +2169,666 @@ mod tests {
         router_info_verify(RI_SIGTYPE_2)
     }
 
+    #[test]
+    fn router_info_verify_sigtype_3() {
+        router_info_verify(RI_SIGTYPE_3)
+    }
+
     #[test]
     fn router_info_verify_sigtype_7() {
         router_info_verify(ROUTER_INFO)
     }
+
+    #[test]
+    fn router_info_verify_ed25519_key() {
+        // A RouterInfo generated by Java I2P
+        let ri = RouterInfo::from_bytes(ROUTER_INFO).unwrap();
+        let vk = Ed25519VerifyingKey::from_bytes(ri.router_id.signing_key.as_bytes()).unwrap();
+        assert_eq!(SigningPublicKey::from(vk.clone()), ri.router_id.signing_key);
+
+        let (signed, sig) = ROUTER_INFO.split_at(ROUTER_INFO.len() - 64);
+        let sig = Signature::from_bytes(SigType::Ed25519, sig).unwrap();
+        assert_eq!(vk.verify(signed, &sig), Ok(()));
+        assert_eq!(
+            vk.verify(&signed[1..], &sig),
+            Err(crypto::Error::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn router_secret_keys_ed25519() {
+        let rsk = RouterSecretKeys::new();
+        assert_eq!(rsk.rid.signing_key.sig_type(), SigType::Ed25519);
+
+        let mut ri = RouterInfo::new(rsk.rid.clone());
+        ri.sign(&rsk.signing_private_key);
+        assert!(ri.verify().is_ok());
+    }
+
+    #[test]
+    fn router_info_verify_sigtype_11() {
+        router_info_verify(RI_SIGTYPE_11)
+    }
+
+    #[test]
+    fn router_identity_key_lengths() {
+        for &(data, sig_type, key_len, pad_len, extra_len) in [
+            (&RI_SIGTYPE_1[..], SigType::EcdsaSha256P256, 64, 64, 0),
+            (&RI_SIGTYPE_2[..], SigType::EcdsaSha384P384, 96, 32, 0),
+            (&RI_SIGTYPE_3[..], SigType::EcdsaSha512P521, 132, 0, 4),
+            (&ROUTER_INFO[..], SigType::Ed25519, 32, 96, 0),
+            (&RI_SIGTYPE_11[..], SigType::RedDsaSha512Ed25519, 32, 96, 0),
+        ]
+        .iter()
+        {
+            let (_, ri) = frame::router_info(data).unwrap();
+            let rid = &ri.router_id;
+            assert_eq!(rid.signing_key.sig_type(), sig_type);
+            assert_eq!(rid.signing_key.as_bytes().len(), key_len);
+            assert_eq!(rid.padding.as_ref().map(|p| p.0.len()).unwrap_or(0), pad_len);
+            match rid.certificate {
+                Certificate::Key(ref kc) => {
+                    assert_eq!(kc.sig_type, sig_type);
+                    assert_eq!(kc.sig_data.len(), extra_len);
+                }
+                _ => panic!("Expected a key certificate"),
+            }
+            assert_eq!(
+                ri.signature.as_ref().unwrap().to_bytes().len(),
+                sig_type.sig_len() as usize
+            );
+
+            // A RouterIdentity built from the same keys uses the same layout
+            let rebuilt = RouterIdentity::from_keys(rid.encryption_key(), rid.signing_key.clone());
+            assert_eq!(rebuilt.certificate, rid.certificate);
+            assert_eq!(rebuilt.to_bytes().len(), rid.to_bytes().len());
+        }
+    }
+
+    #[test]
+    fn router_info_verify_tampered_p521() {
+        let (_, mut ri) = frame::router_info(RI_SIGTYPE_3).unwrap();
+        let mut sig = ri.signature.as_ref().unwrap().to_bytes();
+        sig[70] ^= 1;
+        ri.signature = Some(Signature::from_bytes(SigType::EcdsaSha512P521, &sig).unwrap());
+        assert_eq!(ri.verify(), Err(VerifyError::InvalidSignature));
+    }
+
+    #[test]
+    fn router_info_verify_reserialized() {
+        for &data in [
+            &RI_SIGTYPE_1[..],
+            &RI_SIGTYPE_2[..],
+            &RI_SIGTYPE_3[..],
+            &ROUTER_INFO[..],
+            &RI_SIGTYPE_11[..],
+        ]
+        .iter()
+        {
+            let (_, ri) = frame::router_info(data).unwrap();
+            assert!(ri.verify().is_ok());
+
+            // The signed bytes must be reproduced exactly, including Mapping order
+            let buf = ri.to_bytes();
+            assert_eq!(&buf[..], data);
+            let (_, ri) = frame::router_info(&buf).unwrap();
+            assert!(ri.verify().is_ok());
+        }
+    }
+
+    #[test]
+    fn router_info_builder() {
+        let rsk = RouterSecretKeys::new();
+        let addr = RouterAddress::new(&I2PString::new("NTCP"), "127.0.0.1:12345".parse().unwrap());
+        let ri = RouterInfoBuilder::new(rsk.rid.clone())
+            .option("zzz", "last")
+            .option("aaa", "first")
+            .caps(
+                RouterCaps::default()
+                    .bandwidth(Bandwidth::O)
+                    .floodfill(true)
+                    .reachable(true),
+            )
+            .addresses(vec![addr.clone()])
+            .sign(&rsk.signing_private_key);
+        assert!(ri.verify().is_ok());
+        assert_eq!(ri.addresses, vec![addr]);
+        assert_eq!(ri.network_id(), Some(&*NET_ID));
+        assert!(ri.is_floodfill());
+
+        // Options are serialized in canonical order
+        let options = serialize(|input| frame::gen_mapping(input, &ri.options));
+        assert_eq!(
+            &options[..],
+            &b"\x00\x44\
+               \x03aaa=\x05first;\
+               \x04caps=\x03OfR;\
+               \x05netId=\x012;\
+               \x0erouter.version=\x060.9.37;\
+               \x03zzz=\x04last;"[..]
+        );
+
+        // The result survives a round trip
+        let (_, parsed) = frame::router_info(&ri.to_bytes()).unwrap();
+        assert_eq!(parsed, ri);
+        assert!(parsed.verify().is_ok());
+    }
+
+    #[test]
+    fn router_caps() {
+        use self::Bandwidth::*;
+        use self::Congestion::*;
+
+        // (caps, bandwidth, floodfill, hidden, reachable, unreachable, congestion)
+        for &(s, bw, ff, hidden, reachable, unreachable, congestion) in &[
+            ("KU", Some(K), false, false, false, true, None),
+            ("L", Some(L), false, false, false, false, None),
+            ("LR", Some(L), false, false, true, false, None),
+            ("MU", Some(M), false, false, false, true, None),
+            ("NR", Some(N), false, false, true, false, None),
+            ("OfR", Some(O), true, false, true, false, None),
+            ("PfR", Some(P), true, false, true, false, None),
+            ("XfR", Some(X), true, false, true, false, None),
+            ("OPfR", Some(P), true, false, true, false, None),
+            ("LHU", Some(L), false, true, false, true, None),
+            ("XfRD", Some(X), true, false, true, false, Some(Moderate)),
+            ("NRE", Some(N), false, false, true, false, Some(Severe)),
+            ("LUG", Some(L), false, false, false, true, Some(NoTransit)),
+            ("fR", None, true, false, true, false, None),
+            ("", None, false, false, false, false, None),
+        ] {
+            let caps: RouterCaps = s.parse().unwrap();
+            assert_eq!(caps.bandwidth_class(), bw, "{}", s);
+            assert_eq!(caps.is_floodfill(), ff, "{}", s);
+            assert_eq!(caps.is_hidden(), hidden, "{}", s);
+            assert_eq!(caps.is_reachable(), reachable, "{}", s);
+            assert_eq!(caps.is_unreachable(), unreachable, "{}", s);
+            assert_eq!(caps.congestion_level(), congestion, "{}", s);
+            assert_eq!(caps.unknown(), "");
+            assert_eq!(caps.to_string(), s);
+        }
+
+        // Unknown characters are preserved
+        let caps: RouterCaps = "XfRqz".parse().unwrap();
+        assert_eq!(caps.bandwidth_class(), Some(X));
+        assert_eq!(caps.unknown(), "qz");
+        assert_eq!(caps.to_string(), "XfRqz");
+
+        // Known capabilities are written in canonical order
+        let caps: RouterCaps = "RzfX".parse().unwrap();
+        assert_eq!(caps.to_string(), "XfRz");
+        assert_eq!(caps.to_string().parse::<RouterCaps>().unwrap(), caps);
+
+        // Setters replace conflicting capabilities
+        let caps = RouterCaps::default();
+        assert_eq!(caps.to_string(), "KU");
+        let caps = caps
+            .bandwidth(P)
+            .floodfill(true)
+            .reachable(true)
+            .congestion(Some(Severe));
+        assert_eq!(caps.to_string(), "PfRE");
+        let caps = caps
+            .bandwidth(L)
+            .floodfill(false)
+            .hidden(true)
+            .reachable(false)
+            .congestion(None);
+        assert_eq!(caps.to_string(), "LHU");
+
+        // Containment compares known capabilities only
+        let caps: RouterCaps = "XfRz".parse().unwrap();
+        assert!(caps.contains(&"fR".parse().unwrap()));
+        assert!(caps.contains(&"q".parse().unwrap()));
+        assert!(!caps.contains(&"fU".parse().unwrap()));
+
+        // RouterInfos expose their parsed caps
+        let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+        assert_eq!(ri.caps(), Some(RouterCaps::from_str("L").unwrap()));
+        assert!(!ri.is_floodfill());
+        let ri = RouterInfoBuilder::new(ri.router_id)
+            .option("caps", "XfRz")
+            .sign(&RouterSecretKeys::new().signing_private_key);
+        assert_eq!(ri.caps().unwrap().unknown(), "z");
+        assert!(ri.is_floodfill());
+    }
+
+    #[test]
+    fn router_info_resign() {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfoBuilder::new(rsk.rid.clone()).sign(&rsk.signing_private_key);
+        let published = ri.published;
+
+        ri.set_addresses(vec![RouterAddress::new(
+            &I2PString::new("NTCP"),
+            "127.0.0.1:12345".parse().unwrap(),
+        )]);
+        assert_eq!(ri.verify(), Err(VerifyError::Unsigned));
+
+        ri.resign(&rsk.signing_private_key);
+        assert!(ri.published > published);
+        assert!(ri.verify().is_ok());
+
+        // Even if the previous published date is ahead of our clock
+        let future = I2PDate(ri.published.0 + 3_600_000);
+        ri.published = future;
+        ri.resign(&rsk.signing_private_key);
+        assert_eq!(ri.published, I2PDate(future.0 + 1));
+        assert!(ri.verify().is_ok());
+    }
+
+    #[test]
+    fn router_info_builder_from_router_info() {
+        let rsk = RouterSecretKeys::new();
+        let ri = RouterInfoBuilder::new(rsk.rid.clone())
+            .caps(RouterCaps::default().floodfill(true))
+            .addresses(vec![RouterAddress::new(
+                &I2PString::new("NTCP2"),
+                "127.0.0.1:12345".parse().unwrap(),
+            )])
+            .sign(&rsk.signing_private_key);
+
+        let rebuilt = RouterInfoBuilder::from_router_info(&ri)
+            .published_after(ri.published)
+            .sign(&rsk.signing_private_key);
+        assert!(rebuilt.verify().is_ok());
+        assert_eq!(rebuilt.router_id, ri.router_id);
+        assert_eq!(rebuilt.addresses(), ri.addresses());
+        assert_eq!(rebuilt.options, ri.options);
+        assert!(rebuilt.published > ri.published);
+    }
+
+    #[test]
+    fn router_info_verify_tampered() {
+        let (_, mut ri) = frame::router_info(ROUTER_INFO).unwrap();
+        ri.published = I2PDate(ri.published.0 + 1);
+        assert_eq!(ri.verify(), Err(VerifyError::InvalidSignature));
+
+        let rsk = RouterSecretKeys::new();
+        let ri = RouterInfo::new(rsk.rid);
+        assert_eq!(ri.verify(), Err(VerifyError::Unsigned));
+    }
+
+    #[test]
+    fn verify_error_from_crypto() {
+        assert_eq!(
+            VerifyError::from(crypto::Error::NoSignature),
+            VerifyError::Unsigned
+        );
+        assert_eq!(
+            VerifyError::from(crypto::Error::InvalidSignature),
+            VerifyError::InvalidSignature
+        );
+        assert_eq!(
+            VerifyError::from(crypto::Error::InvalidKey),
+            VerifyError::Crypto(crypto::Error::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn router_secret_keys_fixture() {
+        // A router.keys.dat in the Java I2P layout, with an Ed25519 signing key
+        let rsk = RouterSecretKeys::from_bytes(&ROUTER_KEYS[..]).unwrap();
+        assert_eq!(rsk.rid.signing_key.sig_type(), SigType::Ed25519);
+        assert_eq!(rsk.rid.encryption_key(), rsk.private_key.public_key());
+        assert_eq!(&rsk.to_bytes()[..], &ROUTER_KEYS[..]);
+    }
+
+    #[test]
+    fn router_secret_keys_x25519() {
+        let rsk = RouterSecretKeys::with_enc_type(EncType::X25519);
+        match rsk.rid.certificate {
+            Certificate::Key(ref kc) => {
+                assert_eq!(kc.sig_type, SigType::Ed25519);
+                assert_eq!(kc.enc_type, EncType::X25519);
+            }
+            _ => panic!("Expected a key certificate"),
+        }
+        let enc_key = rsk.rid.encryption_key();
+        assert_eq!(enc_key.enc_type(), EncType::X25519);
+        assert_eq!(enc_key, rsk.private_key.public_key());
+        assert_eq!(&rsk.rid.public_key.0[..32], enc_key.as_bytes());
+
+        // The private key is stored at its own length
+        let data = rsk.to_bytes();
+        assert_eq!(data.len(), rsk.rid.to_bytes().len() + 32 + 32);
+        let parsed = RouterSecretKeys::from_bytes(&data).unwrap();
+        assert_eq!(parsed.rid, rsk.rid);
+        assert_eq!(parsed.private_key, rsk.private_key);
+
+        // Keys for a different encryption key don't match the RouterIdentity
+        let mut other = rsk.clone();
+        other.private_key = DecryptionKey::with_type(EncType::X25519);
+        assert_eq!(
+            RouterSecretKeys::from_bytes(&other.to_bytes()).err(),
+            Some(ReadError::KeyMismatch)
+        );
+    }
+
+    #[test]
+    fn router_info_x25519() {
+        let rsk = RouterSecretKeys::with_enc_type(EncType::X25519);
+        let ri = RouterInfoBuilder::new(rsk.rid.clone()).sign(&rsk.signing_private_key);
+        assert_eq!(ri.verify(), Ok(()));
+
+        let parsed = RouterInfo::from_bytes(&ri.to_bytes()).unwrap();
+        assert_eq!(parsed.router_id.encryption_key(), rsk.private_key.public_key());
+        assert_eq!(parsed.router_id.hash(), rsk.rid.hash());
+    }
+
+    #[test]
+    fn router_secret_keys_corrupt() {
+        // Truncated
+        assert_eq!(
+            RouterSecretKeys::from_bytes(&ROUTER_KEYS[..ROUTER_KEYS.len() - 1]).err(),
+            Some(ReadError::Incomplete(Needed::new(1)))
+        );
+
+        // Trailing data
+        let mut data = ROUTER_KEYS.to_vec();
+        data.extend_from_slice(&[0; 3]);
+        assert_eq!(
+            RouterSecretKeys::from_bytes(&data).err(),
+            Some(ReadError::TrailingData(3))
+        );
+
+        // Private encryption key doesn't match the identity
+        let mut data = ROUTER_KEYS.to_vec();
+        data[391] ^= 1;
+        assert_eq!(
+            RouterSecretKeys::from_bytes(&data).err(),
+            Some(ReadError::KeyMismatch)
+        );
+
+        // Private signing key doesn't match the identity
+        let mut data = ROUTER_KEYS.to_vec();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(
+            RouterSecretKeys::from_bytes(&data).err(),
+            Some(ReadError::KeyMismatch)
+        );
+    }
+
+    #[test]
+    fn router_secret_keys_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("router.keys.dat");
+        let path = path.to_str().unwrap();
+
+        let rsk = RouterSecretKeys::new();
+        rsk.to_file(path).unwrap();
+        let read = RouterSecretKeys::from_file(path).unwrap();
+        assert_eq!(read.rid, rsk.rid);
+        assert_eq!(read.to_bytes(), rsk.to_bytes());
+
+        // Overwriting replaces the keys, and leaves no temporary file behind
+        let rsk2 = RouterSecretKeys::new();
+        rsk2.to_file(path).unwrap();
+        assert_eq!(RouterSecretKeys::from_file(path).unwrap().rid, rsk2.rid);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            RouterSecretKeys::from_file(missing.to_str().unwrap()),
+            Err(ReadError::FileIo(_))
+        ));
+    }
+
+    // Legacy certificates, in the forms Java I2P has produced them
+    const NULL_CERT: &[u8] = &[0x00, 0x00, 0x00];
+    const HASHCASH_CERT: &[u8] = b"\x01\x00\x2a1:20:080101:example.i2p::4e1a8e3d52:1f6b";
+    const HIDDEN_CERT: &[u8] = &[0x02, 0x00, 0x00];
+    const MULTIPLE_CERT: &[u8] = &[0x04, 0x00, 0x06, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00];
+    // The key certificate of the Ed25519 RouterIdentity in ROUTER_INFO
+    const KEY_CERT: &[u8] = &[0x05, 0x00, 0x04, 0x00, 0x07, 0x00, 0x00];
+
+    #[test]
+    fn hashcash_certificate() {
+        // SHA-1 is 00008604...
+        let cert =
+            Certificate::from_bytes(b"\x01\x00\x2d1:16:200101:routeridentity::aXJlLXRlc3Q=:95b5")
+                .unwrap();
+        assert_eq!(cert.validate_hashcash(16), Ok(()));
+        assert_eq!(
+            cert.validate_hashcash(20),
+            Err(HashcashError::TooFewBits {
+                claimed: 16,
+                required: 20
+            })
+        );
+
+        // The legacy fixture doesn't have the collision it claims
+        assert_eq!(
+            Certificate::from_bytes(HASHCASH_CERT)
+                .unwrap()
+                .validate_hashcash(0),
+            Err(HashcashError::InvalidCollision {
+                claimed: 20,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            Certificate::from_bytes(NULL_CERT)
+                .unwrap()
+                .validate_hashcash(0),
+            Err(HashcashError::NotHashCash)
+        );
+    }
+
+    fn signed_cert() -> Vec<u8> {
+        // A 40-byte DSA signature
+        let mut cert = vec![0x03, 0x00, 0x28];
+        cert.extend((0..40).map(|i| i as u8));
+        cert
+    }
+
+    #[test]
+    fn certificate_fixtures() {
+        let signed = signed_cert();
+        for &(data, code) in [
+            (NULL_CERT, constants::NULL_CERT),
+            (HASHCASH_CERT, constants::HASH_CERT),
+            (HIDDEN_CERT, constants::HIDDEN_CERT),
+            (&signed[..], constants::SIGNED_CERT),
+            (MULTIPLE_CERT, constants::MULTI_CERT),
+            (KEY_CERT, constants::KEY_CERT),
+        ]
+        .iter()
+        {
+            let cert = Certificate::from_bytes(data).unwrap();
+            assert_eq!(cert.code(), code);
+            assert_eq!(cert.to_bytes(), data);
+            assert_eq!(frame::certificate(data), Ok((&[][..], cert)));
+        }
+
+        assert_eq!(Certificate::from_bytes(NULL_CERT), Ok(Certificate::Null));
+        assert_eq!(
+            Certificate::from_bytes(HASHCASH_CERT),
+            Ok(Certificate::HashCash(HASHCASH_CERT[3..].to_vec()))
+        );
+        assert_eq!(
+            Certificate::from_bytes(HIDDEN_CERT),
+            Ok(Certificate::Hidden)
+        );
+
+        let cert = Certificate::from_bytes(KEY_CERT).unwrap();
+        let kc = cert.as_key_cert().unwrap();
+        assert_eq!(kc.sig_type(), SigType::Ed25519);
+        assert_eq!(kc.enc_type(), EncType::ElGamal2048);
+        assert!(kc.excess().is_empty());
+        assert_eq!(Certificate::Null.as_key_cert(), None);
+
+        let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+        assert_eq!(ri.router_id.certificate, cert);
+    }
+
+    #[test]
+    fn certificate_preserves_unknown_data() {
+        // Unknown certificate types
+        let data = [0x07, 0x00, 0x03, 0x01, 0x02, 0x03];
+        let cert = Certificate::from_bytes(&data).unwrap();
+        assert_eq!(cert, Certificate::Unknown(7, vec![1, 2, 3]));
+        assert_eq!(cert.code(), 7);
+        assert_eq!(cert.to_bytes(), data);
+
+        // Bytes after the key data in a key certificate
+        let data = [0x05, 0x00, 0x06, 0x00, 0x07, 0x00, 0x00, 0xab, 0xcd];
+        let cert = Certificate::from_bytes(&data).unwrap();
+        assert_eq!(cert.as_key_cert().unwrap().excess(), &[0xab, 0xcd]);
+        assert_eq!(cert.to_bytes(), data);
+    }
+
+    #[test]
+    fn certificate_errors() {
+        assert_eq!(
+            Certificate::from_bytes(&[0x00, 0x00]),
+            Err(CertificateError::Truncated {
+                needed: 3,
+                available: 2
+            })
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x01, 0x00, 0x04, 0x00]),
+            Err(CertificateError::Truncated {
+                needed: 7,
+                available: 4
+            })
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x00, 0x00, 0x00, 0x00]),
+            Err(CertificateError::TrailingData(1))
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x00, 0x00, 0x01, 0x00]),
+            Err(CertificateError::UnexpectedPayload(constants::NULL_CERT))
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x02, 0x00, 0x01, 0x00]),
+            Err(CertificateError::UnexpectedPayload(constants::HIDDEN_CERT))
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x05, 0x00, 0x02, 0x00, 0x07]),
+            Err(CertificateError::KeyCertificateTooShort(2))
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x05, 0x00, 0x04, 0x00, 0xff, 0x00, 0x00]),
+            Err(CertificateError::UnknownSigType(0xff))
+        );
+        assert_eq!(
+            Certificate::from_bytes(&[0x05, 0x00, 0x04, 0x00, 0x07, 0x00, 0xff]),
+            Err(CertificateError::UnknownEncType(0xff))
+        );
+        // P-521 keys need four bytes of extra key data
+        assert_eq!(
+            Certificate::from_bytes(&[0x05, 0x00, 0x06, 0x00, 0x03, 0x00, 0x00, 0x01, 0x02]),
+            Err(CertificateError::KeyCertificateTooShort(6))
+        );
+    }
+
+    #[test]
+    fn certificate_random_round_trip() {
+        let mut rng = OsRng;
+        for _ in 0..1000 {
+            let code = rng.gen_range(0..8u8);
+            let mut payload = vec![0; rng.gen_range(0..64)];
+            rng.fill(&mut payload[..]);
+            if code == constants::KEY_CERT && payload.len() >= 4 && rng.gen() {
+                // Use a known key type most of the time
+                payload[0..4].copy_from_slice(&[0x00, 0x07, 0x00, 0x00]);
+            }
+
+            let mut data = vec![code];
+            data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            data.extend_from_slice(&payload);
+
+            match Certificate::from_bytes(&data) {
+                Ok(cert) => {
+                    assert_eq!(cert.code(), code);
+                    assert_eq!(cert.to_bytes(), data);
+                    assert_eq!(frame::certificate(&data), Ok((&[][..], cert)));
+                }
+                Err(CertificateError::UnexpectedPayload(c)) => {
+                    assert_eq!(c, code);
+                    assert!(!payload.is_empty());
+                }
+                Err(CertificateError::KeyCertificateTooShort(_))
+                | Err(CertificateError::UnknownSigType(_))
+                | Err(CertificateError::UnknownEncType(_)) => {
+                    assert_eq!(code, constants::KEY_CERT)
+                }
+                Err(e) => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn keys_and_cert_legacy_certificates() {
+        // DSA key generation isn't supported, so use arbitrary 256-byte public
+        // and 128-byte DSA signing keys.
+        let keys: Vec<u8> = (0..384).map(|i| i as u8).collect();
+        let mut data = keys.clone();
+        data.extend_from_slice(NULL_CERT);
+        let (_, base) = dest::frame::destination(&data).unwrap();
+        assert_eq!(base.signing_key.sig_type(), SigType::DsaSha1);
+        assert_eq!(base.to_bytes(), data);
+
+        // Legacy certificates are preserved in Destinations
+        let signed = signed_cert();
+        for &cert in [HASHCASH_CERT, HIDDEN_CERT, &signed[..], MULTIPLE_CERT].iter() {
+            let mut data = keys.to_vec();
+            data.extend_from_slice(cert);
+            let (rest, dest) = dest::frame::destination(&data).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(dest.certificate, Certificate::from_bytes(cert).unwrap());
+            assert_eq!(dest.signing_key, base.signing_key);
+            assert_eq!(dest.to_bytes(), data);
+        }
+
+        // Unknown certificates are rejected
+        let mut data = keys.to_vec();
+        data.extend_from_slice(&[0x07, 0x00, 0x00]);
+        assert!(dest::frame::destination(&data).is_err());
+        assert_eq!(
+            Certificate::Unknown(7, vec![]).check_for_keys(),
+            Err(CertificateError::UnsupportedForKeys(7))
+        );
+    }
+
+    #[cfg(all(test, feature = "nightly"))]
+    mod bench {
+        use test::{black_box, Bencher};
+
+        use super::super::{frame, Hash};
+        use crate::tests::ROUTER_INFO;
+
+        const HASH_CALLS: usize = 1_000_000;
+
+        #[bench]
+        fn router_identity_hash_cached(b: &mut Bencher) {
+            let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+            b.iter(|| {
+                for _ in 0..HASH_CALLS {
+                    black_box(black_box(&ri.router_id).hash());
+                }
+            });
+        }
+
+        #[bench]
+        fn router_identity_hash_uncached(b: &mut Bencher) {
+            let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+            b.iter(|| {
+                for _ in 0..HASH_CALLS {
+                    black_box(Hash::digest(&black_box(&ri.router_id).to_bytes()[..]));
+                }
+            });
+        }
+    }
 }
@@ -0,0 +1,433 @@
+//! Serde support for the core data types, enabled by the `serde` feature.
+//!
+//! This is a human-readable representation intended for JSON, and is entirely
+//! separate from the wire format. Hashes, keys, identities and signatures are
+//! I2P base64 strings, dates are milliseconds since the epoch, and Mappings are
+//! maps.
+
+use serde::{
+    de::{self, Deserializer, MapAccess, Visitor},
+    ser::{SerializeMap, Serializer},
+    Deserialize, Serialize,
+};
+use std::fmt;
+
+use super::{
+    encoding, frame, Destination, Hash, I2PDate, I2PString, Lease, LeaseSet, Mapping, MappingError,
+    RouterAddress, RouterIdentity, RouterInfo, TunnelId,
+};
+use crate::crypto::{PublicKey, SigType, Signature, SigningPublicKey};
+
+/// Bytes encoded as an I2P base64 string.
+struct Base64(Vec<u8>);
+
+impl Serialize for Base64 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encoding::b64_encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for Base64 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        encoding::b64_decode(&s)
+            .map(Base64)
+            .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&s), &"I2P base64"))
+    }
+}
+
+fn signature_from_base64<E: de::Error>(
+    sig_type: SigType,
+    sig: Option<Base64>,
+) -> Result<Option<Signature>, E> {
+    sig.map(|sig| Signature::from_bytes(sig_type, &sig.0).map_err(E::custom))
+        .transpose()
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for I2PDate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.as_millis())
+    }
+}
+
+impl<'de> Deserialize<'de> for I2PDate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let millis = u64::deserialize(deserializer)?;
+        I2PDate::from_millis(millis).ok_or_else(|| {
+            de::Error::invalid_value(de::Unexpected::Unsigned(millis), &"a valid I2P date")
+        })
+    }
+}
+
+impl Serialize for I2PString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string_lossy())
+    }
+}
+
+impl<'de> Deserialize<'de> for I2PString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        I2PString::from_bytes(s.as_bytes())
+            .ok_or_else(|| de::Error::invalid_length(s.len(), &"a string of at most 255 bytes"))
+    }
+}
+
+impl Serialize for Mapping {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+struct MappingVisitor;
+
+impl<'de> Visitor<'de> for MappingVisitor {
+    type Value = Mapping;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a map of strings")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Mapping, A::Error> {
+        let mut mapping = Mapping::new();
+        while let Some((key, value)) = access.next_entry::<I2PString, I2PString>()? {
+            if mapping.get(&key).is_some() {
+                return Err(de::Error::custom(MappingError::DuplicateKey(key)));
+            }
+            mapping.insert(key, value).map_err(de::Error::custom)?;
+        }
+        Ok(mapping)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(MappingVisitor)
+    }
+}
+
+impl Serialize for TunnelId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for TunnelId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u32::deserialize(deserializer).map(TunnelId)
+    }
+}
+
+/// Signatures can only be serialized, because their type is determined by the
+/// key that created them.
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Base64(self.to_bytes()).serialize(serializer)
+    }
+}
+
+impl Serialize for RouterIdentity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Base64(self.to_bytes()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RouterIdentity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = Base64::deserialize(deserializer)?;
+        match frame::router_identity(&data.0) {
+            Ok((rest, rid)) if rest.is_empty() => Ok(rid),
+            _ => Err(de::Error::custom("invalid RouterIdentity")),
+        }
+    }
+}
+
+impl Serialize for Destination {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_base64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Destination {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RouterAddressRepr {
+    cost: u8,
+    expiration: I2PDate,
+    transport_style: I2PString,
+    options: Mapping,
+}
+
+impl Serialize for RouterAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RouterAddressRepr {
+            cost: self.cost,
+            expiration: self.expiration,
+            transport_style: self.transport_style.clone(),
+            options: self.options.clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RouterAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = RouterAddressRepr::deserialize(deserializer)?;
+        Ok(RouterAddress {
+            cost: repr.cost,
+            expiration: repr.expiration,
+            transport_style: repr.transport_style,
+            options: repr.options,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RouterInfoRepr {
+    identity: RouterIdentity,
+    published: I2PDate,
+    addresses: Vec<RouterAddress>,
+    peers: Vec<Hash>,
+    options: Mapping,
+    signature: Option<Base64>,
+}
+
+impl Serialize for RouterInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RouterInfoRepr {
+            identity: self.router_id.clone(),
+            published: self.published,
+            addresses: self.addresses.clone(),
+            peers: self.peers.clone(),
+            options: self.options.clone(),
+            signature: self.signature.as_ref().map(|sig| Base64(sig.to_bytes())),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for RouterInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = RouterInfoRepr::deserialize(deserializer)?;
+        let signature =
+            signature_from_base64(repr.identity.signing_key.sig_type(), repr.signature)?;
+        Ok(RouterInfo {
+            router_id: repr.identity,
+            published: repr.published,
+            addresses: repr.addresses,
+            peers: repr.peers,
+            options: repr.options,
+            signature,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseRepr {
+    gateway: Hash,
+    tunnel_id: TunnelId,
+    end_date: I2PDate,
+}
+
+impl Serialize for Lease {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LeaseRepr {
+            gateway: self.tunnel_gw.clone(),
+            tunnel_id: self.tid,
+            end_date: self.end_date,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Lease {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = LeaseRepr::deserialize(deserializer)?;
+        Ok(Lease::new(repr.gateway, repr.tunnel_id, repr.end_date))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LeaseSetRepr {
+    destination: Destination,
+    encryption_key: Base64,
+    signing_key: Base64,
+    leases: Vec<Lease>,
+    signature: Option<Base64>,
+}
+
+impl Serialize for LeaseSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LeaseSetRepr {
+            destination: self.dest.clone(),
+            encryption_key: Base64(self.enc_key.0.to_vec()),
+            signing_key: Base64(self.sig_key.as_bytes().to_vec()),
+            leases: self.leases.clone(),
+            signature: self.signature.as_ref().map(|sig| Base64(sig.to_bytes())),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LeaseSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = LeaseSetRepr::deserialize(deserializer)?;
+
+        if repr.encryption_key.0.len() != 256 {
+            return Err(de::Error::invalid_length(
+                repr.encryption_key.0.len(),
+                &"a 256-byte encryption key",
+            ));
+        }
+        let enc_key = PublicKey(*array_ref![repr.encryption_key.0, 0, 256]);
+
+        // The signing key and signature have the same type as the Destination's key
+        let sig_type = repr.destination.signing_key.sig_type();
+        if repr.signing_key.0.len() != sig_type.pubkey_len() as usize {
+            return Err(de::Error::invalid_length(
+                repr.signing_key.0.len(),
+                &"a signing key matching the Destination",
+            ));
+        }
+        let sig_key = SigningPublicKey::from_bytes(sig_type, &repr.signing_key.0)
+            .map_err(de::Error::custom)?;
+        let signature = signature_from_base64(sig_type, repr.signature)?;
+
+        Ok(LeaseSet {
+            dest: repr.destination,
+            enc_key,
+            sig_key,
+            leases: repr.leases,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use std::time::{Duration, SystemTime};
+
+    use crate::crypto::{EncType, SigType};
+    use crate::data::{
+        dest::frame::gen_lease_set, frame, DestinationSecretKeys, Hash, I2PDate, I2PString, Lease,
+        LeaseSet, Mapping, RouterAddress, RouterInfo, TunnelId,
+    };
+    use crate::tests::ROUTER_INFO;
+    use crate::util::serialize;
+
+    #[test]
+    fn hash_json() {
+        let hash = Hash([0x42; 32]);
+        let json = serde_json::to_value(&hash).unwrap();
+        assert_eq!(json, Value::String(hash.to_base64()));
+        assert_eq!(serde_json::from_value::<Hash>(json).unwrap(), hash);
+
+        assert!(serde_json::from_str::<Hash>("\"AAAA\"").is_err());
+        assert!(serde_json::from_str::<Hash>("42").is_err());
+    }
+
+    #[test]
+    fn date_json() {
+        let date = I2PDate::from_millis(1_600_000_000_000).unwrap();
+        assert_eq!(serde_json::to_string(&date).unwrap(), "1600000000000");
+        assert_eq!(
+            serde_json::from_str::<I2PDate>("1600000000000").unwrap(),
+            date
+        );
+        assert!(serde_json::from_str::<I2PDate>("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn mapping_json() {
+        let mut mapping = Mapping::new();
+        mapping.insert("foo".into(), "bar".into()).unwrap();
+        mapping.insert("caps".into(), "KU".into()).unwrap();
+        let json = serde_json::to_value(&mapping).unwrap();
+        assert_eq!(json, json!({"caps": "KU", "foo": "bar"}));
+        assert_eq!(serde_json::from_value::<Mapping>(json).unwrap(), mapping);
+
+        // Invalid Mappings are rejected
+        assert!(serde_json::from_str::<Mapping>(r#"{"a": "b", "a": "c"}"#).is_err());
+        assert!(serde_json::from_str::<Mapping>(r#"{"a=b": "c"}"#).is_err());
+        assert!(serde_json::from_str::<Mapping>(r#"{"a": "b;"}"#).is_err());
+        let long = format!(r#"{{"{}": "b"}}"#, "a".repeat(256));
+        assert!(serde_json::from_str::<Mapping>(&long).is_err());
+    }
+
+    #[test]
+    fn router_address_json() {
+        let ra = RouterAddress::new(&I2PString::new("NTCP"), "127.0.0.1:12345".parse().unwrap());
+        let json = serde_json::to_value(&ra).unwrap();
+        assert_eq!(json["transport_style"], "NTCP");
+        assert_eq!(json["options"]["host"], "127.0.0.1");
+        assert_eq!(json["options"]["port"], "12345");
+        assert_eq!(serde_json::from_value::<RouterAddress>(json).unwrap(), ra);
+    }
+
+    #[test]
+    fn router_info_json() {
+        let (_, ri) = frame::router_info(ROUTER_INFO).unwrap();
+        let json = serde_json::to_string(&ri).unwrap();
+        let parsed: RouterInfo = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, ri);
+        assert!(parsed.verify().is_ok());
+
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["published"], ri.published.as_millis());
+        assert_eq!(value["options"]["netId"], "2");
+
+        // A signature of the wrong length is rejected
+        let mut value = value;
+        value["signature"] = Value::String("AAAA".into());
+        assert!(serde_json::from_value::<RouterInfo>(value).is_err());
+    }
+
+    #[test]
+    fn lease_set_json() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let end_date = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(600));
+        let ls = LeaseSet::signed(
+            dsk.dest.clone(),
+            dsk.dest.public_key.clone(),
+            dsk.dest.signing_key.clone(),
+            vec![Lease::new(Hash([1; 32]), TunnelId(42), end_date)],
+            &dsk.signing_private_key,
+        )
+        .unwrap();
+
+        let json = serde_json::to_value(&ls).unwrap();
+        assert_eq!(json["destination"], dsk.dest.to_base64());
+        assert_eq!(json["leases"][0]["tunnel_id"], 42);
+        assert_eq!(json["leases"][0]["end_date"], end_date.as_millis());
+
+        let parsed: LeaseSet = serde_json::from_value(json).unwrap();
+        assert!(parsed.verify().is_ok());
+        assert_eq!(
+            serialize(|input| gen_lease_set(input, &parsed)),
+            serialize(|input| gen_lease_set(input, &ls))
+        );
+    }
+}
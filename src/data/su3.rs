@@ -0,0 +1,509 @@
+//! SU3 files, used for reseed bundles, news feeds, and router and plugin updates.
+//!
+//! [SU3 specification](https://geti2p.net/spec/updates#su3-file-specification)
+
+use nom::bytes::streaming::take_until;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use crate::crypto::{self, OfflineSigningPublicKey, SigType, Signature};
+use crate::data::{frame::router_info, ReadError, RouterInfo};
+
+mod frame;
+
+const SU3_MAGIC: &[u8; 6] = b"I2Psu3";
+
+/// Maximum number of files read from a reseed bundle. Bundles from the public
+/// reseed servers contain around 75.
+const MAX_RESEED_ENTRIES: usize = 1000;
+
+/// Maximum uncompressed size of a single RouterInfo in a reseed.
+pub(crate) const MAX_RESEED_ENTRY_SIZE: u64 = 64 * 1024;
+
+/// SU3 errors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Crypto(crypto::Error),
+    Http(u16),
+    Read(ReadError),
+    /// The content does not have the type required by the caller.
+    UnexpectedContent(FileType, ContentType),
+    UnknownSigner,
+    /// The reseed bundle contains more files than we are willing to read.
+    TooManyEntries(usize),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Crypto(e) => format!("Crypto error: {}", e).fmt(f),
+            Error::Http(status) => format!("HTTP status code {}", status).fmt(f),
+            Error::Read(e) => format!("Read error: {}", e).fmt(f),
+            Error::UnexpectedContent(file_type, content_type) => format!(
+                "Unexpected content (file type {:?}, content type {:?})",
+                file_type, content_type
+            )
+            .fmt(f),
+            Error::UnknownSigner => "Unknown signer".fmt(f),
+            Error::TooManyEntries(n) => format!("Too many files in reseed bundle ({})", n).fmt(f),
+        }
+    }
+}
+
+/// Errors for a single RouterInfo within a reseed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryError {
+    /// The uncompressed entry is larger than any valid RouterInfo.
+    TooLarge,
+    Read(ReadError),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryError::TooLarge => {
+                format!("Entry is larger than {} bytes", MAX_RESEED_ENTRY_SIZE).fmt(f)
+            }
+            EntryError::Read(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<ReadError> for EntryError {
+    fn from(e: ReadError) -> Self {
+        EntryError::Read(e)
+    }
+}
+
+impl From<crypto::Error> for Error {
+    fn from(e: crypto::Error) -> Self {
+        Error::Crypto(e)
+    }
+}
+
+impl<T> From<nom::Err<T>> for Error {
+    fn from(e: nom::Err<T>) -> Self {
+        Error::Read(e.into())
+    }
+}
+
+/// The format of the content of an SU3 file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Zip,
+    Xml,
+    Html,
+    XmlGz,
+    TxtGz,
+    Dmg,
+    Exe,
+    Unknown(u8),
+}
+
+impl FileType {
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => FileType::Zip,
+            0x01 => FileType::Xml,
+            0x02 => FileType::Html,
+            0x03 => FileType::XmlGz,
+            0x04 => FileType::TxtGz,
+            0x05 => FileType::Dmg,
+            0x06 => FileType::Exe,
+            code => FileType::Unknown(code),
+        }
+    }
+}
+
+/// What the content of an SU3 file is used for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    Unknown,
+    RouterUpdate,
+    PluginUpdate,
+    Reseed,
+    News,
+    BlocklistFeed,
+    Other(u8),
+}
+
+impl ContentType {
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => ContentType::Unknown,
+            0x01 => ContentType::RouterUpdate,
+            0x02 => ContentType::PluginUpdate,
+            0x03 => ContentType::Reseed,
+            0x04 => ContentType::News,
+            0x05 => ContentType::BlocklistFeed,
+            code => ContentType::Other(code),
+        }
+    }
+}
+
+/// A parsed SU3 file. Once returned from [`Su3File::from_bytes`], its signature
+/// has been verified by a trusted signer.
+#[derive(Debug)]
+pub struct Su3File {
+    version: String,
+    signer: String,
+    sig_type: SigType,
+    file_type: FileType,
+    content_type: ContentType,
+    content: Vec<u8>,
+    msg_len: usize,
+    sig: Signature,
+}
+
+impl Su3File {
+    pub fn from_http_data(
+        input: &[u8],
+        signers: &HashMap<&'static str, OfflineSigningPublicKey>,
+    ) -> Result<Su3File, Error> {
+        let (input, ret) = frame::http_status_line(input)?;
+        if let Err(e) = ret {
+            return Err(e);
+        }
+
+        let res: Result<_, nom::Err<()>> = take_until(&SU3_MAGIC[..])(input);
+        let (data, _) = res?;
+        Su3File::from_bytes(data, signers)
+    }
+
+    /// Parses an SU3 file, and verifies its signature against the matching key in
+    /// `signers`, which maps signer IDs to their public keys.
+    pub fn from_bytes(
+        data: &[u8],
+        signers: &HashMap<&'static str, OfflineSigningPublicKey>,
+    ) -> Result<Su3File, Error> {
+        let (_, su3_file) = frame::su3_file(data)?;
+
+        // Verify the SU3 file signature
+        if let Some(pk) = signers.get(&su3_file.signer.as_str()) {
+            pk.verify(&data[..su3_file.msg_len], &su3_file.sig)?;
+        } else {
+            return Err(Error::UnknownSigner);
+        }
+
+        Ok(su3_file)
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn signer(&self) -> &str {
+        &self.signer
+    }
+
+    pub fn sig_type(&self) -> SigType {
+        self.sig_type
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        self.content_type
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    pub fn into_content(self) -> Vec<u8> {
+        self.content
+    }
+
+    /// Extracts the RouterInfos from a zipped reseed bundle. RouterInfos that fail
+    /// to parse are skipped.
+    pub fn reseed_router_infos(&self) -> Result<Vec<RouterInfo>, Error> {
+        Ok(self
+            .reseed_entries()?
+            .into_iter()
+            .filter_map(|(name, entry)| match entry {
+                Ok(ri) => Some(ri),
+                Err(e) => {
+                    warn!("Error while parsing {} from reseed:\n{}", name, e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Extracts every file from a zipped reseed bundle, along with the result of
+    /// parsing it as a RouterInfo.
+    ///
+    /// Bundles with too many files are rejected outright, and files that
+    /// decompress to more than 64 KiB are reported as [`EntryError::TooLarge`].
+    pub fn reseed_entries(&self) -> Result<Vec<(String, Result<RouterInfo, EntryError>)>, Error> {
+        if (self.file_type, self.content_type) != (FileType::Zip, ContentType::Reseed) {
+            return Err(Error::UnexpectedContent(self.file_type, self.content_type));
+        }
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(&self.content[..]))
+            .map_err(|_| Error::Read(ReadError::Parser))?;
+        if zip.len() > MAX_RESEED_ENTRIES {
+            return Err(Error::TooManyEntries(zip.len()));
+        }
+
+        let mut entries = Vec::with_capacity(zip.len());
+        for i in 0..zip.len() {
+            let file = zip
+                .by_index(i)
+                .map_err(|_| Error::Read(ReadError::Parser))?;
+            let name = file.name().to_owned();
+            entries.push((name, read_entry(file)));
+        }
+        Ok(entries)
+    }
+}
+
+/// Reads a single RouterInfo from a reseed, without trusting its declared size.
+pub(crate) fn read_entry<R: Read>(entry: R) -> Result<RouterInfo, EntryError> {
+    let mut buf = vec![];
+    entry
+        .take(MAX_RESEED_ENTRY_SIZE + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| EntryError::Read(e.into()))?;
+    if buf.len() as u64 > MAX_RESEED_ENTRY_SIZE {
+        return Err(EntryError::TooLarge);
+    }
+
+    let (_, ri) = router_info(&buf).map_err(|e| EntryError::Read(e.into()))?;
+    Ok(ri)
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::Needed;
+    use std::collections::HashMap;
+    use std::io::{self, Read};
+    use std::num::NonZeroUsize;
+
+    use super::{
+        read_entry, ContentType, EntryError, Error, FileType, Su3File, MAX_RESEED_ENTRY_SIZE,
+    };
+    use crate::crypto::{self, x509::pem_to_der, OfflineSigningPublicKey, SigType};
+    use crate::data::ReadError;
+    use crate::tests::{I2PSEEDS_SU3, TEST_ED25519_SU3};
+
+    fn reseed_signers() -> HashMap<&'static str, OfflineSigningPublicKey> {
+        let cert = pem_to_der(include_str!(
+            "../../assets/certificates/reseed/meeh_at_mail.i2p.crt"
+        ))
+        .unwrap();
+        let mut signers = HashMap::new();
+        signers.insert(
+            "meeh@mail.i2p",
+            OfflineSigningPublicKey::from_certificate(SigType::Rsa4096Sha512, &cert).unwrap(),
+        );
+        signers
+    }
+
+    fn ed25519_signers() -> HashMap<&'static str, OfflineSigningPublicKey> {
+        let mut signers = HashMap::new();
+        signers.insert(
+            "test@example.i2p",
+            OfflineSigningPublicKey::from_certificate(
+                SigType::Ed25519,
+                include_bytes!("../../assets/test-ed25519-signer.der"),
+            )
+            .unwrap(),
+        );
+        signers
+    }
+
+    #[test]
+    fn reseed_http_errors() {
+        let signers = HashMap::new();
+
+        for (incomplete, needed) in [
+            (&b""[..], 7),
+            (&b"HTTP"[..], 3),
+            (&b"HTTP/1"[..], 1),
+            (&b"HTTP/1."[..], 1),
+            (&b"HTTP/1.0"[..], 1),
+            (&b"HTTP/1.1"[..], 1),
+            (&b"HTTP/1.0 "[..], 3),
+        ]
+        .iter()
+        {
+            match Su3File::from_http_data(incomplete, &signers) {
+                Ok(_) => panic!("Wat"),
+                Err(e) => assert_eq!(
+                    e,
+                    Error::Read(ReadError::Incomplete(Needed::Size(
+                        NonZeroUsize::new(*needed).unwrap()
+                    )))
+                ),
+            }
+        }
+
+        for bad in [
+            &b"Foo"[..],
+            &b"Foo "[..],
+            &b"Foo bar"[..],
+            &b"Foo 12b"[..],
+            &b"Foo 123"[..],
+            &b"HTTP/1.2"[..],
+            &b"HTTP/1.0 12b"[..],
+            &b"Spam/1.0 123"[..],
+        ]
+        .iter()
+        {
+            match Su3File::from_http_data(bad, &signers) {
+                Ok(_) => panic!("Wat"),
+                Err(e) => assert_eq!(e, Error::Read(ReadError::Parser)),
+            }
+        }
+
+        for (input, status) in [
+            (&b"HTTP/1.0 123"[..], 123),
+            (&b"HTTP/1.1 401"[..], 401),
+            (&b"HTTP/1.0 404"[..], 404),
+            (&b"HTTP/1.1 429"[..], 429),
+            (&b"HTTP/1.0 429"[..], 429),
+        ]
+        .iter()
+        {
+            match Su3File::from_http_data(input, &signers) {
+                Ok(_) => panic!("Wat"),
+                Err(e) => assert_eq!(e, Error::Http(*status)),
+            }
+        }
+
+        // Now just missing SU3_MAGIC
+        match Su3File::from_http_data(b"HTTP/1.0 200", &signers) {
+            Ok(_) => panic!("Wat"),
+            Err(e) => assert_eq!(e, Error::Read(ReadError::Incomplete(Needed::Unknown))),
+        }
+    }
+
+    #[test]
+    fn reseed_file() {
+        match Su3File::from_bytes(I2PSEEDS_SU3, &reseed_signers()) {
+            Ok(su3_file) => {
+                assert_eq!(su3_file.version(), "1539145006");
+                assert_eq!(su3_file.signer(), "meeh@mail.i2p");
+                assert_eq!(su3_file.sig_type(), SigType::Rsa4096Sha512);
+                assert_eq!(su3_file.file_type(), FileType::Zip);
+                assert_eq!(su3_file.content_type(), ContentType::Reseed);
+                assert_eq!(su3_file.reseed_router_infos().unwrap().len(), 75);
+
+                let entries = su3_file.reseed_entries().unwrap();
+                assert_eq!(entries.len(), 75);
+                assert!(entries
+                    .iter()
+                    .all(|(name, ri)| name.starts_with("routerInfo-") && ri.is_ok()));
+            }
+            Err(e) => panic!("Error while parsing reseed file: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reseed_entry_size_limit() {
+        // An endless entry is cut off rather than read into memory
+        assert_eq!(read_entry(io::repeat(0)).unwrap_err(), EntryError::TooLarge);
+        assert_eq!(
+            read_entry(io::repeat(0).take(MAX_RESEED_ENTRY_SIZE + 1)).unwrap_err(),
+            EntryError::TooLarge
+        );
+
+        // Entries within the limit are parsed
+        match read_entry(io::repeat(0).take(MAX_RESEED_ENTRY_SIZE)) {
+            Err(EntryError::Read(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn ed25519_file() {
+        let su3_file = Su3File::from_bytes(TEST_ED25519_SU3, &ed25519_signers()).unwrap();
+        assert_eq!(su3_file.version(), "1600000000");
+        assert_eq!(su3_file.signer(), "test@example.i2p");
+        assert_eq!(su3_file.sig_type(), SigType::Ed25519);
+        assert_eq!(su3_file.file_type(), FileType::Xml);
+        assert_eq!(su3_file.content_type(), ContentType::News);
+        assert_eq!(
+            su3_file.content(),
+            &b"<feed xmlns=\"http://www.w3.org/2005/Atom\"></feed>\n"[..]
+        );
+        assert_eq!(
+            su3_file.reseed_router_infos().unwrap_err(),
+            Error::UnexpectedContent(FileType::Xml, ContentType::News)
+        );
+    }
+
+    #[test]
+    fn tampered_content() {
+        // Flip a bit inside the content of each file
+        let mut reseed = I2PSEEDS_SU3.to_vec();
+        reseed[I2PSEEDS_SU3.len() / 2] ^= 0x01;
+        assert_eq!(
+            Su3File::from_bytes(&reseed, &reseed_signers()).unwrap_err(),
+            Error::Crypto(crypto::Error::InvalidSignature)
+        );
+
+        let mut news = TEST_ED25519_SU3.to_vec();
+        news[80] ^= 0x01;
+        assert_eq!(
+            Su3File::from_bytes(&news, &ed25519_signers()).unwrap_err(),
+            Error::Crypto(crypto::Error::InvalidSignature)
+        );
+
+        // Tampering with the header is also detected
+        let mut news = TEST_ED25519_SU3.to_vec();
+        news[45] ^= 0x01;
+        assert_eq!(
+            Su3File::from_bytes(&news, &ed25519_signers()).unwrap_err(),
+            Error::Crypto(crypto::Error::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn unknown_signer() {
+        assert_eq!(
+            Su3File::from_bytes(I2PSEEDS_SU3, &HashMap::new()).unwrap_err(),
+            Error::UnknownSigner
+        );
+        assert_eq!(
+            Su3File::from_bytes(I2PSEEDS_SU3, &ed25519_signers()).unwrap_err(),
+            Error::UnknownSigner
+        );
+
+        // A trusted key registered under the wrong signer ID is not used
+        let mut signers = HashMap::new();
+        signers.insert(
+            "meeh@mail.i2p",
+            ed25519_signers().remove("test@example.i2p").unwrap(),
+        );
+        assert_eq!(
+            Su3File::from_bytes(TEST_ED25519_SU3, &signers).unwrap_err(),
+            Error::UnknownSigner
+        );
+        assert_eq!(
+            Su3File::from_bytes(I2PSEEDS_SU3, &signers).unwrap_err(),
+            Error::Crypto(crypto::Error::TypeMismatch)
+        );
+    }
+
+    #[test]
+    fn certificate_type_mismatch() {
+        assert_eq!(
+            OfflineSigningPublicKey::from_certificate(
+                SigType::Rsa4096Sha512,
+                include_bytes!("../../assets/test-ed25519-signer.der"),
+            ),
+            Err(crypto::Error::TypeMismatch)
+        );
+        assert_eq!(
+            OfflineSigningPublicKey::from_certificate(SigType::Ed25519, &[]),
+            Err(crypto::Error::InvalidKey)
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Deterministic example messages for every I2NP payload type.
+//!
+//! Every fixture has a fixed message ID and an expiration on a whole second, so that
+//! it round-trips through both the NTCP and NTCP2 serializations.
+
+use super::*;
+use crate::data::frame::router_info;
+
+const ROUTER_INFO: &[u8] = include_bytes!("../../assets/router.info");
+
+/// Every message type, in wire code order.
+pub const MESSAGE_TYPES: [MessageType; 14] = [
+    MessageType::DatabaseStore,
+    MessageType::DatabaseLookup,
+    MessageType::DatabaseSearchReply,
+    MessageType::DeliveryStatus,
+    MessageType::Garlic,
+    MessageType::TunnelData,
+    MessageType::TunnelGateway,
+    MessageType::Data,
+    MessageType::TunnelBuild,
+    MessageType::TunnelBuildReply,
+    MessageType::VariableTunnelBuild,
+    MessageType::VariableTunnelBuildReply,
+    MessageType::ShortTunnelBuild,
+    MessageType::OutboundTunnelBuildReply,
+];
+
+fn fixture(payload: MessagePayload) -> Message {
+    Message {
+        id: 0x1234_5678,
+        expiration: I2PDate(1_524_874_654_000),
+        payload,
+    }
+}
+
+/// Returns the fixture for the given message type.
+pub fn message(msg_type: MessageType) -> Message {
+    match msg_type {
+        MessageType::DatabaseStore => database_store(),
+        MessageType::DatabaseLookup => database_lookup(),
+        MessageType::DatabaseSearchReply => database_search_reply(),
+        MessageType::DeliveryStatus => delivery_status(),
+        MessageType::Garlic => garlic(),
+        MessageType::TunnelData => tunnel_data(TunnelId(0x0102_0304)),
+        MessageType::TunnelGateway => tunnel_gateway(TunnelId(0x0102_0304)),
+        MessageType::Data => data(),
+        MessageType::TunnelBuild => tunnel_build(),
+        MessageType::TunnelBuildReply => tunnel_build_reply(),
+        MessageType::VariableTunnelBuild => variable_tunnel_build(),
+        MessageType::VariableTunnelBuildReply => variable_tunnel_build_reply(),
+        MessageType::ShortTunnelBuild => short_tunnel_build(),
+        MessageType::OutboundTunnelBuildReply => outbound_tunnel_build_reply(),
+    }
+}
+
+/// A DatabaseStore containing a RouterInfo, with a reply path.
+pub fn database_store() -> Message {
+    let (_, ri) = router_info(ROUTER_INFO).expect("Fixture RouterInfo is valid");
+    fixture(MessagePayload::DatabaseStore(DatabaseStore::from_ri(
+        ri,
+        Some(ReplyPath::new(1, TunnelId(2), Hash([3; 32]))),
+    )))
+}
+
+/// An exploratory DatabaseLookup with an excluded peer and an encrypted reply.
+pub fn database_lookup() -> Message {
+    fixture(MessagePayload::DatabaseLookup(DatabaseLookup {
+        key: Hash([1; 32]),
+        from: Hash([2; 32]),
+        lookup_type: DatabaseLookupType::Exploratory,
+        reply_tid: Some(TunnelId(3)),
+        excluded_peers: vec![Hash([4; 32])],
+        reply_enc: Some((SessionKey([5; 32]), vec![SessionTag([6; 32])])),
+    }))
+}
+
+pub fn database_search_reply() -> Message {
+    fixture(MessagePayload::DatabaseSearchReply(DatabaseSearchReply {
+        key: Hash([1; 32]),
+        peers: vec![Hash([2; 32]), Hash([3; 32])],
+        from: Hash([4; 32]),
+    }))
+}
+
+pub fn delivery_status() -> Message {
+    fixture(MessagePayload::DeliveryStatus(DeliveryStatus {
+        msg_id: 0x7b3f_bba9,
+        time_stamp: I2PDate(1_524_874_654_321),
+    }))
+}
+
+/// A Garlic message containing a single clove, wrapping a Data message for local
+/// delivery.
+pub fn garlic() -> Message {
+    fixture(MessagePayload::Garlic(Garlic {
+        cloves: vec![GarlicClove {
+            delivery_instructions: GarlicCloveDeliveryInstructions {
+                encrypted: false,
+                delivery_type: 0,
+                delay_set: false,
+                session_key: None,
+                to_hash: None,
+                tid: None,
+                delay: None,
+            },
+            msg: data(),
+            clove_id: 1,
+            expiration: I2PDate(1_524_874_654_000),
+            cert: Certificate::Null,
+        }],
+        cert: Certificate::Null,
+        msg_id: 2,
+        expiration: I2PDate(1_524_874_654_000),
+    }))
+}
+
+pub fn tunnel_data(tid: TunnelId) -> Message {
+    let mut data = [0; 1024];
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    fixture(MessagePayload::TunnelData(TunnelData::new(tid, data)))
+}
+
+/// A TunnelGateway wrapping the [`data()`] fixture.
+pub fn tunnel_gateway(tid: TunnelId) -> Message {
+    fixture(MessagePayload::TunnelGateway(TunnelGateway {
+        tid,
+        data: serialize(|input| frame::gen_message(input, &data())).into(),
+    }))
+}
+
+pub fn data() -> Message {
+    fixture(MessagePayload::Data(
+        vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into(),
+    ))
+}
+
+fn build_records(n: usize) -> Vec<[u8; 528]> {
+    (0..n).map(|i| [i as u8; 528]).collect()
+}
+
+pub fn tunnel_build() -> Message {
+    let mut tb = [[0; 528]; 8];
+    tb.copy_from_slice(&build_records(8));
+    fixture(MessagePayload::TunnelBuild(tb))
+}
+
+pub fn tunnel_build_reply() -> Message {
+    let mut tbr = [[0; 528]; 8];
+    tbr.copy_from_slice(&build_records(8));
+    fixture(MessagePayload::TunnelBuildReply(tbr))
+}
+
+pub fn variable_tunnel_build() -> Message {
+    fixture(MessagePayload::VariableTunnelBuild(build_records(3)))
+}
+
+pub fn variable_tunnel_build_reply() -> Message {
+    fixture(MessagePayload::VariableTunnelBuildReply(build_records(3)))
+}
+
+fn short_build_records(n: usize) -> Vec<[u8; 218]> {
+    (0..n).map(|i| [i as u8; 218]).collect()
+}
+
+pub fn short_tunnel_build() -> Message {
+    fixture(MessagePayload::ShortTunnelBuild(short_build_records(4)))
+}
+
+pub fn outbound_tunnel_build_reply() -> Message {
+    fixture(MessagePayload::OutboundTunnelBuildReply(
+        short_build_records(4),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for &msg_type in MESSAGE_TYPES.iter() {
+            let msg = message(msg_type);
+            assert_eq!(msg.message_type(), msg_type);
+
+            // NTCP
+            let buf = serialize(|input| frame::gen_message(input, &msg));
+            let (rest, parsed) = frame::message(&buf).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, msg);
+            assert_eq!(parsed.message_type(), msg_type);
+            assert_eq!(
+                serialize(|input| frame::gen_message(input, &parsed)),
+                buf,
+                "{:?} did not round-trip",
+                msg_type
+            );
+
+            // NTCP2
+            let buf = serialize(|input| frame::gen_ntcp2_message(input, &msg));
+            let (rest, parsed) = frame::ntcp2_message(&buf).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, msg);
+            assert_eq!(parsed.message_type(), msg_type);
+            assert_eq!(
+                serialize(|input| frame::gen_ntcp2_message(input, &parsed)),
+                buf,
+                "{:?} did not round-trip",
+                msg_type
+            );
+        }
+    }
+
+    #[test]
+    fn message_type_codes_are_distinct() {
+        let mut codes: Vec<_> = MESSAGE_TYPES.iter().map(|t| t.code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), MESSAGE_TYPES.len());
+    }
+}
@@ -22,11 +22,14 @@ use std::io::{Read, Write};
 use super::*;
 use crate::crypto::frame::{gen_session_key, session_key};
 use crate::data::{
-    dest::frame::{gen_lease_set, lease_set},
+    dest::frame::{
+        encrypted_lease_set2, gen_encrypted_lease_set2, gen_lease_set, gen_lease_set2,
+        lease_set, lease_set2,
+    },
     frame::{
-        certificate, gen_certificate, gen_hash, gen_i2p_date, gen_router_info, gen_session_tag,
-        gen_short_expiry, gen_tunnel_id, hash, i2p_date, router_info, session_tag, short_expiry,
-        tunnel_id,
+        bounded_count, certificate, gen_certificate, gen_hash, gen_i2p_date, gen_mapping,
+        gen_router_info, gen_session_tag, gen_short_expiry, gen_tunnel_id, hash, i2p_date, mapping,
+        router_info, session_tag, short_expiry, tunnel_id,
     },
 };
 
@@ -34,6 +37,26 @@ use crate::data::{
 // Utils
 //
 
+/// The buffer that a message is being parsed from.
+///
+/// When the buffer is held in shared [`Bytes`], opaque payloads are sliced out of it
+/// instead of being copied.
+#[derive(Clone, Copy)]
+pub(crate) struct Source<'a>(Option<&'a Bytes>);
+
+impl<'a> Source<'a> {
+    /// Returns `data` as [`Bytes`], sharing the source buffer if `data` lies inside it.
+    fn bytes(self, data: &[u8]) -> Bytes {
+        if let Some(buf) = self.0 {
+            let start = (data.as_ptr() as usize).wrapping_sub(buf.as_ptr() as usize);
+            if start <= buf.len() && data.len() <= buf.len() - start {
+                return buf.slice(start, start + data.len());
+            }
+        }
+        Bytes::from(data)
+    }
+}
+
 fn iv(input: &[u8]) -> IResult<&[u8], [u8; 16]> {
     let (i, iv) = take(16usize)(input)?;
     let mut x = [0u8; 16];
@@ -172,6 +195,205 @@ pub fn gen_build_response_record<'a>(
     )
 }
 
+// ECIES build records
+
+/// Cleartext lengths of the ECIES build records.
+pub const ECIES_BUILD_REQUEST_RECORD_LEN: usize = 464;
+pub const SHORT_BUILD_REQUEST_RECORD_LEN: usize = 154;
+pub const ECIES_BUILD_RESPONSE_RECORD_LEN: usize = 512;
+pub const SHORT_BUILD_RESPONSE_RECORD_LEN: usize = 202;
+
+fn hop_type_flags(i: &[u8]) -> IResult<&[u8], ParticipantType> {
+    map_opt(be_u8, |flags| match flags & 0b1100_0000 {
+        0b0000_0000 => Some(ParticipantType::Intermediate),
+        0b1000_0000 => Some(ParticipantType::InboundGateway),
+        0b0100_0000 => Some(ParticipantType::OutboundEndpoint),
+        _ => None,
+    })(i)
+}
+
+fn gen_hop_type_flags<'a>(
+    input: (&'a mut [u8], usize),
+    hop_type: ParticipantType,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let flags: u8 = match hop_type {
+        ParticipantType::Intermediate => 0b0000_0000,
+        ParticipantType::InboundGateway => 0b1000_0000,
+        ParticipantType::OutboundEndpoint => 0b0100_0000,
+    };
+    gen_be_u8!(input, flags)
+}
+
+/// Fills the rest of a record that started at `start` with random padding, so that
+/// it is `len` bytes long.
+fn gen_record_padding(
+    input: (&mut [u8], usize),
+    start: usize,
+    len: usize,
+) -> Result<(&mut [u8], usize), GenError> {
+    let padding_len = (start + len)
+        .checked_sub(input.1)
+        .ok_or(GenError::CustomError(1))?;
+    let mut padding = vec![0; padding_len];
+    let mut rng = OsRng;
+    rng.fill(&mut padding[..]);
+    gen_slice!(input, padding)
+}
+
+pub fn ecies_build_request_record(i: &[u8]) -> IResult<&[u8], EciesBuildRequestRecord> {
+    map(
+        tuple((
+            tunnel_id,
+            tunnel_id,
+            hash,
+            session_key,
+            session_key,
+            session_key,
+            iv,
+            hop_type_flags,
+            take(3usize),
+            be_u32,
+            be_u32,
+            be_u32,
+            mapping,
+        )),
+        |(
+            receive_tid,
+            next_tid,
+            next_ident,
+            layer_key,
+            iv_key,
+            reply_key,
+            reply_iv,
+            hop_type,
+            _,
+            request_time,
+            request_expiration,
+            send_msg_id,
+            options,
+        )| EciesBuildRequestRecord {
+            receive_tid,
+            next_tid,
+            next_ident,
+            layer_key,
+            iv_key,
+            reply_key,
+            reply_iv,
+            hop_type,
+            request_time,
+            request_expiration,
+            send_msg_id,
+            options,
+        },
+    )(i)
+}
+
+pub fn gen_ecies_build_request_record<'a>(
+    input: (&'a mut [u8], usize),
+    brr: &EciesBuildRequestRecord,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let start = input.1;
+    let input = do_gen!(
+        input,
+        gen_tunnel_id(&brr.receive_tid)
+            >> gen_tunnel_id(&brr.next_tid)
+            >> gen_hash(&brr.next_ident)
+            >> gen_session_key(&brr.layer_key)
+            >> gen_session_key(&brr.iv_key)
+            >> gen_session_key(&brr.reply_key)
+            >> gen_slice!(&brr.reply_iv)
+            >> gen_hop_type_flags(brr.hop_type)
+            >> gen_slice!(&[0u8; 3])
+            >> gen_be_u32!(brr.request_time)
+            >> gen_be_u32!(brr.request_expiration)
+            >> gen_be_u32!(brr.send_msg_id)
+            >> gen_mapping(&brr.options)
+    )?;
+    gen_record_padding(input, start, ECIES_BUILD_REQUEST_RECORD_LEN)
+}
+
+pub fn short_build_request_record(i: &[u8]) -> IResult<&[u8], ShortBuildRequestRecord> {
+    map(
+        tuple((
+            tunnel_id,
+            tunnel_id,
+            hash,
+            hop_type_flags,
+            take(2usize),
+            be_u8,
+            be_u32,
+            be_u32,
+            be_u32,
+            mapping,
+        )),
+        |(
+            receive_tid,
+            next_tid,
+            next_ident,
+            hop_type,
+            _,
+            layer_enc_type,
+            request_time,
+            request_expiration,
+            send_msg_id,
+            options,
+        )| ShortBuildRequestRecord {
+            receive_tid,
+            next_tid,
+            next_ident,
+            hop_type,
+            layer_enc_type,
+            request_time,
+            request_expiration,
+            send_msg_id,
+            options,
+        },
+    )(i)
+}
+
+pub fn gen_short_build_request_record<'a>(
+    input: (&'a mut [u8], usize),
+    brr: &ShortBuildRequestRecord,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let start = input.1;
+    let input = do_gen!(
+        input,
+        gen_tunnel_id(&brr.receive_tid)
+            >> gen_tunnel_id(&brr.next_tid)
+            >> gen_hash(&brr.next_ident)
+            >> gen_hop_type_flags(brr.hop_type)
+            >> gen_slice!(&[0u8; 2])
+            >> gen_be_u8!(brr.layer_enc_type)
+            >> gen_be_u32!(brr.request_time)
+            >> gen_be_u32!(brr.request_expiration)
+            >> gen_be_u32!(brr.send_msg_id)
+            >> gen_mapping(&brr.options)
+    )?;
+    gen_record_padding(input, start, SHORT_BUILD_REQUEST_RECORD_LEN)
+}
+
+/// Parses the cleartext of a long or short ECIES build response record, which must
+/// take up all of `input`.
+pub fn ecies_build_response_record(input: &[u8]) -> IResult<&[u8], EciesBuildResponseRecord> {
+    let (i, options) = mapping(input)?;
+    match i.split_last() {
+        Some((&reply, _)) => Ok((&i[i.len()..], EciesBuildResponseRecord { options, reply })),
+        None => Err(Err::Error(NomError::new(input, ErrorKind::Eof))),
+    }
+}
+
+/// Writes the cleartext of an ECIES build response record that is `len` bytes long.
+pub fn gen_ecies_build_response_record<'a>(
+    input: (&'a mut [u8], usize),
+    brr: &EciesBuildResponseRecord,
+    len: usize,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    let start = input.1;
+    let input = gen_mapping(input, &brr.options)?;
+    let input = gen_record_padding(input, start, len - 1)?;
+    gen_be_u8!(input, brr.reply)
+}
+
 //
 // Message payloads
 //
@@ -180,9 +402,15 @@ pub fn gen_build_response_record<'a>(
 
 fn compressed_ri(input: &[u8]) -> IResult<&[u8], RouterInfo> {
     let (i, payload) = length_data(be_u16)(input)?;
+    if payload.len() > limits::MAX_NETDB_ENTRY_SIZE {
+        return Err(Err::Error(NomError::new(input, ErrorKind::TooLarge)));
+    }
     let mut buf = Vec::new();
-    let mut d = GzDecoder::new(payload);
+    let mut d = GzDecoder::new(payload).take(limits::MAX_NETDB_ENTRY_SIZE as u64 + 1);
     match d.read_to_end(&mut buf) {
+        Ok(n) if n > limits::MAX_NETDB_ENTRY_SIZE => {
+            Err(Err::Error(NomError::new(input, ErrorKind::TooLarge)))
+        }
         Ok(_) => match router_info(&buf) {
             Ok((_, ri)) => Ok((i, ri)),
             Err(Err::Incomplete(n)) => Err(Err::Incomplete(n)),
@@ -245,7 +473,9 @@ fn database_store(i: &[u8]) -> IResult<&[u8], MessagePayload> {
     let (i, data) = match ds_type {
         0 => map(compressed_ri, |ri| DatabaseStoreData::RI(ri))(i),
         1 => map(lease_set, |ls| DatabaseStoreData::LS(ls))(i),
-        _ => unimplemented!(),
+        3 => map(lease_set2, |ls| DatabaseStoreData::LS2(ls))(i),
+        5 => map(encrypted_lease_set2, |els| DatabaseStoreData::EncryptedLS2(els))(i),
+        _ => Err(Err::Error(NomError::new(i, ErrorKind::Switch))),
     }?;
     Ok((
         i,
@@ -265,6 +495,8 @@ fn gen_database_store_data<'a>(
     match *data {
         DatabaseStoreData::RI(ref ri) => gen_compressed_ri(input, &ri),
         DatabaseStoreData::LS(ref ls) => gen_lease_set(input, &ls),
+        DatabaseStoreData::LS2(ref ls) => gen_lease_set2(input, &ls),
+        DatabaseStoreData::EncryptedLS2(ref els) => gen_encrypted_lease_set2(input, &els),
     }
 }
 
@@ -328,10 +560,10 @@ fn database_lookup(i: &[u8]) -> IResult<&[u8], MessagePayload> {
     let (i, (key, from, flags)) = tuple((hash, hash, database_lookup_flags))(i)?;
     let (i, (reply_tid, excluded_peers, reply_enc)) = tuple((
         cond(flags.delivery, tunnel_id),
-        length_count(be_u16, hash),
+        bounded_count(be_u16, limits::MAX_EXCLUDED_PEERS, 32, hash),
         cond(
             flags.encryption,
-            pair(session_key, length_count(be_u8, session_tag)),
+            pair(session_key, bounded_count(be_u8, 255, 32, session_tag)),
         ),
     ))(i)?;
     Ok((
@@ -383,7 +615,7 @@ fn gen_database_lookup<'a>(
 
 fn database_search_reply(i: &[u8]) -> IResult<&[u8], MessagePayload> {
     map(
-        tuple((hash, length_count(be_u8, hash), hash)),
+        tuple((hash, bounded_count(be_u8, 255, 32, hash), hash)),
         |(key, peers, from)| {
             MessagePayload::DatabaseSearchReply(DatabaseSearchReply { key, peers, from })
         },
@@ -487,11 +719,11 @@ fn gen_garlic_clove_delivery_instructions<'a>(
     )
 }
 
-fn garlic_clove(i: &[u8]) -> IResult<&[u8], GarlicClove> {
+fn garlic_clove<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], GarlicClove> {
     map(
         tuple((
             garlic_clove_delivery_instructions,
-            message,
+            |i: &'a [u8]| message_in(src, i),
             be_u32,
             i2p_date,
             certificate,
@@ -520,10 +752,10 @@ fn gen_garlic_clove<'a>(
     )
 }
 
-fn garlic(i: &[u8]) -> IResult<&[u8], MessagePayload> {
+fn garlic<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], MessagePayload> {
     map(
         tuple((
-            length_count(be_u8, garlic_clove),
+            length_count(be_u8, |i: &'a [u8]| garlic_clove(src, i)),
             certificate,
             be_u32,
             i2p_date,
@@ -555,47 +787,74 @@ fn gen_garlic<'a>(
 
 // TunnelData
 
-fn tunnel_data(i: &[u8]) -> IResult<&[u8], MessagePayload> {
+fn tunnel_data<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], MessagePayload> {
     map(pair(tunnel_id, take(1024usize)), |(tid, data)| {
-        MessagePayload::TunnelData(TunnelData::from(tid, array_ref![data, 0, 1024]))
+        MessagePayload::TunnelData(TunnelData {
+            tid,
+            data: src.bytes(data),
+        })
     })(i)
 }
 
+fn gen_tunnel_data_header<'a>(
+    input: (&'a mut [u8], usize),
+    td: &TunnelData,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    gen_tunnel_id(input, &td.tid)
+}
+
 fn gen_tunnel_data<'a>(
     input: (&'a mut [u8], usize),
     td: &TunnelData,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(input, gen_tunnel_id(&td.tid) >> gen_slice!(td.data))
+    do_gen!(input, gen_tunnel_data_header(td) >> gen_slice!(td.data))
 }
 
 // TunnelGateway
 
-fn tunnel_gateway(i: &[u8]) -> IResult<&[u8], MessagePayload> {
+fn tunnel_gateway<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], MessagePayload> {
     map(pair(tunnel_id, length_data(be_u16)), |(tid, data)| {
         MessagePayload::TunnelGateway(TunnelGateway {
             tid,
-            data: Vec::from(data),
+            data: src.bytes(data),
         })
     })(i)
 }
 
+fn gen_tunnel_gateway_header<'a>(
+    input: (&'a mut [u8], usize),
+    tg: &TunnelGateway,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_tunnel_id(&tg.tid) >> gen_be_u16!(tg.data.len() as u16)
+    )
+}
+
 fn gen_tunnel_gateway<'a>(
     input: (&'a mut [u8], usize),
     tg: &TunnelGateway,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(input, gen_tunnel_id(&tg.tid) >> gen_slice!(tg.data))
+    do_gen!(input, gen_tunnel_gateway_header(tg) >> gen_slice!(tg.data))
 }
 
 // Data
 
-fn data(i: &[u8]) -> IResult<&[u8], MessagePayload> {
+fn data<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], MessagePayload> {
     map(length_data(be_u32), |data| {
-        MessagePayload::Data(Vec::from(data))
+        MessagePayload::Data(src.bytes(data))
     })(i)
 }
 
+fn gen_data_header<'a>(
+    input: (&'a mut [u8], usize),
+    d: &[u8],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    gen_be_u32!(input, d.len())
+}
+
 fn gen_data<'a>(input: (&'a mut [u8], usize), d: &[u8]) -> Result<(&'a mut [u8], usize), GenError> {
-    do_gen!(input, gen_be_u32!(d.len()) >> gen_slice!(d))
+    do_gen!(input, gen_data_header(d) >> gen_slice!(d))
 }
 
 // TunnelBuild
@@ -656,8 +915,12 @@ fn gen_tunnel_build_reply<'a>(
 
 // VariableTunnelBuild
 
+fn build_record_count(i: &[u8]) -> IResult<&[u8], u8> {
+    verify(be_u8, |n| usize::from(*n) <= limits::MAX_BUILD_RECORDS)(i)
+}
+
 fn variable_tunnel_build(input: &[u8]) -> IResult<&[u8], MessagePayload> {
-    let (i, r) = length_count(be_u8, take(528usize))(input)?;
+    let (i, r) = length_count(build_record_count, take(528usize))(input)?;
     Ok((
         i,
         MessagePayload::VariableTunnelBuild(
@@ -687,7 +950,7 @@ fn gen_variable_tunnel_build<'a>(
 // VariableTunnelBuildReply
 
 fn variable_tunnel_build_reply(input: &[u8]) -> IResult<&[u8], MessagePayload> {
-    let (i, r) = length_count(be_u8, take(528usize))(input)?;
+    let (i, r) = length_count(build_record_count, take(528usize))(input)?;
     Ok((
         i,
         MessagePayload::VariableTunnelBuildReply(
@@ -714,11 +977,49 @@ fn gen_variable_tunnel_build_reply<'a>(
     Ok(x)
 }
 
+// ShortTunnelBuild and OutboundTunnelBuildReply
+
+fn short_build_records(input: &[u8]) -> IResult<&[u8], Vec<[u8; 218]>> {
+    let (i, r) = length_count(build_record_count, take(218usize))(input)?;
+    Ok((
+        i,
+        r.iter()
+            .map(|&s| {
+                let mut x = [0u8; 218];
+                x.copy_from_slice(s);
+                x
+            })
+            .collect(),
+    ))
+}
+
+fn gen_short_build_records<'a>(
+    input: (&'a mut [u8], usize),
+    records: &[[u8; 218]],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    if records.len() > limits::MAX_BUILD_RECORDS {
+        return Err(GenError::CustomError(2));
+    }
+    let mut x = gen_be_u8!(input, records.len() as u8)?;
+    for record in records {
+        x = gen_slice!(x, record)?;
+    }
+    Ok(x)
+}
+
+fn short_tunnel_build(input: &[u8]) -> IResult<&[u8], MessagePayload> {
+    map(short_build_records, MessagePayload::ShortTunnelBuild)(input)
+}
+
+fn outbound_tunnel_build_reply(input: &[u8]) -> IResult<&[u8], MessagePayload> {
+    map(short_build_records, MessagePayload::OutboundTunnelBuildReply)(input)
+}
+
 //
 // I2NP message framing
 //
 
-fn checksum(buf: &[u8]) -> u8 {
+pub(super) fn checksum(buf: &[u8]) -> u8 {
     Sha256::digest(&buf)[0]
 }
 
@@ -740,30 +1041,70 @@ fn ntcp2_header(i: &[u8]) -> IResult<&[u8], (u8, u32, I2PDate)> {
     tuple((be_u8, be_u32, short_expiry))(i)
 }
 
-fn payload(msg_type: u8) -> impl Fn(&[u8]) -> IResult<&[u8], MessagePayload> {
+fn payload<'a>(
+    src: Source<'a>,
+    msg_type: u8,
+) -> impl Fn(&[u8]) -> IResult<&[u8], MessagePayload> + 'a {
     move |i: &[u8]| match msg_type {
         1 => database_store(i),
         2 => database_lookup(i),
         3 => database_search_reply(i),
         10 => delivery_status(i),
-        11 => garlic(i),
-        18 => tunnel_data(i),
-        19 => tunnel_gateway(i),
-        20 => data(i),
+        11 => garlic(src, i),
+        18 => tunnel_data(src, i),
+        19 => tunnel_gateway(src, i),
+        20 => data(src, i),
         21 => tunnel_build(i),
         22 => tunnel_build_reply(i),
         23 => variable_tunnel_build(i),
         24 => variable_tunnel_build_reply(i),
+        25 => short_tunnel_build(i),
+        26 => outbound_tunnel_build_reply(i),
         _ => unimplemented!(),
     }
 }
 
+/// Rejects payloads that are larger than the limit for their message type.
+fn check_payload_size(i: &[u8], msg_type: u8, size: usize) -> IResult<&[u8], ()> {
+    match MessageType::from_code(msg_type) {
+        Some(t) if size > limits::max_payload_size(t) => {
+            Err(Err::Error(NomError::new(i, ErrorKind::TooLarge)))
+        }
+        _ => Ok((i, ())),
+    }
+}
+
+/// Rejects payloads written since `start` that are larger than the limit for their
+/// message type.
+fn gen_check_payload_size(
+    input: (&mut [u8], usize),
+    msg_type: MessageType,
+    start: usize,
+) -> Result<(&mut [u8], usize), GenError> {
+    if input.1 - start > limits::max_payload_size(msg_type) {
+        Err(GenError::CustomError(2))
+    } else {
+        Ok(input)
+    }
+}
+
 pub fn message(i: &[u8]) -> IResult<&[u8], Message> {
+    message_in(Source(None), i)
+}
+
+/// Parses an I2NP message from a slice of `buf`, sharing `buf` with any opaque
+/// payloads instead of copying them.
+pub fn message_bytes<'a>(buf: &Bytes, i: &'a [u8]) -> IResult<&'a [u8], Message> {
+    message_in(Source(Some(buf)), i)
+}
+
+fn message_in<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], Message> {
     let (i, (msg_type, id, expiration, size, cs)) = header(i)?;
+    let (i, _) = check_payload_size(i, msg_type, usize::from(size))?;
     map(
         preceded(
             peek(verify(take(size), move |buf| checksum(buf) == cs)),
-            payload(msg_type),
+            payload(src, msg_type),
         ),
         move |payload| Message {
             id,
@@ -773,9 +1114,25 @@ pub fn message(i: &[u8]) -> IResult<&[u8], Message> {
     )(i)
 }
 
+/// Parses an I2NP message with the short NTCP2 header.
+///
+/// The short header has no size field, so `i` must contain exactly one message.
 pub fn ntcp2_message(i: &[u8]) -> IResult<&[u8], Message> {
+    ntcp2_message_in(Source(None), i)
+}
+
+/// Parses an I2NP message with the short NTCP2 header from a slice of `buf`,
+/// sharing `buf` with any opaque payloads instead of copying them.
+///
+/// As with [`ntcp2_message`], `i` must contain exactly one message.
+pub fn ntcp2_message_bytes<'a>(buf: &Bytes, i: &'a [u8]) -> IResult<&'a [u8], Message> {
+    ntcp2_message_in(Source(Some(buf)), i)
+}
+
+fn ntcp2_message_in<'a>(src: Source, i: &'a [u8]) -> IResult<&'a [u8], Message> {
     let (i, hdr) = ntcp2_header(i)?;
-    let (i, payload) = payload(hdr.0)(i)?;
+    let (i, _) = check_payload_size(i, hdr.0, i.len())?;
+    let (i, payload) = payload(src, hdr.0)(i)?;
     Ok((
         i,
         Message {
@@ -790,24 +1147,10 @@ fn gen_message_type<'a>(
     input: (&'a mut [u8], usize),
     msg: &Message,
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    let msg_type = match msg.payload {
-        MessagePayload::DatabaseStore(_) => 1,
-        MessagePayload::DatabaseLookup(_) => 2,
-        MessagePayload::DatabaseSearchReply(_) => 3,
-        MessagePayload::DeliveryStatus(_) => 10,
-        MessagePayload::Garlic(_) => 11,
-        MessagePayload::TunnelData(_) => 18,
-        MessagePayload::TunnelGateway(_) => 19,
-        MessagePayload::Data(_) => 20,
-        MessagePayload::TunnelBuild(_) => 21,
-        MessagePayload::TunnelBuildReply(_) => 22,
-        MessagePayload::VariableTunnelBuild(_) => 23,
-        MessagePayload::VariableTunnelBuildReply(_) => 24,
-    };
-    gen_be_u8!(input, msg_type)
+    gen_be_u8!(input, msg.message_type().code())
 }
 
-fn gen_payload<'a>(
+pub fn gen_payload<'a>(
     input: (&'a mut [u8], usize),
     payload: &MessagePayload,
 ) -> Result<(&'a mut [u8], usize), GenError> {
@@ -826,9 +1169,55 @@ fn gen_payload<'a>(
         MessagePayload::VariableTunnelBuildReply(ref vtbr) => {
             gen_variable_tunnel_build_reply(input, &vtbr)
         }
+        MessagePayload::ShortTunnelBuild(ref stb) => gen_short_build_records(input, &stb),
+        MessagePayload::OutboundTunnelBuildReply(ref otbr) => {
+            gen_short_build_records(input, &otbr)
+        }
+    }
+}
+
+/// Serializes the fields of `payload` that precede its opaque body, for payloads
+/// whose body is written separately.
+pub(super) fn gen_payload_header<'a>(
+    input: (&'a mut [u8], usize),
+    payload: &MessagePayload,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    match *payload {
+        MessagePayload::TunnelData(ref td) => gen_tunnel_data_header(input, &td),
+        MessagePayload::TunnelGateway(ref tg) => gen_tunnel_gateway_header(input, &tg),
+        MessagePayload::Data(ref d) => gen_data_header(input, &d),
+        _ => Err(GenError::NotYetImplemented),
     }
 }
 
+/// Serializes the standard I2NP header for a payload of `size` bytes with checksum `cs`.
+pub(super) fn gen_header<'a>(
+    input: (&'a mut [u8], usize),
+    msg: &Message,
+    size: u16,
+    cs: u8,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_message_type(msg)
+            >> gen_be_u32!(msg.id)
+            >> gen_i2p_date(&msg.expiration)
+            >> gen_be_u16!(size)
+            >> gen_be_u8!(cs)
+    )
+}
+
+/// Serializes the short NTCP2 I2NP header.
+pub(super) fn gen_ntcp2_header<'a>(
+    input: (&'a mut [u8], usize),
+    msg: &Message,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_message_type(msg) >> gen_be_u32!(msg.id) >> gen_short_expiry(&msg.expiration)
+    )
+}
+
 pub fn gen_message<'a>(
     input: (&'a mut [u8], usize),
     msg: &Message,
@@ -840,6 +1229,7 @@ pub fn gen_message<'a>(
         size:  gen_skip!(2) >>
         cs:    gen_skip!(1) >>
         start: gen_payload(&msg.payload) >>
+               gen_check_payload_size(msg.message_type(), start) >>
         end:   gen_at_offset!(size, gen_be_u16!(end-start)) >>
                gen_at_offset!(cs, gen_checksum(start, end))
     )
@@ -854,7 +1244,8 @@ pub fn gen_ntcp2_message<'a>(
         gen_message_type(msg)
             >> gen_be_u32!(msg.id)
             >> gen_short_expiry(&msg.expiration)
-            >> gen_payload(&msg.payload)
+            >> start: gen_payload(&msg.payload)
+            >> gen_check_payload_size(msg.message_type(), start)
     )
 }
 
@@ -1040,6 +1431,107 @@ mod tests {
         eval!(BuildResponseRecord { reply: 255 });
     }
 
+    #[test]
+    fn test_ecies_build_records() {
+        use crate::data::I2PString;
+
+        let mut options = Mapping::default();
+        options
+            .insert(I2PString::new("foo"), I2PString::new("bar"))
+            .unwrap();
+
+        for &hop_type in &[
+            ParticipantType::Intermediate,
+            ParticipantType::InboundGateway,
+            ParticipantType::OutboundEndpoint,
+        ] {
+            let brr = EciesBuildRequestRecord {
+                receive_tid: TunnelId(7),
+                next_tid: TunnelId(2),
+                next_ident: Hash([9; 32]),
+                layer_key: SessionKey([6; 32]),
+                iv_key: SessionKey([8; 32]),
+                reply_key: SessionKey([1; 32]),
+                reply_iv: [3; 16],
+                hop_type,
+                request_time: 27_000_000,
+                request_expiration: 600,
+                send_msg_id: 12,
+                options: options.clone(),
+            };
+            let flags = match hop_type {
+                ParticipantType::Intermediate => 0x00,
+                ParticipantType::InboundGateway => 0x80,
+                ParticipantType::OutboundEndpoint => 0x40,
+            };
+
+            let mut buf = vec![0; ECIES_BUILD_REQUEST_RECORD_LEN];
+            let (_, n) = gen_ecies_build_request_record((&mut buf, 0), &brr).unwrap();
+            assert_eq!(n, ECIES_BUILD_REQUEST_RECORD_LEN);
+            assert_eq!(buf[152], flags);
+            assert_eq!(&buf[156..160], &27_000_000u32.to_be_bytes());
+            assert_eq!(ecies_build_request_record(&buf).map(|(_, r)| r), Ok(brr));
+
+            let brr = ShortBuildRequestRecord {
+                receive_tid: TunnelId(7),
+                next_tid: TunnelId(2),
+                next_ident: Hash([9; 32]),
+                hop_type,
+                layer_enc_type: 0,
+                request_time: 27_000_000,
+                request_expiration: 600,
+                send_msg_id: 12,
+                options: options.clone(),
+            };
+            let mut buf = vec![0; SHORT_BUILD_REQUEST_RECORD_LEN];
+            let (_, n) = gen_short_build_request_record((&mut buf, 0), &brr).unwrap();
+            assert_eq!(n, SHORT_BUILD_REQUEST_RECORD_LEN);
+            assert_eq!(buf[40], flags);
+            assert_eq!(&buf[44..48], &27_000_000u32.to_be_bytes());
+            assert_eq!(short_build_request_record(&buf).map(|(_, r)| r), Ok(brr));
+        }
+
+        // Both flag bits can't be set
+        let mut buf = vec![0; SHORT_BUILD_REQUEST_RECORD_LEN];
+        buf[40] = 0xc0;
+        assert!(short_build_request_record(&buf).is_err());
+
+        // Options that don't fit in the record
+        let mut big = Mapping::default();
+        big.insert(I2PString::new("foo"), I2PString::new(&"x".repeat(150)))
+            .unwrap();
+        let brr = ShortBuildRequestRecord {
+            receive_tid: TunnelId(7),
+            next_tid: TunnelId(2),
+            next_ident: Hash([9; 32]),
+            hop_type: ParticipantType::Intermediate,
+            layer_enc_type: 0,
+            request_time: 0,
+            request_expiration: 600,
+            send_msg_id: 12,
+            options: big,
+        };
+        let mut buf = vec![0; 1024];
+        assert!(gen_short_build_request_record((&mut buf, 0), &brr).is_err());
+
+        for &len in &[
+            ECIES_BUILD_RESPONSE_RECORD_LEN,
+            SHORT_BUILD_RESPONSE_RECORD_LEN,
+        ] {
+            for &reply in &[0, 30, 255] {
+                let brr = EciesBuildResponseRecord {
+                    options: options.clone(),
+                    reply,
+                };
+                let mut buf = vec![0; len];
+                let (_, n) = gen_ecies_build_response_record((&mut buf, 0), &brr, len).unwrap();
+                assert_eq!(n, len);
+                assert_eq!(buf[len - 1], reply);
+                assert_eq!(ecies_build_response_record(&buf).map(|(_, r)| r), Ok(brr));
+            }
+        }
+    }
+
     #[test]
     fn test_database_lookup_flags() {
         macro_rules! eval {
@@ -1118,7 +1610,7 @@ mod tests {
             Message {
                 id: 0,
                 expiration: I2PDate::from_system_time(UNIX_EPOCH),
-                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into()),
             },
             [
                 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 14, 44, 0, 0, 0, 10, 0, 1, 2, 3, 4, 5,
@@ -1154,7 +1646,7 @@ mod tests {
             Message {
                 id: 0,
                 expiration: I2PDate::from_system_time(UNIX_EPOCH),
-                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into()),
             },
             [20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,]
         );
@@ -1174,4 +1666,155 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_message_checksum_mismatch() {
+        let msg = Message {
+            id: 0,
+            expiration: I2PDate::from_system_time(UNIX_EPOCH),
+            payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into()),
+        };
+        let mut buf = serialize(|input| gen_message(input, &msg));
+        assert_eq!(buf[15], 44);
+        buf[15] ^= 0xff;
+        match message(&buf) {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::Verify),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Accepted a message with an invalid checksum"),
+        }
+    }
+
+    fn assert_too_large(res: IResult<&[u8], Message>) {
+        match res {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::TooLarge),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Accepted an oversized message"),
+        }
+    }
+
+    fn assert_not_too_large(res: IResult<&[u8], Message>) {
+        if let Err(Err::Error(e)) = res {
+            assert_ne!(e.code, ErrorKind::TooLarge);
+        }
+    }
+
+    /// Returns an NTCP header claiming a payload of the given size, with no payload.
+    fn oversized_header(msg_type: MessageType, size: usize) -> Vec<u8> {
+        let mut buf = vec![msg_type.code(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        buf.extend_from_slice(&(size as u16).to_be_bytes());
+        buf.push(0);
+        buf
+    }
+
+    #[test]
+    fn test_message_too_large() {
+        for &(msg_type, limit) in [
+            (MessageType::DeliveryStatus, 12),
+            (MessageType::TunnelData, 1028),
+            (MessageType::TunnelBuild, 8 * 528),
+            (MessageType::VariableTunnelBuild, 1 + 8 * 528),
+            (MessageType::ShortTunnelBuild, 1 + 8 * 218),
+        ]
+        .iter()
+        {
+            assert_eq!(limits::max_payload_size(msg_type), limit);
+            assert_not_too_large(message(&oversized_header(msg_type, limit)));
+            assert_too_large(message(&oversized_header(msg_type, limit + 1)));
+        }
+
+        // NTCP2 messages are bounded by the enclosing block
+        let mut buf = vec![10, 0, 0, 0, 0, 0, 0, 0, 0];
+        buf.extend_from_slice(&[0; 13]);
+        assert_too_large(ntcp2_message(&buf));
+    }
+
+    #[test]
+    fn test_too_many_build_records() {
+        let mut buf = vec![9];
+        buf.extend(std::iter::repeat(0).take(9 * 528));
+        match variable_tunnel_build(&buf) {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::Verify),
+            v => panic!("Unexpected returned value: {:?}", v.map(|_| ())),
+        }
+        match variable_tunnel_build_reply(&buf) {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::Verify),
+            v => panic!("Unexpected returned value: {:?}", v.map(|_| ())),
+        }
+
+        buf[0] = 8;
+        buf.truncate(1 + 8 * 528);
+        assert!(variable_tunnel_build(&buf).is_ok());
+
+        let mut buf = vec![9];
+        buf.extend(std::iter::repeat(0).take(9 * 218));
+        match short_tunnel_build(&buf) {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::Verify),
+            v => panic!("Unexpected returned value: {:?}", v.map(|_| ())),
+        }
+        let mut out = vec![0; buf.len()];
+        assert!(gen_short_build_records((&mut out[..], 0), &[[0; 218]; 9]).is_err());
+    }
+
+    #[test]
+    fn test_too_many_excluded_peers() {
+        let lookup = |n: u16| {
+            // key, from, flags (no reply tunnel, no encryption), excluded peers
+            let mut buf = vec![0; 65];
+            buf.extend_from_slice(&n.to_be_bytes());
+            buf.extend(std::iter::repeat(0).take(32 * usize::from(n)));
+            buf
+        };
+        match database_lookup(&lookup(513)) {
+            Err(Err::Error(e)) => assert_eq!(e.code, ErrorKind::Verify),
+            v => panic!("Unexpected returned value: {:?}", v.map(|_| ())),
+        }
+        assert!(database_lookup(&lookup(512)).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "payload is too large")]
+    #[cfg(debug_assertions)]
+    fn test_oversized_payload_panics_in_debug() {
+        Message::data(
+            &MessageIdGenerator::default(),
+            vec![0; limits::MAX_PAYLOAD_SIZE],
+        );
+    }
+
+    #[test]
+    fn test_gen_message_too_large() {
+        let msg = Message {
+            id: 0,
+            expiration: I2PDate::from_system_time(UNIX_EPOCH),
+            payload: MessagePayload::Data(vec![0; limits::MAX_PAYLOAD_SIZE].into()),
+        };
+        let mut buf = vec![0; 16 + 4 + limits::MAX_PAYLOAD_SIZE];
+        match gen_message((&mut buf, 0), &msg) {
+            Err(GenError::CustomError(_)) => (),
+            v => panic!("Unexpected returned value: {:?}", v.map(|(_, n)| n)),
+        }
+    }
+
+    #[test]
+    fn test_relay_ntcp2_to_ntcp() {
+        // Received over NTCP2, which carries no checksum
+        let ntcp2_buf = [
+            0x0a, 0x12, 0x34, 0x56, 0x78, 0x5a, 0xe3, 0xbd, 0x9e, 0x7b, 0x3f, 0xbb, 0xa9, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let (_, msg) = ntcp2_message(&ntcp2_buf[..]).unwrap();
+
+        // Relayed over NTCP, so the checksum must be freshly computed
+        let ntcp_buf = serialize(|input| gen_message(input, &msg));
+        assert_eq!(&ntcp_buf[13..15], &[0x00, 0x0c]);
+        assert_eq!(ntcp_buf[15], checksum(&ntcp_buf[16..]));
+        assert_eq!(&ntcp_buf[16..], &ntcp2_buf[9..]);
+        match message(&ntcp_buf) {
+            Ok((rest, parsed)) => {
+                assert!(rest.is_empty());
+                assert_eq!(parsed, msg);
+            }
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        }
+    }
 }
@@ -0,0 +1,47 @@
+//! Upper bounds on the size of I2NP messages.
+//!
+//! All limits are on the serialized payload, excluding the I2NP header. They are
+//! enforced when parsing, so that oversized messages are rejected before their
+//! contents are allocated, and when serializing.
+
+use super::MessageType;
+
+/// The largest payload that fits in the size field of the standard I2NP header.
+pub const MAX_PAYLOAD_SIZE: usize = 65535;
+
+/// The largest RouterInfo or LeaseSet we will accept in a DatabaseStore.
+pub const MAX_NETDB_ENTRY_SIZE: usize = 8 * 1024;
+
+/// The largest number of peers that can be excluded in a DatabaseLookup.
+pub const MAX_EXCLUDED_PEERS: usize = 512;
+
+/// The largest number of records in a VariableTunnelBuild(Reply), ShortTunnelBuild or
+/// OutboundTunnelBuildReply.
+pub const MAX_BUILD_RECORDS: usize = 8;
+
+/// Key, type, and the longest reply path.
+const DATABASE_STORE_HEADER_SIZE: usize = 32 + 1 + 4 + 4 + 32;
+
+/// Returns the largest valid payload size for the given message type.
+pub fn max_payload_size(msg_type: MessageType) -> usize {
+    match msg_type {
+        MessageType::DatabaseStore => DATABASE_STORE_HEADER_SIZE + 2 + MAX_NETDB_ENTRY_SIZE,
+        MessageType::DatabaseLookup => {
+            // Keys, flags, reply tunnel, excluded peers, and reply key with 255 tags
+            65 + 4 + 2 + 32 * MAX_EXCLUDED_PEERS + 33 + 32 * 255
+        }
+        MessageType::DatabaseSearchReply => 65 + 32 * 255,
+        MessageType::DeliveryStatus => 12,
+        MessageType::TunnelData => 1028,
+        MessageType::TunnelBuild | MessageType::TunnelBuildReply => 8 * 528,
+        MessageType::VariableTunnelBuild | MessageType::VariableTunnelBuildReply => {
+            1 + MAX_BUILD_RECORDS * 528
+        }
+        MessageType::ShortTunnelBuild | MessageType::OutboundTunnelBuildReply => {
+            1 + MAX_BUILD_RECORDS * 218
+        }
+        MessageType::Garlic | MessageType::TunnelGateway | MessageType::Data => {
+            MAX_PAYLOAD_SIZE
+        }
+    }
+}
@@ -8,19 +8,32 @@
 //!
 //! [I2NP specification](https://geti2p.net/spec/i2np)
 
-use rand::{rngs::OsRng, thread_rng, Rng};
+use bytes::{BufMut, Bytes, BytesMut};
+use cookie_factory::GenError;
+use rand::{Rng, RngCore};
 use std::fmt;
+use std::iter;
+use std::mem;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::crypto::{self, elgamal, SessionKey};
+use crate::crypto::{
+    self, elgamal,
+    rand::{CryptoRng, OsRng},
+    SessionKey,
+};
 use crate::data::{
-    Certificate, Hash, I2PDate, LeaseSet, ReadError, RouterInfo, SessionTag, TunnelId,
+    Certificate, EncryptedLeaseSet2, Hash, I2PDate, LeaseSet, LeaseSet2, Mapping, ReadError,
+    RouterInfo, SessionTag, TunnelId,
 };
 use crate::util::serialize;
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod fixtures;
 #[allow(clippy::double_parens)]
 #[allow(clippy::needless_pass_by_value)]
 pub(crate) mod frame;
+pub mod limits;
 
 const MESSAGE_EXPIRATION_MS: u64 = 60 * 1000;
 
@@ -49,7 +62,7 @@ impl<T> From<nom::Err<T>> for BuildRequestError {
 // Common structures
 //
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ParticipantType {
     InboundGateway,
     Intermediate,
@@ -117,12 +130,21 @@ impl BuildRequestRecord {
     }
 
     pub fn encrypt(&self, encryptor: &elgamal::Encryptor) -> [u8; 528] {
+        self.encrypt_with(encryptor, elgamal::Ephemeral::generate())
+    }
+
+    /// As [`BuildRequestRecord::encrypt`], but using a previously-generated ephemeral.
+    pub fn encrypt_with(
+        &self,
+        encryptor: &elgamal::Encryptor,
+        ephemeral: elgamal::Ephemeral,
+    ) -> [u8; 528] {
         let mut pt = [0; 222];
         frame::gen_build_request_record((&mut pt, 0), self).unwrap();
 
         let mut ct = [0; 528];
         ct[0..16].copy_from_slice(&self.our_ident.0[0..16]);
-        ct[16..].copy_from_slice(&encryptor.encrypt(&pt, false).unwrap());
+        ct[16..].copy_from_slice(&encryptor.encrypt_with(&pt, false, ephemeral).unwrap());
         ct
     }
 }
@@ -134,6 +156,52 @@ pub struct BuildResponseRecord {
     pub reply: u8,
 }
 
+/// The cleartext of a long build request record for a hop with an ECIES-X25519
+/// router key.
+#[derive(Debug, PartialEq)]
+pub struct EciesBuildRequestRecord {
+    pub receive_tid: TunnelId,
+    pub next_tid: TunnelId,
+    pub next_ident: Hash,
+    pub layer_key: SessionKey,
+    pub iv_key: SessionKey,
+    pub reply_key: SessionKey,
+    pub reply_iv: [u8; 16],
+    pub hop_type: ParticipantType,
+    /// Minutes since the epoch.
+    pub request_time: u32,
+    /// Seconds after the request time at which the request expires.
+    pub request_expiration: u32,
+    pub send_msg_id: u32,
+    pub options: Mapping,
+}
+
+/// The cleartext of a short build request record. The hop's keys are not included;
+/// they are derived from the encryption of the record.
+#[derive(Debug, PartialEq)]
+pub struct ShortBuildRequestRecord {
+    pub receive_tid: TunnelId,
+    pub next_tid: TunnelId,
+    pub next_ident: Hash,
+    pub hop_type: ParticipantType,
+    /// The tunnel layer encryption type; 0 is AES.
+    pub layer_enc_type: u8,
+    /// Minutes since the epoch.
+    pub request_time: u32,
+    /// Seconds after the request time at which the request expires.
+    pub request_expiration: u32,
+    pub send_msg_id: u32,
+    pub options: Mapping,
+}
+
+/// The cleartext of a long or short build response record from a hop with an
+/// ECIES-X25519 router key.
+#[derive(Debug, PartialEq)]
+pub struct EciesBuildResponseRecord {
+    pub options: Mapping,
+    pub reply: u8,
+}
+
 //
 // Messages
 //
@@ -144,9 +212,38 @@ pub struct ReplyPath {
     gateway: Hash,
 }
 
+impl ReplyPath {
+    /// Requests a DeliveryStatus reply with the given token, sent to the given tunnel
+    /// gateway.
+    pub fn new(token: u32, tid: TunnelId, gateway: Hash) -> Self {
+        ReplyPath {
+            token,
+            tid,
+            gateway,
+        }
+    }
+
+    /// The token to return in the DeliveryStatus reply.
+    pub fn token(&self) -> u32 {
+        self.token
+    }
+
+    /// The tunnel to send the reply into, or zero to send it directly to the
+    /// gateway router.
+    pub fn tid(&self) -> TunnelId {
+        self.tid
+    }
+
+    pub fn gateway(&self) -> &Hash {
+        &self.gateway
+    }
+}
+
 pub enum DatabaseStoreData {
     RI(RouterInfo),
     LS(LeaseSet),
+    LS2(LeaseSet2),
+    EncryptedLS2(EncryptedLeaseSet2),
 }
 
 /// An unsolicited database store, or the response to a successful DatabaseLookup
@@ -176,6 +273,29 @@ impl DatabaseStore {
             data: DatabaseStoreData::LS(ls),
         }
     }
+
+    pub fn from_ls2(ls: LeaseSet2, reply: Option<ReplyPath>) -> Self {
+        DatabaseStore {
+            key: ls.dest.hash(),
+            ds_type: 3,
+            reply,
+            data: DatabaseStoreData::LS2(ls),
+        }
+    }
+
+    pub fn from_encrypted_ls2(els: EncryptedLeaseSet2, reply: Option<ReplyPath>) -> Self {
+        DatabaseStore {
+            key: els.store_key(),
+            ds_type: 5,
+            reply,
+            data: DatabaseStoreData::EncryptedLS2(els),
+        }
+    }
+
+    /// Returns where the sender wants the store acknowledged, if anywhere.
+    pub fn reply(&self) -> Option<&ReplyPath> {
+        self.reply.as_ref()
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -212,15 +332,64 @@ pub struct DatabaseLookup {
 }
 
 impl DatabaseLookup {
-    pub fn create_msg(key: Hash, from: Hash, lookup_type: DatabaseLookupType) -> Message {
-        Message::from_payload(MessagePayload::DatabaseLookup(DatabaseLookup {
+    /// Creates a lookup whose reply should be sent directly to the router `from`.
+    pub fn new(key: Hash, from: Hash, lookup_type: DatabaseLookupType) -> Self {
+        DatabaseLookup {
             key,
             from,
             lookup_type,
             reply_tid: None,
             excluded_peers: vec![],
             reply_enc: None,
-        }))
+        }
+    }
+
+    pub fn create_msg(
+        ids: &MessageIdGenerator,
+        key: Hash,
+        from: Hash,
+        lookup_type: DatabaseLookupType,
+    ) -> Message {
+        Message::database_lookup(ids, DatabaseLookup::new(key, from, lookup_type))
+    }
+
+    /// Requests that the reply be sent into the tunnel `tid`, in which case `from`
+    /// is the tunnel's gateway.
+    pub fn reply_tunnel(mut self, tid: TunnelId) -> Self {
+        self.reply_tid = Some(tid);
+        self
+    }
+
+    /// Sets the peers that should not be returned in a DatabaseSearchReply.
+    pub fn excluding(mut self, peers: Vec<Hash>) -> Self {
+        self.excluded_peers = peers;
+        self
+    }
+
+    pub fn key(&self) -> &Hash {
+        &self.key
+    }
+
+    /// The router to reply to, or the reply tunnel's gateway.
+    pub fn from(&self) -> &Hash {
+        &self.from
+    }
+
+    pub fn lookup_type(&self) -> DatabaseLookupType {
+        self.lookup_type
+    }
+
+    pub fn reply_tid(&self) -> Option<TunnelId> {
+        self.reply_tid
+    }
+
+    pub fn excluded_peers(&self) -> &[Hash] {
+        &self.excluded_peers
+    }
+
+    /// Returns true if the reply must be garlic-encrypted to the included key.
+    pub fn wants_encrypted_reply(&self) -> bool {
+        self.reply_enc.is_some()
     }
 }
 
@@ -280,6 +449,14 @@ pub struct DeliveryStatus {
     time_stamp: I2PDate,
 }
 
+impl DeliveryStatus {
+    /// The ID of the message being acknowledged, or the reply token of the
+    /// DatabaseStore being acknowledged.
+    pub fn msg_id(&self) -> u32 {
+        self.msg_id
+    }
+}
+
 #[cfg_attr(tarpaulin, skip)]
 impl fmt::Display for DeliveryStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -308,6 +485,28 @@ pub struct GarlicClove {
     cert: Certificate,
 }
 
+impl GarlicClove {
+    /// Creates a clove containing `msg`, to be delivered locally to the recipient
+    /// of the Garlic message. The clove expires along with `msg`.
+    pub fn local(ids: &MessageIdGenerator, msg: Message) -> Self {
+        GarlicClove {
+            delivery_instructions: GarlicCloveDeliveryInstructions {
+                encrypted: false,
+                delivery_type: 0,
+                delay_set: false,
+                session_key: None,
+                to_hash: None,
+                tid: None,
+                delay: None,
+            },
+            clove_id: ids.next_id(),
+            expiration: msg.expiration,
+            msg,
+            cert: Certificate::Null,
+        }
+    }
+}
+
 /// Used to wrap multiple encrypted I2NP messages.
 pub struct Garlic {
     cloves: Vec<GarlicClove>,
@@ -319,24 +518,120 @@ pub struct Garlic {
 /// A message sent from a tunnel's gateway or participant to the next participant
 /// or endpoint. The data is of fixed length, containing I2NP messages that are
 /// fragmented, batched, padded, and encrypted.
+///
+/// The data is kept as [`Bytes`], so that a message parsed from a transport frame
+/// shares the frame's buffer instead of copying it.
 pub struct TunnelData {
     pub tid: TunnelId,
-    pub data: [u8; 1024],
+    data: Bytes,
 }
 
 impl TunnelData {
-    fn from(tid: TunnelId, data: &[u8; 1024]) -> Self {
-        let mut x = [0u8; 1024];
-        x.copy_from_slice(data);
-        TunnelData { tid, data: x }
+    pub fn new(tid: TunnelId, data: [u8; 1024]) -> Self {
+        TunnelData {
+            tid,
+            data: Bytes::from(&data[..]),
+        }
+    }
+
+    /// Returns the encrypted data.
+    pub fn data(&self) -> &[u8; 1024] {
+        array_ref![self.data, 0, 1024]
+    }
+
+    /// Modifies the data in place. It is only copied if its buffer is shared.
+    pub fn update_data<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut [u8; 1024]),
+    {
+        let mut data = BytesMut::from(mem::replace(&mut self.data, Bytes::new()));
+        f(array_mut_ref![data, 0, 1024]);
+        self.data = data.freeze();
     }
 }
 
 /// Wraps another I2NP message to be sent into a tunnel at the tunnel's inbound
 /// gateway.
+///
+/// The wrapped message is kept as opaque [`Bytes`], so that forwarding it does not
+/// require copying.
 pub struct TunnelGateway {
     tid: TunnelId,
-    data: Vec<u8>,
+    data: Bytes,
+}
+
+impl TunnelGateway {
+    /// Returns the tunnel that the wrapped message should be sent into.
+    pub fn tid(&self) -> TunnelId {
+        self.tid
+    }
+
+    /// Returns the wrapped message, serialized with the standard I2NP header.
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+}
+
+/// The type of an I2NP message.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MessageType {
+    DatabaseStore,
+    DatabaseLookup,
+    DatabaseSearchReply,
+    DeliveryStatus,
+    Garlic,
+    TunnelData,
+    TunnelGateway,
+    Data,
+    TunnelBuild,
+    TunnelBuildReply,
+    VariableTunnelBuild,
+    VariableTunnelBuildReply,
+    ShortTunnelBuild,
+    OutboundTunnelBuildReply,
+}
+
+impl MessageType {
+    /// Returns the message type with the given wire code, if known.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(MessageType::DatabaseStore),
+            2 => Some(MessageType::DatabaseLookup),
+            3 => Some(MessageType::DatabaseSearchReply),
+            10 => Some(MessageType::DeliveryStatus),
+            11 => Some(MessageType::Garlic),
+            18 => Some(MessageType::TunnelData),
+            19 => Some(MessageType::TunnelGateway),
+            20 => Some(MessageType::Data),
+            21 => Some(MessageType::TunnelBuild),
+            22 => Some(MessageType::TunnelBuildReply),
+            23 => Some(MessageType::VariableTunnelBuild),
+            24 => Some(MessageType::VariableTunnelBuildReply),
+            25 => Some(MessageType::ShortTunnelBuild),
+            26 => Some(MessageType::OutboundTunnelBuildReply),
+            _ => None,
+        }
+    }
+
+    /// Returns the wire code for this message type.
+    pub fn code(self) -> u8 {
+        match self {
+            MessageType::DatabaseStore => 1,
+            MessageType::DatabaseLookup => 2,
+            MessageType::DatabaseSearchReply => 3,
+            MessageType::DeliveryStatus => 10,
+            MessageType::Garlic => 11,
+            MessageType::TunnelData => 18,
+            MessageType::TunnelGateway => 19,
+            MessageType::Data => 20,
+            MessageType::TunnelBuild => 21,
+            MessageType::TunnelBuildReply => 22,
+            MessageType::VariableTunnelBuild => 23,
+            MessageType::VariableTunnelBuildReply => 24,
+            MessageType::ShortTunnelBuild => 25,
+            MessageType::OutboundTunnelBuildReply => 26,
+        }
+    }
 }
 
 pub enum MessagePayload {
@@ -349,11 +644,122 @@ pub enum MessagePayload {
     TunnelGateway(TunnelGateway),
 
     /// Used by Garlic messages and Garlic Cloves to wrap arbitrary data.
-    Data(Vec<u8>),
+    Data(Bytes),
     TunnelBuild([[u8; 528]; 8]),
     TunnelBuildReply([[u8; 528]; 8]),
     VariableTunnelBuild(Vec<[u8; 528]>),
     VariableTunnelBuildReply(Vec<[u8; 528]>),
+    /// A build request with short records, which is also used for the reply of an
+    /// inbound tunnel.
+    ShortTunnelBuild(Vec<[u8; 218]>),
+    /// The reply to a build request with short records, sent by the outbound endpoint.
+    OutboundTunnelBuildReply(Vec<[u8; 218]>),
+}
+
+impl MessagePayload {
+    /// Returns the type of this payload.
+    pub fn message_type(&self) -> MessageType {
+        match *self {
+            MessagePayload::DatabaseStore(_) => MessageType::DatabaseStore,
+            MessagePayload::DatabaseLookup(_) => MessageType::DatabaseLookup,
+            MessagePayload::DatabaseSearchReply(_) => MessageType::DatabaseSearchReply,
+            MessagePayload::DeliveryStatus(_) => MessageType::DeliveryStatus,
+            MessagePayload::Garlic(_) => MessageType::Garlic,
+            MessagePayload::TunnelData(_) => MessageType::TunnelData,
+            MessagePayload::TunnelGateway(_) => MessageType::TunnelGateway,
+            MessagePayload::Data(_) => MessageType::Data,
+            MessagePayload::TunnelBuild(_) => MessageType::TunnelBuild,
+            MessagePayload::TunnelBuildReply(_) => MessageType::TunnelBuildReply,
+            MessagePayload::VariableTunnelBuild(_) => MessageType::VariableTunnelBuild,
+            MessagePayload::VariableTunnelBuildReply(_) => MessageType::VariableTunnelBuildReply,
+            MessagePayload::ShortTunnelBuild(_) => MessageType::ShortTunnelBuild,
+            MessagePayload::OutboundTunnelBuildReply(_) => MessageType::OutboundTunnelBuildReply,
+        }
+    }
+
+    /// Returns the serialized length of this payload.
+    pub fn byte_len(&self) -> usize {
+        match *self {
+            MessagePayload::DatabaseLookup(ref dl) => {
+                65 + dl.reply_tid.map_or(0, |_| 4)
+                    + 2
+                    + 32 * dl.excluded_peers.len()
+                    + dl
+                        .reply_enc
+                        .as_ref()
+                        .map_or(0, |(_, tags)| 33 + 32 * tags.len())
+            }
+            MessagePayload::DatabaseSearchReply(ref dsr) => 65 + 32 * dsr.peers.len(),
+            MessagePayload::DeliveryStatus(_) => 12,
+            MessagePayload::TunnelData(_) => 1028,
+            MessagePayload::TunnelGateway(ref tg) => 6 + tg.data.len(),
+            MessagePayload::Data(ref d) => 4 + d.len(),
+            MessagePayload::TunnelBuild(_) | MessagePayload::TunnelBuildReply(_) => 8 * 528,
+            MessagePayload::VariableTunnelBuild(ref tb)
+            | MessagePayload::VariableTunnelBuildReply(ref tb) => 1 + 528 * tb.len(),
+            MessagePayload::ShortTunnelBuild(ref tb)
+            | MessagePayload::OutboundTunnelBuildReply(ref tb) => 1 + 218 * tb.len(),
+            // These contain compressed or nested structures, so the simplest way to
+            // determine their length is to serialize them.
+            MessagePayload::DatabaseStore(_) | MessagePayload::Garlic(_) => {
+                serialize(|input| frame::gen_payload(input, self)).len()
+            }
+        }
+    }
+
+    /// Returns the body of this payload if it is carried as opaque bytes, along with
+    /// the length of the fields that precede it.
+    fn opaque_body(&self) -> Option<(usize, &Bytes)> {
+        match *self {
+            MessagePayload::TunnelData(ref td) => Some((4, &td.data)),
+            MessagePayload::TunnelGateway(ref tg) => Some((6, &tg.data)),
+            MessagePayload::Data(ref d) => Some((4, d)),
+            _ => None,
+        }
+    }
+
+    /// Serializes this payload, appending it to `buf`.
+    ///
+    /// The buffer is grown at most once. Opaque bodies are appended directly from
+    /// their buffers.
+    pub fn write_payload_to(&self, buf: &mut BytesMut) -> Result<(), GenError> {
+        self.write_payload_with_len(buf, self.byte_len())
+    }
+
+    fn write_payload_with_len(&self, buf: &mut BytesMut, len: usize) -> Result<(), GenError> {
+        match self.opaque_body() {
+            Some((header_len, body)) => {
+                buf.reserve(len);
+                write_exact(buf, header_len, |input| {
+                    frame::gen_payload_header(input, self)
+                })?;
+                buf.put(body);
+                Ok(())
+            }
+            None => write_exact(buf, len, |input| frame::gen_payload(input, self)),
+        }
+    }
+}
+
+/// Appends exactly `len` bytes to `buf` using the given serializer.
+fn write_exact<S>(buf: &mut BytesMut, len: usize, serializer: S) -> Result<(), GenError>
+where
+    S: Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError>,
+{
+    let start = buf.len();
+    buf.reserve(len);
+    buf.extend(iter::repeat(0).take(len));
+    match serializer((&mut buf[start..], 0)) {
+        Ok((_, sz)) if sz == len => Ok(()),
+        Ok(_) => {
+            buf.truncate(start);
+            Err(GenError::InvalidOffset)
+        }
+        Err(e) => {
+            buf.truncate(start);
+            Err(e)
+        }
+    }
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -388,6 +794,10 @@ impl fmt::Debug for MessagePayload {
             MessagePayload::VariableTunnelBuildReply(_) => {
                 "VariableTunnelBuildReply".fmt(formatter)
             }
+            MessagePayload::ShortTunnelBuild(_) => "ShortTunnelBuild".fmt(formatter),
+            MessagePayload::OutboundTunnelBuildReply(_) => {
+                "OutboundTunnelBuildReply".fmt(formatter)
+            }
         }
     }
 }
@@ -414,10 +824,43 @@ impl fmt::Display for MessagePayload {
             MessagePayload::VariableTunnelBuildReply(_) => {
                 "VariableTunnelBuildReply".fmt(formatter)
             }
+            MessagePayload::ShortTunnelBuild(_) => "ShortTunnelBuild".fmt(formatter),
+            MessagePayload::OutboundTunnelBuildReply(_) => {
+                "OutboundTunnelBuildReply".fmt(formatter)
+            }
         }
     }
 }
 
+/// Generates the IDs of the messages that we create.
+///
+/// Clones share the same source, so the router hands a single generator to each of
+/// its subsystems through its [`Context`](crate::router::Context).
+#[derive(Clone)]
+pub struct MessageIdGenerator {
+    rng: Arc<Mutex<dyn RngCore + Send>>,
+}
+
+impl MessageIdGenerator {
+    /// Returns a generator drawing IDs from `rng`.
+    pub fn new<R: CryptoRng + Send + 'static>(rng: R) -> Self {
+        MessageIdGenerator {
+            rng: Arc::new(Mutex::new(rng)),
+        }
+    }
+
+    /// Returns a fresh message ID.
+    pub fn next_id(&self) -> u32 {
+        self.rng.lock().unwrap().next_u32()
+    }
+}
+
+impl Default for MessageIdGenerator {
+    fn default() -> Self {
+        MessageIdGenerator::new(OsRng)
+    }
+}
+
 #[derive(Debug)]
 pub struct Message {
     pub(crate) id: u32,
@@ -443,9 +886,26 @@ impl PartialEq for Message {
 }
 
 impl Message {
-    pub fn from_payload(payload: MessagePayload) -> Self {
+    /// Creates a message with an ID from `ids`, expiring in one minute.
+    ///
+    /// Panics in debug builds if the payload exceeds the [size limit] for its type; in
+    /// release builds, the message will fail to serialize instead.
+    ///
+    /// [size limit]: limits::max_payload_size
+    pub fn from_payload(ids: &MessageIdGenerator, payload: MessagePayload) -> Self {
+        Self::with_id(ids.next_id(), payload)
+    }
+
+    /// As [`Message::from_payload`], but with the given ID. Tunnel build messages
+    /// are sent with the ID that the previous hop was told to expect.
+    fn with_id(id: u32, payload: MessagePayload) -> Self {
+        debug_assert!(
+            payload.byte_len() <= limits::max_payload_size(payload.message_type()),
+            "{:?} payload is too large",
+            payload.message_type()
+        );
         Message {
-            id: thread_rng().gen(),
+            id,
             expiration: I2PDate::from_system_time(
                 SystemTime::now() + Duration::from_millis(MESSAGE_EXPIRATION_MS),
             ),
@@ -453,20 +913,213 @@ impl Message {
         }
     }
 
+    /// Creates a Data message wrapping the given bytes.
+    pub fn data<B: Into<Bytes>>(ids: &MessageIdGenerator, data: B) -> Self {
+        Message::from_payload(ids, MessagePayload::Data(data.into()))
+    }
+
+    /// Creates a DatabaseStore message containing the given RouterInfo. The key is
+    /// derived from the RouterInfo's identity.
+    pub fn database_store(
+        ids: &MessageIdGenerator,
+        ri: RouterInfo,
+        reply: Option<ReplyPath>,
+    ) -> Self {
+        Message::from_payload(
+            ids,
+            MessagePayload::DatabaseStore(DatabaseStore::from_ri(ri, reply)),
+        )
+    }
+
+    /// Creates a DatabaseLookup message.
+    pub fn database_lookup(ids: &MessageIdGenerator, dl: DatabaseLookup) -> Self {
+        Message::from_payload(ids, MessagePayload::DatabaseLookup(dl))
+    }
+
+    /// Creates a DatabaseSearchReply message, suggesting `peers` to ask for `key`
+    /// instead.
+    pub fn database_search_reply(
+        ids: &MessageIdGenerator,
+        key: Hash,
+        peers: Vec<Hash>,
+        from: Hash,
+    ) -> Self {
+        Message::from_payload(
+            ids,
+            MessagePayload::DatabaseSearchReply(DatabaseSearchReply { key, peers, from }),
+        )
+    }
+
+    /// Creates a DeliveryStatus message acknowledging the message with the given ID.
+    pub fn delivery_status(ids: &MessageIdGenerator, msg_id: u32) -> Self {
+        Message::from_payload(
+            ids,
+            MessagePayload::DeliveryStatus(DeliveryStatus {
+                msg_id,
+                time_stamp: I2PDate::from_system_time(SystemTime::now()),
+            }),
+        )
+    }
+
+    /// Creates a Garlic message containing the given cloves. The Garlic itself
+    /// expires along with the message.
+    pub fn garlic(ids: &MessageIdGenerator, cloves: Vec<GarlicClove>) -> Self {
+        let mut msg = Message::from_payload(
+            ids,
+            MessagePayload::Garlic(Garlic {
+                cloves,
+                cert: Certificate::Null,
+                msg_id: ids.next_id(),
+                expiration: I2PDate::UNSET,
+            }),
+        );
+        msg.sync_garlic_expiration();
+        msg
+    }
+
+    /// Creates a TunnelData message for the given tunnel.
+    pub fn tunnel_data(ids: &MessageIdGenerator, tid: TunnelId, data: [u8; 1024]) -> Self {
+        Message::from_payload(ids, MessagePayload::TunnelData(TunnelData::new(tid, data)))
+    }
+
+    /// Creates a TunnelGateway message wrapping `inner`, to be sent into the given
+    /// tunnel.
+    pub fn tunnel_gateway(ids: &MessageIdGenerator, tid: TunnelId, inner: &Message) -> Self {
+        Message::from_payload(
+            ids,
+            MessagePayload::TunnelGateway(TunnelGateway {
+                tid,
+                data: serialize(|input| frame::gen_message(input, inner)).into(),
+            }),
+        )
+    }
+
+    /// Creates a TunnelBuild message with the ID `msg_id`.
+    pub fn tunnel_build(msg_id: u32, records: [[u8; 528]; 8]) -> Self {
+        Message::with_id(msg_id, MessagePayload::TunnelBuild(records))
+    }
+
+    /// Creates a TunnelBuildReply message with the ID `msg_id`.
+    pub fn tunnel_build_reply(msg_id: u32, records: [[u8; 528]; 8]) -> Self {
+        Message::with_id(msg_id, MessagePayload::TunnelBuildReply(records))
+    }
+
+    /// Creates a VariableTunnelBuild message with the ID `msg_id`.
+    pub fn variable_tunnel_build(msg_id: u32, records: Vec<[u8; 528]>) -> Self {
+        Message::with_id(msg_id, MessagePayload::VariableTunnelBuild(records))
+    }
+
+    /// Creates a VariableTunnelBuildReply message with the ID `msg_id`.
+    pub fn variable_tunnel_build_reply(msg_id: u32, records: Vec<[u8; 528]>) -> Self {
+        Message::with_id(msg_id, MessagePayload::VariableTunnelBuildReply(records))
+    }
+
+    /// Creates a ShortTunnelBuild message with the ID `msg_id`.
+    pub fn short_tunnel_build(msg_id: u32, records: Vec<[u8; 218]>) -> Self {
+        Message::with_id(msg_id, MessagePayload::ShortTunnelBuild(records))
+    }
+
+    /// Creates an OutboundTunnelBuildReply message with the ID `msg_id`.
+    pub fn outbound_tunnel_build_reply(msg_id: u32, records: Vec<[u8; 218]>) -> Self {
+        Message::with_id(msg_id, MessagePayload::OutboundTunnelBuildReply(records))
+    }
+
+    /// Sets the expiration of this message to `dur` from now.
+    pub fn expires_in(mut self, dur: Duration) -> Self {
+        self.expiration = I2PDate::from_system_time(SystemTime::now() + dur);
+        self.sync_garlic_expiration();
+        self
+    }
+
+    fn sync_garlic_expiration(&mut self) {
+        if let MessagePayload::Garlic(ref mut garlic) = self.payload {
+            garlic.expiration = self.expiration;
+        }
+    }
+
+    #[cfg(any(test, feature = "test-util"))]
     pub fn dummy_data() -> Self {
         Message {
             id: 0,
             expiration: I2PDate(0x123_4567_87c0),
-            payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into()),
         }
     }
 
+    /// Parses a message with the standard I2NP header, which must take up all of
+    /// `data`. Opaque payloads are copied out of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, msg) = frame::message(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(msg)
+    }
+
+    /// Parses a message with the standard I2NP header, which must take up all of
+    /// `buf`. Opaque payloads share `buf` instead of being copied out of it.
+    pub fn from_shared(buf: &Bytes) -> Result<Self, ReadError> {
+        let (rest, msg) = frame::message_bytes(buf, buf)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(msg)
+    }
+
+    /// Returns the type of this message.
+    pub fn message_type(&self) -> MessageType {
+        self.payload.message_type()
+    }
+
     pub fn size(&self) -> usize {
-        serialize(|input| frame::gen_message(input, self)).len()
+        16 + self.payload.byte_len()
     }
 
     pub fn ntcp2_size(&self) -> usize {
-        serialize(|input| frame::gen_ntcp2_message(input, self)).len()
+        9 + self.payload.byte_len()
+    }
+
+    /// Serializes this message with the standard I2NP header, appending it to `buf`.
+    ///
+    /// The buffer is grown at most once.
+    pub fn write_to(&self, buf: &mut BytesMut) -> Result<(), GenError> {
+        let len = self.checked_payload_len()?;
+        let start = buf.len();
+        buf.reserve(16 + len);
+        buf.extend_from_slice(&[0; 16]);
+        if let Err(e) = self.payload.write_payload_with_len(buf, len) {
+            buf.truncate(start);
+            return Err(e);
+        }
+
+        // The header covers the payload, so it is filled in last
+        let cs = frame::checksum(&buf[start + 16..]);
+        frame::gen_header((&mut buf[start..start + 16], 0), self, len as u16, cs).map(|_| ())
+    }
+
+    /// Serializes this message with the short NTCP2 I2NP header, appending it to `buf`.
+    ///
+    /// The buffer is grown at most once.
+    pub fn write_ntcp2_to(&self, buf: &mut BytesMut) -> Result<(), GenError> {
+        let len = self.checked_payload_len()?;
+        let start = buf.len();
+        buf.reserve(9 + len);
+        write_exact(buf, 9, |input| frame::gen_ntcp2_header(input, self))?;
+        self.payload.write_payload_with_len(buf, len).map_err(|e| {
+            buf.truncate(start);
+            e
+        })
+    }
+
+    /// Returns the serialized length of the payload, if it is within the limit for
+    /// its message type.
+    fn checked_payload_len(&self) -> Result<usize, GenError> {
+        let len = self.payload.byte_len();
+        if len > limits::max_payload_size(self.message_type()) {
+            Err(GenError::CustomError(2))
+        } else {
+            Ok(len)
+        }
     }
 }
 
@@ -476,6 +1129,17 @@ mod tests {
 
     use std::time::SystemTime;
 
+    use crate::crypto::{EncType, SigType};
+    use crate::data::{dest::DestinationSecretKeys, EncryptionKey, Lease, RouterSecretKeys};
+
+    fn round_trip(msg: &Message) -> Message {
+        let buf = serialize(|input| frame::gen_message(input, msg));
+        let (rest, parsed) = frame::message(&buf).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(&parsed, msg);
+        parsed
+    }
+
     #[test]
     fn build_request_record_encryption() {
         let brr = BuildRequestRecord::new(
@@ -498,11 +1162,7 @@ mod tests {
         ($size_func:ident, $header_size:expr) => {{
             assert_eq!(Message::dummy_data().$size_func(), $header_size + 4 + 10);
             assert_eq!(
-                Message::from_payload(MessagePayload::DeliveryStatus(DeliveryStatus {
-                    msg_id: 0,
-                    time_stamp: I2PDate::from_system_time(SystemTime::now())
-                }))
-                .$size_func(),
+                Message::delivery_status(&MessageIdGenerator::default(), 0).$size_func(),
                 $header_size + 12
             );
         }};
@@ -517,4 +1177,389 @@ mod tests {
     fn message_ntcp2_size() {
         check_size!(ntcp2_size, 9)
     }
+
+    #[test]
+    fn write_to() {
+        for &msg_type in fixtures::MESSAGE_TYPES.iter() {
+            let msg = fixtures::message(msg_type);
+
+            let mut buf = BytesMut::new();
+            msg.write_to(&mut buf).unwrap();
+            let legacy = serialize(|input| frame::gen_message(input, &msg));
+            assert_eq!(&buf[..], &legacy[..], "{:?}", msg_type);
+            assert_eq!(msg.size(), legacy.len(), "{:?}", msg_type);
+
+            let mut buf = BytesMut::new();
+            msg.write_ntcp2_to(&mut buf).unwrap();
+            let legacy = serialize(|input| frame::gen_ntcp2_message(input, &msg));
+            assert_eq!(&buf[..], &legacy[..], "{:?}", msg_type);
+            assert_eq!(msg.ntcp2_size(), legacy.len(), "{:?}", msg_type);
+
+            // Payloads are appended after existing data
+            let mut buf = BytesMut::from(&b"prefix"[..]);
+            msg.payload.write_payload_to(&mut buf).unwrap();
+            let legacy = serialize(|input| frame::gen_payload(input, &msg.payload));
+            assert_eq!(&buf[..6], b"prefix");
+            assert_eq!(&buf[6..], &legacy[..], "{:?}", msg_type);
+        }
+    }
+
+    #[test]
+    fn expires_in() {
+        let before = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(300));
+        let ids = MessageIdGenerator::default();
+        let msg = Message::data(&ids, vec![]).expires_in(Duration::from_secs(300));
+        let after = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(300));
+        assert!(before <= msg.expiration && msg.expiration <= after);
+    }
+
+    #[test]
+    fn seeded_message_ids() {
+        let ids = MessageIdGenerator::new(crypto::rand::TestRng::from_seed([0; 32]));
+        let a = Message::data(&ids, Bytes::new());
+        let b = Message::data(&ids.clone(), Bytes::new());
+        assert_eq!(a.id, 0xade0_b876);
+        assert_eq!(b.id, 0x903d_f1a0);
+    }
+
+    #[test]
+    fn data_round_trip() {
+        let msg = Message::data(&MessageIdGenerator::default(), vec![1, 2, 3, 4]);
+        match round_trip(&msg).payload {
+            MessagePayload::Data(data) => assert_eq!(&data[..], &[1, 2, 3, 4][..]),
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn database_store_round_trip() {
+        let keys = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(keys.rid.clone());
+        ri.sign(&keys.signing_private_key);
+
+        let msg = Message::database_store(
+            &MessageIdGenerator::default(),
+            ri.clone(),
+            Some(ReplyPath::new(7, TunnelId(8), Hash([9; 32]))),
+        );
+        match round_trip(&msg).payload {
+            MessagePayload::DatabaseStore(ds) => {
+                assert_eq!(ds.key, keys.rid.hash());
+                let reply = ds.reply.unwrap();
+                assert_eq!(reply.token, 7);
+                assert_eq!(reply.tid, TunnelId(8));
+                assert_eq!(reply.gateway, Hash([9; 32]));
+                match ds.data {
+                    DatabaseStoreData::RI(parsed) => assert_eq!(parsed, ri),
+                    _ => panic!("Unexpected LeaseSet"),
+                }
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn database_store_ls2_round_trip() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let key = dsk.dest.hash();
+        let published = I2PDate::from_short_expiry(1_524_874_654);
+        let mut ls = LeaseSet2::new(
+            dsk.dest,
+            published,
+            I2PDate(published.0 + 600_000),
+            vec![EncryptionKey::X25519([7; 32])],
+            vec![Lease::new(Hash([1; 32]), TunnelId(2), published)],
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+
+        let msg = Message::from_payload(
+            &MessageIdGenerator::default(),
+            MessagePayload::DatabaseStore(DatabaseStore::from_ls2(ls, None)),
+        );
+        match round_trip(&msg).payload {
+            MessagePayload::DatabaseStore(ds) => {
+                assert_eq!(ds.key, key);
+                match ds.data {
+                    DatabaseStoreData::LS2(parsed) => assert_eq!(parsed.verify(), Ok(())),
+                    _ => panic!("Unexpected DatabaseStore data"),
+                }
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn database_store_encrypted_ls2_round_trip() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let published = I2PDate::from_system_time(SystemTime::now());
+        let mut ls = LeaseSet2::new(
+            dsk.dest.clone(),
+            published,
+            I2PDate(published.0 + 600_000),
+            vec![EncryptionKey::X25519([7; 32])],
+            vec![Lease::new(Hash([1; 32]), TunnelId(2), published)],
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+        let els =
+            EncryptedLeaseSet2::encrypt(&ls, &dsk.signing_private_key, SystemTime::now()).unwrap();
+        let key = els.store_key();
+
+        let msg = Message::from_payload(
+            &MessageIdGenerator::default(),
+            MessagePayload::DatabaseStore(DatabaseStore::from_encrypted_ls2(els, None)),
+        );
+        let data = serialize(|input| frame::gen_message(input, &msg));
+        match frame::message(&data).unwrap().1.payload {
+            MessagePayload::DatabaseStore(ds) => {
+                assert_eq!(ds.key, key);
+                match ds.data {
+                    DatabaseStoreData::EncryptedLS2(parsed) => {
+                        assert_eq!(parsed.verify(), Ok(()));
+                        assert_eq!(parsed.decrypt(&dsk.dest).unwrap().verify(), Ok(()));
+                    }
+                    _ => panic!("Unexpected DatabaseStore data"),
+                }
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn delivery_status_round_trip() {
+        let msg = Message::delivery_status(&MessageIdGenerator::default(), 0x1234_5678);
+        let time_stamp = match msg.payload {
+            MessagePayload::DeliveryStatus(ref ds) => ds.time_stamp,
+            _ => unreachable!(),
+        };
+        match round_trip(&msg).payload {
+            MessagePayload::DeliveryStatus(ds) => {
+                assert_eq!(ds.msg_id, 0x1234_5678);
+                assert_eq!(ds.time_stamp, time_stamp);
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn tunnel_data_round_trip() {
+        let msg = Message::tunnel_data(&MessageIdGenerator::default(), TunnelId(42), [7; 1024]);
+        match round_trip(&msg).payload {
+            MessagePayload::TunnelData(td) => {
+                assert_eq!(td.tid, TunnelId(42));
+                assert_eq!(&td.data[..], &[7; 1024][..]);
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn from_shared() {
+        let ids = MessageIdGenerator::default();
+        let inner = Message::data(&ids, vec![5; 100]);
+        let msgs = vec![
+            Message::data(&ids, vec![6; 100]),
+            Message::tunnel_data(&ids, TunnelId(1), [7; 1024]),
+            Message::tunnel_gateway(&ids, TunnelId(2), &inner),
+            Message::garlic(&ids, vec![GarlicClove::local(&ids, inner.clone())]),
+        ];
+
+        let within = |buf: &Bytes, data: &[u8]| {
+            let (start, ptr) = (buf.as_ptr() as usize, data.as_ptr() as usize);
+            ptr >= start && ptr + data.len() <= start + buf.len()
+        };
+
+        for msg in msgs {
+            let mut buf = BytesMut::new();
+            msg.write_to(&mut buf).unwrap();
+            let buf = buf.freeze();
+
+            let copied = Message::from_bytes(&buf).unwrap();
+            let shared = Message::from_shared(&buf).unwrap();
+            assert_eq!(copied, msg);
+            assert_eq!(shared, msg);
+
+            let body = |msg: &Message| match msg.payload {
+                MessagePayload::Data(ref data) => data.clone(),
+                MessagePayload::TunnelData(ref td) => td.data.clone(),
+                MessagePayload::TunnelGateway(ref tg) => tg.data.clone(),
+                MessagePayload::Garlic(ref g) => match g.cloves[0].msg.payload {
+                    MessagePayload::Data(ref data) => data.clone(),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+            assert!(!within(&buf, &body(&copied)), "{:?}", msg.message_type());
+            assert!(within(&buf, &body(&shared)), "{:?}", msg.message_type());
+        }
+
+        // Trailing data is rejected
+        let mut buf = BytesMut::new();
+        inner.write_to(&mut buf).unwrap();
+        buf.extend_from_slice(&[0; 3]);
+        assert_eq!(
+            Message::from_shared(&buf.freeze()),
+            Err(ReadError::TrailingData(3))
+        );
+    }
+
+    #[test]
+    fn tunnel_data_update_data() {
+        let ids = MessageIdGenerator::default();
+
+        // Data that we own is modified in place
+        let mut td = TunnelData::new(TunnelId(1), [1; 1024]);
+        let before = td.data().as_ptr();
+        td.update_data(|data| data[0] = 2);
+        assert_eq!(td.data().as_ptr(), before);
+        assert_eq!(td.data()[0], 2);
+
+        // Data that shares a buffer is copied before being modified
+        let mut buf = BytesMut::new();
+        Message::tunnel_data(&ids, TunnelId(1), [1; 1024])
+            .write_to(&mut buf)
+            .unwrap();
+        let buf = buf.freeze();
+        let mut td = match Message::from_shared(&buf).unwrap().payload {
+            MessagePayload::TunnelData(td) => td,
+            _ => unreachable!(),
+        };
+        td.update_data(|data| data[0] = 2);
+        assert_eq!(td.data()[0], 2);
+        assert_eq!(&buf[20..], &[1; 1024][..]);
+    }
+
+    #[test]
+    fn tunnel_gateway_round_trip() {
+        let ids = MessageIdGenerator::default();
+        let inner = Message::data(&ids, vec![5, 6, 7]);
+        let msg = Message::tunnel_gateway(&ids, TunnelId(42), &inner);
+        match round_trip(&msg).payload {
+            MessagePayload::TunnelGateway(tg) => {
+                assert_eq!(tg.tid, TunnelId(42));
+                let (_, parsed) = frame::message(&tg.data).unwrap();
+                assert_eq!(parsed, inner);
+                match parsed.payload {
+                    MessagePayload::Data(data) => assert_eq!(&data[..], &[5, 6, 7][..]),
+                    p => panic!("Unexpected payload: {:?}", p),
+                }
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn database_lookup_round_trip() {
+        let dl = DatabaseLookup::new(Hash([1; 32]), Hash([2; 32]), DatabaseLookupType::LeaseSet)
+            .reply_tunnel(TunnelId(3))
+            .excluding(vec![Hash([4; 32]), Hash([5; 32])]);
+        let msg = Message::database_lookup(&MessageIdGenerator::default(), dl);
+        match round_trip(&msg).payload {
+            MessagePayload::DatabaseLookup(dl) => {
+                assert_eq!(dl.key(), &Hash([1; 32]));
+                assert_eq!(dl.from(), &Hash([2; 32]));
+                assert_eq!(dl.lookup_type(), DatabaseLookupType::LeaseSet);
+                assert_eq!(dl.reply_tid(), Some(TunnelId(3)));
+                assert_eq!(dl.excluded_peers(), &[Hash([4; 32]), Hash([5; 32])][..]);
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn database_search_reply_round_trip() {
+        let msg = Message::database_search_reply(
+            &MessageIdGenerator::default(),
+            Hash([1; 32]),
+            vec![Hash([2; 32]), Hash([3; 32])],
+            Hash([4; 32]),
+        );
+        match round_trip(&msg).payload {
+            MessagePayload::DatabaseSearchReply(dsr) => {
+                assert_eq!(dsr.key, Hash([1; 32]));
+                assert_eq!(dsr.peers, vec![Hash([2; 32]), Hash([3; 32])]);
+                assert_eq!(dsr.from, Hash([4; 32]));
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn garlic_round_trip() {
+        let ids = MessageIdGenerator::new(crypto::rand::TestRng::from_seed([1; 32]));
+        let inner = Message::data(&ids, vec![5, 6, 7]);
+        let clove = GarlicClove::local(&ids, inner);
+        let clove_id = clove.clove_id;
+        let msg = Message::garlic(&ids, vec![clove]).expires_in(Duration::from_secs(30));
+        match round_trip(&msg).payload {
+            MessagePayload::Garlic(garlic) => {
+                assert_eq!(garlic.expiration, msg.expiration);
+                assert_eq!(garlic.cloves.len(), 1);
+                let clove = &garlic.cloves[0];
+                assert_eq!(clove.delivery_instructions.delivery_type, 0);
+                assert_eq!(clove.clove_id, clove_id);
+                match clove.msg.payload {
+                    MessagePayload::Data(ref data) => assert_eq!(&data[..], &[5, 6, 7][..]),
+                    ref p => panic!("Unexpected payload: {:?}", p),
+                }
+            }
+            p => panic!("Unexpected payload: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn tunnel_build_round_trip() {
+        macro_rules! check_records {
+            ($msg:expr, $variant:ident, $records:expr) => {{
+                let msg = $msg;
+                assert_eq!(msg.id, 0x0102_0304);
+                match round_trip(&msg).payload {
+                    MessagePayload::$variant(records) => {
+                        assert_eq!(records.len(), $records.len());
+                        for (parsed, record) in records.iter().zip($records.iter()) {
+                            assert_eq!(&parsed[..], &record[..]);
+                        }
+                    }
+                    p => panic!("Unexpected payload: {:?}", p),
+                }
+            }};
+        }
+
+        let mut fixed = [[0; 528]; 8];
+        for (i, record) in fixed.iter_mut().enumerate() {
+            *record = [i as u8; 528];
+        }
+        let variable: Vec<_> = (0..4).map(|i| [i as u8; 528]).collect();
+        let short: Vec<_> = (0..3).map(|i| [i as u8; 218]).collect();
+
+        check_records!(
+            Message::tunnel_build(0x0102_0304, fixed),
+            TunnelBuild,
+            fixed
+        );
+        check_records!(
+            Message::tunnel_build_reply(0x0102_0304, fixed),
+            TunnelBuildReply,
+            fixed
+        );
+        check_records!(
+            Message::variable_tunnel_build(0x0102_0304, variable.clone()),
+            VariableTunnelBuild,
+            variable
+        );
+        check_records!(
+            Message::variable_tunnel_build_reply(0x0102_0304, variable.clone()),
+            VariableTunnelBuildReply,
+            variable
+        );
+        check_records!(
+            Message::short_tunnel_build(0x0102_0304, short.clone()),
+            ShortTunnelBuild,
+            short
+        );
+        check_records!(
+            Message::outbound_tunnel_build_reply(0x0102_0304, short.clone()),
+            OutboundTunnelBuildReply,
+            short
+        );
+    }
 }
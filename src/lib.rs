@@ -26,7 +26,6 @@ extern crate test;
 mod constants;
 pub mod crypto;
 pub mod data;
-mod file;
 pub mod i2np;
 pub mod netdb;
 pub mod router;
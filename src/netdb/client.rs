@@ -5,24 +5,34 @@ use futures::{
     Async, Future, Poll,
 };
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::spawn;
 
-use super::{errors::*, LocalNetworkDatabase};
-use crate::data::{Hash, LeaseSet, RouterInfo};
+use super::{errors::*, store::RouterInfoStore, LocalNetworkDatabase, NetDbStats, PeerCriteria};
+use crate::data::{Hash, LeaseSet, RouterCaps, RouterInfo};
+
+/// A predicate selecting RouterInfos from the netDb.
+pub type RouterFilter = Box<dyn Fn(&RouterInfo) -> bool + Send>;
 
 pub enum Query {
     KnownRouters(oneshot::Sender<usize>),
+    Stats(oneshot::Sender<NetDbStats>),
+    FilterRouters(RouterFilter, oneshot::Sender<Vec<RouterInfo>>),
     SelectClosestFloodfill(Hash, oneshot::Sender<Option<RouterInfo>>),
+    ClosestFloodfills(Hash, usize, Vec<Hash>, oneshot::Sender<Vec<RouterInfo>>),
+    SelectPeers(PeerCriteria, oneshot::Sender<Vec<RouterInfo>>),
     LookupRouterInfo(
         Hash,
         u64,
         Option<RouterInfo>,
+        bool,
         oneshot::Sender<Result<RouterInfo, LookupError>>,
     ),
     LookupLeaseSet(
         Hash,
         u64,
         Option<Hash>,
+        bool,
         oneshot::Sender<Result<LeaseSet, LookupError>>,
     ),
     StoreRouterInfo(
@@ -46,25 +56,48 @@ impl Query {
                     warn!("Completed known routers query, but client gave up");
                 }
             }
+            Query::Stats(ret) => {
+                if ret.send(netdb.stats(Instant::now())).is_err() {
+                    warn!("Completed stats query, but client gave up");
+                }
+            }
+            Query::FilterRouters(pred, ret) => {
+                if ret.send(netdb.filter_routers(pred)).is_err() {
+                    warn!("Completed router query, but client gave up");
+                }
+            }
             Query::SelectClosestFloodfill(key, ret) => {
                 if ret.send(netdb.select_closest_ff(&key)).is_err() {
                     warn!("Completed floodfill selection, but client gave up");
                 }
             }
-            Query::LookupRouterInfo(key, timeout_ms, from_peer, ret) => {
+            Query::ClosestFloodfills(key, count, exclude, ret) => {
+                if ret
+                    .send(netdb.closest_floodfills(&key, count, &exclude))
+                    .is_err()
+                {
+                    warn!("Completed floodfill selection, but client gave up");
+                }
+            }
+            Query::SelectPeers(criteria, ret) => {
+                if ret.send(netdb.select_peers(&criteria)).is_err() {
+                    warn!("Completed peer selection, but client gave up");
+                }
+            }
+            Query::LookupRouterInfo(key, timeout_ms, from_peer, force, ret) => {
                 spawn(
                     netdb
-                        .lookup_router_info(&key, timeout_ms, from_peer)
+                        .lookup_router_info(&key, timeout_ms, from_peer, force)
                         .then(|res| ret.send(res))
                         .map_err(move |_| {
                             warn!("Completed RouterInfo lookup on {}, but client gave up", key)
                         }),
                 );
             }
-            Query::LookupLeaseSet(key, timeout_ms, from_local_dest, ret) => {
+            Query::LookupLeaseSet(key, timeout_ms, from_local_dest, force, ret) => {
                 spawn(
                     netdb
-                        .lookup_lease_set(&key, timeout_ms, from_local_dest)
+                        .lookup_lease_set(&key, timeout_ms, from_local_dest, force)
                         .then(|res| ret.send(res))
                         .map_err(move |_| {
                             warn!("Completed LeaseSet lookup on {}, but client gave up", key)
@@ -121,6 +154,74 @@ impl Future for KnownRouters {
     }
 }
 
+pub struct Stats {
+    client: Client,
+    response_rx: Option<oneshot::Receiver<NetDbStats>>,
+}
+
+impl Stats {
+    fn new(client: Client) -> Self {
+        Stats {
+            client,
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for Stats {
+    type Item = NetDbStats;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.response_rx.is_none() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client.send(Query::Stats(response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
+pub struct FilterRouters {
+    client: Client,
+    query: Option<RouterFilter>,
+    response_rx: Option<oneshot::Receiver<Vec<RouterInfo>>>,
+}
+
+impl FilterRouters {
+    fn new(client: Client, pred: RouterFilter) -> Self {
+        FilterRouters {
+            client,
+            query: Some(pred),
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for FilterRouters {
+    type Item = Vec<RouterInfo>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(pred) = self.query.take() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client.send(Query::FilterRouters(pred, response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
 pub struct SelectClosestFloodfill {
     client: Client,
     query: Option<Hash>,
@@ -157,17 +258,95 @@ impl Future for SelectClosestFloodfill {
     }
 }
 
+pub struct ClosestFloodfills {
+    client: Client,
+    query: Option<(Hash, usize, Vec<Hash>)>,
+    response_rx: Option<oneshot::Receiver<Vec<RouterInfo>>>,
+}
+
+impl ClosestFloodfills {
+    fn new(client: Client, key: Hash, count: usize, exclude: Vec<Hash>) -> Self {
+        ClosestFloodfills {
+            client,
+            query: Some((key, count, exclude)),
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for ClosestFloodfills {
+    type Item = Vec<RouterInfo>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some((key, count, exclude)) = self.query.take() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client
+                .send(Query::ClosestFloodfills(key, count, exclude, response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
+pub struct SelectPeers {
+    client: Client,
+    query: Option<PeerCriteria>,
+    response_rx: Option<oneshot::Receiver<Vec<RouterInfo>>>,
+}
+
+impl SelectPeers {
+    fn new(client: Client, criteria: PeerCriteria) -> Self {
+        SelectPeers {
+            client,
+            query: Some(criteria),
+            response_rx: None,
+        }
+    }
+}
+
+impl Future for SelectPeers {
+    type Item = Vec<RouterInfo>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(criteria) = self.query.take() {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.response_rx = Some(response_rx);
+            self.client
+                .send(Query::SelectPeers(criteria, response_tx))?;
+        }
+
+        self.response_rx
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|_| Error::Closed)
+    }
+}
+
 pub struct LookupRouterInfo {
     client: Client,
-    query: Option<(Hash, u64, Option<RouterInfo>)>,
+    query: Option<(Hash, u64, Option<RouterInfo>, bool)>,
     response_rx: Option<oneshot::Receiver<Result<RouterInfo, LookupError>>>,
 }
 
 impl LookupRouterInfo {
-    fn new(client: Client, key: Hash, timeout_ms: u64, from_peer: Option<RouterInfo>) -> Self {
+    fn new(
+        client: Client,
+        key: Hash,
+        timeout_ms: u64,
+        from_peer: Option<RouterInfo>,
+        force: bool,
+    ) -> Self {
         LookupRouterInfo {
             client,
-            query: Some((key, timeout_ms, from_peer)),
+            query: Some((key, timeout_ms, from_peer, force)),
             response_rx: None,
         }
     }
@@ -185,6 +364,7 @@ impl Future for LookupRouterInfo {
                 query.0,
                 query.1,
                 query.2,
+                query.3,
                 response_tx,
             ))?;
         }
@@ -200,15 +380,21 @@ impl Future for LookupRouterInfo {
 
 pub struct LookupLeaseSet {
     client: Client,
-    query: Option<(Hash, u64, Option<Hash>)>,
+    query: Option<(Hash, u64, Option<Hash>, bool)>,
     response_rx: Option<oneshot::Receiver<Result<LeaseSet, LookupError>>>,
 }
 
 impl LookupLeaseSet {
-    fn new(client: Client, key: Hash, timeout_ms: u64, from_local_dest: Option<Hash>) -> Self {
+    fn new(
+        client: Client,
+        key: Hash,
+        timeout_ms: u64,
+        from_local_dest: Option<Hash>,
+        force: bool,
+    ) -> Self {
         LookupLeaseSet {
             client,
-            query: Some((key, timeout_ms, from_local_dest)),
+            query: Some((key, timeout_ms, from_local_dest, force)),
             response_rx: None,
         }
     }
@@ -226,6 +412,7 @@ impl Future for LookupLeaseSet {
                 query.0,
                 query.1,
                 query.2,
+                query.3,
                 response_tx,
             ))?;
         }
@@ -315,15 +502,38 @@ impl Future for StoreLeaseSet {
 
 /// A client for interacting with I2P's network database.
 #[derive(Clone)]
-pub struct Client(Arc<mpsc::UnboundedSender<Query>>);
+pub struct Client {
+    client_tx: Arc<mpsc::UnboundedSender<Query>>,
+    store: Arc<RouterInfoStore>,
+}
 
 impl Client {
     pub fn new(client_tx: mpsc::UnboundedSender<Query>) -> Self {
-        Client(Arc::new(client_tx))
+        Client {
+            client_tx: Arc::new(client_tx),
+            store: Arc::new(RouterInfoStore::default()),
+        }
     }
 
     fn send(&self, query: Query) -> Result<(), Error> {
-        self.0.unbounded_send(query).map_err(|_| Error::Closed)
+        self.client_tx
+            .unbounded_send(query)
+            .map_err(|_| Error::Closed)
+    }
+
+    /// Returns the RouterInfo store that this client reads from, for the
+    /// database to write to.
+    pub(super) fn store(&self) -> Arc<RouterInfoStore> {
+        self.store.clone()
+    }
+
+    /// Returns the RouterInfo stored locally at the given key, without waiting
+    /// for the database to handle any other queries. No remote lookup is
+    /// performed.
+    ///
+    /// This may return a RouterInfo that is about to be expired.
+    pub fn local_router_info(&self, key: &Hash) -> Option<RouterInfo> {
+        self.store.get(key).map(|ri| (*ri).clone())
     }
 
     /// Returns the number of RouterInfos that this database contains.
@@ -331,32 +541,91 @@ impl Client {
         KnownRouters::new(self.clone())
     }
 
+    /// Returns a summary of what this database contains.
+    pub fn stats(&self) -> Stats {
+        Stats::new(self.clone())
+    }
+
+    /// Returns a snapshot of every RouterInfo in this database.
+    ///
+    /// The snapshot is a copy, so the database keeps handling stores and
+    /// lookups while the caller works through it.
+    pub fn router_infos(&self) -> FilterRouters {
+        FilterRouters::new(self.clone(), Box::new(|_: &RouterInfo| true))
+    }
+
+    /// Returns a snapshot of the RouterInfos advertising every capability in
+    /// `caps`.
+    pub fn routers_by_caps(&self, caps: RouterCaps) -> FilterRouters {
+        FilterRouters::new(
+            self.clone(),
+            Box::new(move |ri: &RouterInfo| ri.caps().map(|c| c.contains(&caps)).unwrap_or(false)),
+        )
+    }
+
+    /// Returns a snapshot of the RouterInfos whose published router version
+    /// matches `pred`.
+    pub fn routers_by_version<F>(&self, pred: F) -> FilterRouters
+    where
+        F: Fn(&str) -> bool + Send + 'static,
+    {
+        FilterRouters::new(
+            self.clone(),
+            Box::new(move |ri: &RouterInfo| ri.router_version().map(&pred).unwrap_or(false)),
+        )
+    }
+
     /// Returns the closest floodfill router to the given netDb key.
     pub fn select_closest_ff(&self, key: Hash) -> SelectClosestFloodfill {
         SelectClosestFloodfill::new(self.clone(), key)
     }
 
+    /// Returns up to `count` floodfill routers closest to the given netDb key,
+    /// closest first, skipping any in `exclude`.
+    pub fn closest_floodfills(
+        &self,
+        key: Hash,
+        count: usize,
+        exclude: Vec<Hash>,
+    ) -> ClosestFloodfills {
+        ClosestFloodfills::new(self.clone(), key, count, exclude)
+    }
+
+    /// Returns peers chosen at random that meet `criteria`, for building tunnels
+    /// through or sending messages to.
+    pub fn select_peers(&self, criteria: PeerCriteria) -> SelectPeers {
+        SelectPeers::new(self.clone(), criteria)
+    }
+
     /// Finds the RouterInfo stored at the given key. A remote lookup will be performed if
     /// the key is not found locally.
+    ///
+    /// If a lookup for the key failed recently, this fails immediately with
+    /// [`LookupError::RecentlyFailed`], unless `force` is set.
     pub fn lookup_router_info(
         &self,
         key: Hash,
         timeout_ms: u64,
         from_peer: Option<RouterInfo>,
+        force: bool,
     ) -> LookupRouterInfo {
-        LookupRouterInfo::new(self.clone(), key, timeout_ms, from_peer)
+        LookupRouterInfo::new(self.clone(), key, timeout_ms, from_peer, force)
     }
 
     /// Finds the LeaseSet stored at the given key. If not known locally, the LeaseSet is
     /// looked up using the client tunnels for `from_local_dest` if provided, or
     /// exploratory tunnels otherwise.
+    ///
+    /// If a lookup for the key failed recently, this fails immediately with
+    /// [`LookupError::RecentlyFailed`], unless `force` is set.
     pub fn lookup_lease_set(
         &self,
         key: Hash,
         timeout_ms: u64,
         from_local_dest: Option<Hash>,
+        force: bool,
     ) -> LookupLeaseSet {
-        LookupLeaseSet::new(self.clone(), key, timeout_ms, from_local_dest)
+        LookupLeaseSet::new(self.clone(), key, timeout_ms, from_local_dest, force)
     }
 
     /// Stores a RouterInfo locally.
@@ -378,3 +647,111 @@ impl Client {
         StoreLeaseSet::new(self.clone(), key, ls)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use tokio::runtime::Runtime;
+
+    use crate::data::{RouterCaps, RouterInfoBuilder, RouterSecretKeys};
+    use crate::netdb::mock::loopback_engine;
+    use crate::router::mock::LoopbackPeers;
+
+    #[test]
+    fn concurrent_stats_and_snapshots() {
+        let mut rt = Runtime::new().unwrap();
+        let (engine, client, _) = loopback_engine(&LoopbackPeers::default(), false);
+        rt.spawn(engine);
+
+        // Keep reading while the database is being filled
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let client = client.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut last = 0;
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) || reads == 0 {
+                    let snapshot = client.router_infos().wait().unwrap();
+                    let stats = client.stats().wait().unwrap();
+                    assert!(snapshot.len() >= last);
+                    assert!(stats.routers >= snapshot.len());
+                    assert!(stats.floodfills <= stats.routers);
+                    assert_eq!(stats.added_last_hour, stats.routers as u64);
+                    last = snapshot.len();
+                    reads += 1;
+                }
+            })
+        };
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let mut stored = vec![];
+                    for i in 0..25 {
+                        let rsk = RouterSecretKeys::new();
+                        let ri = RouterInfoBuilder::new(rsk.rid)
+                            .caps(RouterCaps::default().floodfill(i % 2 == 0))
+                            .sign(&rsk.signing_private_key);
+                        let replaced = client
+                            .store_router_info(ri.router_id.hash(), ri.clone(), false)
+                            .wait()
+                            .unwrap();
+                        assert!(replaced.is_none());
+
+                        // Reads don't wait for the engine
+                        assert_eq!(
+                            client.local_router_info(&ri.router_id.hash()),
+                            Some(ri.clone())
+                        );
+                        stored.push(ri);
+                    }
+                    stored
+                })
+            })
+            .collect();
+        let stored: Vec<_> = writers
+            .into_iter()
+            .flat_map(|writer| writer.join().unwrap())
+            .collect();
+        done.store(true, Ordering::SeqCst);
+        reader.join().unwrap();
+
+        let stats = client.stats().wait().unwrap();
+        assert_eq!(stats.routers, 100);
+        assert_eq!(stats.floodfills, 52);
+        assert_eq!(stats.lease_sets, 0);
+        assert_eq!(stats.added_last_hour, 100);
+        assert_eq!(stats.evicted_last_hour, 0);
+        assert_eq!(client.router_infos().wait().unwrap().len(), 100);
+        for ri in &stored {
+            assert_eq!(
+                client.local_router_info(&ri.router_id.hash()).as_ref(),
+                Some(ri)
+            );
+        }
+
+        let floodfills = client.routers_by_caps("f".parse().unwrap()).wait().unwrap();
+        assert_eq!(floodfills.len(), 52);
+        assert!(floodfills.iter().all(|ri| ri.is_floodfill()));
+        assert_eq!(
+            client
+                .routers_by_version(|v| v == "0.9.37")
+                .wait()
+                .unwrap()
+                .len(),
+            100
+        );
+        assert!(client
+            .routers_by_version(|v| v.starts_with("0.8"))
+            .wait()
+            .unwrap()
+            .is_empty());
+
+        rt.shutdown_now().wait().unwrap();
+    }
+}
@@ -4,6 +4,7 @@ use std::fmt;
 use std::time::Duration;
 
 use crate::crypto;
+use crate::data::VerifyError;
 
 pub enum Error {
     Lookup(LookupError),
@@ -42,6 +43,8 @@ pub enum LookupError {
     SendFailure,
     TimedOut,
     TimerFailure,
+    /// A lookup for this key failed recently, so we didn't try again.
+    RecentlyFailed,
 }
 
 #[cfg_attr(tarpaulin, skip)]
@@ -53,6 +56,7 @@ impl fmt::Display for LookupError {
             LookupError::SendFailure => "Send failure".fmt(f),
             LookupError::TimedOut => "Lookup timed out".fmt(f),
             LookupError::TimerFailure => "Timer failure".fmt(f),
+            LookupError::RecentlyFailed => "Lookup failed recently".fmt(f),
         }
     }
 }
@@ -60,9 +64,18 @@ impl fmt::Display for LookupError {
 /// Network database store errors
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum StoreError {
+    /// The router is on our ban list.
+    Banned,
     Crypto(crypto::Error),
     Expired(Duration),
     InvalidKey,
+    /// Every Lease in the LeaseSet has expired.
+    LeaseSetExpired,
+    /// The router publishes no reachable address, but doesn't say that it is
+    /// hidden or unreachable.
+    NoAddresses,
+    /// We already hold a LeaseSet at this key that is at least as new.
+    NotNewer,
     PublishedInFuture,
     WrongNetwork,
 }
@@ -73,17 +86,58 @@ impl From<crypto::Error> for StoreError {
     }
 }
 
+impl From<VerifyError> for StoreError {
+    fn from(e: VerifyError) -> Self {
+        StoreError::Crypto(match e {
+            VerifyError::Unsigned => crypto::Error::NoSignature,
+            VerifyError::InvalidSignature => crypto::Error::InvalidSignature,
+            VerifyError::Crypto(e) => e,
+        })
+    }
+}
+
 #[cfg_attr(tarpaulin, skip)]
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            StoreError::Banned => "Router is banned".fmt(f),
             StoreError::Crypto(e) => e.fmt(f),
             StoreError::Expired(age) => {
                 format!("Too old (published {} seconds ago)", age.as_secs()).fmt(f)
             }
-            StoreError::InvalidKey => "Key does not match RouterInfo's RouterIdentity".fmt(f),
+            StoreError::InvalidKey => "Key does not match the entry's hash".fmt(f),
+            StoreError::LeaseSetExpired => "LeaseSet has expired".fmt(f),
+            StoreError::NoAddresses => "No usable addresses".fmt(f),
+            StoreError::NotNewer => "Not newer than the entry we hold".fmt(f),
             StoreError::PublishedInFuture => "Published in future".fmt(f),
             StoreError::WrongNetwork => "Not in our network".fmt(f),
         }
     }
 }
+
+/// Counts of the RouterInfos we have refused to store, by reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RejectionStats {
+    pub banned: u64,
+    pub bad_signature: u64,
+    pub expired: u64,
+    pub invalid_key: u64,
+    pub no_addresses: u64,
+    pub published_in_future: u64,
+    pub wrong_network: u64,
+}
+
+impl RejectionStats {
+    pub(super) fn record(&mut self, e: StoreError) {
+        match e {
+            StoreError::Banned => self.banned += 1,
+            StoreError::Crypto(_) => self.bad_signature += 1,
+            StoreError::Expired(_) => self.expired += 1,
+            StoreError::InvalidKey => self.invalid_key += 1,
+            StoreError::NoAddresses => self.no_addresses += 1,
+            StoreError::PublishedInFuture => self.published_in_future += 1,
+            StoreError::WrongNetwork => self.wrong_network += 1,
+            StoreError::LeaseSetExpired | StoreError::NotNewer => (),
+        }
+    }
+}
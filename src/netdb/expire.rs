@@ -0,0 +1,219 @@
+//! Deciding when netDb entries are too old to use.
+//!
+//! RouterInfos are republished regularly, so an old one most likely belongs to
+//! a router that has left the network. How old is too old depends on how many
+//! routers we know: with few, we keep entries for as long as they can be valid,
+//! and as the netDb grows we drop them sooner. Routers that can only be reached
+//! through introducers (or not at all) change their addresses often, so their
+//! RouterInfos are dropped sooner still.
+
+use std::time::{Duration, SystemTime};
+
+use crate::data::RouterInfo;
+use crate::router::config::{self, Config};
+
+/// Maximum age of a RouterInfo when the netDb is small, in seconds.
+const MAX_AGE: u64 = super::ROUTER_INFO_EXPIRATION;
+/// Age below which RouterInfos are never expired, however large the netDb, in
+/// seconds.
+const MIN_AGE: u64 = 90 * 60;
+/// Maximum age of a RouterInfo without a directly reachable address, in
+/// seconds.
+const UNREACHABLE_AGE: u64 = 60 * 60;
+/// Up to this many routers, RouterInfos are kept for the maximum age. Beyond
+/// it, the age at which they expire falls towards the minimum.
+const SCALE_ROUTERS: usize = 1000;
+
+/// Why an entry was expired.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum ExpiryReason {
+    /// The RouterInfo is older than the maximum age for the netDb's size.
+    TooOld,
+    /// The RouterInfo has no reachable address, and is older than the maximum
+    /// age for such routers.
+    Unreachable,
+}
+
+/// Counts of the entries we have expired, by reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvictionStats {
+    pub router_infos_too_old: u64,
+    pub router_infos_unreachable: u64,
+    pub lease_sets: u64,
+}
+
+impl EvictionStats {
+    pub(super) fn record(&mut self, reason: ExpiryReason) {
+        match reason {
+            ExpiryReason::TooOld => self.router_infos_too_old += 1,
+            ExpiryReason::Unreachable => self.router_infos_unreachable += 1,
+        }
+    }
+}
+
+/// The ages at which RouterInfos expire.
+pub(super) struct Expiration {
+    min_age: Duration,
+    max_age: Duration,
+    unreachable_age: Duration,
+}
+
+impl Default for Expiration {
+    fn default() -> Self {
+        Expiration {
+            min_age: Duration::from_secs(MIN_AGE),
+            max_age: Duration::from_secs(MAX_AGE),
+            unreachable_age: Duration::from_secs(UNREACHABLE_AGE),
+        }
+    }
+}
+
+impl Expiration {
+    pub(super) fn from_config(config: &Config) -> Self {
+        let get = |key: &str, default: u64| {
+            Duration::from_secs(
+                config
+                    .get_int(key)
+                    .ok()
+                    .map(|v| v as u64)
+                    .unwrap_or(default),
+            )
+        };
+        Expiration {
+            min_age: get(config::NETDB_EXPIRE_MIN_AGE, MIN_AGE),
+            max_age: get(config::NETDB_EXPIRE_MAX_AGE, MAX_AGE),
+            unreachable_age: get(config::NETDB_EXPIRE_UNREACHABLE_AGE, UNREACHABLE_AGE),
+        }
+    }
+
+    /// Returns the maximum age of a RouterInfo when we know `known_routers`.
+    pub(super) fn max_age(&self, known_routers: usize) -> Duration {
+        if known_routers <= SCALE_ROUTERS || self.max_age <= self.min_age {
+            return self.max_age;
+        }
+        let range = (self.max_age - self.min_age).as_secs();
+        let scaled = range * SCALE_ROUTERS as u64 / known_routers as u64;
+        self.min_age + Duration::from_secs(scaled)
+    }
+
+    /// Returns why `ri` should be expired at `now` when we know `known_routers`,
+    /// or `None` if it is still current.
+    pub(super) fn check(
+        &self,
+        ri: &RouterInfo,
+        known_routers: usize,
+        now: SystemTime,
+    ) -> Option<ExpiryReason> {
+        let age = now
+            .duration_since(ri.published.to_system_time())
+            .unwrap_or_default();
+        let max_age = self.max_age(known_routers);
+        if age > max_age {
+            Some(ExpiryReason::TooOld)
+        } else if age > self.unreachable_age.min(max_age) && !ri.is_reachable() {
+            Some(ExpiryReason::Unreachable)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{Expiration, ExpiryReason, MAX_AGE, MIN_AGE, SCALE_ROUTERS, UNREACHABLE_AGE};
+    use crate::data::{I2PDate, RouterCaps, RouterInfo};
+    use crate::router::{
+        config::{self, Config},
+        mock::{mock_reachable_router_info, mock_router_info},
+    };
+
+    fn router_info(age: u64, reachable: bool) -> RouterInfo {
+        let mut ri = if reachable {
+            mock_reachable_router_info(RouterCaps::default(), "127.0.0.1")
+        } else {
+            mock_router_info(RouterCaps::default())
+        };
+        ri.published = I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(age));
+        ri
+    }
+
+    #[test]
+    fn adaptive_max_age() {
+        let expiration = Expiration::default();
+        let max = Duration::from_secs(MAX_AGE);
+        let min = Duration::from_secs(MIN_AGE);
+
+        // Small netDbs keep entries for the maximum age
+        assert_eq!(expiration.max_age(0), max);
+        assert_eq!(expiration.max_age(SCALE_ROUTERS), max);
+
+        // Larger ones expire them sooner, but never below the minimum
+        assert_eq!(expiration.max_age(2 * SCALE_ROUTERS), min + (max - min) / 2);
+        assert!(expiration.max_age(10 * SCALE_ROUTERS) < expiration.max_age(2 * SCALE_ROUTERS));
+        assert!(expiration.max_age(1_000_000) >= min);
+    }
+
+    #[test]
+    fn check_ages() {
+        let expiration = Expiration::default();
+        let now = SystemTime::now();
+        let small = 100;
+        let large = 20 * SCALE_ROUTERS;
+        let hours = |h: u64| h * 60 * 60;
+
+        let fresh = router_info(60, true);
+        assert_eq!(expiration.check(&fresh, small, now), None);
+        assert_eq!(expiration.check(&fresh, large, now), None);
+
+        // A few hours old is fine in a small netDb, but not in a large one
+        let older = router_info(hours(5), true);
+        assert_eq!(expiration.check(&older, small, now), None);
+        assert_eq!(
+            expiration.check(&older, large, now),
+            Some(ExpiryReason::TooOld)
+        );
+
+        // Nothing is kept past the maximum age
+        let oldest = router_info(MAX_AGE + 60, true);
+        assert_eq!(
+            expiration.check(&oldest, small, now),
+            Some(ExpiryReason::TooOld)
+        );
+
+        // Unreachable routers expire sooner, whatever the netDb size
+        let unreachable = router_info(UNREACHABLE_AGE + 60, false);
+        assert_eq!(
+            expiration.check(&unreachable, small, now),
+            Some(ExpiryReason::Unreachable)
+        );
+        let reachable = router_info(UNREACHABLE_AGE + 60, true);
+        assert_eq!(expiration.check(&reachable, small, now), None);
+    }
+
+    #[test]
+    fn from_config() {
+        let mut cfg = Config::default();
+        cfg.set(config::NETDB_EXPIRE_MIN_AGE, 60).unwrap();
+        cfg.set(config::NETDB_EXPIRE_MAX_AGE, 600).unwrap();
+        cfg.set(config::NETDB_EXPIRE_UNREACHABLE_AGE, 120).unwrap();
+        let expiration = Expiration::from_config(&cfg);
+
+        assert_eq!(expiration.max_age(SCALE_ROUTERS), Duration::from_secs(600));
+        assert_eq!(
+            expiration.max_age(2 * SCALE_ROUTERS),
+            Duration::from_secs(330)
+        );
+
+        let now = SystemTime::now();
+        assert_eq!(
+            expiration.check(&router_info(180, false), SCALE_ROUTERS, now),
+            Some(ExpiryReason::Unreachable)
+        );
+        assert_eq!(
+            expiration.check(&router_info(180, true), SCALE_ROUTERS, now),
+            None
+        );
+    }
+}
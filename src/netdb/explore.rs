@@ -0,0 +1,309 @@
+//! Exploring the network for routers we don't know about.
+//!
+//! On each round we pick a random key, and send exploratory lookups for it to
+//! the floodfills closest to it. They reply with routers that are not
+//! floodfills, which we then fetch if they are new to us. Rounds are frequent
+//! while the netDb is small, slow once it is healthy, and skipped entirely once
+//! it is large.
+
+use futures::{sync::oneshot, Async, Future};
+use rand::{thread_rng, Rng};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::executor::DefaultExecutor;
+
+use super::{errors::LookupError, lookup, LocalNetworkDatabase, PendingTx};
+use crate::data::{Hash, RouterInfo};
+use crate::router::{
+    config::{self, Config},
+    Context,
+};
+
+/// Explore this often while we know fewer than the minimum number of routers,
+/// in seconds.
+const EXPLORE_MIN_INTERVAL: u64 = 30;
+/// Explore this often otherwise, in seconds.
+const EXPLORE_MAX_INTERVAL: u64 = 15 * 60;
+/// Explore quickly if we have fewer than this many routers.
+const EXPLORE_MIN_ROUTERS: usize = 250;
+/// Explore quickly if we are a floodfill and have fewer than this many routers.
+const FLOODFILL_EXPLORE_MIN_ROUTERS: usize = 1000;
+/// Don't explore if we have at least this many routers.
+const EXPLORE_MAX_ROUTERS: usize = 4000;
+/// How many floodfills we send exploratory lookups to each round.
+const EXPLORE_FLOODFILLS: usize = 2;
+/// How many of the routers we already know we ask floodfills not to return.
+const EXPLORE_MAX_EXCLUDED: usize = 16;
+/// Maximum number of exploratory lookups waiting for a reply.
+const MAX_ACTIVE_EXPLORATIONS: usize = 4;
+/// Maximum number of RouterInfos being fetched at once.
+const MAX_ACTIVE_FETCHES: usize = 8;
+/// How long to spend fetching each new RouterInfo, in milliseconds.
+const FETCH_TIMEOUT: u64 = 10 * 1000;
+
+/// Counts of the exploration we have done.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExploreStats {
+    /// Exploratory lookups sent to floodfills.
+    pub explorations_sent: u64,
+    /// RouterInfos we fetched because an exploration told us about them.
+    pub routers_learned: u64,
+}
+
+/// Runs exploration rounds, and fetches the routers they find.
+pub(super) struct Explorer {
+    min_routers: usize,
+    max_routers: usize,
+    explorations: Vec<oneshot::SpawnHandle<(RouterInfo, Vec<Hash>), ()>>,
+    to_fetch: VecDeque<(Hash, RouterInfo)>,
+    fetching: HashSet<Hash>,
+    fetches: Vec<(Hash, oneshot::SpawnHandle<RouterInfo, LookupError>)>,
+    stats: ExploreStats,
+}
+
+impl Explorer {
+    pub(super) fn new(min_routers: usize, max_routers: usize) -> Self {
+        Explorer {
+            min_routers,
+            max_routers,
+            explorations: vec![],
+            to_fetch: VecDeque::new(),
+            fetching: HashSet::new(),
+            fetches: vec![],
+            stats: ExploreStats::default(),
+        }
+    }
+
+    /// Reads the netDb size thresholds from the config, defaulting to higher
+    /// ones for floodfills.
+    pub(super) fn from_config(config: &Config, floodfill: bool) -> Self {
+        let get = |key: &str, default: usize| {
+            config
+                .get_int(key)
+                .ok()
+                .map(|v| v as usize)
+                .unwrap_or(default)
+        };
+        let min_default = if floodfill {
+            FLOODFILL_EXPLORE_MIN_ROUTERS
+        } else {
+            EXPLORE_MIN_ROUTERS
+        };
+        Explorer::new(
+            get(config::NETDB_EXPLORE_MIN_ROUTERS, min_default),
+            get(config::NETDB_EXPLORE_MAX_ROUTERS, EXPLORE_MAX_ROUTERS),
+        )
+    }
+
+    pub(super) fn stats(&self) -> ExploreStats {
+        self.stats
+    }
+
+    /// Returns how long to wait before the next round, given the number of
+    /// routers we know.
+    pub(super) fn interval(&self, known_routers: usize) -> Duration {
+        Duration::from_secs(if known_routers < self.min_routers {
+            EXPLORE_MIN_INTERVAL
+        } else {
+            EXPLORE_MAX_INTERVAL
+        })
+    }
+
+    /// Starts an exploration round, unless the netDb is already large or the
+    /// previous rounds are still waiting on replies.
+    pub(super) fn explore(
+        &mut self,
+        ctx: &Arc<Context>,
+        register_pending: &PendingTx,
+        netdb: &LocalNetworkDatabase,
+    ) {
+        if netdb.known_routers() >= self.max_routers {
+            debug!("Not exploring, we know {} routers", netdb.known_routers());
+            return;
+        }
+
+        // Pick a random key to search for
+        let mut key = Hash([0u8; 32]);
+        thread_rng().fill(&mut key.0);
+
+        let exclude = closest_routers(netdb, &key, EXPLORE_MAX_EXCLUDED);
+        let ffs = netdb.closest_floodfills(&key, EXPLORE_FLOODFILLS, &[]);
+        if ffs.is_empty() {
+            debug!("Not exploring, we don't know any floodfills");
+            return;
+        }
+
+        debug!("Exploring netDB for RouterInfo with key {}", key);
+        debug!("Known routers before exploring: {}", netdb.known_routers());
+        for ff in ffs {
+            if self.explorations.len() >= MAX_ACTIVE_EXPLORATIONS {
+                debug!("Too many explorations in progress");
+                break;
+            }
+
+            let explore = lookup::explore_netdb(
+                ctx.clone(),
+                register_pending.clone(),
+                key.clone(),
+                ff.clone(),
+                exclude.clone(),
+            )
+            .map(move |peers| (ff, peers))
+            .map_err(|e| error!("Error while exploring: {}", e));
+            self.explorations
+                .push(oneshot::spawn(explore, &DefaultExecutor::current()));
+            self.stats.explorations_sent += 1;
+        }
+    }
+
+    /// Collects the results of finished explorations, and fetches the routers
+    /// they found that we don't already know.
+    pub(super) fn poll(&mut self, netdb: &mut LocalNetworkDatabase) {
+        let mut i = 0;
+        while i < self.explorations.len() {
+            match self.explorations[i].poll() {
+                Ok(Async::NotReady) => i += 1,
+                res => {
+                    self.explorations.swap_remove(i);
+                    if let Ok(Async::Ready((ff, peers))) = res {
+                        for peer in peers {
+                            if netdb.router_info(&peer).is_none()
+                                && self.fetching.insert(peer.clone())
+                            {
+                                self.to_fetch.push_back((peer, ff.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        while self.fetches.len() < MAX_ACTIVE_FETCHES {
+            match self.to_fetch.pop_front() {
+                Some((peer, ff)) => {
+                    let fetch = netdb.lookup_router_info(&peer, FETCH_TIMEOUT, Some(ff), false);
+                    self.fetches
+                        .push((peer, oneshot::spawn(fetch, &DefaultExecutor::current())));
+                }
+                None => break,
+            }
+        }
+
+        let mut i = 0;
+        while i < self.fetches.len() {
+            match self.fetches[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                res => {
+                    let (peer, _) = self.fetches.swap_remove(i);
+                    self.fetching.remove(&peer);
+                    match res {
+                        Ok(_) => self.stats.routers_learned += 1,
+                        Err(e) => debug!("Failed to fetch explored router {}: {}", peer, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the hashes of up to `count` routers we know that are closest to `key`.
+fn closest_routers(netdb: &LocalNetworkDatabase, key: &Hash, count: usize) -> Vec<Hash> {
+    let rk = key.routing_key(SystemTime::now());
+    let mut closest = netdb.ri_ds.keys();
+    closest.sort_by(|a, b| rk.cmp_distance(a, b));
+    closest.truncate(count);
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, Future};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    use super::{ExploreStats, Explorer, EXPLORE_MAX_INTERVAL, EXPLORE_MIN_INTERVAL};
+    use crate::data::{RouterInfoBuilder, RouterSecretKeys};
+    use crate::netdb::mock::loopback_engine;
+    use crate::router::{
+        config::{self, Config},
+        mock::LoopbackPeers,
+    };
+
+    #[test]
+    fn from_config() {
+        let mut cfg = Config::default();
+        let explorer = Explorer::from_config(&cfg, false);
+        assert_eq!((explorer.min_routers, explorer.max_routers), (250, 4000));
+        let explorer = Explorer::from_config(&cfg, true);
+        assert_eq!((explorer.min_routers, explorer.max_routers), (1000, 4000));
+
+        cfg.set(config::NETDB_EXPLORE_MIN_ROUTERS, 100).unwrap();
+        cfg.set(config::NETDB_EXPLORE_MAX_ROUTERS, 200).unwrap();
+        let explorer = Explorer::from_config(&cfg, true);
+        assert_eq!((explorer.min_routers, explorer.max_routers), (100, 200));
+
+        // Exploration backs off once we know enough routers
+        assert_eq!(
+            explorer.interval(99),
+            Duration::from_secs(EXPLORE_MIN_INTERVAL)
+        );
+        assert_eq!(
+            explorer.interval(100),
+            Duration::from_secs(EXPLORE_MAX_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn learns_routers_from_floodfill() {
+        let peers = LoopbackPeers::default();
+        let (mut explorer, client, explorer_ri) = loopback_engine(&peers, false);
+        let (mut floodfill, _, floodfill_ri) = loopback_engine(&peers, true);
+
+        // The explorer only knows the floodfill, which knows ten other routers
+        explorer
+            .netdb
+            .store_router_info(floodfill_ri.router_id.hash(), floodfill_ri, false)
+            .unwrap();
+        floodfill
+            .netdb
+            .store_router_info(explorer_ri.router_id.hash(), explorer_ri, false)
+            .unwrap();
+        for _ in 0..10 {
+            let rsk = RouterSecretKeys::new();
+            let ri = RouterInfoBuilder::new(rsk.rid).sign(&rsk.signing_private_key);
+            floodfill
+                .netdb
+                .store_router_info(ri.router_id.hash(), ri, false)
+                .unwrap();
+        }
+
+        // Keep hold of the explorer so we can check its counters
+        let explorer = Arc::new(Mutex::new(explorer));
+        let mut rt = Runtime::new().unwrap();
+        let polled = explorer.clone();
+        rt.spawn(future::poll_fn(move || polled.lock().unwrap().poll()));
+        rt.spawn(floodfill);
+
+        // The first round starts immediately, and the floodfill suggests three
+        // routers the explorer doesn't know.
+        let mut stats = ExploreStats::default();
+        for _ in 0..100 {
+            stats = explorer.lock().unwrap().explorer.stats();
+            if stats.routers_learned >= 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(
+            stats,
+            ExploreStats {
+                explorations_sent: 1,
+                routers_learned: 3,
+            }
+        );
+        assert_eq!(rt.block_on(client.known_routers()).unwrap(), 4);
+    }
+}
@@ -0,0 +1,335 @@
+//! Exporting, importing, and inspecting the contents of the network database.
+//!
+//! RouterInfos can be exported to a directory laid out like an on-disk netDb
+//! (which Java I2P can also read), or to JSON when the `serde` feature is
+//! enabled. Imports from either format go through the same validation as
+//! RouterInfos loaded from disk, and report a result for every entry.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use super::{
+    persist::{scan_router_infos, write_router_info, LoadError},
+    validate_router_info,
+};
+use crate::data::{RouterCaps, RouterInfo};
+use crate::router::profiles::Profiles;
+
+/// Selects which RouterInfos to export.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Only export routers that advertise all of these capabilities.
+    pub caps: Option<RouterCaps>,
+    /// Only export RouterInfos published at most this long ago.
+    pub max_age: Option<Duration>,
+}
+
+impl Filter {
+    pub fn matches(&self, ri: &RouterInfo) -> bool {
+        if let Some(caps) = &self.caps {
+            if !ri.caps().map(|c| c.contains(caps)).unwrap_or(false) {
+                return false;
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            let age = SystemTime::now()
+                .duration_since(ri.published.to_system_time())
+                .unwrap_or_default();
+            if age > max_age {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns copies of the RouterInfos in `ris` that match this filter.
+    pub fn apply<I, R>(&self, ris: I) -> Vec<RouterInfo>
+    where
+        I: IntoIterator<Item = R>,
+        R: Borrow<RouterInfo>,
+    {
+        ris.into_iter()
+            .filter(|ri| self.matches(ri.borrow()))
+            .map(|ri| ri.borrow().clone())
+            .collect()
+    }
+}
+
+/// A set of exported RouterInfos, in the form they are written to JSON.
+#[cfg(feature = "serde")]
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Export {
+    pub router_infos: Vec<RouterInfo>,
+}
+
+/// The outcome of importing a single RouterInfo.
+#[derive(Debug)]
+pub struct Imported {
+    /// Where the RouterInfo came from: a file path for directory imports, or the
+    /// router's hash otherwise.
+    pub source: String,
+    pub result: Result<RouterInfo, LoadError>,
+}
+
+/// Writes the given RouterInfos into `dir` using the on-disk netDb layout,
+/// returning how many were written.
+pub fn export_dir(ris: &[RouterInfo], dir: &Path) -> io::Result<usize> {
+    for ri in ris {
+        write_router_info(dir, ri)?;
+    }
+    Ok(ris.len())
+}
+
+/// Reads and validates every RouterInfo in `dir`, which uses the on-disk netDb
+/// layout. A missing directory is treated as empty.
+///
+/// Nothing is stored; callers decide what to do with the valid entries.
+pub fn import_dir(dir: &Path, profiles: &Profiles) -> io::Result<Vec<Imported>> {
+    Ok(scan_router_infos(dir, true, profiles)?
+        .into_iter()
+        .map(|(path, result)| Imported {
+            source: path.display().to_string(),
+            result,
+        })
+        .collect())
+}
+
+/// Validates RouterInfos that were obtained by other means, such as from a JSON
+/// export.
+pub fn import_router_infos<I>(ris: I, profiles: &Profiles) -> Vec<Imported>
+where
+    I: IntoIterator<Item = RouterInfo>,
+{
+    ris.into_iter()
+        .map(|ri| {
+            let hash = ri.router_id.hash();
+            Imported {
+                source: hash.to_base64(),
+                result: validate_router_info(&hash, &ri, false, true, profiles)
+                    .map(|()| ri)
+                    .map_err(LoadError::from),
+            }
+        })
+        .collect()
+}
+
+/// Renders a human-readable description of a RouterInfo.
+///
+/// The RouterInfo isn't validated beyond checking its signature, so this can be
+/// used to see why an entry was rejected.
+pub fn inspect(ri: &RouterInfo) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = write_description(&mut out, ri);
+    out
+}
+
+fn write_description(out: &mut String, ri: &RouterInfo) -> std::fmt::Result {
+    writeln!(out, "Hash:       {}", ri.router_id.hash())?;
+    writeln!(out, "Published:  {}", ri.published)?;
+    writeln!(
+        out,
+        "Sig type:   {:?} ({})",
+        ri.router_id.signing_key().sig_type(),
+        match ri.verify() {
+            Ok(()) => "valid signature".to_string(),
+            Err(e) => format!("invalid signature: {}", e),
+        }
+    )?;
+    writeln!(
+        out,
+        "Caps:       {}",
+        ri.caps().map(|caps| caps.to_string()).unwrap_or_default()
+    )?;
+    writeln!(
+        out,
+        "Version:    {}",
+        ri.router_version().unwrap_or("unknown")
+    )?;
+    writeln!(
+        out,
+        "Network ID: {}",
+        ri.network_id()
+            .map(|net_id| net_id.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    )?;
+
+    writeln!(out, "Addresses:")?;
+    for addr in ri.addresses() {
+        write!(
+            out,
+            "  {} {} cost={}",
+            addr.transport_style(),
+            addr.addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|| "(introduced)".to_string()),
+            addr.cost()
+        )?;
+        if let Some(caps) = addr.caps() {
+            write!(out, " caps={}", caps)?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "Options:")?;
+    for (key, value) in ri.options.iter() {
+        writeln!(out, "  {}={}", key, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{export_dir, import_dir, import_router_infos, inspect, Filter};
+    use crate::data::{I2PDate, RouterCaps, RouterInfo, RouterInfoBuilder, RouterSecretKeys};
+    use crate::netdb::{persist::LoadError, StoreError, ROUTER_INFO_EXPIRATION};
+    use crate::router::profiles::Profiles;
+
+    fn router_infos() -> Vec<RouterInfo> {
+        (0..10)
+            .map(|i| {
+                let rsk = RouterSecretKeys::new();
+                RouterInfoBuilder::new(rsk.rid)
+                    .caps(RouterCaps::default().floodfill(i % 2 == 0))
+                    .sign(&rsk.signing_private_key)
+            })
+            .collect()
+    }
+
+    fn sorted(mut ris: Vec<RouterInfo>) -> Vec<RouterInfo> {
+        ris.sort_by_key(|ri| ri.router_id.hash().0);
+        ris
+    }
+
+    #[test]
+    fn filter() {
+        let ris = router_infos();
+        assert_eq!(Filter::default().apply(&ris), ris);
+
+        let floodfills = Filter {
+            caps: Some("f".parse().unwrap()),
+            max_age: None,
+        }
+        .apply(&ris);
+        assert_eq!(floodfills.len(), 5);
+        assert!(floodfills.iter().all(|ri| ri.is_floodfill()));
+
+        let mut old = ris[0].clone();
+        old.published =
+            I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(2 * 60 * 60));
+        let recent = Filter {
+            caps: None,
+            max_age: Some(Duration::from_secs(60 * 60)),
+        };
+        assert!(recent.matches(&ris[1]));
+        assert!(!recent.matches(&old));
+    }
+
+    #[test]
+    fn dir_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let ris = router_infos();
+        assert_eq!(export_dir(&ris, dir.path()).unwrap(), ris.len());
+
+        let imported = import_dir(dir.path(), &Profiles::default()).unwrap();
+        assert_eq!(imported.len(), ris.len());
+        let imported = imported
+            .into_iter()
+            .map(|entry| {
+                assert!(entry.source.ends_with(".dat"));
+                entry.result.unwrap()
+            })
+            .collect();
+        assert_eq!(sorted(imported), sorted(ris));
+
+        // A missing directory is empty
+        assert!(
+            import_dir(&dir.path().join("missing"), &Profiles::default())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn invalid_entries_are_reported() {
+        let mut ris = router_infos();
+        let rsk = RouterSecretKeys::new();
+        let mut expired = RouterInfo::new(rsk.rid);
+        expired.published = I2PDate::from_system_time(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        expired.sign(&rsk.signing_private_key);
+        ris.push(expired.clone());
+
+        let imported = import_router_infos(ris, &Profiles::default());
+        assert_eq!(imported.len(), 11);
+        assert!(imported[..10].iter().all(|entry| entry.result.is_ok()));
+        assert_eq!(imported[10].source, expired.router_id.hash().to_base64());
+        match &imported[10].result {
+            Err(LoadError::Store(StoreError::Expired(_))) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        // Directory imports report each file
+        let dir = tempfile::tempdir().unwrap();
+        export_dir(&[expired], dir.path()).unwrap();
+        let imported = import_dir(dir.path(), &Profiles::default()).unwrap();
+        assert_eq!(imported.len(), 1);
+        match &imported[0].result {
+            Err(LoadError::Store(StoreError::Expired(_))) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        use super::Export;
+
+        let export = Export {
+            router_infos: router_infos(),
+        };
+        let json = serde_json::to_string(&export).unwrap();
+        let parsed: Export = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, export);
+
+        let imported = import_router_infos(parsed.router_infos, &Profiles::default());
+        let imported: Vec<_> = imported
+            .into_iter()
+            .map(|entry| entry.result.unwrap())
+            .collect();
+        assert_eq!(imported, export.router_infos);
+    }
+
+    #[test]
+    fn inspect_fixture() {
+        let ri = RouterInfo::from_bytes(crate::tests::ROUTER_INFO).unwrap();
+        let output = inspect(&ri);
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Hash:       JnqHeA0MoJohoCm3TXvDTQfDUwJyxjCqTMEdYZDHtrQ=",
+                "Published:  2017-09-16 18:55:33.655 UTC",
+                "Sig type:   Ed25519 (valid signature)",
+                "Caps:       L",
+                "Version:    0.9.28",
+                "Network ID: 2",
+                "Addresses:",
+                "  SSU 82.26.253.115:26698 cost=5 caps=BC",
+                "  NTCP 82.26.253.115:26698 cost=10",
+                "Options:",
+                "  caps=L",
+                "  netId=2",
+                "  router.version=0.9.28",
+            ]
+        );
+    }
+}
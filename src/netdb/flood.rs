@@ -0,0 +1,381 @@
+//! Acknowledging and flooding DatabaseStore messages.
+//!
+//! A DatabaseStore carrying a reply token was sent to us directly by the
+//! publisher, who expects a DeliveryStatus in return. When we are a floodfill,
+//! we also pass the entry on to the floodfills next-closest to its key, without
+//! a reply token so that they don't flood it any further.
+//!
+//! Whether we act as a floodfill is set by the `netdb.floodfill` option. If it
+//! was set when our previous RouterInfo was published and has since been
+//! cleared, other routers will keep treating us as a floodfill until they see
+//! our new RouterInfo, so we keep serving for a while before stopping.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::LocalNetworkDatabase;
+use crate::data::{Hash, TunnelId};
+use crate::i2np::{DatabaseStore, Message, MessageIdGenerator, MessagePayload, ReplyPath};
+
+/// How many floodfills we pass each accepted store on to.
+const FLOOD_REDUNDANCY: usize = 3;
+/// How long after flooding a key we refuse to flood it again, in seconds.
+const FLOOD_SUPPRESSION_PERIOD: u64 = 60;
+/// How long we keep serving as a floodfill after the option is turned off, in
+/// seconds.
+const FLOODFILL_RETIREMENT_PERIOD: u64 = 30 * 60;
+
+/// Whether we are acting as a floodfill.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum FloodfillMode {
+    Disabled,
+    /// We were a floodfill, and will stop serving at the given time.
+    Retiring(Instant),
+    Enabled,
+}
+
+impl FloodfillMode {
+    /// Returns the mode for a router that is configured as a floodfill if
+    /// `enabled` is true, and last published a floodfill RouterInfo if
+    /// `was_enabled` is true.
+    pub(super) fn new(enabled: bool, was_enabled: bool, now: Instant) -> Self {
+        match (enabled, was_enabled) {
+            (true, _) => FloodfillMode::Enabled,
+            (false, true) => {
+                FloodfillMode::Retiring(now + Duration::from_secs(FLOODFILL_RETIREMENT_PERIOD))
+            }
+            (false, false) => FloodfillMode::Disabled,
+        }
+    }
+
+    /// Returns true if we should answer lookups and flood stores.
+    pub(super) fn is_serving(&mut self, now: Instant) -> bool {
+        match *self {
+            FloodfillMode::Disabled => false,
+            FloodfillMode::Retiring(until) if now >= until => {
+                info!("No longer serving as a floodfill");
+                *self = FloodfillMode::Disabled;
+                false
+            }
+            FloodfillMode::Retiring(_) | FloodfillMode::Enabled => true,
+        }
+    }
+}
+
+/// Counts of the work we have done as a floodfill.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FloodfillStats {
+    /// DatabaseStores we validated and stored.
+    pub stores_accepted: u64,
+    /// DatabaseStores we passed on to other floodfills.
+    pub floods_sent: u64,
+}
+
+/// Decides which messages to send after accepting a DatabaseStore.
+pub(super) struct Flooder {
+    our_hash: Hash,
+    recent: HashMap<Hash, Instant>,
+    last_cleaned: Instant,
+    stats: FloodfillStats,
+}
+
+impl Flooder {
+    pub(super) fn new(our_hash: Hash) -> Self {
+        Flooder {
+            our_hash,
+            recent: HashMap::new(),
+            last_cleaned: Instant::now(),
+            stats: FloodfillStats::default(),
+        }
+    }
+
+    pub(super) fn stats(&self) -> FloodfillStats {
+        self.stats
+    }
+
+    /// Returns true if `key` was flooded in the current suppression period, and
+    /// otherwise records that it is being flooded now.
+    fn is_suppressed(&mut self, key: &Hash, now: Instant) -> bool {
+        let period = Duration::from_secs(FLOOD_SUPPRESSION_PERIOD);
+        if now.duration_since(self.last_cleaned) >= period {
+            self.recent
+                .retain(|_, flooded| now.duration_since(*flooded) < period);
+            self.last_cleaned = now;
+        }
+
+        match self.recent.get(key) {
+            Some(flooded) if now.duration_since(*flooded) < period => true,
+            _ => {
+                self.recent.insert(key.clone(), now);
+                false
+            }
+        }
+    }
+
+    /// Returns the messages to send, and the routers to send them to, after `ds`
+    /// from `from` has been validated and stored in `netdb`: the acknowledgement
+    /// first, followed by floods if `flood` is true.
+    pub(super) fn stored(
+        &mut self,
+        netdb: &LocalNetworkDatabase,
+        from: &Hash,
+        ds: &DatabaseStore,
+        flood: bool,
+        now: Instant,
+    ) -> Vec<(Hash, Message)> {
+        self.stats.stores_accepted += 1;
+
+        // Stores without a reply token are floods themselves, or responses to
+        // our own lookups.
+        let reply = match ds.reply() {
+            Some(reply) => reply,
+            None => return vec![],
+        };
+
+        let mut msgs = vec![ack(&netdb.ctx.msg_ids, reply)];
+
+        if flood && !self.is_suppressed(&ds.key, now) {
+            // Near midnight this also includes the floodfills closest to the key
+            // under the next day's routing key.
+            let exclude = [from.clone(), self.our_hash.clone()];
+            for ri in netdb.closest_floodfills(&ds.key, FLOOD_REDUNDANCY, &exclude) {
+                if let Some(flood) = flood_msg(netdb, &ds.key) {
+                    self.stats.floods_sent += 1;
+                    msgs.push((ri.router_id.hash(), flood));
+                }
+            }
+        }
+
+        msgs
+    }
+
+    /// Returns floods of the entry we hold at `key` to the floodfills closest to
+    /// it other than those in `exclude`, after an earlier flood of it couldn't
+    /// be verified.
+    pub(super) fn reflood(
+        &mut self,
+        netdb: &LocalNetworkDatabase,
+        key: &Hash,
+        exclude: &[Hash],
+    ) -> Vec<(Hash, Message)> {
+        let mut exclude = exclude.to_vec();
+        exclude.push(self.our_hash.clone());
+        let mut msgs = vec![];
+        for ri in netdb.closest_floodfills(key, FLOOD_REDUNDANCY, &exclude) {
+            if let Some(flood) = flood_msg(netdb, key) {
+                self.stats.floods_sent += 1;
+                msgs.push((ri.router_id.hash(), flood));
+            }
+        }
+        msgs
+    }
+}
+
+/// Builds the DeliveryStatus acknowledging a store, addressed to the reply
+/// gateway.
+fn ack(ids: &MessageIdGenerator, reply: &ReplyPath) -> (Hash, Message) {
+    let status = Message::delivery_status(ids, reply.token());
+    let msg = match reply.tid() {
+        TunnelId(0) => status,
+        tid => Message::tunnel_gateway(ids, tid, &status),
+    };
+    (reply.gateway().clone(), msg)
+}
+
+/// Builds a DatabaseStore without a reply token for the entry we hold at `key`.
+fn flood_msg(netdb: &LocalNetworkDatabase, key: &Hash) -> Option<Message> {
+    let ds = if let Some(ri) = netdb.router_info(key) {
+        DatabaseStore::from_ri((*ri).clone(), None)
+    } else if let Some(ls) = netdb.lease_set(key) {
+        ls.to_database_store()
+    } else {
+        return None;
+    };
+    Some(Message::from_payload(
+        &netdb.ctx.msg_ids,
+        MessagePayload::DatabaseStore(ds),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{
+        Flooder, FloodfillMode, FloodfillStats, FLOODFILL_RETIREMENT_PERIOD, FLOOD_REDUNDANCY,
+        FLOOD_SUPPRESSION_PERIOD,
+    };
+    use crate::data::{Hash, TunnelId};
+    use crate::i2np::{DatabaseStore, DatabaseStoreData, MessagePayload, MessageType, ReplyPath};
+    use crate::netdb::{mock::populated_netdb, LocalNetworkDatabase};
+
+    /// Returns a store of the RouterInfo at `key`, as if received from a peer.
+    fn store(netdb: &LocalNetworkDatabase, key: &Hash, reply: Option<ReplyPath>) -> DatabaseStore {
+        DatabaseStore::from_ri((*netdb.ri_ds.get(key).unwrap()).clone(), reply)
+    }
+
+    #[test]
+    fn acknowledged_without_flooding() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let mut flooder = Flooder::new(Hash([0xff; 32]));
+        let from = Hash([1; 32]);
+        let gateway = Hash([2; 32]);
+        let now = Instant::now();
+
+        // Stores without a reply token are neither acknowledged nor flooded
+        let ds = store(&netdb, &floodfills[0], None);
+        assert!(flooder.stored(&netdb, &from, &ds, false, now).is_empty());
+
+        // Replies are sent directly to the gateway when there is no tunnel...
+        let ds = store(
+            &netdb,
+            &floodfills[0],
+            Some(ReplyPath::new(1234, TunnelId(0), gateway.clone())),
+        );
+        let msgs = flooder.stored(&netdb, &from, &ds, false, now);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].0, gateway);
+        assert_eq!(msgs[0].1.message_type(), MessageType::DeliveryStatus);
+
+        // ...and otherwise into the reply tunnel
+        let ds = store(
+            &netdb,
+            &floodfills[0],
+            Some(ReplyPath::new(1234, TunnelId(5678), gateway.clone())),
+        );
+        let msgs = flooder.stored(&netdb, &from, &ds, false, now);
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].0, gateway);
+        assert_eq!(msgs[0].1.message_type(), MessageType::TunnelGateway);
+
+        assert_eq!(
+            flooder.stats(),
+            FloodfillStats {
+                stores_accepted: 3,
+                floods_sent: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn flooded_to_closest_floodfills() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let key = floodfills[0].clone();
+        let rk = key.routing_key(SystemTime::now());
+
+        // Our own hash and the sender are the two floodfills closest to the key
+        let mut sorted = floodfills.clone();
+        sorted.sort_by(|a, b| rk.cmp_distance(a, b));
+        let us = sorted[0].clone();
+        let from = sorted[1].clone();
+
+        let mut flooder = Flooder::new(us.clone());
+        let gateway = Hash([2; 32]);
+        let ds = store(
+            &netdb,
+            &key,
+            Some(ReplyPath::new(1234, TunnelId(0), gateway.clone())),
+        );
+        let msgs = flooder.stored(&netdb, &from, &ds, true, Instant::now());
+
+        // The ack comes first
+        assert_eq!(msgs[0].0, gateway);
+        assert_eq!(msgs[0].1.message_type(), MessageType::DeliveryStatus);
+
+        // Followed by the floods, which don't request acks themselves
+        let recipients: Vec<_> = msgs[1..].iter().map(|(to, _)| to.clone()).collect();
+        assert_eq!(
+            recipients[..FLOOD_REDUNDANCY],
+            sorted[2..2 + FLOOD_REDUNDANCY]
+        );
+        assert!(!recipients.contains(&us));
+        assert!(!recipients.contains(&from));
+        for (_, msg) in &msgs[1..] {
+            match &msg.payload {
+                MessagePayload::DatabaseStore(flood) => {
+                    assert_eq!(flood.key, key);
+                    assert!(flood.reply().is_none());
+                    match &flood.data {
+                        DatabaseStoreData::RI(ri) => assert_eq!(ri.router_id.hash(), key),
+                        _ => panic!("Expected a RouterInfo"),
+                    }
+                }
+                _ => panic!("Expected a DatabaseStore"),
+            }
+        }
+    }
+
+    #[test]
+    fn reflooded_to_other_floodfills() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let key = floodfills[0].clone();
+        let rk = key.routing_key(SystemTime::now());
+        let mut sorted = floodfills.clone();
+        sorted.sort_by(|a, b| rk.cmp_distance(a, b));
+        let us = sorted[0].clone();
+        let mut flooder = Flooder::new(us.clone());
+
+        // The floodfills that were already tried are skipped, even though they
+        // are closest to the key
+        let tried = sorted[1..4].to_vec();
+        let msgs = flooder.reflood(&netdb, &key, &tried);
+        let recipients: Vec<_> = msgs.iter().map(|(to, _)| to.clone()).collect();
+        assert_eq!(
+            recipients[..FLOOD_REDUNDANCY],
+            sorted[4..4 + FLOOD_REDUNDANCY]
+        );
+        assert!(!recipients.contains(&us));
+        assert_eq!(flooder.stats().floods_sent, msgs.len() as u64);
+
+        // We can't flood entries we no longer have
+        assert!(flooder.reflood(&netdb, &Hash([1; 32]), &tried).is_empty());
+    }
+
+    #[test]
+    fn floods_suppressed() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let mut flooder = Flooder::new(Hash([0xff; 32]));
+        let from = Hash([1; 32]);
+        let reply = || Some(ReplyPath::new(1234, TunnelId(0), Hash([2; 32])));
+        let now = Instant::now();
+
+        let ds = store(&netdb, &floodfills[0], reply());
+        assert!(flooder.stored(&netdb, &from, &ds, true, now).len() > FLOOD_REDUNDANCY);
+
+        // The same key is only acknowledged within the suppression period
+        let later = now + Duration::from_secs(FLOOD_SUPPRESSION_PERIOD - 1);
+        assert_eq!(flooder.stored(&netdb, &from, &ds, true, later).len(), 1);
+
+        // Other keys are unaffected
+        let other = store(&netdb, &floodfills[1], reply());
+        assert!(flooder.stored(&netdb, &from, &other, true, later).len() > FLOOD_REDUNDANCY);
+
+        // The key is flooded again once the period has passed
+        let after = now + Duration::from_secs(FLOOD_SUPPRESSION_PERIOD);
+        assert!(flooder.stored(&netdb, &from, &ds, true, after).len() > FLOOD_REDUNDANCY);
+    }
+
+    #[test]
+    fn floodfill_mode() {
+        let now = Instant::now();
+
+        let mut mode = FloodfillMode::new(false, false, now);
+        assert_eq!(mode, FloodfillMode::Disabled);
+        assert!(!mode.is_serving(now));
+
+        for &was_enabled in &[false, true] {
+            let mut mode = FloodfillMode::new(true, was_enabled, now);
+            assert_eq!(mode, FloodfillMode::Enabled);
+            assert!(mode.is_serving(now));
+            assert!(mode.is_serving(now + Duration::from_secs(24 * 60 * 60)));
+        }
+
+        // After the option is turned off, we keep serving for a while
+        let mut mode = FloodfillMode::new(false, true, now);
+        let period = Duration::from_secs(FLOODFILL_RETIREMENT_PERIOD);
+        assert!(mode.is_serving(now));
+        assert!(mode.is_serving(now + period - Duration::from_secs(1)));
+        assert!(!mode.is_serving(now + period));
+        assert_eq!(mode, FloodfillMode::Disabled);
+    }
+}
@@ -0,0 +1,188 @@
+//! An index of floodfill routers, for finding those closest to a routing key.
+//!
+//! Floodfills are kept sorted by their router hash, which is also the order of
+//! the leaves of a binary trie over the hashes. Under the XOR metric, every
+//! router whose hash agrees with the target on the next bit is closer than
+//! every router that does not, so walking that trie preferring the target's
+//! side yields routers in order of distance, and only touches the entries that
+//! are returned (plus any excluded ones on the way).
+
+use std::cmp::Ordering;
+use std::ops::Range;
+
+use crate::data::Hash;
+
+/// Returns whether bit `bit` of `hash` is set, counting from the most
+/// significant bit of the first byte.
+fn bit_set(hash: &Hash, bit: usize) -> bool {
+    hash.0[bit / 8] & (0x80 >> (bit % 8)) != 0
+}
+
+/// The hashes of the floodfill routers in the netDb.
+#[derive(Default)]
+pub(super) struct FloodfillIndex {
+    hashes: Vec<Hash>,
+}
+
+impl FloodfillIndex {
+    pub(super) fn insert(&mut self, hash: Hash) {
+        if let Err(i) = self.hashes.binary_search(&hash) {
+            self.hashes.insert(i, hash);
+        }
+    }
+
+    pub(super) fn remove(&mut self, hash: &Hash) {
+        if let Ok(i) = self.hashes.binary_search(hash) {
+            self.hashes.remove(i);
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Returns up to `count` floodfills closest to the routing key `rk`, closest
+    /// first, skipping any in `exclude`.
+    pub(super) fn closest(&self, rk: &Hash, count: usize, exclude: &[Hash]) -> Vec<Hash> {
+        self.closest_examined(rk, count, exclude).0
+    }
+
+    /// As [`FloodfillIndex::closest`], also returning how many entries were
+    /// examined to find them.
+    fn closest_examined(&self, rk: &Hash, count: usize, exclude: &[Hash]) -> (Vec<Hash>, usize) {
+        let mut found = Vec::with_capacity(count.min(self.hashes.len()));
+        let mut examined = 0;
+        self.walk(
+            rk,
+            0..self.hashes.len(),
+            0,
+            count,
+            exclude,
+            &mut found,
+            &mut examined,
+        );
+        (found, examined)
+    }
+
+    /// Collects entries from `range`, all of which share their first `bit` bits,
+    /// in order of XOR distance from `rk`.
+    #[allow(clippy::too_many_arguments)]
+    fn walk(
+        &self,
+        rk: &Hash,
+        range: Range<usize>,
+        bit: usize,
+        count: usize,
+        exclude: &[Hash],
+        found: &mut Vec<Hash>,
+        examined: &mut usize,
+    ) {
+        if found.len() >= count || range.start == range.end {
+            return;
+        }
+
+        if bit == 256 || range.end - range.start == 1 {
+            for hash in &self.hashes[range] {
+                if found.len() >= count {
+                    break;
+                }
+                *examined += 1;
+                if !exclude.contains(hash) {
+                    found.push(hash.clone());
+                }
+            }
+            return;
+        }
+
+        // Entries with this bit unset sort before those with it set
+        let split = range.start
+            + self.hashes[range.clone()]
+                .binary_search_by(|hash| {
+                    if bit_set(hash, bit) {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Less
+                    }
+                })
+                .unwrap_err();
+        let (near, far) = if bit_set(rk, bit) {
+            (split..range.end, range.start..split)
+        } else {
+            (range.start..split, split..range.end)
+        };
+
+        self.walk(rk, near, bit + 1, count, exclude, found, examined);
+        self.walk(rk, far, bit + 1, count, exclude, found, examined);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::FloodfillIndex;
+    use crate::data::Hash;
+
+    fn brute_force(hashes: &[Hash], rk: &Hash, count: usize, exclude: &[Hash]) -> Vec<Hash> {
+        let mut sorted: Vec<_> = hashes
+            .iter()
+            .filter(|hash| !exclude.contains(hash))
+            .cloned()
+            .collect();
+        sorted.sort_by(|a, b| rk.cmp_distance(a, b));
+        sorted.truncate(count);
+        sorted
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut index = FloodfillIndex::default();
+        index.insert(Hash([2; 32]));
+        index.insert(Hash([1; 32]));
+        index.insert(Hash([2; 32]));
+        assert_eq!(index.hashes.len(), 2);
+        assert_eq!(
+            index.closest(&Hash([0; 32]), 5, &[]),
+            vec![Hash([1; 32]), Hash([2; 32])]
+        );
+
+        index.remove(&Hash([1; 32]));
+        index.remove(&Hash([3; 32]));
+        assert_eq!(index.hashes.len(), 1);
+        assert_eq!(index.closest(&Hash([0; 32]), 5, &[]), vec![Hash([2; 32])]);
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut index = FloodfillIndex::default();
+        let hashes: Vec<_> = (0..1000).map(|_| Hash(rng.gen())).collect();
+        for hash in &hashes {
+            index.insert(hash.clone());
+        }
+        assert_eq!(index.hashes.len(), 1000);
+
+        for _ in 0..200 {
+            let rk = Hash(rng.gen());
+            let count = rng.gen_range(0..20);
+            let exclude: Vec<_> = brute_force(&hashes, &rk, 10, &[])
+                .into_iter()
+                .filter(|_| rng.gen())
+                .collect();
+
+            let (closest, examined) = index.closest_examined(&rk, count, &exclude);
+            assert_eq!(closest, brute_force(&hashes, &rk, count, &exclude));
+
+            // Only the returned entries and the excluded ones are looked at
+            assert!(examined <= count + exclude.len());
+        }
+
+        // The target itself is closest, and asking for everything returns everything
+        let rk = hashes[500].clone();
+        assert_eq!(index.closest(&rk, 1, &[]), vec![rk.clone()]);
+        assert_eq!(
+            index.closest(&rk, 2000, &[]),
+            brute_force(&hashes, &rk, 2000, &[])
+        );
+    }
+}
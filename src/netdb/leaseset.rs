@@ -0,0 +1,290 @@
+//! Storage for LeaseSets, kept apart from RouterInfos.
+//!
+//! LeaseSets last minutes rather than hours, are signed by a Destination (or a
+//! key it has authorized) rather than a router, and are only ever held in
+//! memory. Each one is checked when it is stored, and replaced only by a newer
+//! version, so that an old LeaseSet can't be replayed over a current one.
+
+use std::collections::HashMap;
+
+use super::errors::StoreError;
+use crate::data::{EncryptedLeaseSet2, Hash, I2PDate, LeaseSet, LeaseSet2, LeaseSetError, Leases};
+use crate::i2np::DatabaseStore;
+
+/// A LeaseSet of any type.
+#[derive(Clone)]
+pub(super) enum StoredLeaseSet {
+    LS(LeaseSet),
+    LS2(LeaseSet2),
+    EncryptedLS2(EncryptedLeaseSet2),
+}
+
+impl StoredLeaseSet {
+    /// Returns the netDb key that this LeaseSet must be stored under: the hash of
+    /// its Destination, or of the blinded key for an EncryptedLeaseSet2.
+    pub(super) fn key(&self) -> Hash {
+        match self {
+            StoredLeaseSet::LS(ls) => ls.dest.hash(),
+            StoredLeaseSet::LS2(ls) => ls.dest.hash(),
+            StoredLeaseSet::EncryptedLS2(els) => els.store_key(),
+        }
+    }
+
+    /// Returns the date that orders versions of this LeaseSet.
+    ///
+    /// The original LeaseSet has no published date, so the end date of its last
+    /// Lease is used instead.
+    pub(super) fn version(&self) -> Option<I2PDate> {
+        match self {
+            StoredLeaseSet::LS(ls) => ls.latest_expiry(),
+            StoredLeaseSet::LS2(ls) => Some(ls.published()),
+            StoredLeaseSet::EncryptedLS2(els) => Some(els.published()),
+        }
+    }
+
+    fn is_current(&self, now: I2PDate) -> bool {
+        match self {
+            StoredLeaseSet::LS(ls) => ls.is_current(now),
+            StoredLeaseSet::LS2(ls) => ls.is_current(now),
+            StoredLeaseSet::EncryptedLS2(els) => els.is_current(now),
+        }
+    }
+
+    pub(super) fn verify(&self, now: I2PDate) -> Result<(), StoreError> {
+        match self {
+            StoredLeaseSet::LS(ls) => ls.verify()?,
+            StoredLeaseSet::LS2(ls) => match ls.verify_at(now) {
+                Ok(_) => (),
+                Err(LeaseSetError::Crypto(e)) => return Err(e.into()),
+                Err(_) => return Err(StoreError::LeaseSetExpired),
+            },
+            StoredLeaseSet::EncryptedLS2(els) => els.verify()?,
+        }
+        Ok(())
+    }
+
+    /// Returns a DatabaseStore without a reply token for this LeaseSet.
+    pub(super) fn to_database_store(&self) -> DatabaseStore {
+        match self.clone() {
+            StoredLeaseSet::LS(ls) => DatabaseStore::from_ls(ls, None),
+            StoredLeaseSet::LS2(ls) => DatabaseStore::from_ls2(ls, None),
+            StoredLeaseSet::EncryptedLS2(els) => DatabaseStore::from_encrypted_ls2(els, None),
+        }
+    }
+}
+
+/// The LeaseSets in the netDb, keyed by Destination hash (or blinded hash).
+#[derive(Default)]
+pub(super) struct LeaseSetStore {
+    entries: HashMap<Hash, StoredLeaseSet>,
+}
+
+impl LeaseSetStore {
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns the LeaseSet stored at `key`, unless it has expired as of `now`.
+    pub(super) fn get(&self, key: &Hash, now: I2PDate) -> Option<&StoredLeaseSet> {
+        self.entries.get(key).filter(|ls| ls.is_current(now))
+    }
+
+    /// Validates `ls` and stores it at `key`, returning the LeaseSet it replaced.
+    ///
+    /// The LeaseSet must be current as of `now`, correctly signed, and newer than
+    /// any current LeaseSet we already hold at `key`.
+    pub(super) fn insert(
+        &mut self,
+        key: Hash,
+        ls: StoredLeaseSet,
+        now: I2PDate,
+    ) -> Result<Option<StoredLeaseSet>, StoreError> {
+        if key != ls.key() {
+            return Err(StoreError::InvalidKey);
+        }
+        if !ls.is_current(now) {
+            return Err(StoreError::LeaseSetExpired);
+        }
+        if let Some(held) = self.get(&key, now) {
+            if ls.version() <= held.version() {
+                return Err(StoreError::NotNewer);
+            }
+        }
+        ls.verify(now)?;
+
+        Ok(self.entries.insert(key, ls))
+    }
+
+    /// Removes every LeaseSet that has expired as of `now`, returning how many
+    /// were removed.
+    pub(super) fn expire(&mut self, now: I2PDate) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, ls| ls.is_current(now));
+        before - self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::{LeaseSetStore, StoredLeaseSet};
+    use crate::crypto::{EncType, SigType};
+    use crate::data::{
+        DestinationSecretKeys, EncryptionKey, Hash, I2PDate, Lease, LeaseSet, LeaseSet2, TunnelId,
+        CLOCK_SKEW_TOLERANCE,
+    };
+    use crate::netdb::errors::StoreError;
+
+    fn in_secs(secs: u64) -> I2PDate {
+        // LeaseSet2 dates are stored to the second
+        let date = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(secs));
+        I2PDate(date.0 - date.0 % 1_000)
+    }
+
+    fn lease_set(dsk: &DestinationSecretKeys, end_date: I2PDate) -> StoredLeaseSet {
+        StoredLeaseSet::LS(
+            LeaseSet::signed(
+                dsk.dest.clone(),
+                dsk.dest.public_key.clone(),
+                dsk.dest.signing_key.clone(),
+                vec![Lease::new(Hash([1; 32]), TunnelId(42), end_date)],
+                &dsk.signing_private_key,
+            )
+            .unwrap(),
+        )
+    }
+
+    fn lease_set2(dsk: &DestinationSecretKeys, published: I2PDate) -> StoredLeaseSet {
+        let mut ls = LeaseSet2::new(
+            dsk.dest.clone(),
+            published,
+            in_secs(600),
+            vec![EncryptionKey::X25519([7; 32])],
+            vec![Lease::new(Hash([1; 32]), TunnelId(42), in_secs(600))],
+        );
+        ls.sign(&dsk.signing_private_key).unwrap();
+        StoredLeaseSet::LS2(ls)
+    }
+
+    #[test]
+    fn replace_older() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let key = dsk.dest.hash();
+        let now = in_secs(0);
+        let mut store = LeaseSetStore::default();
+
+        let (older, newer) = (in_secs(300), in_secs(600));
+        assert!(store
+            .insert(key.clone(), lease_set(&dsk, older), now)
+            .unwrap()
+            .is_none());
+        let replaced = store
+            .insert(key.clone(), lease_set(&dsk, newer), now)
+            .unwrap();
+        assert_eq!(replaced.unwrap().version(), Some(older));
+        assert_eq!(store.get(&key, now).unwrap().version(), Some(newer));
+        assert_eq!(store.len(), 1);
+
+        // LeaseSet2s are ordered by their published date
+        let mut store = LeaseSetStore::default();
+        store
+            .insert(key.clone(), lease_set2(&dsk, now), now)
+            .unwrap();
+        assert!(store
+            .insert(key.clone(), lease_set2(&dsk, in_secs(1)), now)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn reject_rollback() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let key = dsk.dest.hash();
+        let now = in_secs(0);
+        let published = in_secs(60);
+        let mut store = LeaseSetStore::default();
+
+        store
+            .insert(key.clone(), lease_set2(&dsk, published), now)
+            .unwrap();
+        assert_eq!(
+            store.insert(key.clone(), lease_set2(&dsk, now), now).err(),
+            Some(StoreError::NotNewer)
+        );
+        assert_eq!(
+            store
+                .insert(key.clone(), lease_set2(&dsk, published), now)
+                .err(),
+            Some(StoreError::NotNewer)
+        );
+        assert_eq!(store.get(&key, now).unwrap().version(), Some(published));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let now = in_secs(0);
+        let mut store = LeaseSetStore::default();
+
+        // Wrong key
+        assert_eq!(
+            store
+                .insert(Hash([0; 32]), lease_set(&dsk, in_secs(600)), now)
+                .err(),
+            Some(StoreError::InvalidKey)
+        );
+
+        // Signed by a different Destination
+        let other = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let mut forged = LeaseSet2::new(
+            dsk.dest.clone(),
+            in_secs(0),
+            in_secs(600),
+            vec![EncryptionKey::X25519([7; 32])],
+            vec![Lease::new(Hash([1; 32]), TunnelId(42), in_secs(600))],
+        );
+        forged.sign(&other.signing_private_key).unwrap();
+        assert!(store
+            .insert(dsk.dest.hash(), StoredLeaseSet::LS2(forged), now)
+            .is_err());
+
+        // Already expired
+        let expired = lease_set(&dsk, in_secs(0));
+        assert_eq!(
+            store
+                .insert(
+                    dsk.dest.hash(),
+                    expired,
+                    in_secs(0).saturating_add(CLOCK_SKEW_TOLERANCE)
+                )
+                .err(),
+            Some(StoreError::LeaseSetExpired)
+        );
+        assert_eq!(store.len(), 0);
+    }
+
+    #[test]
+    fn expiry() {
+        let now = in_secs(0);
+        let mut store = LeaseSetStore::default();
+
+        let short = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let long = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        store
+            .insert(short.dest.hash(), lease_set(&short, in_secs(60)), now)
+            .unwrap();
+        store
+            .insert(long.dest.hash(), lease_set(&long, in_secs(600)), now)
+            .unwrap();
+
+        let later = in_secs(60).saturating_add(CLOCK_SKEW_TOLERANCE);
+        assert!(store.get(&short.dest.hash(), now).is_some());
+        assert!(store.get(&short.dest.hash(), later).is_none());
+
+        assert_eq!(store.expire(now), 0);
+        assert_eq!(store.expire(later), 1);
+        assert_eq!(store.len(), 1);
+        assert!(store.get(&long.dest.hash(), later).is_some());
+    }
+}
@@ -0,0 +1,224 @@
+//! Rate limits on inbound netDb traffic.
+//!
+//! Every DatabaseStore we are sent costs a signature verification, and every
+//! DatabaseLookup we answer costs an outbound message, possibly to a router
+//! other than the one that asked. Without limits, a single peer could keep us
+//! busy verifying junk, or use us to send replies at a victim. Sources are
+//! limited individually, so one noisy peer does not affect the others.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::data::Hash;
+use crate::router::config::{self, Config};
+
+/// The number of unsolicited DatabaseStores we will accept from a single source
+/// per limit period.
+const MAX_STORES_PER_PERIOD: u32 = 100;
+
+/// The length of the per-source limit period, in seconds.
+pub(super) const LIMIT_PERIOD: u64 = 60;
+
+/// The number of unsolicited DatabaseStores we will verify per second, across
+/// all sources.
+const MAX_VERIFICATIONS_PER_SECOND: u32 = 100;
+
+/// Counts events per key over fixed periods, refusing those over a limit.
+pub(super) struct RateLimiter {
+    limit: u32,
+    period: Duration,
+    counts: HashMap<Hash, (Instant, u32)>,
+    last_cleaned: Instant,
+}
+
+impl RateLimiter {
+    pub(super) fn new(limit: u32, period: Duration) -> Self {
+        RateLimiter {
+            limit,
+            period,
+            counts: HashMap::new(),
+            last_cleaned: Instant::now(),
+        }
+    }
+
+    /// Records an event for `key`, returning false if `key` has already reached
+    /// the limit in the current period.
+    pub(super) fn allow(&mut self, key: &Hash, now: Instant) -> bool {
+        let period = self.period;
+        if now.duration_since(self.last_cleaned) >= period {
+            self.counts
+                .retain(|_, (start, _)| now.duration_since(*start) < period);
+            self.last_cleaned = now;
+        }
+
+        let (start, count) = self.counts.entry(key.clone()).or_insert_with(|| (now, 0));
+        if now.duration_since(*start) >= period {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.limit
+    }
+}
+
+/// Counts events across all sources over fixed periods, refusing those over a
+/// limit.
+struct Budget {
+    limit: u32,
+    period: Duration,
+    start: Instant,
+    used: u32,
+}
+
+impl Budget {
+    fn new(limit: u32, period: Duration) -> Self {
+        Budget {
+            limit,
+            period,
+            start: Instant::now(),
+            used: 0,
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.start) >= self.period {
+            self.start = now;
+            self.used = 0;
+        }
+        if self.used < self.limit {
+            self.used += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Counts of the DatabaseStores we dropped before verifying them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StoreLimitStats {
+    /// The source has sent too many stores recently.
+    pub throttled: u64,
+    /// We had already verified as many stores as we will this second.
+    pub over_budget: u64,
+}
+
+/// Decides whether an inbound DatabaseStore is worth verifying.
+pub(super) struct StoreLimiter {
+    per_source: RateLimiter,
+    verifications: Budget,
+    stats: StoreLimitStats,
+}
+
+impl StoreLimiter {
+    pub(super) fn new(max_stores: u32, max_verifications: u32) -> Self {
+        StoreLimiter {
+            per_source: RateLimiter::new(max_stores, Duration::from_secs(LIMIT_PERIOD)),
+            verifications: Budget::new(max_verifications, Duration::from_secs(1)),
+            stats: StoreLimitStats::default(),
+        }
+    }
+
+    pub(super) fn from_config(config: &Config) -> Self {
+        let get = |key: &str, default: u32| {
+            config
+                .get_int(key)
+                .ok()
+                .map(|v| v as u32)
+                .unwrap_or(default)
+        };
+        StoreLimiter::new(
+            get(config::NETDB_LIMITS_STORES, MAX_STORES_PER_PERIOD),
+            get(
+                config::NETDB_LIMITS_VERIFICATIONS,
+                MAX_VERIFICATIONS_PER_SECOND,
+            ),
+        )
+    }
+
+    pub(super) fn stats(&self) -> StoreLimitStats {
+        self.stats
+    }
+
+    /// Returns true if an unsolicited DatabaseStore from `from` should be
+    /// verified and stored. Stores answering our own lookups should not be
+    /// passed through here.
+    pub(super) fn allow(&mut self, from: &Hash, now: Instant) -> bool {
+        if !self.per_source.allow(from, now) {
+            self.stats.throttled += 1;
+            false
+        } else if !self.verifications.allow(now) {
+            self.stats.over_budget += 1;
+            false
+        } else {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimiter, StoreLimitStats, StoreLimiter, LIMIT_PERIOD};
+    use crate::data::Hash;
+    use crate::router::config::{self, Config};
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let (a, b) = (Hash([1; 32]), Hash([2; 32]));
+        let now = Instant::now();
+
+        assert!(limiter.allow(&a, now));
+        assert!(limiter.allow(&a, now));
+        assert!(!limiter.allow(&a, now));
+        assert!(limiter.allow(&b, now));
+
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.allow(&a, later));
+    }
+
+    #[test]
+    fn store_flood() {
+        let mut limiter = StoreLimiter::new(10, 15);
+        let (flooder, other) = (Hash([1; 32]), Hash([2; 32]));
+        let now = Instant::now();
+
+        // One source flooding us is cut off at its own limit
+        let allowed = (0..100).filter(|_| limiter.allow(&flooder, now)).count();
+        assert_eq!(allowed, 10);
+
+        // Another source is still accepted
+        assert!(limiter.allow(&other, now));
+        assert_eq!(
+            limiter.stats(),
+            StoreLimitStats {
+                throttled: 90,
+                over_budget: 0,
+            }
+        );
+
+        // Verifications across all sources are bounded per second
+        let sources: Vec<_> = (10..20).map(|i| Hash([i; 32])).collect();
+        let allowed = sources.iter().filter(|h| limiter.allow(h, now)).count();
+        assert_eq!(allowed, 4);
+        assert_eq!(limiter.stats().over_budget, 6);
+
+        // Both limits recover over time
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.allow(&other, later));
+        let later = now + Duration::from_secs(LIMIT_PERIOD);
+        assert!(limiter.allow(&flooder, later));
+    }
+
+    #[test]
+    fn from_config() {
+        let mut cfg = Config::default();
+        cfg.set(config::NETDB_LIMITS_STORES, 1).unwrap();
+        let mut limiter = StoreLimiter::from_config(&cfg);
+        let now = Instant::now();
+        assert!(limiter.allow(&Hash([1; 32]), now));
+        assert!(!limiter.allow(&Hash([1; 32]), now));
+    }
+}
@@ -1,7 +1,7 @@
 use futures::{
     future::{self, Either},
     sync::oneshot,
-    Future, Sink,
+    Async, Future, Poll, Sink,
 };
 use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
@@ -15,13 +15,20 @@ use super::{
 };
 use crate::data::{Hash, RouterInfo};
 use crate::i2np::{DatabaseLookup, DatabaseLookupType, DatabaseSearchReply, Message};
-use crate::router::Context;
+use crate::router::{config, Context};
 
 /// The time before we give up on a peer and try the next one.
 ///
 /// Much shorter than the message's expire time. Longer than the typical response time of
 /// 1.0 - 1.5 sec, but short enough that we move on to another peer quickly.
-const SINGLE_LOOKUP_TIMEOUT: u64 = 5;
+pub(super) const SINGLE_LOOKUP_TIMEOUT: u64 = 5;
+
+/// The number of floodfills we ask at once, by default.
+const LOOKUP_PARALLELISM: usize = 2;
+/// The most floodfills we ask during a single lookup.
+pub(super) const MAX_LOOKUP_PEERS: usize = 8;
+/// How many DatabaseSearchReplies in a row we follow towards closer floodfills.
+const MAX_LOOKUP_DEPTH: usize = 3;
 
 type LookupFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
 
@@ -84,9 +91,12 @@ fn process_dsr(
         Box::new(future::ok(vec![]))
     } else {
         // Get RouterInfo for peer we queried
-        let from_ri =
-            ctx.netdb
-                .lookup_router_info(dsr.from.clone(), SINGLE_LOOKUP_TIMEOUT * 1000, None);
+        let from_ri = ctx.netdb.lookup_router_info(
+            dsr.from.clone(),
+            SINGLE_LOOKUP_TIMEOUT * 1000,
+            None,
+            false,
+        );
 
         let processed = from_ri.and_then(move |from| {
             // Look up each of the returned peers with the router that sent us the DSR
@@ -98,19 +108,24 @@ fn process_dsr(
                         peer,
                         SINGLE_LOOKUP_TIMEOUT * 1000,
                         Some(from.clone()),
+                        false,
                     )
                 })
                 .collect();
 
             // Collect all lookups that succeed
             future::loop_fn((vec![], peer_lookups), |(mut found, peer_lookups)| {
-                future::select_ok(peer_lookups).and_then(|(ri, remaining)| {
-                    found.push(ri);
-                    if remaining.is_empty() {
-                        Ok(future::Loop::Break(found))
-                    } else {
-                        Ok(future::Loop::Continue((found, remaining)))
+                future::select_ok(peer_lookups).then(|res| match res {
+                    Ok((ri, remaining)) => {
+                        found.push(ri);
+                        if remaining.is_empty() {
+                            Ok(future::Loop::Break(found))
+                        } else {
+                            Ok(future::Loop::Continue((found, remaining)))
+                        }
                     }
+                    // None of the remaining lookups succeeded
+                    Err(_) => Ok(future::Loop::Break(found)),
                 })
             })
         });
@@ -119,6 +134,11 @@ fn process_dsr(
     }
 }
 
+/// Follows DatabaseSearchReplies towards the floodfills closest to a key, asking
+/// up to `parallelism` of them at a time.
+///
+/// Resolves once there is nobody left to ask. Finding the entry is signalled
+/// separately, when it is stored in the netDb.
 struct IterativeLookup {
     ctx: Arc<Context>,
     register_pending: PendingTx,
@@ -126,12 +146,15 @@ struct IterativeLookup {
     rk: Hash,
     from: Hash,
     lookup_type: DatabaseLookupType,
-    to_try: BTreeMap<XorMetric, RouterInfo>,
+    parallelism: usize,
+    /// Peers we can still ask, and how many replies led us to each.
+    to_try: BTreeMap<XorMetric, (RouterInfo, usize)>,
     tried: HashSet<Hash>,
+    queries: Vec<(usize, LookupFuture<Option<DatabaseSearchReply>, Error>)>,
+    chases: Vec<(usize, LookupFuture<Vec<RouterInfo>, Error>)>,
 }
 
 impl IterativeLookup {
-    /// Returns the lookup state and the first peer to try
     fn new(
         ctx: Arc<Context>,
         register_pending: PendingTx,
@@ -139,35 +162,38 @@ impl IterativeLookup {
         from: Hash,
         lookup_type: DatabaseLookupType,
         ffs: Vec<RouterInfo>,
-    ) -> (Self, RouterInfo) {
+        parallelism: usize,
+    ) -> Self {
         let rk = create_routing_key(&key);
 
         let mut to_try = BTreeMap::new();
         for ri in ffs {
-            to_try.insert(XorMetric::for_hash(&ri.router_id.hash(), &rk), ri);
+            to_try.insert(XorMetric::for_hash(&ri.router_id.hash(), &rk), (ri, 0));
         }
 
-        let first = to_try.keys().next().cloned().unwrap();
-        let first = to_try.remove(&first).unwrap();
-
-        (
-            IterativeLookup {
-                ctx,
-                register_pending,
-                key,
-                rk,
-                from,
-                lookup_type,
-                to_try,
-                tried: HashSet::new(),
-            },
-            first,
-        )
+        IterativeLookup {
+            ctx,
+            register_pending,
+            key,
+            rk,
+            from,
+            lookup_type,
+            parallelism: parallelism.max(1),
+            to_try,
+            tried: HashSet::new(),
+            queries: vec![],
+            chases: vec![],
+        }
     }
 
-    fn process_peer(mut self, peer: RouterInfo) -> LookupFuture<Self, ()> {
+    fn query_peer(&mut self, peer: RouterInfo, depth: usize) {
         // Create the lookup
-        let dlm = DatabaseLookup::create_msg(self.key.clone(), self.from.clone(), self.lookup_type);
+        let dlm = DatabaseLookup::create_msg(
+            &self.ctx.msg_ids,
+            self.key.clone(),
+            self.from.clone(),
+            self.lookup_type,
+        );
 
         // Send the lookup
         let peer_hash = peer.router_id.hash();
@@ -179,102 +205,141 @@ impl IterativeLookup {
             peer,
             self.key.clone(),
             dlm,
-        )
-        .or_else(|e| {
-            error!("Error while sending lookup: {}", e);
-            Ok(None)
-        });
-
-        // Process the reply
-        let processed = reply.and_then(move |dsr| {
-            let processed: LookupFuture<_, _> = if let Some(dsr) = dsr {
-                let peer_ris = process_dsr(self.ctx.clone(), &self.tried, dsr);
-
-                let processed = peer_ris.then(move |ret| {
-                    match ret {
-                        Ok(ris) => {
-                            // Update the internal state
-                            for ri in ris {
-                                let hash = ri.router_id.hash();
-                                if !self.tried.contains(&hash) {
-                                    self.to_try.insert(XorMetric::for_hash(&hash, &self.rk), ri);
-                                }
-                            }
-                        }
-                        Err(e) => error!("Error while processing DSR: {}", e),
-                    }
-                    Ok(self)
-                });
-
-                Box::new(processed)
-            } else {
-                Box::new(future::ok(self))
-            };
-            processed
-        });
+        );
+        self.queries.push((depth, reply));
+    }
 
-        Box::new(processed.map_err(|()| unreachable!()))
+    /// Returns true if there is nobody left that we are willing to ask.
+    fn exhausted(&self) -> bool {
+        self.to_try.is_empty() || self.tried.len() >= MAX_LOOKUP_PEERS
     }
 
-    fn select_next_peer(&mut self) -> Option<RouterInfo> {
+    fn select_next_peer(&mut self) -> Option<(RouterInfo, usize)> {
         // Return the next peer to try, if any
+        if self.exhausted() {
+            return None;
+        }
         debug!("{} more peers to try", self.to_try.len());
         let next = self.to_try.keys().next().cloned();
         next.map(|next| self.to_try.remove(&next).unwrap())
     }
 }
 
-fn iterative_lookup(
-    ctx: Arc<Context>,
-    register_pending: PendingTx,
-    key: Hash,
-    from: Hash,
-    lookup_type: DatabaseLookupType,
-    ff: RouterInfo,
-) -> LookupFuture<(), ()> {
-    Box::new(future::loop_fn(
-        IterativeLookup::new(ctx, register_pending, key, from, lookup_type, vec![ff]),
-        move |(lookup, peer)| {
-            lookup.process_peer(peer).and_then(|mut lookup| {
-                if let Some(peer) = lookup.select_next_peer() {
-                    Ok(future::Loop::Continue((lookup, peer)))
-                } else {
-                    // All peers have either timed out or returned DSRs, and we
-                    // have no more peers to try, so we are finished.
-                    Ok(future::Loop::Break(()))
+impl Future for IterativeLookup {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            while self.queries.len() < self.parallelism {
+                match self.select_next_peer() {
+                    Some((peer, depth)) => self.query_peer(peer, depth),
+                    None => break,
                 }
-            })
-        },
-    ))
+            }
+
+            let mut progressed = false;
+
+            let mut i = 0;
+            while i < self.queries.len() {
+                let res = match self.queries[i].1.poll() {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                        continue;
+                    }
+                    Ok(Async::Ready(dsr)) => Ok(dsr),
+                    Err(e) => Err(e),
+                };
+                let (depth, _) = self.queries.swap_remove(i);
+                progressed = true;
+
+                match res {
+                    Ok(Some(dsr)) if depth < MAX_LOOKUP_DEPTH => {
+                        // Find out about the closer peers it suggested
+                        let chase = process_dsr(self.ctx.clone(), &self.tried, dsr);
+                        self.chases.push((depth + 1, chase));
+                    }
+                    Ok(Some(_)) => debug!("Not following DatabaseSearchReply, too deep"),
+                    // Timed out; move on to the next peer
+                    Ok(None) => (),
+                    Err(e) => error!("Error while sending lookup: {}", e),
+                }
+            }
+
+            let mut i = 0;
+            while i < self.chases.len() {
+                let res = match self.chases[i].1.poll() {
+                    Ok(Async::NotReady) => {
+                        i += 1;
+                        continue;
+                    }
+                    Ok(Async::Ready(ris)) => Ok(ris),
+                    Err(e) => Err(e),
+                };
+                let (depth, _) = self.chases.swap_remove(i);
+                progressed = true;
+
+                match res {
+                    Ok(ris) => {
+                        for ri in ris {
+                            let hash = ri.router_id.hash();
+                            if !self.tried.contains(&hash) {
+                                self.to_try
+                                    .insert(XorMetric::for_hash(&hash, &self.rk), (ri, depth));
+                            }
+                        }
+                    }
+                    Err(e) => error!("Error while processing DSR: {}", e),
+                }
+            }
+
+            if self.queries.is_empty() && self.chases.is_empty() && self.exhausted() {
+                // All peers have either timed out or returned DSRs, and we
+                // have no more peers to try, so we are finished.
+                return Ok(Async::Ready(()));
+            } else if !progressed {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
 }
 
-/// Looks up a netDb entry with the given floodfill router.
+/// Looks up a netDb entry, starting with the given floodfill routers and
+/// following their DatabaseSearchReplies towards closer ones.
 ///
-/// If the floodfill does not have the entry, the lookup will time out, as
-/// DatabaseSearchReply messages are not yet handled.
+/// Fails with [`LookupError::NotFound`] once every floodfill we are willing to
+/// ask has replied without the entry or timed out.
 pub fn lookup_db_entry<T: Send + 'static>(
     ctx: Arc<Context>,
     register_pending: PendingTx,
     key: Hash,
     lookup_type: DatabaseLookupType,
-    ff: RouterInfo,
+    ffs: Vec<RouterInfo>,
     pending: &mut PendingLookup<T>,
     timeout_ms: u64,
 ) -> LookupFuture<T, LookupError> {
     let from = ctx.ri.read().unwrap().router_id.hash();
+    let parallelism = ctx
+        .config
+        .read()
+        .unwrap()
+        .get_int(config::NETDB_LOOKUP_PARALLELISM)
+        .map(|n| n as usize)
+        .unwrap_or(LOOKUP_PARALLELISM);
 
     // Set up a channel so we get notified when the RouterInfo arrives
     let (tx_store, rx_store) = oneshot::channel();
     pending.entry(key.clone()).or_default().push(tx_store);
 
     let lookup = rx_store
-        .select2(iterative_lookup(
+        .select2(IterativeLookup::new(
             ctx,
             register_pending,
             key,
             from,
             lookup_type,
-            ff,
+            ffs,
+            parallelism,
         ))
         .then(|res| match res {
             Ok(Either::A((ri, _))) => Box::new(future::ok(ri)),
@@ -296,39 +361,326 @@ pub fn lookup_db_entry<T: Send + 'static>(
     )
 }
 
-/// Explores the netDb for a given key.
+/// Asks the floodfill `ff` for routers close to `key`, other than those in
+/// `exclude`, returning the hashes it suggests.
+///
+/// The floodfill replies with a DatabaseSearchReply listing routers that are not
+/// floodfills. The caller is responsible for fetching the ones it doesn't know.
 pub fn explore_netdb(
     ctx: Arc<Context>,
     register_pending: PendingTx,
     key: Hash,
     ff: RouterInfo,
-    timeout_ms: u64,
-) -> LookupFuture<(), LookupError> {
+    exclude: Vec<Hash>,
+) -> LookupFuture<Vec<Hash>, Error> {
     let from = ctx.ri.read().unwrap().router_id.hash();
+    let dlm = Message::database_lookup(
+        &ctx.msg_ids,
+        DatabaseLookup::new(key.clone(), from, DatabaseLookupType::Exploratory).excluding(exclude),
+    );
 
-    let explorer = iterative_lookup(
-        ctx,
-        register_pending,
-        key,
-        from,
-        DatabaseLookupType::Exploratory,
-        ff,
+    debug!(
+        "Sending exploratory lookup to peer {}:\n{}",
+        ff.router_id.hash(),
+        dlm
+    );
+    Box::new(
+        wait_for_search_reply(&ctx, register_pending, ff, key, dlm)
+            .map(|dsr| dsr.map(|dsr| dsr.peers).unwrap_or_default()),
     )
-    .then(|_| {
-        // No more peers to explore, so we are done
-        debug!("Finished exploratory lookup");
-        Ok(())
-    });
+}
 
-    Box::new(
-        Timeout::new(explorer, Duration::from_millis(timeout_ms)).map_err(|e| {
-            if e.is_inner() {
-                e.into_inner().unwrap()
-            } else if e.is_elapsed() {
-                LookupError::TimedOut
-            } else {
-                LookupError::TimerFailure
+#[cfg(test)]
+mod tests {
+    use futures::{future, sync::mpsc, try_ready, Async, Future, Poll, Stream};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use tokio::runtime::Runtime;
+
+    use crate::data::{Hash, RouterCaps, RouterInfo, RouterInfoBuilder, RouterSecretKeys};
+    use crate::i2np::{
+        DatabaseLookupType, DatabaseSearchReply, DatabaseStore, Message, MessageIdGenerator,
+        MessagePayload,
+    };
+    use crate::netdb::{
+        client::Client,
+        errors::{Error, LookupError},
+        mock::loopback_engine,
+        pending::FloodfillLookupStats,
+        Engine,
+    };
+    use crate::router::mock::LoopbackPeers;
+
+    /// A floodfill that answers DatabaseLookups from a fixed set of entries, and
+    /// ignores exploratory lookups.
+    struct MockFloodfill {
+        hash: Hash,
+        ib_rx: mpsc::Receiver<(Hash, Message)>,
+        peers: LoopbackPeers,
+        entries: HashMap<Hash, RouterInfo>,
+        /// The peers to suggest for entries we don't have, or `None` to not reply.
+        suggest: Option<Vec<Hash>>,
+    }
+
+    impl MockFloodfill {
+        fn new(peers: &LoopbackPeers, suggest: Option<Vec<Hash>>) -> (Self, RouterInfo) {
+            let rsk = RouterSecretKeys::new();
+            let ri = RouterInfoBuilder::new(rsk.rid)
+                .caps(RouterCaps::default().floodfill(true))
+                .sign(&rsk.signing_private_key);
+            let hash = ri.router_id.hash();
+
+            let (ib_tx, ib_rx) = mpsc::channel(16);
+            peers.lock().unwrap().insert(hash.clone(), ib_tx);
+
+            let ff = MockFloodfill {
+                hash,
+                ib_rx,
+                peers: peers.clone(),
+                entries: HashMap::new(),
+                suggest,
+            };
+            (ff, ri)
+        }
+
+        fn with_entry(mut self, ri: RouterInfo) -> Self {
+            self.entries.insert(ri.router_id.hash(), ri);
+            self
+        }
+    }
+
+    impl Future for MockFloodfill {
+        type Item = ();
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<(), ()> {
+            while let Some((from, msg)) = try_ready!(self.ib_rx.poll()) {
+                let dl = match msg.payload {
+                    MessagePayload::DatabaseLookup(dl) => dl,
+                    _ => continue,
+                };
+                if let DatabaseLookupType::Exploratory = dl.lookup_type() {
+                    continue;
+                }
+
+                let payload = match (self.entries.get(dl.key()), &self.suggest) {
+                    (Some(ri), _) => {
+                        MessagePayload::DatabaseStore(DatabaseStore::from_ri(ri.clone(), None))
+                    }
+                    (None, Some(peers)) => {
+                        MessagePayload::DatabaseSearchReply(DatabaseSearchReply {
+                            key: dl.key().clone(),
+                            peers: peers.clone(),
+                            from: self.hash.clone(),
+                        })
+                    }
+                    (None, None) => continue,
+                };
+                let tx = self.peers.lock().unwrap().get(&from).cloned();
+                if let Some(mut tx) = tx {
+                    let msg = Message::from_payload(&MessageIdGenerator::default(), payload);
+                    tx.try_send((self.hash.clone(), msg)).unwrap();
+                }
             }
-        }),
-    )
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn router() -> RouterInfo {
+        let rsk = RouterSecretKeys::new();
+        RouterInfoBuilder::new(rsk.rid).sign(&rsk.signing_private_key)
+    }
+
+    /// Starts a netDb engine that knows the given floodfills, returning it and
+    /// a client for it.
+    fn requester(
+        rt: &mut Runtime,
+        peers: &LoopbackPeers,
+        ffs: &[&RouterInfo],
+    ) -> (Arc<Mutex<Engine>>, Client) {
+        let (mut engine, client, _) = loopback_engine(peers, false);
+        for ff in ffs {
+            engine
+                .netdb
+                .store_router_info(ff.router_id.hash(), (*ff).clone(), false)
+                .unwrap();
+        }
+
+        // Keep hold of the engine so we can check its counters
+        let engine = Arc::new(Mutex::new(engine));
+        let polled = engine.clone();
+        rt.spawn(future::poll_fn(move || polled.lock().unwrap().poll()));
+        (engine, client)
+    }
+
+    fn lookup(
+        rt: &mut Runtime,
+        client: &Client,
+        key: &Hash,
+        timeout_ms: u64,
+    ) -> Result<Hash, LookupError> {
+        match rt.block_on(client.lookup_router_info(key.clone(), timeout_ms, None, false)) {
+            Ok(ri) => Ok(ri.router_id.hash()),
+            Err(Error::Lookup(e)) => Err(e),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn found() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let target = router();
+        let key = target.router_id.hash();
+
+        let (ff, ff_ri) = MockFloodfill::new(&peers, Some(vec![]));
+        rt.spawn(ff.with_entry(target));
+        let (engine, client) = requester(&mut rt, &peers, &[&ff_ri]);
+
+        assert_eq!(lookup(&mut rt, &client, &key, 5_000), Ok(key.clone()));
+        assert_eq!(
+            engine
+                .lock()
+                .unwrap()
+                .floodfill_lookup_stats(&ff_ri.router_id.hash()),
+            FloodfillLookupStats {
+                found: 1,
+                not_found: 0,
+                timed_out: 0,
+            }
+        );
+
+        // Now that we have it, we don't ask again
+        assert_eq!(lookup(&mut rt, &client, &key, 5_000), Ok(key));
+        assert_eq!(
+            engine
+                .lock()
+                .unwrap()
+                .floodfill_lookup_stats(&ff_ri.router_id.hash())
+                .found,
+            1
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let key = router().router_id.hash();
+
+        let (ff1, ff1_ri) = MockFloodfill::new(&peers, Some(vec![]));
+        let (ff2, ff2_ri) = MockFloodfill::new(&peers, Some(vec![]));
+        rt.spawn(ff1);
+        rt.spawn(ff2);
+        let (engine, client) = requester(&mut rt, &peers, &[&ff1_ri, &ff2_ri]);
+
+        // Both floodfills are asked, and neither has it
+        assert_eq!(
+            lookup(&mut rt, &client, &key, 5_000),
+            Err(LookupError::NotFound)
+        );
+        let engine = engine.lock().unwrap();
+        for ff in &[ff1_ri, ff2_ri] {
+            assert_eq!(
+                engine.floodfill_lookup_stats(&ff.router_id.hash()),
+                FloodfillLookupStats {
+                    found: 0,
+                    not_found: 1,
+                    timed_out: 0,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn recently_failed() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let key = router().router_id.hash();
+
+        let (ff, ff_ri) = MockFloodfill::new(&peers, Some(vec![]));
+        rt.spawn(ff);
+        let (engine, client) = requester(&mut rt, &peers, &[&ff_ri]);
+        let not_found = || {
+            engine
+                .lock()
+                .unwrap()
+                .floodfill_lookup_stats(&ff_ri.router_id.hash())
+                .not_found
+        };
+
+        assert_eq!(
+            lookup(&mut rt, &client, &key, 5_000),
+            Err(LookupError::NotFound)
+        );
+        assert_eq!(not_found(), 1);
+
+        // Asking again fails fast, without bothering the floodfill
+        assert_eq!(
+            lookup(&mut rt, &client, &key, 5_000),
+            Err(LookupError::RecentlyFailed)
+        );
+        assert_eq!(not_found(), 1);
+
+        // Unless we insist
+        match rt.block_on(client.lookup_router_info(key.clone(), 5_000, None, true)) {
+            Err(Error::Lookup(e)) => assert_eq!(e, LookupError::NotFound),
+            Ok(_) => panic!("Unexpected success"),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+        assert_eq!(not_found(), 2);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let key = router().router_id.hash();
+
+        let (ff, ff_ri) = MockFloodfill::new(&peers, None);
+        rt.spawn(ff);
+        let (_engine, client) = requester(&mut rt, &peers, &[&ff_ri]);
+
+        assert_eq!(
+            lookup(&mut rt, &client, &key, 500),
+            Err(LookupError::TimedOut)
+        );
+    }
+
+    #[test]
+    fn chase_then_found() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let target = router();
+        let key = target.router_id.hash();
+
+        // We only know the first floodfill, which points us to the second
+        let (ff2, ff2_ri) = MockFloodfill::new(&peers, Some(vec![]));
+        let ff2_hash = ff2_ri.router_id.hash();
+        let (ff1, ff1_ri) = MockFloodfill::new(&peers, Some(vec![ff2_hash.clone()]));
+        rt.spawn(ff1.with_entry(ff2_ri));
+        rt.spawn(ff2.with_entry(target));
+        let (engine, client) = requester(&mut rt, &peers, &[&ff1_ri]);
+
+        assert_eq!(lookup(&mut rt, &client, &key, 15_000), Ok(key));
+        let engine = engine.lock().unwrap();
+
+        // The first floodfill didn't have the entry, but gave us the second
+        assert_eq!(
+            engine.floodfill_lookup_stats(&ff1_ri.router_id.hash()),
+            FloodfillLookupStats {
+                found: 1,
+                not_found: 1,
+                timed_out: 0,
+            }
+        );
+        assert_eq!(
+            engine.floodfill_lookup_stats(&ff2_hash),
+            FloodfillLookupStats {
+                found: 1,
+                not_found: 0,
+                timed_out: 0,
+            }
+        );
+    }
 }
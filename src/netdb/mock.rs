@@ -1,15 +1,31 @@
-use futures::{sync::mpsc, try_ready, Future, Poll, Stream};
+use futures::{sync::mpsc, try_ready, Async, Future, Poll, Stream};
+use rand::thread_rng;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
-use super::client::Query;
+use super::{
+    client::{Client, Query},
+    select::select_peers,
+    Engine, LocalNetworkDatabase,
+};
 use crate::{
-    data::{Hash, RouterInfo},
+    crypto::pool::Pools,
+    data::{
+        Hash, I2PString, RouterAddress, RouterCaps, RouterInfo, RouterInfoBuilder, RouterSecretKeys,
+    },
+    i2np::MessageIdGenerator,
     netdb::errors::LookupError,
-    router::Context,
+    router::{
+        config::{self, Config},
+        mock::{mock_context, mock_router_info, LoopbackCommSystem, LoopbackPeers},
+        profiles::Profiles,
+        Context,
+    },
 };
 
 pub struct MockNetDb {
+    our_hash: Hash,
     client_rx: mpsc::UnboundedReceiver<Query>,
     ri_ds: HashMap<Hash, RouterInfo>,
 }
@@ -17,6 +33,7 @@ pub struct MockNetDb {
 impl MockNetDb {
     pub fn new(ctx: Arc<Context>, client_rx: mpsc::UnboundedReceiver<Query>) -> Self {
         MockNetDb {
+            our_hash: ctx.keys.rid.hash(),
             client_rx,
             ri_ds: HashMap::new(),
         }
@@ -33,12 +50,89 @@ impl Future for MockNetDb {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
-            if let Some(Query::LookupRouterInfo(key, timeout_ms, from_peer, ret)) =
-                try_ready!(self.client_rx.poll())
-            {
-                ret.send(self.ri_ds.get(&key).cloned().ok_or(LookupError::NotFound))
-                    .unwrap()
+            match try_ready!(self.client_rx.poll()) {
+                Some(Query::LookupRouterInfo(key, timeout_ms, from_peer, force, ret)) => ret
+                    .send(self.ri_ds.get(&key).cloned().ok_or(LookupError::NotFound))
+                    .unwrap(),
+                Some(Query::SelectPeers(criteria, ret)) => {
+                    let _ = ret.send(select_peers(
+                        self.ri_ds.values(),
+                        &criteria,
+                        &self.our_hash,
+                        &Profiles::default(),
+                        |_| false,
+                        SystemTime::now(),
+                        &mut thread_rng(),
+                    ));
+                }
+                Some(_) => (),
+                None => return Ok(Async::Ready(())),
             }
         }
     }
 }
+
+/// Creates a netDb engine for a new router, reachable by the other routers
+/// sharing `peers`.
+pub fn loopback_engine(peers: &LoopbackPeers, floodfill: bool) -> (Engine, Client, RouterInfo) {
+    let keys = RouterSecretKeys::new();
+    let ri = RouterInfoBuilder::new(keys.rid.clone())
+        .caps(RouterCaps::default().floodfill(floodfill))
+        .sign(&keys.signing_private_key);
+    let hash = ri.router_id.hash();
+
+    let mut cfg = Config::default();
+    cfg.set(config::RESEED_ENABLE, false).unwrap();
+    cfg.set(config::NETDB_FLOODFILL, floodfill).unwrap();
+
+    let (pending_tx, pending_rx) = mpsc::channel(1024);
+    let (ib_tx, ib_rx) = mpsc::channel(1024);
+    let (client_tx, client_rx) = mpsc::unbounded();
+    let client = Client::new(client_tx);
+    peers.lock().unwrap().insert(hash.clone(), ib_tx);
+
+    let ctx = Arc::new(Context {
+        config: RwLock::new(cfg),
+        keys,
+        ri: Arc::new(RwLock::new(ri.clone())),
+        msg_ids: MessageIdGenerator::default(),
+        netdb: client.clone(),
+        comms: Arc::new(RwLock::new(LoopbackCommSystem::new(hash, peers.clone()))),
+        pools: Pools::new(),
+        profiles: Profiles::default(),
+    });
+
+    let engine = Engine::new(ctx, pending_tx, pending_rx, ib_rx, client_rx);
+    (engine, client, ri)
+}
+
+/// Returns a netDb containing `floodfills` floodfills and `others` other routers,
+/// and the hashes of the floodfills.
+pub fn populated_netdb(floodfills: usize, others: usize) -> (LocalNetworkDatabase, Vec<Hash>) {
+    let (tx, _) = mpsc::channel(0);
+    let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+
+    let mut ff_hashes = vec![];
+    for i in 0..floodfills + others {
+        let ri = mock_router_info(RouterCaps::default().floodfill(i < floodfills));
+        let key = ri.router_id.hash();
+        if ri.is_floodfill() {
+            ff_hashes.push(key.clone());
+        }
+        netdb.store_router_info(key, ri, false).unwrap();
+    }
+    (netdb, ff_hashes)
+}
+
+/// Gives the netDb's own RouterInfo an NTCP2 address on the given port, so that it
+/// can be published.
+pub fn set_address(netdb: &LocalNetworkDatabase, port: u16) {
+    let addr = RouterAddress::new(
+        &I2PString::new("NTCP2"),
+        format!("203.0.113.1:{}", port).parse().unwrap(),
+    );
+    let mut ri = netdb.ctx.ri.write().unwrap();
+    *ri = RouterInfoBuilder::from_router_info(&ri)
+        .addresses(vec![addr])
+        .sign(&netdb.ctx.keys.signing_private_key);
+}
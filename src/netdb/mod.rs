@@ -1,34 +1,71 @@
 //! The I2P network database.
 
-use chrono::offset::Utc;
 use futures::{
     future,
     sync::{mpsc, oneshot},
     Async, Future, Poll, Stream,
 };
-use rand::{thread_rng, Rng};
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use rand::thread_rng;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::{
     executor::{spawn, DefaultExecutor},
     timer::Delay,
 };
 
-use crate::data::{Hash, LeaseSet, RouterInfo, NET_ID};
+use crate::data::{Hash, I2PDate, LeaseSet, RouterInfo, NET_ID};
 use crate::i2np::{
-    DatabaseLookupType, DatabaseSearchReply, DatabaseStoreData, Message, MessagePayload,
+    DatabaseLookupType, DatabaseSearchReply, DatabaseStore, DatabaseStoreData, Message,
+    MessagePayload,
 };
-use crate::router::{config, Context};
+use crate::router::{config, profiles::Profiles, Context};
 
 pub mod client;
 mod errors;
+mod expire;
+mod explore;
+pub mod export;
+mod flood;
+mod kademlia;
+mod leaseset;
+mod limits;
 mod lookup;
 pub mod mock;
+mod negative;
+mod pending;
+pub mod persist;
+mod publish;
+mod referral;
 pub mod reseed;
-
-use errors::{LookupError, StoreError};
+mod responder;
+mod select;
+mod stats;
+mod store;
+mod sybil;
+mod verify;
+
+use errors::{LookupError, RejectionStats};
+use expire::{EvictionStats, Expiration};
+use explore::Explorer;
+use flood::{Flooder, FloodfillMode};
+use kademlia::FloodfillIndex;
+use leaseset::{LeaseSetStore, StoredLeaseSet};
+use limits::StoreLimiter;
+use negative::FailedLookups;
+use pending::{FloodfillLookupStats, PendingLookups};
+use publish::{PublishStats, Publisher};
+use referral::ReferralFetcher;
+use responder::LookupResponder;
+use stats::RecentCount;
+use store::RouterInfoStore;
+use sybil::SelectionPolicy;
+use verify::{Failure, Outcome, Stored, Verifier, VerifyStats};
+
+pub use errors::StoreError;
+pub use select::{select_peers, FloodfillPolicy, PeerCriteria};
+pub use stats::NetDbStats;
 
 /// Maximum age of a local RouterInfo.
 const ROUTER_INFO_EXPIRATION: u64 = 27 * 60 * 60;
@@ -37,18 +74,23 @@ const ROUTER_INFO_EXPIRATION: u64 = 27 * 60 * 60;
 const EXPIRE_RI_INTERVAL: u64 = 5 * 60;
 /// Interval on which we expire LeaseSets.
 const EXPIRE_LS_INTERVAL: u64 = 60;
+/// Interval on which we write new RouterInfos to disk.
+const FLUSH_RI_INTERVAL: u64 = 60;
+/// Interval on which we check whether to publish our RouterInfo, or retry a
+/// publication.
+const PUBLISH_CHECK_INTERVAL: u64 = 5;
 /// If we know fewer than this many routers, we will reseed.
 const MINIMUM_ROUTERS: usize = 50;
 /// If we know fewer than this many routers, we won't expire RouterInfos.
 const KEEP_ROUTERS: usize = 150;
-/// Don't explore the network more often than this.
-const EXPLORE_MIN_INTERVAL: u64 = 30;
-/// Explore the network at least this often.
-const EXPLORE_MAX_INTERVAL: u64 = 15 * 60;
-/// Explore quickly if we have fewer than this many routers.
-const EXPLORE_MIN_ROUTERS: usize = 250;
-
-type PendingLookups = HashMap<(Hash, Hash), oneshot::Sender<DatabaseSearchReply>>;
+/// How long before UTC midnight we also target the floodfills closest to the
+/// next day's routing keys, so that entries stored just before the rotation can
+/// still be found just after it.
+const ROUTING_KEY_ROTATION_MARGIN: u64 = 10 * 60;
+/// How long to spend finding the RouterInfo of a peer we need to reply or flood to,
+/// in milliseconds.
+const REPLY_LOOKUP_TIMEOUT: u64 = 10 * 1000;
+
 pub(crate) type PendingTx = mpsc::Sender<(Hash, Hash, oneshot::Sender<DatabaseSearchReply>)>;
 type PendingRx = mpsc::Receiver<(Hash, Hash, oneshot::Sender<DatabaseSearchReply>)>;
 
@@ -62,6 +104,14 @@ enum EngineState {
 pub struct Engine {
     state: Option<EngineState>,
     netdb: LocalNetworkDatabase,
+    floodfill: FloodfillMode,
+    responder: LookupResponder,
+    store_limiter: StoreLimiter,
+    flooder: Flooder,
+    explorer: Explorer,
+    referrals: ReferralFetcher,
+    publisher: Publisher,
+    verifier: Verifier,
     ctx: Arc<Context>,
     active_reseed: Option<oneshot::SpawnHandle<(), ()>>,
     pending_lookups: PendingLookups,
@@ -71,7 +121,9 @@ pub struct Engine {
     client_rx: mpsc::UnboundedReceiver<client::Query>,
     expire_ri_timer: Delay,
     expire_ls_timer: Delay,
+    flush_ri_timer: Delay,
     explore_timer: Delay,
+    publish_timer: Delay,
 }
 
 impl Engine {
@@ -82,21 +134,173 @@ impl Engine {
         ib_rx: mpsc::Receiver<(Hash, Message)>,
         client_rx: mpsc::UnboundedReceiver<client::Query>,
     ) -> Self {
+        let (floodfill, explorer, publisher, responder, store_limiter) = {
+            let config = ctx.config.read().unwrap();
+            let floodfill = config.get_bool(config::NETDB_FLOODFILL).unwrap_or(false);
+            (
+                floodfill,
+                Explorer::from_config(&config, floodfill),
+                Publisher::from_config(&config),
+                LookupResponder::from_config(ctx.keys.rid.hash(), &config),
+                StoreLimiter::from_config(&config),
+            )
+        };
+        let pending_lookups = PendingLookups::new(ctx.profiles.clone());
+
         Engine {
             state: Some(EngineState::CheckReseed),
             netdb: LocalNetworkDatabase::new(ctx.clone(), register_pending.clone()),
+            floodfill: FloodfillMode::new(floodfill, false, Instant::now()),
+            responder,
+            store_limiter,
+            flooder: Flooder::new(ctx.keys.rid.hash()),
+            explorer,
+            referrals: ReferralFetcher::default(),
+            publisher,
+            verifier: Verifier::new(ctx.keys.rid.hash()),
             ctx,
             active_reseed: None,
-            pending_lookups: HashMap::new(),
+            pending_lookups,
             register_pending,
             pending_rx,
             ib_rx,
             client_rx,
             expire_ri_timer: Delay::new(Instant::now() + Duration::from_secs(EXPIRE_RI_INTERVAL)),
             expire_ls_timer: Delay::new(Instant::now() + Duration::from_secs(EXPIRE_LS_INTERVAL)),
+            flush_ri_timer: Delay::new(Instant::now() + Duration::from_secs(FLUSH_RI_INTERVAL)),
             explore_timer: Delay::new(Instant::now() + Duration::from_secs(0)),
+            publish_timer: Delay::new(Instant::now() + Duration::from_secs(0)),
+        }
+    }
+
+    /// Keeps serving as a floodfill for a while if the RouterInfo we published
+    /// before this start had the floodfill capability, even if we are no longer
+    /// configured as a floodfill.
+    pub fn was_floodfill(mut self, was_floodfill: bool) -> Self {
+        let enabled = self.floodfill == FloodfillMode::Enabled;
+        self.floodfill = FloodfillMode::new(enabled, was_floodfill, Instant::now());
+        if let FloodfillMode::Retiring(_) = self.floodfill {
+            info!("No longer configured as a floodfill, retiring");
+        }
+        self
+    }
+
+    /// Returns how the floodfill `ff` has answered the lookups we sent it.
+    pub fn floodfill_lookup_stats(&self, ff: &Hash) -> FloodfillLookupStats {
+        self.pending_lookups.stats(ff)
+    }
+
+    /// Returns how the publications of our RouterInfo have gone.
+    pub fn publish_stats(&self) -> PublishStats {
+        self.publisher.stats()
+    }
+
+    /// Returns how the verifications of our stores have gone.
+    pub fn verify_stats(&self) -> VerifyStats {
+        self.verifier.stats()
+    }
+
+    /// Returns the RouterInfos we currently know, for exporting.
+    pub fn router_infos(&self) -> Vec<Arc<RouterInfo>> {
+        self.netdb.ri_ds.snapshot()
+    }
+
+    /// Validates and stores an entry sent to us by `from`, acknowledging and
+    /// flooding it if necessary.
+    fn handle_store(&mut self, from: Hash, ds: DatabaseStore) {
+        let stored = match &ds.data {
+            DatabaseStoreData::RI(ri) => self
+                .netdb
+                .store_router_info(ds.key.clone(), ri.clone(), false)
+                .map(|_| ()),
+            DatabaseStoreData::LS(ls) => self
+                .netdb
+                .store_any_lease_set(ds.key.clone(), StoredLeaseSet::LS(ls.clone()))
+                .map(|_| ()),
+            DatabaseStoreData::LS2(ls) => self
+                .netdb
+                .store_any_lease_set(ds.key.clone(), StoredLeaseSet::LS2(ls.clone()))
+                .map(|_| ()),
+            DatabaseStoreData::EncryptedLS2(els) => self
+                .netdb
+                .store_any_lease_set(ds.key.clone(), StoredLeaseSet::EncryptedLS2(els.clone()))
+                .map(|_| ()),
+        };
+
+        match stored {
+            Ok(()) => {
+                // Acknowledge the store, and flood it if we are a
+                // floodfill
+                let now = Instant::now();
+                let flood = self.floodfill.is_serving(now);
+                let msgs = self.flooder.stored(&self.netdb, &from, &ds, flood, now);
+
+                // Any messages after the ack are floods, which we verify later
+                if msgs.len() > 1 {
+                    let mut stored_to: Vec<_> =
+                        msgs[1..].iter().map(|(to, _)| to.clone()).collect();
+                    stored_to.push(from);
+                    self.verifier.stored(&ds, Stored::Flooded, stored_to, now);
+                }
+
+                for (to, msg) in msgs {
+                    self.send_message(to, msg);
+                }
+            }
+            Err(e) => debug!(
+                "Rejected DatabaseStore from {} at key {}: {}",
+                from, ds.key, e
+            ),
         }
     }
+
+    /// Handles the outcome of verifying one of our stores, storing the entry
+    /// again to other floodfills if it couldn't be found.
+    fn verified(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Verified => (),
+            Outcome::Failed(failure) => self.verify_failed(failure),
+        }
+    }
+
+    fn verify_failed(&mut self, failure: Failure) {
+        match failure.stored {
+            Stored::OurRouterInfo => self.publisher.verify_failed(failure.tried),
+            Stored::Flooded => {
+                debug!("Flooding the entry at key {} again", failure.key);
+                for (to, msg) in self
+                    .flooder
+                    .reflood(&self.netdb, &failure.key, &failure.tried)
+                {
+                    self.send_message(to, msg);
+                }
+            }
+        }
+    }
+
+    /// Sends a reply, ack or flood to a peer, looking up the recipient's
+    /// RouterInfo first if necessary.
+    fn send_message(&mut self, to: Hash, msg: Message) {
+        let comms = self.ctx.comms.clone();
+        let send = self
+            .netdb
+            .lookup_router_info(&to, REPLY_LOOKUP_TIMEOUT, None, false)
+            .map_err(move |e| debug!("Can't find RouterInfo for {}: {}", to, e))
+            .and_then(move |ri| {
+                let msg_type = msg.message_type();
+                let res = comms.read().unwrap().send(ri, msg);
+                match res {
+                    Ok(f) => future::Either::A(
+                        f.map_err(move |e| debug!("Failed to send {:?}: {}", msg_type, e)),
+                    ),
+                    Err(_) => {
+                        debug!("No path for {:?}", msg_type);
+                        future::Either::B(future::err(()))
+                    }
+                }
+            });
+        spawn(send);
+    }
 }
 
 impl Future for Engine {
@@ -115,19 +319,13 @@ impl Future for Engine {
                     }
 
                     // Fire off a new reseed if we need to
-                    let enabled = self
-                        .ctx
-                        .config
-                        .read()
-                        .unwrap()
-                        .get_bool(config::RESEED_ENABLE)
-                        .unwrap();
-                    if enabled
+                    let config = self.ctx.config.read().unwrap();
+                    if config.get_bool(config::RESEED_ENABLE).unwrap()
                         && self.active_reseed.is_none()
                         && self.netdb.known_routers() < MINIMUM_ROUTERS
                     {
                         self.active_reseed = Some(oneshot::spawn(
-                            reseed::HttpsReseeder::new(self.ctx.netdb.clone()),
+                            reseed::from_config(self.ctx.netdb.clone(), &config),
                             &DefaultExecutor::current(),
                         ));
                     }
@@ -140,54 +338,87 @@ impl Future for Engine {
                         if self.netdb.known_routers() >= KEEP_ROUTERS {
                             self.netdb.expire_router_infos(Some(self.ctx.clone()));
                         }
+                        debug!("DatabaseLookups received: {:?}", self.responder.stats());
+                        debug!("DatabaseStores dropped: {:?}", self.store_limiter.stats());
+                        debug!("Exploration: {:?}", self.explorer.stats());
+                        debug!("Referred routers: {:?}", self.referrals.stats());
+                        debug!("Publication: {:?}", self.publisher.stats());
+                        debug!("Store verification: {:?}", self.verifier.stats());
+                        debug!("Evicted from netDb: {:?}", self.netdb.evictions);
+                        debug!("Rejected RouterInfos: {:?}", self.netdb.rejections);
+                        debug!("NetDb: {:?}", self.netdb.stats(Instant::now()));
+                        debug!(
+                            "Floodfill responses to our lookups: {:?}",
+                            self.pending_lookups.total_stats()
+                        );
+                        if self.floodfill.is_serving(Instant::now()) {
+                            let stats = self.flooder.stats();
+                            let lookups = self.responder.stats();
+                            info!(
+                                "Floodfill: {} stores accepted, {} floods sent, {} lookups served",
+                                stats.stores_accepted,
+                                stats.floods_sent,
+                                lookups.hits + lookups.misses
+                            );
+                        }
                         // Reset timer
                         self.expire_ri_timer =
                             Delay::new(Instant::now() + Duration::from_secs(EXPIRE_RI_INTERVAL));
                     }
 
                     if let Ok(Async::Ready(())) = self.expire_ls_timer.poll() {
-                        // Expire LeaseSets
+                        // Expire LeaseSets, and lookups that won't be answered
                         self.netdb.expire_lease_sets();
+                        self.pending_lookups.expire(Instant::now());
                         // Reset timer
                         self.expire_ls_timer =
                             Delay::new(Instant::now() + Duration::from_secs(EXPIRE_LS_INTERVAL));
                     }
 
+                    if let Ok(Async::Ready(())) = self.flush_ri_timer.poll() {
+                        // Write new RouterInfos to disk
+                        self.netdb.flush();
+                        // Reset timer
+                        self.flush_ri_timer =
+                            Delay::new(Instant::now() + Duration::from_secs(FLUSH_RI_INTERVAL));
+                    }
+
                     if let Ok(Async::Ready(())) = self.explore_timer.poll() {
-                        // Pick a random key to search for
-                        let mut key = Hash([0u8; 32]);
-                        thread_rng().fill(&mut key.0);
+                        // Fire off an exploration round
+                        self.explorer
+                            .explore(&self.ctx, &self.register_pending, &self.netdb);
 
-                        if let Some(ff) = self.netdb.select_closest_ff(&key) {
-                            debug!("Exploring netDB for RouterInfo with key {}", key);
+                        // Reset timer
+                        let interval = self.explorer.interval(self.netdb.known_routers());
+                        self.explore_timer = Delay::new(Instant::now() + interval);
+                    }
 
-                            // Fire off an exploration job
-                            debug!(
-                                "Known routers before exploring: {}",
-                                self.netdb.known_routers()
-                            );
-                            let explore = lookup::explore_netdb(
-                                self.ctx.clone(),
-                                self.register_pending.clone(),
-                                key,
-                                ff,
-                                30 * 1000,
-                            );
-                            spawn(
-                                explore
-                                    .map(|_| ())
-                                    .map_err(|e| error!("Error while exploring: {}", e)),
-                            );
+                    // Fetch any new routers that exploration or other replies
+                    // have found
+                    self.explorer.poll(&mut self.netdb);
+                    self.referrals.poll(&mut self.netdb);
+
+                    if let Ok(Async::Ready(())) = self.publish_timer.poll() {
+                        // Publish our RouterInfo if necessary
+                        let now = Instant::now();
+                        // Verify earlier stores, and store them again if they
+                        // couldn't be found. Republications of our RouterInfo
+                        // are sent by the publisher tick that follows.
+                        let (lookups, failures) = self.verifier.tick(&self.netdb, now);
+                        for (to, msg) in lookups {
+                            self.send_message(to, msg);
+                        }
+                        for failure in failures {
+                            self.verify_failed(failure);
+                        }
+
+                        for (to, msg) in self.publisher.tick(&self.netdb, now) {
+                            self.send_message(to, msg);
                         }
 
                         // Reset timer
-                        let interval = if self.netdb.known_routers() < EXPLORE_MIN_ROUTERS {
-                            EXPLORE_MIN_INTERVAL
-                        } else {
-                            EXPLORE_MAX_INTERVAL
-                        };
-                        self.explore_timer =
-                            Delay::new(Instant::now() + Duration::from_secs(interval));
+                        self.publish_timer =
+                            Delay::new(now + Duration::from_secs(PUBLISH_CHECK_INTERVAL));
                     }
 
                     EngineState::Messages
@@ -196,7 +427,7 @@ impl Future for Engine {
                     // First update the pending lookup table
                     while let Async::Ready(f) = self.pending_rx.poll()? {
                         if let Some((from, key, tx)) = f {
-                            self.pending_lookups.insert((from, key), tx);
+                            self.pending_lookups.register(from, key, tx, Instant::now());
                         } else {
                             // pending_rx.poll() returned None, so we are done
                             return Ok(Async::Ready(()));
@@ -225,22 +456,27 @@ impl Future for Engine {
                     // Handle the network message
                     if let Some((from, msg)) = next_ib {
                         match msg.payload {
-                            MessagePayload::DatabaseStore(ds) => match ds.data {
-                                DatabaseStoreData::RI(ri) => {
-                                    self.netdb
-                                        .store_router_info(ds.key, ri, false)
-                                        .expect("Failed to store RouterInfo");
-                                }
-                                DatabaseStoreData::LS(ls) => {
-                                    self.netdb
-                                        .store_lease_set(ds.key, ls)
-                                        .expect("Failed to store LeaseSet");
+                            MessagePayload::DatabaseStore(ds) => {
+                                // Stores answering our own lookups are always verified
+                                if let Some(outcome) = self.verifier.store_reply(&from, &ds) {
+                                    self.verified(outcome);
+                                } else if self.pending_lookups.stored(&from, &ds.key)
+                                    || self.store_limiter.allow(&from, Instant::now())
+                                {
+                                    self.handle_store(from, ds);
+                                } else {
+                                    debug!(
+                                        "Dropping DatabaseStore from {} at key {}",
+                                        from, ds.key
+                                    );
                                 }
-                            },
+                            }
                             MessagePayload::DatabaseSearchReply(dsr) => {
-                                if let Some(pending) = self
-                                    .pending_lookups
-                                    .remove(&(from.clone(), dsr.key.clone()))
+                                self.referrals.referred(&self.netdb, &dsr);
+                                if let Some(outcome) = self.verifier.search_reply(&from, &dsr) {
+                                    self.verified(outcome);
+                                } else if let Some(pending) =
+                                    self.pending_lookups.search_reply(&from, &dsr.key)
                                 {
                                     debug!("Received msg {} from {}:\n{}", msg.id, from, dsr);
                                     if let Err(dsr) = pending.send(dsr) {
@@ -256,6 +492,37 @@ impl Future for Engine {
                                     )
                                 }
                             }
+                            MessagePayload::DatabaseLookup(dl) => {
+                                debug!("Received msg {} from {}:\n{}", msg.id, from, dl);
+                                let now = Instant::now();
+                                if !self.floodfill.is_serving(now) {
+                                    debug!("Ignoring DatabaseLookup, we are not a floodfill");
+                                } else if let Some((to, reply)) =
+                                    self.responder.respond(&self.netdb, &from, &dl, now)
+                                {
+                                    self.send_message(to, reply);
+                                }
+                            }
+                            MessagePayload::DeliveryStatus(ds) => {
+                                if self.publisher.acked(ds.msg_id()) {
+                                    if let Some((ri, stored_to)) =
+                                        self.publisher.take_verification()
+                                    {
+                                        let ds = DatabaseStore::from_ri(ri, None);
+                                        self.verifier.stored(
+                                            &ds,
+                                            Stored::OurRouterInfo,
+                                            stored_to,
+                                            Instant::now(),
+                                        );
+                                    }
+                                } else {
+                                    debug!(
+                                        "Received msg {} from {} with no pending store:\n{}",
+                                        msg.id, from, ds
+                                    )
+                                }
+                            }
                             _ => debug!("Received message from {}:\n{}", from, msg),
                         }
                     }
@@ -273,6 +540,54 @@ impl Future for Engine {
     }
 }
 
+/// Checks that `ri` may be stored in the netDb at `key`.
+///
+/// Every RouterInfo we learn about passes through here, whether it came from a
+/// reseed, the netDb directory, a DatabaseStore, or a transport handshake. Its
+/// signature is checked unless `verify_signature` is false, which callers should
+/// take from [`config::verify_router_infos`].
+pub fn validate_router_info(
+    key: &Hash,
+    ri: &RouterInfo,
+    from_reseed: bool,
+    verify_signature: bool,
+    profiles: &Profiles,
+) -> Result<(), StoreError> {
+    if *key != ri.router_id.hash() {
+        return Err(StoreError::InvalidKey);
+    }
+    if profiles.is_banned(key, SystemTime::now()) {
+        return Err(StoreError::Banned);
+    }
+    if verify_signature {
+        ri.verify()?;
+    }
+    if ri
+        .network_id()
+        .map(|net_id| *net_id != *NET_ID)
+        .unwrap_or(true)
+    {
+        return Err(StoreError::WrongNetwork);
+    }
+
+    // Don't require RouterInfos from reseeds to satisfy liveness
+    if !from_reseed {
+        router_info_is_current(ri)?;
+    }
+
+    // Routers we can't connect to must say so
+    if !ri.is_reachable()
+        && !ri
+            .caps()
+            .map(|caps| caps.is_hidden() || caps.is_unreachable())
+            .unwrap_or(false)
+    {
+        return Err(StoreError::NoAddresses);
+    }
+
+    Ok(())
+}
+
 fn router_info_is_current(ri: &RouterInfo) -> Result<(), StoreError> {
     let published = ri.published.to_system_time();
     let now = SystemTime::now();
@@ -290,12 +605,7 @@ fn router_info_is_current(ri: &RouterInfo) -> Result<(), StoreError> {
 }
 
 fn create_routing_key(key: &Hash) -> Hash {
-    let mut data = [0u8; 40];
-    data[0..32].copy_from_slice(&key.0);
-    data[32..40].copy_from_slice(Utc::now().format("%Y%m%d").to_string().as_bytes());
-    let mut out = [0u8; 32];
-    out.copy_from_slice(Sha256::digest(&data).as_slice());
-    Hash(out)
+    key.routing_key(SystemTime::now())
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -303,19 +613,42 @@ struct XorMetric([u8; 32]);
 
 impl XorMetric {
     fn for_hash(hash: &Hash, key: &Hash) -> Self {
-        let mut metric = hash.clone();
-        metric.xor(key);
-        XorMetric(metric.0)
+        XorMetric(hash.xor_distance(key))
     }
 }
 
 type PendingLookup<T> = HashMap<Hash, Vec<oneshot::Sender<T>>>;
 
 /// A NetworkDatabase that never publishes data to the network.
+///
+/// If a netDb directory is configured, RouterInfos are loaded from it on
+/// startup. New RouterInfos are written to it periodically by [`Engine`], and
+/// when the database is dropped.
 pub struct LocalNetworkDatabase {
     ctx: Arc<Context>,
-    ri_ds: HashMap<Hash, RouterInfo>,
-    ls_ds: HashMap<Hash, LeaseSet>,
+    dir: Option<PathBuf>,
+    /// Shared with every [`client::Client`], which read from it directly.
+    ri_ds: Arc<RouterInfoStore>,
+    /// The floodfills in `ri_ds`.
+    floodfills: FloodfillIndex,
+    /// When we first saw the floodfills that we learned about since starting.
+    first_seen: HashMap<Hash, SystemTime>,
+    selection: SelectionPolicy,
+    /// RouterInfos that have been stored since the last flush.
+    ri_dirty: HashSet<Hash>,
+    /// LeaseSets are never written to disk.
+    ls_ds: LeaseSetStore,
+    expiration: Expiration,
+    evictions: EvictionStats,
+    rejections: RejectionStats,
+    /// Entries stored under new keys recently.
+    added: RecentCount,
+    /// Entries expired recently.
+    evicted: RecentCount,
+    /// The size of the RouterInfos on disk, as of the last flush.
+    disk_bytes: u64,
+    /// Keys that we recently failed to find.
+    failed_lookups: FailedLookups,
     pending_ri: PendingLookup<RouterInfo>,
     pending_ls: PendingLookup<LeaseSet>,
     register_pending: PendingTx,
@@ -323,10 +656,59 @@ pub struct LocalNetworkDatabase {
 
 impl LocalNetworkDatabase {
     pub(super) fn new(ctx: Arc<Context>, pending_tx: PendingTx) -> Self {
+        let (dir, verify_signature, expiration, failed_lookups, selection) = {
+            let config = ctx.config.read().unwrap();
+            let dir = if config.get_bool(config::NETDB_PERSIST).unwrap_or(true) {
+                config.get_str(config::NETDB_DIR).ok().map(PathBuf::from)
+            } else {
+                None
+            };
+            (
+                dir,
+                config::verify_router_infos(&config),
+                Expiration::from_config(&config),
+                FailedLookups::from_config(&config),
+                SelectionPolicy::from_config(&config),
+            )
+        };
+
+        // Load any RouterInfos we stored previously
+        let ri_ds = ctx.netdb.store();
+        let mut floodfills = FloodfillIndex::default();
+        let mut disk_bytes = 0;
+        if let Some(dir) = dir.as_ref() {
+            match persist::load_router_infos(dir, true, verify_signature, &ctx.profiles) {
+                Ok(ris) => {
+                    info!("Loaded {} RouterInfos from {}", ris.len(), dir.display());
+                    for ri in ris {
+                        let key = ri.router_id.hash();
+                        if ri.is_floodfill() {
+                            floodfills.insert(key.clone());
+                        }
+                        ri_ds.insert(key, ri);
+                    }
+                }
+                Err(e) => error!("Failed to read netDb from {}: {}", dir.display(), e),
+            }
+            disk_bytes = persist::disk_usage(dir).unwrap_or(0);
+        }
+
         LocalNetworkDatabase {
             ctx,
-            ri_ds: HashMap::new(),
-            ls_ds: HashMap::new(),
+            dir,
+            ri_ds,
+            floodfills,
+            first_seen: HashMap::new(),
+            selection,
+            ri_dirty: HashSet::new(),
+            ls_ds: LeaseSetStore::default(),
+            expiration,
+            evictions: EvictionStats::default(),
+            rejections: RejectionStats::default(),
+            added: RecentCount::default(),
+            evicted: RecentCount::default(),
+            disk_bytes,
+            failed_lookups,
             pending_ri: HashMap::new(),
             pending_ls: HashMap::new(),
             register_pending: pending_tx,
@@ -337,25 +719,161 @@ impl LocalNetworkDatabase {
         self.ri_ds.len()
     }
 
-    fn select_closest_ff(&self, key: &Hash) -> Option<RouterInfo> {
-        let key = create_routing_key(key);
+    /// Returns a summary of the database's contents.
+    fn stats(&self, now: Instant) -> NetDbStats {
+        NetDbStats {
+            routers: self.known_routers(),
+            floodfills: self.floodfills.len(),
+            lease_sets: self.ls_ds.len(),
+            added_last_hour: self.added.total(now),
+            evicted_last_hour: self.evicted.total(now),
+            disk_bytes: self.disk_bytes,
+        }
+    }
+
+    /// Returns copies of the unexpired RouterInfos matching `pred`.
+    fn filter_routers<F>(&self, pred: F) -> Vec<RouterInfo>
+    where
+        F: Fn(&RouterInfo) -> bool,
+    {
+        let now = SystemTime::now();
         self.ri_ds
-            .values()
-            .filter(|ri| ri.is_floodfill())
-            .min_by_key(|ri| XorMetric::for_hash(&ri.router_id.hash(), &key))
-            .cloned()
+            .snapshot()
+            .into_iter()
+            .filter(|ri| !self.is_expired(ri, now) && pred(ri))
+            .map(|ri| (*ri).clone())
+            .collect()
+    }
+
+    /// Returns the RouterInfo stored at `key`, unless it has expired and is just
+    /// waiting to be removed.
+    fn router_info(&self, key: &Hash) -> Option<Arc<RouterInfo>> {
+        let now = SystemTime::now();
+        self.ri_ds.get(key).filter(|ri| !self.is_expired(ri, now))
+    }
+
+    /// Returns true if `ri` would be removed by the next expiry pass.
+    fn is_expired(&self, ri: &RouterInfo, now: SystemTime) -> bool {
+        let known = self.known_routers();
+        known >= KEEP_ROUTERS && self.expiration.check(ri, known, now).is_some()
+    }
+
+    /// Returns the LeaseSet of any type stored at `key`, unless it has expired.
+    fn lease_set(&self, key: &Hash) -> Option<&StoredLeaseSet> {
+        let now = I2PDate::from_system_time(SystemTime::now());
+        self.ls_ds.get(key, now)
+    }
+
+    /// Returns peers chosen at random that meet `criteria`.
+    fn select_peers(&self, criteria: &PeerCriteria) -> Vec<RouterInfo> {
+        let now = SystemTime::now();
+        let comms = self.ctx.comms.read().unwrap();
+        let ris = self.ri_ds.snapshot();
+        select::select_peers(
+            ris.iter()
+                .map(|ri| &**ri)
+                .filter(|ri| !self.is_expired(ri, now)),
+            criteria,
+            &self.ctx.keys.rid.hash(),
+            &self.ctx.profiles,
+            |hash| comms.is_established(hash),
+            now,
+            &mut thread_rng(),
+        )
+    }
+
+    fn select_closest_ff(&self, key: &Hash) -> Option<RouterInfo> {
+        self.closest_floodfills(key, 1, &[]).into_iter().next()
+    }
+
+    /// Returns up to `count` floodfills closest to the given netDb key, closest
+    /// first, skipping any in `exclude`.
+    ///
+    /// Within [`ROUTING_KEY_ROTATION_MARGIN`] of UTC midnight, this is followed by
+    /// up to `count` more floodfills closest to the key's routing key for the next
+    /// day.
+    fn closest_floodfills(&self, key: &Hash, count: usize, exclude: &[Hash]) -> Vec<RouterInfo> {
+        self.closest_floodfills_at(key, count, exclude, SystemTime::now())
     }
 
+    fn closest_floodfills_at(
+        &self,
+        key: &Hash,
+        count: usize,
+        exclude: &[Hash],
+        now: SystemTime,
+    ) -> Vec<RouterInfo> {
+        // Skip floodfills that are failing or banned
+        let mut exclude = exclude.to_vec();
+        exclude.extend(self.ctx.profiles.avoided(now));
+
+        // Derive every routing key from the same time, so that a call spanning
+        // midnight doesn't mix days.
+        let mut closest = self.select_floodfills(key, &key.routing_key(now), count, &exclude, now);
+
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let until_midnight = 24 * 60 * 60 - since_epoch % (24 * 60 * 60);
+        if until_midnight <= ROUTING_KEY_ROTATION_MARGIN {
+            let tomorrow = key.routing_key(now + Duration::from_secs(until_midnight));
+            exclude.extend(closest.iter().cloned());
+            closest.extend(self.select_floodfills(key, &tomorrow, count, &exclude, now));
+        }
+
+        closest
+            .into_iter()
+            .filter_map(|hash| self.router_info(&hash))
+            .map(|ri| (*ri).clone())
+            .collect()
+    }
+
+    /// Chooses up to `count` floodfills close to the routing key `rk` of `key`,
+    /// closest first, so that no single subnet or batch of new identities can
+    /// take over the key.
+    fn select_floodfills(
+        &self,
+        key: &Hash,
+        rk: &Hash,
+        count: usize,
+        exclude: &[Hash],
+        now: SystemTime,
+    ) -> Vec<Hash> {
+        let candidates: Vec<_> = self
+            .floodfills
+            .closest(rk, self.selection.candidates(count), exclude)
+            .into_iter()
+            .filter_map(|hash| {
+                let ri = self.router_info(&hash)?;
+                let first_seen = self.first_seen.get(&hash).cloned();
+                Some(self.selection.candidate(hash, &ri, first_seen, now))
+            })
+            .collect();
+
+        let (chosen, relaxed) = self.selection.select(&candidates, count);
+        if relaxed.any() {
+            debug!(
+                "Relaxed floodfill selection for key {} to choose {} of {}: {:?}",
+                key,
+                chosen.len(),
+                candidates.len(),
+                relaxed
+            );
+        }
+        chosen
+    }
+
+    /// Returns a lookup that fails fast if a lookup for `key` recently failed,
+    /// unless `force` is set.
     fn lookup_router_info(
         &mut self,
         key: &Hash,
         timeout_ms: u64,
         from_peer: Option<RouterInfo>,
+        force: bool,
     ) -> Box<dyn Future<Item = RouterInfo, Error = LookupError> + Send> {
         // First look for it locally, either available or pending
         let local: Option<Box<dyn Future<Item = RouterInfo, Error = LookupError> + Send>> =
-            match self.ri_ds.get(key) {
-                Some(ri) => Some(Box::new(future::ok(ri.clone()))),
+            match self.router_info(key) {
+                Some(ri) => Some(Box::new(future::ok((*ri).clone()))),
                 None => match self.pending_ri.get_mut(key) {
                     Some(ref mut pending) => {
                         // There's a pending lookup; register to receive the result
@@ -370,34 +888,49 @@ impl LocalNetworkDatabase {
         match local {
             Some(f) => f,
             None => {
+                if !force && self.failed_lookups.contains(key, Instant::now()) {
+                    return Box::new(future::err(LookupError::RecentlyFailed));
+                }
+
                 // TODO: Handle case where we don't know any floodfills
-                match from_peer.or_else(|| self.select_closest_ff(key)) {
-                    Some(ff) => lookup::lookup_db_entry(
+                let ffs = match from_peer {
+                    Some(ff) => vec![ff],
+                    None => self.closest_floodfills(key, lookup::MAX_LOOKUP_PEERS, &[]),
+                };
+                if ffs.is_empty() {
+                    return Box::new(future::err(LookupError::NotFound));
+                }
+                self.remember_failure(
+                    key.clone(),
+                    lookup::lookup_db_entry(
                         self.ctx.clone(),
                         self.register_pending.clone(),
                         key.clone(),
                         DatabaseLookupType::RouterInfo,
-                        ff,
+                        ffs,
                         &mut self.pending_ri,
                         timeout_ms,
                     ),
-                    None => Box::new(future::err(LookupError::NotFound)),
-                }
+                )
             }
         }
     }
 
+    /// Returns a lookup that fails fast if a lookup for `key` recently failed,
+    /// unless `force` is set.
     fn lookup_lease_set(
         &mut self,
         key: &Hash,
         timeout_ms: u64,
         _from_local_dest: Option<Hash>,
+        force: bool,
     ) -> Box<dyn Future<Item = LeaseSet, Error = LookupError> + Send> {
         // First look for it locally, either available or pending
         let local: Option<Box<dyn Future<Item = LeaseSet, Error = LookupError> + Send>> =
-            match self.ls_ds.get(key) {
-                Some(ls) => Some(Box::new(future::ok(ls.clone()))),
-                None => match self.pending_ls.get_mut(key) {
+            match self.lease_set(key) {
+                Some(StoredLeaseSet::LS(ls)) => Some(Box::new(future::ok(ls.clone()))),
+                // Clients can only use the original LeaseSet type so far
+                _ => match self.pending_ls.get_mut(key) {
                     Some(ref mut pending) => {
                         // There's a pending lookup; register to receive the result
                         let (tx, rx) = oneshot::channel();
@@ -411,46 +944,60 @@ impl LocalNetworkDatabase {
         match local {
             Some(f) => f,
             None => {
+                if !force && self.failed_lookups.contains(key, Instant::now()) {
+                    return Box::new(future::err(LookupError::RecentlyFailed));
+                }
+
                 // TODO: Handle case where we don't know any floodfills
                 // TODO: Handle from_local_dest case
-                match self.select_closest_ff(key) {
-                    Some(ff) => lookup::lookup_db_entry(
+                let ffs = self.closest_floodfills(key, lookup::MAX_LOOKUP_PEERS, &[]);
+                if ffs.is_empty() {
+                    return Box::new(future::err(LookupError::NotFound));
+                }
+                self.remember_failure(
+                    key.clone(),
+                    lookup::lookup_db_entry(
                         self.ctx.clone(),
                         self.register_pending.clone(),
                         key.clone(),
                         DatabaseLookupType::LeaseSet,
-                        ff,
+                        ffs,
                         &mut self.pending_ls,
                         timeout_ms,
                     ),
-                    None => Box::new(future::err(LookupError::NotFound)),
-                }
+                )
             }
         }
     }
 
+    /// Records `key` as recently failed if `lookup` times out or doesn't find
+    /// it.
+    fn remember_failure<T: Send + 'static>(
+        &self,
+        key: Hash,
+        lookup: Box<dyn Future<Item = T, Error = LookupError> + Send>,
+    ) -> Box<dyn Future<Item = T, Error = LookupError> + Send> {
+        let failed_lookups = self.failed_lookups.clone();
+        Box::new(lookup.map_err(move |e| {
+            if let LookupError::NotFound | LookupError::TimedOut = e {
+                failed_lookups.insert(key, Instant::now());
+            }
+            e
+        }))
+    }
+
     fn store_router_info(
         &mut self,
         key: Hash,
         ri: RouterInfo,
         from_reseed: bool,
     ) -> Result<Option<RouterInfo>, StoreError> {
-        // Validate the RouterInfo
-        if key != ri.router_id.hash() {
-            return Err(StoreError::InvalidKey);
-        }
-        ri.verify()?;
-        if ri
-            .network_id()
-            .map(|net_id| *net_id != *NET_ID)
-            .unwrap_or(true)
+        let verify_signature = config::verify_router_infos(&self.ctx.config.read().unwrap());
+        if let Err(e) =
+            validate_router_info(&key, &ri, from_reseed, verify_signature, &self.ctx.profiles)
         {
-            return Err(StoreError::WrongNetwork);
-        }
-
-        // Don't require RouterInfos from reseeds to satisfy liveness
-        if !from_reseed {
-            router_info_is_current(&ri)?;
+            self.rejections.record(e);
+            return Err(e);
         }
 
         // If anyone was waiting on this RouterInfo, notify them
@@ -463,65 +1010,187 @@ impl LocalNetworkDatabase {
         }
 
         debug!("Storing RouterInfo at key {}", key);
-        Ok(self.ri_ds.insert(key, ri))
+        self.failed_lookups.remove(&key);
+        if self.dir.is_some() {
+            self.ri_dirty.insert(key.clone());
+        }
+        if ri.is_floodfill() {
+            // Floodfills from a reseed are as trusted as those we had on disk
+            if !from_reseed && !self.ri_ds.contains_key(&key) {
+                self.first_seen.insert(key.clone(), SystemTime::now());
+            }
+            self.floodfills.insert(key.clone());
+        } else {
+            self.first_seen.remove(&key);
+            self.floodfills.remove(&key);
+        }
+        let replaced = self.ri_ds.insert(key, ri);
+        if replaced.is_none() {
+            self.added.add(1, Instant::now());
+        }
+        Ok(replaced.map(|ri| Arc::try_unwrap(ri).unwrap_or_else(|ri| (*ri).clone())))
     }
 
     fn store_lease_set(&mut self, key: Hash, ls: LeaseSet) -> Result<Option<LeaseSet>, StoreError> {
+        match self.store_any_lease_set(key, StoredLeaseSet::LS(ls))? {
+            Some(StoredLeaseSet::LS(replaced)) => Ok(Some(replaced)),
+            _ => Ok(None),
+        }
+    }
+
+    fn store_any_lease_set(
+        &mut self,
+        key: Hash,
+        ls: StoredLeaseSet,
+    ) -> Result<Option<StoredLeaseSet>, StoreError> {
+        let now = I2PDate::from_system_time(SystemTime::now());
+        let replaced = self.ls_ds.insert(key.clone(), ls, now)?;
+
         // If anyone was waiting on this LeaseSet, notify them
-        if let Some(pending) = self.pending_ls.remove(&key) {
-            for p in pending {
-                if p.send(ls.clone()).is_err() {
-                    warn!("Lookup task timed out waiting for LeaseSet at {}", key);
+        if let Some(StoredLeaseSet::LS(ls)) = self.ls_ds.get(&key, now) {
+            if let Some(pending) = self.pending_ls.remove(&key) {
+                for p in pending {
+                    if p.send(ls.clone()).is_err() {
+                        warn!("Lookup task timed out waiting for LeaseSet at {}", key);
+                    }
                 }
             }
         }
 
         debug!("Storing LeaseSet at key {}", key);
-        Ok(self.ls_ds.insert(key, ls))
+        self.failed_lookups.remove(&key);
+        if replaced.is_none() {
+            self.added.add(1, Instant::now());
+        }
+        Ok(replaced)
     }
 
     fn expire_router_infos(&mut self, ctx: Option<Arc<Context>>) {
         let comms = ctx.as_ref().map(|ctx| ctx.comms.read().unwrap());
 
-        let before = self.ri_ds.len();
-        self.ri_ds.retain(|_, ri| {
+        let known = self.known_routers();
+        let now = SystemTime::now();
+        let expiration = &self.expiration;
+        let evictions = &mut self.evictions;
+
+        // Decide what to expire from a snapshot, so that the store is never
+        // locked while we check entries.
+        let mut expired = vec![];
+        for ri in self.ri_ds.snapshot() {
             // Don't expire RIs for peers we are connected to.
+            let key = ri.router_id.hash();
             if let Some(comms) = comms.as_ref() {
-                if comms.is_established(&ri.router_id.hash()) {
-                    return true;
+                if comms.is_established(&key) {
+                    continue;
                 }
             }
 
-            router_info_is_current(ri).is_ok()
-        });
-        let expired = before - self.ri_ds.len();
-        if expired > 0 {
-            debug!("Expired {} RouterInfos", expired);
+            if let Some(reason) = expiration.check(&ri, known, now) {
+                evictions.record(reason);
+                expired.push(key);
+            }
+        }
+        if !expired.is_empty() {
+            debug!("Expired {} RouterInfos", expired.len());
+            self.evicted.add(expired.len() as u64, Instant::now());
+        }
+
+        for key in expired {
+            self.ri_ds.remove(&key);
+            self.ri_dirty.remove(&key);
+            self.floodfills.remove(&key);
+            self.first_seen.remove(&key);
+            if let Some(dir) = self.dir.as_ref() {
+                if let Err(e) = persist::delete_router_info(dir, &key) {
+                    warn!("Failed to delete RouterInfo {} from disk: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Writes any RouterInfos stored since the last flush to disk.
+    fn flush(&mut self) {
+        let dir = match self.dir.as_ref() {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        let mut written = 0;
+        for key in self.ri_dirty.drain() {
+            if let Some(ri) = self.ri_ds.get(&key) {
+                match persist::write_router_info(dir, &ri) {
+                    Ok(()) => written += 1,
+                    Err(e) => warn!("Failed to write RouterInfo {} to disk: {}", key, e),
+                }
+            }
+        }
+        if written > 0 {
+            debug!("Wrote {} RouterInfos to {}", written, dir.display());
+        }
+
+        match persist::disk_usage(dir) {
+            Ok(bytes) => self.disk_bytes = bytes,
+            Err(e) => warn!("Failed to measure netDb in {}: {}", dir.display(), e),
         }
     }
 
     fn expire_lease_sets(&mut self) {
-        let before = self.ls_ds.len();
-        self.ls_ds.retain(|_, ls| ls.is_current());
-        let expired = before - self.ls_ds.len();
+        let now = I2PDate::from_system_time(SystemTime::now());
+        let expired = self.ls_ds.expire(now);
+        self.evictions.lease_sets += expired as u64;
         if expired > 0 {
-            debug!("Expired {} LeaseSets", expired);
+            debug!("Expired {} LeaseSets, {} remain", expired, self.ls_ds.len());
+            self.evicted.add(expired as u64, Instant::now());
         }
     }
 }
 
+impl Drop for LocalNetworkDatabase {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use futures::{sync::mpsc, Async};
-    use std::time::{Duration, SystemTime};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
     use super::{
-        errors::StoreError, router_info_is_current, LocalNetworkDatabase, XorMetric,
+        errors::{LookupError, RejectionStats, StoreError},
+        lookup, persist, router_info_is_current, LocalNetworkDatabase, XorMetric,
         ROUTER_INFO_EXPIRATION,
     };
     use crate::crypto;
-    use crate::data::{Hash, I2PDate, RouterInfo, RouterSecretKeys, OPT_NET_ID};
-    use crate::router::mock::mock_context;
+    use crate::data::{
+        Hash, I2PDate, I2PString, RouterAddress, RouterCaps, RouterInfo, RouterInfoBuilder,
+        RouterSecretKeys, OPT_NET_ID,
+    };
+    use crate::router::{config, mock::mock_context, Context};
+
+    fn persistent_context(dir: &Path, persist: bool) -> Arc<Context> {
+        let ctx = mock_context();
+        {
+            let mut config = ctx.config.write().unwrap();
+            config
+                .set(config::NETDB_DIR, dir.to_str().unwrap())
+                .unwrap();
+            config.set(config::NETDB_PERSIST, persist).unwrap();
+        }
+        ctx
+    }
+
+    fn signed_router_info(published: SystemTime) -> RouterInfo {
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.published = I2PDate::from_system_time(published);
+        ri.sign(&rsk.signing_private_key);
+        ri
+    }
+    use crate::tests::RI_SIGTYPE_0;
 
     #[test]
     fn xor_metric() {
@@ -575,7 +1244,11 @@ mod tests {
         );
 
         // Storing a RouterInfo modified after signing should fail
-        let old_netid = ri.options.0.insert(OPT_NET_ID.clone(), "0".into()).unwrap();
+        let old_netid = ri
+            .options
+            .insert(OPT_NET_ID.clone(), "0".into())
+            .unwrap()
+            .unwrap();
         assert_eq!(
             netdb.store_router_info(key.clone(), ri.clone(), false),
             Err(StoreError::Crypto(crypto::Error::InvalidSignature))
@@ -587,7 +1260,7 @@ mod tests {
             netdb.store_router_info(key.clone(), ri.clone(), false),
             Err(StoreError::WrongNetwork)
         );
-        ri.options.0.insert(OPT_NET_ID.clone(), old_netid);
+        ri.options.insert(OPT_NET_ID.clone(), old_netid).unwrap();
         ri.sign(&rsk.signing_private_key);
 
         // Storing the new RouterInfo should return no data
@@ -597,7 +1270,304 @@ mod tests {
         );
         assert_eq!(netdb.known_routers(), 1);
 
-        match netdb.lookup_router_info(&key, 100, None).poll() {
+        match netdb.lookup_router_info(&key, 100, None, false).poll() {
+            Ok(Async::Ready(entry)) => assert_eq!(entry, ri),
+            Ok(_) => panic!("Local lookup should complete immediately"),
+            Err(e) => panic!("Unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn store_unverified() {
+        let ctx = mock_context();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(ctx.clone(), tx);
+
+        let rsk = RouterSecretKeys::new();
+        let mut ri = RouterInfo::new(rsk.rid);
+        ri.sign(&rsk.signing_private_key);
+        ri.published = I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        let key = ri.router_id.hash();
+
+        // Signatures are checked by default
+        assert_eq!(
+            netdb.store_router_info(key.clone(), ri.clone(), false),
+            Err(StoreError::Crypto(crypto::Error::InvalidSignature))
+        );
+
+        // Tests can turn the check off
+        ctx.config
+            .write()
+            .unwrap()
+            .set(config::TEST_VERIFY_RI, false)
+            .unwrap();
+        assert_eq!(netdb.store_router_info(key, ri, false), Ok(None));
+    }
+
+    #[test]
+    fn persist_across_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let ris: Vec<_> = (0..5)
+            .map(|_| signed_router_info(SystemTime::now()))
+            .collect();
+
+        {
+            let (tx, _) = mpsc::channel(0);
+            let mut netdb = LocalNetworkDatabase::new(persistent_context(dir.path(), true), tx);
+            assert_eq!(netdb.known_routers(), 0);
+            for ri in &ris {
+                netdb
+                    .store_router_info(ri.router_id.hash(), ri.clone(), false)
+                    .unwrap();
+            }
+
+            // Nothing is written until the next flush
+            let path = persist::router_info_path(dir.path(), &ris[0].router_id.hash());
+            assert!(!path.exists());
+            netdb.flush();
+            assert!(path.exists());
+        }
+
+        // An expired RouterInfo left on disk from an earlier run
+        let expired = signed_router_info(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        persist::write_router_info(dir.path(), &expired).unwrap();
+        let expired_path = persist::router_info_path(dir.path(), &expired.router_id.hash());
+
+        // Stored after the last flush, so only written when the netDb is dropped
+        let late = signed_router_info(SystemTime::now());
+        {
+            let (tx, _) = mpsc::channel(0);
+            let mut netdb = LocalNetworkDatabase::new(persistent_context(dir.path(), true), tx);
+            assert_eq!(netdb.known_routers(), ris.len());
+            assert!(!expired_path.exists());
+
+            netdb
+                .store_router_info(late.router_id.hash(), late.clone(), false)
+                .unwrap();
+        }
+
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(persistent_context(dir.path(), true), tx);
+        assert_eq!(netdb.known_routers(), ris.len() + 1);
+        for ri in ris.iter().chain(Some(&late)) {
+            match netdb
+                .lookup_router_info(&ri.router_id.hash(), 100, None, false)
+                .poll()
+            {
+                Ok(Async::Ready(entry)) => assert_eq!(&entry, ri),
+                Ok(_) => panic!("Local lookup should complete immediately"),
+                Err(e) => panic!("Unexpected error: {}", e),
+            }
+        }
+        assert!(netdb.ri_ds.get(&expired.router_id.hash()).is_none());
+
+        // With persistence disabled, the directory is neither read nor written
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(persistent_context(dir.path(), false), tx);
+        assert_eq!(netdb.known_routers(), 0);
+        let ri = signed_router_info(SystemTime::now());
+        netdb
+            .store_router_info(ri.router_id.hash(), ri.clone(), false)
+            .unwrap();
+        drop(netdb);
+        assert!(!persist::router_info_path(dir.path(), &ri.router_id.hash()).exists());
+    }
+
+    #[test]
+    fn expired_router_infos_deleted_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(persistent_context(dir.path(), true), tx);
+
+        let ri = signed_router_info(SystemTime::now());
+        let key = ri.router_id.hash();
+        netdb.store_router_info(key.clone(), ri, false).unwrap();
+        netdb.flush();
+        let path = persist::router_info_path(dir.path(), &key);
+        assert!(path.exists());
+
+        // Age the stored copy so that it expires
+        let mut ri = (*netdb.ri_ds.get(&key).unwrap()).clone();
+        ri.published = I2PDate::from_system_time(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        netdb.ri_ds.insert(key.clone(), ri);
+        netdb.expire_router_infos(None);
+        assert_eq!(netdb.known_routers(), 0);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn validation_rejections() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let ntcp2 = I2PString::new("NTCP2");
+        let addr = RouterAddress::new(&ntcp2, "203.0.113.1:12345".parse().unwrap());
+
+        let reachable = |addresses: Vec<RouterAddress>| {
+            let rsk = RouterSecretKeys::new();
+            let ri = RouterInfoBuilder::new(rsk.rid)
+                .caps(RouterCaps::default().reachable(true))
+                .addresses(addresses)
+                .sign(&rsk.signing_private_key);
+            (ri, rsk.signing_private_key)
+        };
+
+        // A reachable router with an address is accepted
+        let (ri, _) = reachable(vec![addr.clone()]);
+        assert_eq!(
+            netdb.store_router_info(ri.router_id.hash(), ri, false),
+            Ok(None)
+        );
+
+        // Stored at the wrong key
+        let (ri, _) = reachable(vec![addr.clone()]);
+        assert_eq!(
+            netdb.store_router_info(Hash([0; 32]), ri, false),
+            Err(StoreError::InvalidKey)
+        );
+
+        // Modified after signing
+        let (mut ri, _) = reachable(vec![addr.clone()]);
+        ri.published = I2PDate::from_system_time(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(
+            netdb.store_router_info(ri.router_id.hash(), ri, false),
+            Err(StoreError::Crypto(crypto::Error::InvalidSignature))
+        );
+
+        // From a test network, or with no network at all
+        for net_id in &[Some("99"), None] {
+            let (mut ri, spk) = reachable(vec![addr.clone()]);
+            match net_id {
+                Some(net_id) => ri
+                    .options
+                    .insert(OPT_NET_ID.clone(), (*net_id).into())
+                    .unwrap(),
+                None => ri.options.remove(&OPT_NET_ID),
+            };
+            ri.sign(&spk);
+            assert_eq!(
+                netdb.store_router_info(ri.router_id.hash(), ri, false),
+                Err(StoreError::WrongNetwork)
+            );
+        }
+
+        // Published too long ago, or too far in the future
+        let (mut ri, spk) = reachable(vec![addr.clone()]);
+        ri.published = I2PDate::from_system_time(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        ri.sign(&spk);
+        match netdb.store_router_info(ri.router_id.hash(), ri, false) {
+            Err(StoreError::Expired(_)) => (),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        let (mut ri, spk) = reachable(vec![addr.clone()]);
+        ri.published = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(10 * 60));
+        ri.sign(&spk);
+        assert_eq!(
+            netdb.store_router_info(ri.router_id.hash(), ri, false),
+            Err(StoreError::PublishedInFuture)
+        );
+
+        // Claiming to be reachable without an address
+        let (ri, _) = reachable(vec![]);
+        assert_eq!(
+            netdb.store_router_info(ri.router_id.hash(), ri, false),
+            Err(StoreError::NoAddresses)
+        );
+
+        // Routers that say they are hidden don't need an address
+        let rsk = RouterSecretKeys::new();
+        let ri = RouterInfoBuilder::new(rsk.rid)
+            .caps(RouterCaps::default().reachable(true).hidden(true))
+            .sign(&rsk.signing_private_key);
+        assert_eq!(
+            netdb.store_router_info(ri.router_id.hash(), ri, false),
+            Ok(None)
+        );
+
+        // Banned
+        let (ri, _) = reachable(vec![addr]);
+        let key = ri.router_id.hash();
+        netdb
+            .ctx
+            .profiles
+            .ban(&key, Duration::from_secs(60), SystemTime::now());
+        assert_eq!(
+            netdb.store_router_info(key, ri, false),
+            Err(StoreError::Banned)
+        );
+
+        assert_eq!(netdb.known_routers(), 2);
+        assert_eq!(
+            netdb.rejections,
+            RejectionStats {
+                banned: 1,
+                bad_signature: 1,
+                expired: 1,
+                invalid_key: 1,
+                no_addresses: 1,
+                published_in_future: 1,
+                wrong_network: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn store_dsa_router_info() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+
+        let ri = RouterInfo::from_bytes(RI_SIGTYPE_0).unwrap();
+        let key = ri.router_id.hash();
+
+        // DSA-signed RouterInfos are verified, not skipped
+        let mut data = RI_SIGTYPE_0.to_vec();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        assert_eq!(
+            netdb.store_router_info(key.clone(), RouterInfo::from_bytes(&data).unwrap(), true),
+            Err(StoreError::Crypto(crypto::Error::InvalidSignature))
+        );
+        assert_eq!(
+            netdb.store_router_info(key.clone(), ri.clone(), true),
+            Ok(None)
+        );
+        assert_eq!(netdb.known_routers(), 1);
+    }
+
+    #[test]
+    fn recently_failed_lookups() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let ri = signed_router_info(SystemTime::now());
+        let key = ri.router_id.hash();
+
+        // A recent failure makes lookups fail fast, unless forced
+        netdb.failed_lookups.insert(key.clone(), Instant::now());
+        for (force, expected) in &[
+            (false, LookupError::RecentlyFailed),
+            (true, LookupError::NotFound),
+        ] {
+            match netdb.lookup_router_info(&key, 100, None, *force).poll() {
+                Err(e) => assert_eq!(e, *expected),
+                Ok(_) => panic!("Lookup should have failed"),
+            }
+            match netdb.lookup_lease_set(&key, 100, None, *force).poll() {
+                Err(e) => assert_eq!(e, *expected),
+                Ok(_) => panic!("Lookup should have failed"),
+            }
+        }
+
+        // Storing the entry clears the failure
+        netdb
+            .store_router_info(key.clone(), ri.clone(), false)
+            .unwrap();
+        assert!(!netdb.failed_lookups.contains(&key, Instant::now()));
+        match netdb.lookup_router_info(&key, 100, None, false).poll() {
             Ok(Async::Ready(entry)) => assert_eq!(entry, ri),
             Ok(_) => panic!("Local lookup should complete immediately"),
             Err(e) => panic!("Unexpected error: {}", e),
@@ -629,4 +1599,208 @@ mod tests {
             Err(StoreError::PublishedInFuture)
         );
     }
+
+    #[test]
+    fn closest_floodfills() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+
+        let mut keys = HashMap::new();
+        let mut floodfills = vec![];
+        for i in 0..30 {
+            let rsk = RouterSecretKeys::new();
+            let ri = RouterInfoBuilder::new(rsk.rid.clone())
+                .caps(RouterCaps::default().floodfill(i % 3 == 0))
+                .sign(&rsk.signing_private_key);
+            let key = ri.router_id.hash();
+            if ri.is_floodfill() {
+                floodfills.push(key.clone());
+            }
+            netdb.store_router_info(key.clone(), ri, false).unwrap();
+            keys.insert(key, rsk);
+        }
+        assert_eq!(floodfills.len(), 10);
+
+        let closest = |netdb: &LocalNetworkDatabase,
+                       key: &Hash,
+                       count: usize,
+                       exclude: &[Hash],
+                       now: SystemTime|
+         -> Vec<Hash> {
+            netdb
+                .closest_floodfills_at(key, count, exclude, now)
+                .into_iter()
+                .map(|ri| ri.router_id.hash())
+                .collect()
+        };
+        let brute_force =
+            |floodfills: &[Hash], rk: &Hash, count: usize, exclude: &[Hash]| -> Vec<Hash> {
+                let mut sorted: Vec<_> = floodfills
+                    .iter()
+                    .filter(|hash| !exclude.contains(hash))
+                    .cloned()
+                    .collect();
+                sorted.sort_by(|a, b| rk.cmp_distance(a, b));
+                sorted.truncate(count);
+                sorted
+            };
+
+        // Midday on 2020-09-13, and five minutes before the following midnight
+        let midday = UNIX_EPOCH + Duration::from_secs(18_518 * 24 * 60 * 60 + 12 * 60 * 60);
+        let before_midnight = UNIX_EPOCH + Duration::from_secs(18_519 * 24 * 60 * 60 - 5 * 60);
+        let after_midnight = UNIX_EPOCH + Duration::from_secs(18_519 * 24 * 60 * 60);
+
+        let key = Hash::digest(b"closest_floodfills");
+        let rk = key.routing_key(midday);
+        assert_eq!(rk, key.routing_key(before_midnight));
+        assert_ne!(rk, key.routing_key(after_midnight));
+
+        // Only floodfills are returned, closest to the day's routing key first
+        assert_eq!(
+            closest(&netdb, &key, 4, &[], midday),
+            brute_force(&floodfills, &rk, 4, &[])
+        );
+        assert_eq!(
+            closest(&netdb, &key, 20, &[], midday),
+            brute_force(&floodfills, &rk, 20, &[])
+        );
+        assert_eq!(
+            netdb.select_closest_ff(&key).map(|ri| ri.router_id.hash()),
+            brute_force(&floodfills, &key.routing_key(SystemTime::now()), 1, &[]).pop()
+        );
+
+        // Excluded floodfills are skipped
+        let exclude = brute_force(&floodfills, &rk, 2, &[]);
+        assert_eq!(
+            closest(&netdb, &key, 4, &exclude, midday),
+            brute_force(&floodfills, &rk, 4, &exclude)
+        );
+
+        // As are floodfills that are failing or banned
+        let avoided = brute_force(&floodfills, &rk, 2, &[]);
+        netdb
+            .ctx
+            .profiles
+            .ban(&avoided[0], Duration::from_secs(60), midday);
+        for _ in 0..3 {
+            netdb.ctx.profiles.lookup_timed_out(&avoided[1], midday);
+        }
+        assert_eq!(
+            closest(&netdb, &key, 4, &[], midday),
+            brute_force(&floodfills, &rk, 4, &avoided)
+        );
+        assert_eq!(
+            closest(&netdb, &key, 4, &[], midday + Duration::from_secs(60 * 60)),
+            brute_force(&floodfills, &rk, 4, &[])
+        );
+
+        // Just before midnight, the floodfills for tomorrow's key are included too
+        let today = brute_force(&floodfills, &rk, 3, &[]);
+        let tomorrow = brute_force(&floodfills, &key.routing_key(after_midnight), 3, &today);
+        assert_eq!(
+            closest(&netdb, &key, 3, &[], before_midnight),
+            [today, tomorrow].concat()
+        );
+
+        // From midnight, only the new day's key is used
+        assert_eq!(
+            closest(&netdb, &key, 3, &[], after_midnight),
+            brute_force(&floodfills, &key.routing_key(after_midnight), 3, &[])
+        );
+
+        // A router that stops being a floodfill drops out of the index
+        let nearest = brute_force(&floodfills, &rk, 1, &[]).pop().unwrap();
+        let rsk = &keys[&nearest];
+        let ri = RouterInfoBuilder::new(rsk.rid.clone())
+            .caps(RouterCaps::default().floodfill(false))
+            .sign(&rsk.signing_private_key);
+        netdb.store_router_info(nearest.clone(), ri, false).unwrap();
+        floodfills.retain(|hash| *hash != nearest);
+        assert_eq!(
+            closest(&netdb, &key, 20, &[], midday),
+            brute_force(&floodfills, &rk, 20, &[])
+        );
+
+        // As does one that expires
+        let nearest = brute_force(&floodfills, &rk, 1, &[]).pop().unwrap();
+        let mut ri = (*netdb.ri_ds.get(&nearest).unwrap()).clone();
+        ri.published = I2PDate::from_system_time(
+            SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+        );
+        netdb.ri_ds.insert(nearest.clone(), ri);
+        netdb.expire_router_infos(None);
+        floodfills.retain(|hash| *hash != nearest);
+        assert_eq!(
+            closest(&netdb, &key, 20, &[], midday),
+            brute_force(&floodfills, &rk, 20, &[])
+        );
+    }
+
+    #[test]
+    fn floodfill_eclipse() {
+        let (tx, _) = mpsc::channel(0);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let key = Hash::digest(b"floodfill_eclipse");
+        let midday = UNIX_EPOCH + Duration::from_secs(18_518 * 24 * 60 * 60 + 12 * 60 * 60);
+        let rk = key.routing_key(midday);
+
+        // The 20 floodfills closest to the key share a /16, and the next 20 are
+        // each in their own
+        let mut rsks: Vec<_> = (0..40).map(|_| RouterSecretKeys::new()).collect();
+        rsks.sort_by(|a, b| rk.cmp_distance(&a.rid.hash(), &b.rid.hash()));
+        let mut attackers = vec![];
+        let mut outside = vec![];
+        for (i, rsk) in rsks.into_iter().enumerate() {
+            let ip = if i < 20 {
+                format!("198.51.{}.{}", i, i + 1)
+            } else {
+                format!("10.{}.0.1", i)
+            };
+            let ri = RouterInfoBuilder::new(rsk.rid.clone())
+                .caps(RouterCaps::default().floodfill(true))
+                .addresses(vec![RouterAddress::new(
+                    &I2PString::new("NTCP2"),
+                    format!("{}:12345", ip).parse().unwrap(),
+                )])
+                .sign(&rsk.signing_private_key);
+            let hash = ri.router_id.hash();
+            netdb.store_router_info(hash.clone(), ri, false).unwrap();
+            if i < 20 {
+                attackers.push(hash);
+            } else {
+                outside.push(hash);
+            }
+        }
+
+        let closest = |netdb: &LocalNetworkDatabase, count| -> Vec<Hash> {
+            netdb
+                .closest_floodfills_at(&key, count, &[], midday)
+                .into_iter()
+                .map(|ri| ri.router_id.hash())
+                .collect()
+        };
+        let count_attackers =
+            |chosen: &[Hash]| chosen.iter().filter(|h| attackers.contains(h)).count();
+
+        // Every floodfill is equally new, so the closest attacker is chosen,
+        // but the rest come from outside the crowded subnet
+        let chosen = closest(&netdb, 3);
+        assert_eq!(chosen.len(), 3);
+        assert_eq!(chosen[0], attackers[0]);
+        assert_eq!(count_attackers(&chosen), 1);
+        assert!(chosen[1..].iter().all(|h| outside.contains(h)));
+
+        // Larger selections take at most two from the subnet
+        let chosen = closest(&netdb, lookup::MAX_LOOKUP_PEERS);
+        assert_eq!(chosen.len(), lookup::MAX_LOOKUP_PEERS);
+        assert_eq!(count_attackers(&chosen), 2);
+
+        // Once the outside floodfills are established, the new attackers are
+        // passed over entirely
+        for hash in &outside {
+            netdb.first_seen.remove(hash);
+        }
+        let chosen = closest(&netdb, 3);
+        assert_eq!(chosen, outside[..3].to_vec());
+    }
 }
@@ -0,0 +1,141 @@
+//! Remembering lookups that recently failed.
+//!
+//! A destination that has gone offline, or a router that has left the network,
+//! will keep failing to be found for a while. Callers that retry aggressively
+//! would otherwise send a full round of lookups to floodfills each time, so we
+//! fail those lookups fast for a short period instead. A store of the key
+//! clears it immediately.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::data::Hash;
+use crate::router::config::{self, Config};
+
+/// How long we fail lookups for a key fast after a lookup for it failed, in
+/// seconds.
+const FAILED_LOOKUP_TTL: u64 = 2 * 60;
+/// Maximum number of failed keys we remember.
+const MAX_FAILED_LOOKUPS: usize = 1024;
+
+/// The keys whose lookups recently failed, shared with the lookups in progress.
+#[derive(Clone)]
+pub(super) struct FailedLookups {
+    ttl: Duration,
+    capacity: usize,
+    failed: Arc<Mutex<HashMap<Hash, Instant>>>,
+}
+
+impl FailedLookups {
+    pub(super) fn new(ttl: Duration, capacity: usize) -> Self {
+        FailedLookups {
+            ttl,
+            capacity,
+            failed: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(super) fn from_config(config: &Config) -> Self {
+        let ttl = config
+            .get_int(config::NETDB_LOOKUP_FAILED_TTL)
+            .ok()
+            .map(|v| v as u64)
+            .unwrap_or(FAILED_LOOKUP_TTL);
+        FailedLookups::new(Duration::from_secs(ttl), MAX_FAILED_LOOKUPS)
+    }
+
+    /// Records that a lookup for `key` failed at `now`.
+    pub(super) fn insert(&self, key: Hash, now: Instant) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let mut failed = self.failed.lock().unwrap();
+        if failed.len() >= self.capacity && !failed.contains_key(&key) {
+            // Make room, dropping expired entries or else the oldest one
+            let ttl = self.ttl;
+            failed.retain(|_, at| now.duration_since(*at) < ttl);
+            if failed.len() >= self.capacity {
+                let oldest = failed
+                    .iter()
+                    .min_by_key(|(_, at)| **at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    failed.remove(&oldest);
+                }
+            }
+        }
+        failed.insert(key, now);
+    }
+
+    /// Returns true if a lookup for `key` failed within the TTL before `now`.
+    pub(super) fn contains(&self, key: &Hash, now: Instant) -> bool {
+        let mut failed = self.failed.lock().unwrap();
+        match failed.get(key) {
+            Some(at) if now.duration_since(*at) < self.ttl => true,
+            Some(_) => {
+                failed.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets any failed lookup for `key`, because we now have an entry for it.
+    pub(super) fn remove(&self, key: &Hash) {
+        self.failed.lock().unwrap().remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::FailedLookups;
+    use crate::data::Hash;
+    use crate::router::config::{self, Config};
+
+    #[test]
+    fn ttl_and_removal() {
+        let failed = FailedLookups::new(Duration::from_secs(60), 10);
+        let key = Hash([1; 32]);
+        let now = Instant::now();
+
+        assert!(!failed.contains(&key, now));
+        failed.insert(key.clone(), now);
+        assert!(failed.contains(&key, now));
+        assert!(failed.contains(&key, now + Duration::from_secs(59)));
+        assert!(!failed.contains(&key, now + Duration::from_secs(60)));
+
+        failed.insert(key.clone(), now);
+        failed.remove(&key);
+        assert!(!failed.contains(&key, now));
+    }
+
+    #[test]
+    fn bounded() {
+        let failed = FailedLookups::new(Duration::from_secs(60), 3);
+        let now = Instant::now();
+        for i in 0..4 {
+            failed.insert(Hash([i; 32]), now + Duration::from_secs(i as u64));
+        }
+
+        // The oldest entry made room for the newest
+        let later = now + Duration::from_secs(5);
+        assert!(!failed.contains(&Hash([0; 32]), later));
+        for i in 1..4 {
+            assert!(failed.contains(&Hash([i; 32]), later));
+        }
+    }
+
+    #[test]
+    fn from_config() {
+        let mut cfg = Config::default();
+        cfg.set(config::NETDB_LOOKUP_FAILED_TTL, 0).unwrap();
+        let failed = FailedLookups::from_config(&cfg);
+        let key = Hash([1; 32]);
+        failed.insert(key.clone(), Instant::now());
+        assert!(!failed.contains(&key, Instant::now()));
+    }
+}
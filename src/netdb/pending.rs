@@ -0,0 +1,207 @@
+//! Matching replies from floodfills to the lookups we sent them.
+//!
+//! Neither DatabaseStore nor DatabaseSearchReply messages refer to the lookup
+//! that prompted them, so a lookup is identified by the floodfill we sent it to
+//! and the key we asked for. Tracking each lookup until it is answered also
+//! tells us how well each floodfill is responding, which is recorded in its
+//! peer profile.
+
+use futures::sync::oneshot;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::lookup::SINGLE_LOOKUP_TIMEOUT;
+use crate::data::Hash;
+use crate::i2np::DatabaseSearchReply;
+use crate::router::profiles::Profiles;
+
+/// How a floodfill has answered the lookups we sent it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FloodfillLookupStats {
+    /// Lookups answered with the entry.
+    pub found: u64,
+    /// Lookups answered with a DatabaseSearchReply.
+    pub not_found: u64,
+    /// Lookups not answered in time.
+    pub timed_out: u64,
+}
+
+impl FloodfillLookupStats {
+    fn add(&mut self, other: &FloodfillLookupStats) {
+        self.found += other.found;
+        self.not_found += other.not_found;
+        self.timed_out += other.timed_out;
+    }
+}
+
+struct SentLookup {
+    tx: oneshot::Sender<DatabaseSearchReply>,
+    sent: Instant,
+}
+
+/// The lookups we have sent that haven't been answered yet.
+pub(super) struct PendingLookups {
+    pending: HashMap<(Hash, Hash), SentLookup>,
+    stats: HashMap<Hash, FloodfillLookupStats>,
+    profiles: Profiles,
+}
+
+impl PendingLookups {
+    pub(super) fn new(profiles: Profiles) -> Self {
+        PendingLookups {
+            pending: HashMap::new(),
+            stats: HashMap::new(),
+            profiles,
+        }
+    }
+
+    /// Registers a lookup for `key` sent to `ff`, so that a DatabaseSearchReply
+    /// from `ff` is passed on to `tx`.
+    pub(super) fn register(
+        &mut self,
+        ff: Hash,
+        key: Hash,
+        tx: oneshot::Sender<DatabaseSearchReply>,
+        now: Instant,
+    ) {
+        self.pending.insert((ff, key), SentLookup { tx, sent: now });
+    }
+
+    /// Records that `ff` sent us the entry at `key`, returning true if we had
+    /// asked it for the entry.
+    pub(super) fn stored(&mut self, ff: &Hash, key: &Hash) -> bool {
+        if let Some(pending) = self.pending.remove(&(ff.clone(), key.clone())) {
+            self.stats.entry(ff.clone()).or_default().found += 1;
+            self.profiles
+                .lookup_answered(ff, pending.sent.elapsed(), SystemTime::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns where to pass on a DatabaseSearchReply from `ff`, if we are waiting
+    /// for one.
+    pub(super) fn search_reply(
+        &mut self,
+        ff: &Hash,
+        key: &Hash,
+    ) -> Option<oneshot::Sender<DatabaseSearchReply>> {
+        let pending = self.pending.remove(&(ff.clone(), key.clone()))?;
+        self.stats.entry(ff.clone()).or_default().not_found += 1;
+        self.profiles
+            .lookup_answered(ff, pending.sent.elapsed(), SystemTime::now());
+        Some(pending.tx)
+    }
+
+    /// Forgets lookups that have gone unanswered for longer than a lookup waits,
+    /// counting them against their floodfills.
+    pub(super) fn expire(&mut self, now: Instant) {
+        let timeout = Duration::from_secs(SINGLE_LOOKUP_TIMEOUT);
+        let stats = &mut self.stats;
+        let profiles = &self.profiles;
+        self.pending.retain(|(ff, _), pending| {
+            let waiting = now.duration_since(pending.sent) < timeout;
+            if !waiting {
+                stats.entry(ff.clone()).or_default().timed_out += 1;
+                profiles.lookup_timed_out(ff, SystemTime::now());
+            }
+            waiting
+        });
+    }
+
+    /// Returns how `ff` has answered our lookups.
+    pub(super) fn stats(&self, ff: &Hash) -> FloodfillLookupStats {
+        self.stats.get(ff).cloned().unwrap_or_default()
+    }
+
+    /// Returns how all floodfills have answered our lookups.
+    pub(super) fn total_stats(&self) -> FloodfillLookupStats {
+        let mut total = FloodfillLookupStats::default();
+        for stats in self.stats.values() {
+            total.add(stats);
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::oneshot, Future};
+    use std::time::{Duration, Instant};
+
+    use super::{FloodfillLookupStats, PendingLookups};
+    use crate::data::Hash;
+    use crate::i2np::DatabaseSearchReply;
+    use crate::netdb::lookup::SINGLE_LOOKUP_TIMEOUT;
+    use crate::router::profiles::Profiles;
+
+    #[test]
+    fn replies_and_timeouts() {
+        let ff = Hash([1; 32]);
+        let other = Hash([2; 32]);
+        let key = Hash([3; 32]);
+        let now = Instant::now();
+        let profiles = Profiles::default();
+        let mut pending = PendingLookups::new(profiles.clone());
+
+        // A DatabaseSearchReply is passed on to the lookup
+        let (tx, rx) = oneshot::channel();
+        pending.register(ff.clone(), key.clone(), tx, now);
+        assert!(pending.search_reply(&other, &key).is_none());
+        let dsr = DatabaseSearchReply {
+            key: key.clone(),
+            peers: vec![],
+            from: ff.clone(),
+        };
+        assert!(pending.search_reply(&ff, &key).unwrap().send(dsr).is_ok());
+        assert_eq!(rx.wait().unwrap().key, key);
+        assert!(pending.search_reply(&ff, &key).is_none());
+
+        // The entry itself completes the lookup
+        let (tx, _rx) = oneshot::channel();
+        pending.register(ff.clone(), key.clone(), tx, now);
+        pending.stored(&other, &key);
+        pending.stored(&ff, &key);
+        pending.stored(&ff, &key);
+
+        // Lookups left unanswered time out
+        let (tx, _rx) = oneshot::channel();
+        pending.register(other.clone(), key.clone(), tx, now);
+        pending.expire(now);
+        assert_eq!(pending.stats(&other), FloodfillLookupStats::default());
+        pending.expire(now + Duration::from_secs(SINGLE_LOOKUP_TIMEOUT));
+        assert!(pending.search_reply(&other, &key).is_none());
+
+        assert_eq!(
+            pending.stats(&ff),
+            FloodfillLookupStats {
+                found: 1,
+                not_found: 1,
+                timed_out: 0,
+            }
+        );
+        assert_eq!(
+            pending.stats(&other),
+            FloodfillLookupStats {
+                found: 0,
+                not_found: 0,
+                timed_out: 1,
+            }
+        );
+        assert_eq!(
+            pending.total_stats(),
+            FloodfillLookupStats {
+                found: 1,
+                not_found: 1,
+                timed_out: 1,
+            }
+        );
+
+        // The outcomes are recorded in the floodfills' profiles
+        let lookups = profiles.get(&ff).unwrap().lookups;
+        assert_eq!((lookups.answered, lookups.timed_out), (2, 0));
+        let lookups = profiles.get(&other).unwrap().lookups;
+        assert_eq!((lookups.answered, lookups.timed_out), (0, 1));
+    }
+}
@@ -0,0 +1,373 @@
+//! On-disk storage for the network database, compatible with Java I2P.
+//!
+//! Each RouterInfo is stored as its raw signed bytes, in a file named
+//! `routerInfo-<base64 hash>.dat`. Files are sharded into subdirectories by the
+//! first character of the base64 hash, so the full path is
+//! `<netDb>/r<first char>/routerInfo-<base64 hash>.dat`.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{errors::StoreError, validate_router_info};
+use crate::data::{Hash, ReadError, RouterInfo};
+use crate::router::profiles::Profiles;
+use crate::util::write_file;
+
+const RI_FILE_PREFIX: &str = "routerInfo-";
+const RI_FILE_SUFFIX: &str = ".dat";
+
+/// Errors encountered while loading a single RouterInfo.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    Read(ReadError),
+    Store(StoreError),
+}
+
+impl From<ReadError> for LoadError {
+    fn from(e: ReadError) -> Self {
+        LoadError::Read(e)
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Read(e.into())
+    }
+}
+
+impl From<StoreError> for LoadError {
+    fn from(e: StoreError) -> Self {
+        LoadError::Store(e)
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Read(e) => e.fmt(f),
+            LoadError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Returns the path within the netDb directory `dir` at which the RouterInfo with
+/// the given hash is stored.
+pub fn router_info_path(dir: &Path, hash: &Hash) -> PathBuf {
+    let b64 = hash.to_base64();
+    dir.join(format!("r{}", &b64[..1]))
+        .join(format!("{}{}{}", RI_FILE_PREFIX, b64, RI_FILE_SUFFIX))
+}
+
+/// Parses the hash out of a RouterInfo file name, or returns `None` if the name
+/// isn't of the form `routerInfo-<base64 hash>.dat`.
+pub(super) fn hash_from_file_name(name: &str) -> Option<Hash> {
+    name.strip_prefix(RI_FILE_PREFIX)?
+        .strip_suffix(RI_FILE_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+/// Atomically writes the given RouterInfo into the netDb directory `dir`,
+/// creating its subdirectory if necessary.
+pub fn write_router_info(dir: &Path, ri: &RouterInfo) -> io::Result<()> {
+    let path = router_info_path(dir, &ri.router_id.hash());
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_file(&path, &ri.to_bytes())
+}
+
+/// Deletes the RouterInfo with the given hash from the netDb directory `dir`, if
+/// it is stored there.
+pub fn delete_router_info(dir: &Path, hash: &Hash) -> io::Result<()> {
+    match fs::remove_file(router_info_path(dir, hash)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+fn load_router_info(
+    path: &Path,
+    hash: &Hash,
+    verify_signature: bool,
+    profiles: &Profiles,
+) -> Result<RouterInfo, LoadError> {
+    let ri = RouterInfo::from_bytes(&fs::read(path)?)?;
+    validate_router_info(hash, &ri, false, verify_signature, profiles)?;
+    Ok(ri)
+}
+
+/// Loads every RouterInfo in the netDb directory `dir`.
+///
+/// Files that are corrupt, are stored under the wrong hash, or contain a
+/// RouterInfo that is invalid, has expired, or is from a router banned in
+/// `profiles` are skipped, and are deleted if `delete_invalid` is set. A missing
+/// directory is treated as empty.
+pub fn load_router_infos(
+    dir: &Path,
+    delete_invalid: bool,
+    verify_signature: bool,
+    profiles: &Profiles,
+) -> io::Result<Vec<RouterInfo>> {
+    let mut ris = vec![];
+    for (path, res) in scan_router_infos(dir, verify_signature, profiles)? {
+        match res {
+            Ok(ri) => ris.push(ri),
+            Err(e) => {
+                debug!("Skipping RouterInfo file {}: {}", path.display(), e);
+                if delete_invalid {
+                    if let Err(e) = fs::remove_file(&path) {
+                        warn!("Failed to delete {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+    }
+    Ok(ris)
+}
+
+/// Loads and validates every RouterInfo file in the netDb directory `dir`,
+/// returning the result for each file alongside its path. A missing directory is
+/// treated as empty.
+pub(super) fn scan_router_infos(
+    dir: &Path,
+    verify_signature: bool,
+    profiles: &Profiles,
+) -> io::Result<Vec<(PathBuf, Result<RouterInfo, LoadError>)>> {
+    let mut results = vec![];
+
+    let shards = match fs::read_dir(dir) {
+        Ok(shards) => shards,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(results),
+        Err(e) => return Err(e),
+    };
+
+    for shard in shards {
+        let shard = shard?;
+        let is_shard = shard
+            .file_name()
+            .to_str()
+            .map(|name| name.len() == 2 && name.starts_with('r'))
+            .unwrap_or(false);
+        if !is_shard || !shard.file_type()?.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(shard.path())? {
+            let path = entry?.path();
+            let hash = match path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(hash_from_file_name)
+            {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            let res = load_router_info(&path, &hash, verify_signature, profiles);
+            results.push((path, res));
+        }
+    }
+
+    Ok(results)
+}
+
+/// Returns the total size of the RouterInfo files in the netDb directory `dir`.
+/// A missing directory is treated as empty.
+pub fn disk_usage(dir: &Path) -> io::Result<u64> {
+    let shards = match fs::read_dir(dir) {
+        Ok(shards) => shards,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut total = 0;
+    for shard in shards {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(shard.path())? {
+            let entry = entry?;
+            let is_ri = entry
+                .file_name()
+                .to_str()
+                .and_then(hash_from_file_name)
+                .is_some();
+            if is_ri {
+                total += entry.metadata()?.len();
+            }
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*};
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use super::{
+        delete_router_info, disk_usage, hash_from_file_name, load_router_infos, router_info_path,
+        write_router_info,
+    };
+    use crate::data::{arbitrary::peer, Hash, I2PDate, RouterInfo, RouterSecretKeys};
+    use crate::netdb::ROUTER_INFO_EXPIRATION;
+    use crate::router::profiles::Profiles;
+
+    #[test]
+    fn file_paths() {
+        let hash: Hash = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA="
+            .parse()
+            .unwrap();
+        assert_eq!(
+            router_info_path(Path::new("netDb"), &hash),
+            Path::new("netDb/rA/routerInfo-AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=.dat")
+        );
+
+        assert_eq!(
+            hash_from_file_name("routerInfo-AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=.dat"),
+            Some(hash)
+        );
+        assert_eq!(
+            hash_from_file_name("routerInfo-AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=.dat.tmp"),
+            None
+        );
+        assert_eq!(
+            hash_from_file_name("leaseSet-AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=.dat"),
+            None
+        );
+        assert_eq!(hash_from_file_name("routerInfo-AQID.dat"), None);
+    }
+
+    #[test]
+    fn write_and_scan() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Scanning a missing directory finds nothing
+        assert!(
+            load_router_infos(&dir.path().join("netDb"), false, true, &Profiles::default())
+                .unwrap()
+                .is_empty()
+        );
+
+        let mut valid = HashSet::new();
+        let mut invalid = vec![];
+        for i in 0..100 {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            if i % 10 == 1 {
+                // Expired
+                ri.published = I2PDate::from_system_time(
+                    SystemTime::now() - Duration::from_secs(ROUTER_INFO_EXPIRATION + 100),
+                );
+            }
+            ri.sign(&rsk.signing_private_key);
+            write_router_info(dir.path(), &ri).unwrap();
+
+            let hash = ri.router_id.hash();
+            let path = router_info_path(dir.path(), &hash);
+            match i % 10 {
+                1 => invalid.push(path),
+                2 => {
+                    // Truncated
+                    let data = fs::read(&path).unwrap();
+                    fs::write(&path, &data[..data.len() - 10]).unwrap();
+                    invalid.push(path);
+                }
+                3 => {
+                    // Bad signature
+                    let mut data = fs::read(&path).unwrap();
+                    let last = data.len() - 1;
+                    data[last] ^= 0xff;
+                    fs::write(&path, &data).unwrap();
+                    invalid.push(path);
+                }
+                4 => {
+                    // Stored under the wrong hash
+                    let wrong = router_info_path(dir.path(), &Hash::digest(&hash.0));
+                    fs::create_dir_all(wrong.parent().unwrap()).unwrap();
+                    fs::rename(&path, &wrong).unwrap();
+                    invalid.push(wrong);
+                }
+                _ => {
+                    valid.insert(hash);
+                }
+            }
+        }
+
+        // Unrelated files are ignored
+        fs::write(dir.path().join("README"), b"foo").unwrap();
+        fs::write(dir.path().join("rA").with_extension("dat"), b"foo").unwrap();
+
+        let hashes = |ris: Vec<RouterInfo>| -> HashSet<Hash> {
+            ris.iter().map(|ri| ri.router_id.hash()).collect()
+        };
+
+        // Without deletion, invalid files are skipped but left in place
+        let loaded = load_router_infos(dir.path(), false, true, &Profiles::default()).unwrap();
+        assert_eq!(loaded.len(), 60);
+        assert_eq!(hashes(loaded), valid);
+        assert!(invalid.iter().all(|path| path.exists()));
+
+        // With deletion, they are removed
+        let loaded = load_router_infos(dir.path(), true, true, &Profiles::default()).unwrap();
+        assert_eq!(hashes(loaded), valid);
+        assert!(invalid.iter().all(|path| !path.exists()));
+        assert_eq!(
+            hashes(load_router_infos(dir.path(), true, true, &Profiles::default()).unwrap()),
+            valid
+        );
+
+        // Only the remaining RouterInfo files count towards disk usage
+        let expected: u64 = valid
+            .iter()
+            .map(|hash| {
+                fs::metadata(router_info_path(dir.path(), hash))
+                    .unwrap()
+                    .len()
+            })
+            .sum();
+        assert_eq!(disk_usage(dir.path()).unwrap(), expected);
+        assert_eq!(disk_usage(&dir.path().join("netDb")).unwrap(), 0);
+
+        // RouterInfos from banned routers are skipped
+        let profiles = Profiles::default();
+        let banned = valid.iter().next().unwrap().clone();
+        profiles.ban(&banned, Duration::from_secs(60), SystemTime::now());
+        let loaded = hashes(load_router_infos(dir.path(), false, true, &profiles).unwrap());
+        assert_eq!(loaded.len(), valid.len() - 1);
+        assert!(!loaded.contains(&banned));
+
+        // Deleting is idempotent
+        let hash = valid.iter().next().unwrap().clone();
+        delete_router_info(dir.path(), &hash).unwrap();
+        assert!(!router_info_path(dir.path(), &hash).exists());
+        delete_router_info(dir.path(), &hash).unwrap();
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn peers_round_trip(peers in vec(peer(), 1..10)) {
+            let dir = tempfile::tempdir().unwrap();
+            for ri in &peers {
+                write_router_info(dir.path(), ri).unwrap();
+            }
+
+            // Later writes of the same router replace earlier ones
+            let by_hash = |ris: Vec<RouterInfo>| -> HashMap<Hash, RouterInfo> {
+                ris.into_iter().map(|ri| (ri.router_id.hash(), ri)).collect()
+            };
+            let loaded = load_router_infos(dir.path(), false, true, &Profiles::default()).unwrap();
+            prop_assert_eq!(by_hash(loaded), by_hash(peers));
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Publishing our own RouterInfo to the floodfills.
+//!
+//! Other routers can only reach us if they can find our RouterInfo in the
+//! netDb. We publish it once we know our addresses, again whenever our
+//! addresses or capabilities change, and otherwise every half hour or so, so
+//! that floodfills never expire it. Each publication re-signs the RouterInfo
+//! with a new published date, and is sent to the floodfills closest to our
+//! hash with a reply token. If none of them acknowledge it in time, we try the
+//! next-closest ones.
+//!
+//! Once a publication is acknowledged, it is handed on to be verified. If our
+//! RouterInfo can't be found through another floodfill, we publish it again to
+//! floodfills that we haven't already tried.
+//!
+//! Hidden routers never publish their RouterInfo.
+
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::LocalNetworkDatabase;
+use crate::data::{Hash, Mapping, RouterAddress, RouterInfo, RouterInfoBuilder, TunnelId};
+use crate::i2np::{DatabaseStore, Message, MessagePayload, ReplyPath};
+use crate::router::config::{self, Config};
+
+/// Republish at least this often, in seconds.
+const PUBLISH_MIN_INTERVAL: u64 = 30 * 60;
+/// Republish at most this often, in seconds.
+const PUBLISH_MAX_INTERVAL: u64 = 40 * 60;
+/// How many floodfills we send each publication to at once.
+const PUBLISH_REDUNDANCY: usize = 2;
+/// How many floodfills we try before giving up on a publication.
+const MAX_PUBLISH_ATTEMPTS: usize = 6;
+/// How long we wait for a floodfill to acknowledge a store, in seconds.
+const PUBLISH_ACK_TIMEOUT: u64 = 10;
+/// How long we wait before trying again after a publication fails, in seconds.
+const PUBLISH_RETRY_DELAY: u64 = 60;
+
+/// Counts of the publications of our RouterInfo.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PublishStats {
+    /// The published date of the last RouterInfo a floodfill acknowledged.
+    pub last_published: Option<SystemTime>,
+    /// Publications that a floodfill acknowledged.
+    pub succeeded: u64,
+    /// Publications that no floodfill acknowledged.
+    pub failed: u64,
+    /// Stores that a floodfill did not acknowledge in time.
+    pub timed_out: u64,
+    /// Publications we sent again because they couldn't be verified.
+    pub republished: u64,
+}
+
+/// A publication waiting for a floodfill to acknowledge it.
+struct Round {
+    ri: RouterInfo,
+    tried: Vec<Hash>,
+    /// Floodfills that a failed verification has already covered.
+    exclude: Vec<Hash>,
+    /// Whether to verify this publication once it is acknowledged.
+    verify: bool,
+    pending: HashMap<u32, Instant>,
+}
+
+/// Decides when to publish our RouterInfo, and tracks the acknowledgements.
+pub(super) struct Publisher {
+    hidden: bool,
+    /// The addresses and options of the RouterInfo we last published.
+    published: Option<(Vec<RouterAddress>, Mapping)>,
+    next_publish: Option<Instant>,
+    round: Option<Round>,
+    /// Set when the next publication replaces one that couldn't be verified.
+    exclude: Option<Vec<Hash>>,
+    /// An acknowledged publication, and the floodfills it was sent to.
+    to_verify: Option<(RouterInfo, Vec<Hash>)>,
+    stats: PublishStats,
+}
+
+impl Publisher {
+    pub(super) fn new(hidden: bool) -> Self {
+        Publisher {
+            hidden,
+            published: None,
+            next_publish: None,
+            round: None,
+            exclude: None,
+            to_verify: None,
+            stats: PublishStats::default(),
+        }
+    }
+
+    pub(super) fn from_config(config: &Config) -> Self {
+        Publisher::new(config.get_bool(config::ROUTER_HIDDEN).unwrap_or(false))
+    }
+
+    pub(super) fn stats(&self) -> PublishStats {
+        self.stats
+    }
+
+    /// Returns the stores to send, and the floodfills to send them to: the first
+    /// of a new publication if one is due, or retries if the floodfills we last
+    /// tried haven't acknowledged it in time.
+    pub(super) fn tick(
+        &mut self,
+        netdb: &LocalNetworkDatabase,
+        now: Instant,
+    ) -> Vec<(Hash, Message)> {
+        if self.hidden {
+            return vec![];
+        }
+
+        if let Some(round) = self.round.as_mut() {
+            let timeout = Duration::from_secs(PUBLISH_ACK_TIMEOUT);
+            let before = round.pending.len();
+            round
+                .pending
+                .retain(|_, sent| now.duration_since(*sent) < timeout);
+            self.stats.timed_out += (before - round.pending.len()) as u64;
+            if !round.pending.is_empty() {
+                return vec![];
+            }
+
+            let msgs = if round.tried.len() < MAX_PUBLISH_ATTEMPTS {
+                send_stores(netdb, round, now)
+            } else {
+                vec![]
+            };
+            if msgs.is_empty() {
+                warn!("No floodfill acknowledged our RouterInfo");
+                self.stats.failed += 1;
+                self.round = None;
+                self.next_publish = Some(now + Duration::from_secs(PUBLISH_RETRY_DELAY));
+            }
+            return msgs;
+        }
+
+        let ri = netdb.ctx.ri.read().unwrap().clone();
+        if ri.addresses().is_empty() {
+            // Wait until the transports know our addresses
+            return vec![];
+        }
+        let changed = self
+            .published
+            .as_ref()
+            .map(|(addresses, options)| addresses[..] != *ri.addresses() || *options != ri.options)
+            .unwrap_or(true);
+        let due = self.next_publish.map(|next| now >= next).unwrap_or(true);
+        if !changed && !due {
+            return vec![];
+        }
+
+        // Rebuild our RouterInfo with a newer published date, so that floodfills
+        // replace their copy of it
+        let ri = {
+            let mut ri = netdb.ctx.ri.write().unwrap();
+            *ri = RouterInfoBuilder::from_router_info(&ri)
+                .published_after(ri.published)
+                .sign(&netdb.ctx.keys.signing_private_key);
+            ri.clone()
+        };
+        let published = (ri.addresses().to_vec(), ri.options.clone());
+        let exclude = self.exclude.take();
+        let mut round = Round {
+            ri,
+            tried: vec![],
+            verify: exclude.is_none(),
+            exclude: exclude.unwrap_or_default(),
+            pending: HashMap::new(),
+        };
+        let msgs = send_stores(netdb, &mut round, now);
+        if msgs.is_empty() {
+            // We don't know any floodfills yet
+            return msgs;
+        }
+
+        debug!("Publishing our RouterInfo to {} floodfills", msgs.len());
+        self.published = Some(published);
+        let interval = thread_rng().gen_range(PUBLISH_MIN_INTERVAL..=PUBLISH_MAX_INTERVAL);
+        self.next_publish = Some(now + Duration::from_secs(interval));
+        self.round = Some(round);
+        msgs
+    }
+
+    /// Records a DeliveryStatus with the given token, returning true if it
+    /// acknowledged a publication.
+    pub(super) fn acked(&mut self, token: u32) -> bool {
+        match self.round.take() {
+            Some(round) if round.pending.contains_key(&token) => {
+                debug!("Floodfill acknowledged our RouterInfo");
+                self.stats.succeeded += 1;
+                self.stats.last_published = Some(round.ri.published.to_system_time());
+                if round.verify {
+                    self.to_verify = Some((round.ri, round.tried));
+                }
+                true
+            }
+            round => {
+                self.round = round;
+                false
+            }
+        }
+    }
+
+    /// Returns the last acknowledged publication that should be verified, and
+    /// the floodfills it was sent to.
+    pub(super) fn take_verification(&mut self) -> Option<(RouterInfo, Vec<Hash>)> {
+        self.to_verify.take()
+    }
+
+    /// Publishes our RouterInfo again at the next tick, to floodfills other than
+    /// those in `tried`, because the last publication couldn't be verified.
+    ///
+    /// Republications aren't verified themselves, so that a RouterInfo that no
+    /// floodfill will keep doesn't get republished in a loop.
+    pub(super) fn verify_failed(&mut self, tried: Vec<Hash>) {
+        if self.round.is_some() {
+            // A newer publication is already under way
+            return;
+        }
+        debug!("Republishing our RouterInfo after a failed verification");
+        self.stats.republished += 1;
+        self.exclude = Some(tried);
+        self.next_publish = None;
+    }
+}
+
+/// Sends the round's store to the closest floodfills we haven't tried yet.
+fn send_stores(
+    netdb: &LocalNetworkDatabase,
+    round: &mut Round,
+    now: Instant,
+) -> Vec<(Hash, Message)> {
+    let us = netdb.ctx.keys.rid.hash();
+    let mut exclude = round.tried.clone();
+    exclude.extend(round.exclude.iter().cloned());
+    exclude.push(us.clone());
+    let count = PUBLISH_REDUNDANCY.min(MAX_PUBLISH_ATTEMPTS - round.tried.len());
+
+    netdb
+        .closest_floodfills(&us, count, &exclude)
+        .into_iter()
+        .map(|ff| {
+            let ff = ff.router_id.hash();
+            let token = loop {
+                let token = netdb.ctx.msg_ids.next_id();
+                if token != 0 && !round.pending.contains_key(&token) {
+                    break token;
+                }
+            };
+            round.tried.push(ff.clone());
+            round.pending.insert(token, now);
+
+            let reply = ReplyPath::new(token, TunnelId(0), us.clone());
+            let ds = DatabaseStore::from_ri(round.ri.clone(), Some(reply));
+            let msg = Message::from_payload(&netdb.ctx.msg_ids, MessagePayload::DatabaseStore(ds));
+            (ff, msg)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use super::{
+        Publisher, MAX_PUBLISH_ATTEMPTS, PUBLISH_ACK_TIMEOUT, PUBLISH_MAX_INTERVAL,
+        PUBLISH_REDUNDANCY, PUBLISH_RETRY_DELAY,
+    };
+    use crate::data::Hash;
+    use crate::i2np::{DatabaseStoreData, Message, MessagePayload};
+    use crate::netdb::{
+        mock::{populated_netdb, set_address},
+        LocalNetworkDatabase,
+    };
+    use crate::router::mock::mock_context;
+
+    /// Checks that each message is a signed store of our RouterInfo, and returns
+    /// the floodfills and reply tokens.
+    fn stores(netdb: &LocalNetworkDatabase, msgs: Vec<(Hash, Message)>) -> Vec<(Hash, u32)> {
+        let us = netdb.ctx.keys.rid.hash();
+        msgs.into_iter()
+            .map(|(ff, msg)| match msg.payload {
+                MessagePayload::DatabaseStore(ds) => {
+                    assert_eq!(ds.key, us);
+                    match ds.data {
+                        DatabaseStoreData::RI(ref ri) => assert!(ri.verify().is_ok()),
+                        _ => panic!("Expected a RouterInfo"),
+                    }
+                    let reply = ds.reply().unwrap();
+                    assert_eq!(reply.gateway(), &us);
+                    (ff, reply.token())
+                }
+                _ => panic!("Expected a DatabaseStore"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn publish_and_retry() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let now = Instant::now();
+
+        // We publish to the closest floodfills to us
+        let first = stores(&netdb, publisher.tick(&netdb, now));
+        assert_eq!(first.len(), PUBLISH_REDUNDANCY);
+        let us = netdb.ctx.keys.rid.hash();
+        let closest: Vec<_> = netdb
+            .closest_floodfills(&us, PUBLISH_REDUNDANCY, &[])
+            .into_iter()
+            .map(|ri| ri.router_id.hash())
+            .collect();
+        assert_eq!(
+            first.iter().map(|(ff, _)| ff.clone()).collect::<Vec<_>>(),
+            closest
+        );
+
+        // Nothing more is sent while we wait for an acknowledgement
+        assert!(publisher.tick(&netdb, now).is_empty());
+
+        // If they don't acknowledge it, we try other floodfills
+        let later = now + Duration::from_secs(PUBLISH_ACK_TIMEOUT);
+        let second = stores(&netdb, publisher.tick(&netdb, later));
+        assert_eq!(second.len(), PUBLISH_REDUNDANCY);
+        assert!(second.iter().all(|(ff, _)| !closest.contains(ff)));
+        assert_eq!(publisher.stats().timed_out, PUBLISH_REDUNDANCY as u64);
+
+        // Late or unknown acknowledgements are ignored
+        assert!(!publisher.acked(first[0].1));
+        assert!(!publisher.acked(0));
+
+        // One of the new floodfills acknowledges it
+        assert!(publisher.acked(second[1].1));
+        assert!(!publisher.acked(second[0].1));
+        let stats = publisher.stats();
+        assert_eq!((stats.succeeded, stats.failed), (1, 0));
+        assert_eq!(
+            stats.last_published,
+            Some(netdb.ctx.ri.read().unwrap().published.to_system_time())
+        );
+
+        // We don't publish again until our addresses change
+        assert!(publisher.tick(&netdb, later).is_empty());
+        set_address(&netdb, 23456);
+        assert_eq!(stores(&netdb, publisher.tick(&netdb, later)).len(), 2);
+    }
+
+    #[test]
+    fn periodic() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let now = Instant::now();
+
+        let sent = stores(&netdb, publisher.tick(&netdb, now));
+        assert!(publisher.acked(sent[0].1));
+        assert!(publisher.tick(&netdb, now).is_empty());
+
+        let later = now + Duration::from_secs(PUBLISH_MAX_INTERVAL);
+        assert_eq!(stores(&netdb, publisher.tick(&netdb, later)).len(), 2);
+    }
+
+    #[test]
+    fn gives_up() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let mut now = Instant::now();
+
+        let mut tried = vec![];
+        loop {
+            let sent = stores(&netdb, publisher.tick(&netdb, now));
+            if sent.is_empty() {
+                break;
+            }
+            tried.extend(sent.into_iter().map(|(ff, _)| ff));
+            now += Duration::from_secs(PUBLISH_ACK_TIMEOUT);
+        }
+        assert_eq!(tried.len(), MAX_PUBLISH_ATTEMPTS);
+        let stats = publisher.stats();
+        assert_eq!((stats.succeeded, stats.failed), (0, 1));
+        assert_eq!(stats.last_published, None);
+
+        // We try again after a delay
+        assert!(publisher.tick(&netdb, now).is_empty());
+        now += Duration::from_secs(PUBLISH_RETRY_DELAY);
+        assert_eq!(stores(&netdb, publisher.tick(&netdb, now)).len(), 2);
+    }
+
+    #[test]
+    fn waits_for_addresses_and_floodfills() {
+        let (tx, _) = mpsc::channel(0);
+        let netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        let mut publisher = Publisher::new(false);
+        let now = Instant::now();
+
+        // We have no addresses
+        assert!(publisher.tick(&netdb, now).is_empty());
+
+        // We know no floodfills
+        set_address(&netdb, 12345);
+        assert!(publisher.tick(&netdb, now).is_empty());
+        assert_eq!(publisher.stats().failed, 0);
+    }
+
+    #[test]
+    fn hidden() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(true);
+        let now = Instant::now();
+        assert!(publisher.tick(&netdb, now).is_empty());
+        assert!(publisher
+            .tick(&netdb, now + Duration::from_secs(PUBLISH_MAX_INTERVAL))
+            .is_empty());
+        assert_eq!(publisher.stats(), Default::default());
+    }
+}
@@ -0,0 +1,238 @@
+//! Fetching the routers that DatabaseSearchReplies refer us to.
+//!
+//! Every DatabaseSearchReply lists routers close to the key that was looked up,
+//! whether it answers one of our lookups, an exploration, or nothing at all.
+//! Any of those we don't know are worth fetching, but how many is worth it
+//! depends on how many routers we already know: a small netDb takes all it can
+//! get, while a large one only needs a trickle of new routers.
+
+use futures::{sync::oneshot, Async, Future};
+use std::collections::{HashSet, VecDeque};
+use tokio::executor::DefaultExecutor;
+
+use super::{errors::LookupError, LocalNetworkDatabase, MINIMUM_ROUTERS};
+use crate::data::{Hash, RouterInfo};
+use crate::i2np::DatabaseSearchReply;
+
+/// Up to this many routers, we fetch every router a reply refers to.
+const FEW_ROUTERS: usize = 1000;
+/// Up to this many routers, we fetch a few routers from each reply. Beyond it,
+/// we only fetch one.
+const MANY_ROUTERS: usize = 4000;
+/// How many referenced routers we fetch from each reply while the netDb is
+/// small.
+const FETCHES_PER_REPLY: usize = 4;
+/// Maximum number of referenced routers waiting to be fetched.
+const MAX_QUEUED_FETCHES: usize = 64;
+/// Maximum number of referenced routers being fetched at once.
+const MAX_ACTIVE_FETCHES: usize = 8;
+/// How long to spend fetching each referenced router, in milliseconds.
+const FETCH_TIMEOUT: u64 = 10 * 1000;
+
+/// Counts of the routers that DatabaseSearchReplies have referred us to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReferralStats {
+    /// Router hashes listed in the replies we received.
+    pub referenced: u64,
+    /// Referenced routers we already knew.
+    pub already_known: u64,
+    /// Referenced routers we were already fetching.
+    pub in_flight: u64,
+    /// Referenced routers we didn't fetch because we had fetched enough.
+    pub skipped: u64,
+    /// Lookups we sent for referenced routers.
+    pub fetched: u64,
+}
+
+/// Returns how many of the unknown routers in a single reply to fetch, given the
+/// number of routers we know.
+fn fetch_limit(known_routers: usize) -> usize {
+    if known_routers < MINIMUM_ROUTERS {
+        usize::max_value()
+    } else if known_routers < FEW_ROUTERS {
+        FETCHES_PER_REPLY
+    } else if known_routers < MANY_ROUTERS {
+        1
+    } else {
+        0
+    }
+}
+
+/// Queues lookups for the routers that DatabaseSearchReplies refer to.
+#[derive(Default)]
+pub(super) struct ReferralFetcher {
+    to_fetch: VecDeque<(Hash, Option<RouterInfo>)>,
+    fetching: HashSet<Hash>,
+    fetches: Vec<(Hash, oneshot::SpawnHandle<RouterInfo, LookupError>)>,
+    stats: ReferralStats,
+}
+
+impl ReferralFetcher {
+    pub(super) fn stats(&self) -> ReferralStats {
+        self.stats
+    }
+
+    /// Queues lookups for the routers in `dsr` that we don't know, and aren't
+    /// already looking up.
+    pub(super) fn referred(&mut self, netdb: &LocalNetworkDatabase, dsr: &DatabaseSearchReply) {
+        // Ask the router that replied, if it is a floodfill we know
+        let from = netdb
+            .router_info(&dsr.from)
+            .filter(|ri| ri.is_floodfill())
+            .map(|ri| (*ri).clone());
+        let us = netdb.ctx.keys.rid.hash();
+
+        let mut limit = fetch_limit(netdb.known_routers());
+        for peer in &dsr.peers {
+            self.stats.referenced += 1;
+            if *peer == us || netdb.router_info(peer).is_some() {
+                self.stats.already_known += 1;
+            } else if self.fetching.contains(peer) || netdb.pending_ri.contains_key(peer) {
+                self.stats.in_flight += 1;
+            } else if limit == 0 || self.to_fetch.len() >= MAX_QUEUED_FETCHES {
+                self.stats.skipped += 1;
+            } else {
+                limit -= 1;
+                self.fetching.insert(peer.clone());
+                self.to_fetch.push_back((peer.clone(), from.clone()));
+            }
+        }
+    }
+
+    /// Starts queued lookups while there is room for them, and collects those
+    /// that have finished.
+    pub(super) fn poll(&mut self, netdb: &mut LocalNetworkDatabase) {
+        while self.fetches.len() < MAX_ACTIVE_FETCHES {
+            match self.to_fetch.pop_front() {
+                Some((peer, from)) => {
+                    let fetch = netdb.lookup_router_info(&peer, FETCH_TIMEOUT, from, false);
+                    self.fetches
+                        .push((peer, oneshot::spawn(fetch, &DefaultExecutor::current())));
+                    self.stats.fetched += 1;
+                }
+                None => break,
+            }
+        }
+
+        let mut i = 0;
+        while i < self.fetches.len() {
+            match self.fetches[i].1.poll() {
+                Ok(Async::NotReady) => i += 1,
+                res => {
+                    let (peer, _) = self.fetches.swap_remove(i);
+                    self.fetching.remove(&peer);
+                    if let Err(e) = res {
+                        debug!("Failed to fetch referenced router {}: {}", peer, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, sync::mpsc};
+    use tokio::runtime::Runtime;
+
+    use super::{fetch_limit, ReferralFetcher, ReferralStats, FETCHES_PER_REPLY};
+    use crate::data::{Hash, RouterCaps};
+    use crate::i2np::DatabaseSearchReply;
+    use crate::netdb::LocalNetworkDatabase;
+    use crate::router::mock::{mock_context, mock_router_info};
+
+    #[test]
+    fn limits() {
+        assert_eq!(fetch_limit(0), usize::max_value());
+        assert_eq!(fetch_limit(500), FETCHES_PER_REPLY);
+        assert_eq!(fetch_limit(2000), 1);
+        assert_eq!(fetch_limit(4000), 0);
+    }
+
+    #[test]
+    fn fetches_unknown_routers() {
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let (tx, _rx) = mpsc::channel(1024);
+            let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+            let mut fetcher = ReferralFetcher::default();
+
+            // A floodfill we know replies with two routers we know and three we
+            // don't.
+            let ff = mock_router_info(RouterCaps::default().floodfill(true));
+            let known = mock_router_info(RouterCaps::default());
+            for ri in &[&ff, &known] {
+                netdb
+                    .store_router_info(ri.router_id.hash(), (*ri).clone(), false)
+                    .unwrap();
+            }
+            let unknown: Vec<Hash> = (0..3)
+                .map(|_| mock_router_info(RouterCaps::default()).router_id.hash())
+                .collect();
+            let mut peers = unknown.clone();
+            peers.push(ff.router_id.hash());
+            peers.push(known.router_id.hash());
+            let dsr = DatabaseSearchReply {
+                key: Hash([1; 32]),
+                peers,
+                from: ff.router_id.hash(),
+            };
+
+            fetcher.referred(&netdb, &dsr);
+            fetcher.poll(&mut netdb);
+            assert_eq!(
+                fetcher.stats(),
+                ReferralStats {
+                    referenced: 5,
+                    already_known: 2,
+                    in_flight: 0,
+                    skipped: 0,
+                    fetched: 3,
+                }
+            );
+            assert_eq!(netdb.pending_ri.len(), 3);
+            assert!(unknown.iter().all(|h| netdb.pending_ri.contains_key(h)));
+
+            // The same reply again doesn't start any new lookups
+            fetcher.referred(&netdb, &dsr);
+            fetcher.poll(&mut netdb);
+            assert_eq!(
+                fetcher.stats(),
+                ReferralStats {
+                    referenced: 10,
+                    already_known: 4,
+                    in_flight: 3,
+                    skipped: 0,
+                    fetched: 3,
+                }
+            );
+            assert_eq!(netdb.pending_ri.len(), 3);
+
+            Ok::<(), ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn skips_when_netdb_is_large() {
+        let (tx, _rx) = mpsc::channel(1024);
+        let mut netdb = LocalNetworkDatabase::new(mock_context(), tx);
+        for _ in 0..1000 {
+            let ri = mock_router_info(RouterCaps::default());
+            netdb.ri_ds.insert(ri.router_id.hash(), ri);
+        }
+        let mut fetcher = ReferralFetcher::default();
+
+        // Only one router is fetched from each reply
+        let dsr = DatabaseSearchReply {
+            key: Hash([1; 32]),
+            peers: (0..3)
+                .map(|_| mock_router_info(RouterCaps::default()).router_id.hash())
+                .collect(),
+            from: Hash([2; 32]),
+        };
+        fetcher.referred(&netdb, &dsr);
+        assert_eq!(fetcher.to_fetch.len(), 1);
+        assert_eq!(fetcher.stats().skipped, 2);
+    }
+}
@@ -1,37 +1,171 @@
-use futures::{future, Async, Future, Poll};
+//! Bootstrapping the network database from reseed servers.
+//!
+//! A reseed server publishes a signed SU3 bundle of RouterInfos at
+//! `<url>i2pseeds.su3`. [`HttpsReseeder`] fetches bundles from a list of servers
+//! in turn, verifies each against the bundled reseed signer certificates, and
+//! stores the RouterInfos they contain in the netDb.
+//!
+//! For routers that cannot reach any reseed server, [`reseed_from_path`] imports
+//! a bundle (or a directory of RouterInfo files) that was fetched out-of-band.
+
+use futures::{future, sync::mpsc, Async, Future, Poll};
 use native_tls::{Certificate, TlsConnector};
 use rand::{seq::SliceRandom, thread_rng};
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
 use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::str::FromStr;
 use std::time::Duration;
-use tokio::{io, net::tcp::TcpStream, timer::Timeout};
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite},
+    net::tcp::TcpStream,
+    timer::Timeout,
+};
 
-use super::client::{Client, StoreRouterInfo};
+use super::{
+    client::{Client, StoreRouterInfo},
+    persist,
+};
 use crate::crypto::{OfflineSigningPublicKey, SigType};
-use crate::file::{Error as FileError, Su3Content, Su3File};
+use crate::data::{
+    su3::{self, EntryError, Error as Su3Error, Su3File},
+    Hash, RouterInfo,
+};
+use crate::router::config::{self, Config};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
 // newest first, please add new ones at the top
 //
-// ((url, port), path)                      // certificates/reseed/      // certificates/ssl/          // notes
-// ----------------------------------       ------------------------     -------------------------     ---------------
+// url                                          // certificates/reseed/      // certificates/ssl/          // notes
+// ----------------------------------           ------------------------     -------------------------     ---------------
 #[cfg_attr(rustfmt, rustfmt_skip)]
-const DEFAULT_RESEED_HOSTS: [((&str, u16), &str); 12] = [
-    (("i2p.novg.net", 443), "/"),           // igor_at_novg.net.crt      // CA
-    (("i2pseed.creativecowpat.net", 8443), "/"), // creativecowpat_at_mail.i2p.crt // i2pseed.creativecowpat.net.crt
-    (("itoopie.atomike.ninja", 443), "/"),  // atomike_at_mail.i2p.crt   // CA
-    (("reseed.onion.im", 443), "/"),        // lazygravy_at_mail.i2p     // reseed.onion.im.crt
-    (("reseed.memcpy.io", 443), "/"),       // hottuna_at_mail.i2p.crt   // CA                         // SNI required
-    (("reseed.atomike.ninja", 443), "/"),   // atomike_at_mail.i2p.crt   // CA                         // SNI required
-    (("i2p.manas.ca", 8443), "/"),          // zmx_at_mail.i2p.crt       // CA                         // SNI required
-    (("i2p-0.manas.ca", 8443), "/"),        // zmx_at_mail.i2p.crt       // CA                         // SNI required
-    (("i2p.mooo.com", 443), "/netDb/"),     // bugme_at_mail.i2p.crt     // i2p.mooo.com.crt
-    (("download.xxlspeed.com", 443), "/"),  // backup_at_mail.i2p.crt    // CA
-    (("netdb.i2p2.no", 443), "/"),          // meeh_at_mail.i2p.crt      // CA                         // SNI required
-    (("reseed.i2p-projekt.de", 443), "/"),  // echelon_at_mail.i2p.crt   // echelon.reseed2017.crt
+const DEFAULT_RESEED_HOSTS: [&str; 12] = [
+    "https://i2p.novg.net/",                    // igor_at_novg.net.crt      // CA
+    "https://i2pseed.creativecowpat.net:8443/", // creativecowpat_at_mail.i2p.crt // i2pseed.creativecowpat.net.crt
+    "https://itoopie.atomike.ninja/",           // atomike_at_mail.i2p.crt   // CA
+    "https://reseed.onion.im/",                 // lazygravy_at_mail.i2p     // reseed.onion.im.crt
+    "https://reseed.memcpy.io/",                // hottuna_at_mail.i2p.crt   // CA                         // SNI required
+    "https://reseed.atomike.ninja/",            // atomike_at_mail.i2p.crt   // CA                         // SNI required
+    "https://i2p.manas.ca:8443/",               // zmx_at_mail.i2p.crt       // CA                         // SNI required
+    "https://i2p-0.manas.ca:8443/",             // zmx_at_mail.i2p.crt       // CA                         // SNI required
+    "https://i2p.mooo.com/netDb/",              // bugme_at_mail.i2p.crt     // i2p.mooo.com.crt
+    "https://download.xxlspeed.com/",           // backup_at_mail.i2p.crt    // CA
+    "https://netdb.i2p2.no/",                   // meeh_at_mail.i2p.crt      // CA                         // SNI required
+    "https://reseed.i2p-projekt.de/",           // echelon_at_mail.i2p.crt   // echelon.reseed2017.crt
 ];
 
+/// Errors that can occur while parsing a reseed server URL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UrlError {
+    /// Only `https://` and `http://` URLs are supported.
+    UnsupportedScheme,
+    MissingHost,
+    InvalidPort,
+    /// The path must end in `/`, as the bundle name is appended to it.
+    InvalidPath,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlError::UnsupportedScheme => "Unsupported URL scheme".fmt(f),
+            UrlError::MissingHost => "Missing host".fmt(f),
+            UrlError::InvalidPort => "Invalid port".fmt(f),
+            UrlError::InvalidPath => "Path must end with '/'".fmt(f),
+        }
+    }
+}
+
+/// A reseed server, serving `i2pseeds.su3` from the directory at `path`.
+///
+/// Plain HTTP servers are only useful for testing, as the bundle signature is
+/// then the only protection against a network attacker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReseedHost {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for ReseedHost {
+    type Err = UrlError;
+
+    /// Parses a URL of the form `https://host[:port]/path/`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (tls, rest, default_port) = if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest, 443)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest, 80)
+        } else {
+            return Err(UrlError::UnsupportedScheme);
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if !path.ends_with('/') {
+            return Err(UrlError::InvalidPath);
+        }
+
+        let (host, port) = match authority.rfind(':') {
+            Some(i) => (
+                &authority[..i],
+                authority[i + 1..]
+                    .parse()
+                    .map_err(|_| UrlError::InvalidPort)?,
+            ),
+            None => (authority, default_port),
+        };
+        if host.is_empty() {
+            return Err(UrlError::MissingHost);
+        }
+
+        Ok(ReseedHost {
+            tls,
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        })
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for ReseedHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}:{}{}",
+            if self.tls { "https" } else { "http" },
+            self.host,
+            self.port,
+            self.path
+        )
+    }
+}
+
+/// Progress of a reseed, for callers that want more than log output.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReseedEvent {
+    Fetching(ReseedHost),
+    /// A bundle was fetched and verified, and contained this many RouterInfos.
+    Fetched(ReseedHost, usize),
+    HostFailed(ReseedHost, String),
+    /// The reseed finished, having stored `valid` RouterInfos.
+    Finished {
+        servers: usize,
+        fetched: usize,
+        valid: usize,
+    },
+    /// Every server failed, and no RouterInfos were stored.
+    Failed,
+}
+
 macro_rules! reseed_cert {
     ($m:ident, $name:expr, $sig_type:ident, $der_file:expr) => {
         $m.insert(
@@ -82,7 +216,7 @@ const SSL_CERT_ECHELON: &[u8] =
 
 const MIN_RI_WANTED: usize = 100;
 const MIN_RESEED_SERVERS: usize = 2;
-/// Maximum response time for a single reseed server, in seconds.
+/// Default maximum response time for a single reseed server, in seconds.
 const PER_RESEED_TIMEOUT: u64 = 10;
 
 macro_rules! try_poll {
@@ -98,57 +232,80 @@ macro_rules! try_poll {
     };
 }
 
+/// Sends a request for the reseed bundle over `socket`, and reads the response.
+fn http_get<S>(socket: S, host: &ReseedHost) -> IoFuture<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let request = format!(
+        "\
+         GET {}i2pseeds.su3 HTTP/1.0\r\n\
+         Host: {}\r\n\
+         User-Agent: Wget/1.11.4\r\n\
+         \r\n\
+         ",
+        host.path, host.host
+    );
+    Box::new(
+        io::write_all(socket, request)
+            .and_then(|(socket, _)| io::read_to_end(socket, Vec::new()))
+            .map(|(_, data)| data),
+    )
+}
+
 fn reseed_from_host(
     cx: &TlsConnector,
-    (host, path): ((&'static str, u16), &'static str),
+    host: ReseedHost,
+    signers: &'static HashMap<&'static str, OfflineSigningPublicKey>,
+    timeout: Duration,
 ) -> IoFuture<Su3File> {
-    debug!("Reseeding from {}:{}", host.0, host.1);
-    let addr = host.to_socket_addrs().unwrap().next().unwrap();
+    debug!("Reseeding from {}", host);
+    let addr = match (host.host.as_str(), host.port)
+        .to_socket_addrs()
+        .and_then(|mut addrs| {
+            addrs
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Host has no addresses"))
+        }) {
+        Ok(addr) => addr,
+        Err(e) => return Box::new(future::err(e)),
+    };
 
     let socket = TcpStream::connect(&addr);
-    let cx = tokio_tls::TlsConnector::from(cx.clone());
-
-    let reseeder = socket
-        .and_then(move |socket| {
-            cx.connect(host.0, socket)
+    let response = if host.tls {
+        let cx = tokio_tls::TlsConnector::from(cx.clone());
+        let host = host.clone();
+        Box::new(socket.and_then(move |socket| {
+            cx.connect(&host.host, socket)
                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-        })
-        .and_then(move |socket| {
-            io::write_all(
-                socket,
-                format!(
-                    "\
-                     GET {}i2pseeds.su3 HTTP/1.0\r\n\
-                     Host: {}\r\n\
-                     User-Agent: Wget/1.11.4\r\n\
-                     \r\n\
-                     ",
-                    path, host.0
+                .and_then(move |socket| http_get(socket, &host))
+        })) as IoFuture<_>
+    } else {
+        let host = host.clone();
+        Box::new(socket.and_then(move |socket| http_get(socket, &host)))
+    };
+
+    let reseeder = response.and_then(move |data| {
+        Su3File::from_http_data(&data, signers).map_err(|e| match e {
+            Su3Error::Http(status) => match status {
+                401 | 402 | 403 | 451 => io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Permission denied ({})", status),
                 ),
-            )
+                404 => io::Error::new(io::ErrorKind::NotFound, "Reseed file not found"),
+                status => {
+                    io::Error::new(io::ErrorKind::Other, format!("HTTP status code {}", status))
+                }
+            },
+            e => io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid SU3 file: {}", e),
+            ),
         })
-        .and_then(|(socket, _)| io::read_to_end(socket, Vec::new()))
-        .and_then(|(_, data)| {
-            Su3File::from_http_data(&data, &RESEED_SIGNERS).map_err(|e| match e {
-                FileError::Http(status) => match status {
-                    401 | 402 | 403 | 451 => io::Error::new(
-                        io::ErrorKind::PermissionDenied,
-                        format!("Permission denied ({})", status),
-                    ),
-                    404 => io::Error::new(io::ErrorKind::NotFound, "Reseed file not found"),
-                    status => {
-                        io::Error::new(io::ErrorKind::Other, format!("HTTP status code {}", status))
-                    }
-                },
-                e => io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid SU3 file: {:?}", e),
-                ),
-            })
-        });
+    });
 
     // Add a timeout
-    let timed = Timeout::new(reseeder, Duration::new(PER_RESEED_TIMEOUT, 0)).map_err(|e| {
+    let timed = Timeout::new(reseeder, timeout).map_err(|e| {
         if e.is_inner() {
             e.into_inner().unwrap()
         } else if e.is_elapsed() {
@@ -166,24 +323,69 @@ fn reseed_from_host(
 }
 
 enum ReseedState {
-    Fetching(IoFuture<Su3File>),
+    Fetching(ReseedHost, IoFuture<Su3File>),
     Storing(future::SelectAll<StoreRouterInfo>),
     NextHost,
 }
 
 /// Fetches RouterInfos from reseed servers via HTTPS.
+///
+/// Servers are tried in order, each with its own timeout, until enough
+/// RouterInfos have been stored or every server has been tried.
 pub struct HttpsReseeder {
     state: Option<ReseedState>,
     netdb: Client,
     cx: TlsConnector,
-    pending: Vec<((&'static str, u16), &'static str)>,
+    signers: &'static HashMap<&'static str, OfflineSigningPublicKey>,
+    timeout: Duration,
+    events: Option<mpsc::UnboundedSender<ReseedEvent>>,
+    pending: Vec<ReseedHost>,
     succeeded: usize,
     fetched: usize,
     valid: usize,
 }
 
 impl HttpsReseeder {
+    /// Reseeds from the default servers, in a random order.
     pub fn new(netdb: Client) -> Self {
+        let mut hosts = default_hosts();
+        hosts.shuffle(&mut thread_rng());
+        HttpsReseeder::with_hosts(netdb, hosts)
+    }
+
+    /// Reseeds from the servers and timeout in the router config, falling back
+    /// to the defaults for anything that is unset.
+    pub fn from_config(netdb: Client, config: &Config) -> Self {
+        let hosts = match config.get_array(config::RESEED_HOSTS) {
+            Ok(urls) => urls
+                .into_iter()
+                .filter_map(|url| {
+                    let url = url.into_str().ok()?;
+                    match url.parse() {
+                        Ok(host) => Some(host),
+                        Err(e) => {
+                            warn!("Ignoring reseed server {}: {}", url, e);
+                            None
+                        }
+                    }
+                })
+                .collect(),
+            Err(_) => {
+                let mut hosts = default_hosts();
+                hosts.shuffle(&mut thread_rng());
+                hosts
+            }
+        };
+
+        let reseeder = HttpsReseeder::with_hosts(netdb, hosts);
+        match config.get_int(config::RESEED_TIMEOUT) {
+            Ok(secs) if secs > 0 => reseeder.timeout(Duration::from_secs(secs as u64)),
+            _ => reseeder,
+        }
+    }
+
+    /// Reseeds from the given servers, in order.
+    pub fn with_hosts(netdb: Client, hosts: Vec<ReseedHost>) -> Self {
         // Build TLS context with the necessary self-signed certificates
         let mut cx = TlsConnector::builder();
         cx.add_root_certificate(Certificate::from_pem(SSL_CERT_CREATIVECOWPAT_NET).unwrap());
@@ -192,20 +394,58 @@ impl HttpsReseeder {
         cx.add_root_certificate(Certificate::from_pem(SSL_CERT_ECHELON).unwrap());
         let cx = cx.build().unwrap();
 
-        let mut hosts: Vec<_> = DEFAULT_RESEED_HOSTS.to_vec();
-        hosts.shuffle(&mut thread_rng());
-        let active = reseed_from_host(&cx, hosts.swap_remove(0));
-
         HttpsReseeder {
-            state: Some(ReseedState::Fetching(active)),
+            state: Some(ReseedState::NextHost),
             netdb,
             cx,
+            signers: &RESEED_SIGNERS,
+            timeout: Duration::from_secs(PER_RESEED_TIMEOUT),
+            events: None,
             pending: hosts,
             succeeded: 0,
             fetched: 0,
             valid: 0,
         }
     }
+
+    /// Sets the maximum response time for each server.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sends progress events to `events`, in addition to logging them.
+    pub fn events(mut self, events: mpsc::UnboundedSender<ReseedEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn emit(&self, event: ReseedEvent) {
+        if let Some(events) = self.events.as_ref() {
+            // The receiver may have lost interest, which is fine
+            let _ = events.unbounded_send(event);
+        }
+    }
+
+    fn finish(&self) -> Poll<(), ()> {
+        info!(
+            "Fetched {} RouterInfos from {} servers ({} valid)",
+            self.fetched, self.succeeded, self.valid
+        );
+        self.emit(ReseedEvent::Finished {
+            servers: self.succeeded,
+            fetched: self.fetched,
+            valid: self.valid,
+        });
+        Ok(Async::Ready(()))
+    }
+}
+
+fn default_hosts() -> Vec<ReseedHost> {
+    DEFAULT_RESEED_HOSTS
+        .iter()
+        .map(|url| url.parse().unwrap())
+        .collect()
 }
 
 impl Future for HttpsReseeder {
@@ -215,12 +455,21 @@ impl Future for HttpsReseeder {
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             let next_state = match self.state.take().unwrap() {
-                ReseedState::Fetching(mut f) => {
-                    match try_poll!(f.poll(), self, ReseedState::Fetching(f)) {
-                        Ok(su3) => match su3.content {
-                            Su3Content::Reseed(new_ri) => {
+                ReseedState::Fetching(host, mut f) => {
+                    match try_poll!(f.poll(), self, ReseedState::Fetching(host, f)) {
+                        Ok(su3) => match su3.reseed_router_infos() {
+                            Ok(ref new_ri) if new_ri.is_empty() => {
+                                error!("Reseed file from {} contains no RouterInfos", host);
+                                self.emit(ReseedEvent::HostFailed(
+                                    host,
+                                    "No RouterInfos in reseed file".to_owned(),
+                                ));
+                                ReseedState::NextHost
+                            }
+                            Ok(new_ri) => {
                                 self.succeeded += 1;
                                 self.fetched += new_ri.len();
+                                self.emit(ReseedEvent::Fetched(host, new_ri.len()));
 
                                 ReseedState::Storing(future::select_all(new_ri.into_iter().map(
                                     |ri| {
@@ -229,9 +478,15 @@ impl Future for HttpsReseeder {
                                     },
                                 )))
                             }
+                            Err(e) => {
+                                error!("Invalid reseed file from {}: {}", host, e);
+                                self.emit(ReseedEvent::HostFailed(host, e.to_string()));
+                                ReseedState::NextHost
+                            }
                         },
                         Err(e) => {
-                            error!("Error while reseeding: {}", e);
+                            error!("Error while reseeding from {}: {}", host, e);
+                            self.emit(ReseedEvent::HostFailed(host, e.to_string()));
                             ReseedState::NextHost
                         }
                     }
@@ -251,11 +506,7 @@ impl Future for HttpsReseeder {
                     if remaining.is_empty() {
                         // Check if we are done reseeding
                         if self.valid >= MIN_RI_WANTED && self.succeeded >= MIN_RESEED_SERVERS {
-                            info!(
-                                "Fetched {} RouterInfos from {} servers ({} valid)",
-                                self.fetched, self.succeeded, self.valid
-                            );
-                            return Ok(Async::Ready(()));
+                            return self.finish();
                         } else {
                             ReseedState::NextHost
                         }
@@ -264,23 +515,21 @@ impl Future for HttpsReseeder {
                     }
                 }
                 ReseedState::NextHost => {
-                    // If we reach here, the active reseed has finished
+                    // If we reach here, the active reseed (if any) has finished
                     if self.pending.is_empty() {
                         if self.valid == 0 {
                             error!("Failed to reseed from any server");
+                            self.emit(ReseedEvent::Failed);
                             return Err(());
                         } else {
-                            info!(
-                                "Fetched {} RouterInfos from {} servers ({} valid)",
-                                self.fetched, self.succeeded, self.valid
-                            );
-                            return Ok(Async::Ready(()));
+                            return self.finish();
                         }
                     } else {
-                        ReseedState::Fetching(reseed_from_host(
-                            &self.cx,
-                            self.pending.swap_remove(0),
-                        ))
+                        let host = self.pending.remove(0);
+                        self.emit(ReseedEvent::Fetching(host.clone()));
+                        let f =
+                            reseed_from_host(&self.cx, host.clone(), self.signers, self.timeout);
+                        ReseedState::Fetching(host, f)
                     }
                 }
             };
@@ -288,3 +537,418 @@ impl Future for HttpsReseeder {
         }
     }
 }
+
+/// Starts the reseed configured for the router: from a local bundle or directory
+/// if one is set, and otherwise from reseed servers.
+pub fn from_config(
+    netdb: Client,
+    config: &Config,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    match config.get_str(config::RESEED_FROM) {
+        Ok(path) => Box::new(
+            reseed_from_path(netdb, Path::new(&path))
+                .map(move |report| {
+                    info!(
+                        "Imported {} RouterInfos from {} ({} rejected)",
+                        report.accepted, path, report.rejected
+                    )
+                })
+                .map_err(|e| error!("Failed to reseed from file: {}", e)),
+        ),
+        Err(_) => Box::new(HttpsReseeder::from_config(netdb, config)),
+    }
+}
+
+/// The outcome of reseeding from a local file or directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImportReport {
+    /// RouterInfos that were stored in the netDb.
+    pub accepted: usize,
+    /// Entries that could not be read, or that contained an invalid RouterInfo.
+    pub rejected: usize,
+}
+
+type LocalEntry = (String, Result<(Hash, RouterInfo), EntryError>);
+
+/// Reads every RouterInfo file in a local reseed, along with the key it should be
+/// stored at.
+fn read_local_reseed(
+    path: &Path,
+    signers: &HashMap<&'static str, OfflineSigningPublicKey>,
+) -> io::Result<Vec<LocalEntry>> {
+    if !path.is_dir() {
+        let su3 = Su3File::from_bytes(&fs::read(path)?, signers)
+            .and_then(|su3| su3.reseed_entries())
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid SU3 file: {}", e),
+                )
+            })?;
+        return Ok(su3
+            .into_iter()
+            .map(|(name, ri)| (name, ri.map(|ri| (ri.router_id.hash(), ri))))
+            .collect());
+    }
+
+    // Accept both a flat directory and a netDb-style sharded one
+    let mut files = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            for entry in fs::read_dir(entry.path())? {
+                files.push(entry?.path());
+            }
+        } else {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .filter_map(|path| {
+            let hash = persist::hash_from_file_name(path.file_name()?.to_str()?)?;
+            let ri = fs::File::open(&path)
+                .map_err(|e| EntryError::Read(e.into()))
+                .and_then(su3::read_entry);
+            Some((path.display().to_string(), ri.map(|ri| (hash, ri))))
+        })
+        .collect())
+}
+
+/// Reseeds from a local SU3 bundle, or from a directory of
+/// `routerInfo-<base64 hash>.dat` files.
+///
+/// Bundles must be signed by a trusted reseed signer, and the RouterInfos are
+/// verified exactly as if they had been fetched from a reseed server. Entries
+/// that fail are logged and counted in the returned report, and do not stop
+/// the rest of the import.
+pub fn reseed_from_path(netdb: Client, path: &Path) -> IoFuture<ImportReport> {
+    debug!("Reseeding from {}", path.display());
+    let entries = match read_local_reseed(path, &RESEED_SIGNERS) {
+        Ok(entries) => entries,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let mut rejected = 0;
+    let stores: Vec<_> = entries
+        .into_iter()
+        .filter_map(|(name, entry)| match entry {
+            Ok((hash, ri)) => Some(netdb.store_router_info(hash, ri, true).then(
+                move |res| -> io::Result<bool> {
+                    if let Err(e) = &res {
+                        warn!("Invalid RouterInfo {} in reseed: {}", name, e);
+                    }
+                    Ok(res.is_ok())
+                },
+            )),
+            Err(e) => {
+                warn!("Error while reading {} from reseed: {}", name, e);
+                rejected += 1;
+                None
+            }
+        })
+        .collect();
+
+    Box::new(future::join_all(stores).map(move |stored| {
+        let accepted = stored.iter().filter(|stored| **stored).count();
+        ImportReport {
+            accepted,
+            rejected: rejected + stored.len() - accepted,
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Future, Stream};
+    use std::fs;
+    use std::io::{self, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    use super::{reseed_from_path, HttpsReseeder, ImportReport, ReseedEvent, ReseedHost, UrlError};
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::netdb::{client::Client, persist, LocalNetworkDatabase};
+    use crate::router::mock::mock_context;
+    use crate::tests::I2PSEEDS_SU3;
+
+    /// Starts a reseed server on localhost that answers every request with the
+    /// given HTTP status line and body.
+    fn stub_server(status: &'static str, body: Vec<u8>) -> ReseedHost {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+
+                let (status, body) = if request.starts_with(b"GET /i2pseeds.su3 HTTP/1.0\r\n") {
+                    (status, &body[..])
+                } else {
+                    ("400 Bad Request", &[][..])
+                };
+                let _ = stream.write_all(format!("HTTP/1.0 {}\r\n\r\n", status).as_bytes());
+                let _ = stream.write_all(body);
+            }
+        });
+        format!("http://127.0.0.1:{}/", port).parse().unwrap()
+    }
+
+    /// Starts a reseed server on localhost that accepts connections and then
+    /// never responds.
+    fn silent_server() -> ReseedHost {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut streams = vec![];
+            for stream in listener.incoming() {
+                streams.push(stream);
+            }
+        });
+        format!("http://127.0.0.1:{}/", port).parse().unwrap()
+    }
+
+    fn tampered_su3() -> Vec<u8> {
+        let mut reseed = I2PSEEDS_SU3.to_vec();
+        reseed[I2PSEEDS_SU3.len() / 2] ^= 0x01;
+        reseed
+    }
+
+    /// Runs the future returned by `f` against a real netDb, returning its result
+    /// and the number of routers then in the netDb.
+    fn run_with_netdb<F, R>(f: F) -> (Result<R::Item, R::Error>, usize)
+    where
+        F: FnOnce(Client) -> R,
+        R: Future + Send + 'static,
+        R::Item: Send + 'static,
+        R::Error: Send + 'static,
+    {
+        let mut rt = Runtime::new().unwrap();
+
+        let (client_tx, client_rx) = mpsc::unbounded();
+        let (pending_tx, _) = mpsc::channel(0);
+        let netdb = Arc::new(Mutex::new(LocalNetworkDatabase::new(
+            mock_context(),
+            pending_tx,
+        )));
+        let handler = netdb.clone();
+        rt.spawn(client_rx.for_each(move |q| {
+            q.handle(&mut handler.lock().unwrap());
+            Ok(())
+        }));
+
+        let res = rt.block_on(f(Client::new(client_tx)));
+        let known_routers = netdb.lock().unwrap().known_routers();
+        (res, known_routers)
+    }
+
+    /// Runs a reseed from the given hosts against a real netDb, returning the
+    /// result, the events emitted, and the number of routers then in the netDb.
+    fn run_reseed(hosts: Vec<ReseedHost>) -> (Result<(), ()>, Vec<ReseedEvent>, usize) {
+        let (events_tx, events_rx) = mpsc::unbounded();
+        let (res, known_routers) = run_with_netdb(|netdb| {
+            HttpsReseeder::with_hosts(netdb, hosts)
+                .timeout(Duration::from_secs(1))
+                .events(events_tx)
+        });
+
+        let events = events_rx.collect().wait().unwrap();
+        (res, events, known_routers)
+    }
+
+    #[test]
+    fn parse_reseed_host() {
+        assert_eq!(
+            "https://reseed.i2p-projekt.de/".parse(),
+            Ok(ReseedHost {
+                tls: true,
+                host: "reseed.i2p-projekt.de".to_owned(),
+                port: 443,
+                path: "/".to_owned(),
+            })
+        );
+        assert_eq!(
+            "http://127.0.0.1:8080/netDb/".parse(),
+            Ok(ReseedHost {
+                tls: false,
+                host: "127.0.0.1".to_owned(),
+                port: 8080,
+                path: "/netDb/".to_owned(),
+            })
+        );
+        assert_eq!(
+            "https://i2p.novg.net".parse::<ReseedHost>().map(|h| h.path),
+            Ok("/".to_owned())
+        );
+
+        assert_eq!(
+            "ftp://i2p.novg.net/".parse::<ReseedHost>(),
+            Err(UrlError::UnsupportedScheme)
+        );
+        assert_eq!(
+            "https://:443/".parse::<ReseedHost>(),
+            Err(UrlError::MissingHost)
+        );
+        assert_eq!(
+            "https://i2p.novg.net:port/".parse::<ReseedHost>(),
+            Err(UrlError::InvalidPort)
+        );
+        assert_eq!(
+            "https://i2p.novg.net/i2pseeds.su3".parse::<ReseedHost>(),
+            Err(UrlError::InvalidPath)
+        );
+    }
+
+    #[test]
+    fn reseed_skips_failed_hosts() {
+        let silent = silent_server();
+        let tampered = stub_server("200 OK", tampered_su3());
+        let missing = stub_server("404 Not Found", vec![]);
+        let good = stub_server("200 OK", I2PSEEDS_SU3.to_vec());
+
+        let (res, events, known_routers) = run_reseed(vec![
+            silent.clone(),
+            tampered.clone(),
+            missing.clone(),
+            good.clone(),
+        ]);
+        assert_eq!(res, Ok(()));
+        assert_eq!(events.len(), 9);
+
+        for (i, host) in [&silent, &tampered, &missing].iter().enumerate() {
+            assert_eq!(events[2 * i], ReseedEvent::Fetching((*host).clone()));
+            match &events[2 * i + 1] {
+                ReseedEvent::HostFailed(failed, _) => assert_eq!(failed, *host),
+                e => panic!("Unexpected event: {:?}", e),
+            }
+        }
+        assert_eq!(events[6], ReseedEvent::Fetching(good.clone()));
+        assert_eq!(events[7], ReseedEvent::Fetched(good, 75));
+        match events[8] {
+            ReseedEvent::Finished {
+                servers,
+                fetched,
+                valid,
+            } => {
+                assert_eq!(servers, 1);
+                assert_eq!(fetched, 75);
+                assert!(valid > 0);
+                assert_eq!(known_routers, valid);
+            }
+            ref e => panic!("Unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reseed_fails_without_valid_hosts() {
+        let tampered = stub_server("200 OK", tampered_su3());
+
+        let (res, events, known_routers) = run_reseed(vec![tampered.clone()]);
+        assert_eq!(res, Err(()));
+        assert_eq!(known_routers, 0);
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0], ReseedEvent::Fetching(tampered.clone()));
+        match &events[1] {
+            ReseedEvent::HostFailed(failed, _) => assert_eq!(failed, &tampered),
+            e => panic!("Unexpected event: {:?}", e),
+        }
+        assert_eq!(events[2], ReseedEvent::Failed);
+    }
+
+    #[test]
+    fn reseed_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir.path().join("i2pseeds.su3");
+
+        fs::write(&bundle, &I2PSEEDS_SU3[..]).unwrap();
+        let (res, known_routers) = run_with_netdb(|netdb| reseed_from_path(netdb, &bundle));
+        let report = res.unwrap();
+        assert!(report.accepted > 0);
+        assert_eq!(report.accepted + report.rejected, 75);
+        assert_eq!(known_routers, report.accepted);
+
+        // A bundle that fails verification is rejected as a whole
+        fs::write(&bundle, tampered_su3()).unwrap();
+        let (res, known_routers) = run_with_netdb(|netdb| reseed_from_path(netdb, &bundle));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(known_routers, 0);
+
+        // As is a missing file
+        let missing = dir.path().join("missing.su3");
+        let (res, _) = run_with_netdb(|netdb| reseed_from_path(netdb, &missing));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn reseed_from_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let signed_router_info = || {
+            let rsk = RouterSecretKeys::new();
+            let mut ri = RouterInfo::new(rsk.rid);
+            ri.sign(&rsk.signing_private_key);
+            ri
+        };
+        let flat_path = |ri: &RouterInfo| {
+            let path = persist::router_info_path(dir.path(), &ri.router_id.hash());
+            dir.path().join(path.file_name().unwrap())
+        };
+
+        // Valid RouterInfos, both in netDb shards and directly in the directory
+        for _ in 0..5 {
+            persist::write_router_info(dir.path(), &signed_router_info()).unwrap();
+        }
+        for _ in 0..3 {
+            let ri = signed_router_info();
+            fs::write(flat_path(&ri), ri.to_bytes()).unwrap();
+        }
+
+        // Truncated
+        let ri = signed_router_info();
+        let data = ri.to_bytes();
+        fs::write(flat_path(&ri), &data[..data.len() - 10]).unwrap();
+
+        // Bad signature
+        let ri = signed_router_info();
+        let mut data = ri.to_bytes();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(flat_path(&ri), &data).unwrap();
+
+        // Stored under the wrong hash
+        let ri = signed_router_info();
+        fs::write(flat_path(&signed_router_info()), ri.to_bytes()).unwrap();
+
+        // Far too large
+        let ri = signed_router_info();
+        let mut data = ri.to_bytes();
+        data.resize(1024 * 1024, 0);
+        fs::write(flat_path(&ri), &data).unwrap();
+
+        // Unrelated files are ignored
+        fs::write(dir.path().join("README"), b"foo").unwrap();
+
+        let (res, known_routers) = run_with_netdb(|netdb| reseed_from_path(netdb, dir.path()));
+        assert_eq!(
+            res.unwrap(),
+            ImportReport {
+                accepted: 8,
+                rejected: 4,
+            }
+        );
+        assert_eq!(known_routers, 8);
+    }
+}
@@ -0,0 +1,458 @@
+//! Answering DatabaseLookup messages from other routers.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{
+    limits::{RateLimiter, LIMIT_PERIOD},
+    LocalNetworkDatabase,
+};
+use crate::data::Hash;
+use crate::i2np::{
+    DatabaseLookup, DatabaseLookupType, DatabaseSearchReply, DatabaseStore, Message, MessagePayload,
+};
+use crate::router::config::{self, Config};
+
+/// The number of peers returned in a DatabaseSearchReply.
+const MAX_ROUTERS_RETURNED: usize = 3;
+
+/// The number of lookups we will answer from a single source per limit period.
+const MAX_LOOKUPS_PER_PERIOD: u32 = 20;
+
+/// The number of replies we will send per limit period to a single router that
+/// did not send us the lookups it is named in.
+const MAX_THIRD_PARTY_REPLIES_PER_PERIOD: u32 = 10;
+
+/// Counts of the lookups we have been sent, by outcome.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LookupStats {
+    /// We had the entry, and replied with a DatabaseStore.
+    pub hits: u64,
+    /// We didn't have the entry, and replied with a DatabaseSearchReply.
+    pub misses: u64,
+    /// The lookup was malformed or unsupported, and was dropped.
+    pub invalid: u64,
+    /// The source has sent too many lookups recently, so we dropped it.
+    pub throttled: u64,
+    /// The reply would have gone to a router other than the source, which we
+    /// have sent too many such replies recently, so we dropped it.
+    pub third_party: u64,
+}
+
+/// Decides how to answer inbound DatabaseLookup messages, throttling sources
+/// that send too many, and routers that are sent too many replies they didn't
+/// ask for.
+pub(super) struct LookupResponder {
+    our_hash: Hash,
+    sources: RateLimiter,
+    third_parties: RateLimiter,
+    stats: LookupStats,
+}
+
+impl LookupResponder {
+    pub(super) fn new(our_hash: Hash, max_lookups: u32, max_third_party: u32) -> Self {
+        let period = Duration::from_secs(LIMIT_PERIOD);
+        LookupResponder {
+            our_hash,
+            sources: RateLimiter::new(max_lookups, period),
+            third_parties: RateLimiter::new(max_third_party, period),
+            stats: LookupStats::default(),
+        }
+    }
+
+    pub(super) fn from_config(our_hash: Hash, config: &Config) -> Self {
+        let get = |key: &str, default: u32| {
+            config
+                .get_int(key)
+                .ok()
+                .map(|v| v as u32)
+                .unwrap_or(default)
+        };
+        LookupResponder::new(
+            our_hash,
+            get(config::NETDB_LIMITS_LOOKUPS, MAX_LOOKUPS_PER_PERIOD),
+            get(
+                config::NETDB_LIMITS_THIRD_PARTY_REPLIES,
+                MAX_THIRD_PARTY_REPLIES_PER_PERIOD,
+            ),
+        )
+    }
+
+    pub(super) fn stats(&self) -> LookupStats {
+        self.stats
+    }
+
+    /// Returns the reply to `dl`, which was sent to us by `source`, and the
+    /// router it should be sent to, or `None` if the lookup should be dropped.
+    pub(super) fn respond(
+        &mut self,
+        netdb: &LocalNetworkDatabase,
+        source: &Hash,
+        dl: &DatabaseLookup,
+        now: Instant,
+    ) -> Option<(Hash, Message)> {
+        if *dl.from() == self.our_hash {
+            debug!("Dropping DatabaseLookup claiming to be from us");
+            self.stats.invalid += 1;
+            return None;
+        }
+        if dl.wants_encrypted_reply() {
+            // TODO: Garlic-encrypt the reply to the provided session key
+            debug!("Dropping DatabaseLookup requesting an encrypted reply");
+            self.stats.invalid += 1;
+            return None;
+        }
+        if !self.sources.allow(source, now) {
+            debug!("Throttling DatabaseLookups from {}", source);
+            self.stats.throttled += 1;
+            return None;
+        }
+        if dl.from() != source && !self.third_parties.allow(dl.from(), now) {
+            debug!(
+                "Dropping DatabaseLookup from {} with reply to {}",
+                source,
+                dl.from()
+            );
+            self.stats.third_party += 1;
+            return None;
+        }
+
+        let key = dl.key();
+        let found = match dl.lookup_type() {
+            DatabaseLookupType::Any | DatabaseLookupType::RouterInfo => netdb
+                .router_info(key)
+                .map(|ri| DatabaseStore::from_ri((*ri).clone(), None)),
+            _ => None,
+        }
+        .or_else(|| match dl.lookup_type() {
+            DatabaseLookupType::Any | DatabaseLookupType::LeaseSet => {
+                netdb.lease_set(key).map(|ls| ls.to_database_store())
+            }
+            _ => None,
+        });
+
+        let payload = match found {
+            Some(ds) => {
+                self.stats.hits += 1;
+                MessagePayload::DatabaseStore(ds)
+            }
+            None => {
+                self.stats.misses += 1;
+                MessagePayload::DatabaseSearchReply(DatabaseSearchReply {
+                    key: key.clone(),
+                    peers: self.closest_peers(netdb, dl),
+                    from: self.our_hash.clone(),
+                })
+            }
+        };
+        let ids = &netdb.ctx.msg_ids;
+        let reply = Message::from_payload(ids, payload);
+
+        Some(match dl.reply_tid() {
+            Some(tid) => (dl.from().clone(), Message::tunnel_gateway(ids, tid, &reply)),
+            None => (dl.from().clone(), reply),
+        })
+    }
+
+    /// Returns the peers to suggest in a DatabaseSearchReply: the closest
+    /// floodfills to the key, or for exploratory lookups the closest routers
+    /// that are not floodfills.
+    fn closest_peers(&self, netdb: &LocalNetworkDatabase, dl: &DatabaseLookup) -> Vec<Hash> {
+        let mut exclude = dl.excluded_peers().to_vec();
+        exclude.push(dl.from().clone());
+        exclude.push(self.our_hash.clone());
+
+        match dl.lookup_type() {
+            DatabaseLookupType::Exploratory => {
+                let now = SystemTime::now();
+                let rk = dl.key().routing_key(now);
+                let mut peers: Vec<_> = netdb
+                    .ri_ds
+                    .snapshot()
+                    .into_iter()
+                    .filter(|ri| !ri.is_floodfill() && !netdb.is_expired(ri, now))
+                    .map(|ri| ri.router_id.hash())
+                    .filter(|hash| !exclude.contains(hash))
+                    .collect();
+                peers.sort_by(|a, b| rk.cmp_distance(a, b));
+                peers.truncate(MAX_ROUTERS_RETURNED);
+                peers
+            }
+            _ => netdb
+                .closest_floodfills(dl.key(), MAX_ROUTERS_RETURNED, &exclude)
+                .into_iter()
+                .take(MAX_ROUTERS_RETURNED)
+                .map(|ri| ri.router_id.hash())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{
+        LookupResponder, LookupStats, LIMIT_PERIOD, MAX_LOOKUPS_PER_PERIOD, MAX_ROUTERS_RETURNED,
+        MAX_THIRD_PARTY_REPLIES_PER_PERIOD,
+    };
+    use crate::crypto::{EncType, SigType};
+    use crate::data::{DestinationSecretKeys, Hash, I2PDate, Lease, LeaseSet, TunnelId};
+    use crate::i2np::{
+        DatabaseLookup, DatabaseLookupType, DatabaseStoreData, MessagePayload, MessageType,
+    };
+    use crate::netdb::mock::populated_netdb;
+
+    fn responder(us: Hash) -> LookupResponder {
+        LookupResponder::new(
+            us,
+            MAX_LOOKUPS_PER_PERIOD,
+            MAX_THIRD_PARTY_REPLIES_PER_PERIOD,
+        )
+    }
+
+    fn closest_floodfills(floodfills: &[Hash], key: &Hash, exclude: &[Hash]) -> Vec<Hash> {
+        let rk = key.routing_key(SystemTime::now());
+        let mut sorted: Vec<_> = floodfills
+            .iter()
+            .filter(|hash| !exclude.contains(hash))
+            .cloned()
+            .collect();
+        sorted.sort_by(|a, b| rk.cmp_distance(a, b));
+        sorted.truncate(MAX_ROUTERS_RETURNED);
+        sorted
+    }
+
+    #[test]
+    fn present_keys() {
+        let (mut netdb, floodfills) = populated_netdb(10, 10);
+        let mut responder = responder(Hash([0xff; 32]));
+        let requester = Hash([1; 32]);
+        let now = Instant::now();
+
+        // RouterInfos are returned for RouterInfo and Any lookups
+        let key = floodfills[3].clone();
+        for lookup_type in &[DatabaseLookupType::RouterInfo, DatabaseLookupType::Any] {
+            let dl = DatabaseLookup::new(key.clone(), requester.clone(), *lookup_type);
+            let (to, reply) = responder.respond(&netdb, dl.from(), &dl, now).unwrap();
+            assert_eq!(to, requester);
+            match reply.payload {
+                MessagePayload::DatabaseStore(ds) => {
+                    assert_eq!(ds.key, key);
+                    match ds.data {
+                        DatabaseStoreData::RI(ri) => assert_eq!(ri.router_id.hash(), key),
+                        _ => panic!("Expected a RouterInfo"),
+                    }
+                }
+                _ => panic!("Expected a DatabaseStore"),
+            }
+        }
+
+        // LeaseSets are returned for LeaseSet and Any lookups
+        let dsk = DestinationSecretKeys::new(SigType::Ed25519, EncType::ElGamal2048);
+        let end_date = I2PDate::from_system_time(SystemTime::now() + Duration::from_secs(600));
+        let ls = LeaseSet::signed(
+            dsk.dest.clone(),
+            dsk.dest.public_key.clone(),
+            dsk.dest.signing_key.clone(),
+            vec![Lease::new(Hash([1; 32]), TunnelId(42), end_date)],
+            &dsk.signing_private_key,
+        )
+        .unwrap();
+        let ls_key = dsk.dest.hash();
+        netdb.store_lease_set(ls_key.clone(), ls).unwrap();
+        for lookup_type in &[DatabaseLookupType::LeaseSet, DatabaseLookupType::Any] {
+            let dl = DatabaseLookup::new(ls_key.clone(), requester.clone(), *lookup_type);
+            let (_, reply) = responder.respond(&netdb, dl.from(), &dl, now).unwrap();
+            match reply.payload {
+                MessagePayload::DatabaseStore(ds) => match ds.data {
+                    DatabaseStoreData::LS(ls) => assert_eq!(ls.dest.hash(), ls_key),
+                    _ => panic!("Expected a LeaseSet"),
+                },
+                _ => panic!("Expected a DatabaseStore"),
+            }
+        }
+
+        // Entries of the wrong type are not returned
+        let dl = DatabaseLookup::new(ls_key, requester.clone(), DatabaseLookupType::RouterInfo);
+        let (_, reply) = responder.respond(&netdb, dl.from(), &dl, now).unwrap();
+        assert_eq!(reply.message_type(), MessageType::DatabaseSearchReply);
+
+        // Replies can be sent via a tunnel
+        let gateway = Hash([2; 32]);
+        let dl = DatabaseLookup::new(key, gateway.clone(), DatabaseLookupType::RouterInfo)
+            .reply_tunnel(TunnelId(1234));
+        let (to, reply) = responder.respond(&netdb, dl.from(), &dl, now).unwrap();
+        assert_eq!(to, gateway);
+        assert_eq!(reply.message_type(), MessageType::TunnelGateway);
+
+        assert_eq!(
+            responder.stats(),
+            LookupStats {
+                hits: 5,
+                misses: 1,
+                invalid: 0,
+                throttled: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn absent_keys() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let us = Hash([0xff; 32]);
+        let mut responder = responder(us.clone());
+        let requester = floodfills[0].clone();
+        let key = Hash::digest(b"absent");
+
+        // The closest floodfills are suggested, skipping the requester
+        let dl = DatabaseLookup::new(key.clone(), requester.clone(), DatabaseLookupType::Any);
+        let (to, reply) = responder
+            .respond(&netdb, dl.from(), &dl, Instant::now())
+            .unwrap();
+        assert_eq!(to, requester);
+        match reply.payload {
+            MessagePayload::DatabaseSearchReply(dsr) => {
+                assert_eq!(dsr.key, key);
+                assert_eq!(dsr.from, us);
+                assert_eq!(
+                    dsr.peers,
+                    closest_floodfills(&floodfills, &key, &[requester.clone()])
+                );
+            }
+            _ => panic!("Expected a DatabaseSearchReply"),
+        }
+
+        // Excluded peers are skipped
+        let exclude = closest_floodfills(&floodfills, &key, &[])[..2].to_vec();
+        let dl = DatabaseLookup::new(key.clone(), requester.clone(), DatabaseLookupType::Any)
+            .excluding(exclude.clone());
+        let (_, reply) = responder
+            .respond(&netdb, dl.from(), &dl, Instant::now())
+            .unwrap();
+        match reply.payload {
+            MessagePayload::DatabaseSearchReply(dsr) => {
+                let mut all_excluded = exclude;
+                all_excluded.push(requester.clone());
+                assert_eq!(
+                    dsr.peers,
+                    closest_floodfills(&floodfills, &key, &all_excluded)
+                );
+            }
+            _ => panic!("Expected a DatabaseSearchReply"),
+        }
+
+        // Exploratory lookups return routers that are not floodfills
+        let dl = DatabaseLookup::new(key, requester, DatabaseLookupType::Exploratory);
+        let (_, reply) = responder
+            .respond(&netdb, dl.from(), &dl, Instant::now())
+            .unwrap();
+        match reply.payload {
+            MessagePayload::DatabaseSearchReply(dsr) => {
+                assert_eq!(dsr.peers.len(), MAX_ROUTERS_RETURNED);
+                assert!(dsr.peers.iter().all(|peer| !netdb
+                    .ri_ds
+                    .get(peer)
+                    .unwrap()
+                    .is_floodfill()));
+            }
+            _ => panic!("Expected a DatabaseSearchReply"),
+        }
+
+        assert_eq!(responder.stats().misses, 3);
+    }
+
+    #[test]
+    fn everything_excluded() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let mut responder = responder(Hash([0xff; 32]));
+
+        let dl = DatabaseLookup::new(
+            Hash::digest(b"absent"),
+            Hash([1; 32]),
+            DatabaseLookupType::RouterInfo,
+        )
+        .excluding(floodfills);
+        let (_, reply) = responder
+            .respond(&netdb, dl.from(), &dl, Instant::now())
+            .unwrap();
+        match reply.payload {
+            MessagePayload::DatabaseSearchReply(dsr) => assert!(dsr.peers.is_empty()),
+            _ => panic!("Expected a DatabaseSearchReply"),
+        }
+    }
+
+    #[test]
+    fn invalid_and_throttled() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let us = Hash([0xff; 32]);
+        let mut responder = responder(us.clone());
+        let key = floodfills[0].clone();
+        let now = Instant::now();
+
+        // Lookups claiming to be from us are dropped
+        let dl = DatabaseLookup::new(key.clone(), us, DatabaseLookupType::Any);
+        assert!(responder.respond(&netdb, dl.from(), &dl, now).is_none());
+
+        // Each source gets a limited number of lookups per period
+        let requester = Hash([1; 32]);
+        let dl = DatabaseLookup::new(key.clone(), requester, DatabaseLookupType::Any);
+        for _ in 0..MAX_LOOKUPS_PER_PERIOD {
+            assert!(responder.respond(&netdb, dl.from(), &dl, now).is_some());
+        }
+        assert!(responder.respond(&netdb, dl.from(), &dl, now).is_none());
+
+        // Other sources are unaffected
+        let other = DatabaseLookup::new(key, Hash([2; 32]), DatabaseLookupType::Any);
+        assert!(responder
+            .respond(&netdb, other.from(), &other, now)
+            .is_some());
+
+        // The limit resets after the period
+        let later = now + Duration::from_secs(LIMIT_PERIOD);
+        assert!(responder.respond(&netdb, dl.from(), &dl, later).is_some());
+
+        assert_eq!(
+            responder.stats(),
+            LookupStats {
+                hits: u64::from(MAX_LOOKUPS_PER_PERIOD) + 2,
+                misses: 0,
+                invalid: 1,
+                throttled: 1,
+                third_party: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn third_party_replies() {
+        let (netdb, floodfills) = populated_netdb(10, 10);
+        let mut responder = responder(Hash([0xff; 32]));
+        let key = floodfills[0].clone();
+        let now = Instant::now();
+
+        // Several sources asking for replies to be sent to the same victim share
+        // a limit
+        let victim = Hash([1; 32]);
+        let dl = DatabaseLookup::new(key.clone(), victim.clone(), DatabaseLookupType::Any);
+        for i in 0..MAX_THIRD_PARTY_REPLIES_PER_PERIOD {
+            let source = Hash([10 + i as u8; 32]);
+            let (to, _) = responder.respond(&netdb, &source, &dl, now).unwrap();
+            assert_eq!(to, victim);
+        }
+        assert!(responder
+            .respond(&netdb, &Hash([2; 32]), &dl, now)
+            .is_none());
+
+        // The victim can still look up entries for itself
+        assert!(responder.respond(&netdb, &victim, &dl, now).is_some());
+
+        // Replies to other routers are unaffected
+        let other = DatabaseLookup::new(key, Hash([3; 32]), DatabaseLookupType::Any);
+        assert!(responder
+            .respond(&netdb, &Hash([2; 32]), &other, now)
+            .is_some());
+
+        assert_eq!(responder.stats().third_party, 1);
+        assert_eq!(responder.stats().throttled, 0);
+    }
+}
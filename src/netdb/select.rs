@@ -0,0 +1,530 @@
+//! Choosing peers from the netDb to build tunnels through or send to.
+//!
+//! Peers are chosen at random from the RouterInfos that meet the caller's
+//! criteria, never including ourselves or peers whose profiles show that they
+//! are failing, banned, or refusing to join our tunnels. By default at most one peer is chosen from each /16
+//! (IPv4) or /48 (IPv6) subnet, so that a single operator can't easily supply
+//! several hops of one tunnel.
+//!
+//! Callers that want fast peers can ask for those with the higher capacity
+//! scores to be tried first; the choice within each half stays random.
+
+use rand::{seq::SliceRandom, Rng};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use crate::data::{Bandwidth, Hash, RouterInfo};
+use crate::router::profiles::Profiles;
+
+/// Whether floodfills may be selected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloodfillPolicy {
+    Allow,
+    Exclude,
+    Require,
+}
+
+/// The peers that [`Client::select_peers`] should return.
+///
+/// [`Client::select_peers`]: super::client::Client::select_peers
+#[derive(Clone, Debug)]
+pub struct PeerCriteria {
+    count: usize,
+    min_bandwidth: Option<Bandwidth>,
+    floodfills: FloodfillPolicy,
+    exclude: Vec<Hash>,
+    prefer_connected: bool,
+    prefer_high_capacity: bool,
+    distinct_subnets: bool,
+}
+
+impl PeerCriteria {
+    /// Selects up to `count` peers, at most one from each subnet.
+    pub fn new(count: usize) -> Self {
+        PeerCriteria {
+            count,
+            min_bandwidth: None,
+            floodfills: FloodfillPolicy::Allow,
+            exclude: vec![],
+            prefer_connected: false,
+            prefer_high_capacity: false,
+            distinct_subnets: true,
+        }
+    }
+
+    /// Only selects peers advertising at least this bandwidth class.
+    pub fn min_bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.min_bandwidth = Some(bandwidth);
+        self
+    }
+
+    pub fn floodfills(mut self, policy: FloodfillPolicy) -> Self {
+        self.floodfills = policy;
+        self
+    }
+
+    /// Never selects any of these peers.
+    pub fn exclude(mut self, peers: Vec<Hash>) -> Self {
+        self.exclude.extend(peers);
+        self
+    }
+
+    /// Selects peers we are connected to before any others.
+    pub fn prefer_connected(mut self, prefer: bool) -> Self {
+        self.prefer_connected = prefer;
+        self
+    }
+
+    /// Selects peers from the half with the highest capacity scores before any
+    /// others. Applied before [`PeerCriteria::prefer_connected`].
+    pub fn prefer_high_capacity(mut self, prefer: bool) -> Self {
+        self.prefer_high_capacity = prefer;
+        self
+    }
+
+    /// Controls whether at most one peer is selected from each subnet.
+    pub fn distinct_subnets(mut self, distinct: bool) -> Self {
+        self.distinct_subnets = distinct;
+        self
+    }
+
+    fn accepts(&self, ri: &RouterInfo, hash: &Hash) -> bool {
+        if self.exclude.contains(hash) {
+            return false;
+        }
+
+        let caps = ri.caps();
+        if let Some(min) = self.min_bandwidth {
+            match caps.as_ref().and_then(|caps| caps.bandwidth_class()) {
+                Some(bandwidth) if bandwidth >= min => (),
+                _ => return false,
+            }
+        }
+
+        let floodfill = caps.map(|caps| caps.is_floodfill()).unwrap_or(false);
+        match self.floodfills {
+            FloodfillPolicy::Allow => true,
+            FloodfillPolicy::Exclude => !floodfill,
+            FloodfillPolicy::Require => floodfill,
+        }
+    }
+}
+
+/// A /16 IPv4 or /48 IPv6 subnet.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(super) enum Subnet {
+    V4([u8; 2]),
+    V6([u16; 3]),
+}
+
+impl From<IpAddr> for Subnet {
+    fn from(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => {
+                let o = ip.octets();
+                Subnet::V4([o[0], o[1]])
+            }
+            IpAddr::V6(ip) => {
+                let s = ip.segments();
+                Subnet::V6([s[0], s[1], s[2]])
+            }
+        }
+    }
+}
+
+/// Selects peers from `candidates` that meet `criteria`, skipping ourselves
+/// (`us`) and any peers that `profiles` show to be failing, banned or rejecting
+/// our tunnels. Each peer is selected at most once, even if it appears in
+/// `candidates` more than once.
+///
+/// The result depends only on the set of candidates and on `rng`, not on the
+/// order in which the candidates are given.
+pub fn select_peers<'a, I, F, R>(
+    candidates: I,
+    criteria: &PeerCriteria,
+    us: &Hash,
+    profiles: &Profiles,
+    is_connected: F,
+    now: SystemTime,
+    rng: &mut R,
+) -> Vec<RouterInfo>
+where
+    I: IntoIterator<Item = &'a RouterInfo>,
+    F: Fn(&Hash) -> bool,
+    R: Rng + ?Sized,
+{
+    let mut eligible: Vec<(Hash, &RouterInfo)> = candidates
+        .into_iter()
+        .map(|ri| (ri.router_id.hash(), ri))
+        .filter(|(hash, ri)| {
+            hash != us
+                && criteria.accepts(ri, hash)
+                && !profiles.is_failing(hash, now)
+                && !profiles.is_banned(hash, now)
+                && !profiles.is_rejecting(hash, now)
+        })
+        .collect();
+
+    eligible.sort_by(|a, b| a.0.cmp(&b.0));
+    eligible.dedup_by(|a, b| a.0 == b.0);
+    if criteria.prefer_high_capacity {
+        // Stable, so peers with equal scores stay in a fixed order before shuffling
+        let mut scored: Vec<_> = eligible
+            .into_iter()
+            .map(|(hash, ri)| (profiles.capacity_score(&hash, now), hash, ri))
+            .collect();
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        let mut rest: Vec<_> = scored.into_iter().map(|(_, hash, ri)| (hash, ri)).collect();
+        eligible = rest.drain(..(rest.len() + 1) / 2).collect();
+        eligible.shuffle(rng);
+        rest.shuffle(rng);
+        eligible.extend(rest);
+    } else {
+        eligible.shuffle(rng);
+    }
+    if criteria.prefer_connected {
+        // Stable, so the peers in each group stay shuffled
+        eligible.sort_by_key(|(hash, _)| !is_connected(hash));
+    }
+
+    let mut used = HashSet::new();
+    let mut selected = Vec::with_capacity(criteria.count.min(eligible.len()));
+    for (_, ri) in eligible {
+        if selected.len() >= criteria.count {
+            break;
+        }
+        if criteria.distinct_subnets {
+            let subnets: Vec<Subnet> = ri.hosts().map(Subnet::from).collect();
+            if subnets.iter().any(|subnet| used.contains(subnet)) {
+                continue;
+            }
+            used.extend(subnets);
+        }
+        selected.push(ri.clone());
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::HashSet;
+    use std::net::IpAddr;
+    use std::time::{Duration, SystemTime};
+
+    use super::{select_peers, FloodfillPolicy, PeerCriteria};
+    use crate::data::{Bandwidth, Hash, RouterCaps, RouterInfo, RouterSecretKeys};
+    use crate::router::{
+        mock::mock_reachable_router_info,
+        profiles::{Profiles, Transport},
+    };
+
+    fn router(bandwidth: Bandwidth, floodfill: bool, ip: &str) -> RouterInfo {
+        let caps = RouterCaps::default()
+            .bandwidth(bandwidth)
+            .floodfill(floodfill);
+        mock_reachable_router_info(caps, ip)
+    }
+
+    /// Routers in 12 distinct subnets, plus 4 that share subnets with them.
+    fn netdb() -> Vec<RouterInfo> {
+        let mut ris = vec![];
+        for i in 0..8 {
+            let bandwidth = if i % 2 == 0 {
+                Bandwidth::O
+            } else {
+                Bandwidth::L
+            };
+            ris.push(router(bandwidth, i < 2, &format!("10.{}.0.1", i)));
+        }
+        for i in 0..4 {
+            ris.push(router(Bandwidth::X, false, &format!("2001:db8:{}::1", i)));
+        }
+        // Same /16 or /48 as the first routers above
+        ris.push(router(Bandwidth::O, false, "10.0.200.1"));
+        ris.push(router(Bandwidth::O, false, "10.1.200.1"));
+        ris.push(router(Bandwidth::X, false, "2001:db8:0:ffff::1"));
+        ris.push(router(Bandwidth::X, false, "2001:db8:1:ffff::1"));
+        ris
+    }
+
+    fn hashes(ris: &[RouterInfo]) -> HashSet<Hash> {
+        ris.iter().map(|ri| ri.router_id.hash()).collect()
+    }
+
+    fn select(
+        ris: &[RouterInfo],
+        criteria: &PeerCriteria,
+        us: &Hash,
+        profiles: &Profiles,
+        connected: &[Hash],
+        seed: u64,
+    ) -> Vec<RouterInfo> {
+        select_peers(
+            ris,
+            criteria,
+            us,
+            profiles,
+            |hash| connected.contains(hash),
+            SystemTime::now(),
+            &mut StdRng::seed_from_u64(seed),
+        )
+    }
+
+    #[test]
+    fn deterministic() {
+        let ris = netdb();
+        let us = Hash([0; 32]);
+        let profiles = Profiles::default();
+        let criteria = PeerCriteria::new(5);
+
+        let first = select(&ris, &criteria, &us, &profiles, &[], 1);
+        assert_eq!(first.len(), 5);
+
+        // The same seed gives the same peers, whatever order the netDb is in
+        let mut reversed = ris.clone();
+        reversed.reverse();
+        let again = select(&reversed, &criteria, &us, &profiles, &[], 1);
+        let order = |ris: &[RouterInfo]| -> Vec<Hash> {
+            ris.iter().map(|ri| ri.router_id.hash()).collect()
+        };
+        assert_eq!(order(&first), order(&again));
+
+        // Asking for more than there are returns one per subnet
+        assert_eq!(
+            select(&ris, &PeerCriteria::new(100), &us, &profiles, &[], 1).len(),
+            12
+        );
+        assert_eq!(
+            select(
+                &ris,
+                &PeerCriteria::new(100).distinct_subnets(false),
+                &us,
+                &profiles,
+                &[],
+                1
+            )
+            .len(),
+            ris.len()
+        );
+    }
+
+    #[test]
+    fn criteria() {
+        let ris = netdb();
+        let profiles = Profiles::default();
+        let all = PeerCriteria::new(100).distinct_subnets(false);
+
+        for seed in 0..10 {
+            // Bandwidth
+            let selected = select(
+                &ris,
+                &all.clone().min_bandwidth(Bandwidth::O),
+                &Hash([0; 32]),
+                &profiles,
+                &[],
+                seed,
+            );
+            assert_eq!(selected.len(), 12);
+            assert!(selected
+                .iter()
+                .all(|ri| { ri.caps().unwrap().bandwidth_class().unwrap() >= Bandwidth::O }));
+
+            // Floodfills
+            let selected = select(
+                &ris,
+                &all.clone().floodfills(FloodfillPolicy::Require),
+                &Hash([0; 32]),
+                &profiles,
+                &[],
+                seed,
+            );
+            assert_eq!(selected.len(), 2);
+            assert!(selected.iter().all(|ri| ri.is_floodfill()));
+            let selected = select(
+                &ris,
+                &all.clone().floodfills(FloodfillPolicy::Exclude),
+                &Hash([0; 32]),
+                &profiles,
+                &[],
+                seed,
+            );
+            assert_eq!(selected.len(), ris.len() - 2);
+            assert!(selected.iter().all(|ri| !ri.is_floodfill()));
+
+            // Excluded peers and ourselves
+            let us = ris[0].router_id.hash();
+            let excluded = vec![ris[1].router_id.hash(), ris[2].router_id.hash()];
+            let selected = hashes(&select(
+                &ris,
+                &all.clone().exclude(excluded.clone()),
+                &us,
+                &profiles,
+                &[],
+                seed,
+            ));
+            assert_eq!(selected.len(), ris.len() - 3);
+            assert!(!selected.contains(&us));
+            assert!(excluded.iter().all(|hash| !selected.contains(hash)));
+        }
+    }
+
+    #[test]
+    fn failing_and_banned() {
+        let ris = netdb();
+        let us = Hash([0; 32]);
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+        let failing = ris[3].router_id.hash();
+        let banned = ris[4].router_id.hash();
+        for _ in 0..3 {
+            profiles.connect_failed(&failing, Transport::Ntcp2, now);
+        }
+        profiles.ban(&banned, Duration::from_secs(60), now);
+
+        let all = PeerCriteria::new(100).distinct_subnets(false);
+        for seed in 0..10 {
+            let selected = hashes(&select(&ris, &all, &us, &profiles, &[], seed));
+            assert_eq!(selected.len(), ris.len() - 2);
+            assert!(!selected.contains(&failing));
+            assert!(!selected.contains(&banned));
+        }
+    }
+
+    #[test]
+    fn prefer_connected() {
+        let ris = netdb();
+        let us = Hash([0; 32]);
+        let profiles = Profiles::default();
+        let connected: Vec<Hash> = [5, 9, 11]
+            .iter()
+            .map(|i| ris[*i].router_id.hash())
+            .collect();
+
+        for seed in 0..10 {
+            let selected = select(
+                &ris,
+                &PeerCriteria::new(3).prefer_connected(true),
+                &us,
+                &profiles,
+                &connected,
+                seed,
+            );
+            assert_eq!(
+                hashes(&selected),
+                connected.iter().cloned().collect::<HashSet<_>>()
+            );
+        }
+
+        // Without the preference, connected peers are not always chosen
+        assert!((0..10).any(|seed| {
+            let selected = select(
+                &ris,
+                &PeerCriteria::new(3),
+                &us,
+                &profiles,
+                &connected,
+                seed,
+            );
+            hashes(&selected) != connected.iter().cloned().collect::<HashSet<_>>()
+        }));
+    }
+
+    #[test]
+    fn prefer_high_capacity() {
+        let ris = netdb();
+        let us = Hash([0; 32]);
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+
+        // Peers that have agreed to our tunnels score higher than unknown peers
+        let fast: Vec<Hash> = (0..8).map(|i| ris[i].router_id.hash()).collect();
+        for hash in &fast {
+            for _ in 0..10 {
+                profiles.tunnel_build_agreed(hash, now);
+            }
+        }
+        let fast: HashSet<_> = fast.into_iter().collect();
+
+        let criteria = PeerCriteria::new(5).distinct_subnets(false);
+        for seed in 0..10 {
+            let selected = hashes(&select(
+                &ris,
+                &criteria.clone().prefer_high_capacity(true),
+                &us,
+                &profiles,
+                &[],
+                seed,
+            ));
+            assert_eq!(selected.len(), 5);
+            assert!(selected.is_subset(&fast));
+        }
+
+        // Without the preference, other peers are sometimes chosen
+        assert!((0..10).any(|seed| {
+            !hashes(&select(&ris, &criteria, &us, &profiles, &[], seed)).is_subset(&fast)
+        }));
+
+        // The preference doesn't stop slower peers being used when needed
+        let selected = select(
+            &ris,
+            &PeerCriteria::new(100)
+                .distinct_subnets(false)
+                .prefer_high_capacity(true),
+            &us,
+            &profiles,
+            &[],
+            1,
+        );
+        assert_eq!(selected.len(), ris.len());
+    }
+
+    #[test]
+    fn distinct_subnets() {
+        let ris = netdb();
+        let us = Hash([0; 32]);
+        let profiles = Profiles::default();
+
+        for seed in 0..20 {
+            let selected = select(&ris, &PeerCriteria::new(100), &us, &profiles, &[], seed);
+            assert_eq!(selected.len(), 12);
+
+            let mut v4 = HashSet::new();
+            let mut v6 = HashSet::new();
+            for ri in &selected {
+                for host in ri.hosts() {
+                    match host {
+                        IpAddr::V4(ip) => {
+                            let o = ip.octets();
+                            assert!(v4.insert((o[0], o[1])));
+                        }
+                        IpAddr::V6(ip) => {
+                            let s = ip.segments();
+                            assert!(v6.insert((s[0], s[1], s[2])));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Routers with no published address are never excluded by subnet
+        let rsk = RouterSecretKeys::new();
+        let hidden = RouterInfo::new(rsk.rid);
+        let mut ris = ris;
+        ris.push(hidden.clone());
+        for seed in 0..10 {
+            let selected = hashes(&select(
+                &ris,
+                &PeerCriteria::new(100),
+                &us,
+                &profiles,
+                &[],
+                seed,
+            ));
+            assert_eq!(selected.len(), 13);
+            assert!(selected.contains(&hidden.router_id.hash()));
+        }
+    }
+}
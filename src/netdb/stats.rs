@@ -0,0 +1,95 @@
+//! Summary statistics about the contents of the netDb.
+//!
+//! These are cheap to compute from the engine's own state, so they can be
+//! requested at any time without holding up stores and lookups.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The period over which recent additions and evictions are counted, in
+/// seconds.
+const RECENT_WINDOW: u64 = 60 * 60;
+/// The granularity with which recent events are counted, in seconds.
+const RECENT_BUCKET: u64 = 60;
+
+/// A summary of the contents of the netDb.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetDbStats {
+    /// The number of RouterInfos we know.
+    pub routers: usize,
+    /// How many of those routers are floodfills.
+    pub floodfills: usize,
+    /// The number of LeaseSets we know, of any type.
+    pub lease_sets: usize,
+    /// The number of new entries stored in the last hour.
+    pub added_last_hour: u64,
+    /// The number of entries expired in the last hour.
+    pub evicted_last_hour: u64,
+    /// The size of the RouterInfos stored on disk, as of the last flush.
+    pub disk_bytes: u64,
+}
+
+/// Counts events over a sliding window, in buckets of [`RECENT_BUCKET`].
+#[derive(Default)]
+pub(super) struct RecentCount {
+    buckets: VecDeque<(Instant, u64)>,
+}
+
+impl RecentCount {
+    pub(super) fn add(&mut self, n: u64, now: Instant) {
+        self.expire(now);
+        match self.buckets.back_mut() {
+            Some((start, count))
+                if now.duration_since(*start) < Duration::from_secs(RECENT_BUCKET) =>
+            {
+                *count += n
+            }
+            _ => self.buckets.push_back((now, n)),
+        }
+    }
+
+    /// Returns the number of events in the window ending at `now`.
+    pub(super) fn total(&self, now: Instant) -> u64 {
+        self.buckets
+            .iter()
+            .filter(|(start, _)| now.duration_since(*start) < Duration::from_secs(RECENT_WINDOW))
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.buckets.front() {
+            if now.duration_since(*start) < Duration::from_secs(RECENT_WINDOW) {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RecentCount, RECENT_BUCKET, RECENT_WINDOW};
+
+    #[test]
+    fn recent_count() {
+        let mut recent = RecentCount::default();
+        let now = Instant::now();
+        assert_eq!(recent.total(now), 0);
+
+        recent.add(2, now);
+        recent.add(3, now + Duration::from_secs(1));
+        recent.add(1, now + Duration::from_secs(RECENT_BUCKET));
+        assert_eq!(recent.buckets.len(), 2);
+        assert_eq!(recent.total(now + Duration::from_secs(RECENT_BUCKET)), 6);
+
+        // Events drop out of the window a bucket at a time
+        let later = now + Duration::from_secs(RECENT_WINDOW);
+        assert_eq!(recent.total(later), 1);
+        recent.add(4, later);
+        assert_eq!(recent.buckets.len(), 2);
+        assert_eq!(recent.total(later + Duration::from_secs(RECENT_BUCKET)), 4);
+    }
+}
@@ -0,0 +1,310 @@
+//! Concurrent storage for the RouterInfos in the netDb.
+//!
+//! The netDb engine is the only writer, but many tasks need to read RouterInfos:
+//! transports looking up peers, tunnel builds, and the engine itself. Entries
+//! are split across shards by the first bits of their hash, each behind its own
+//! lock, so readers only contend with writes to the same shard, and never wait
+//! behind the engine's message queue.
+//!
+//! No caller-supplied code ever runs while a shard lock is held. Reads return
+//! shared references to the entries, and iteration works on a snapshot, so a
+//! caller can read from or write to the store while processing the results
+//! without deadlocking.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::data::{Hash, RouterInfo};
+
+/// The number of shards the store is split into.
+const SHARDS: usize = 16;
+
+/// Returns the shard that `key` is stored in.
+fn shard_index(key: &Hash) -> usize {
+    key.0[0] as usize * SHARDS / 256
+}
+
+/// A map from router hashes to RouterInfos, safe to share between threads.
+pub struct RouterInfoStore {
+    shards: Vec<RwLock<HashMap<Hash, Arc<RouterInfo>>>>,
+}
+
+impl Default for RouterInfoStore {
+    fn default() -> Self {
+        RouterInfoStore {
+            shards: (0..SHARDS).map(|_| RwLock::new(HashMap::new())).collect(),
+        }
+    }
+}
+
+impl RouterInfoStore {
+    fn shard(&self, key: &Hash) -> &RwLock<HashMap<Hash, Arc<RouterInfo>>> {
+        &self.shards[shard_index(key)]
+    }
+
+    /// Returns the RouterInfo stored at `key`.
+    pub fn get(&self, key: &Hash) -> Option<Arc<RouterInfo>> {
+        self.shard(key).read().unwrap().get(key).cloned()
+    }
+
+    pub fn contains_key(&self, key: &Hash) -> bool {
+        self.shard(key).read().unwrap().contains_key(key)
+    }
+
+    /// Returns the number of RouterInfos stored.
+    ///
+    /// Shards are counted one at a time, so writes made while counting may or
+    /// may not be included.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.read().unwrap().is_empty())
+    }
+
+    /// Stores `ri` at `key`, returning the RouterInfo it replaced.
+    pub(super) fn insert(&self, key: Hash, ri: RouterInfo) -> Option<Arc<RouterInfo>> {
+        self.shard(&key).write().unwrap().insert(key, Arc::new(ri))
+    }
+
+    /// Removes the RouterInfo stored at `key`, returning it.
+    pub(super) fn remove(&self, key: &Hash) -> Option<Arc<RouterInfo>> {
+        self.shard(key).write().unwrap().remove(key)
+    }
+
+    /// Returns the keys of every stored RouterInfo.
+    pub fn keys(&self) -> Vec<Hash> {
+        let mut keys = Vec::with_capacity(self.len());
+        for shard in &self.shards {
+            keys.extend(shard.read().unwrap().keys().cloned());
+        }
+        keys
+    }
+
+    /// Returns every stored RouterInfo.
+    ///
+    /// Shards are copied one at a time, so the snapshot includes every entry
+    /// that was present for the whole call, and may include entries written
+    /// during it.
+    pub fn snapshot(&self) -> Vec<Arc<RouterInfo>> {
+        let mut ris = Vec::with_capacity(self.len());
+        for shard in &self.shards {
+            ris.extend(shard.read().unwrap().values().cloned());
+        }
+        ris
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{shard_index, RouterInfoStore, SHARDS};
+    use crate::data::{Hash, I2PDate, RouterInfo, RouterSecretKeys};
+
+    const WRITERS: u64 = 4;
+    const PER_WRITER: u64 = 500;
+
+    /// Returns the key for entry `n`, and a RouterInfo identifying it by its
+    /// publication time. The store doesn't check that keys match.
+    fn entry(template: &RouterInfo, n: u64) -> (Hash, RouterInfo) {
+        let mut ri = template.clone();
+        ri.published = I2PDate::from_system_time(UNIX_EPOCH + Duration::from_secs(n));
+        (Hash::digest(&n.to_be_bytes()), ri)
+    }
+
+    fn number(ri: &RouterInfo) -> u64 {
+        ri.published
+            .to_system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn template() -> RouterInfo {
+        RouterInfo::new(RouterSecretKeys::new().rid)
+    }
+
+    #[test]
+    fn sharding() {
+        assert_eq!(shard_index(&Hash([0; 32])), 0);
+        assert_eq!(shard_index(&Hash([0x0f; 32])), 0);
+        assert_eq!(shard_index(&Hash([0x10; 32])), 1);
+        assert_eq!(shard_index(&Hash([0xff; 32])), SHARDS - 1);
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let store = RouterInfoStore::default();
+        let template = template();
+        assert!(store.is_empty());
+
+        let (key, ri) = entry(&template, 1);
+        assert!(store.insert(key.clone(), ri.clone()).is_none());
+        assert!(store.contains_key(&key));
+        assert_eq!(store.get(&key).as_deref(), Some(&ri));
+        assert_eq!(store.len(), 1);
+
+        let (_, newer) = entry(&template, 2);
+        assert_eq!(store.insert(key.clone(), newer).as_deref(), Some(&ri));
+        assert_eq!(store.len(), 1);
+
+        assert!(store.remove(&key).is_some());
+        assert!(store.remove(&key).is_none());
+        assert!(store.get(&key).is_none());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn callbacks_can_use_the_store() {
+        let store = RouterInfoStore::default();
+        let template = template();
+        for n in 0..100 {
+            let (key, ri) = entry(&template, n);
+            store.insert(key, ri);
+        }
+
+        // Nothing is locked while we work through a snapshot
+        for ri in store.snapshot() {
+            let n = number(&ri);
+            let (key, _) = entry(&template, n);
+            assert_eq!(store.get(&key).map(|ri| number(&ri)), Some(n));
+            let (key, ri) = entry(&template, n + 1000);
+            store.insert(key, ri);
+        }
+        assert_eq!(store.len(), 200);
+    }
+
+    #[test]
+    fn concurrent_access() {
+        let store = Arc::new(RouterInfoStore::default());
+        let template = template();
+        let (done_tx, done_rx) = mpsc::channel();
+
+        // Writers store their own entries, rewriting each one once
+        for w in 0..WRITERS {
+            let (store, template, done) = (store.clone(), template.clone(), done_tx.clone());
+            thread::spawn(move || {
+                for i in 0..PER_WRITER {
+                    let (key, ri) = entry(&template, w * PER_WRITER + i);
+                    store.insert(key.clone(), ri.clone());
+                    assert!(store.insert(key, ri).is_some());
+                }
+                done.send("writer").unwrap();
+            });
+        }
+
+        // Readers look up entries, which are either missing or correct
+        for _ in 0..4 {
+            let (store, template, done) = (store.clone(), template.clone(), done_tx.clone());
+            thread::spawn(move || {
+                for _ in 0..5 {
+                    for n in 0..WRITERS * PER_WRITER {
+                        let (key, _) = entry(&template, n);
+                        if let Some(ri) = store.get(&key) {
+                            assert_eq!(number(&ri), n);
+                        }
+                    }
+                }
+                done.send("reader").unwrap();
+            });
+        }
+
+        // Iterators see the store grow, and every entry in a snapshot is
+        // stored at its own key
+        for _ in 0..2 {
+            let (store, template, done) = (store.clone(), template.clone(), done_tx.clone());
+            thread::spawn(move || {
+                let mut last = 0;
+                for _ in 0..20 {
+                    let snapshot = store.snapshot();
+                    assert!(snapshot.len() >= last);
+                    last = snapshot.len();
+                    for ri in snapshot {
+                        let (key, _) = entry(&template, number(&ri));
+                        assert!(store.contains_key(&key));
+                    }
+                }
+                done.send("iterator").unwrap();
+            });
+        }
+        drop(done_tx);
+
+        // Every thread finishes; a deadlock would stall here
+        for _ in 0..WRITERS + 4 + 2 {
+            let finished = done_rx.recv_timeout(Duration::from_secs(60));
+            assert!(finished.is_ok(), "No progress: {:?}", finished);
+        }
+
+        assert_eq!(store.len() as u64, WRITERS * PER_WRITER);
+        assert_eq!(store.keys().len() as u64, WRITERS * PER_WRITER);
+        let mut numbers: Vec<_> = store.snapshot().iter().map(|ri| number(ri)).collect();
+        numbers.sort_unstable();
+        assert_eq!(numbers, (0..WRITERS * PER_WRITER).collect::<Vec<_>>());
+    }
+
+    #[cfg(all(test, feature = "nightly"))]
+    mod bench {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+        use test::{black_box, Bencher};
+
+        use super::{entry, template};
+        use crate::netdb::store::RouterInfoStore;
+
+        const ENTRIES: u64 = 1000;
+
+        fn lookups(b: &mut Bencher, writers: usize) {
+            let store = Arc::new(RouterInfoStore::default());
+            let template = template();
+            let keys: Vec<_> = (0..ENTRIES)
+                .map(|n| {
+                    let (key, ri) = entry(&template, n);
+                    store.insert(key.clone(), ri);
+                    key
+                })
+                .collect();
+
+            // Keep rewriting entries while we look them up
+            let stop = Arc::new(AtomicBool::new(false));
+            let handles: Vec<_> = (0..writers)
+                .map(|w| {
+                    let (store, template, stop) = (store.clone(), template.clone(), stop.clone());
+                    thread::spawn(move || {
+                        let mut n = w as u64;
+                        while !stop.load(Ordering::Relaxed) {
+                            let (key, ri) = entry(&template, n % ENTRIES);
+                            store.insert(key, ri);
+                            n += writers as u64;
+                        }
+                    })
+                })
+                .collect();
+
+            b.iter(|| {
+                for key in &keys {
+                    black_box(store.get(key));
+                }
+            });
+
+            stop.store(true, Ordering::Relaxed);
+            for handle in handles {
+                handle.join().unwrap();
+            }
+        }
+
+        #[bench]
+        fn lookup_uncontended(b: &mut Bencher) {
+            lookups(b, 0);
+        }
+
+        #[bench]
+        fn lookup_under_store_load(b: &mut Bencher) {
+            lookups(b, 4);
+        }
+    }
+}
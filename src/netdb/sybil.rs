@@ -0,0 +1,372 @@
+//! Choosing floodfills close to a key without letting one operator eclipse it.
+//!
+//! Router hashes are cheap to generate, so an attacker can create many
+//! floodfill identities that are all close to a key, and take over its lookups
+//! and stores if floodfills are chosen purely by distance. Such identities tend
+//! to share a few subnets, and to be new to the network, so we look a little
+//! further out than the floodfills we need and:
+//!
+//! - choose at most a few floodfills from each /16 (IPv4) or /48 (IPv6) subnet,
+//! - prefer floodfills whose identities we have known for a while, and
+//! - require the chosen set to cover several distinct subnets.
+//!
+//! When the floodfills we know can't satisfy these, the constraints are relaxed
+//! one at a time, so that a selection always returns as many floodfills as it
+//! can.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use super::select::Subnet;
+use crate::data::{Hash, RouterInfo};
+use crate::router::config::{self, Config};
+
+/// The most floodfills we choose from a single subnet, by default.
+const MAX_PER_SUBNET: usize = 2;
+/// The number of distinct subnets the chosen floodfills should cover, by
+/// default.
+const MIN_SUBNETS: usize = 3;
+/// Floodfills we first saw less than this long ago are only chosen when there
+/// aren't enough others, by default, in seconds.
+const MIN_AGE: u64 = 30 * 60;
+/// How many floodfills beyond those needed we consider, by default.
+const EXTRA_CANDIDATES: usize = 32;
+
+/// The constraints that a selection had to give up to choose enough floodfills.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(super) struct Relaxed {
+    /// Floodfills we only recently saw were chosen.
+    pub(super) new_floodfills: bool,
+    /// The chosen floodfills cover fewer subnets than required.
+    pub(super) diversity: bool,
+    /// More floodfills than allowed were chosen from a subnet.
+    pub(super) subnet_cap: bool,
+}
+
+impl Relaxed {
+    pub(super) fn any(&self) -> bool {
+        self.new_floodfills || self.diversity || self.subnet_cap
+    }
+}
+
+/// A floodfill that could be chosen.
+pub(super) struct Candidate {
+    pub(super) hash: Hash,
+    subnets: Vec<Subnet>,
+    established: bool,
+}
+
+/// How floodfills close to a key are chosen.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct SelectionPolicy {
+    /// 0 disables the cap.
+    max_per_subnet: usize,
+    min_subnets: usize,
+    /// 0 treats every floodfill as established.
+    min_age: Duration,
+    extra_candidates: usize,
+}
+
+impl Default for SelectionPolicy {
+    fn default() -> Self {
+        SelectionPolicy {
+            max_per_subnet: MAX_PER_SUBNET,
+            min_subnets: MIN_SUBNETS,
+            min_age: Duration::from_secs(MIN_AGE),
+            extra_candidates: EXTRA_CANDIDATES,
+        }
+    }
+}
+
+impl SelectionPolicy {
+    pub(super) fn from_config(config: &Config) -> Self {
+        let get = |key, default| {
+            config
+                .get_int(key)
+                .ok()
+                .map(|v| v as usize)
+                .unwrap_or(default)
+        };
+        SelectionPolicy {
+            max_per_subnet: get(config::NETDB_SELECTION_MAX_PER_SUBNET, MAX_PER_SUBNET),
+            min_subnets: get(config::NETDB_SELECTION_MIN_SUBNETS, MIN_SUBNETS),
+            min_age: Duration::from_secs(
+                get(config::NETDB_SELECTION_MIN_AGE, MIN_AGE as usize) as u64
+            ),
+            extra_candidates: get(config::NETDB_SELECTION_CANDIDATES, EXTRA_CANDIDATES),
+        }
+    }
+
+    /// Returns how many of the floodfills closest to a key to consider, when
+    /// choosing `count` of them.
+    pub(super) fn candidates(&self, count: usize) -> usize {
+        count.saturating_add(self.extra_candidates)
+    }
+
+    /// Returns the candidate for the floodfill `ri` at `hash`, whose identity we
+    /// first saw at `first_seen`, if we have only seen it since we started.
+    pub(super) fn candidate(
+        &self,
+        hash: Hash,
+        ri: &RouterInfo,
+        first_seen: Option<SystemTime>,
+        now: SystemTime,
+    ) -> Candidate {
+        let mut subnets: Vec<Subnet> = vec![];
+        for subnet in ri.hosts().map(Subnet::from) {
+            if !subnets.contains(&subnet) {
+                subnets.push(subnet);
+            }
+        }
+        let established = match first_seen {
+            Some(first_seen) => now
+                .duration_since(first_seen)
+                .map(|age| age >= self.min_age)
+                .unwrap_or(false),
+            None => true,
+        };
+        Candidate {
+            hash,
+            subnets,
+            established,
+        }
+    }
+
+    /// Chooses up to `count` of `candidates`, which are in order of distance
+    /// from the key, returning them in the same order.
+    pub(super) fn select(&self, candidates: &[Candidate], count: usize) -> (Vec<Hash>, Relaxed) {
+        let wanted = count.min(candidates.len());
+        let mut relaxed = Relaxed::default();
+        loop {
+            let chosen = self.choose(candidates, count, &relaxed);
+            if chosen.len() >= wanted {
+                return (chosen, relaxed);
+            }
+
+            // Give up the least important constraint we still hold
+            if !relaxed.new_floodfills {
+                relaxed.new_floodfills = true;
+            } else if !relaxed.diversity {
+                relaxed.diversity = true;
+            } else if !relaxed.subnet_cap {
+                relaxed.subnet_cap = true;
+            } else {
+                return (chosen, relaxed);
+            }
+        }
+    }
+
+    fn choose(&self, candidates: &[Candidate], count: usize, relaxed: &Relaxed) -> Vec<Hash> {
+        let eligible: Vec<(usize, &Candidate)> = if relaxed.new_floodfills {
+            // New floodfills are only considered after every established one
+            let (established, new): (Vec<_>, Vec<_>) = candidates
+                .iter()
+                .enumerate()
+                .partition(|(_, c)| c.established);
+            established.into_iter().chain(new).collect()
+        } else {
+            candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| c.established)
+                .collect()
+        };
+
+        // Require as many distinct subnets as the eligible floodfills can cover
+        let required = if relaxed.diversity {
+            0
+        } else {
+            let available: HashSet<&Subnet> = eligible
+                .iter()
+                .flat_map(|(_, c)| c.subnets.iter())
+                .collect();
+            self.min_subnets.min(count).min(available.len())
+        };
+
+        let mut per_subnet: HashMap<&Subnet, usize> = HashMap::new();
+        let mut chosen: Vec<usize> = Vec::with_capacity(count);
+        for (i, c) in eligible {
+            if chosen.len() >= count {
+                break;
+            }
+            if !relaxed.subnet_cap
+                && self.max_per_subnet > 0
+                && c.subnets
+                    .iter()
+                    .any(|s| per_subnet.get(s).copied().unwrap_or(0) >= self.max_per_subnet)
+            {
+                continue;
+            }
+
+            // Leave enough room to reach the required number of subnets
+            let adds_subnet = c.subnets.iter().any(|s| !per_subnet.contains_key(s));
+            let still_needed = required.saturating_sub(per_subnet.len());
+            if !adds_subnet && count - chosen.len() <= still_needed {
+                continue;
+            }
+
+            for s in &c.subnets {
+                *per_subnet.entry(s).or_insert(0) += 1;
+            }
+            chosen.push(i);
+        }
+
+        chosen.sort_unstable();
+        chosen
+            .into_iter()
+            .map(|i| candidates[i].hash.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, SystemTime};
+
+    use super::{Candidate, Relaxed, SelectionPolicy, MIN_AGE};
+    use crate::data::{Hash, RouterInfo, RouterSecretKeys};
+    use crate::netdb::select::Subnet;
+    use crate::router::config::{self, Config};
+
+    /// Returns candidate `n`, with an address in `ip`.
+    fn candidate(n: u8, ip: &str, established: bool) -> Candidate {
+        Candidate {
+            hash: Hash([n; 32]),
+            subnets: vec![Subnet::from(ip.parse::<IpAddr>().unwrap())],
+            established,
+        }
+    }
+
+    fn numbers(chosen: &[Hash]) -> Vec<u8> {
+        chosen.iter().map(|h| h.0[0]).collect()
+    }
+
+    #[test]
+    fn subnet_cap_and_diversity() {
+        let policy = SelectionPolicy::default();
+
+        // The closest five floodfills share a subnet
+        let mut candidates: Vec<_> = (0..5)
+            .map(|n| candidate(n, &format!("10.5.0.{}", n), true))
+            .collect();
+        candidates.push(candidate(5, "10.6.0.1", true));
+        candidates.push(candidate(6, "10.7.0.1", true));
+        candidates.push(candidate(7, "10.8.0.1", true));
+
+        // Two come from the crowded subnet, and the rest cover others
+        let (chosen, relaxed) = policy.select(&candidates, 4);
+        assert_eq!(numbers(&chosen), vec![0, 1, 5, 6]);
+        assert_eq!(relaxed, Relaxed::default());
+
+        // The third subnet is required even when it is the only one left
+        let (chosen, _) = policy.select(&candidates[..6], 3);
+        assert_eq!(numbers(&chosen), vec![0, 1, 5]);
+        let (chosen, _) = policy.select(&candidates, 3);
+        assert_eq!(numbers(&chosen), vec![0, 5, 6]);
+    }
+
+    #[test]
+    fn new_floodfills_penalized() {
+        let policy = SelectionPolicy::default();
+        let candidates = vec![
+            candidate(0, "10.0.0.1", false),
+            candidate(1, "10.1.0.1", true),
+            candidate(2, "10.2.0.1", false),
+            candidate(3, "10.3.0.1", true),
+            candidate(4, "10.4.0.1", true),
+        ];
+
+        let (chosen, relaxed) = policy.select(&candidates, 3);
+        assert_eq!(numbers(&chosen), vec![1, 3, 4]);
+        assert!(!relaxed.any());
+
+        // New floodfills fill in when there aren't enough established ones
+        let (chosen, relaxed) = policy.select(&candidates, 4);
+        assert_eq!(numbers(&chosen), vec![0, 1, 3, 4]);
+        assert!(relaxed.new_floodfills);
+        assert!(!relaxed.diversity && !relaxed.subnet_cap);
+    }
+
+    #[test]
+    fn relaxes_when_necessary() {
+        let policy = SelectionPolicy::default();
+
+        // Every floodfill we know is in one subnet
+        let candidates: Vec<_> = (0..5)
+            .map(|n| candidate(n, &format!("10.5.0.{}", n), true))
+            .collect();
+        let (chosen, relaxed) = policy.select(&candidates, 3);
+        assert_eq!(numbers(&chosen), vec![0, 1, 2]);
+        assert_eq!(
+            relaxed,
+            Relaxed {
+                new_floodfills: true,
+                diversity: true,
+                subnet_cap: true,
+            }
+        );
+
+        // Floodfills without addresses are never limited
+        let candidates: Vec<_> = (0..5)
+            .map(|n| Candidate {
+                hash: Hash([n; 32]),
+                subnets: vec![],
+                established: true,
+            })
+            .collect();
+        let (chosen, relaxed) = policy.select(&candidates, 3);
+        assert_eq!(numbers(&chosen), vec![0, 1, 2]);
+        assert!(!relaxed.any());
+    }
+
+    #[test]
+    fn first_seen() {
+        let policy = SelectionPolicy::default();
+        let now = SystemTime::now();
+        let ri = RouterInfo::new(RouterSecretKeys::new().rid);
+        let hash = ri.router_id.hash();
+        let seen_ago = |secs| {
+            let first_seen = Some(now - Duration::from_secs(secs));
+            policy.candidate(hash.clone(), &ri, first_seen, now)
+        };
+
+        assert!(!seen_ago(60).established);
+        assert!(seen_ago(MIN_AGE).established);
+        // Floodfills we knew before we started are established
+        assert!(policy.candidate(hash.clone(), &ri, None, now).established);
+    }
+
+    #[test]
+    fn from_config() {
+        let mut cfg = Config::default();
+        cfg.set(config::NETDB_SELECTION_MAX_PER_SUBNET, 0).unwrap();
+        cfg.set(config::NETDB_SELECTION_MIN_SUBNETS, 1).unwrap();
+        cfg.set(config::NETDB_SELECTION_MIN_AGE, 0).unwrap();
+        cfg.set(config::NETDB_SELECTION_CANDIDATES, 8).unwrap();
+        let policy = SelectionPolicy::from_config(&cfg);
+        assert_eq!(
+            policy,
+            SelectionPolicy {
+                max_per_subnet: 0,
+                min_subnets: 1,
+                min_age: Duration::from_secs(0),
+                extra_candidates: 8,
+            }
+        );
+        assert_eq!(policy.candidates(3), 11);
+
+        // Without a cap, the closest floodfills are chosen
+        let candidates: Vec<_> = (0..5)
+            .map(|n| candidate(n, &format!("10.5.0.{}", n), true))
+            .collect();
+        let (chosen, relaxed) = policy.select(&candidates, 3);
+        assert_eq!(numbers(&chosen), vec![0, 1, 2]);
+        assert!(!relaxed.any());
+
+        assert_eq!(
+            SelectionPolicy::from_config(&Config::default()),
+            SelectionPolicy::default()
+        );
+    }
+}
@@ -0,0 +1,532 @@
+//! Verifying that the entries we store to floodfills can be found.
+//!
+//! A floodfill acknowledging a store only tells us that it received it, not
+//! that it kept it, or passed it on. Some time after our RouterInfo is
+//! acknowledged, or after we flood an entry as a floodfill, we look the key up
+//! again through a floodfill that we didn't store it to. If that floodfill
+//! doesn't have the entry, or only an older version of it, the store didn't
+//! take hold, and we store it again to a different set of floodfills.
+
+use rand::{thread_rng, Rng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{leaseset::StoredLeaseSet, LocalNetworkDatabase};
+use crate::data::{Hash, I2PDate};
+use crate::i2np::{
+    DatabaseLookup, DatabaseLookupType, DatabaseSearchReply, DatabaseStore, DatabaseStoreData,
+    Message,
+};
+
+/// Verify a store at least this long after it, in seconds.
+const VERIFY_MIN_DELAY: u64 = 30;
+/// Verify a store at most this long after it, in seconds.
+const VERIFY_MAX_DELAY: u64 = 60;
+/// How long we wait for the verifying floodfill to reply, in seconds.
+const VERIFY_TIMEOUT: u64 = 20;
+/// Maximum number of stores waiting to be verified.
+const MAX_VERIFICATIONS: usize = 256;
+
+/// Counts of the verifications of our stores.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VerifyStats {
+    /// Lookups we sent to verify a store.
+    pub started: u64,
+    /// Stores that the verifying floodfill returned.
+    pub verified: u64,
+    /// Stores that the verifying floodfill didn't have, or had an older version
+    /// of.
+    pub failed: u64,
+    /// Verifications that the floodfill didn't reply to in time.
+    pub timed_out: u64,
+    /// Stores we didn't verify, because there were too many pending or we knew
+    /// no other floodfill.
+    pub skipped: u64,
+}
+
+/// What a verified store was.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Stored {
+    /// A publication of our own RouterInfo.
+    OurRouterInfo,
+    /// An entry we flooded as a floodfill.
+    Flooded,
+}
+
+/// A store that could not be found afterwards.
+#[derive(Debug, PartialEq)]
+pub(super) struct Failure {
+    pub(super) key: Hash,
+    pub(super) stored: Stored,
+    /// The floodfills that were sent the entry, or asked for it.
+    pub(super) tried: Vec<Hash>,
+}
+
+/// The result of a verification.
+#[derive(Debug, PartialEq)]
+pub(super) enum Outcome {
+    Verified,
+    Failed(Failure),
+}
+
+/// A store waiting to be verified.
+struct Verification {
+    stored: Stored,
+    lookup_type: DatabaseLookupType,
+    version: I2PDate,
+    /// The floodfills we stored the entry to, or that sent it to us.
+    stored_to: Vec<Hash>,
+    due: Instant,
+    /// The floodfill we asked, and when.
+    via: Option<(Hash, Instant)>,
+}
+
+impl Verification {
+    fn failure(self, key: Hash) -> Failure {
+        let mut tried = self.stored_to;
+        tried.extend(self.via.map(|(ff, _)| ff));
+        Failure {
+            key,
+            stored: self.stored,
+            tried,
+        }
+    }
+}
+
+/// Returns the version of the entry in `ds`, or `None` if it isn't a validly
+/// signed entry for the store's key.
+fn checked_version(ds: &DatabaseStore) -> Option<I2PDate> {
+    let ls = match &ds.data {
+        DatabaseStoreData::RI(ri) => {
+            return if ri.router_id.hash() == ds.key && ri.verify().is_ok() {
+                Some(ri.published)
+            } else {
+                None
+            };
+        }
+        DatabaseStoreData::LS(ls) => StoredLeaseSet::LS(ls.clone()),
+        DatabaseStoreData::LS2(ls) => StoredLeaseSet::LS2(ls.clone()),
+        DatabaseStoreData::EncryptedLS2(els) => StoredLeaseSet::EncryptedLS2(els.clone()),
+    };
+    let now = I2PDate::from_system_time(SystemTime::now());
+    if ls.key() == ds.key && ls.verify(now).is_ok() {
+        ls.version()
+    } else {
+        None
+    }
+}
+
+/// Schedules lookups that verify our stores, and matches the replies to them.
+pub(super) struct Verifier {
+    our_hash: Hash,
+    verifications: HashMap<Hash, Verification>,
+    stats: VerifyStats,
+}
+
+impl Verifier {
+    pub(super) fn new(our_hash: Hash) -> Self {
+        Verifier {
+            our_hash,
+            verifications: HashMap::new(),
+            stats: VerifyStats::default(),
+        }
+    }
+
+    pub(super) fn stats(&self) -> VerifyStats {
+        self.stats
+    }
+
+    /// Schedules a verification of `ds`, which was stored to the floodfills in
+    /// `stored_to`, replacing any earlier verification of the same key.
+    pub(super) fn stored(
+        &mut self,
+        ds: &DatabaseStore,
+        stored: Stored,
+        stored_to: Vec<Hash>,
+        now: Instant,
+    ) {
+        let version = match checked_version(ds) {
+            Some(version) => version,
+            None => return,
+        };
+        if self.verifications.len() >= MAX_VERIFICATIONS
+            && !self.verifications.contains_key(&ds.key)
+        {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        let lookup_type = match ds.data {
+            DatabaseStoreData::RI(_) => DatabaseLookupType::RouterInfo,
+            _ => DatabaseLookupType::LeaseSet,
+        };
+        let delay = thread_rng().gen_range(VERIFY_MIN_DELAY..=VERIFY_MAX_DELAY);
+        self.verifications.insert(
+            ds.key.clone(),
+            Verification {
+                stored,
+                lookup_type,
+                version,
+                stored_to,
+                due: now + Duration::from_secs(delay),
+                via: None,
+            },
+        );
+    }
+
+    /// Returns the lookups to send for the verifications that are due, and the
+    /// floodfills to send them to, along with the verifications that timed out.
+    pub(super) fn tick(
+        &mut self,
+        netdb: &LocalNetworkDatabase,
+        now: Instant,
+    ) -> (Vec<(Hash, Message)>, Vec<Failure>) {
+        let timeout = Duration::from_secs(VERIFY_TIMEOUT);
+        let timed_out: Vec<_> = self
+            .verifications
+            .iter()
+            .filter(|(_, v)| match v.via {
+                Some((_, sent)) => now.duration_since(sent) >= timeout,
+                None => false,
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        let failures = timed_out
+            .into_iter()
+            .map(|key| {
+                debug!("Verification of our store at key {} timed out", key);
+                self.stats.timed_out += 1;
+                let v = self.verifications.remove(&key).unwrap();
+                v.failure(key)
+            })
+            .collect();
+
+        let mut lookups = vec![];
+        let mut unverifiable = vec![];
+        for (key, v) in self.verifications.iter_mut() {
+            if v.via.is_some() || now < v.due {
+                continue;
+            }
+
+            // Never ask a floodfill that we know was sent the entry
+            let mut exclude = v.stored_to.clone();
+            exclude.push(self.our_hash.clone());
+            match netdb
+                .closest_floodfills(key, 1, &exclude)
+                .into_iter()
+                .next()
+            {
+                Some(ff) => {
+                    let ff = ff.router_id.hash();
+                    let dlm = DatabaseLookup::create_msg(
+                        &netdb.ctx.msg_ids,
+                        key.clone(),
+                        self.our_hash.clone(),
+                        v.lookup_type,
+                    );
+                    v.via = Some((ff.clone(), now));
+                    self.stats.started += 1;
+                    lookups.push((ff, dlm));
+                }
+                None => unverifiable.push(key.clone()),
+            }
+        }
+        for key in unverifiable {
+            debug!("No floodfill to verify our store at key {} with", key);
+            self.stats.skipped += 1;
+            self.verifications.remove(&key);
+        }
+
+        (lookups, failures)
+    }
+
+    /// Returns the outcome if `ds` from `from` answers one of our verifications.
+    pub(super) fn store_reply(&mut self, from: &Hash, ds: &DatabaseStore) -> Option<Outcome> {
+        self.take(from, &ds.key).map(|v| match checked_version(ds) {
+            Some(version) if version >= v.version => {
+                debug!("Verified our store at key {}", ds.key);
+                self.stats.verified += 1;
+                Outcome::Verified
+            }
+            _ => {
+                debug!("Floodfill {} has an old entry at key {}", from, ds.key);
+                self.stats.failed += 1;
+                Outcome::Failed(v.failure(ds.key.clone()))
+            }
+        })
+    }
+
+    /// Returns the outcome if `dsr` from `from` answers one of our verifications.
+    pub(super) fn search_reply(
+        &mut self,
+        from: &Hash,
+        dsr: &DatabaseSearchReply,
+    ) -> Option<Outcome> {
+        self.take(from, &dsr.key).map(|v| {
+            debug!("Floodfill {} has no entry at key {}", from, dsr.key);
+            self.stats.failed += 1;
+            Outcome::Failed(v.failure(dsr.key.clone()))
+        })
+    }
+
+    /// Removes and returns the verification of `key` that we asked `from` for.
+    fn take(&mut self, from: &Hash, key: &Hash) -> Option<Verification> {
+        match self.verifications.get(key) {
+            Some(Verification {
+                via: Some((ff, _)), ..
+            }) if ff == from => self.verifications.remove(key),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use super::{Outcome, Stored, Verifier, VerifyStats, VERIFY_MAX_DELAY, VERIFY_TIMEOUT};
+    use crate::data::{Hash, RouterCaps, RouterInfo};
+    use crate::i2np::{
+        DatabaseLookupType, DatabaseSearchReply, DatabaseStore, Message, MessagePayload,
+    };
+    use crate::netdb::{
+        mock::{populated_netdb, set_address},
+        publish::Publisher,
+        LocalNetworkDatabase,
+    };
+    use crate::router::mock::mock_router_info;
+
+    /// A floodfill that answers lookups from the entries it holds.
+    struct MockFloodfill {
+        hash: Hash,
+        entries: HashMap<Hash, RouterInfo>,
+    }
+
+    impl MockFloodfill {
+        fn new(hash: Hash) -> Self {
+            MockFloodfill {
+                hash,
+                entries: HashMap::new(),
+            }
+        }
+
+        fn with_entry(mut self, ri: RouterInfo) -> Self {
+            self.entries.insert(ri.router_id.hash(), ri);
+            self
+        }
+
+        /// Passes our lookup to the floodfill, and its reply to the verifier.
+        fn answer(&self, verifier: &mut Verifier, msg: Message) -> Option<Outcome> {
+            let dl = match msg.payload {
+                MessagePayload::DatabaseLookup(dl) => dl,
+                _ => panic!("Expected a DatabaseLookup"),
+            };
+            assert_eq!(dl.lookup_type(), DatabaseLookupType::RouterInfo);
+            match self.entries.get(dl.key()) {
+                Some(ri) => {
+                    verifier.store_reply(&self.hash, &DatabaseStore::from_ri(ri.clone(), None))
+                }
+                None => verifier.search_reply(
+                    &self.hash,
+                    &DatabaseSearchReply {
+                        key: dl.key().clone(),
+                        peers: vec![],
+                        from: self.hash.clone(),
+                    },
+                ),
+            }
+        }
+    }
+
+    /// Publishes our RouterInfo, and has one of the floodfills acknowledge it.
+    /// Returns the floodfills it was sent to.
+    fn publish(
+        netdb: &LocalNetworkDatabase,
+        publisher: &mut Publisher,
+        verifier: &mut Verifier,
+        now: Instant,
+    ) -> Vec<Hash> {
+        let sent = publisher.tick(netdb, now);
+        assert!(!sent.is_empty());
+        let token = match &sent[0].1.payload {
+            MessagePayload::DatabaseStore(ds) => ds.reply().unwrap().token(),
+            _ => panic!("Expected a DatabaseStore"),
+        };
+        assert!(publisher.acked(token));
+
+        let (ri, stored_to) = publisher.take_verification().unwrap();
+        let ds = DatabaseStore::from_ri(ri, None);
+        verifier.stored(&ds, Stored::OurRouterInfo, stored_to.clone(), now);
+        stored_to
+    }
+
+    /// Returns the verification lookup that is sent once it is due, checking
+    /// that it isn't sent early.
+    fn lookup(
+        netdb: &LocalNetworkDatabase,
+        verifier: &mut Verifier,
+        stored_to: &[Hash],
+        now: Instant,
+    ) -> (Hash, Message) {
+        assert!(verifier.tick(netdb, now).0.is_empty());
+
+        let due = now + Duration::from_secs(VERIFY_MAX_DELAY);
+        let (mut lookups, failures) = verifier.tick(netdb, due);
+        assert!(failures.is_empty());
+        assert_eq!(lookups.len(), 1);
+        let (via, msg) = lookups.pop().unwrap();
+        assert!(!stored_to.contains(&via));
+        assert_ne!(via, netdb.ctx.keys.rid.hash());
+
+        // Only one lookup is sent for each store
+        assert!(verifier.tick(netdb, due).0.is_empty());
+        (via, msg)
+    }
+
+    #[test]
+    fn verified() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let mut verifier = Verifier::new(netdb.ctx.keys.rid.hash());
+        let now = Instant::now();
+
+        let stored_to = publish(&netdb, &mut publisher, &mut verifier, now);
+        let (via, msg) = lookup(&netdb, &mut verifier, &stored_to, now);
+
+        // The floodfill we ask has our current RouterInfo
+        let ri = netdb.ctx.ri.read().unwrap().clone();
+        let ff = MockFloodfill::new(via).with_entry(ri);
+        assert_eq!(ff.answer(&mut verifier, msg), Some(Outcome::Verified));
+        assert_eq!(
+            verifier.stats(),
+            VerifyStats {
+                started: 1,
+                verified: 1,
+                failed: 0,
+                timed_out: 0,
+                skipped: 0,
+            }
+        );
+
+        // So we don't publish it again
+        let later = now + Duration::from_secs(VERIFY_MAX_DELAY);
+        assert!(publisher.tick(&netdb, later).is_empty());
+        assert_eq!(publisher.stats().republished, 0);
+    }
+
+    #[test]
+    fn republished_after_failure() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let mut verifier = Verifier::new(netdb.ctx.keys.rid.hash());
+        let now = Instant::now();
+
+        let stored_to = publish(&netdb, &mut publisher, &mut verifier, now);
+        let (via, msg) = lookup(&netdb, &mut verifier, &stored_to, now);
+
+        // Replies from other routers are ignored
+        let us = netdb.ctx.keys.rid.hash();
+        let dsr = DatabaseSearchReply {
+            key: us.clone(),
+            peers: vec![],
+            from: stored_to[0].clone(),
+        };
+        assert_eq!(verifier.search_reply(&stored_to[0], &dsr), None);
+
+        // The floodfill we ask doesn't have our RouterInfo
+        let ff = MockFloodfill::new(via.clone());
+        let failure = match ff.answer(&mut verifier, msg) {
+            Some(Outcome::Failed(failure)) => failure,
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        };
+        assert_eq!(failure.key, us);
+        assert_eq!(failure.stored, Stored::OurRouterInfo);
+        let mut tried = stored_to.clone();
+        tried.push(via);
+        assert_eq!(failure.tried, tried);
+        assert_eq!(verifier.stats().failed, 1);
+
+        // So we publish it again, to floodfills we haven't tried
+        publisher.verify_failed(failure.tried);
+        let later = now + Duration::from_secs(VERIFY_MAX_DELAY);
+        let republished = publisher.tick(&netdb, later);
+        assert!(!republished.is_empty());
+        assert!(republished.iter().all(|(ff, _)| !tried.contains(ff)));
+        assert_eq!(publisher.stats().republished, 1);
+
+        // The republication isn't verified itself
+        let token = match &republished[0].1.payload {
+            MessagePayload::DatabaseStore(ds) => ds.reply().unwrap().token(),
+            _ => panic!("Expected a DatabaseStore"),
+        };
+        assert!(publisher.acked(token));
+        assert!(publisher.take_verification().is_none());
+    }
+
+    #[test]
+    fn old_version_fails() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let mut verifier = Verifier::new(netdb.ctx.keys.rid.hash());
+        let now = Instant::now();
+
+        // The floodfill we ask still has the RouterInfo from before we
+        // published
+        let old = netdb.ctx.ri.read().unwrap().clone();
+        let stored_to = publish(&netdb, &mut publisher, &mut verifier, now);
+        let (via, msg) = lookup(&netdb, &mut verifier, &stored_to, now);
+        let ff = MockFloodfill::new(via).with_entry(old);
+        match ff.answer(&mut verifier, msg) {
+            Some(Outcome::Failed(failure)) => assert_eq!(failure.stored, Stored::OurRouterInfo),
+            outcome => panic!("Unexpected outcome: {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn timed_out() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut publisher = Publisher::new(false);
+        let mut verifier = Verifier::new(netdb.ctx.keys.rid.hash());
+        let now = Instant::now();
+
+        let stored_to = publish(&netdb, &mut publisher, &mut verifier, now);
+        let (via, _) = lookup(&netdb, &mut verifier, &stored_to, now);
+
+        let later = now + Duration::from_secs(VERIFY_MAX_DELAY + VERIFY_TIMEOUT);
+        let (lookups, mut failures) = verifier.tick(&netdb, later);
+        assert!(lookups.is_empty());
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures.pop().unwrap().tried.last(), Some(&via));
+        assert_eq!(verifier.stats().timed_out, 1);
+
+        // A late reply is ignored
+        let ri = netdb.ctx.ri.read().unwrap().clone();
+        assert_eq!(
+            verifier.store_reply(&via, &DatabaseStore::from_ri(ri, None)),
+            None
+        );
+    }
+
+    #[test]
+    fn no_other_floodfill() {
+        let (netdb, _) = populated_netdb(10, 0);
+        set_address(&netdb, 12345);
+        let mut verifier = Verifier::new(netdb.ctx.keys.rid.hash());
+        let now = Instant::now();
+
+        // Every floodfill we know already has the entry
+        let ri = mock_router_info(RouterCaps::default());
+        let ds = DatabaseStore::from_ri(ri, None);
+        verifier.stored(&ds, Stored::Flooded, netdb.ri_ds.keys(), now);
+
+        let later = now + Duration::from_secs(VERIFY_MAX_DELAY);
+        let (lookups, failures) = verifier.tick(&netdb, later);
+        assert!(lookups.is_empty() && failures.is_empty());
+        assert_eq!(verifier.stats().skipped, 1);
+    }
+}
@@ -1,12 +1,15 @@
-use ::config::{Config, ConfigError, File};
+use ::config::{Config, ConfigError};
 use futures::sync::mpsc;
 use std::fmt;
 use std::fs;
 use std::io;
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 
-use super::{types::CommSystem, Context, Distributor, Router};
-use crate::data::{ReadError, RouterInfo, RouterSecretKeys};
+use super::{profiles::Profiles, types::CommSystem, Context, Dispatcher, DistributorTx, Router};
+use crate::crypto::{self, pool::Pools, EncType, SelfTestError};
+use crate::data::{ReadError, RouterCaps, RouterInfo, RouterInfoBuilder, RouterSecretKeys};
+use crate::i2np::{MessageIdGenerator, MessageType};
 use crate::netdb::{client::Client as NetDbClient, Engine as NetDbEngine};
 use crate::router::config;
 use crate::transport;
@@ -15,7 +18,12 @@ use crate::tunnel;
 /// Builder errors
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Error {
+    Config(config::LoadError),
     Read(ReadError),
+    SelfTest(SelfTestError),
+    /// The router's keys use an encryption type that we can't receive messages
+    /// for, so they must not be published.
+    UnsupportedEncType(EncType),
     Write(String),
 }
 
@@ -23,18 +31,35 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Error::Config(e) => e.fmt(f),
             Error::Read(e) => format!("{}", e).fmt(f),
+            Error::SelfTest(e) => e.fmt(f),
+            Error::UnsupportedEncType(enc_type) => {
+                write!(f, "Router encryption type {:?} is not supported", enc_type)
+            }
             Error::Write(e) => e.fmt(f),
         }
     }
 }
 
+impl From<config::LoadError> for Error {
+    fn from(e: config::LoadError) -> Self {
+        Error::Config(e)
+    }
+}
+
 impl From<ReadError> for Error {
     fn from(e: ReadError) -> Self {
         Error::Read(e)
     }
 }
 
+impl From<SelfTestError> for Error {
+    fn from(e: SelfTestError) -> Self {
+        Error::SelfTest(e)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::Write(format!("{}", e))
@@ -43,9 +68,13 @@ impl From<io::Error> for Error {
 
 pub struct Builder {
     cfg_file: Option<String>,
+    settings: Option<Config>,
     keys: Option<RouterSecretKeys>,
     ri_file: Option<String>,
+    reseed_from: Option<String>,
     comms: Option<Arc<RwLock<dyn CommSystem>>>,
+    handlers: Vec<(MessageType, DistributorTx)>,
+    fallback: Option<DistributorTx>,
 }
 
 impl Builder {
@@ -53,9 +82,13 @@ impl Builder {
     pub fn new() -> Self {
         Builder {
             cfg_file: None,
+            settings: None,
             keys: None,
             ri_file: None,
+            reseed_from: None,
             comms: None,
+            handlers: vec![],
+            fallback: None,
         }
     }
 
@@ -64,6 +97,13 @@ impl Builder {
         self
     }
 
+    /// Uses a config that was assembled with a [`config::Loader`], instead of the
+    /// config file.
+    pub fn config(mut self, settings: Config) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
     pub fn router_keys(mut self, keys: RouterSecretKeys) -> Self {
         self.keys = Some(keys);
         self
@@ -74,35 +114,71 @@ impl Builder {
         self
     }
 
+    /// Reseeds from a local bundle or directory, overriding the config file.
+    pub fn reseed_from(mut self, path: String) -> Self {
+        self.reseed_from = Some(path);
+        self
+    }
+
     pub fn comm_system(mut self, comms: Arc<RwLock<dyn CommSystem>>) -> Self {
         self.comms = Some(comms);
         self
     }
 
+    /// Registers a handler for inbound messages of the given type, replacing the
+    /// router's default handler for that type.
+    pub fn message_handler(mut self, msg_type: MessageType, handler: DistributorTx) -> Self {
+        self.handlers.push((msg_type, handler));
+        self
+    }
+
+    /// Sets the handler for inbound messages that have no other registered handler.
+    pub fn fallback_handler(mut self, handler: DistributorTx) -> Self {
+        self.fallback = Some(handler);
+        self
+    }
+
     /// Build a Router.
     pub fn build(self) -> Result<Router, Error> {
-        let mut settings = Config::default();
-
-        // Default config options
-        settings.set_default(config::RESEED_ENABLE, true).unwrap();
+        let mut settings = match self.settings {
+            Some(settings) => settings,
+            None => {
+                let mut loader = config::Loader::new();
+                if let Some(ref cfg_file) = self.cfg_file {
+                    loader.file(Path::new(cfg_file))?;
+                }
+                for option in loader.unknown() {
+                    warn!("{}", option);
+                }
+                loader.finish()
+            }
+        };
+        if let Some(reseed_from) = self.reseed_from {
+            settings.set(config::RESEED_FROM, reseed_from).unwrap();
+        }
 
-        if let Some(ref cfg_file) = self.cfg_file {
-            settings.merge(File::with_name(&cfg_file)).unwrap();
+        // Refuse to run on a broken crypto backend
+        if settings.get_bool(config::CRYPTO_SELF_TEST).unwrap_or(true) {
+            crypto::self_test()?;
+        } else {
+            warn!("Crypto self-test is disabled");
         }
 
         let keys = match self.keys {
             Some(keys) => keys,
             None => match settings.get_str(config::ROUTER_KEYFILE) {
-                // Check if the keyfile exists
+                // Check if the keyfile exists. If it exists but can't be read, fail
+                // rather than replacing the router's identity.
                 Ok(keyfile) => match fs::metadata(&keyfile) {
                     Ok(_) => RouterSecretKeys::from_file(&keyfile)?,
-                    Err(_) => {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                         // We have a keyfile that doesn't exist, so create it
                         info!("Writing new router keys to {}", keyfile);
                         let keys = RouterSecretKeys::new();
                         keys.to_file(&keyfile)?;
                         keys
                     }
+                    Err(e) => return Err(e.into()),
                 },
                 Err(ConfigError::NotFound(key)) => {
                     info!(
@@ -115,12 +191,19 @@ impl Builder {
             },
         };
 
+        // Peers would send ECIES-X25519-AEAD-Ratchet messages to an X25519 key,
+        // which we can't decrypt.
+        let enc_type = keys.rid.encryption_key().enc_type();
+        if enc_type != EncType::ElGamal2048 {
+            return Err(Error::UnsupportedEncType(enc_type));
+        }
+
         // The goal of the next section is to build this subsystem graph:
         //
         //                 Incoming messages
         //                         |
         //                         v
-        //           +------- Distributor -----+------------------------+
+        //           +------- Dispatcher ------+------------------------+
         //           |                         |                        |
         //           v                         v                        v
         //     netdb::Engine <-----> tunnel::Listener ------> tunnel::Participant
@@ -137,28 +220,88 @@ impl Builder {
         let (netdb_client_tx, netdb_client_rx) = mpsc::unbounded();
         let (tunnel_build_ib_tx, tunnel_build_ib_rx) = mpsc::channel(1024);
         let (new_participating_tx, new_participating_rx) = mpsc::channel(1024);
+        let (new_own_tunnel_tx, new_own_tunnel_rx) = mpsc::channel(1024);
         let (tunnel_data_ib_tx, tunnel_data_ib_rx) = mpsc::channel(1024);
 
-        let distributor = Distributor::new(netdb_ib_tx, tunnel_build_ib_tx, tunnel_data_ib_tx);
+        // Load the profiles of the peers we knew before this start
+        let profiles = match settings.get_str(config::PROFILES_FILE) {
+            Ok(file) if Path::new(&file).exists() => match Profiles::read(Path::new(&file)) {
+                Ok(profiles) => {
+                    info!("Loaded {} peer profiles from {}", profiles.len(), file);
+                    profiles
+                }
+                Err(e) => {
+                    warn!("Failed to read peer profiles from {}: {}", file, e);
+                    Profiles::default()
+                }
+            },
+            _ => Profiles::default(),
+        };
+
+        // The listener and participant share the limits on our participating tunnels
+        let transit = tunnel::Transit::new(tunnel::TransitLimits::from_config(&settings));
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.set_profiles(profiles.clone());
+        dispatcher.register(MessageType::DatabaseStore, netdb_ib_tx.clone());
+        dispatcher.register(MessageType::DatabaseLookup, netdb_ib_tx.clone());
+        dispatcher.register(MessageType::DatabaseSearchReply, netdb_ib_tx.clone());
+        dispatcher.register(MessageType::DeliveryStatus, netdb_ib_tx);
+
+        // Tunnel messages are routed by tunnel ID before they reach the participant
+        let tunnel_dispatcher =
+            tunnel::TunnelDispatcher::new(transit.tunnel_ids().clone(), tunnel_data_ib_tx);
+        dispatcher.register_distributor(MessageType::TunnelData, tunnel_dispatcher.clone());
+        dispatcher.register_distributor(MessageType::TunnelGateway, tunnel_dispatcher);
+
+        dispatcher.register(MessageType::TunnelBuild, tunnel_build_ib_tx.clone());
+        dispatcher.register(MessageType::VariableTunnelBuild, tunnel_build_ib_tx.clone());
+        dispatcher.register(MessageType::VariableTunnelBuildReply, tunnel_build_ib_tx.clone());
+        dispatcher.register(MessageType::ShortTunnelBuild, tunnel_build_ib_tx.clone());
+        dispatcher.register(MessageType::OutboundTunnelBuildReply, tunnel_build_ib_tx);
+        for (msg_type, handler) in self.handlers {
+            dispatcher.register(msg_type, handler);
+        }
+        if let Some(fallback) = self.fallback {
+            dispatcher.set_fallback(fallback);
+        }
         let netdb_client = NetDbClient::new(netdb_client_tx);
 
         let comms = match self.comms {
             Some(comms) => comms,
             None => Arc::new(RwLock::new(transport::Manager::from_config(
                 &settings,
-                distributor,
+                dispatcher.clone(),
             ))),
         };
 
+        let msg_ids = MessageIdGenerator::default();
         let tunnel_participant = Some(tunnel::Participant::new(
             new_participating_rx,
+            new_own_tunnel_rx,
             tunnel_data_ib_rx,
+            transit.clone(),
+            netdb_client.clone(),
+            dispatcher,
             comms.clone(),
+            msg_ids.clone(),
         ));
 
-        let mut ri = RouterInfo::new(keys.rid.clone());
-        ri.set_addresses(comms.read().unwrap().addresses());
-        ri.sign(&keys.signing_private_key);
+        // Check whether the RouterInfo we last published advertised us as a
+        // floodfill, before replacing it.
+        let was_floodfill = settings
+            .get_str(config::RI_FILE)
+            .ok()
+            .and_then(|ri_file| RouterInfo::from_file(&ri_file).ok())
+            .map(|prev| prev.router_id == keys.rid && prev.is_floodfill())
+            .unwrap_or(false);
+
+        let floodfill = settings.get_bool(config::NETDB_FLOODFILL).unwrap_or(false);
+        let hidden = settings.get_bool(config::ROUTER_HIDDEN).unwrap_or(false);
+        let ri = RouterInfoBuilder::new(keys.rid.clone())
+            .caps(RouterCaps::default().floodfill(floodfill).hidden(hidden))
+            .addresses(comms.read().unwrap().addresses())
+            .sign(&keys.signing_private_key);
 
         match settings.get_str(config::RI_FILE) {
             Ok(ri_file) => ri.to_file(&ri_file)?,
@@ -173,29 +316,158 @@ impl Builder {
             config: RwLock::new(settings),
             keys,
             ri: Arc::new(RwLock::new(ri)),
+            msg_ids,
             netdb: netdb_client,
             comms,
+            pools: Pools::new(),
+            profiles,
         });
 
-        let netdb_engine = Some(NetDbEngine::new(
-            ctx.clone(),
-            netdb_pending_tx,
-            netdb_pending_rx,
-            netdb_ib_rx,
-            netdb_client_rx,
-        ));
+        let netdb_engine = Some(
+            NetDbEngine::new(
+                ctx.clone(),
+                netdb_pending_tx,
+                netdb_pending_rx,
+                netdb_ib_rx,
+                netdb_client_rx,
+            )
+            .was_floodfill(was_floodfill),
+        );
 
+        // Build replies arrive at the listener, which passes them to the creator. Both
+        // take tunnel IDs from those tracked by the participant.
+        let build_replies = tunnel::PendingReplies::default();
+        let tunnel_ids = transit.tunnel_ids().clone();
         let tunnel_listener = Some(tunnel::Listener::new(
             ctx.clone(),
+            build_replies.clone(),
+            transit,
             new_participating_tx,
             tunnel_build_ib_rx,
         ));
+        let tunnel_creator = tunnel::Creator::new(
+            ctx.clone(),
+            build_replies,
+            tunnel_ids,
+            new_own_tunnel_tx,
+        );
 
         Ok(Router {
             ctx,
             netdb_engine,
+            tunnel_creator,
             tunnel_listener,
             tunnel_participant,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use tempfile::tempdir;
+
+    use super::{Builder, Error};
+    use crate::crypto::EncType;
+    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::router::{
+        config::{self, Config},
+        mock::MockCommSystem,
+    };
+
+    /// Builds a router with the given floodfill option, and returns its
+    /// RouterInfo along with the one it wrote to disk.
+    fn build_router(dir: &Path, floodfill: bool) -> (RouterInfo, RouterInfo) {
+        let cfg_file = dir.join("router.toml");
+        let ri_file = dir.join("router.info");
+        fs::write(
+            &cfg_file,
+            format!(
+                "[router]\nkeyfile = {:?}\ninfofile = {:?}\n\n[netdb]\nfloodfill = {}\n",
+                dir.join("router.keys.dat"),
+                ri_file,
+                floodfill
+            ),
+        )
+        .unwrap();
+
+        let router = Builder::new()
+            .config_file(cfg_file.to_str().unwrap().to_owned())
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .build()
+            .unwrap();
+        let ri = router.ctx.ri.read().unwrap().clone();
+        let published = RouterInfo::from_file(ri_file.to_str().unwrap()).unwrap();
+        (ri, published)
+    }
+
+    #[test]
+    fn floodfill_capability() {
+        let dir = tempdir().unwrap();
+
+        let (ri, published) = build_router(dir.path(), true);
+        assert!(ri.is_floodfill());
+        assert!(published.is_floodfill());
+
+        // Turning the option off stops advertising the capability
+        let (ri, published) = build_router(dir.path(), false);
+        assert!(!ri.is_floodfill());
+        assert!(!published.is_floodfill());
+    }
+
+    #[test]
+    fn example_config() {
+        let dir = tempdir().unwrap();
+        let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/router.toml");
+
+        // Every option in the example is one that the router reads
+        let mut loader = config::Loader::new();
+        loader.file(&example).unwrap();
+        assert!(loader.unknown().is_empty(), "{:?}", loader.unknown());
+
+        // Keep the router's files out of the working directory
+        loader
+            .set(&format!(
+                "transport.ntcp2.keyfile={}",
+                dir.path().join("ntcp2.keys.dat").display()
+            ))
+            .unwrap();
+        let router = Builder::new().config(loader.finish()).build().unwrap();
+
+        // The router uses the example's options
+        let settings = router.ctx.config.read().unwrap();
+        assert_eq!(
+            settings.get_int(config::NETDB_EXPLORE_MAX_ROUTERS).unwrap(),
+            4000
+        );
+        let ri = router.ctx.ri.read().unwrap();
+        assert_eq!(
+            ri.addresses()[0].addr(),
+            Some("127.0.0.1:12345".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn empty_config() {
+        // Options that a Loader would have defaulted fall back to the same values
+        let router = Builder::new()
+            .config(Config::default())
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .build()
+            .unwrap();
+        let ri = router.ctx.ri.read().unwrap();
+        assert!(!ri.is_floodfill());
+        assert!(!ri.caps().unwrap().is_hidden());
+    }
+
+    #[test]
+    fn x25519_router_keys_rejected() {
+        let err = Builder::new()
+            .router_keys(RouterSecretKeys::with_enc_type(EncType::X25519))
+            .comm_system(Arc::new(RwLock::new(MockCommSystem::new())))
+            .build();
+        assert_eq!(err.err(), Some(Error::UnsupportedEncType(EncType::X25519)));
+    }
+}
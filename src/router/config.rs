@@ -1,13 +1,175 @@
+//! Router configuration options.
+//!
+//! Every option the router reads is listed in [`OPTIONS`], and can be set in the TOML
+//! config file read by [`Loader`]. See `examples/router.toml` for their meanings.
+
+use std::fmt;
+
 pub use config::Config;
 
+mod loader;
+pub use self::loader::{LoadError, Loader, Source, UnknownOption};
+
 // Router
 pub const ROUTER_KEYFILE: &str = "router.keyfile";
 pub const RI_FILE: &str = "router.infofile";
+pub const PROFILES_FILE: &str = "router.profilesfile";
+pub const ROUTER_HIDDEN: &str = "router.hidden";
+pub const ROUTER_BANDWIDTH: &str = "router.bandwidth";
+
+// Cryptography
+pub const CRYPTO_SELF_TEST: &str = "crypto.selftest";
+
+// Network database
+pub const NETDB_DIR: &str = "netdb.dir";
+pub const NETDB_PERSIST: &str = "netdb.persist";
+pub const NETDB_FLOODFILL: &str = "netdb.floodfill";
+pub const NETDB_EXPLORE_MIN_ROUTERS: &str = "netdb.explore.minrouters";
+pub const NETDB_EXPLORE_MAX_ROUTERS: &str = "netdb.explore.maxrouters";
+pub const NETDB_EXPIRE_MIN_AGE: &str = "netdb.expire.minage";
+pub const NETDB_EXPIRE_MAX_AGE: &str = "netdb.expire.maxage";
+pub const NETDB_EXPIRE_UNREACHABLE_AGE: &str = "netdb.expire.unreachableage";
+pub const NETDB_LOOKUP_PARALLELISM: &str = "netdb.lookup.parallelism";
+pub const NETDB_LOOKUP_FAILED_TTL: &str = "netdb.lookup.failedttl";
+pub const NETDB_LIMITS_LOOKUPS: &str = "netdb.limits.lookups";
+pub const NETDB_LIMITS_THIRD_PARTY_REPLIES: &str = "netdb.limits.thirdpartyreplies";
+pub const NETDB_LIMITS_STORES: &str = "netdb.limits.stores";
+pub const NETDB_LIMITS_VERIFICATIONS: &str = "netdb.limits.verifications";
+pub const NETDB_SELECTION_MAX_PER_SUBNET: &str = "netdb.selection.maxpersubnet";
+pub const NETDB_SELECTION_MIN_SUBNETS: &str = "netdb.selection.minsubnets";
+pub const NETDB_SELECTION_MIN_AGE: &str = "netdb.selection.minage";
+pub const NETDB_SELECTION_CANDIDATES: &str = "netdb.selection.candidates";
+
+// Tunnels
+pub const TUNNEL_PARTICIPATING_MAX: &str = "tunnel.participating.max";
+pub const TUNNEL_PARTICIPATING_BANDWIDTH: &str = "tunnel.participating.bandwidth";
+pub const TUNNEL_PARTICIPATING_SHARE: &str = "tunnel.participating.share";
+pub const TUNNEL_TEST_INTERVAL: &str = "tunnel.test.interval";
+pub const TUNNEL_TEST_FAILURES: &str = "tunnel.test.failures";
+pub const TUNNEL_EXPLORATORY_LENGTH: &str = "tunnel.exploratory.length";
+pub const TUNNEL_EXPLORATORY_VARIANCE: &str = "tunnel.exploratory.variance";
+pub const TUNNEL_EXPLORATORY_QUANTITY: &str = "tunnel.exploratory.quantity";
+pub const TUNNEL_EXPLORATORY_BACKUP: &str = "tunnel.exploratory.backup";
+pub const TUNNEL_EXPLORATORY_MIN_BANDWIDTH: &str = "tunnel.exploratory.minbandwidth";
+pub const TUNNEL_CLIENT_LENGTH: &str = "tunnel.client.length";
+pub const TUNNEL_CLIENT_VARIANCE: &str = "tunnel.client.variance";
+pub const TUNNEL_CLIENT_QUANTITY: &str = "tunnel.client.quantity";
+pub const TUNNEL_CLIENT_BACKUP: &str = "tunnel.client.backup";
+pub const TUNNEL_CLIENT_MIN_BANDWIDTH: &str = "tunnel.client.minbandwidth";
+pub const TUNNEL_GATEWAY_BATCH_DELAY: &str = "tunnel.gateway.batchdelay";
 
 // Reseeding
 pub const RESEED_ENABLE: &str = "reseed.enable";
+pub const RESEED_HOSTS: &str = "reseed.hosts";
+pub const RESEED_TIMEOUT: &str = "reseed.timeout";
+pub const RESEED_FROM: &str = "reseed.from";
 
 // Transports
 pub const NTCP_LISTEN: &str = "transport.ntcp.listen";
 pub const NTCP2_LISTEN: &str = "transport.ntcp2.listen";
 pub const NTCP2_KEYFILE: &str = "transport.ntcp2.keyfile";
+
+// Logging
+pub const LOGGING_FILTER: &str = "logging.filter";
+
+// Testing
+/// Set to false to accept RouterInfos without checking their signatures. This is
+/// not in [`OPTIONS`], and only exists in test builds or with the `test-util`
+/// feature.
+#[cfg(any(test, feature = "test-util"))]
+pub const TEST_VERIFY_RI: &str = "test.verifyri";
+
+/// The type of value that an option takes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionType {
+    Bool,
+    Integer,
+    String,
+    StringList,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for OptionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionType::Bool => "a boolean".fmt(f),
+            OptionType::Integer => "an integer".fmt(f),
+            OptionType::String => "a string".fmt(f),
+            OptionType::StringList => "a list of strings".fmt(f),
+        }
+    }
+}
+
+/// Every option that the router reads, and the type of its value.
+pub const OPTIONS: &[(&str, OptionType)] = &[
+    (ROUTER_KEYFILE, OptionType::String),
+    (RI_FILE, OptionType::String),
+    (PROFILES_FILE, OptionType::String),
+    (ROUTER_HIDDEN, OptionType::Bool),
+    (ROUTER_BANDWIDTH, OptionType::Integer),
+    (CRYPTO_SELF_TEST, OptionType::Bool),
+    (NETDB_DIR, OptionType::String),
+    (NETDB_PERSIST, OptionType::Bool),
+    (NETDB_FLOODFILL, OptionType::Bool),
+    (NETDB_EXPLORE_MIN_ROUTERS, OptionType::Integer),
+    (NETDB_EXPLORE_MAX_ROUTERS, OptionType::Integer),
+    (NETDB_EXPIRE_MIN_AGE, OptionType::Integer),
+    (NETDB_EXPIRE_MAX_AGE, OptionType::Integer),
+    (NETDB_EXPIRE_UNREACHABLE_AGE, OptionType::Integer),
+    (NETDB_LOOKUP_PARALLELISM, OptionType::Integer),
+    (NETDB_LOOKUP_FAILED_TTL, OptionType::Integer),
+    (NETDB_LIMITS_LOOKUPS, OptionType::Integer),
+    (NETDB_LIMITS_THIRD_PARTY_REPLIES, OptionType::Integer),
+    (NETDB_LIMITS_STORES, OptionType::Integer),
+    (NETDB_LIMITS_VERIFICATIONS, OptionType::Integer),
+    (NETDB_SELECTION_MAX_PER_SUBNET, OptionType::Integer),
+    (NETDB_SELECTION_MIN_SUBNETS, OptionType::Integer),
+    (NETDB_SELECTION_MIN_AGE, OptionType::Integer),
+    (NETDB_SELECTION_CANDIDATES, OptionType::Integer),
+    (TUNNEL_PARTICIPATING_MAX, OptionType::Integer),
+    (TUNNEL_PARTICIPATING_BANDWIDTH, OptionType::Integer),
+    (TUNNEL_PARTICIPATING_SHARE, OptionType::Integer),
+    (TUNNEL_TEST_INTERVAL, OptionType::Integer),
+    (TUNNEL_TEST_FAILURES, OptionType::Integer),
+    (TUNNEL_EXPLORATORY_LENGTH, OptionType::Integer),
+    (TUNNEL_EXPLORATORY_VARIANCE, OptionType::Integer),
+    (TUNNEL_EXPLORATORY_QUANTITY, OptionType::Integer),
+    (TUNNEL_EXPLORATORY_BACKUP, OptionType::Integer),
+    (TUNNEL_EXPLORATORY_MIN_BANDWIDTH, OptionType::String),
+    (TUNNEL_CLIENT_LENGTH, OptionType::Integer),
+    (TUNNEL_CLIENT_VARIANCE, OptionType::Integer),
+    (TUNNEL_CLIENT_QUANTITY, OptionType::Integer),
+    (TUNNEL_CLIENT_BACKUP, OptionType::Integer),
+    (TUNNEL_CLIENT_MIN_BANDWIDTH, OptionType::String),
+    (TUNNEL_GATEWAY_BATCH_DELAY, OptionType::Integer),
+    (RESEED_ENABLE, OptionType::Bool),
+    (RESEED_HOSTS, OptionType::StringList),
+    (RESEED_TIMEOUT, OptionType::Integer),
+    (RESEED_FROM, OptionType::String),
+    (NTCP_LISTEN, OptionType::String),
+    (NTCP2_LISTEN, OptionType::String),
+    (NTCP2_KEYFILE, OptionType::String),
+    (LOGGING_FILTER, OptionType::String),
+];
+
+/// Returns the type of an option, or `None` if the router doesn't read it.
+pub fn option_type(key: &str) -> Option<OptionType> {
+    OPTIONS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, option_type)| *option_type)
+}
+
+/// Returns whether RouterInfo signatures should be checked. They always are, unless
+/// a test has set [`TEST_VERIFY_RI`] to false.
+#[cfg(not(any(test, feature = "test-util")))]
+pub fn verify_router_infos(_config: &Config) -> bool {
+    true
+}
+
+/// Returns whether RouterInfo signatures should be checked. They always are, unless
+/// a test has set [`TEST_VERIFY_RI`] to false.
+#[cfg(any(test, feature = "test-util"))]
+pub fn verify_router_infos(config: &Config) -> bool {
+    config.get_bool(TEST_VERIFY_RI).unwrap_or(true)
+}
@@ -0,0 +1,392 @@
+//! Loading the router config from a TOML file and command-line overrides.
+//!
+//! Each option takes its value from the last of these that sets it:
+//!
+//! - The defaults set by [`Loader::new`].
+//! - The config file. Its tables mirror the option names, so `netdb.limits.lookups`
+//!   is set by `lookups` in the `[netdb.limits]` table.
+//! - `--set key=value` arguments on the command line.
+//!
+//! Values are checked against the types in [`OPTIONS`](super::OPTIONS), and a value
+//! of the wrong type is an error naming where it was set. Options that the router
+//! doesn't read are collected as [`UnknownOption`]s rather than rejected, so that a
+//! config file written for a newer version can still be loaded.
+
+use ::config::{Config, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use super::{
+    option_type, OptionType, CRYPTO_SELF_TEST, NETDB_FLOODFILL, NETDB_PERSIST, RESEED_ENABLE,
+    RESEED_TIMEOUT, ROUTER_HIDDEN,
+};
+
+/// Where an option was set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// In the given config file, on the given line if it could be found.
+    File(String, Option<usize>),
+    /// In a `--set` argument.
+    CommandLine,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path, Some(line)) => write!(f, "{}:{}", path, line),
+            Source::File(path, None) => path.fmt(f),
+            Source::CommandLine => "--set".fmt(f),
+        }
+    }
+}
+
+/// Config loading errors
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The config file couldn't be read.
+    Read(String, String),
+    /// The config file isn't valid TOML. The message gives the line.
+    Syntax(String, String),
+    /// An option was set to a value of the wrong type.
+    WrongType {
+        key: String,
+        expected: OptionType,
+        source: Source,
+    },
+    /// A `--set` argument isn't of the form `key=value`.
+    InvalidOverride(String),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Read(path, e) => write!(f, "Failed to read {}: {}", path, e),
+            LoadError::Syntax(path, e) => write!(f, "{}: {}", path, e),
+            LoadError::WrongType {
+                key,
+                expected,
+                source,
+            } => write!(f, "{}: {} must be {}", source, key, expected),
+            LoadError::InvalidOverride(arg) => {
+                write!(f, "Invalid override {:?}, expected key=value", arg)
+            }
+        }
+    }
+}
+
+/// An option that the router doesn't read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownOption {
+    pub key: String,
+    pub source: Source,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for UnknownOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: Ignoring unknown option {}", self.source, self.key)
+    }
+}
+
+/// Assembles the router config from the defaults, a config file, and command-line
+/// overrides, applied in that order.
+pub struct Loader {
+    settings: Config,
+    unknown: Vec<UnknownOption>,
+}
+
+impl Default for Loader {
+    fn default() -> Self {
+        Loader::new()
+    }
+}
+
+impl Loader {
+    /// Creates a `Loader` holding the default config.
+    pub fn new() -> Self {
+        let mut settings = Config::default();
+        settings.set_default(ROUTER_HIDDEN, false).unwrap();
+        settings.set_default(CRYPTO_SELF_TEST, true).unwrap();
+        settings.set_default(NETDB_PERSIST, true).unwrap();
+        settings.set_default(NETDB_FLOODFILL, false).unwrap();
+        settings.set_default(RESEED_ENABLE, true).unwrap();
+        settings.set_default(RESEED_TIMEOUT, 10).unwrap();
+
+        Loader {
+            settings,
+            unknown: vec![],
+        }
+    }
+
+    /// Sets the options in the TOML config file at `path`.
+    pub fn file(&mut self, path: &Path) -> Result<(), LoadError> {
+        let name = path.display().to_string();
+        let contents =
+            fs::read_to_string(path).map_err(|e| LoadError::Read(name.clone(), e.to_string()))?;
+        self.toml(&name, &contents)
+    }
+
+    /// Sets the options in `contents`, which were read from the file `name`.
+    fn toml(&mut self, name: &str, contents: &str) -> Result<(), LoadError> {
+        let table: toml::value::Table = toml::from_str(contents)
+            .map_err(|e| LoadError::Syntax(name.to_owned(), e.to_string()))?;
+        let lines = key_lines(contents);
+
+        let mut options = vec![];
+        flatten("", table, &mut options);
+        for (key, value) in options {
+            let source = Source::File(name.to_owned(), lines.get(&key).cloned());
+            self.set_value(key, value, source)?;
+        }
+        Ok(())
+    }
+
+    /// Sets an option from a `key=value` argument. The value is read as the type of
+    /// the option, and lists of strings are separated by commas.
+    pub fn set(&mut self, arg: &str) -> Result<(), LoadError> {
+        let mut parts = arg.splitn(2, '=');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if !key.trim().is_empty() => (key.trim(), value.trim()),
+            _ => return Err(LoadError::InvalidOverride(arg.to_owned())),
+        };
+
+        // Values that can't be read as the option's type are passed on as strings, so
+        // that they are reported as the wrong type.
+        let parsed = match option_type(key) {
+            Some(OptionType::Bool) => value.parse().ok().map(toml::Value::Boolean),
+            Some(OptionType::Integer) => value.parse().ok().map(toml::Value::Integer),
+            Some(OptionType::StringList) => Some(toml::Value::Array(
+                value
+                    .split(',')
+                    .map(|item| toml::Value::String(item.trim().to_owned()))
+                    .collect(),
+            )),
+            Some(OptionType::String) | None => None,
+        };
+        self.set_value(
+            key.to_owned(),
+            parsed.unwrap_or_else(|| toml::Value::String(value.to_owned())),
+            Source::CommandLine,
+        )
+    }
+
+    fn set_value(
+        &mut self,
+        key: String,
+        value: toml::Value,
+        source: Source,
+    ) -> Result<(), LoadError> {
+        let expected = match option_type(&key) {
+            Some(expected) => expected,
+            None => {
+                self.unknown.push(UnknownOption { key, source });
+                return Ok(());
+            }
+        };
+
+        let value: Value = match (expected, value) {
+            (OptionType::Bool, toml::Value::Boolean(b)) => b.into(),
+            (OptionType::Integer, toml::Value::Integer(i)) => i.into(),
+            (OptionType::String, toml::Value::String(s)) => s.into(),
+            (OptionType::StringList, toml::Value::Array(items))
+                if items.iter().all(toml::Value::is_str) =>
+            {
+                items
+                    .into_iter()
+                    .filter_map(|item| item.as_str().map(String::from))
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            _ => {
+                return Err(LoadError::WrongType {
+                    key,
+                    expected,
+                    source,
+                })
+            }
+        };
+        self.settings.set(&key, value).unwrap();
+        Ok(())
+    }
+
+    /// Returns the options that were set but that the router doesn't read.
+    pub fn unknown(&self) -> &[UnknownOption] {
+        &self.unknown
+    }
+
+    /// Returns the assembled config.
+    pub fn finish(self) -> Config {
+        self.settings
+    }
+}
+
+/// Collects the values in a TOML table under their dotted option names.
+fn flatten(prefix: &str, table: toml::value::Table, options: &mut Vec<(String, toml::Value)>) {
+    for (name, value) in table {
+        let key = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        match value {
+            toml::Value::Table(table) => flatten(&key, table, options),
+            value => options.push((key, value)),
+        }
+    }
+}
+
+/// Finds the line on which each option in a TOML file is set, for error messages.
+/// This only recognises `key = value` lines under `[table]` headers, which is how
+/// router config files are written.
+fn key_lines(contents: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut table = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        } else if line.starts_with('[') {
+            table = line[1..].split(']').next().unwrap_or("").trim().to_owned();
+        } else if let Some(eq) = line.find('=') {
+            let name = line[..eq].trim().trim_matches('"');
+            let key = if table.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{}.{}", table, name)
+            };
+            lines.entry(key).or_insert(i + 1);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempfile::tempdir;
+
+    use super::{LoadError, Loader, Source, UnknownOption};
+    use crate::router::config::{self, OptionType};
+
+    #[test]
+    fn precedence() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("router.toml");
+        fs::write(
+            &path,
+            "[netdb]\nfloodfill = true\npersist = false\n\n[reseed]\ntimeout = 30\n",
+        )
+        .unwrap();
+
+        let mut loader = Loader::new();
+        loader.file(&path).unwrap();
+        loader.set("netdb.persist=true").unwrap();
+        loader
+            .set("reseed.hosts = https://a.example/, https://b.example/")
+            .unwrap();
+        assert!(loader.unknown().is_empty());
+        let settings = loader.finish();
+
+        // Defaults < file < command line
+        assert!(settings.get_bool(config::CRYPTO_SELF_TEST).unwrap());
+        assert!(settings.get_bool(config::NETDB_FLOODFILL).unwrap());
+        assert_eq!(settings.get_int(config::RESEED_TIMEOUT).unwrap(), 30);
+        assert!(settings.get_bool(config::NETDB_PERSIST).unwrap());
+        let hosts: Vec<_> = settings
+            .get_array(config::RESEED_HOSTS)
+            .unwrap()
+            .into_iter()
+            .map(|host| host.into_str().unwrap())
+            .collect();
+        assert_eq!(hosts, vec!["https://a.example/", "https://b.example/"]);
+    }
+
+    #[test]
+    fn wrong_types() {
+        let mut loader = Loader::new();
+        let err = loader
+            .toml(
+                "router.toml",
+                "[router]\nhidden = false\n\n[netdb]\n# Comment = 1\nfloodfill = \"yes\"\n",
+            )
+            .unwrap_err();
+        assert_eq!(
+            err,
+            LoadError::WrongType {
+                key: config::NETDB_FLOODFILL.to_owned(),
+                expected: OptionType::Bool,
+                source: Source::File("router.toml".to_owned(), Some(6)),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "router.toml:6: netdb.floodfill must be a boolean"
+        );
+
+        assert_eq!(
+            loader.toml("router.toml", "[reseed]\nhosts = [\"a\", 1]\n"),
+            Err(LoadError::WrongType {
+                key: config::RESEED_HOSTS.to_owned(),
+                expected: OptionType::StringList,
+                source: Source::File("router.toml".to_owned(), Some(2)),
+            })
+        );
+        assert_eq!(
+            loader.set("reseed.timeout=soon"),
+            Err(LoadError::WrongType {
+                key: config::RESEED_TIMEOUT.to_owned(),
+                expected: OptionType::Integer,
+                source: Source::CommandLine,
+            })
+        );
+
+        // Invalid TOML and overrides are rejected
+        match loader.toml("router.toml", "[netdb\nfloodfill = true\n") {
+            Err(LoadError::Syntax(path, e)) => {
+                assert_eq!(path, "router.toml");
+                assert!(e.contains("line"), "{}", e);
+            }
+            v => panic!("Unexpected returned value: {:?}", v),
+        }
+        assert_eq!(
+            loader.set("netdb.floodfill"),
+            Err(LoadError::InvalidOverride("netdb.floodfill".to_owned()))
+        );
+        assert_eq!(
+            loader.set("=true"),
+            Err(LoadError::InvalidOverride("=true".to_owned()))
+        );
+    }
+
+    #[test]
+    fn unknown_options() {
+        let mut loader = Loader::new();
+        loader
+            .toml(
+                "router.toml",
+                "[netdb]\nfloodfill = true\n\n[future]\nfeature = 1\n",
+            )
+            .unwrap();
+        loader.set("netdb.future=yes").unwrap();
+        assert_eq!(
+            loader.unknown(),
+            &[
+                UnknownOption {
+                    key: "future.feature".to_owned(),
+                    source: Source::File("router.toml".to_owned(), Some(5)),
+                },
+                UnknownOption {
+                    key: "netdb.future".to_owned(),
+                    source: Source::CommandLine,
+                },
+            ][..]
+        );
+
+        // The known options were still set
+        assert!(loader.finish().get_bool(config::NETDB_FLOODFILL).unwrap());
+    }
+}
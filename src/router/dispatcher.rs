@@ -0,0 +1,200 @@
+//! Routing of inbound I2NP messages to the subsystems that handle them.
+
+use futures::{future, Future, Sink};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use super::{profiles::Profiles, types, DistributorTx};
+use crate::data::Hash;
+use crate::i2np::{Message, MessageType};
+
+type Handler = Arc<dyn Fn(Hash, Message) -> types::DistributorResult + Send + Sync>;
+
+/// Routes inbound messages by type to registered sub-handlers.
+///
+/// Messages of a type without a registered handler are sent to the fallback handler if
+/// one is set, and dropped otherwise. Every message is recorded as having been
+/// heard from its sender in the peer profiles.
+#[derive(Clone)]
+pub struct Dispatcher {
+    handlers: HashMap<MessageType, Handler>,
+    fallback: Option<DistributorTx>,
+    received: Arc<Mutex<HashMap<MessageType, u64>>>,
+    profiles: Profiles,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher {
+            handlers: HashMap::new(),
+            fallback: None,
+            received: Arc::new(Mutex::new(HashMap::new())),
+            profiles: Profiles::default(),
+        }
+    }
+
+    /// Sets the peer profiles in which senders are recorded.
+    pub fn set_profiles(&mut self, profiles: Profiles) {
+        self.profiles = profiles;
+    }
+
+    /// Registers a handler for the given message type, replacing any existing handler.
+    pub fn register(&mut self, msg_type: MessageType, handler: DistributorTx) {
+        self.register_distributor(msg_type, handler);
+    }
+
+    /// Registers a [`Distributor`] for the given message type, replacing any existing
+    /// handler. Messages are passed to it directly, instead of through a channel.
+    ///
+    /// [`Distributor`]: types::Distributor
+    pub fn register_distributor<D: types::Distributor>(
+        &mut self,
+        msg_type: MessageType,
+        handler: D,
+    ) {
+        self.handlers.insert(
+            msg_type,
+            Arc::new(move |from, msg| handler.handle(from, msg)),
+        );
+    }
+
+    /// Sets the handler for messages of types that have no registered handler.
+    pub fn set_fallback(&mut self, handler: DistributorTx) {
+        self.fallback = Some(handler);
+    }
+
+    /// Returns the number of messages of the given type that have been received.
+    pub fn received(&self, msg_type: MessageType) -> u64 {
+        self.received
+            .lock()
+            .unwrap()
+            .get(&msg_type)
+            .cloned()
+            .unwrap_or(0)
+    }
+}
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        Dispatcher::new()
+    }
+}
+
+impl types::Distributor for DistributorTx {
+    fn handle(&self, from: Hash, msg: Message) -> types::DistributorResult {
+        Box::new(self.clone().send((from, msg)).map(|_| ()))
+    }
+}
+
+impl types::Distributor for Dispatcher {
+    fn handle(&self, from: Hash, msg: Message) -> types::DistributorResult {
+        let msg_type = msg.message_type();
+        *self.received.lock().unwrap().entry(msg_type).or_insert(0) += 1;
+        self.profiles.heard_from(&from, SystemTime::now());
+
+        if let Some(handler) = self.handlers.get(&msg_type) {
+            return handler(from, msg);
+        }
+        match &self.fallback {
+            Some(fallback) => fallback.handle(from, msg),
+            None => {
+                debug!("Dropping unhandled message from {}:\n{}", from, msg);
+                Box::new(future::ok(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Async, Future, Stream};
+
+    use super::Dispatcher;
+    use crate::data::Hash;
+    use crate::i2np::{Message, MessageIdGenerator, MessageType};
+    use crate::router::{profiles::Profiles, types::Distributor};
+
+    #[test]
+    fn routing() {
+        let (netdb_tx, mut netdb_rx) = mpsc::channel(4);
+        let (tunnel_tx, mut tunnel_rx) = mpsc::channel(4);
+
+        let msg_ids = MessageIdGenerator::default();
+        let profiles = Profiles::default();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.set_profiles(profiles.clone());
+        dispatcher.register(MessageType::DeliveryStatus, netdb_tx);
+        dispatcher.register(MessageType::Data, tunnel_tx);
+
+        dispatcher
+            .handle(Hash([1; 32]), Message::delivery_status(&msg_ids, 7))
+            .wait()
+            .unwrap();
+        dispatcher
+            .handle(Hash([2; 32]), Message::data(&msg_ids, vec![1, 2, 3]))
+            .wait()
+            .unwrap();
+
+        match netdb_rx.poll() {
+            Ok(Async::Ready(Some((from, msg)))) => {
+                assert_eq!(from, Hash([1; 32]));
+                assert_eq!(msg.message_type(), MessageType::DeliveryStatus);
+            }
+            v => panic!("Unexpected returned value: {:?}", v),
+        }
+        match tunnel_rx.poll() {
+            Ok(Async::Ready(Some((from, msg)))) => {
+                assert_eq!(from, Hash([2; 32]));
+                assert_eq!(msg.message_type(), MessageType::Data);
+            }
+            v => panic!("Unexpected returned value: {:?}", v),
+        }
+
+        assert_eq!(dispatcher.received(MessageType::DeliveryStatus), 1);
+        assert_eq!(dispatcher.received(MessageType::Data), 1);
+        assert_eq!(dispatcher.received(MessageType::Garlic), 0);
+
+        // Senders are recorded in the peer profiles
+        assert!(!profiles
+            .get(&Hash([1; 32]))
+            .unwrap()
+            .last_heard_from
+            .is_unset());
+        assert!(profiles.get(&Hash([3; 32])).is_none());
+    }
+
+    #[test]
+    fn fallback() {
+        let (handler_tx, mut handler_rx) = mpsc::channel(4);
+        let (fallback_tx, mut fallback_rx) = mpsc::channel(4);
+
+        let msg_ids = MessageIdGenerator::default();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(MessageType::DeliveryStatus, handler_tx);
+
+        // Without a fallback, unregistered messages are dropped
+        dispatcher
+            .handle(Hash([1; 32]), Message::data(&msg_ids, vec![1, 2, 3]))
+            .wait()
+            .unwrap();
+        assert_eq!(dispatcher.received(MessageType::Data), 1);
+
+        // With a fallback, unregistered messages are sent to it
+        dispatcher.set_fallback(fallback_tx);
+        dispatcher
+            .handle(Hash([1; 32]), Message::data(&msg_ids, vec![1, 2, 3]))
+            .wait()
+            .unwrap();
+        assert_eq!(dispatcher.received(MessageType::Data), 2);
+
+        match fallback_rx.poll() {
+            Ok(Async::Ready(Some((_, msg)))) => {
+                assert_eq!(msg.message_type(), MessageType::Data)
+            }
+            v => panic!("Unexpected returned value: {:?}", v),
+        }
+        drop(dispatcher);
+        assert_eq!(handler_rx.poll(), Ok(Async::Ready(None)));
+    }
+}
@@ -4,15 +4,20 @@
 //! self-consistency across its component's API.
 
 use config::Config;
-use futures::{future, sync::mpsc, Future};
+use futures::{future, sync::mpsc, Future, Sink};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex, RwLock};
 use tokio::io;
 
 use super::types::{CommSystem, Distributor, DistributorResult};
-use crate::data::{Hash, RouterAddress, RouterInfo, RouterSecretKeys};
-use crate::i2np::Message;
+use crate::crypto::pool::Pools;
+use crate::data::{
+    Hash, I2PString, RouterAddress, RouterCaps, RouterInfo, RouterInfoBuilder, RouterSecretKeys,
+};
+use crate::i2np::{Message, MessageIdGenerator};
 use crate::netdb::{client::Client as NetDbClient, mock::MockNetDb};
-use crate::router::Context;
+use crate::router::{profiles::Profiles, Context};
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
@@ -66,6 +71,67 @@ impl CommSystem for MockCommSystem {
     }
 }
 
+/// The inbound message queues of the routers sharing a [`LoopbackCommSystem`].
+pub type LoopbackPeers = Arc<Mutex<HashMap<Hash, mpsc::Sender<(Hash, Message)>>>>;
+
+/// Delivers messages straight to the inbound queue of another in-process router.
+pub struct LoopbackCommSystem {
+    our_hash: Hash,
+    peers: LoopbackPeers,
+}
+
+impl LoopbackCommSystem {
+    pub fn new(our_hash: Hash, peers: LoopbackPeers) -> Self {
+        LoopbackCommSystem { our_hash, peers }
+    }
+}
+
+impl CommSystem for LoopbackCommSystem {
+    fn addresses(&self) -> Vec<RouterAddress> {
+        vec![]
+    }
+
+    fn start(&mut self, _ctx: Arc<Context>) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        Box::new(future::ok(()))
+    }
+
+    fn is_established(&self, _hash: &Hash) -> bool {
+        false
+    }
+
+    fn send(&self, peer: RouterInfo, msg: Message) -> Result<IoFuture<()>, (RouterInfo, Message)> {
+        let tx = match self.peers.lock().unwrap().get(&peer.router_id.hash()) {
+            Some(tx) => tx.clone(),
+            None => return Err((peer, msg)),
+        };
+        Ok(Box::new(
+            tx.send((self.our_hash.clone(), msg))
+                .map(|_| ())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Peer has stopped")),
+        ))
+    }
+}
+
+/// Returns a signed RouterInfo for a new router with the given capabilities.
+pub fn mock_router_info(caps: RouterCaps) -> RouterInfo {
+    let rsk = RouterSecretKeys::new();
+    RouterInfoBuilder::new(rsk.rid)
+        .caps(caps)
+        .sign(&rsk.signing_private_key)
+}
+
+/// Like [`mock_router_info`], for a router with an NTCP2 address at `ip`.
+pub fn mock_reachable_router_info(caps: RouterCaps, ip: &str) -> RouterInfo {
+    let rsk = RouterSecretKeys::new();
+    RouterInfoBuilder::new(rsk.rid)
+        .caps(caps)
+        .addresses(vec![RouterAddress::new(
+            &I2PString::new("NTCP2"),
+            SocketAddr::new(ip.parse().unwrap(), 12345),
+        )])
+        .sign(&rsk.signing_private_key)
+}
+
 pub fn mock_context() -> Arc<Context> {
     let (tx, _) = mpsc::unbounded();
     mock_context_with_netdb(NetDbClient::new(tx))
@@ -78,6 +144,41 @@ pub fn mock_context_and_netdb() -> (Arc<Context>, MockNetDb) {
     (ctx, netdb)
 }
 
+/// Creates a context and netDb for a new router, reachable by the other routers
+/// sharing `peers`. Messages sent to the router arrive on the returned receiver.
+pub fn loopback_context_and_netdb(
+    peers: &LoopbackPeers,
+) -> (Arc<Context>, MockNetDb, mpsc::Receiver<(Hash, Message)>) {
+    loopback_context_and_netdb_with_keys(peers, RouterSecretKeys::new())
+}
+
+/// Like [`loopback_context_and_netdb`], for a router with the given keys.
+pub fn loopback_context_and_netdb_with_keys(
+    peers: &LoopbackPeers,
+    keys: RouterSecretKeys,
+) -> (Arc<Context>, MockNetDb, mpsc::Receiver<(Hash, Message)>) {
+    let mut ri = RouterInfo::new(keys.rid.clone());
+    ri.sign(&keys.signing_private_key);
+    let hash = ri.router_id.hash();
+
+    let (ib_tx, ib_rx) = mpsc::channel(1024);
+    peers.lock().unwrap().insert(hash.clone(), ib_tx);
+
+    let (client_tx, client_rx) = mpsc::unbounded();
+    let ctx = Arc::new(Context {
+        config: RwLock::new(Config::default()),
+        keys,
+        ri: Arc::new(RwLock::new(ri)),
+        msg_ids: MessageIdGenerator::default(),
+        netdb: NetDbClient::new(client_tx),
+        comms: Arc::new(RwLock::new(LoopbackCommSystem::new(hash, peers.clone()))),
+        pools: Pools::new(),
+        profiles: Profiles::default(),
+    });
+    let netdb = MockNetDb::new(ctx.clone(), client_rx);
+    (ctx, netdb, ib_rx)
+}
+
 fn mock_context_with_netdb(netdb: NetDbClient) -> Arc<Context> {
     let keys = RouterSecretKeys::new();
     let mut ri = RouterInfo::new(keys.rid.clone());
@@ -87,7 +188,10 @@ fn mock_context_with_netdb(netdb: NetDbClient) -> Arc<Context> {
         config: RwLock::new(Config::default()),
         keys,
         ri: Arc::new(RwLock::new(ri)),
+        msg_ids: MessageIdGenerator::default(),
         netdb,
         comms: Arc::new(RwLock::new(MockCommSystem::new())),
+        pools: Pools::new(),
+        profiles: Profiles::default(),
     })
 }
@@ -1,13 +1,10 @@
-use futures::{
-    future::{self, lazy},
-    sync::mpsc,
-    Future, Sink,
-};
+use futures::{future::lazy, sync::mpsc, Future};
 use std::sync::{Arc, RwLock};
 use tokio::{io, spawn};
 
+use crate::crypto::pool::Pools;
 use crate::data::{Hash, RouterInfo, RouterSecretKeys};
-use crate::i2np::{Message, MessagePayload};
+use crate::i2np::{Message, MessageIdGenerator};
 use crate::netdb;
 use crate::tunnel;
 
@@ -15,68 +12,22 @@ type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
 mod builder;
 pub mod config;
+mod dispatcher;
 pub mod mock;
+pub mod profiles;
 pub mod types;
 
 pub use self::builder::Builder;
+pub use self::dispatcher::Dispatcher;
 use self::config::Config;
 
-pub(crate) type DistributorTx = mpsc::Sender<(Hash, Message)>;
-
-#[derive(Clone)]
-struct Distributor {
-    netdb: DistributorTx,
-    tunnel_acceptor: DistributorTx,
-    tunnel_processor: DistributorTx,
-}
-
-impl Distributor {
-    fn new(
-        netdb: DistributorTx,
-        tunnel_acceptor: DistributorTx,
-        tunnel_processor: DistributorTx,
-    ) -> Self {
-        Distributor {
-            netdb,
-            tunnel_acceptor,
-            tunnel_processor,
-        }
-    }
-}
-
-impl types::Distributor for Distributor {
-    fn handle(&self, from: Hash, msg: Message) -> types::DistributorResult {
-        match msg.payload {
-            MessagePayload::DatabaseStore(_)
-            | MessagePayload::DatabaseLookup(_)
-            | MessagePayload::DatabaseSearchReply(_) => {
-                let f: types::DistributorResult =
-                    Box::new(self.netdb.clone().send((from, msg)).map(|_| ()));
-                f
-            }
-            MessagePayload::TunnelData(_) | MessagePayload::TunnelGateway(_) => {
-                let f: types::DistributorResult =
-                    Box::new(self.tunnel_processor.clone().send((from, msg)).map(|_| ()));
-                f
-            }
-            MessagePayload::TunnelBuild(_) | MessagePayload::VariableTunnelBuild(_) => {
-                let f: types::DistributorResult =
-                    Box::new(self.tunnel_acceptor.clone().send((from, msg)).map(|_| ()));
-                f
-            }
-            _ => {
-                debug!("Dropping unhandled message from {}:\n{}", from, msg);
-                let f: types::DistributorResult = Box::new(future::ok(()));
-                f
-            }
-        }
-    }
-}
+pub type DistributorTx = mpsc::Sender<(Hash, Message)>;
 
 /// An I2P router.
 pub struct Router {
     ctx: Arc<Context>,
     netdb_engine: Option<netdb::Engine>,
+    tunnel_creator: tunnel::Creator,
     tunnel_listener: Option<tunnel::Listener>,
     tunnel_participant: Option<tunnel::Participant>,
 }
@@ -85,8 +36,11 @@ pub struct Context {
     pub config: RwLock<Config>,
     pub keys: RouterSecretKeys,
     pub ri: Arc<RwLock<RouterInfo>>,
+    pub msg_ids: MessageIdGenerator,
     pub netdb: netdb::client::Client,
     pub comms: Arc<RwLock<dyn types::CommSystem>>,
+    pub pools: Pools,
+    pub profiles: profiles::Profiles,
 }
 
 impl Router {
@@ -94,6 +48,7 @@ impl Router {
     pub fn handle(&self) -> Handle {
         Handle {
             ctx: self.ctx.clone(),
+            tunnel_creator: self.tunnel_creator.clone(),
         }
     }
 
@@ -103,6 +58,10 @@ impl Router {
     pub fn start(&mut self) -> impl Future<Item = (), Error = ()> {
         info!("Our router hash is {}", self.ctx.keys.rid.hash());
 
+        if let Err(e) = self.ctx.pools.start() {
+            warn!("Failed to start precomputation pools: {}", e);
+        }
+
         let comms_engine = self.ctx.comms.write().unwrap().start(self.ctx.clone());
         let netdb_engine = self
             .netdb_engine
@@ -119,6 +78,8 @@ impl Router {
             .take()
             .expect("Can only call start() once");
 
+        let profiles_maintainer = profiles::Maintainer::new(&self.ctx);
+
         lazy(|| {
             // Start the transport system
             spawn(comms_engine);
@@ -132,6 +93,9 @@ impl Router {
             // Start network database operations
             spawn(netdb_engine);
 
+            // Start peer profile maintenance
+            spawn(profiles_maintainer);
+
             Ok(())
         })
     }
@@ -140,6 +104,7 @@ impl Router {
 #[derive(Clone)]
 pub struct Handle {
     ctx: Arc<Context>,
+    tunnel_creator: tunnel::Creator,
 }
 
 impl Handle {
@@ -154,4 +119,15 @@ impl Handle {
     ) -> Result<IoFuture<()>, (RouterInfo, Message)> {
         self.ctx.comms.read().unwrap().send(peer, msg)
     }
+
+    /// Builds a tunnel through peers chosen from the netDb by `selector`, sending the
+    /// build along `path`. The router must have been started.
+    pub fn build_tunnel(
+        &self,
+        role: tunnel::TunnelRole,
+        selector: &tunnel::HopSelector,
+        path: tunnel::BuildPath,
+    ) -> tunnel::BuildTunnel {
+        self.tunnel_creator.build(role, selector, path)
+    }
 }
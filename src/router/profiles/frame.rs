@@ -0,0 +1,207 @@
+use cookie_factory::*;
+use nom::{
+    combinator::{map, verify},
+    number::streaming::{be_u32, be_u8},
+    sequence::{preceded, tuple},
+    IResult,
+};
+use std::collections::HashMap;
+
+use super::{ConnectStats, LookupStats, PeerProfile, TunnelBuildStats};
+use crate::data::{
+    frame::{bounded_count, gen_hash, gen_i2p_date, hash, i2p_date},
+    Hash,
+};
+
+/// Version of the profile file format.
+const PROFILES_VERSION: u8 = 2;
+
+/// Upper bound on the number of profiles in a file.
+const MAX_PROFILES: usize = 100_000;
+
+/// Size of a single serialized profile.
+const PROFILE_SIZE: usize = 32 + 5 * 8 + 13 * 4;
+
+fn connect_stats(i: &[u8]) -> IResult<&[u8], ConnectStats> {
+    map(tuple((be_u32, be_u32)), |(succeeded, failed)| {
+        ConnectStats { succeeded, failed }
+    })(i)
+}
+
+fn gen_connect_stats<'a>(
+    input: (&'a mut [u8], usize),
+    stats: &ConnectStats,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u32!(stats.succeeded) >> gen_be_u32!(stats.failed)
+    )
+}
+
+fn lookup_stats(i: &[u8]) -> IResult<&[u8], LookupStats> {
+    map(
+        tuple((be_u32, be_u32, be_u32)),
+        |(answered, timed_out, avg_response_ms)| LookupStats {
+            answered,
+            timed_out,
+            avg_response_ms,
+        },
+    )(i)
+}
+
+fn gen_lookup_stats<'a>(
+    input: (&'a mut [u8], usize),
+    stats: &LookupStats,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u32!(stats.answered)
+            >> gen_be_u32!(stats.timed_out)
+            >> gen_be_u32!(stats.avg_response_ms)
+    )
+}
+
+fn tunnel_build_stats(i: &[u8]) -> IResult<&[u8], TunnelBuildStats> {
+    map(
+        tuple((be_u32, be_u32, be_u32, be_u32)),
+        |(agreed, rejected, critical, timed_out)| TunnelBuildStats {
+            agreed,
+            rejected,
+            critical,
+            timed_out,
+        },
+    )(i)
+}
+
+fn gen_tunnel_build_stats<'a>(
+    input: (&'a mut [u8], usize),
+    stats: &TunnelBuildStats,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u32!(stats.agreed)
+            >> gen_be_u32!(stats.rejected)
+            >> gen_be_u32!(stats.critical)
+            >> gen_be_u32!(stats.timed_out)
+    )
+}
+
+fn peer_profile(i: &[u8]) -> IResult<&[u8], (Hash, PeerProfile)> {
+    map(
+        tuple((
+            hash,
+            connect_stats,
+            connect_stats,
+            lookup_stats,
+            tunnel_build_stats,
+            i2p_date,
+            i2p_date,
+            i2p_date,
+            i2p_date,
+            be_u32,
+            i2p_date,
+            be_u32,
+        )),
+        |(
+            peer,
+            ntcp,
+            ntcp2,
+            lookups,
+            tunnel_builds,
+            last_heard_from,
+            last_updated,
+            banned_until,
+            last_failed,
+            consecutive_failures,
+            last_rejected,
+            consecutive_rejections,
+        )| {
+            (
+                peer,
+                PeerProfile {
+                    ntcp,
+                    ntcp2,
+                    lookups,
+                    tunnel_builds,
+                    last_heard_from,
+                    last_updated,
+                    banned_until,
+                    last_failed,
+                    consecutive_failures,
+                    last_rejected,
+                    consecutive_rejections,
+                },
+            )
+        },
+    )(i)
+}
+
+fn gen_peer_profile<'a>(
+    input: (&'a mut [u8], usize),
+    (peer, profile): (&Hash, &PeerProfile),
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_hash(peer)
+            >> gen_connect_stats(&profile.ntcp)
+            >> gen_connect_stats(&profile.ntcp2)
+            >> gen_lookup_stats(&profile.lookups)
+            >> gen_tunnel_build_stats(&profile.tunnel_builds)
+            >> gen_i2p_date(&profile.last_heard_from)
+            >> gen_i2p_date(&profile.last_updated)
+            >> gen_i2p_date(&profile.banned_until)
+            >> gen_i2p_date(&profile.last_failed)
+            >> gen_be_u32!(profile.consecutive_failures)
+            >> gen_i2p_date(&profile.last_rejected)
+            >> gen_be_u32!(profile.consecutive_rejections)
+    )
+}
+
+pub(super) fn profiles(i: &[u8]) -> IResult<&[u8], HashMap<Hash, PeerProfile>> {
+    map(
+        preceded(
+            verify(be_u8, |version| *version == PROFILES_VERSION),
+            bounded_count(
+                map(be_u32, |n| n as usize),
+                MAX_PROFILES,
+                PROFILE_SIZE,
+                peer_profile,
+            ),
+        ),
+        |profiles| profiles.into_iter().collect(),
+    )(i)
+}
+
+pub(super) fn gen_profiles<'a>(
+    input: (&'a mut [u8], usize),
+    profiles: &HashMap<Hash, PeerProfile>,
+) -> Result<(&'a mut [u8], usize), GenError> {
+    do_gen!(
+        input,
+        gen_be_u8!(PROFILES_VERSION)
+            >> gen_be_u32!(profiles.len() as u32)
+            >> gen_many!(profiles.iter(), gen_peer_profile)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_version() {
+        let mut data = vec![0; 5 + PROFILE_SIZE];
+        data[4] = 1;
+        assert!(profiles(&data).is_err());
+        data[0] = PROFILES_VERSION;
+        let (rest, parsed) = profiles(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(parsed.len(), 1);
+    }
+
+    #[test]
+    fn too_many() {
+        let data = [PROFILES_VERSION, 0xff, 0xff, 0xff, 0xff];
+        assert!(profiles(&data).is_err());
+    }
+}
@@ -0,0 +1,684 @@
+//! Profiles of how the peers we interact with have behaved.
+//!
+//! Every interaction with a peer (connecting to it, sending it a lookup, asking
+//! it to join a tunnel) is recorded against its profile, along with when we last
+//! heard from it. The profiles are used to avoid peers that are failing, and to
+//! prefer peers that answer quickly and reliably.
+//!
+//! Profiles are written to a single file periodically, and forgotten once we
+//! have had nothing to do with a peer for a while.
+
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Delay;
+
+use super::{config, Context};
+use crate::data::{Hash, I2PDate, ReadError};
+use crate::util::{serialize, write_file};
+
+mod frame;
+
+/// How many failures in a row mark a peer as failing.
+const FAILING_THRESHOLD: u32 = 3;
+/// How long we avoid a failing peer before giving it another chance.
+const FAILING_PERIOD: u64 = 10 * 60;
+/// How long we keep a profile after our last interaction with the peer.
+const PROFILE_EXPIRATION: u64 = 3 * 24 * 60 * 60;
+/// Weight given to the newest response time in the running average, out of 8.
+const RESPONSE_TIME_WEIGHT: u64 = 2;
+/// How many rejections a critical rejection of a tunnel build counts as.
+const CRITICAL_REJECT_WEIGHT: u64 = 4;
+/// How many tunnel build rejections in a row mark a peer as rejecting.
+const REJECTING_THRESHOLD: u32 = 3;
+/// How long we avoid asking a rejecting peer to join our tunnels.
+const REJECTING_PERIOD: u64 = 5 * 60;
+
+/// Interval on which we expire profiles and write them to disk.
+const MAINTAIN_INTERVAL: u64 = 5 * 60;
+
+/// The transports over which we connect to peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Ntcp,
+    Ntcp2,
+}
+
+/// Outbound connection attempts to a peer over a single transport.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ConnectStats {
+    pub succeeded: u32,
+    pub failed: u32,
+}
+
+/// Lookups we have sent to a peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LookupStats {
+    /// Lookups answered with the entry or a DatabaseSearchReply.
+    pub answered: u32,
+    /// Lookups not answered in time.
+    pub timed_out: u32,
+    /// Running average of the time taken to answer, in milliseconds, or zero if
+    /// the peer has never answered.
+    pub avg_response_ms: u32,
+}
+
+/// Requests we have sent to a peer to join our tunnels.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TunnelBuildStats {
+    pub agreed: u32,
+    pub rejected: u32,
+    /// Rejections for a critical reason, which are also counted in `rejected`.
+    pub critical: u32,
+    pub timed_out: u32,
+}
+
+/// Everything we know about how a single peer has behaved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerProfile {
+    pub ntcp: ConnectStats,
+    pub ntcp2: ConnectStats,
+    pub lookups: LookupStats,
+    pub tunnel_builds: TunnelBuildStats,
+    /// When the peer last connected to us or sent us a message, if ever.
+    pub last_heard_from: I2PDate,
+    /// When anything was last recorded against this profile.
+    pub last_updated: I2PDate,
+    /// Until when we refuse to use the peer, if it has been banned.
+    pub banned_until: I2PDate,
+    last_failed: I2PDate,
+    consecutive_failures: u32,
+    last_rejected: I2PDate,
+    consecutive_rejections: u32,
+}
+
+impl PeerProfile {
+    fn new(now: I2PDate) -> Self {
+        PeerProfile {
+            ntcp: ConnectStats::default(),
+            ntcp2: ConnectStats::default(),
+            lookups: LookupStats::default(),
+            tunnel_builds: TunnelBuildStats::default(),
+            last_heard_from: I2PDate::UNSET,
+            last_updated: now,
+            banned_until: I2PDate::UNSET,
+            last_failed: I2PDate::UNSET,
+            consecutive_failures: 0,
+            last_rejected: I2PDate::UNSET,
+            consecutive_rejections: 0,
+        }
+    }
+
+    fn connect_stats(&mut self, transport: Transport) -> &mut ConnectStats {
+        match transport {
+            Transport::Ntcp => &mut self.ntcp,
+            Transport::Ntcp2 => &mut self.ntcp2,
+        }
+    }
+
+    fn succeeded(&mut self, now: I2PDate) {
+        self.last_heard_from = now;
+        self.consecutive_failures = 0;
+    }
+
+    fn failed(&mut self, now: I2PDate) {
+        self.last_failed = now;
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+    }
+
+    fn record_response_time(&mut self, response_time: Duration) {
+        let ms = u64::try_from(response_time.as_millis()).unwrap_or(u64::MAX);
+        let ms = ms.min(u64::from(u32::MAX));
+        let avg = u64::from(self.lookups.avg_response_ms);
+        self.lookups.avg_response_ms = if avg == 0 {
+            ms as u32
+        } else {
+            ((avg * (8 - RESPONSE_TIME_WEIGHT) + ms * RESPONSE_TIME_WEIGHT) / 8) as u32
+        };
+    }
+
+    /// Returns true if the peer has failed several times in a row, most recently
+    /// within the last few minutes.
+    pub fn is_failing(&self, now: I2PDate) -> bool {
+        self.consecutive_failures >= FAILING_THRESHOLD
+            && now
+                < self
+                    .last_failed
+                    .saturating_add(Duration::from_secs(FAILING_PERIOD))
+    }
+
+    /// Returns true if the peer has rejected several of our tunnels in a row, most
+    /// recently within the last few minutes.
+    pub fn is_rejecting(&self, now: I2PDate) -> bool {
+        self.consecutive_rejections >= REJECTING_THRESHOLD
+            && now
+                < self
+                    .last_rejected
+                    .saturating_add(Duration::from_secs(REJECTING_PERIOD))
+    }
+
+    /// Returns true if the peer has been banned until after `now`.
+    pub fn is_banned(&self, now: I2PDate) -> bool {
+        now < self.banned_until
+    }
+
+    /// Returns a score between 0 and 1 for how well the peer could carry our
+    /// traffic, or 0 if it is failing.
+    ///
+    /// The score is the product of how reliably the peer responds to us, how
+    /// often it agrees to join our tunnels, and how quickly it answers lookups.
+    /// Each factor starts at 0.5 for a peer we know nothing about. Critical
+    /// rejections count as [`CRITICAL_REJECT_WEIGHT`] rejections.
+    pub fn capacity_score(&self, now: I2PDate) -> f64 {
+        if self.is_failing(now) {
+            return 0.0;
+        }
+
+        let responded = u64::from(self.ntcp.succeeded)
+            + u64::from(self.ntcp2.succeeded)
+            + u64::from(self.lookups.answered)
+            + u64::from(self.tunnel_builds.agreed)
+            + u64::from(self.tunnel_builds.rejected);
+        let failed = u64::from(self.ntcp.failed)
+            + u64::from(self.ntcp2.failed)
+            + u64::from(self.lookups.timed_out)
+            + u64::from(self.tunnel_builds.timed_out);
+        let reliability = ratio(responded, failed);
+
+        let acceptance = ratio(
+            u64::from(self.tunnel_builds.agreed),
+            u64::from(self.tunnel_builds.rejected)
+                + u64::from(self.tunnel_builds.critical) * (CRITICAL_REJECT_WEIGHT - 1),
+        );
+
+        let speed = if self.lookups.avg_response_ms == 0 {
+            0.5
+        } else {
+            1000.0 / (1000.0 + f64::from(self.lookups.avg_response_ms))
+        };
+
+        reliability * acceptance * speed
+    }
+}
+
+/// Returns the fraction of events that were good, starting from an even split.
+fn ratio(good: u64, bad: u64) -> f64 {
+    (good as f64 + 1.0) / ((good + bad) as f64 + 2.0)
+}
+
+/// The profiles of every peer we have interacted with recently.
+///
+/// This is a handle that can be cloned and shared between subsystems.
+#[derive(Clone, Default)]
+pub struct Profiles(Arc<Mutex<HashMap<Hash, PeerProfile>>>);
+
+impl Profiles {
+    /// Reads the profiles previously written to `path`.
+    pub fn read(path: &Path) -> Result<Self, ReadError> {
+        Profiles::from_bytes(&fs::read(path)?)
+    }
+
+    /// Atomically writes the profiles to `path`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        write_file(path, &self.to_bytes())
+    }
+
+    fn from_bytes(data: &[u8]) -> Result<Self, ReadError> {
+        let (rest, profiles) = frame::profiles(data)?;
+        if !rest.is_empty() {
+            return Err(ReadError::TrailingData(rest.len()));
+        }
+        Ok(Profiles(Arc::new(Mutex::new(profiles))))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let profiles = self.0.lock().unwrap();
+        serialize(|input| frame::gen_profiles(input, &profiles))
+    }
+
+    /// Returns the number of peers we have a profile for.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a copy of the profile for `peer`, if we have one.
+    pub fn get(&self, peer: &Hash) -> Option<PeerProfile> {
+        self.0.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Returns true if `peer` has failed several times in a row recently.
+    pub fn is_failing(&self, peer: &Hash, now: SystemTime) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|profile| profile.is_failing(I2PDate::from_system_time(now)))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if `peer` has rejected several of our tunnels in a row recently.
+    pub fn is_rejecting(&self, peer: &Hash, now: SystemTime) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|profile| profile.is_rejecting(I2PDate::from_system_time(now)))
+            .unwrap_or(false)
+    }
+
+    /// Returns true if `peer` has been banned until after `now`.
+    pub fn is_banned(&self, peer: &Hash, now: SystemTime) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(peer)
+            .map(|profile| profile.is_banned(I2PDate::from_system_time(now)))
+            .unwrap_or(false)
+    }
+
+    /// Returns the peers that are failing or banned as of `now`.
+    pub fn avoided(&self, now: SystemTime) -> Vec<Hash> {
+        let now = I2PDate::from_system_time(now);
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, profile)| profile.is_failing(now) || profile.is_banned(now))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    /// Returns a score between 0 and 1 for how well `peer` could carry our
+    /// traffic. See [`PeerProfile::capacity_score`].
+    pub fn capacity_score(&self, peer: &Hash, now: SystemTime) -> f64 {
+        let now = I2PDate::from_system_time(now);
+        match self.0.lock().unwrap().get(peer) {
+            Some(profile) => profile.capacity_score(now),
+            None => PeerProfile::new(now).capacity_score(now),
+        }
+    }
+
+    fn update<F>(&self, peer: &Hash, now: SystemTime, f: F)
+    where
+        F: FnOnce(&mut PeerProfile, I2PDate),
+    {
+        let now = I2PDate::from_system_time(now);
+        let mut profiles = self.0.lock().unwrap();
+        let profile = profiles
+            .entry(peer.clone())
+            .or_insert_with(|| PeerProfile::new(now));
+        profile.last_updated = now;
+        f(profile, now);
+    }
+
+    /// Records that `peer` connected to us or sent us a message.
+    pub fn heard_from(&self, peer: &Hash, now: SystemTime) {
+        self.update(peer, now, |profile, now| profile.last_heard_from = now);
+    }
+
+    /// Records that we connected to `peer` over `transport`.
+    pub fn connected(&self, peer: &Hash, transport: Transport, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            let stats = profile.connect_stats(transport);
+            stats.succeeded = stats.succeeded.saturating_add(1);
+            profile.succeeded(now);
+        });
+    }
+
+    /// Records that we failed to connect to `peer` over `transport`.
+    pub fn connect_failed(&self, peer: &Hash, transport: Transport, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            let stats = profile.connect_stats(transport);
+            stats.failed = stats.failed.saturating_add(1);
+            profile.failed(now);
+        });
+    }
+
+    /// Records that `peer` answered a lookup after `response_time`.
+    pub fn lookup_answered(&self, peer: &Hash, response_time: Duration, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            profile.lookups.answered = profile.lookups.answered.saturating_add(1);
+            profile.record_response_time(response_time);
+            profile.succeeded(now);
+        });
+    }
+
+    /// Records that `peer` did not answer a lookup in time.
+    pub fn lookup_timed_out(&self, peer: &Hash, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            profile.lookups.timed_out = profile.lookups.timed_out.saturating_add(1);
+            profile.failed(now);
+        });
+    }
+
+    /// Records that `peer` agreed to join one of our tunnels.
+    pub fn tunnel_build_agreed(&self, peer: &Hash, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            profile.tunnel_builds.agreed = profile.tunnel_builds.agreed.saturating_add(1);
+            profile.consecutive_rejections = 0;
+            profile.succeeded(now);
+        });
+    }
+
+    /// Records that `peer` refused to join one of our tunnels, for a critical reason
+    /// if `critical` is true.
+    ///
+    /// The peer still answered us, so this doesn't count towards it failing, but we
+    /// stop asking it for a while if it keeps refusing.
+    pub fn tunnel_build_rejected(&self, peer: &Hash, critical: bool, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            let stats = &mut profile.tunnel_builds;
+            stats.rejected = stats.rejected.saturating_add(1);
+            if critical {
+                stats.critical = stats.critical.saturating_add(1);
+            }
+            profile.last_rejected = now;
+            profile.consecutive_rejections = profile.consecutive_rejections.saturating_add(1);
+            profile.succeeded(now);
+        });
+    }
+
+    /// Records that `peer` did not answer a request to join one of our tunnels.
+    pub fn tunnel_build_timed_out(&self, peer: &Hash, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            profile.tunnel_builds.timed_out = profile.tunnel_builds.timed_out.saturating_add(1);
+            profile.failed(now);
+        });
+    }
+
+    /// Records that a test of one of our tunnels that `peer` is in succeeded.
+    ///
+    /// Tunnel tests aren't kept in the profile's counters, but a success clears the
+    /// peer's failures.
+    pub fn tunnel_test_succeeded(&self, peer: &Hash, now: SystemTime) {
+        self.update(peer, now, |profile, _| profile.consecutive_failures = 0);
+    }
+
+    /// Records that a test of one of our tunnels that `peer` is in failed. We can't
+    /// tell which hop lost the test message, so every hop is blamed.
+    pub fn tunnel_test_failed(&self, peer: &Hash, now: SystemTime) {
+        self.update(peer, now, |profile, now| profile.failed(now));
+    }
+
+    /// Refuses to use `peer` for the next `duration`.
+    pub fn ban(&self, peer: &Hash, duration: Duration, now: SystemTime) {
+        self.update(peer, now, |profile, now| {
+            profile.banned_until = now.saturating_add(duration);
+        });
+    }
+
+    /// Forgets the profiles of peers we have had nothing to do with for a while,
+    /// returning how many were forgotten.
+    pub fn expire(&self, now: SystemTime) -> usize {
+        let now = I2PDate::from_system_time(now);
+        let mut profiles = self.0.lock().unwrap();
+        let before = profiles.len();
+        profiles.retain(|_, profile| {
+            now < profile
+                .last_updated
+                .saturating_add(Duration::from_secs(PROFILE_EXPIRATION))
+        });
+        before - profiles.len()
+    }
+}
+
+/// Periodically expires profiles, and writes them to disk if a profiles file is
+/// configured.
+pub struct Maintainer {
+    profiles: Profiles,
+    file: Option<PathBuf>,
+    timer: Delay,
+}
+
+impl Maintainer {
+    pub fn new(ctx: &Context) -> Self {
+        let file = ctx
+            .config
+            .read()
+            .unwrap()
+            .get_str(config::PROFILES_FILE)
+            .ok()
+            .map(PathBuf::from);
+        Maintainer {
+            profiles: ctx.profiles.clone(),
+            file,
+            timer: Delay::new(Instant::now() + Duration::from_secs(MAINTAIN_INTERVAL)),
+        }
+    }
+}
+
+impl Future for Maintainer {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        while let Async::Ready(()) = self.timer.poll().map_err(|_| ())? {
+            let expired = self.profiles.expire(SystemTime::now());
+            debug!(
+                "Expired {} peer profiles, {} remaining",
+                expired,
+                self.profiles.len()
+            );
+
+            if let Some(file) = &self.file {
+                if let Err(e) = self.profiles.write(file) {
+                    warn!("Failed to write peer profiles to {}: {}", file.display(), e);
+                }
+            }
+
+            self.timer = Delay::new(Instant::now() + Duration::from_secs(MAINTAIN_INTERVAL));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use tempfile::tempdir;
+
+    use super::{
+        ConnectStats, Profiles, Transport, FAILING_PERIOD, FAILING_THRESHOLD, PROFILE_EXPIRATION,
+        REJECTING_PERIOD, REJECTING_THRESHOLD,
+    };
+    use crate::data::{Hash, ReadError};
+
+    #[test]
+    fn score_movement() {
+        let peer = Hash([1; 32]);
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+
+        let unknown = profiles.capacity_score(&peer, now);
+        assert!(unknown > 0.0 && unknown < 1.0);
+
+        // Answering quickly and agreeing to tunnels raises the score
+        profiles.connected(&peer, Transport::Ntcp2, now);
+        profiles.lookup_answered(&peer, Duration::from_millis(200), now);
+        profiles.tunnel_build_agreed(&peer, now);
+        let good = profiles.capacity_score(&peer, now);
+        assert!(good > unknown);
+
+        // Rejections lower it, but a peer that answers isn't failing
+        for _ in 0..FAILING_THRESHOLD {
+            profiles.tunnel_build_rejected(&peer, false, now);
+        }
+        let busy = profiles.capacity_score(&peer, now);
+        assert!(busy < good);
+        assert!(!profiles.is_failing(&peer, now));
+
+        // Slow answers lower it
+        for _ in 0..10 {
+            profiles.lookup_answered(&peer, Duration::from_secs(5), now);
+        }
+        let slow = profiles.capacity_score(&peer, now);
+        assert!(slow < busy);
+
+        // Timeouts lower it
+        profiles.lookup_timed_out(&peer, now);
+        let flaky = profiles.capacity_score(&peer, now);
+        assert!(flaky < slow);
+        assert!(flaky > 0.0);
+
+        assert_eq!(
+            profiles.get(&peer).unwrap().ntcp2,
+            ConnectStats {
+                succeeded: 1,
+                failed: 0,
+            }
+        );
+        assert!(!profiles.get(&peer).unwrap().last_heard_from.is_unset());
+    }
+
+    #[test]
+    fn failing() {
+        let peer = Hash([1; 32]);
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+        assert!(!profiles.is_failing(&peer, now));
+
+        // Failures in a row mark the peer as failing
+        profiles.connect_failed(&peer, Transport::Ntcp, now);
+        profiles.connect_failed(&peer, Transport::Ntcp2, now);
+        assert!(!profiles.is_failing(&peer, now));
+        profiles.tunnel_build_timed_out(&peer, now);
+        assert!(profiles.is_failing(&peer, now));
+        assert_eq!(profiles.capacity_score(&peer, now), 0.0);
+
+        // After a while we give it another chance
+        let later = now + Duration::from_secs(FAILING_PERIOD);
+        assert!(!profiles.is_failing(&peer, later));
+        assert!(profiles.capacity_score(&peer, later) > 0.0);
+
+        // A single success clears the failures
+        profiles.connect_failed(&peer, Transport::Ntcp, now);
+        assert!(profiles.is_failing(&peer, now));
+        profiles.connected(&peer, Transport::Ntcp, now);
+        assert!(!profiles.is_failing(&peer, now));
+
+        // Tunnel tests count too
+        for _ in 0..FAILING_THRESHOLD {
+            profiles.tunnel_test_failed(&peer, now);
+        }
+        assert!(profiles.is_failing(&peer, now));
+        profiles.tunnel_test_succeeded(&peer, now);
+        assert!(!profiles.is_failing(&peer, now));
+    }
+
+    #[test]
+    fn rejections() {
+        let (bandwidth, critical) = (Hash([1; 32]), Hash([2; 32]));
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+
+        // Critical rejections lower the score more than other rejections
+        profiles.tunnel_build_rejected(&bandwidth, false, now);
+        profiles.tunnel_build_rejected(&critical, true, now);
+        assert!(profiles.capacity_score(&critical, now) < profiles.capacity_score(&bandwidth, now));
+        let stats = profiles.get(&critical).unwrap().tunnel_builds;
+        assert_eq!((stats.rejected, stats.critical), (1, 1));
+
+        // Rejections in a row mark the peer as rejecting for a while, but not failing
+        for _ in 1..REJECTING_THRESHOLD {
+            assert!(!profiles.is_rejecting(&bandwidth, now));
+            profiles.tunnel_build_rejected(&bandwidth, false, now);
+        }
+        assert!(profiles.is_rejecting(&bandwidth, now));
+        assert!(!profiles.is_failing(&bandwidth, now));
+        assert!(profiles.avoided(now).is_empty());
+        let later = now + Duration::from_secs(REJECTING_PERIOD);
+        assert!(!profiles.is_rejecting(&bandwidth, later));
+
+        // Agreeing to a tunnel clears the rejections, but other successes don't
+        profiles.lookup_answered(&bandwidth, Duration::from_millis(100), now);
+        assert!(profiles.is_rejecting(&bandwidth, now));
+        profiles.tunnel_build_agreed(&bandwidth, now);
+        assert!(!profiles.is_rejecting(&bandwidth, now));
+    }
+
+    #[test]
+    fn banned() {
+        let peer = Hash([1; 32]);
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+        assert!(!profiles.is_banned(&peer, now));
+
+        profiles.ban(&peer, Duration::from_secs(60), now);
+        assert!(profiles.is_banned(&peer, now));
+        assert!(!profiles.is_failing(&peer, now));
+        assert_eq!(profiles.avoided(now), vec![peer.clone()]);
+
+        let later = now + Duration::from_secs(60);
+        assert!(!profiles.is_banned(&peer, later));
+        assert!(profiles.avoided(later).is_empty());
+    }
+
+    #[test]
+    fn expiry() {
+        let old = Hash([1; 32]);
+        let recent = Hash([2; 32]);
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(PROFILE_EXPIRATION);
+        let profiles = Profiles::default();
+
+        profiles.lookup_timed_out(&old, now);
+        profiles.heard_from(&recent, now);
+        profiles.heard_from(&recent, later - Duration::from_secs(1));
+
+        assert_eq!(profiles.expire(now), 0);
+        assert_eq!(profiles.expire(later), 1);
+        assert!(profiles.get(&old).is_none());
+        assert!(profiles.get(&recent).is_some());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("profiles.dat");
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+
+        for i in 0..10 {
+            let peer = Hash([i; 32]);
+            profiles.connected(&peer, Transport::Ntcp, now);
+            profiles.connect_failed(&peer, Transport::Ntcp2, now);
+            profiles.lookup_answered(&peer, Duration::from_millis(u64::from(i) * 100), now);
+            profiles.lookup_timed_out(&peer, now);
+            profiles.tunnel_build_agreed(&peer, now);
+            profiles.tunnel_build_rejected(&peer, i % 2 == 0, now);
+            profiles.tunnel_build_timed_out(&peer, now);
+            profiles.ban(&peer, Duration::from_secs(60), now);
+        }
+        profiles.write(&path).unwrap();
+
+        let read = Profiles::read(&path).unwrap();
+        assert_eq!(read.len(), 10);
+        for i in 0..10 {
+            let peer = Hash([i; 32]);
+            assert_eq!(read.get(&peer), profiles.get(&peer));
+            assert_eq!(
+                read.capacity_score(&peer, now),
+                profiles.capacity_score(&peer, now)
+            );
+        }
+
+        // Truncated or extended files are rejected
+        let data = profiles.to_bytes();
+        assert!(Profiles::from_bytes(&data[..data.len() - 1]).is_err());
+        let mut extended = data.clone();
+        extended.push(0);
+        assert_eq!(
+            Profiles::from_bytes(&extended).err(),
+            Some(ReadError::TrailingData(1))
+        );
+    }
+}
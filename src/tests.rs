@@ -1,5 +1,15 @@
 pub const ROUTER_INFO: &[u8; 670] = include_bytes!("../assets/router.info");
+pub const ROUTER_KEYS: &[u8; 679] = include_bytes!("../assets/router.keys.dat");
+// Java I2P no longer creates DSA identities, so this was generated with an
+// independent implementation (Python's cryptography library).
+pub const RI_SIGTYPE_0: &[u8; 483] = include_bytes!("../assets/sigType-0.router.info");
 pub const RI_SIGTYPE_1: &[u8; 746] = include_bytes!("../assets/sigType-1.router.info");
 pub const RI_SIGTYPE_2: &[u8; 778] = include_bytes!("../assets/sigType-2.router.info");
+// There are no P-521 or RedDSA routers on the network, so these were generated
+// with an independent implementation (Python's cryptography library).
+pub const RI_SIGTYPE_3: &[u8; 583] = include_bytes!("../assets/sigType-3.router.info");
+pub const RI_SIGTYPE_11: &[u8; 511] = include_bytes!("../assets/sigType-11.router.info");
 
 pub const I2PSEEDS_SU3: &[u8; 71025] = include_bytes!("../assets/i2pseeds.su3");
+// A news feed signed with the Ed25519 key in test-ed25519-signer.der
+pub const TEST_ED25519_SU3: &[u8; 186] = include_bytes!("../assets/test-ed25519.su3");
@@ -1,3 +1,4 @@
+use bytes::{Bytes, BytesMut};
 use cookie_factory::*;
 use nom::{
     bytes::streaming::{tag, take},
@@ -7,9 +8,10 @@ use nom::{
     sequence::{pair, terminated},
     IResult,
 };
+use std::iter::repeat;
 
 use super::Frame;
-use crate::i2np::frame::{gen_message, message};
+use crate::i2np::frame::{gen_message, message, message_bytes};
 use crate::i2np::Message;
 
 //
@@ -115,7 +117,44 @@ fn gen_timestamp_frame(
     )
 }
 
+/// Returns the serialized length of the given frame.
+pub fn frame_len(frame: &Frame) -> usize {
+    match *frame {
+        Frame::Standard(ref msg) => {
+            let size = msg.size();
+            2 + size + padding_len(size + 6) + 4
+        }
+        Frame::TimeSync(_) => 16,
+    }
+}
+
+/// Returns the serialized length of the frame at the start of `i`, using only its
+/// size field.
+pub fn peek_frame_len(i: &[u8]) -> IResult<&[u8], usize> {
+    map(peek(be_u16), |sz| match sz {
+        0 => 16,
+        size => {
+            let size = size as usize;
+            2 + size + padding_len(size + 6) + 4
+        }
+    })(i)
+}
+
 pub fn frame(i: &[u8]) -> IResult<&[u8], Frame> {
+    frame_in(None, i)
+}
+
+/// Parses a frame from `buf`, sharing `buf` with any opaque I2NP payloads instead of
+/// copying them.
+pub fn frame_bytes(buf: &Bytes) -> IResult<&[u8], Frame> {
+    frame_in(Some(buf), buf)
+}
+
+fn frame_in<'a>(buf: Option<&Bytes>, i: &'a [u8]) -> IResult<&'a [u8], Frame> {
+    let msg = |i: &'a [u8]| match buf {
+        Some(buf) => message_bytes(buf, i),
+        None => message(i),
+    };
     let (i, (cs, sz)) = pair(get_adler, be_u16)(i)?;
     match sz {
         0 => map(
@@ -124,7 +163,7 @@ pub fn frame(i: &[u8]) -> IResult<&[u8], Frame> {
         )(i),
         size => map(
             terminated(
-                length_value(success(size), message),
+                length_value(success(size), msg),
                 pair(padding((size + 6) as usize), tag(cs)),
             ),
             Frame::Standard,
@@ -142,6 +181,38 @@ pub fn gen_frame<'a>(
     }
 }
 
+/// Serializes the given frame, appending it to `buf`. The I2NP message in a standard
+/// frame is written directly into `buf`.
+pub fn write_frame(frame: &Frame, buf: &mut BytesMut) -> Result<(), GenError> {
+    let start = buf.len();
+    buf.reserve(frame_len(frame));
+    match *frame {
+        Frame::Standard(ref msg) => {
+            buf.extend_from_slice(&[0; 2]);
+            if let Err(e) = msg.write_to(buf) {
+                buf.truncate(start);
+                return Err(e);
+            }
+            let size = buf.len() - start - 2;
+            buf[start] = (size >> 8) as u8;
+            buf[start + 1] = (size & 0xff) as u8;
+            buf.extend(repeat(0).take(padding_len(size + 6)));
+            let cs = adler(&buf[start..]);
+            buf.extend_from_slice(&cs);
+            Ok(())
+        }
+        Frame::TimeSync(ts) => {
+            buf.extend(repeat(0).take(16));
+            gen_timestamp_frame((&mut buf[start..], 0), ts)
+                .map(|_| ())
+                .map_err(|e| {
+                    buf.truncate(start);
+                    e
+                })
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use nom::Err;
@@ -281,4 +352,58 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn frame_len_exact() {
+        for &msg_type in crate::i2np::fixtures::MESSAGE_TYPES.iter() {
+            let frame = Frame::Standard(crate::i2np::fixtures::message(msg_type));
+            let len = frame_len(&frame);
+            assert_eq!(len % 16, 0);
+
+            let mut buf = vec![0u8; len];
+            match gen_frame((&mut buf[..], 0), &frame).map(|tup| tup.1) {
+                Ok(sz) => assert_eq!(sz, len),
+                Err(e) => panic!("error in gen_frame: {:?}", e),
+            }
+        }
+
+        assert_eq!(frame_len(&Frame::TimeSync(12_345_678)), 16);
+    }
+
+    #[test]
+    fn write_frame_matches_gen_frame() {
+        let frames = crate::i2np::fixtures::MESSAGE_TYPES
+            .iter()
+            .map(|&msg_type| Frame::Standard(crate::i2np::fixtures::message(msg_type)))
+            .chain(Some(Frame::TimeSync(12_345_678)));
+        for frame in frames {
+            let mut expected = vec![0u8; frame_len(&frame)];
+            gen_frame((&mut expected[..], 0), &frame).unwrap();
+
+            // Frames are appended after existing data
+            let mut buf = BytesMut::from(&b"prefix"[..]);
+            write_frame(&frame, &mut buf).unwrap();
+            assert_eq!(&buf[..6], b"prefix");
+            assert_eq!(&buf[6..], &expected[..]);
+        }
+    }
+
+    #[test]
+    fn frame_invalid_checksum() {
+        let frame = Frame::Standard(crate::i2np::fixtures::data());
+        let len = frame_len(&frame);
+        let mut buf = vec![0u8; len];
+        gen_frame((&mut buf[..], 0), &frame).unwrap();
+
+        // Corrupt the I2NP checksum, and fix up the Adler checksum over the frame
+        buf[2 + 15] ^= 0xff;
+        let cs = adler(&buf[..len - 4]);
+        buf[len - 4..].copy_from_slice(&cs);
+
+        match frame(&buf) {
+            Err(Err::Error(e)) => assert_eq!(e.code, nom::error::ErrorKind::Verify),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Accepted a frame with an invalid I2NP checksum"),
+        }
+    }
 }
@@ -11,7 +11,7 @@ use tokio::{
 };
 
 use super::{Codec, NTCP_MTU};
-use crate::crypto::{Aes256, Signature, SigningPrivateKey, AES_BLOCK_SIZE};
+use crate::crypto::{math::ct_eq, Aes256, Signature, SigningPrivateKey, AES_BLOCK_SIZE};
 use crate::data::{Hash, RouterIdentity};
 use crate::transport::DHSessionKeyBuilder;
 use crate::util::serialize;
@@ -549,9 +549,12 @@ where
     T: AsyncRead + AsyncWrite,
     T: Send + 'static,
 {
-    pub fn new(stream: T, own_ri: RouterIdentity, own_key: SigningPrivateKey) -> Self {
-        // Generate a new DH pair
-        let dh_key_builder = DHSessionKeyBuilder::new();
+    pub fn new(
+        stream: T,
+        own_ri: RouterIdentity,
+        own_key: SigningPrivateKey,
+        dh_key_builder: DHSessionKeyBuilder,
+    ) -> Self {
         let dh_y = dh_key_builder.get_pub();
         let mut iv_enc = [0u8; AES_BLOCK_SIZE];
         iv_enc.copy_from_slice(&dh_y[dh_y.len() - AES_BLOCK_SIZE..]);
@@ -602,7 +605,7 @@ where
                     // that the X isn't corrupt
                     let mut hxxorhb = Hash::digest(&sr.dh_x[..]);
                     hxxorhb.xor(&self.shared.own_ri.hash());
-                    if hxxorhb != sr.hash {
+                    if !ct_eq(&hxxorhb.0, &sr.hash.0) {
                         return Err(io::Error::new(
                             io::ErrorKind::InvalidData,
                             "Invalid SessionRequest HXxorHB",
@@ -721,9 +724,8 @@ where
         own_ri: RouterIdentity,
         own_key: SigningPrivateKey,
         ri_remote: RouterIdentity,
+        dh_key_builder: DHSessionKeyBuilder,
     ) -> Self {
-        // Generate a new DH pair
-        let dh_key_builder = DHSessionKeyBuilder::new();
         let dh_x = dh_key_builder.get_pub();
         let mut hxxorhb = Hash::digest(&dh_x[..]);
         hxxorhb.xor(&ri_remote.hash());
@@ -800,7 +802,7 @@ where
                     let msg = gen_session_confirm_sig_msg(&self.shared, false);
                     // Check part 2 (which happens to be hash of first part of signed message)
                     let hxy = Hash::digest(&msg[..512]);
-                    if hxy != sc.hash {
+                    if !ct_eq(&hxy.0, &sc.hash.0) {
                         return Err(io::Error::new(
                             io::ErrorKind::InvalidData,
                             "Invalid SessionCreated hash",
@@ -862,7 +864,9 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState};
+    use super::{
+        DHSessionKeyBuilder, IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState,
+    };
     use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
 
     use futures::{Async, Future};
@@ -906,8 +910,14 @@ mod tests {
         let bob_net = BobNet::new(cable);
 
         // Set up the handshake
-        let mut alice = OBHandshake::new(alice_net, alice_rid, alice_sk, bob_rid.clone());
-        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk);
+        let mut alice = OBHandshake::new(
+            alice_net,
+            alice_rid,
+            alice_sk,
+            bob_rid.clone(),
+            DHSessionKeyBuilder::new(),
+        );
+        let mut bob = IBHandshake::new(bob_net, bob_rid, bob_sk, DHSessionKeyBuilder::new());
         test_state!(alice, SessionRequest, bob, SessionRequest);
 
         // Alice -> SessionRequest
@@ -9,11 +9,10 @@ use futures::{
     sync::mpsc,
     try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
 };
-use nom::{Err, Offset};
-use std::iter::repeat;
+use nom::{error::ErrorKind, Err};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::{
     codec::{Decoder, Encoder, Framed},
     io::{self, AsyncRead, AsyncWrite},
@@ -26,10 +25,11 @@ use super::{
     session::{self, SessionContext, SessionManager, SessionRefs, SessionRx},
     Bid, Transport,
 };
-use crate::crypto::{Aes256, SigningPrivateKey};
+use crate::crypto::{dh::DHSessionKeyBuilder, pool::Precomputed, Aes256, SigningPrivateKey};
 use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo};
 use crate::i2np::Message;
 use crate::router::{
+    profiles::Transport as ProfileTransport,
     types::{Distributor, DistributorResult},
     Context,
 };
@@ -87,21 +87,37 @@ impl Decoder for Codec {
             }
         };
 
-        // Parse a frame
-        let (consumed, f) = match frame::frame(&buf[0..self.decrypted]) {
-            Err(Err::Incomplete(_)) => return Ok(None),
+        // Wait for the rest of the frame
+        let frame_len = match frame::peek_frame_len(&buf[0..self.decrypted]) {
+            Ok((_, frame_len)) if frame_len <= self.decrypted => frame_len,
+            _ => return Ok(None),
+        };
+        let data = buf.split_to(frame_len).freeze();
+        self.decrypted -= frame_len;
+
+        // Parse the frame, sharing its buffer with the message it contains
+        let f = match frame::frame_bytes(&data) {
+            Err(Err::Incomplete(n)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("truncated I2NP message, needed: {:?}", n),
+                ));
+            }
+            Err(Err::Error(e)) | Err(Err::Failure(e)) if e.code == ErrorKind::Verify => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid I2NP message checksum",
+                ));
+            }
             Err(Err::Error(e)) | Err(Err::Failure(e)) => {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
                     format!("parse error: {:?}", e),
                 ));
             }
-            Ok((i, frame)) => (buf.offset(i), frame),
+            Ok((_, frame)) => frame,
         };
 
-        buf.split_to(consumed);
-        self.decrypted -= consumed;
-
         Ok(Some(f))
     }
 }
@@ -112,32 +128,35 @@ impl Encoder for Codec {
 
     fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
         let start = buf.len();
-        buf.extend(repeat(0).take(NTCP_MTU));
-
-        match frame::gen_frame((buf, start), &frame).map(|tup| tup.1) {
-            Ok(sz) => {
-                buf.truncate(sz);
-                // Encrypt message in-place
-                match self.aes.encrypt_blocks(&mut buf[start..]) {
-                    Some(end) if start + end == sz => Ok(()),
-                    _ => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "invalid serialization",
-                    )),
-                }
-            }
-            Err(e) => match e {
-                GenError::BufferTooSmall(sz) => Err(io::Error::new(
+        let frame_len = frame::frame_len(&frame);
+        if frame_len > NTCP_MTU {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message ({}) larger than MTU ({})", frame_len, NTCP_MTU),
+            ));
+        }
+
+        if let Err(e) = frame::write_frame(&frame, buf) {
+            return Err(match e {
+                GenError::BufferTooSmall(sz) => io::Error::new(
                     io::ErrorKind::InvalidData,
-                    format!("message ({}) larger than MTU ({})", sz - start, NTCP_MTU),
-                )),
+                    format!("message ({}) larger than MTU ({})", sz, NTCP_MTU),
+                ),
                 GenError::InvalidOffset
                 | GenError::CustomError(_)
-                | GenError::NotYetImplemented => Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "could not generate",
-                )),
-            },
+                | GenError::NotYetImplemented => {
+                    io::Error::new(io::ErrorKind::InvalidData, "could not generate")
+                }
+            });
+        }
+
+        // Encrypt message in-place
+        match self.aes.encrypt_blocks(&mut buf[start..]) {
+            Some(end) if start + end == buf.len() => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid serialization",
+            )),
         }
     }
 }
@@ -374,6 +393,15 @@ impl<D: Distributor> Manager<D> {
         }
     }
 
+    fn dh_pool(&self) -> &Precomputed<DHSessionKeyBuilder> {
+        &self
+            .ctx
+            .as_ref()
+            .expect("Should have called set_context()")
+            .pools
+            .dh
+    }
+
     pub fn address(&self) -> RouterAddress {
         RouterAddress::new(&NTCP_STYLE, self.addr)
     }
@@ -387,6 +415,7 @@ impl<D: Distributor> Manager<D> {
 
         // Bind to the address
         let listener = TcpListener::bind(&self.addr).unwrap();
+        let dh_pool = self.dh_pool().clone();
 
         // Give each incoming connection the references it needs
         let session_refs = self.session_manager.refs();
@@ -396,7 +425,8 @@ impl<D: Distributor> Manager<D> {
         conns.for_each(move |(conn, session_refs)| {
             info!("Incoming connection!");
             // Execute the handshake
-            let conn = handshake::IBHandshake::new(conn, own_ri.clone(), own_key.clone());
+            let conn =
+                handshake::IBHandshake::new(conn, own_ri.clone(), own_key.clone(), dh_pool.take());
 
             // Once connected:
             let process_conn = conn.and_then(|(ri, conn)| Session::new(ri, conn, session_refs));
@@ -413,7 +443,13 @@ impl<D: Distributor> Manager<D> {
         own_key: SigningPrivateKey,
         peer_ri: RouterInfo,
     ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
-        connect(own_ri, own_key, peer_ri, self.session_manager.refs())
+        connect(
+            own_ri,
+            own_key,
+            peer_ri,
+            self.dh_pool().take(),
+            self.session_manager.refs(),
+        )
     }
 }
 
@@ -421,6 +457,7 @@ fn connect<D: Distributor>(
     own_ri: RouterIdentity,
     own_key: SigningPrivateKey,
     peer_ri: RouterInfo,
+    dh_key_builder: DHSessionKeyBuilder,
     session_refs: SessionRefs<Frame, D>,
 ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
     let addr = match peer_ri.address(&NTCP_STYLE, |_| true) {
@@ -434,8 +471,9 @@ fn connect<D: Distributor>(
     };
 
     // Connect to the peer
-    let conn = TcpStream::connect(&addr)
-        .and_then(|socket| handshake::OBHandshake::new(socket, own_ri, own_key, peer_ri.router_id));
+    let conn = TcpStream::connect(&addr).and_then(|socket| {
+        handshake::OBHandshake::new(socket, own_ri, own_key, peer_ri.router_id, dh_key_builder)
+    });
 
     // Add a timeout
     let timed = Timeout::new(conn, Duration::new(10, 0))
@@ -495,11 +533,29 @@ impl<D: Distributor> Sink for OutboundSink<D> {
                 let own_rid = self.ctx.keys.rid.clone();
                 let own_key = self.ctx.keys.signing_private_key.clone();
                 let peer = peer.clone();
+                let hash = peer.router_id.hash();
+                let dh_key_builder = self.ctx.pools.dh.take();
                 let session_refs = session_refs.clone();
-                match connect(own_rid, own_key, peer, session_refs) {
+                match connect(own_rid, own_key, peer, dh_key_builder, session_refs) {
                     Ok(f) => {
-                        spawn(f.map_err(|e| {
-                            error!("Error while connecting: {}", e);
+                        let profiles = self.ctx.profiles.clone();
+                        spawn(f.then(move |res| -> Result<(), ()> {
+                            match res {
+                                Ok(()) => profiles.connected(
+                                    &hash,
+                                    ProfileTransport::Ntcp,
+                                    SystemTime::now(),
+                                ),
+                                Err(e) => {
+                                    error!("Error while connecting: {}", e);
+                                    profiles.connect_failed(
+                                        &hash,
+                                        ProfileTransport::Ntcp,
+                                        SystemTime::now(),
+                                    );
+                                }
+                            }
+                            Ok(())
                         }));
                     }
                     Err(e) => error!("{}", e),
@@ -525,7 +581,7 @@ impl<D: Distributor> Sink for OutboundSink<D> {
 mod tests {
     use bytes::BytesMut;
     use cookie_factory::GenError;
-    use futures::{lazy, Future, Sink};
+    use futures::{lazy, sync::mpsc, Async, Future, Sink, Stream};
     use nom::{Err, Offset};
     use std::iter::repeat;
     use tokio::{
@@ -534,8 +590,9 @@ mod tests {
     };
 
     use super::{frame, Frame, Manager, Session, NTCP_MTU};
-    use crate::i2np::Message;
+    use crate::i2np::{Message, MessageType};
     use crate::router::mock::{mock_context, MockDistributor};
+    use crate::router::Dispatcher;
     use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
 
     struct TestCodec;
@@ -683,4 +740,48 @@ mod tests {
         .wait()
         .unwrap();
     }
+
+    #[test]
+    fn session_receive_into_dispatcher() {
+        let ctx = mock_context();
+        let rid = ctx.keys.rid.clone();
+        let hash = rid.hash();
+
+        let cable = NetworkCable::new();
+        let bob_net = BobNet::new(cable.clone());
+        let bob_framed = TestCodec {}.framed(bob_net);
+
+        let (data_tx, mut data_rx) = mpsc::channel(4);
+        let (fallback_tx, mut fallback_rx) = mpsc::channel(4);
+        let mut dispatcher = Dispatcher::default();
+        dispatcher.register(MessageType::Data, data_tx);
+        dispatcher.set_fallback(fallback_tx);
+
+        let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), dispatcher.clone());
+        let mut session = Session::new(rid, bob_framed, manager.session_manager.refs());
+
+        // Run on a task context
+        lazy(move || {
+            let mut alice_net = AliceNet::new(cable);
+            assert!(alice_net.write_all(DUMMY_MSG_NTCP_DATA).is_ok());
+
+            // Pass it through the session
+            session.poll().unwrap();
+
+            // The dispatcher routed it to the Data handler
+            match data_rx.poll() {
+                Ok(Async::Ready(Some((from, msg)))) => {
+                    assert_eq!(from, hash);
+                    assert_eq!(msg, *DUMMY_MSG);
+                }
+                v => panic!("Unexpected returned value: {:?}", v),
+            }
+            assert_eq!(fallback_rx.poll(), Ok(Async::NotReady));
+            assert_eq!(dispatcher.received(MessageType::Data), 1);
+
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
 }
@@ -1,3 +1,4 @@
+use bytes::{Bytes, BytesMut};
 use cookie_factory::*;
 use nom::{
     bits::{bits, streaming::take as take_bits},
@@ -10,10 +11,11 @@ use nom::{
     IResult,
 };
 use rand::{rngs::OsRng, Rng};
+use std::iter::repeat;
 
 use crate::data::frame::{gen_router_info, router_info};
 use crate::data::RouterInfo;
-use crate::i2np::frame::{gen_ntcp2_message, ntcp2_message};
+use crate::i2np::frame::{gen_ntcp2_message, ntcp2_message, ntcp2_message_bytes};
 use crate::i2np::Message;
 
 use super::{Block, Frame, RouterInfoFlags};
@@ -93,8 +95,12 @@ fn gen_routerinfo<'a>(
 
 // I2NP Message
 
-fn message(i: &[u8]) -> IResult<&[u8], Block> {
-    map(length_value(be_u16, ntcp2_message), Block::Message)(i)
+fn message<'a>(buf: Option<&Bytes>, i: &'a [u8]) -> IResult<&'a [u8], Block> {
+    let msg = |i: &'a [u8]| match buf {
+        Some(buf) => ntcp2_message_bytes(buf, i),
+        None => ntcp2_message(i),
+    };
+    map(length_value(be_u16, msg), Block::Message)(i)
 }
 
 fn gen_message<'a>(
@@ -168,13 +174,13 @@ fn gen_unknown<'a>(
 // Framing
 //
 
-fn block(i: &[u8]) -> IResult<&[u8], Block> {
+fn block<'a>(buf: Option<&Bytes>, i: &'a [u8]) -> IResult<&'a [u8], Block> {
     let (i, blk) = be_u8(i)?;
     match blk {
         0 => datetime(i),
         1 => options(i),
         2 => routerinfo(i),
-        3 => message(i),
+        3 => message(buf, i),
         4 => termination(i),
         254 => padding(i),
         _ => map(unknown, |data| Block::Unknown(blk, data))(i),
@@ -205,7 +211,69 @@ fn gen_block<'a>(
 }
 
 pub fn frame(i: &[u8]) -> IResult<&[u8], Frame> {
-    many1(complete(block))(i)
+    frame_in(None, i)
+}
+
+/// Parses a frame from a slice of `buf`, sharing `buf` with any opaque I2NP payloads
+/// instead of copying them.
+pub fn frame_bytes<'a>(buf: &Bytes, i: &'a [u8]) -> IResult<&'a [u8], Frame> {
+    frame_in(Some(buf), i)
+}
+
+fn frame_in<'a>(buf: Option<&Bytes>, i: &'a [u8]) -> IResult<&'a [u8], Frame> {
+    many1(complete(|i: &'a [u8]| block(buf, i)))(i)
+}
+
+/// Serializes the given frame, appending it to `buf`. I2NP messages are written
+/// directly into `buf`.
+#[allow(clippy::ptr_arg)]
+pub fn write_frame(frame: &Frame, buf: &mut BytesMut) -> Result<(), GenError> {
+    let start = buf.len();
+    for block in frame {
+        let res = match *block {
+            Block::Message(ref msg) => write_message(msg, buf),
+            _ => write_block(block, buf),
+        };
+        if let Err(e) = res {
+            buf.truncate(start);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn write_message(msg: &Message, buf: &mut BytesMut) -> Result<(), GenError> {
+    let start = buf.len();
+    buf.extend_from_slice(&[3, 0, 0]);
+    msg.write_ntcp2_to(buf)?;
+    let size = buf.len() - start - 3;
+    if size > usize::from(u16::MAX) {
+        return Err(GenError::BufferTooSmall(size));
+    }
+    buf[start + 1] = (size >> 8) as u8;
+    buf[start + 2] = (size & 0xff) as u8;
+    Ok(())
+}
+
+/// Appends a block with [`gen_block`], growing `buf` until the block fits.
+fn write_block(block: &Block, buf: &mut BytesMut) -> Result<(), GenError> {
+    let start = buf.len();
+    let mut len = 0;
+    loop {
+        let grow = len - (buf.len() - start);
+        buf.extend(repeat(0).take(grow));
+        match gen_block((&mut buf[start..], 0), block) {
+            Ok((_, sz)) => {
+                buf.truncate(start + sz);
+                return Ok(());
+            }
+            Err(GenError::BufferTooSmall(sz)) if sz > len => len = sz,
+            Err(e) => {
+                buf.truncate(start);
+                return Err(e);
+            }
+        }
+    }
 }
 
 #[allow(clippy::ptr_arg)]
@@ -326,9 +394,13 @@ mod tests {
         };
     }
 
+    fn parse_block(i: &[u8]) -> IResult<&[u8], Block> {
+        block(None, i)
+    }
+
     macro_rules! eval_block {
         ($value:expr, $expected:expr) => {
-            bake_and_eat!(gen_block, block, $value, $expected)
+            bake_and_eat!(gen_block, parse_block, $value, $expected)
         };
     }
 
@@ -369,7 +441,7 @@ mod tests {
             Block::Message(Message {
                 id: 0,
                 expiration: I2PDate::from_system_time(UNIX_EPOCH + Duration::new(1_524_874_654, 0)),
-                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+                payload: MessagePayload::Data(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9].into()),
             }),
             [
                 0x03, 0x00, 0x17, 0x14, 0x00, 0x00, 0x00, 0x00, 0x5a, 0xe3, 0xbd, 0x9e, 0x00, 0x00,
@@ -378,6 +450,65 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_frame_bytes() {
+        let msg = Message {
+            id: 0,
+            expiration: I2PDate::from_system_time(UNIX_EPOCH + Duration::new(1_524_874_654, 0)),
+            payload: MessagePayload::Data(vec![7; 100].into()),
+        };
+        let frame = vec![Block::Message(msg), Block::Padding(3)];
+        let mut buf = vec![0; 200];
+        let (_, sz) = gen_frame((&mut buf, 0), &frame).unwrap();
+        buf.truncate(sz);
+        let buf = Bytes::from(buf);
+
+        let (_, parsed) = frame_bytes(&buf, &buf).unwrap();
+        assert_eq!(parsed, frame);
+
+        // The Data payload points into the frame buffer
+        match &parsed[0] {
+            Block::Message(Message {
+                payload: MessagePayload::Data(data),
+                ..
+            }) => {
+                let (start, ptr) = (buf.as_ptr() as usize, data.as_ptr() as usize);
+                assert!(ptr >= start && ptr + data.len() <= start + buf.len());
+            }
+            _ => panic!("Unexpected block: {:?}", parsed[0]),
+        }
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let ri = match router_info(ROUTER_INFO) {
+            Ok((_, ri)) => ri,
+            Err(e) => panic!("Unexpected error: {:?}", e),
+        };
+        let blocks = vec![
+            Block::DateTime(42),
+            Block::Options(vec![0x00, 0x01, 0x02]),
+            Block::RouterInfo(ri, RouterInfoFlags { flood: true }),
+            Block::Message(Message::dummy_data()),
+            Block::Termination(42, 7, vec![0xfe]),
+        ];
+        let mut expected = vec![0; 2048];
+        let (_, sz) = gen_frame((&mut expected, 0), &blocks).unwrap();
+        expected.truncate(sz);
+
+        // Frames are appended after existing data
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        write_frame(&blocks, &mut buf).unwrap();
+        assert_eq!(&buf[..6], b"prefix");
+        assert_eq!(&buf[6..], &expected[..]);
+
+        // Padding is random, so only check that it parses
+        let mut buf = BytesMut::new();
+        write_frame(&vec![Block::Padding(10)], &mut buf).unwrap();
+        assert_eq!(buf.len(), 13);
+        assert_eq!(frame(&buf), Ok((&[][..], vec![Block::Padding(10)])));
+    }
+
     #[test]
     fn test_termination() {
         eval_block!(
@@ -389,7 +520,7 @@ mod tests {
     #[test]
     fn test_padding() {
         // Test parsing
-        match block(&[
+        match parse_block(&[
             0xfe, 0x00, 0x0a, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09,
         ]) {
             Ok((_, m)) => assert_eq!(m, Block::Padding(10)),
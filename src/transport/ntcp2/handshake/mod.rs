@@ -1,9 +1,7 @@
 use cookie_factory::GenError;
 use futures::{Async, Future, Poll};
-use i2p_snow::{Builder, Session};
 use nom::Err;
-use rand::{rngs::OsRng, Rng};
-use siphasher::sip::SipHasher;
+use rand::Rng;
 use std::net::SocketAddr;
 use std::ops::AddAssign;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -13,28 +11,35 @@ use tokio::{
 };
 
 use super::{
-    frame, Block, Codec, NTCP2_MTU, NTCP2_NOISE_PROTOCOL_NAME, NTCP2_OPT_I, NTCP2_OPT_S,
-    NTCP2_OPT_V, NTCP2_STYLE, NTCP2_VERSION,
+    frame, Block, Codec, NTCP2_MTU, NTCP2_NOISE_PROTOCOL_NAME, NTCP2_STYLE, NTCP2_VERSION,
+};
+use crate::crypto::{
+    self,
+    noise::{
+        xk::{
+            ExpectSessionConfirmed, ExpectSessionCreated, ExpectSessionRequest, Initiator,
+            SendSessionConfirmed, SendSessionCreated, EPHEMERAL_MSG_OVERHEAD, STATIC_KEY_CT_LEN,
+        },
+        Transport,
+    },
+    rand::{CryptoRng, OsRng},
 };
-use crate::constants::I2P_BASE64;
 use crate::data::{RouterAddress, RouterIdentity, RouterInfo};
 use crate::transport::ntcp::NTCP_STYLE;
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 
 const SESSION_REQUEST_PT_LEN: usize = 16;
-const SESSION_REQUEST_CT_LEN: usize = 32 + SESSION_REQUEST_PT_LEN + 16;
+const SESSION_REQUEST_CT_LEN: usize = EPHEMERAL_MSG_OVERHEAD + SESSION_REQUEST_PT_LEN;
 const SESSION_CREATED_PT_LEN: usize = 16;
-const SESSION_CREATED_CT_LEN: usize = 32 + SESSION_CREATED_PT_LEN + 16;
+const SESSION_CREATED_CT_LEN: usize = EPHEMERAL_MSG_OVERHEAD + SESSION_CREATED_PT_LEN;
 
+/// Polls a future, and once it is ready takes the Noise state that was waiting on it.
 macro_rules! try_poll {
-    ($f:expr, $parent:expr, $noise:expr) => {
+    ($f:expr, $noise:expr) => {
         match $f.poll()? {
-            Async::Ready(t) => t,
-            Async::NotReady => {
-                $parent.noise = Some($noise);
-                return Ok(Async::NotReady);
-            }
+            Async::Ready(t) => (t, $noise.take().expect("Noise state is present")),
+            Async::NotReady => return Ok(Async::NotReady),
         }
     };
 }
@@ -45,21 +50,44 @@ macro_rules! io_err {
     };
 }
 
+fn noise_err(msg: &str, e: crypto::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", msg, e))
+}
+
+fn random_padding<R: CryptoRng>(rng: &mut R) -> Vec<u8> {
+    // TODO: Sample padding sizes from an appropriate distribution
+    let mut padding = vec![0u8; rng.gen_range(0..16)];
+    rng.fill(&mut padding[..]);
+    padding
+}
+
 //
 // Establishment handshake
 //
 
 #[allow(clippy::enum_variant_names)]
 enum IBHandshakeState<T> {
-    SessionRequest(ReadExact<T, Vec<u8>>),
-    SessionRequestPadding(ReadExact<T, Vec<u8>>),
-    SessionCreated((WriteAll<T, Vec<u8>>, SystemTime)),
-    SessionConfirmed((ReadExact<T, Vec<u8>>, SystemTime)),
+    SessionRequest((ReadExact<T, Vec<u8>>, Option<ExpectSessionRequest>)),
+    SessionRequestPadding((ReadExact<T, Vec<u8>>, Option<SendSessionCreated>)),
+    SessionCreated(
+        (
+            WriteAll<T, Vec<u8>>,
+            SystemTime,
+            Option<ExpectSessionConfirmed>,
+        ),
+    ),
+    SessionConfirmed(
+        (
+            ReadExact<T, Vec<u8>>,
+            SystemTime,
+            Option<ExpectSessionConfirmed>,
+        ),
+    ),
 }
 
 pub struct IBHandshake<T> {
-    noise: Option<Session>,
     sclen: usize,
+    verify_ri: bool,
     state: IBHandshakeState<T>,
 }
 
@@ -68,22 +96,29 @@ where
     T: AsyncRead + AsyncWrite,
     T: Send + 'static,
 {
-    pub fn new(conn: T, static_key: &[u8], aesobfse_key: &[u8], aesobfse_iv: &[u8; 16]) -> Self {
-        // Initialize our responder NoiseSession using a builder.
-        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
-        let noise = builder
-            .local_private_key(&static_key)
-            .aesobfse(&aesobfse_key, &aesobfse_iv)
-            .enable_ask()
-            .build_responder()
-            .unwrap();
-        let state = IBHandshakeState::SessionRequest(io::read_exact(
-            conn,
-            vec![0u8; SESSION_REQUEST_CT_LEN],
+    /// Starts the handshake for an inbound connection. If `verify_ri` is false, the
+    /// signature of the RouterInfo received in SessionConfirmed is not checked.
+    pub fn new(
+        conn: T,
+        static_key: &[u8],
+        aesobfse_key: &[u8; 32],
+        aesobfse_iv: &[u8; 16],
+        verify_ri: bool,
+    ) -> Self {
+        let noise = ExpectSessionRequest::new(
+            NTCP2_NOISE_PROTOCOL_NAME.as_bytes(),
+            array_ref![static_key, 0, 32],
+            aesobfse_key,
+            aesobfse_iv,
+            &mut OsRng,
+        );
+        let state = IBHandshakeState::SessionRequest((
+            io::read_exact(conn, vec![0u8; SESSION_REQUEST_CT_LEN]),
+            Some(noise),
         ));
         IBHandshake {
-            noise: Some(noise),
             sclen: 0,
+            verify_ri,
             state,
         }
     }
@@ -99,15 +134,15 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
-            let mut noise = self.noise.take().unwrap();
             let next_state = match self.state {
-                IBHandshakeState::SessionRequest(ref mut f) => {
-                    let (conn, msg) = try_poll!(f, self, noise);
+                IBHandshakeState::SessionRequest((ref mut f, ref mut noise)) => {
+                    let ((conn, msg), noise) = try_poll!(f, noise);
 
                     // <- e, es
                     debug!("S <- e, es");
-                    let mut buf = [0u8; SESSION_REQUEST_PT_LEN];
-                    noise.read_message(&msg, &mut buf).unwrap();
+                    let (noise, buf) = noise
+                        .read_session_request(&msg)
+                        .map_err(|e| noise_err("Invalid SessionRequest", e))?;
 
                     // SessionRequest
                     let (padlen, sclen, _ts_a) = match frame::session_request(&buf) {
@@ -123,12 +158,13 @@ where
                     };
                     self.sclen = sclen;
 
-                    IBHandshakeState::SessionRequestPadding(io::read_exact(conn, vec![0u8; padlen]))
+                    IBHandshakeState::SessionRequestPadding((
+                        io::read_exact(conn, vec![0u8; padlen]),
+                        Some(noise),
+                    ))
                 }
-                IBHandshakeState::SessionRequestPadding(ref mut f) => {
-                    let (conn, padding) = try_poll!(f, self, noise);
-
-                    noise.set_h_data(2, &padding).unwrap();
+                IBHandshakeState::SessionRequestPadding((ref mut f, ref mut noise)) => {
+                    let ((conn, sr_padding), noise) = try_poll!(f, noise);
 
                     let now = SystemTime::now();
                     let mut ts_b = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
@@ -136,12 +172,11 @@ where
                     let ts_b = ts_b.as_secs() as u32;
 
                     let mut rng = OsRng;
-                    // TODO: Sample padding sizes from an appropriate distribution
-                    let sc_padlen = rng.gen_range(0..16);
+                    let padding = random_padding(&mut rng);
 
                     // SessionCreated
                     let mut sc_buf = [0u8; SESSION_CREATED_PT_LEN];
-                    match frame::gen_session_created((&mut sc_buf, 0), sc_padlen, ts_b)
+                    match frame::gen_session_created((&mut sc_buf, 0), padding.len() as u16, ts_b)
                         .map(|tup| tup.1)
                     {
                         Ok(sz) if sz == sc_buf.len() => (),
@@ -158,31 +193,32 @@ where
 
                     // -> e, ee
                     debug!("S -> e, ee");
-                    let mut buf = vec![0u8; SESSION_CREATED_CT_LEN + sc_padlen as usize];
-                    noise.write_message(&sc_buf, &mut buf).unwrap();
-                    rng.fill(&mut buf[SESSION_CREATED_CT_LEN..]);
-                    noise.set_h_data(3, &buf[SESSION_CREATED_CT_LEN..]).unwrap();
+                    let (noise, buf) = noise
+                        .write_session_created(&sr_padding, &sc_buf, &padding)
+                        .map_err(|e| noise_err("Failed to create SessionCreated", e))?;
 
-                    IBHandshakeState::SessionCreated((io::write_all(conn, buf), now))
+                    IBHandshakeState::SessionCreated((io::write_all(conn, buf), now, Some(noise)))
                 }
-                IBHandshakeState::SessionCreated((ref mut f, rtt_timer)) => {
-                    let (conn, _) = try_poll!(f, self, noise);
+                IBHandshakeState::SessionCreated((ref mut f, rtt_timer, ref mut noise)) => {
+                    let ((conn, _), noise) = try_poll!(f, noise);
 
                     IBHandshakeState::SessionConfirmed((
-                        io::read_exact(conn, vec![0u8; self.sclen + 48]),
+                        io::read_exact(conn, vec![0u8; STATIC_KEY_CT_LEN + self.sclen]),
                         rtt_timer,
+                        Some(noise),
                     ))
                 }
-                IBHandshakeState::SessionConfirmed((ref mut f, rtt_timer)) => {
-                    let (conn, msg) = try_poll!(f, self, noise);
+                IBHandshakeState::SessionConfirmed((ref mut f, rtt_timer, ref mut noise)) => {
+                    let ((conn, msg), noise) = try_poll!(f, noise);
 
                     // <- s, se
                     debug!("S <- s, se");
-                    let mut buf = vec![0u8; msg.len()];
-                    let len = noise.read_message(&msg, &mut buf).unwrap();
+                    let (transport, buf) = noise
+                        .read_session_confirmed(&msg)
+                        .map_err(|e| noise_err("Invalid SessionConfirmed", e))?;
 
                     // SessionConfirmed
-                    let mut frames = match frame::session_confirmed(&buf[..len]) {
+                    let mut frames = match frame::session_confirmed(&buf) {
                         Err(Err::Incomplete(n)) => {
                             return io_err!(
                                 Other,
@@ -213,75 +249,47 @@ where
                         }
                     };
 
+                    // Don't trust anything in the RouterInfo until its signature is checked
+                    if self.verify_ri {
+                        if let Err(e) = ri_a.verify() {
+                            return io_err!(InvalidData, e);
+                        }
+                    }
+
                     // Get peer skew
                     let rtt = rtt_timer.elapsed().expect("Time went backwards?");
                     debug!("Peer RTT: {:?}", rtt);
 
-                    // Prepare length obfuscation keys and IVs
-                    let (ek0, ek1, eiv, dk0, dk1, div) = {
-                        let label = String::from("siphash");
-                        noise.initialize_ask(vec![label.clone()]).unwrap();
-                        let (ask0, ask1) = noise.finalize_ask(&label).unwrap();
-
-                        // Bob to Alice
-                        let mut ek0 = [0; 8];
-                        let mut ek1 = [0; 8];
-                        let mut eiv = [0; 8];
-                        ek0.copy_from_slice(&ask1[0..8]);
-                        ek1.copy_from_slice(&ask1[8..16]);
-                        eiv.copy_from_slice(&ask1[16..24]);
-
-                        // Alice to Bob
-                        let mut dk0 = [0; 8];
-                        let mut dk1 = [0; 8];
-                        let mut div = [0; 8];
-                        dk0.copy_from_slice(&ask0[0..8]);
-                        dk1.copy_from_slice(&ask0[8..16]);
-                        div.copy_from_slice(&ask0[16..24]);
-
-                        (
-                            u64::from_le_bytes(ek0),
-                            u64::from_le_bytes(ek1),
-                            u64::from_le_bytes(eiv),
-                            u64::from_le_bytes(dk0),
-                            u64::from_le_bytes(dk1),
-                            u64::from_le_bytes(div),
-                        )
-                    };
-
-                    // Transition the state machine into transport mode now that the handshake is complete.
-                    let noise = noise.into_transport_mode().unwrap();
                     info!("Connection established!");
-
-                    let codec = Codec {
-                        noise,
-                        noise_buf: [0u8; NTCP2_MTU],
-                        enc_len_masker: SipHasher::new_with_keys(ek0, ek1),
-                        enc_len_iv: eiv,
-                        dec_len_masker: SipHasher::new_with_keys(dk0, dk1),
-                        dec_len_iv: div,
-                        next_len: None,
-                    };
-
-                    return Ok(Async::Ready((ri_a, codec.framed(conn))));
+                    return Ok(Async::Ready((ri_a, Codec::new(transport).framed(conn))));
                 }
             };
-            self.noise = Some(noise);
             self.state = next_state;
         }
     }
 }
 
 enum OBHandshakeState<T> {
-    Connecting(IoFuture<T>),
-    SessionRequest((WriteAll<T, Vec<u8>>, SystemTime)),
-    SessionCreated((ReadExact<T, Vec<u8>>, SystemTime)),
-    SessionCreatedPadding(ReadExact<T, Vec<u8>>),
-    SessionConfirmed(WriteAll<T, Vec<u8>>),
+    Connecting((IoFuture<T>, Option<Initiator>)),
+    SessionRequest(
+        (
+            WriteAll<T, Vec<u8>>,
+            SystemTime,
+            Option<ExpectSessionCreated>,
+        ),
+    ),
+    SessionCreated(
+        (
+            ReadExact<T, Vec<u8>>,
+            SystemTime,
+            Option<ExpectSessionCreated>,
+        ),
+    ),
+    SessionCreatedPadding((ReadExact<T, Vec<u8>>, Option<SendSessionConfirmed>)),
+    SessionConfirmed((WriteAll<T, Vec<u8>>, Option<Transport>)),
 }
 
 pub struct OBHandshake<T> {
-    noise: Option<Session>,
     sc_buf: Vec<u8>,
     sc_len: usize,
     peer_ri: RouterInfo,
@@ -303,15 +311,11 @@ where
         F: FnOnce(&SocketAddr) -> IoFuture<T>,
     {
         let filter = |ra: &RouterAddress| {
-            match ra.option(&NTCP2_OPT_V) {
-                Some(v) => {
-                    if !v.to_csv().contains(&NTCP2_VERSION) {
-                        return false;
-                    }
-                }
-                None => return false,
-            };
-            ra.option(&NTCP2_OPT_S).is_some() && ra.option(&NTCP2_OPT_I).is_some()
+            ra.version()
+                .map(|v| v.contains(&NTCP2_VERSION))
+                .unwrap_or(false)
+                && ra.ntcp2_static_key().is_some()
+                && ra.ntcp2_iv().is_some()
         };
 
         let ra = match peer_ri.address(&NTCP2_STYLE, filter) {
@@ -323,23 +327,10 @@ where
         };
 
         let addr = ra.addr().unwrap();
-        let remote_key = match ra.option(&NTCP2_OPT_S) {
-            Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
-                Ok(key) => key,
-                Err(e) => return Err(format!("Invalid static key in address: {}", e)),
-            },
-            None => return Err("No static key in address".to_string()),
-        };
-
+        // The filter guarantees that these are present and valid
+        let remote_key = ra.ntcp2_static_key().unwrap();
         let aesobfse_key = peer_ri.router_id.hash().0;
-        let mut aesobfse_iv = [0; 16];
-        match ra.option(&NTCP2_OPT_I) {
-            Some(val) => match I2P_BASE64.decode(val.0.as_bytes()) {
-                Ok(iv) => aesobfse_iv.copy_from_slice(&iv),
-                Err(e) => return Err(format!("Invalid IV in address: {}", e)),
-            },
-            None => return Err("No IV in address".to_string()),
-        }
+        let aesobfse_iv = ra.ntcp2_iv().unwrap();
 
         let sc_padlen = {
             let mut rng = OsRng;
@@ -368,19 +359,17 @@ where
         sc_buf.truncate(sc_len);
         let sc_len = sc_len + 16;
 
-        // Initialize our initiator NoiseSession using a builder.
-        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
-        let noise = builder
-            .local_private_key(&static_key)
-            .remote_public_key(&remote_key)
-            .aesobfse(&aesobfse_key, &aesobfse_iv)
-            .enable_ask()
-            .build_initiator()
-            .unwrap();
-
-        let state = OBHandshakeState::Connecting(conn(&addr));
+        let noise = Initiator::new(
+            NTCP2_NOISE_PROTOCOL_NAME.as_bytes(),
+            array_ref![static_key, 0, 32],
+            &remote_key,
+            &aesobfse_key,
+            &aesobfse_iv,
+            &mut OsRng,
+        );
+
+        let state = OBHandshakeState::Connecting((conn(&addr), Some(noise)));
         Ok(OBHandshake {
-            noise: Some(noise),
             sc_buf,
             sc_len,
             peer_ri,
@@ -399,10 +388,9 @@ where
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
-            let mut noise = self.noise.take().unwrap();
             let next_state = match self.state {
-                OBHandshakeState::Connecting(ref mut f) => {
-                    let conn = try_poll!(f, self, noise);
+                OBHandshakeState::Connecting((ref mut f, ref mut noise)) => {
+                    let (conn, noise) = try_poll!(f, noise);
 
                     let now = SystemTime::now();
                     let mut ts_a = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
@@ -410,15 +398,14 @@ where
                     let ts_a = ts_a.as_secs() as u32;
 
                     let mut rng = OsRng;
-                    // TODO: Sample padding sizes from an appropriate distribution
-                    let padlen = rng.gen_range(0..16);
+                    let padding = random_padding(&mut rng);
 
                     // SessionRequest
                     let mut sr_buf = [0u8; SESSION_REQUEST_PT_LEN];
                     match frame::gen_session_request(
                         (&mut sr_buf, 0),
                         2,
-                        padlen,
+                        padding.len() as u16,
                         self.sc_len as u16,
                         ts_a,
                     )
@@ -438,29 +425,30 @@ where
 
                     // -> e, es
                     debug!("C -> e, es");
-                    let mut buf = vec![0u8; SESSION_REQUEST_CT_LEN + padlen as usize];
-                    noise.write_message(&sr_buf, &mut buf).unwrap();
-                    rng.fill(&mut buf[SESSION_REQUEST_CT_LEN..]);
-                    noise.set_h_data(2, &buf[SESSION_REQUEST_CT_LEN..]).unwrap();
+                    let (noise, buf) = noise
+                        .write_session_request(&sr_buf, &padding)
+                        .map_err(|e| noise_err("Failed to create SessionRequest", e))?;
 
-                    OBHandshakeState::SessionRequest((io::write_all(conn, buf), now))
+                    OBHandshakeState::SessionRequest((io::write_all(conn, buf), now, Some(noise)))
                 }
 
-                OBHandshakeState::SessionRequest((ref mut f, rtt_timer)) => {
-                    let (conn, _) = try_poll!(f, self, noise);
+                OBHandshakeState::SessionRequest((ref mut f, rtt_timer, ref mut noise)) => {
+                    let ((conn, _), noise) = try_poll!(f, noise);
 
                     OBHandshakeState::SessionCreated((
                         io::read_exact(conn, vec![0u8; SESSION_CREATED_CT_LEN]),
                         rtt_timer,
+                        Some(noise),
                     ))
                 }
-                OBHandshakeState::SessionCreated((ref mut f, rtt_timer)) => {
-                    let (conn, msg) = try_poll!(f, self, noise);
+                OBHandshakeState::SessionCreated((ref mut f, rtt_timer, ref mut noise)) => {
+                    let ((conn, msg), noise) = try_poll!(f, noise);
 
                     // <- e, ee
                     debug!("C <- e, ee");
-                    let mut buf = [0u8; SESSION_CREATED_PT_LEN];
-                    noise.read_message(&msg, &mut buf).unwrap();
+                    let (noise, buf) = noise
+                        .read_session_created(&msg)
+                        .map_err(|e| noise_err("Invalid SessionCreated", e))?;
 
                     // SessionCreated
                     let (padlen, _ts_b) = match frame::session_created(&buf) {
@@ -474,78 +462,33 @@ where
                     let rtt = rtt_timer.elapsed().expect("Time went backwards?");
                     debug!("Peer RTT: {:?}", rtt);
 
-                    OBHandshakeState::SessionCreatedPadding(io::read_exact(conn, vec![0u8; padlen]))
+                    OBHandshakeState::SessionCreatedPadding((
+                        io::read_exact(conn, vec![0u8; padlen]),
+                        Some(noise),
+                    ))
                 }
-                OBHandshakeState::SessionCreatedPadding(ref mut f) => {
-                    let (conn, padding) = try_poll!(f, self, noise);
-
-                    noise.set_h_data(3, &padding).unwrap();
+                OBHandshakeState::SessionCreatedPadding((ref mut f, ref mut noise)) => {
+                    let ((conn, sc_padding), noise) = try_poll!(f, noise);
 
                     // SessionConfirmed
 
                     // -> s, se
                     debug!("C -> s, se");
-                    let mut buf = vec![0u8; NTCP2_MTU];
-                    let len = noise.write_message(&self.sc_buf, &mut buf).unwrap();
-                    buf.truncate(len);
+                    let (buf, transport) = noise
+                        .write_session_confirmed(&sc_padding, &self.sc_buf)
+                        .map_err(|e| noise_err("Failed to create SessionConfirmed", e))?;
 
-                    OBHandshakeState::SessionConfirmed(io::write_all(conn, buf))
+                    OBHandshakeState::SessionConfirmed((io::write_all(conn, buf), Some(transport)))
                 }
-                OBHandshakeState::SessionConfirmed(ref mut f) => {
-                    let (conn, _) = try_poll!(f, self, noise);
-
-                    // Prepare length obfuscation keys and IVs
-                    let (ek0, ek1, eiv, dk0, dk1, div) = {
-                        let label = String::from("siphash");
-                        noise.initialize_ask(vec![label.clone()]).unwrap();
-                        let (ask0, ask1) = noise.finalize_ask(&label).unwrap();
-
-                        // Alice to Bob
-                        let mut ek0 = [0; 8];
-                        let mut ek1 = [0; 8];
-                        let mut eiv = [0; 8];
-                        ek0.copy_from_slice(&ask0[0..8]);
-                        ek1.copy_from_slice(&ask0[8..16]);
-                        eiv.copy_from_slice(&ask0[16..24]);
-
-                        // Bob to Alice
-                        let mut dk0 = [0; 8];
-                        let mut dk1 = [0; 8];
-                        let mut div = [0; 8];
-                        dk0.copy_from_slice(&ask1[0..8]);
-                        dk1.copy_from_slice(&ask1[8..16]);
-                        div.copy_from_slice(&ask1[16..24]);
-
-                        (
-                            u64::from_le_bytes(ek0),
-                            u64::from_le_bytes(ek1),
-                            u64::from_le_bytes(eiv),
-                            u64::from_le_bytes(dk0),
-                            u64::from_le_bytes(dk1),
-                            u64::from_le_bytes(div),
-                        )
-                    };
-
-                    // Transition the state machine into transport mode now that the handshake is complete.
-                    let noise = noise.into_transport_mode().unwrap();
-
-                    let codec = Codec {
-                        noise,
-                        noise_buf: [0u8; NTCP2_MTU],
-                        enc_len_masker: SipHasher::new_with_keys(ek0, ek1),
-                        enc_len_iv: eiv,
-                        dec_len_masker: SipHasher::new_with_keys(dk0, dk1),
-                        dec_len_iv: div,
-                        next_len: None,
-                    };
+                OBHandshakeState::SessionConfirmed((ref mut f, ref mut transport)) => {
+                    let ((conn, _), transport) = try_poll!(f, transport);
 
                     return Ok(Async::Ready((
                         self.peer_ri.router_id.clone(),
-                        codec.framed(conn),
+                        Codec::new(transport).framed(conn),
                     )));
                 }
             };
-            self.noise = Some(noise);
             self.state = next_state;
         }
     }
@@ -555,13 +498,17 @@ where
 mod tests {
     use super::{IBHandshake, IBHandshakeState, OBHandshake, OBHandshakeState};
     use crate::transport::{
-        ntcp2::Manager,
+        ntcp2::{Codec, Manager},
         tests::{AliceNet, BobNet, NetworkCable},
     };
 
-    use futures::{done, Async, Future};
+    use futures::{done, Async, Future, Poll};
+    use std::io;
+    use tokio::codec::Framed;
 
-    use crate::data::{RouterInfo, RouterSecretKeys};
+    use crate::data::{
+        I2PDate, RouterIdentity, RouterInfo, RouterInfoBuilder, RouterSecretKeys, VerifyError,
+    };
     use crate::router::mock::MockDistributor;
 
     macro_rules! test_poll {
@@ -583,15 +530,21 @@ mod tests {
         };
     }
 
-    #[test]
-    fn ntcp2_handshake() {
+    fn signed_ri() -> RouterInfo {
+        let sk = RouterSecretKeys::new();
+        RouterInfoBuilder::new(sk.rid.clone()).sign(&sk.signing_private_key)
+    }
+
+    /// Runs a handshake between Alice and Bob, returning the results of Alice's final
+    /// poll and Bob's final poll.
+    fn handshake(
+        alice_ri: RouterInfo,
+        verify_ri: bool,
+    ) -> (
+        Poll<(RouterIdentity, Framed<AliceNet, Codec>), io::Error>,
+        Poll<(RouterInfo, Framed<BobNet, Codec>), io::Error>,
+    ) {
         // Generate key material
-        let alice_ri = {
-            let sk = RouterSecretKeys::new();
-            let mut ri = RouterInfo::new(sk.rid.clone());
-            ri.sign(&sk.signing_private_key);
-            ri
-        };
         let (
             bob_ri,
             bob_static_public_key,
@@ -602,9 +555,9 @@ mod tests {
             let sk = RouterSecretKeys::new();
             let distributor = MockDistributor::new();
             let manager = Manager::new("127.0.0.1:0".parse().unwrap(), distributor);
-            let mut ri = RouterInfo::new(sk.rid.clone());
-            ri.set_addresses(vec![manager.address()]);
-            ri.sign(&sk.signing_private_key);
+            let ri = RouterInfoBuilder::new(sk.rid.clone())
+                .addresses(vec![manager.address()])
+                .sign(&sk.signing_private_key);
             (
                 ri,
                 manager.static_public_key,
@@ -632,6 +585,7 @@ mod tests {
             &bob_static_private_key,
             &bob_aesobfse_key,
             &bob_aesobfse_iv,
+            verify_ri,
         );
         test_state!(alice, Connecting, bob, SessionRequest);
 
@@ -652,8 +606,37 @@ mod tests {
         // Bob <- SessionConfirmed
         let bob_conn = bob.poll();
 
+        (alice_conn, bob_conn)
+    }
+
+    #[test]
+    fn ntcp2_handshake() {
         // Both halves should now be ready
-        match (alice_conn, bob_conn) {
+        match handshake(signed_ri(), true) {
+            (Ok(Async::Ready(_)), Ok(Async::Ready(_))) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn ntcp2_handshake_tampered_ri() {
+        // Alter Alice's RouterInfo after signing it
+        let mut alice_ri = signed_ri();
+        alice_ri.published = I2PDate(alice_ri.published.0 + 1);
+
+        match handshake(alice_ri.clone(), true) {
+            (Ok(Async::Ready(_)), Err(e)) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                assert_eq!(
+                    e.get_ref().and_then(|e| e.downcast_ref::<VerifyError>()),
+                    Some(&VerifyError::InvalidSignature)
+                );
+            }
+            _ => panic!(),
+        }
+
+        // Bob accepts it when verification is disabled
+        match handshake(alice_ri, false) {
             (Ok(Async::Ready(_)), Ok(Async::Ready(_))) => (),
             _ => panic!(),
         }
@@ -671,8 +654,8 @@ mod tests {
             net::{TcpListener, TcpStream},
         };
 
-        use crate::data::{RouterInfo, RouterSecretKeys};
-        use crate::i2np::{Message, MessagePayload};
+        use crate::data::{RouterInfoBuilder, RouterSecretKeys};
+        use crate::i2np::{Message, MessageIdGenerator};
         use crate::router::mock::MockDistributor;
         use crate::transport::ntcp2::{
             handshake::{IBHandshake, OBHandshake},
@@ -704,6 +687,7 @@ mod tests {
             rem: usize,
             chunk: usize,
             frame_size: usize,
+            msg_ids: MessageIdGenerator,
         }
 
         impl Future for Transfer {
@@ -717,9 +701,7 @@ mod tests {
                         let len = cmp::min(self.rem, self.chunk);
                         let buf = DATA[..len].to_vec();
 
-                        frame.push(Block::Message(Message::from_payload(MessagePayload::Data(
-                            buf,
-                        ))));
+                        frame.push(Block::Message(Message::data(&self.msg_ids, buf)));
                         self.rem -= len;
                         if self.rem == 0 {
                             break;
@@ -742,9 +724,7 @@ mod tests {
             // Generate key material
             let alice_ri = {
                 let sk = RouterSecretKeys::new();
-                let mut ri = RouterInfo::new(sk.rid.clone());
-                ri.sign(&sk.signing_private_key);
-                ri
+                RouterInfoBuilder::new(sk.rid.clone()).sign(&sk.signing_private_key)
             };
             let (
                 bob_ri,
@@ -756,9 +736,9 @@ mod tests {
                 let sk = RouterSecretKeys::new();
                 let distributor = MockDistributor::new();
                 let mgr = Manager::new("127.0.0.1:0".parse().unwrap(), distributor);
-                let mut ri = RouterInfo::new(sk.rid.clone());
-                ri.set_addresses(vec![mgr.address()]);
-                ri.sign(&sk.signing_private_key);
+                let ri = RouterInfoBuilder::new(sk.rid.clone())
+                    .addresses(vec![mgr.address()])
+                    .sign(&sk.signing_private_key);
                 (
                     ri,
                     mgr.static_public_key,
@@ -785,6 +765,7 @@ mod tests {
                             &bob_static_private_key,
                             &bob_aesobfse_key,
                             &bob_aesobfse_iv,
+                            true,
                         )
                     })
                     .and_then(|(ri, conn)| {
@@ -806,6 +787,7 @@ mod tests {
                     rem: MB,
                     chunk: write_size,
                     frame_size,
+                    msg_ids: MessageIdGenerator::default(),
                 })
                 .map_err(|e| panic!("client err: {:?}", e));
 
@@ -34,18 +34,14 @@ use futures::{
     sync::mpsc,
     try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
 };
-use i2p_snow::{self, Builder};
 use nom::Err;
 use rand::{rngs::OsRng, Rng};
-use siphasher::sip::SipHasher;
 use std::collections::VecDeque;
 use std::fmt;
 use std::fs::File;
-use std::hash::Hasher;
-use std::iter::repeat;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::{
     codec::{Decoder, Encoder, Framed},
     io::{self, AsyncRead, AsyncWrite, Read, Write},
@@ -53,16 +49,26 @@ use tokio::{
     spawn,
     timer::Timeout,
 };
+use zeroize::Zeroizing;
 
 use super::{
     ntcp::NTCP_STYLE,
     session::{self, SessionContext, SessionManager, SessionRefs, SessionRx},
     Bid, Transport,
 };
-use crate::constants::I2P_BASE64;
-use crate::data::{Hash, I2PString, RouterAddress, RouterIdentity, RouterInfo};
-use crate::i2np::{DatabaseStore, Message, MessagePayload};
+use crate::crypto::{
+    chachapoly::TAG_LEN,
+    dh::x25519_base,
+    noise::{CipherState, Transport as NoiseTransport},
+    siphash::SipState,
+};
+use crate::data::{
+    Hash, I2PString, RouterAddress, RouterAddressBuilder, RouterIdentity, RouterInfo,
+};
+use crate::i2np::{DatabaseStore, Message, MessageIdGenerator, MessagePayload};
 use crate::router::{
+    config,
+    profiles::Transport as ProfileTransport,
     types::{Distributor, DistributorResult},
     Context,
 };
@@ -74,14 +80,12 @@ mod handshake;
 
 lazy_static! {
     static ref NTCP2_STYLE: I2PString = I2PString::new("NTCP2");
-    static ref NTCP2_VERSION: I2PString = I2PString::new("2");
-    static ref NTCP2_OPT_V: I2PString = I2PString::new("v");
-    static ref NTCP2_OPT_S: I2PString = I2PString::new("s");
-    static ref NTCP2_OPT_I: I2PString = I2PString::new("i");
     static ref NTCP2_NOISE_PROTOCOL_NAME: &'static str =
         "Noise_XKaesobfse+hs2+hs3_25519_ChaChaPoly_SHA256";
 }
 
+const NTCP2_VERSION: u8 = 2;
+
 // Max NTCP2 message size is ~64kB
 const NTCP2_MTU: usize = 65535;
 
@@ -161,15 +165,27 @@ impl fmt::Debug for Block {
 type Frame = Vec<Block>;
 
 pub struct Codec {
-    noise: i2p_snow::Session,
-    noise_buf: [u8; NTCP2_MTU],
-    enc_len_masker: SipHasher,
-    enc_len_iv: u64,
-    dec_len_masker: SipHasher,
-    dec_len_iv: u64,
+    enc: CipherState,
+    dec: CipherState,
+    enc_len_masker: SipState,
+    dec_len_masker: SipState,
     next_len: Option<usize>,
 }
 
+impl Codec {
+    fn new(transport: NoiseTransport) -> Self {
+        let (enc_len_masker, dec_len_masker) = transport.sip_states();
+        let (enc, dec) = transport.into_ciphers();
+        Codec {
+            enc,
+            dec,
+            enc_len_masker,
+            dec_len_masker,
+            next_len: None,
+        }
+    }
+}
+
 impl Decoder for Codec {
     type Item = Frame;
     type Error = io::Error;
@@ -180,14 +196,9 @@ impl Decoder for Codec {
                 return Ok(None);
             }
 
-            // Update masker state
-            let mut masker = self.dec_len_masker;
-            masker.write_u64(self.dec_len_iv);
-            self.dec_len_iv = masker.finish();
-
             // Read the length
             let mut msg_len = ((buf[0] as usize) << 8) + (buf[1] as usize);
-            msg_len ^= (self.dec_len_iv & 0xffff) as usize;
+            msg_len ^= self.dec_len_masker.next_mask() as usize;
 
             buf.split_to(2);
             self.next_len = Some(msg_len);
@@ -195,14 +206,22 @@ impl Decoder for Codec {
 
         match self.next_len {
             Some(len) if buf.len() >= len => {
-                // Read the frame
-                let frame_len = match self.noise.read_message(&buf[..len], &mut self.noise_buf) {
-                    Ok(len) => len,
-                    Err(e) => return io_err!(Other, format!("Decryption error: {:?}", e)),
-                };
+                if len < TAG_LEN {
+                    return io_err!(InvalidData, format!("frame too short: {}", len));
+                }
 
-                // Parse the frame
-                let f = match frame::frame(&self.noise_buf[..frame_len]) {
+                // Decrypt the frame in place
+                let frame_len = len - TAG_LEN;
+                let mut tag = [0; TAG_LEN];
+                tag.copy_from_slice(&buf[frame_len..len]);
+                if let Err(e) = self.dec.open(&[], &mut buf[..frame_len], &tag) {
+                    return io_err!(Other, format!("Decryption error: {:?}", e));
+                }
+                let data = buf.split_to(len).freeze();
+                self.next_len = None;
+
+                // Parse the frame, sharing its buffer with the messages it contains
+                let f = match frame::frame_bytes(&data, &data[..frame_len]) {
                     Err(Err::Incomplete(n)) => {
                         return io_err!(
                             Other,
@@ -215,9 +234,6 @@ impl Decoder for Codec {
                     Ok((_, frame)) => frame,
                 };
 
-                buf.split_to(len);
-                self.next_len = None;
-
                 Ok(Some(f))
             }
             _ => Ok(None),
@@ -230,36 +246,12 @@ impl Encoder for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
-        match frame::gen_frame((&mut self.noise_buf, 0), &frame).map(|tup| tup.1) {
-            Ok(sz) => {
-                let msg_len = sz + 16;
-
-                let start = buf.len();
-                buf.extend(repeat(0).take(2 + msg_len));
-
-                // Update masker state
-                let mut masker = self.enc_len_masker;
-                masker.write_u64(self.enc_len_iv);
-                self.enc_len_iv = masker.finish();
-
-                // Mask the length
-                let masked_len = msg_len ^ (self.enc_len_iv & 0xffff) as usize;
-
-                buf[start] = (masked_len >> 8) as u8;
-                buf[start + 1] = (masked_len & 0xff) as u8;
-                match self
-                    .noise
-                    .write_message(&self.noise_buf[..sz], &mut buf[start + 2..])
-                {
-                    Ok(len) if len == msg_len => Ok(()),
-                    Ok(len) => io_err!(
-                        InvalidData,
-                        format!("encrypted frame is unexpected size: {}", len)
-                    ),
-                    Err(e) => io_err!(Other, format!("encryption error: {:?}", e)),
-                }
-            }
-            Err(e) => match e {
+        // Write the frame after its length field, and encrypt it in place
+        let start = buf.len();
+        buf.extend_from_slice(&[0; 2]);
+        if let Err(e) = frame::write_frame(&frame, buf) {
+            buf.truncate(start);
+            return match e {
                 GenError::BufferTooSmall(sz) => io_err!(
                     InvalidData,
                     format!("message ({}) larger than MTU ({})", sz, NTCP2_MTU)
@@ -267,8 +259,27 @@ impl Encoder for Codec {
                 GenError::InvalidOffset
                 | GenError::CustomError(_)
                 | GenError::NotYetImplemented => io_err!(InvalidData, "could not generate"),
-            },
+            };
+        }
+
+        // The encrypted frame must fit in the length field
+        let msg_len = buf.len() - start - 2 + TAG_LEN;
+        if msg_len > NTCP2_MTU {
+            buf.truncate(start);
+            return io_err!(
+                InvalidData,
+                format!("message ({}) larger than MTU ({})", msg_len, NTCP2_MTU)
+            );
         }
+
+        // Mask the length
+        let masked_len = msg_len ^ self.enc_len_masker.next_mask() as usize;
+        buf[start] = (masked_len >> 8) as u8;
+        buf[start + 1] = (masked_len & 0xff) as u8;
+
+        let tag = self.enc.seal(&[], &mut buf[start + 2..]);
+        buf.extend_from_slice(&tag);
+        Ok(())
     }
 }
 
@@ -302,12 +313,13 @@ where
         ri: &RouterIdentity,
         upstream: Framed<T, C>,
         session_refs: SessionRefs<Block, D>,
+        msg_ids: MessageIdGenerator,
     ) -> Self {
         let (downstream, upstream) = upstream.split();
         let (tx, rx) = mpsc::unbounded();
         let ctx = SessionContext::new(ri.hash(), session_refs.state, tx);
         Session {
-            ib: InboundSession::new(ctx, upstream),
+            ib: InboundSession::new(ctx, upstream, msg_ids),
             ob: OutboundSession::new(downstream),
             distributor: session_refs.distributor,
             pending_ib: None,
@@ -386,6 +398,7 @@ where
     ctx: SessionContext<Block>,
     upstream: SplitStream<Framed<T, C>>,
     cached_msgs: VecDeque<Message>,
+    msg_ids: MessageIdGenerator,
 }
 
 impl<T, C> InboundSession<T, C>
@@ -394,11 +407,16 @@ where
     C: Decoder<Item = Frame, Error = io::Error>,
     C: Encoder<Item = Frame, Error = io::Error>,
 {
-    fn new(ctx: SessionContext<Block>, upstream: SplitStream<Framed<T, C>>) -> Self {
+    fn new(
+        ctx: SessionContext<Block>,
+        upstream: SplitStream<Framed<T, C>>,
+        msg_ids: MessageIdGenerator,
+    ) -> Self {
         InboundSession {
             ctx,
             upstream,
             cached_msgs: VecDeque::new(),
+            msg_ids,
         }
     }
 
@@ -419,9 +437,10 @@ where
                     self.ctx.hash
                 );
                 // TODO: Fake-store if we are a FF and flood flag is set
-                let fake_ds = Message::from_payload(MessagePayload::DatabaseStore(
-                    DatabaseStore::from_ri(ri, None),
-                ));
+                let fake_ds = Message::from_payload(
+                    &self.msg_ids,
+                    MessagePayload::DatabaseStore(DatabaseStore::from_ri(ri, None)),
+                );
 
                 Some(fake_ds)
             }
@@ -570,38 +589,42 @@ where
 
 pub struct Manager<D: Distributor> {
     addr: SocketAddr,
-    static_private_key: Vec<u8>,
+    static_private_key: Zeroizing<Vec<u8>>,
     static_public_key: Vec<u8>,
     aesobfse_iv: [u8; 16],
     session_manager: SessionManager<Block, D>,
     ctx: Option<Arc<Context>>,
+    msg_ids: MessageIdGenerator,
+    verify_ri: bool,
 }
 
 impl<D: Distributor> Manager<D> {
     pub fn new(addr: SocketAddr, distributor: D) -> Self {
-        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
-        let dh = builder.generate_keypair().unwrap();
-
+        let mut static_private_key = Zeroizing::new(vec![0; 32]);
         let mut aesobfse_iv = [0; 16];
         let mut rng = OsRng;
+        rng.fill(&mut static_private_key[..]);
         rng.fill(&mut aesobfse_iv[..]);
+        let static_public_key = x25519_base(array_ref![static_private_key, 0, 32]).to_vec();
 
         Manager {
             addr,
-            static_private_key: dh.private,
-            static_public_key: dh.public,
+            static_private_key,
+            static_public_key,
             aesobfse_iv,
             session_manager: session::new_manager(distributor),
             ctx: None,
+            msg_ids: MessageIdGenerator::default(),
+            verify_ri: true,
         }
     }
 
     pub fn from_file(addr: SocketAddr, path: &str, distributor: D) -> io::Result<Self> {
         let mut keys = File::open(path)?;
-        let mut data: Vec<u8> = Vec::new();
+        let mut data = Zeroizing::new(Vec::new());
         keys.read_to_end(&mut data)?;
 
-        let mut static_private_key = Vec::with_capacity(32);
+        let mut static_private_key = Zeroizing::new(Vec::with_capacity(32));
         let mut static_public_key = Vec::with_capacity(32);
         let mut aesobfse_iv = [0; 16];
 
@@ -616,11 +639,13 @@ impl<D: Distributor> Manager<D> {
             aesobfse_iv,
             session_manager: session::new_manager(distributor),
             ctx: None,
+            msg_ids: MessageIdGenerator::default(),
+            verify_ri: true,
         })
     }
 
     pub fn to_file(&self, path: &str) -> io::Result<()> {
-        let mut data = Vec::with_capacity(96);
+        let mut data = Zeroizing::new(Vec::with_capacity(96));
         data.write_all(&self.static_private_key)?;
         data.write_all(&self.static_public_key)?;
         data.write_all(&self.aesobfse_iv)?;
@@ -629,6 +654,8 @@ impl<D: Distributor> Manager<D> {
     }
 
     pub fn set_context(&mut self, ctx: Arc<Context>) {
+        self.msg_ids = ctx.msg_ids.clone();
+        self.verify_ri = config::verify_router_infos(&ctx.config.read().unwrap());
         self.ctx = Some(ctx);
     }
 
@@ -646,17 +673,14 @@ impl<D: Distributor> Manager<D> {
     }
 
     pub fn address(&self) -> RouterAddress {
-        let mut ra = RouterAddress::new(&NTCP2_STYLE, self.addr);
-        ra.set_option(NTCP2_OPT_V.clone(), NTCP2_VERSION.clone());
-        ra.set_option(
-            NTCP2_OPT_S.clone(),
-            I2PString(I2P_BASE64.encode(&self.static_public_key)),
-        );
-        ra.set_option(
-            NTCP2_OPT_I.clone(),
-            I2PString(I2P_BASE64.encode(&self.aesobfse_iv)),
-        );
-        ra
+        let mut static_key = [0; 32];
+        static_key.copy_from_slice(&self.static_public_key);
+        RouterAddressBuilder::new(&NTCP2_STYLE)
+            .addr(self.addr)
+            .version(&[NTCP2_VERSION])
+            .ntcp2_static_key(&static_key)
+            .ntcp2_iv(&self.aesobfse_iv)
+            .build()
     }
 
     pub fn listen(&self, own_rid: &RouterIdentity) -> impl Future<Item = (), Error = io::Error> {
@@ -667,22 +691,31 @@ impl<D: Distributor> Manager<D> {
         let static_key = self.static_private_key.clone();
         let aesobfse_key = own_rid.hash().0;
         let aesobfse_iv = self.aesobfse_iv;
+        let verify_ri = self.verify_ri;
 
         // Give each incoming connection the references it needs
         let session_refs = self.session_manager.refs();
         let conns = listener.incoming().zip(session_refs);
+        let msg_ids = self.msg_ids.clone();
 
         // For each incoming connection:
         conns.for_each(move |(conn, session_refs)| {
             info!("Incoming connection!");
+            let msg_ids = msg_ids.clone();
             // Execute the handshake
-            let conn = handshake::IBHandshake::new(conn, &static_key, &aesobfse_key, &aesobfse_iv);
+            let conn = handshake::IBHandshake::new(
+                conn,
+                &static_key,
+                &aesobfse_key,
+                &aesobfse_iv,
+                verify_ri,
+            );
 
             // Once connected:
             let process_conn = conn
-                .and_then(|(ri, conn)| {
+                .and_then(move |(ri, conn)| {
                     let peer_hash = ri.router_id.hash();
-                    let session = Session::new(&ri.router_id, conn, session_refs);
+                    let session = Session::new(&ri.router_id, conn, session_refs, msg_ids.clone());
 
                     // Treat RouterInfo from handshake as a DatabaseStore
                     debug!(
@@ -690,9 +723,10 @@ impl<D: Distributor> Manager<D> {
                         peer_hash
                     );
                     // TODO: Fake-store if we are a FF and flood flag is set
-                    let fake_ds = Message::from_payload(MessagePayload::DatabaseStore(
-                        DatabaseStore::from_ri(ri, None),
-                    ));
+                    let fake_ds = Message::from_payload(
+                        &msg_ids,
+                        MessagePayload::DatabaseStore(DatabaseStore::from_ri(ri, None)),
+                    );
                     let stored = session.distributor.handle(peer_hash, fake_ds);
 
                     // Start the session
@@ -717,6 +751,7 @@ impl<D: Distributor> Manager<D> {
             own_ri,
             peer_ri,
             self.session_manager.refs(),
+            self.msg_ids.clone(),
         )
     }
 }
@@ -726,6 +761,7 @@ fn connect<D: Distributor>(
     own_ri: &RouterInfo,
     peer_ri: RouterInfo,
     session_refs: SessionRefs<Block, D>,
+    msg_ids: MessageIdGenerator,
 ) -> io::Result<impl Future<Item = (), Error = io::Error>> {
     // Connect to the peer
     let transport = match handshake::OBHandshake::new(
@@ -744,7 +780,7 @@ fn connect<D: Distributor>(
 
     // Once connected:
     Ok(timed.and_then(|(ri, conn)| {
-        let session = Session::new(&ri, conn, session_refs);
+        let session = Session::new(&ri, conn, session_refs, msg_ids);
         spawn(session.map_err(|_| ()));
         Ok(())
     }))
@@ -761,15 +797,11 @@ impl<D: Distributor> Transport for Manager<D> {
         }
 
         let filter = |ra: &RouterAddress| {
-            match ra.option(&NTCP2_OPT_V) {
-                Some(v) => {
-                    if !v.to_csv().contains(&NTCP2_VERSION) {
-                        return false;
-                    }
-                }
-                None => return false,
-            };
-            ra.option(&NTCP2_OPT_S).is_some() && ra.option(&NTCP2_OPT_I).is_some()
+            ra.version()
+                .map(|v| v.contains(&NTCP2_VERSION))
+                .unwrap_or(false)
+                && ra.ntcp2_static_key().is_some()
+                && ra.ntcp2_iv().is_some()
         };
 
         if peer.address(&NTCP2_STYLE, filter).is_none()
@@ -791,7 +823,7 @@ impl<D: Distributor> Transport for Manager<D> {
 
 pub struct OutboundSink<D: Distributor> {
     ctx: Arc<Context>,
-    static_private_key: Vec<u8>,
+    static_private_key: Zeroizing<Vec<u8>>,
     session_refs: SessionRefs<Block, D>,
 }
 
@@ -817,10 +849,28 @@ impl<D: Distributor> Sink for OutboundSink<D> {
                     &self.ctx.ri.read().unwrap(),
                     peer.clone(),
                     session_refs,
+                    self.ctx.msg_ids.clone(),
                 ) {
                     Ok(f) => {
-                        spawn(f.map_err(|e| {
-                            error!("Error while connecting: {}", e);
+                        let profiles = self.ctx.profiles.clone();
+                        let hash = peer.router_id.hash();
+                        spawn(f.then(move |res| -> Result<(), ()> {
+                            match res {
+                                Ok(()) => profiles.connected(
+                                    &hash,
+                                    ProfileTransport::Ntcp2,
+                                    SystemTime::now(),
+                                ),
+                                Err(e) => {
+                                    error!("Error while connecting: {}", e);
+                                    profiles.connect_failed(
+                                        &hash,
+                                        ProfileTransport::Ntcp2,
+                                        SystemTime::now(),
+                                    );
+                                }
+                            }
+                            Ok(())
                         }));
                     }
                     Err(e) => error!("{}", e),
@@ -847,12 +897,14 @@ mod tests {
     use bytes::BytesMut;
     use cookie_factory::GenError;
     use futures::{lazy, Future, Sink};
+    use i2p_snow::Builder;
     use nom::{Err, Offset};
     use std::io::{self, Read, Write};
     use std::iter::repeat;
     use tokio::codec::{Decoder, Encoder};
 
-    use super::{frame, Frame, Manager, Session, NTCP2_MTU};
+    use super::{frame, Frame, Manager, Session, NTCP2_MTU, NTCP2_NOISE_PROTOCOL_NAME};
+    use crate::crypto::dh::{x25519, x25519_base};
     use crate::i2np::Message;
     use crate::router::mock::{mock_context, MockDistributor};
     use crate::transport::tests::{AliceNet, BobNet, NetworkCable};
@@ -951,7 +1003,12 @@ mod tests {
             assert!(received.is_empty());
 
             // Create a session
-            let mut session = Session::new(&rid, alice_framed, manager.session_manager.refs());
+            let mut session = Session::new(
+                &rid,
+                alice_framed,
+                manager.session_manager.refs(),
+                manager.msg_ids.clone(),
+            );
 
             // Pass it through the session, now it's on the wire
             session.poll().unwrap();
@@ -978,7 +1035,12 @@ mod tests {
         let distributor = MockDistributor::new();
         let received = distributor.received.clone();
         let manager = Manager::new("127.0.0.1:1234".parse().unwrap(), distributor);
-        let mut session = Session::new(&rid, bob_framed, manager.session_manager.refs());
+        let mut session = Session::new(
+            &rid,
+            bob_framed,
+            manager.session_manager.refs(),
+            manager.msg_ids.clone(),
+        );
 
         // Run on a task context
         lazy(move || {
@@ -1002,4 +1064,27 @@ mod tests {
         .wait()
         .unwrap();
     }
+
+    #[test]
+    fn noise_keys_match_x25519() {
+        let builder: Builder<'_> = Builder::new(NTCP2_NOISE_PROTOCOL_NAME.parse().unwrap());
+        let alice = builder.generate_keypair().unwrap();
+        let bob = builder.generate_keypair().unwrap();
+
+        let key = |k: &[u8]| {
+            let mut buf = [0; 32];
+            buf.copy_from_slice(k);
+            buf
+        };
+        let (alice_priv, alice_pub) = (key(&alice.private), key(&alice.public));
+        let (bob_priv, bob_pub) = (key(&bob.private), key(&bob.public));
+
+        // The static keys generated for the Noise handshake agree with our X25519
+        assert_eq!(x25519_base(&alice_priv), alice_pub);
+        assert_eq!(x25519_base(&bob_priv), bob_pub);
+        assert_eq!(
+            x25519(&alice_priv, &bob_pub).unwrap(),
+            x25519(&bob_priv, &alice_pub).unwrap()
+        );
+    }
 }
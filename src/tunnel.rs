@@ -5,12 +5,38 @@ use std::time::SystemTime;
 use crate::data::{Hash, RouterInfo, TunnelId};
 
 mod acceptor;
-mod encryption;
+mod accounting;
+mod build;
+mod crypto;
+mod dispatcher;
+mod endpoint;
 mod frame;
+mod gateway;
+mod manager;
+mod pool;
 mod processor;
+mod records;
+mod registry;
+mod select;
+mod tester;
+mod transit;
 
 pub use self::acceptor::Listener;
+pub use self::accounting::{Accounting, TunnelDetails, TunnelStats, TunnelUse};
+pub use self::build::{
+    BuildError, BuildPath, BuildTunnel, BuiltTunnel, Creator, PendingReplies, RejectReason,
+    ReplyStats,
+};
+pub use self::dispatcher::{DispatchStats, TunnelDispatcher};
+pub use self::endpoint::{EndpointError, EndpointStats};
+pub use self::gateway::{GatewayError, OutboundGateway};
+pub use self::manager::TunnelManager;
+pub use self::pool::{PoolConfig, PoolStats, Selection, TunnelBuilder, TunnelPool};
 pub use self::processor::Participant;
+pub use self::registry::{ExpiryStats, TunnelIdState, TunnelIdStats, TunnelIds};
+pub use self::select::HopSelector;
+pub use self::tester::{ProbeSender, TestConfig};
+pub use self::transit::{Transit, TransitLimits, TransitStats};
 
 /// The lifetime of a tunnel. Always 10 minutes for current I2P tunnels.
 const TUNNEL_LIFETIME: u64 = 10 * 60;
@@ -19,7 +45,7 @@ const TUNNEL_LIFETIME: u64 = 10 * 60;
 /// - InboundGateway contains `next_hop`
 /// - Intermediate contains `from_ident` and `next_hop`
 /// - OutboundEndpoint contains `from_ident`
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 enum HopData {
     InboundGateway((RouterInfo, TunnelId)),
     Intermediate(Hash, (RouterInfo, TunnelId)),
@@ -33,7 +59,7 @@ enum HopData {
 #[derive(Debug)]
 pub struct HopConfig {
     hop_data: HopData,
-    layer_cipher: encryption::LayerCipher,
+    layer_cipher: crypto::LayerCipher,
     expires: SystemTime,
 }
 
@@ -43,10 +69,64 @@ impl PartialEq for HopConfig {
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum TunnelMessageDeliveryType {
+/// The direction of a tunnel that we built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelRole {
+    /// We are the endpoint, and the first hop is the gateway.
+    Inbound,
+    /// We are the gateway, and the last hop is the endpoint.
+    Outbound,
+}
+
+/// A hop in a tunnel that we built.
+#[derive(Debug)]
+struct TunnelHop {
+    ident: Hash,
+    receive_tid: TunnelId,
+    layer_cipher: crypto::LayerCipher,
+}
+
+/// A tunnel that we built, with the keys we need to process its messages.
+///
+/// Hops are ordered from the gateway to the endpoint, and do not include us. A
+/// zero-hop tunnel has no hops, and we are both its gateway and its endpoint.
+#[derive(Debug)]
+pub struct OwnTunnel {
+    role: TunnelRole,
+    hops: Vec<TunnelHop>,
+    /// The first hop is looked up at build time, so that we can send messages into
+    /// outbound tunnels. For zero-hop tunnels, this is our own RouterInfo.
+    first_hop: RouterInfo,
+    expires: SystemTime,
+}
+
+impl OwnTunnel {
+    pub fn role(&self) -> TunnelRole {
+        self.role
+    }
+
+    /// Returns the routers in this tunnel, from the gateway to the endpoint.
+    pub fn hops(&self) -> Vec<Hash> {
+        self.hops.iter().map(|hop| hop.ident.clone()).collect()
+    }
+
+    pub fn expires(&self) -> SystemTime {
+        self.expires
+    }
+
+    pub fn is_zero_hop(&self) -> bool {
+        self.hops.is_empty()
+    }
+}
+
+/// Where the endpoint of a tunnel delivers a message sent through it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TunnelMessageDeliveryType {
+    /// The endpoint handles the message itself.
     Local,
+    /// The endpoint sends the message into the given tunnel at its gateway.
     Tunnel(TunnelId, Hash),
+    /// The endpoint sends the message directly to the given router.
     Router(Hash),
 }
 
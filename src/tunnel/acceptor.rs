@@ -1,20 +1,19 @@
 //! Logic for processing incoming tunnel build requests.
 
-use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
 use futures::{sink, sync::mpsc, try_ready, Async, Future, Poll, Sink, Stream};
-use std::slice::IterMut;
+use rand::thread_rng;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::{io, spawn};
 use tokio_threadpool::blocking;
 
-use super::{encryption::LayerCipher, HopConfig, HopData, TUNNEL_LIFETIME};
-use crate::crypto::{elgamal, SessionKey};
-use crate::data::{Hash, RouterInfo, TunnelId};
-use crate::i2np::{
-    frame::gen_build_response_record, BuildRequestError, BuildRequestRecord, BuildResponseRecord,
-    Message, MessagePayload, ParticipantType,
+use super::{
+    crypto::LayerCipher,
+    records::{decrypt_my_record, encrypt_build_reply, DecryptedRecord, ReplyKeys},
+    HopConfig, HopData, PendingReplies, Transit, TUNNEL_LIFETIME,
 };
+use crate::data::{Hash, RouterInfo, TunnelId};
+use crate::i2np::{BuildRequestError, Message, MessagePayload, ParticipantType};
 use crate::netdb::client::LookupRouterInfo;
 use crate::router::Context;
 use crate::util::DecayingBloomFilter;
@@ -30,11 +29,11 @@ const MAX_REQUEST_FUTURE: u64 = 5 * 60;
 /// being asked to participate in.
 const MAX_LOOKUP_TIME: u64 = 30;
 
-const TUNNEL_ACCEPT: u8 = 0;
-const TUNNEL_REJECT_PROBABALISTIC_REJECT: u8 = 10;
-const TUNNEL_REJECT_TRANSIENT_OVERLOAD: u8 = 20;
-const TUNNEL_REJECT_BANDWIDTH: u8 = 30;
-const TUNNEL_REJECT_CRIT: u8 = 50;
+pub(super) const TUNNEL_ACCEPT: u8 = 0;
+pub(super) const TUNNEL_REJECT_PROBABALISTIC_REJECT: u8 = 10;
+pub(super) const TUNNEL_REJECT_TRANSIENT_OVERLOAD: u8 = 20;
+pub(super) const TUNNEL_REJECT_BANDWIDTH: u8 = 30;
+pub(super) const TUNNEL_REJECT_CRIT: u8 = 50;
 
 macro_rules! try_poll {
     ($f:expr, $parent:expr, $state:expr) => {
@@ -54,70 +53,87 @@ macro_rules! try_poll {
 }
 
 trait TunnelBuildRequest {
-    fn entry_mut(&mut self, entry: usize) -> &mut [u8; 528];
-    fn iter_mut(&mut self) -> IterMut<[u8; 528]>;
+    type Record: AsMut<[u8]>;
+    fn entry_mut(&mut self, entry: usize) -> &mut [u8];
+    fn records_mut(&mut self) -> &mut [Self::Record];
     fn to_msg(self, msg_id: u32) -> Message;
     fn to_reply(self, msg_id: u32) -> Message;
 }
 
 impl TunnelBuildRequest for [[u8; 528]; 8] {
-    fn entry_mut(&mut self, entry: usize) -> &mut [u8; 528] {
+    type Record = [u8; 528];
+
+    fn entry_mut(&mut self, entry: usize) -> &mut [u8] {
         &mut self[entry]
     }
 
-    fn iter_mut(&mut self) -> IterMut<[u8; 528]> {
-        (&mut self[..]).iter_mut()
+    fn records_mut(&mut self) -> &mut [[u8; 528]] {
+        &mut self[..]
     }
 
     fn to_msg(self, msg_id: u32) -> Message {
-        let mut msg = Message::from_payload(MessagePayload::TunnelBuild(self));
-        msg.id = msg_id;
-        msg
+        Message::tunnel_build(msg_id, self)
     }
 
     fn to_reply(self, msg_id: u32) -> Message {
-        let mut msg = Message::from_payload(MessagePayload::TunnelBuildReply(self));
-        msg.id = msg_id;
-        msg
+        Message::tunnel_build_reply(msg_id, self)
     }
 }
 
 impl TunnelBuildRequest for Vec<[u8; 528]> {
-    fn entry_mut(&mut self, entry: usize) -> &mut [u8; 528] {
+    type Record = [u8; 528];
+
+    fn entry_mut(&mut self, entry: usize) -> &mut [u8] {
         &mut self[entry]
     }
 
-    fn iter_mut(&mut self) -> IterMut<[u8; 528]> {
-        (&mut self[..]).iter_mut()
+    fn records_mut(&mut self) -> &mut [[u8; 528]] {
+        &mut self[..]
     }
 
     fn to_msg(self, msg_id: u32) -> Message {
-        let mut msg = Message::from_payload(MessagePayload::VariableTunnelBuild(self));
-        msg.id = msg_id;
-        msg
+        Message::variable_tunnel_build(msg_id, self)
     }
 
     fn to_reply(self, msg_id: u32) -> Message {
-        let mut msg = Message::from_payload(MessagePayload::VariableTunnelBuildReply(self));
-        msg.id = msg_id;
-        msg
+        Message::variable_tunnel_build_reply(msg_id, self)
+    }
+}
+
+impl TunnelBuildRequest for Vec<[u8; 218]> {
+    type Record = [u8; 218];
+
+    fn entry_mut(&mut self, entry: usize) -> &mut [u8] {
+        &mut self[entry]
+    }
+
+    fn records_mut(&mut self) -> &mut [[u8; 218]] {
+        &mut self[..]
+    }
+
+    fn to_msg(self, msg_id: u32) -> Message {
+        Message::short_tunnel_build(msg_id, self)
+    }
+
+    fn to_reply(self, msg_id: u32) -> Message {
+        Message::outbound_tunnel_build_reply(msg_id, self)
     }
 }
 
 struct EncryptionInfo<TB: TunnelBuildRequest> {
     is_obep: bool,
     next_hop: RouterInfo,
+    next_tid: TunnelId,
     send_msg_id: u32,
-    response: BuildResponseRecord,
+    reply: u8,
     tb: TB,
     i: usize,
-    reply_key: SessionKey,
-    reply_iv: [u8; 16],
+    reply_keys: ReplyKeys,
 }
 
 enum HopAcceptorState<TB: TunnelBuildRequest> {
     Decrypt(Hash, TB, usize),
-    Resolving(Hash, LookupRouterInfo, BuildRequestRecord, TB, usize),
+    Resolving(Hash, LookupRouterInfo, DecryptedRecord, TB, usize),
     RegisterParticipating(
         sink::Send<mpsc::Sender<(TunnelId, HopConfig)>>,
         EncryptionInfo<TB>,
@@ -131,8 +147,8 @@ enum HopAcceptorState<TB: TunnelBuildRequest> {
 /// Encryption operations are handled using the [`blocking()`] threadpool.
 struct HopAcceptor<TB: TunnelBuildRequest> {
     state: Option<HopAcceptorState<TB>>,
-    decryptor: elgamal::Decryptor,
     filter: Arc<Mutex<DecayingBloomFilter>>,
+    transit: Transit,
     new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
     ctx: Arc<Context>,
 }
@@ -142,15 +158,15 @@ impl<TB: TunnelBuildRequest> HopAcceptor<TB> {
         from: Hash,
         tb: TB,
         entry: usize,
-        decryptor: elgamal::Decryptor,
         filter: Arc<Mutex<DecayingBloomFilter>>,
+        transit: Transit,
         new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
         ctx: Arc<Context>,
     ) -> Self {
         HopAcceptor {
             state: Some(HopAcceptorState::Decrypt(from, tb, entry)),
-            decryptor,
             filter,
+            transit,
             new_participating_tx,
             ctx,
         }
@@ -169,10 +185,10 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
                     match try_poll!(
                         blocking(|| {
                             let brr =
-                                BuildRequestRecord::decrypt(tb.entry_mut(i), &self.decryptor)?;
+                                decrypt_my_record(tb.entry_mut(i), &self.ctx.keys.private_key)?;
                             // Check for duplicates by feeding the reply key into a decaying
                             // Bloom filter.
-                            if self.filter.lock().unwrap().feed(&brr.reply_key.0) {
+                            if self.filter.lock().unwrap().feed(brr.reply.filter_key()) {
                                 Err(BuildRequestError::Duplicate)
                             } else {
                                 Ok(brr)
@@ -209,8 +225,7 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
                             //   We can't detect this (or any longer loops).
 
                             // Timestamp validity
-                            let request_time =
-                                Duration::from_secs(u64::from(brr.request_time) * 3600);
+                            let request_time = brr.request_time;
                             let cur_time = SystemTime::now()
                                 .duration_since(UNIX_EPOCH)
                                 .expect("System time is broken!");
@@ -240,6 +255,7 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
                                 brr.next_ident.clone(),
                                 MAX_LOOKUP_TIME * 1000,
                                 None,
+                                false,
                             );
                             HopAcceptorState::Resolving(from_ident, f, brr, tb, i)
                         }
@@ -257,23 +273,33 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
                         HopAcceptorState::Resolving(from_ident, f, brr, tb, i)
                     );
 
-                    // Decide whether to accept or reject
-                    // TODO: Add support for IBGW, OBEP, metrics
-                    let reply = match brr.hop_type {
-                        ParticipantType::Intermediate => TUNNEL_ACCEPT,
-                        _ => TUNNEL_REJECT_CRIT,
+                    // Decide whether to accept or reject. Like other routers, we give
+                    // the bandwidth reason for every rejection.
+                    let ids = self.transit.tunnel_ids();
+                    let reply = if !ids.reserve(brr.receive_tid, SystemTime::now()) {
+                        debug!(
+                            "Rejecting build request: tunnel ID {} is in use",
+                            brr.receive_tid
+                        );
+                        TUNNEL_REJECT_BANDWIDTH
+                    } else if self.transit.admit(&mut thread_rng(), Instant::now()) {
+                        TUNNEL_ACCEPT
+                    } else {
+                        ids.release(&brr.receive_tid);
+                        debug!("Rejecting build request: over our participating limits");
+                        TUNNEL_REJECT_BANDWIDTH
                     };
 
                     // Prepare the information necessary to forward the response
                     let info = EncryptionInfo {
                         is_obep: brr.hop_type == ParticipantType::OutboundEndpoint,
                         next_hop: next_hop.clone(),
+                        next_tid: brr.next_tid,
                         send_msg_id: brr.send_msg_id,
-                        response: BuildResponseRecord { reply },
+                        reply,
                         tb,
                         i,
-                        reply_key: brr.reply_key,
-                        reply_iv: brr.reply_iv,
+                        reply_keys: brr.reply,
                     };
 
                     if reply == TUNNEL_ACCEPT {
@@ -317,30 +343,26 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
                 HopAcceptorState::Encrypt(mut info) => {
                     try_poll!(
                         blocking(|| {
-                            // Write our response
-                            {
-                                let tb_entry = info.tb.entry_mut(info.i);
-                                gen_build_response_record((tb_entry, 0), &info.response)
-                                    .expect("Should not fail!");
-                            }
-
-                            // Now encrypt all the entries
-                            for tb_entry in info.tb.iter_mut() {
-                                let cipher: Cbc<aes::Aes256, NoPadding> =
-                                    Cbc::new_from_slices(&info.reply_key.0, &info.reply_iv)
-                                        .expect("key and iv are correct length");
-                                let len = tb_entry.len();
-                                cipher.encrypt(tb_entry, len).expect("Should not fail!");
-                            }
+                            // Write our response, and encrypt all the other entries
+                            encrypt_build_reply(
+                                &info.reply_keys,
+                                info.i,
+                                info.tb.records_mut(),
+                                info.reply,
+                            )
                         }),
                         self,
                         HopAcceptorState::Encrypt(info)
                     );
 
-                    // If we are the OBEP in the build request, repackage it as a reply; otherwise,
-                    // leave it as-is.
+                    // If we are the OBEP in the build request, repackage it as a reply, to be
+                    // sent into the reply tunnel at its gateway; otherwise, leave it as-is.
                     let msg = if info.is_obep {
-                        info.tb.to_reply(info.send_msg_id)
+                        Message::tunnel_gateway(
+                            &self.ctx.msg_ids,
+                            info.next_tid,
+                            &info.tb.to_reply(info.send_msg_id),
+                        )
                     } else {
                         info.tb.to_msg(info.send_msg_id)
                     };
@@ -372,12 +394,19 @@ impl<TB: TunnelBuildRequest> Future for HopAcceptor<TB> {
 /// Each build request is spawned into its own task, which uses the [`blocking()`]
 /// threadpool for encryption operations.
 ///
-/// Currently the listener accepts every request for an intermediate position, and rejects
-/// every IBGW and OBEP request.
+/// Requests are accepted while we are within the limits on our participating tunnels
+/// tracked by [`Transit`], and the tunnel ID that the requester picked for us is free.
+/// Requests are read from build records encrypted to our router encryption key, which
+/// may be ElGamal or ECIES-X25519. Short build records are only readable with an
+/// ECIES-X25519 key.
+///
+/// Replies to tunnels we are building are passed to the waiting [`Creator`](super::Creator)
+/// instead.
 pub struct Listener {
     our_hash: Hash,
-    decryptor: elgamal::Decryptor,
+    replies: PendingReplies,
     filter: Arc<Mutex<DecayingBloomFilter>>,
+    transit: Transit,
     new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
     ib_rx: mpsc::Receiver<(Hash, Message)>,
     ctx: Arc<Context>,
@@ -386,22 +415,25 @@ pub struct Listener {
 impl Listener {
     pub fn new(
         ctx: Arc<Context>,
+        replies: PendingReplies,
+        transit: Transit,
         new_participating_tx: mpsc::Sender<(TunnelId, HopConfig)>,
         ib_rx: mpsc::Receiver<(Hash, Message)>,
     ) -> Self {
         Listener {
             our_hash: ctx.keys.rid.hash(),
-            decryptor: elgamal::Decryptor::from(&ctx.keys.private_key),
+            replies,
             filter: Arc::new(Mutex::new(DecayingBloomFilter::new(20_000))),
+            transit,
             new_participating_tx,
             ib_rx,
             ctx,
         }
     }
 
-    fn find_our_entry(&self, tb: &[[u8; 528]]) -> Option<usize> {
+    fn find_our_entry<T: AsRef<[u8]>>(&self, tb: &[T]) -> Option<usize> {
         for (i, tb_entry) in tb.iter().enumerate() {
-            if tb_entry[0..16] == self.our_hash.0[0..16] {
+            if tb_entry.as_ref()[0..16] == self.our_hash.0[0..16] {
                 return Some(i);
             }
         }
@@ -425,27 +457,61 @@ impl Future for Listener {
                                 from,
                                 tb,
                                 i,
-                                self.decryptor.clone(),
                                 self.filter.clone(),
+                                self.transit.clone(),
                                 self.new_participating_tx.clone(),
                                 self.ctx.clone(),
                             ));
                         }
                     }
                     MessagePayload::VariableTunnelBuild(vtb) => {
+                        // The last hop of an inbound tunnel we are building sends the
+                        // request back to us.
+                        let vtb = match self.replies.deliver(&from, msg.id, vtb) {
+                            Ok(()) => continue,
+                            Err(vtb) => vtb,
+                        };
                         if let Some(i) = self.find_our_entry(&vtb) {
                             // Let's try to accept it
                             spawn(HopAcceptor::new(
                                 from,
                                 vtb,
                                 i,
-                                self.decryptor.clone(),
                                 self.filter.clone(),
+                                self.transit.clone(),
                                 self.new_participating_tx.clone(),
                                 self.ctx.clone(),
                             ));
                         }
                     }
+                    MessagePayload::ShortTunnelBuild(stb) => {
+                        // As for a VariableTunnelBuild
+                        let stb = match self.replies.deliver(&from, msg.id, stb) {
+                            Ok(()) => continue,
+                            Err(stb) => stb,
+                        };
+                        if let Some(i) = self.find_our_entry(&stb) {
+                            spawn(HopAcceptor::new(
+                                from,
+                                stb,
+                                i,
+                                self.filter.clone(),
+                                self.transit.clone(),
+                                self.new_participating_tx.clone(),
+                                self.ctx.clone(),
+                            ));
+                        }
+                    }
+                    MessagePayload::VariableTunnelBuildReply(vtbr) => {
+                        if self.replies.deliver(&from, msg.id, vtbr).is_err() {
+                            debug!("Received unexpected build reply from {}", from);
+                        }
+                    }
+                    MessagePayload::OutboundTunnelBuildReply(otbr) => {
+                        if self.replies.deliver(&from, msg.id, otbr).is_err() {
+                            debug!("Received unexpected build reply from {}", from);
+                        }
+                    }
                     _ => {
                         debug!("Received unexpected message from {}:\n{}", from, msg);
                     }
@@ -470,7 +536,7 @@ mod tests {
         data::{Hash, RouterInfo, RouterSecretKeys, TunnelId},
         i2np::{BuildRequestRecord, ParticipantType},
         router::mock::{mock_context, mock_context_and_netdb},
-        tunnel::HopData,
+        tunnel::{HopData, Transit},
         util::DecayingBloomFilter,
     };
 
@@ -479,7 +545,6 @@ mod tests {
     fn accepted_intermediate_build_request() {
         let (ctx, mut netdb) = mock_context_and_netdb();
 
-        let decryptor = elgamal::Decryptor::from(&ctx.keys.private_key);
         let filter = Arc::new(Mutex::new(DecayingBloomFilter::new(10)));
         let (new_participating_tx, mut new_participating_rx) = mpsc::channel(1);
 
@@ -506,7 +571,7 @@ mod tests {
                 next_ident.clone(),
                 ParticipantType::Intermediate,
             );
-            vec![brr.encrypt(&elgamal::Encryptor::from(&ctx.keys.rid.public_key))]
+            vec![brr.encrypt(&elgamal::Encryptor::from(ctx.keys.rid.public_key()))]
         };
 
         // Add the next hop to the NetDB
@@ -516,8 +581,8 @@ mod tests {
             from_ident.clone(),
             tb,
             0,
-            decryptor,
             filter,
+            Transit::default(),
             new_participating_tx,
             ctx,
         );
@@ -562,7 +627,6 @@ mod tests {
     fn build_request_loop_detection_adjacent() {
         let ctx = mock_context();
 
-        let decryptor = elgamal::Decryptor::from(&ctx.keys.private_key);
         let filter = Arc::new(Mutex::new(DecayingBloomFilter::new(10)));
         let (new_participating_tx, mut new_participating_rx) = mpsc::channel(1);
 
@@ -582,15 +646,15 @@ mod tests {
                 next_ident,
                 ParticipantType::Intermediate,
             );
-            vec![brr.encrypt(&elgamal::Encryptor::from(&ctx.keys.rid.public_key))]
+            vec![brr.encrypt(&elgamal::Encryptor::from(ctx.keys.rid.public_key()))]
         };
 
         let f = HopAcceptor::new(
             from_ident.clone(),
             tb,
             0,
-            decryptor,
             filter,
+            Transit::default(),
             new_participating_tx,
             ctx,
         );
@@ -612,7 +676,6 @@ mod tests {
     fn build_request_loop_detection_cycle() {
         let ctx = mock_context();
 
-        let decryptor = elgamal::Decryptor::from(&ctx.keys.private_key);
         let filter = Arc::new(Mutex::new(DecayingBloomFilter::new(10)));
         let (new_participating_tx, mut new_participating_rx) = mpsc::channel(1);
 
@@ -632,15 +695,15 @@ mod tests {
                 next_ident,
                 ParticipantType::Intermediate,
             );
-            vec![brr.encrypt(&elgamal::Encryptor::from(&ctx.keys.rid.public_key))]
+            vec![brr.encrypt(&elgamal::Encryptor::from(ctx.keys.rid.public_key()))]
         };
 
         let f = HopAcceptor::new(
             from_ident.clone(),
             tb,
             0,
-            decryptor,
             filter,
+            Transit::default(),
             new_participating_tx,
             ctx,
         );
@@ -0,0 +1,435 @@
+//! Traffic accounting for the tunnels registered at this router.
+//!
+//! Each registered tunnel has counters that the data path updates with atomic
+//! operations, so that counting never holds up forwarding. [`Accounting`] shares the
+//! counters with operators, and with the [`Transit`] limits that decide whether we can
+//! take on more participating tunnels.
+//!
+//! Rates are measured over windows of [`RATE_WINDOW`] seconds, from the traffic that
+//! arrives in each tunnel. Traffic that we drop is still counted as having arrived, so
+//! that the rates reflect what each tunnel asks of us.
+//!
+//! [`Transit`]: super::Transit
+
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, RwLock,
+};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::TunnelRole;
+use crate::data::TunnelId;
+
+/// The length of the windows over which rates are measured, in seconds.
+const RATE_WINDOW: u64 = 10;
+
+/// What we do in a tunnel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelUse {
+    /// We are the gateway of another router's inbound tunnel.
+    InboundGateway,
+    /// We are an intermediate hop of another router's tunnel.
+    Intermediate,
+    /// We are the endpoint of another router's outbound tunnel.
+    OutboundEndpoint,
+    /// We built the tunnel.
+    Own(TunnelRole),
+}
+
+impl TunnelUse {
+    pub fn is_participating(self) -> bool {
+        !matches!(self, TunnelUse::Own(_))
+    }
+}
+
+/// A snapshot of the counters for one tunnel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TunnelDetails {
+    pub tid: TunnelId,
+    pub usage: TunnelUse,
+    pub created: SystemTime,
+    /// Messages and bytes that arrived in the tunnel.
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    /// Messages and bytes that we sent on, or delivered from the tunnel.
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    /// Messages that arrived in the tunnel, and that we dropped for going over our
+    /// bandwidth share.
+    pub messages_dropped: u64,
+    /// The rate that traffic arrived at in the last complete window, in bytes per
+    /// second.
+    pub rate: u64,
+    /// The highest rate over any complete window, in bytes per second.
+    pub peak_rate: u64,
+}
+
+/// The traffic of the tunnels registered at this router.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TunnelStats {
+    /// The number of tunnels we are participating in.
+    pub participating: usize,
+    /// The number of tunnels we built.
+    pub own: usize,
+    /// The totals over every registered tunnel.
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+    pub messages_dropped: u64,
+    /// The total rate of the tunnels we are participating in, in bytes per second.
+    pub participating_rate: u64,
+    pub tunnels: Vec<TunnelDetails>,
+}
+
+/// The counters for a single tunnel.
+pub(super) struct TunnelCounters {
+    usage: TunnelUse,
+    created: SystemTime,
+    origin: Instant,
+    messages_in: AtomicU64,
+    bytes_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_dropped: AtomicU64,
+    /// The index of the current window, counted from `origin`.
+    window: AtomicU64,
+    window_bytes: AtomicU64,
+    previous_bytes: AtomicU64,
+    peak_bytes: AtomicU64,
+}
+
+impl TunnelCounters {
+    fn new(usage: TunnelUse, created: SystemTime, origin: Instant) -> Self {
+        TunnelCounters {
+            usage,
+            created,
+            origin,
+            messages_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            messages_dropped: AtomicU64::new(0),
+            window: AtomicU64::new(0),
+            window_bytes: AtomicU64::new(0),
+            previous_bytes: AtomicU64::new(0),
+            peak_bytes: AtomicU64::new(0),
+        }
+    }
+
+    fn window_at(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.origin).as_secs() / RATE_WINDOW
+    }
+
+    /// Records a message of `bytes` that arrived in the tunnel.
+    pub(super) fn received(&self, bytes: usize, now: Instant) {
+        let bytes = bytes as u64;
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+
+        // Whoever moves the window on is responsible for closing the previous one.
+        // Traffic that races with this can be counted in either window.
+        let window = self.window_at(now);
+        let current = self.window.load(Ordering::Relaxed);
+        if window > current
+            && self
+                .window
+                .compare_exchange(current, window, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let closed = self.window_bytes.swap(0, Ordering::Relaxed);
+            self.peak_bytes.fetch_max(closed, Ordering::Relaxed);
+            let previous = if window == current + 1 { closed } else { 0 };
+            self.previous_bytes.store(previous, Ordering::Relaxed);
+        }
+        self.window_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a message of `bytes` that we sent on, or delivered from the tunnel.
+    pub(super) fn sent(&self, bytes: usize) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records a message that arrived in the tunnel, and that we dropped.
+    pub(super) fn dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the bytes that arrived in the last complete window, and the most that
+    /// arrived in any complete window.
+    fn window_bytes(&self, now: Instant) -> (u64, u64) {
+        let window = self.window_at(now);
+        let current = self.window.load(Ordering::Relaxed);
+        let current_bytes = self.window_bytes.load(Ordering::Relaxed);
+        let peak = self.peak_bytes.load(Ordering::Relaxed);
+        if window == current {
+            (self.previous_bytes.load(Ordering::Relaxed), peak)
+        } else if window == current + 1 {
+            (current_bytes, peak.max(current_bytes))
+        } else {
+            (0, peak.max(current_bytes))
+        }
+    }
+
+    /// Returns the rate that traffic arrived at in the last complete window, in bytes
+    /// per second.
+    fn rate(&self, now: Instant) -> u64 {
+        self.window_bytes(now).0 / RATE_WINDOW
+    }
+
+    /// Returns how fast traffic is arriving in the tunnel, in bytes per second. Unlike
+    /// [`TunnelCounters::rate`], this also counts the traffic of the current window, so
+    /// that a tunnel which has only just got busy is noticed straight away.
+    pub(super) fn demand(&self, now: Instant) -> u64 {
+        let window = self.window_at(now);
+        let current = self.window.load(Ordering::Relaxed);
+        if window != current {
+            return self.window_bytes(now).0 / RATE_WINDOW;
+        }
+
+        let start = self.origin + Duration::from_secs(current * RATE_WINDOW);
+        let elapsed = (now.saturating_duration_since(start).as_millis() as u64).max(1000);
+        let previous = self.previous_bytes.load(Ordering::Relaxed) / RATE_WINDOW;
+        previous.max(self.window_bytes.load(Ordering::Relaxed) * 1000 / elapsed)
+    }
+
+    fn details(&self, tid: TunnelId, now: Instant) -> TunnelDetails {
+        let (recent, peak) = self.window_bytes(now);
+        TunnelDetails {
+            tid,
+            usage: self.usage,
+            created: self.created,
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+            rate: recent / RATE_WINDOW,
+            peak_rate: peak / RATE_WINDOW,
+        }
+    }
+}
+
+/// The counters of every registered tunnel.
+///
+/// This is a handle that can be cloned and shared between subsystems.
+#[derive(Clone)]
+pub struct Accounting {
+    origin: Instant,
+    tunnels: Arc<RwLock<HashMap<TunnelId, Arc<TunnelCounters>>>>,
+}
+
+impl Default for Accounting {
+    fn default() -> Self {
+        Accounting::new(Instant::now())
+    }
+}
+
+impl Accounting {
+    /// Creates an empty set of counters, measuring rates in windows that start at
+    /// `origin`.
+    pub fn new(origin: Instant) -> Self {
+        Accounting {
+            origin,
+            tunnels: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Starts counting the traffic of a tunnel, returning the counters for the data
+    /// path to update.
+    pub(super) fn register(
+        &self,
+        tid: TunnelId,
+        usage: TunnelUse,
+        created: SystemTime,
+    ) -> Arc<TunnelCounters> {
+        let counters = Arc::new(TunnelCounters::new(usage, created, self.origin));
+        self.tunnels.write().unwrap().insert(tid, counters.clone());
+        counters
+    }
+
+    pub(super) fn unregister(&self, tid: &TunnelId) {
+        self.tunnels.write().unwrap().remove(tid);
+    }
+
+    /// Returns the total rate of the tunnels we are participating in, in bytes per
+    /// second.
+    pub fn participating_rate(&self, now: Instant) -> u64 {
+        self.tunnels
+            .read()
+            .unwrap()
+            .values()
+            .filter(|counters| counters.usage.is_participating())
+            .map(|counters| counters.rate(now))
+            .sum()
+    }
+
+    /// Returns the number of tunnels we are participating in that have had traffic
+    /// recently.
+    pub(super) fn active_participating(&self, now: Instant) -> usize {
+        self.tunnels
+            .read()
+            .unwrap()
+            .values()
+            .filter(|counters| counters.usage.is_participating() && counters.demand(now) > 0)
+            .count()
+    }
+
+    /// Returns the totals and per-tunnel details of the traffic in our tunnels.
+    pub fn stats(&self, now: Instant) -> TunnelStats {
+        let tunnels = self.tunnels.read().unwrap();
+        let mut stats = TunnelStats::default();
+        for (tid, counters) in tunnels.iter() {
+            let details = counters.details(*tid, now);
+            if details.usage.is_participating() {
+                stats.participating += 1;
+                stats.participating_rate += details.rate;
+            } else {
+                stats.own += 1;
+            }
+            stats.messages_in += details.messages_in;
+            stats.bytes_in += details.bytes_in;
+            stats.messages_out += details.messages_out;
+            stats.bytes_out += details.bytes_out;
+            stats.messages_dropped += details.messages_dropped;
+            stats.tunnels.push(details);
+        }
+        stats.tunnels.sort_by_key(|details| details.created);
+        stats
+    }
+}
+
+/// Returns the time at which a tunnel that expires at `expires` was created.
+pub(super) fn created(expires: SystemTime) -> SystemTime {
+    expires - Duration::from_secs(super::TUNNEL_LIFETIME)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{Accounting, TunnelStats, TunnelUse, RATE_WINDOW};
+    use crate::data::TunnelId;
+    use crate::tunnel::TunnelRole;
+
+    #[test]
+    fn counters() {
+        let t0 = Instant::now();
+        let accounting = Accounting::new(t0);
+        let created = SystemTime::now();
+        let window = Duration::from_secs(RATE_WINDOW);
+
+        let counters = accounting.register(TunnelId(1), TunnelUse::Intermediate, created);
+        for _ in 0..10 {
+            counters.received(1024, t0);
+            counters.sent(1024);
+        }
+
+        // Rates are only known once a window is complete
+        let details = &accounting.stats(t0).tunnels[0];
+        assert_eq!((details.messages_in, details.bytes_in), (10, 10240));
+        assert_eq!((details.messages_out, details.bytes_out), (10, 10240));
+        assert_eq!((details.rate, details.peak_rate), (0, 0));
+        assert_eq!(accounting.participating_rate(t0 + window), 1024);
+
+        // A quieter window lowers the rate, but not the peak
+        counters.received(1024, t0 + window);
+        let details = &accounting.stats(t0 + 2 * window).tunnels[0];
+        assert_eq!((details.rate, details.peak_rate), (102, 1024));
+
+        // Idle windows bring the rate down to nothing
+        counters.received(1024, t0 + 5 * window);
+        let details = &accounting.stats(t0 + 5 * window).tunnels[0];
+        assert_eq!((details.rate, details.peak_rate), (0, 1024));
+        assert_eq!(details.messages_in, 12);
+    }
+
+    #[test]
+    fn demand() {
+        let t0 = Instant::now();
+        let accounting = Accounting::new(t0);
+        let created = SystemTime::now();
+        let second = Duration::from_secs(1);
+        let window = Duration::from_secs(RATE_WINDOW);
+
+        let counters = accounting.register(TunnelId(1), TunnelUse::Intermediate, created);
+        assert_eq!(counters.demand(t0), 0);
+        assert_eq!(accounting.active_participating(t0), 0);
+
+        // Demand is known before the first window completes
+        for _ in 0..4 {
+            counters.received(1024, t0);
+        }
+        assert_eq!(counters.demand(t0), 4096);
+        assert_eq!(counters.demand(t0 + 4 * second), 1024);
+        assert_eq!(accounting.active_participating(t0), 1);
+
+        // A quiet start to a window doesn't hide the previous one
+        counters.received(1024, t0 + window);
+        assert_eq!(counters.demand(t0 + window + 4 * second), 409);
+
+        // Idle tunnels have no demand
+        assert_eq!(counters.demand(t0 + 3 * window), 0);
+        assert_eq!(accounting.active_participating(t0 + 3 * window), 0);
+    }
+
+    #[test]
+    fn totals() {
+        let t0 = Instant::now();
+        let accounting = Accounting::new(t0);
+        let created = SystemTime::now();
+        let later = Duration::from_secs(RATE_WINDOW);
+
+        let ibgw = accounting.register(TunnelId(1), TunnelUse::InboundGateway, created);
+        let obep = accounting.register(
+            TunnelId(2),
+            TunnelUse::OutboundEndpoint,
+            created + Duration::from_secs(1),
+        );
+        let own = accounting.register(
+            TunnelId(3),
+            TunnelUse::Own(TunnelRole::Inbound),
+            created + Duration::from_secs(2),
+        );
+        ibgw.received(500, t0);
+        ibgw.sent(1024);
+        obep.received(1024, t0);
+        obep.sent(200);
+        ibgw.received(500, t0);
+        ibgw.dropped();
+        own.received(1024, t0);
+
+        let stats = accounting.stats(t0 + later);
+        assert_eq!(
+            stats.tunnels.iter().map(|d| d.tid).collect::<Vec<_>>(),
+            vec![TunnelId(1), TunnelId(2), TunnelId(3)]
+        );
+        assert_eq!(
+            TunnelStats {
+                tunnels: vec![],
+                ..stats
+            },
+            TunnelStats {
+                participating: 2,
+                own: 1,
+                messages_in: 4,
+                bytes_in: 3048,
+                messages_out: 2,
+                bytes_out: 1224,
+                messages_dropped: 1,
+                participating_rate: 100 + 102,
+                tunnels: vec![],
+            }
+        );
+
+        // Unregistered tunnels are no longer counted
+        accounting.unregister(&TunnelId(1));
+        accounting.unregister(&TunnelId(3));
+        let stats = accounting.stats(t0 + later);
+        assert_eq!((stats.participating, stats.own), (1, 0));
+        assert_eq!(stats.messages_dropped, 0);
+        assert_eq!(accounting.participating_rate(t0 + later), 102);
+    }
+}
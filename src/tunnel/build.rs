@@ -0,0 +1,1587 @@
+//! Logic for building our own tunnels.
+//!
+//! Each hop is sent a build request record encrypted to its router's encryption key.
+//! Every hop encrypts all of the records with its reply key before passing the request
+//! on, so we decrypt each record in advance with the reply keys of the hops before it;
+//! a hop then only finds its own record readable. The reply is decrypted in the same
+//! way to read each hop's response. The record encryption is in [`super::records`].
+//!
+//! If every hop has an ECIES-X25519 key and is recent enough, the request uses short
+//! records; otherwise every hop gets a long record.
+//!
+//! We are one end of every tunnel we build, so half of each build passes directly
+//! between us and the new tunnel: we send an outbound tunnel's request to its gateway,
+//! and an inbound tunnel's last hop sends the reply to us. The other half goes through
+//! one of our exploratory tunnels. An inbound tunnel's request is sent to its gateway
+//! through an outbound tunnel, and an outbound tunnel's endpoint sends the reply into
+//! an inbound tunnel. Only while we have no exploratory tunnels is an inbound
+//! tunnel's request sent directly, and an outbound tunnel's reply sent to a zero-hop
+//! inbound tunnel created for it.
+//!
+//! Each hop's response is recorded in its profile. Rejections lower the hop's capacity
+//! score, and a hop that rejects several builds in a row isn't selected for a while.
+//!
+//! See the ["Request Preparation" section][prep] of the tunnel creation specification
+//! for details.
+//!
+//! [prep]: https://geti2p.net/spec/tunnel-creation#request-preparation
+
+use futures::{
+    future, sink,
+    sync::{mpsc, oneshot},
+    Async, Future, Poll, Sink,
+};
+use rand::{seq::SliceRandom, Rng};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::{io, timer::Delay};
+use tokio_threadpool::blocking;
+
+use super::{
+    acceptor::{
+        TUNNEL_ACCEPT, TUNNEL_REJECT_BANDWIDTH, TUNNEL_REJECT_PROBABALISTIC_REJECT,
+        TUNNEL_REJECT_TRANSIENT_OVERLOAD,
+    },
+    crypto::LayerCipher,
+    gateway::OutboundGateway,
+    records::{decrypt_build_replies, encrypt_build_records, HopRequest, HopSecrets, RecordFormat},
+    registry::TunnelIds,
+    select::HopSelector,
+    OwnTunnel, TunnelHop, TunnelMessageDeliveryType, TunnelRole, TUNNEL_LIFETIME,
+};
+use crate::crypto::{
+    elgamal::Ephemeral,
+    pool::Precomputed,
+    rand::{CryptoRng, OsRng},
+};
+use crate::data::{Hash, RouterInfo, TunnelId};
+use crate::i2np::{Message, MessageIdGenerator, ParticipantType};
+use crate::netdb::client::SelectPeers;
+use crate::router::Context;
+
+type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+/// The longest tunnel we will build, not counting ourselves.
+pub(super) const MAX_HOPS: usize = 7;
+
+/// Build requests for tunnels with up to this many hops have this many records, and
+/// longer ones have [`MAX_BUILD_RECORDS`], so that hops can't tell how long the
+/// tunnel is.
+const MIN_BUILD_RECORDS: usize = 4;
+const MAX_BUILD_RECORDS: usize = 8;
+
+/// How long we wait for the reply to a build request.
+const BUILD_REPLY_TIMEOUT: u64 = 13;
+
+/// Tunnel build errors
+#[derive(Clone, Debug, PartialEq)]
+pub enum BuildError {
+    /// We can't build tunnels with this many hops.
+    InvalidLength(usize),
+    /// The netDb only had this many suitable peers.
+    NotEnoughPeers(usize),
+    /// We can't encrypt a build record to the peer's encryption key.
+    UnsupportedHop(Hash),
+    SendFailure,
+    TimedOut,
+    TimerFailure,
+    /// The reply couldn't be read.
+    InvalidReply,
+    /// At least one hop declined to participate. Gives each hop's response.
+    Rejected(Vec<(Hash, u8)>),
+    /// The exploratory tunnel that the build was to go through has expired, or isn't
+    /// one of ours.
+    NoSuchTunnel(TunnelId),
+    /// The netDb or tunnel subsystem has shut down.
+    Closed,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::InvalidLength(len) => write!(f, "Invalid tunnel length {}", len),
+            BuildError::NotEnoughPeers(found) => write!(f, "Only found {} suitable peers", found),
+            BuildError::UnsupportedHop(hash) => {
+                write!(f, "Peer {} has an unsupported encryption key", hash)
+            }
+            BuildError::SendFailure => "Send failure".fmt(f),
+            BuildError::TimedOut => "Build timed out".fmt(f),
+            BuildError::TimerFailure => "Timer failure".fmt(f),
+            BuildError::InvalidReply => "Invalid build reply".fmt(f),
+            BuildError::Rejected(_) => "Build rejected".fmt(f),
+            BuildError::NoSuchTunnel(tid) => write!(f, "No tunnel {} to build through", tid),
+            BuildError::Closed => "Tunnel subsystem closed".fmt(f),
+        }
+    }
+}
+
+/// Why a hop declined to join one of our tunnels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+    ProbabilisticReject,
+    TransientOverload,
+    Bandwidth,
+    /// The hop can't participate in any tunnels.
+    Critical,
+}
+
+impl RejectReason {
+    /// Returns the reason for a hop's response, or `None` if it agreed. Responses
+    /// between the defined codes are given the reason of the next highest code.
+    pub fn from_reply(reply: u8) -> Option<Self> {
+        match reply {
+            TUNNEL_ACCEPT => None,
+            r if r <= TUNNEL_REJECT_PROBABALISTIC_REJECT => Some(RejectReason::ProbabilisticReject),
+            r if r <= TUNNEL_REJECT_TRANSIENT_OVERLOAD => Some(RejectReason::TransientOverload),
+            r if r <= TUNNEL_REJECT_BANDWIDTH => Some(RejectReason::Bandwidth),
+            _ => Some(RejectReason::Critical),
+        }
+    }
+}
+
+/// The responses that hops have given to our build requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplyStats {
+    pub accepted: u64,
+    pub probabilistic_reject: u64,
+    pub transient_overload: u64,
+    pub bandwidth: u64,
+    pub critical: u64,
+}
+
+impl ReplyStats {
+    fn record(&mut self, reply: u8) {
+        let count = match RejectReason::from_reply(reply) {
+            None => &mut self.accepted,
+            Some(RejectReason::ProbabilisticReject) => &mut self.probabilistic_reject,
+            Some(RejectReason::TransientOverload) => &mut self.transient_overload,
+            Some(RejectReason::Bandwidth) => &mut self.bandwidth,
+            Some(RejectReason::Critical) => &mut self.critical,
+        };
+        *count += 1;
+    }
+}
+
+/// The records of a build message.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum BuildRecords {
+    /// From a VariableTunnelBuild or VariableTunnelBuildReply.
+    Long(Vec<[u8; 528]>),
+    /// From a ShortTunnelBuild or OutboundTunnelBuildReply.
+    Short(Vec<[u8; 218]>),
+}
+
+impl BuildRecords {
+    /// Returns `count` records of the given format, filled with random data.
+    fn random<R: CryptoRng>(format: RecordFormat, count: usize, rng: &mut R) -> Self {
+        match format {
+            RecordFormat::Long => BuildRecords::Long(
+                (0..count)
+                    .map(|_| {
+                        let mut record = [0; 528];
+                        rng.fill(&mut record[..]);
+                        record
+                    })
+                    .collect(),
+            ),
+            RecordFormat::Short => BuildRecords::Short(
+                (0..count)
+                    .map(|_| {
+                        let mut record = [0; 218];
+                        rng.fill(&mut record[..]);
+                        record
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    fn format(&self) -> RecordFormat {
+        match self {
+            BuildRecords::Long(_) => RecordFormat::Long,
+            BuildRecords::Short(_) => RecordFormat::Short,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            BuildRecords::Long(records) => records.len(),
+            BuildRecords::Short(records) => records.len(),
+        }
+    }
+
+    /// Returns a build request containing these records, with an ID from `ids`.
+    fn to_request(&self, ids: &MessageIdGenerator) -> Message {
+        match self {
+            BuildRecords::Long(records) => {
+                Message::variable_tunnel_build(ids.next_id(), records.clone())
+            }
+            BuildRecords::Short(records) => {
+                Message::short_tunnel_build(ids.next_id(), records.clone())
+            }
+        }
+    }
+}
+
+impl From<Vec<[u8; 528]>> for BuildRecords {
+    fn from(records: Vec<[u8; 528]>) -> Self {
+        BuildRecords::Long(records)
+    }
+}
+
+impl From<Vec<[u8; 218]>> for BuildRecords {
+    fn from(records: Vec<[u8; 218]>) -> Self {
+        BuildRecords::Short(records)
+    }
+}
+
+/// Build replies we are waiting for, indexed by the ID of the message they will
+/// arrive in.
+#[derive(Clone, Default)]
+pub struct PendingReplies(Arc<Mutex<HashMap<u32, (Hash, oneshot::Sender<BuildRecords>)>>>);
+
+impl PendingReplies {
+    /// Waits for a reply from `from` in a message with the given ID.
+    fn register(&self, msg_id: u32, from: Hash) -> oneshot::Receiver<BuildRecords> {
+        let (tx, rx) = oneshot::channel();
+        self.0.lock().unwrap().insert(msg_id, (from, tx));
+        rx
+    }
+
+    fn cancel(&self, msg_id: u32) {
+        self.0.lock().unwrap().remove(&msg_id);
+    }
+
+    /// Passes the records of a build message to the build that is waiting for them.
+    /// If no build is waiting for this message from this peer, the records are given
+    /// back.
+    pub(super) fn deliver<T: Into<BuildRecords>>(
+        &self,
+        from: &Hash,
+        msg_id: u32,
+        records: T,
+    ) -> Result<(), T> {
+        let mut pending = self.0.lock().unwrap();
+        match pending.get(&msg_id) {
+            Some((expected, _)) if expected == from => {
+                let (_, tx) = pending.remove(&msg_id).unwrap();
+                // The build may have given up in the meantime
+                let _ = tx.send(records.into());
+                Ok(())
+            }
+            _ => Err(records),
+        }
+    }
+}
+
+/// A build request, along with what we need to process its reply.
+struct Request {
+    role: TunnelRole,
+    /// The tunnel ID under which we register the tunnel.
+    our_tid: TunnelId,
+    reply_msg_id: u32,
+    /// The tunnel ID under which each hop receives messages.
+    receive_tids: Vec<TunnelId>,
+    hops: Vec<HopSecrets>,
+    first_hop: RouterInfo,
+    records: BuildRecords,
+}
+
+fn random_tid<R: CryptoRng>(rng: &mut R) -> TunnelId {
+    // Zero is not a valid tunnel ID
+    TunnelId(rng.gen_range(1..=u32::MAX))
+}
+
+impl Request {
+    /// Prepares a request to build a tunnel through `hops`, ordered from the gateway
+    /// to the endpoint, which we will register under a tunnel ID allocated from `ids`.
+    ///
+    /// The last hop sends the reply into the inbound tunnel at `reply_gateway`, given
+    /// as the gateway and its tunnel ID, or directly to us if there is none.
+    fn new<R: CryptoRng>(
+        role: TunnelRole,
+        hops: &[RouterInfo],
+        us: &Hash,
+        reply_gateway: Option<&(Hash, TunnelId)>,
+        ids: &TunnelIds,
+        ephemerals: &Precomputed<Ephemeral>,
+        rng: &mut R,
+    ) -> Result<Self, BuildError> {
+        if hops.is_empty() || hops.len() > MAX_HOPS {
+            return Err(BuildError::InvalidLength(hops.len()));
+        }
+
+        let our_tid = ids.allocate(rng, SystemTime::now());
+        let reply_msg_id = rng.next_u32();
+        let receive_tids: Vec<_> = hops.iter().map(|_| random_tid(rng)).collect();
+
+        // Unused records are filled with random data, and the hops' records are
+        // placed at random.
+        let num_records = if hops.len() <= MIN_BUILD_RECORDS {
+            MIN_BUILD_RECORDS
+        } else {
+            MAX_BUILD_RECORDS
+        };
+        let mut records = BuildRecords::random(RecordFormat::for_hops(hops), num_records, rng);
+        let mut positions: Vec<_> = (0..num_records).collect();
+        positions.shuffle(rng);
+
+        let last = hops.len() - 1;
+        let requests: Vec<_> = hops
+            .iter()
+            .enumerate()
+            .map(|(i, ri)| {
+                // The last hop sends the reply
+                let (next_tid, next_ident, send_msg_id) = if i == last {
+                    match reply_gateway {
+                        Some((gateway, tid)) => (*tid, gateway.clone(), reply_msg_id),
+                        None => (our_tid, us.clone(), reply_msg_id),
+                    }
+                } else {
+                    (
+                        receive_tids[i + 1],
+                        hops[i + 1].router_id.hash(),
+                        rng.next_u32(),
+                    )
+                };
+                HopRequest {
+                    ident: ri.router_id.hash(),
+                    key: ri.router_id.encryption_key(),
+                    record: positions[i],
+                    receive_tid: receive_tids[i],
+                    next_tid,
+                    next_ident,
+                    hop_type: match role {
+                        TunnelRole::Inbound if i == 0 => ParticipantType::InboundGateway,
+                        TunnelRole::Outbound if i == last => ParticipantType::OutboundEndpoint,
+                        _ => ParticipantType::Intermediate,
+                    },
+                    send_msg_id,
+                }
+            })
+            .collect();
+        let secrets = match &mut records {
+            BuildRecords::Long(records) => {
+                encrypt_build_records(&requests, records, ephemerals, rng)?
+            }
+            BuildRecords::Short(records) => {
+                encrypt_build_records(&requests, records, ephemerals, rng)?
+            }
+        };
+
+        Ok(Request {
+            role,
+            our_tid,
+            reply_msg_id,
+            receive_tids,
+            hops: secrets,
+            first_hop: hops[0].clone(),
+            records,
+        })
+    }
+
+    /// Reads each hop's response from the records of the build reply.
+    fn responses(&self, records: BuildRecords) -> Result<Vec<(Hash, u8)>, BuildError> {
+        if records.format() != self.records.format() || records.len() != self.records.len() {
+            return Err(BuildError::InvalidReply);
+        }
+        match records {
+            BuildRecords::Long(mut records) => decrypt_build_replies(&self.hops, &mut records),
+            BuildRecords::Short(mut records) => decrypt_build_replies(&self.hops, &mut records),
+        }
+    }
+
+    fn into_tunnel(self) -> (TunnelId, OwnTunnel) {
+        let tunnel = OwnTunnel {
+            role: self.role,
+            hops: self
+                .hops
+                .into_iter()
+                .zip(self.receive_tids.into_iter())
+                .map(|(hop, receive_tid)| TunnelHop {
+                    ident: hop.ident,
+                    receive_tid,
+                    layer_cipher: LayerCipher::new(&hop.iv_key, hop.layer_key),
+                })
+                .collect(),
+            first_hop: self.first_hop,
+            expires: SystemTime::now() + Duration::from_secs(TUNNEL_LIFETIME),
+        };
+        (self.our_tid, tunnel)
+    }
+}
+
+/// A tunnel that we built.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuiltTunnel {
+    pub role: TunnelRole,
+    /// The ID under which the tunnel was registered.
+    pub tid: TunnelId,
+    /// The routers in the tunnel, from the gateway to the endpoint.
+    pub hops: Vec<Hash>,
+}
+
+/// How a build gets between us and the end of the new tunnel that we are not.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildPath {
+    /// For bootstrapping, before we have any exploratory tunnels. An inbound tunnel's
+    /// request is sent directly to its gateway, and an outbound tunnel's reply is sent
+    /// to a zero-hop inbound tunnel that is created for it.
+    Direct,
+    /// Through one of our exploratory tunnels, registered under the given ID: an
+    /// outbound tunnel that carries an inbound tunnel's request to its gateway, or an
+    /// inbound tunnel that brings an outbound tunnel's reply back to us.
+    Exploratory(TunnelId),
+}
+
+/// An outbound tunnel that requests can be sent through.
+struct RequestTunnel {
+    /// The tunnel's gateway, and its first hop. `None` for a zero-hop tunnel, which
+    /// ends at us, so requests are sent directly.
+    gateway: Option<(OutboundGateway, RouterInfo)>,
+    expires: SystemTime,
+}
+
+/// An inbound tunnel that replies can be sent into.
+#[derive(Clone)]
+struct ReplyTunnel {
+    /// The gateway, and the tunnel ID that messages are sent to it under.
+    gateway: (Hash, TunnelId),
+    /// The hop that passes the tunnel's messages on to us. `None` for a zero-hop
+    /// tunnel, into which the sender passes messages to us itself.
+    last_hop: Option<Hash>,
+    expires: SystemTime,
+}
+
+/// The tunnels we have built, which later builds can go through.
+#[derive(Default)]
+struct Routes {
+    outbound: HashMap<TunnelId, RequestTunnel>,
+    inbound: HashMap<TunnelId, ReplyTunnel>,
+}
+
+impl Routes {
+    /// Adds a tunnel that we registered as `tid`, and drops the ones that have
+    /// expired.
+    fn insert(&mut self, tid: TunnelId, tunnel: &OwnTunnel, msg_ids: &MessageIdGenerator) {
+        let now = SystemTime::now();
+        self.outbound.retain(|_, t| t.expires > now);
+        self.inbound.retain(|_, t| t.expires > now);
+
+        match tunnel.role {
+            TunnelRole::Outbound => {
+                let gateway = if tunnel.is_zero_hop() {
+                    None
+                } else {
+                    OutboundGateway::new(tid, tunnel, msg_ids.clone())
+                        .map(|gateway| (gateway, tunnel.first_hop.clone()))
+                };
+                self.outbound.insert(
+                    tid,
+                    RequestTunnel {
+                        gateway,
+                        expires: tunnel.expires,
+                    },
+                );
+            }
+            TunnelRole::Inbound => {
+                let reply = match (tunnel.hops.first(), tunnel.hops.last()) {
+                    (Some(first), Some(last)) => ReplyTunnel {
+                        gateway: (first.ident.clone(), first.receive_tid),
+                        last_hop: Some(last.ident.clone()),
+                        expires: tunnel.expires,
+                    },
+                    _ => ReplyTunnel {
+                        gateway: (tunnel.first_hop.router_id.hash(), tid),
+                        last_hop: None,
+                        expires: tunnel.expires,
+                    },
+                };
+                self.inbound.insert(tid, reply);
+            }
+        }
+    }
+}
+
+/// Builds our own tunnels, and registers the ones that are built successfully.
+///
+/// Each build goes through the exploratory tunnel given by its [`BuildPath`], if it
+/// has one. The tunnels that this `Creator` and its clones have built can be given.
+/// Requests are sent in a ShortTunnelBuild message if every hop supports short build
+/// records, and in a VariableTunnelBuild message otherwise.
+#[derive(Clone)]
+pub struct Creator {
+    ctx: Arc<Context>,
+    replies: PendingReplies,
+    new_tunnel_tx: mpsc::Sender<(TunnelId, OwnTunnel)>,
+    ids: TunnelIds,
+    routes: Arc<Mutex<Routes>>,
+    reply_stats: Arc<Mutex<ReplyStats>>,
+}
+
+impl Creator {
+    /// Creates a `Creator` that waits for build replies in `replies`, which must be
+    /// shared with our [`Listener`](super::Listener). Our tunnels are registered under
+    /// IDs allocated from `ids`.
+    pub fn new(
+        ctx: Arc<Context>,
+        replies: PendingReplies,
+        ids: TunnelIds,
+        new_tunnel_tx: mpsc::Sender<(TunnelId, OwnTunnel)>,
+    ) -> Self {
+        Creator {
+            ctx,
+            replies,
+            new_tunnel_tx,
+            ids,
+            routes: Arc::new(Mutex::new(Routes::default())),
+            reply_stats: Arc::new(Mutex::new(ReplyStats::default())),
+        }
+    }
+
+    /// Returns the responses that hops have given to the requests of this `Creator`
+    /// and its clones.
+    pub fn reply_stats(&self) -> ReplyStats {
+        *self.reply_stats.lock().unwrap()
+    }
+
+    /// Records each hop's response to a build request, in its profile and in our
+    /// counters.
+    fn record_responses(&self, responses: &[(Hash, u8)], now: SystemTime) {
+        let mut stats = self.reply_stats.lock().unwrap();
+        for (hop, reply) in responses {
+            stats.record(*reply);
+            match RejectReason::from_reply(*reply) {
+                None => self.ctx.profiles.tunnel_build_agreed(hop, now),
+                Some(reason) => {
+                    debug!("Hop {} rejected our build request: {:?}", hop, reason);
+                    self.ctx.profiles.tunnel_build_rejected(
+                        hop,
+                        reason == RejectReason::Critical,
+                        now,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Builds a tunnel through peers chosen from the netDb by `selector`, with the
+    /// half of the build that doesn't pass through the new tunnel going by `path`.
+    pub fn build(&self, role: TunnelRole, selector: &HopSelector, path: BuildPath) -> BuildTunnel {
+        let count = selector.hop_count(&mut OsRng);
+        BuildTunnel {
+            state: Some(BuildState::Selecting(
+                self.ctx.netdb.select_peers(selector.criteria(count)),
+            )),
+            creator: self.clone(),
+            role,
+            selector: selector.clone(),
+            count,
+            path,
+            reply_tunnel: None,
+            reply_msg_id: None,
+            timeout: None,
+        }
+    }
+
+    /// Returns a zero-hop tunnel, in which we are both the gateway and the endpoint.
+    fn zero_hop_tunnel(&self, role: TunnelRole) -> (TunnelId, OwnTunnel) {
+        let tid = self.ids.allocate(&mut OsRng, SystemTime::now());
+        let tunnel = OwnTunnel {
+            role,
+            hops: vec![],
+            first_hop: self.ctx.ri.read().unwrap().clone(),
+            expires: SystemTime::now() + Duration::from_secs(TUNNEL_LIFETIME),
+        };
+        (tid, tunnel)
+    }
+
+    /// Creates a zero-hop tunnel, in which we are both the gateway and the endpoint.
+    ///
+    /// No build messages are sent, so this works before we know any peers, but the
+    /// tunnel gives us no anonymity.
+    pub fn zero_hop(&self, role: TunnelRole) -> BuildTunnel {
+        let (tid, tunnel) = self.zero_hop_tunnel(role);
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(tid, &tunnel, &self.ctx.msg_ids);
+        let built = BuiltTunnel {
+            role,
+            tid,
+            hops: vec![],
+        };
+        BuildTunnel {
+            state: Some(BuildState::Registering(
+                self.new_tunnel_tx.clone().send((tid, tunnel)),
+                built,
+            )),
+            creator: self.clone(),
+            role,
+            selector: HopSelector::new(0),
+            count: 0,
+            path: BuildPath::Direct,
+            reply_tunnel: None,
+            reply_msg_id: None,
+            timeout: None,
+        }
+    }
+
+    /// Returns the inbound tunnel registered as `tid`, for a reply to be sent into.
+    fn reply_tunnel(&self, tid: TunnelId) -> Result<ReplyTunnel, BuildError> {
+        self.routes
+            .lock()
+            .unwrap()
+            .inbound
+            .get(&tid)
+            .filter(|t| t.expires > SystemTime::now())
+            .cloned()
+            .ok_or(BuildError::NoSuchTunnel(tid))
+    }
+
+    /// Sends `msg` to `to`, through the outbound tunnel registered as `tid`.
+    fn send_through(
+        &self,
+        tid: TunnelId,
+        to: RouterInfo,
+        msg: Message,
+    ) -> Result<IoFuture<()>, BuildError> {
+        let mut routes = self.routes.lock().unwrap();
+        let (gateway, first_hop) = match routes.outbound.get_mut(&tid) {
+            Some(t) if t.expires > SystemTime::now() => match t.gateway.as_mut() {
+                Some((gateway, first_hop)) => (gateway, first_hop.clone()),
+                // We are the endpoint of a zero-hop tunnel
+                None => return self.send_directly(to, msg),
+            },
+            _ => return Err(BuildError::NoSuchTunnel(tid)),
+        };
+
+        if let Err(e) =
+            gateway.push_priority(TunnelMessageDeliveryType::Router(to.router_id.hash()), &msg)
+        {
+            warn!("Could not send build request through tunnel {}: {}", tid, e);
+            return Err(BuildError::SendFailure);
+        }
+        let comms = self.ctx.comms.read().unwrap();
+        let mut sent = vec![];
+        for td in gateway.flush() {
+            match comms.send(first_hop.clone(), td) {
+                Ok(f) => sent.push(f),
+                Err((ri, _)) => {
+                    warn!(
+                        "Could not send to {} over any of our transports",
+                        ri.router_id.hash()
+                    );
+                    return Err(BuildError::SendFailure);
+                }
+            }
+        }
+        Ok(Box::new(future::join_all(sent).map(|_| ())))
+    }
+
+    fn send_directly(&self, to: RouterInfo, msg: Message) -> Result<IoFuture<()>, BuildError> {
+        self.ctx
+            .comms
+            .read()
+            .unwrap()
+            .send(to, msg)
+            .map_err(|(ri, _)| {
+                warn!(
+                    "Could not send build request to {} over any of our transports",
+                    ri.router_id.hash()
+                );
+                BuildError::SendFailure
+            })
+    }
+}
+
+enum BuildState {
+    Selecting(SelectPeers),
+    Preparing(Vec<RouterInfo>),
+    /// Registering the zero-hop tunnel that the reply to a bootstrapping outbound
+    /// build is sent to.
+    RegisteringReply(sink::Send<mpsc::Sender<(TunnelId, OwnTunnel)>>, Request),
+    Sending(IoFuture<()>, Request, oneshot::Receiver<BuildRecords>),
+    Waiting(Request, oneshot::Receiver<BuildRecords>),
+    Registering(sink::Send<mpsc::Sender<(TunnelId, OwnTunnel)>>, BuiltTunnel),
+}
+
+/// A [`Future`] that builds a single tunnel.
+///
+/// Build records are encrypted using the [`blocking()`] threadpool.
+pub struct BuildTunnel {
+    state: Option<BuildState>,
+    creator: Creator,
+    role: TunnelRole,
+    selector: HopSelector,
+    count: usize,
+    path: BuildPath,
+    /// The inbound tunnel that an outbound tunnel's reply is sent into, and the
+    /// zero-hop tunnel to register for it if we are bootstrapping.
+    reply_tunnel: Option<(ReplyTunnel, Option<(TunnelId, OwnTunnel)>)>,
+    reply_msg_id: Option<u32>,
+    timeout: Option<Delay>,
+}
+
+impl BuildTunnel {
+    /// Chooses the inbound tunnel that an outbound tunnel's reply will be sent into.
+    fn choose_reply_tunnel(&mut self) -> Result<(), BuildError> {
+        self.reply_tunnel = match (self.role, self.path) {
+            (TunnelRole::Inbound, _) => None,
+            (TunnelRole::Outbound, BuildPath::Exploratory(tid)) => {
+                Some((self.creator.reply_tunnel(tid)?, None))
+            }
+            (TunnelRole::Outbound, BuildPath::Direct) => {
+                let (tid, tunnel) = self.creator.zero_hop_tunnel(TunnelRole::Inbound);
+                let reply = ReplyTunnel {
+                    gateway: (tunnel.first_hop.router_id.hash(), tid),
+                    last_hop: None,
+                    expires: tunnel.expires,
+                };
+                Some((reply, Some((tid, tunnel))))
+            }
+        };
+        Ok(())
+    }
+
+    /// Sends the request to the first hop, and starts waiting for the reply.
+    fn send(&mut self, request: Request) -> Result<BuildState, BuildError> {
+        // The reply reaches us from the last hop of the tunnel it comes back through
+        let from = match &self.reply_tunnel {
+            Some((
+                ReplyTunnel {
+                    last_hop: Some(hop),
+                    ..
+                },
+                _,
+            )) => hop.clone(),
+            _ => request.hops[request.hops.len() - 1].ident.clone(),
+        };
+        let reply_rx = self.creator.replies.register(request.reply_msg_id, from);
+        self.reply_msg_id = Some(request.reply_msg_id);
+
+        let msg = request.records.to_request(&self.creator.ctx.msg_ids);
+        let first_hop = request.first_hop.clone();
+        let f = match (self.role, self.path) {
+            (TunnelRole::Inbound, BuildPath::Exploratory(tid)) => {
+                self.creator.send_through(tid, first_hop, msg)?
+            }
+            _ => self.creator.send_directly(first_hop, msg)?,
+        };
+        self.timeout = Some(Delay::new(
+            Instant::now() + Duration::from_secs(BUILD_REPLY_TIMEOUT),
+        ));
+        Ok(BuildState::Sending(f, request, reply_rx))
+    }
+}
+
+impl Drop for BuildTunnel {
+    fn drop(&mut self) {
+        if let Some(msg_id) = self.reply_msg_id {
+            self.creator.replies.cancel(msg_id);
+        }
+    }
+}
+
+impl Future for BuildTunnel {
+    type Item = BuiltTunnel;
+    type Error = BuildError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            // The timeout starts once the request has been prepared
+            if let Some(timeout) = self.timeout.as_mut() {
+                match timeout.poll() {
+                    Ok(Async::Ready(())) => return Err(BuildError::TimedOut),
+                    Ok(Async::NotReady) => (),
+                    Err(_) => return Err(BuildError::TimerFailure),
+                }
+            }
+
+            let next_state = match self.state.take().expect("Polled after completion") {
+                BuildState::Selecting(mut f) => match f.poll() {
+                    Ok(Async::Ready(peers)) => {
+                        let peers = self.selector.finish(self.count, peers)?;
+                        self.choose_reply_tunnel()?;
+                        BuildState::Preparing(peers)
+                    }
+                    Ok(Async::NotReady) => {
+                        self.state = Some(BuildState::Selecting(f));
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => return Err(BuildError::Closed),
+                },
+                BuildState::Preparing(peers) => {
+                    let us = self.creator.ctx.keys.rid.hash();
+                    let role = self.role;
+                    let reply_gateway = self.reply_tunnel.as_ref().map(|(t, _)| &t.gateway);
+                    let ids = &self.creator.ids;
+                    let ephemerals = &self.creator.ctx.pools.elgamal;
+                    let request = match blocking(|| {
+                        Request::new(
+                            role,
+                            &peers,
+                            &us,
+                            reply_gateway,
+                            ids,
+                            ephemerals,
+                            &mut OsRng,
+                        )
+                    }) {
+                        Ok(Async::Ready(request)) => request?,
+                        Ok(Async::NotReady) => {
+                            self.state = Some(BuildState::Preparing(peers));
+                            return Ok(Async::NotReady);
+                        }
+                        Err(e) => {
+                            error!("Failed to prepare build request: {}", e);
+                            return Err(BuildError::Closed);
+                        }
+                    };
+
+                    match self
+                        .reply_tunnel
+                        .as_mut()
+                        .and_then(|(_, zero_hop)| zero_hop.take())
+                    {
+                        Some(zero_hop) => BuildState::RegisteringReply(
+                            self.creator.new_tunnel_tx.clone().send(zero_hop),
+                            request,
+                        ),
+                        None => self.send(request)?,
+                    }
+                }
+                BuildState::RegisteringReply(mut f, request) => match f.poll() {
+                    Ok(Async::Ready(_)) => self.send(request)?,
+                    Ok(Async::NotReady) => {
+                        self.state = Some(BuildState::RegisteringReply(f, request));
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => return Err(BuildError::Closed),
+                },
+                BuildState::Sending(mut f, request, reply_rx) => match f.poll() {
+                    Ok(Async::Ready(())) => BuildState::Waiting(request, reply_rx),
+                    Ok(Async::NotReady) => {
+                        self.state = Some(BuildState::Sending(f, request, reply_rx));
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        warn!("Failed to send build request: {}", e);
+                        return Err(BuildError::SendFailure);
+                    }
+                },
+                BuildState::Waiting(request, mut reply_rx) => match reply_rx.poll() {
+                    Ok(Async::Ready(records)) => {
+                        self.timeout = None;
+                        let responses = request.responses(records)?;
+                        self.creator.record_responses(&responses, SystemTime::now());
+                        if responses.iter().any(|(_, reply)| *reply != TUNNEL_ACCEPT) {
+                            return Err(BuildError::Rejected(responses));
+                        }
+
+                        let (tid, tunnel) = request.into_tunnel();
+                        self.creator.routes.lock().unwrap().insert(
+                            tid,
+                            &tunnel,
+                            &self.creator.ctx.msg_ids,
+                        );
+                        let built = BuiltTunnel {
+                            role: tunnel.role,
+                            tid,
+                            hops: tunnel.hops(),
+                        };
+                        BuildState::Registering(
+                            self.creator.new_tunnel_tx.clone().send((tid, tunnel)),
+                            built,
+                        )
+                    }
+                    Ok(Async::NotReady) => {
+                        self.state = Some(BuildState::Waiting(request, reply_rx));
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => return Err(BuildError::Closed),
+                },
+                BuildState::Registering(mut f, built) => match f.poll() {
+                    Ok(Async::Ready(_)) => {
+                        debug!(
+                            "Built {:?} tunnel {} through {} hops",
+                            built.role,
+                            built.tid.0,
+                            built.hops.len()
+                        );
+                        return Ok(Async::Ready(built));
+                    }
+                    Ok(Async::NotReady) => {
+                        self.state = Some(BuildState::Registering(f, built));
+                        return Ok(Async::NotReady);
+                    }
+                    Err(_) => return Err(BuildError::Closed),
+                },
+            };
+            self.state = Some(next_state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Future, Sink, Stream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+    use tokio::runtime::Runtime;
+
+    use super::{
+        BuildError, BuildPath, BuildRecords, Creator, PendingReplies, RejectReason, ReplyStats,
+        Request, TUNNEL_ACCEPT,
+    };
+    use crate::crypto::{pool::Pools, rand::TestRng, EncType};
+    use crate::data::{Hash, RouterInfo, RouterInfoBuilder, RouterSecretKeys, TunnelId};
+    use crate::i2np::{MessageType, ParticipantType};
+    use crate::netdb::mock::MockNetDb;
+    use crate::router::{
+        mock::{loopback_context_and_netdb, loopback_context_and_netdb_with_keys, LoopbackPeers},
+        types::Distributor,
+        Context, Dispatcher,
+    };
+    use crate::tunnel::records::{
+        decrypt_my_record, encrypt_build_reply, DecryptedRecord, RecordFormat,
+    };
+    use crate::tunnel::{
+        HopData, HopSelector, Listener, OwnTunnel, Participant, Transit, TunnelDispatcher,
+        TunnelIdState, TunnelIds, TunnelRole, TunnelUse,
+    };
+
+    fn hop() -> (RouterSecretKeys, RouterInfo) {
+        hop_with_enc_type(EncType::ElGamal2048)
+    }
+
+    fn hop_with_enc_type(enc_type: EncType) -> (RouterSecretKeys, RouterInfo) {
+        let rsk = RouterSecretKeys::with_enc_type(enc_type);
+        let ri = RouterInfoBuilder::new(rsk.rid.clone()).sign(&rsk.signing_private_key);
+        (rsk, ri)
+    }
+
+    /// An ECIES-X25519 hop that supports short build records.
+    fn short_record_hop() -> (RouterSecretKeys, RouterInfo) {
+        let rsk = RouterSecretKeys::with_enc_type(EncType::X25519);
+        let ri = RouterInfoBuilder::new(rsk.rid.clone())
+            .option("router.version", "0.9.51")
+            .sign(&rsk.signing_private_key);
+        (rsk, ri)
+    }
+
+    /// Processes a build request as the given hop would, responding with `reply`.
+    fn process(rsk: &RouterSecretKeys, records: &mut BuildRecords, reply: u8) -> DecryptedRecord {
+        match records {
+            BuildRecords::Long(records) => process_records(rsk, records, reply),
+            BuildRecords::Short(records) => process_records(rsk, records, reply),
+        }
+    }
+
+    fn process_records<T: AsRef<[u8]> + AsMut<[u8]>>(
+        rsk: &RouterSecretKeys,
+        records: &mut [T],
+        reply: u8,
+    ) -> DecryptedRecord {
+        let hash = rsk.rid.hash();
+        let i = records
+            .iter()
+            .position(|record| record.as_ref()[0..16] == hash.0[0..16])
+            .expect("Hop can find its record");
+        let brr = decrypt_my_record(records[i].as_ref(), &rsk.private_key).unwrap();
+        encrypt_build_reply(&brr.reply, i, records, reply);
+        brr
+    }
+
+    #[test]
+    fn layered_records() {
+        let mut rng = TestRng::from_seed([7; 32]);
+        let ids = TunnelIds::default();
+        let pools = Pools::new();
+        let us = Hash([1; 32]);
+        let gateway = (Hash([2; 32]), TunnelId(3));
+
+        for &(role, len) in &[
+            (TunnelRole::Outbound, 1),
+            (TunnelRole::Inbound, 1),
+            (TunnelRole::Outbound, 3),
+            (TunnelRole::Inbound, 5),
+        ] {
+            // Alternate between ElGamal and ECIES hops
+            let hops: Vec<_> = (0..len)
+                .map(|i| {
+                    hop_with_enc_type(if i % 2 == 0 {
+                        EncType::ElGamal2048
+                    } else {
+                        EncType::X25519
+                    })
+                })
+                .collect();
+            let ris: Vec<_> = hops.iter().map(|(_, ri)| ri.clone()).collect();
+            // Outbound tunnels send the reply into one of our inbound tunnels
+            let reply_gateway = match role {
+                TunnelRole::Inbound => None,
+                TunnelRole::Outbound => Some(&gateway),
+            };
+            let request = Request::new(
+                role,
+                &ris,
+                &us,
+                reply_gateway,
+                &ids,
+                &pools.elgamal,
+                &mut rng,
+            )
+            .unwrap();
+            assert_eq!(request.records.format(), RecordFormat::Long);
+            assert_eq!(request.records.len(), if len <= 4 { 4 } else { 8 });
+
+            // Each hop can read its own record in turn, and the middle hop rejects
+            let mut records = request.records.clone();
+            for (i, (rsk, _)) in hops.iter().enumerate() {
+                let reply = if i == len / 2 { 30 } else { TUNNEL_ACCEPT };
+                let brr = process(rsk, &mut records, reply);
+
+                let expected_type = match role {
+                    TunnelRole::Inbound if i == 0 => ParticipantType::InboundGateway,
+                    TunnelRole::Outbound if i == len - 1 => ParticipantType::OutboundEndpoint,
+                    _ => ParticipantType::Intermediate,
+                };
+                assert_eq!(brr.hop_type, expected_type);
+                assert_eq!(brr.receive_tid, request.receive_tids[i]);
+                if i == len - 1 {
+                    // The last hop replies to us, or into the reply tunnel
+                    let (next_ident, next_tid) = match role {
+                        TunnelRole::Inbound => (us.clone(), request.our_tid),
+                        TunnelRole::Outbound => gateway.clone(),
+                    };
+                    assert_eq!(brr.next_ident, next_ident);
+                    assert_eq!(brr.next_tid, next_tid);
+                    assert_eq!(brr.send_msg_id, request.reply_msg_id);
+                } else {
+                    assert_eq!(brr.next_ident, ris[i + 1].router_id.hash());
+                    assert_eq!(brr.next_tid, request.receive_tids[i + 1]);
+                }
+            }
+
+            // We can read every hop's response from the reply
+            let responses = request.responses(records).unwrap();
+            let expected: Vec<_> = ris
+                .iter()
+                .enumerate()
+                .map(|(i, ri)| {
+                    let reply = if i == len / 2 { 30 } else { TUNNEL_ACCEPT };
+                    (ri.router_id.hash(), reply)
+                })
+                .collect();
+            assert_eq!(responses, expected);
+        }
+    }
+
+    #[test]
+    fn short_records() {
+        let mut rng = TestRng::from_seed([7; 32]);
+        let ids = TunnelIds::default();
+        let pools = Pools::new();
+        let us = Hash([1; 32]);
+
+        // Requests use short records only if every hop supports them. Mixed tunnels
+        // fall back to long records for every hop.
+        let hops: Vec<_> = (0..3).map(|_| short_record_hop()).collect();
+        let ris: Vec<_> = hops.iter().map(|(_, ri)| ri.clone()).collect();
+        for (_, old_ri) in [hop_with_enc_type(EncType::X25519), hop()].iter() {
+            let mixed = [&ris[..2], &[old_ri.clone()][..]].concat();
+            let request = Request::new(
+                TunnelRole::Outbound,
+                &mixed,
+                &us,
+                None,
+                &ids,
+                &pools.elgamal,
+                &mut rng,
+            )
+            .unwrap();
+            assert_eq!(request.records.format(), RecordFormat::Long);
+        }
+
+        for &role in &[TunnelRole::Outbound, TunnelRole::Inbound] {
+            let request =
+                Request::new(role, &ris, &us, None, &ids, &pools.elgamal, &mut rng).unwrap();
+            assert_eq!(request.records.format(), RecordFormat::Short);
+            assert_eq!(request.records.len(), 4);
+
+            let mut records = request.records.clone();
+            for (i, (rsk, _)) in hops.iter().enumerate() {
+                let reply = if i == 1 { 30 } else { TUNNEL_ACCEPT };
+                let brr = process(rsk, &mut records, reply);
+                assert_eq!(brr.receive_tid, request.receive_tids[i]);
+            }
+            assert_eq!(
+                request.responses(records).unwrap(),
+                vec![
+                    (ris[0].router_id.hash(), TUNNEL_ACCEPT),
+                    (ris[1].router_id.hash(), 30),
+                    (ris[2].router_id.hash(), TUNNEL_ACCEPT),
+                ]
+            );
+
+            // A reply with long records can't be read
+            assert_eq!(
+                request.responses(BuildRecords::Long(vec![[0; 528]; 4])),
+                Err(BuildError::InvalidReply)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let mut rng = TestRng::from_seed([7; 32]);
+        let ids = TunnelIds::default();
+        let pools = Pools::new();
+        let us = Hash([1; 32]);
+
+        assert_eq!(
+            Request::new(
+                TunnelRole::Outbound,
+                &[],
+                &us,
+                &ids,
+                &pools.elgamal,
+                &mut rng
+            )
+            .err(),
+            Some(BuildError::InvalidLength(0))
+        );
+        let ris: Vec<_> = (0..8).map(|_| hop().1).collect();
+        assert_eq!(
+            Request::new(
+                TunnelRole::Outbound,
+                &ris,
+                &us,
+                None,
+                &ids,
+                &pools.elgamal,
+                &mut rng
+            )
+            .err(),
+            Some(BuildError::InvalidLength(8))
+        );
+
+        // Replies that have the wrong number of records, or that weren't encrypted by
+        // the hops, can't be read.
+        let request = Request::new(
+            TunnelRole::Inbound,
+            &ris[..2],
+            &us,
+            None,
+            &ids,
+            &pools.elgamal,
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(
+            request.responses(BuildRecords::Long(vec![[0; 528]; 8])),
+            Err(BuildError::InvalidReply)
+        );
+        assert_eq!(
+            request.responses(request.records.clone()),
+            Err(BuildError::InvalidReply)
+        );
+    }
+
+    #[test]
+    fn reject_reasons() {
+        let reasons: Vec<_> = [0, 1, 10, 11, 20, 30, 31, 50, 255]
+            .iter()
+            .map(|&reply| RejectReason::from_reply(reply))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                None,
+                Some(RejectReason::ProbabilisticReject),
+                Some(RejectReason::ProbabilisticReject),
+                Some(RejectReason::TransientOverload),
+                Some(RejectReason::TransientOverload),
+                Some(RejectReason::Bandwidth),
+                Some(RejectReason::Critical),
+                Some(RejectReason::Critical),
+                Some(RejectReason::Critical),
+            ]
+        );
+    }
+
+    #[test]
+    fn mixed_responses() {
+        let mut rng = TestRng::from_seed([7; 32]);
+        let ids = TunnelIds::default();
+        let pools = Pools::new();
+        let peers = LoopbackPeers::default();
+        let (ctx, _netdb, _ib_rx) = loopback_context_and_netdb(&peers);
+        let us = ctx.keys.rid.hash();
+        let (new_tunnel_tx, _new_tunnel_rx) = mpsc::channel(16);
+        let creator = Creator::new(
+            ctx.clone(),
+            PendingReplies::default(),
+            ids.clone(),
+            new_tunnel_tx,
+        );
+        let now = SystemTime::now();
+
+        // The same four hops respond to three builds in different ways
+        let hops: Vec<_> = (0..4).map(|_| hop()).collect();
+        let ris: Vec<_> = hops.iter().map(|(_, ri)| ri.clone()).collect();
+        let idents: Vec<_> = ris.iter().map(|ri| ri.router_id.hash()).collect();
+        let replies = [TUNNEL_ACCEPT, 10, 30, 50];
+        for _ in 0..3 {
+            let request = Request::new(
+                TunnelRole::Inbound,
+                &ris,
+                &us,
+                None,
+                &ids,
+                &pools.elgamal,
+                &mut rng,
+            )
+            .unwrap();
+            let mut records = request.records.clone();
+            for ((rsk, _), reply) in hops.iter().zip(replies.iter()) {
+                process(rsk, &mut records, *reply);
+            }
+            let responses = request.responses(records).unwrap();
+            creator.record_responses(&responses, now);
+        }
+        assert_eq!(
+            creator.reply_stats(),
+            ReplyStats {
+                accepted: 3,
+                probabilistic_reject: 3,
+                transient_overload: 0,
+                bandwidth: 3,
+                critical: 3,
+            }
+        );
+
+        // Each response was recorded in the hop's profile, and critical rejections
+        // count against the hop the most
+        let profile = |i: usize| ctx.profiles.get(&idents[i]).unwrap().tunnel_builds;
+        assert_eq!((profile(0).agreed, profile(0).rejected), (3, 0));
+        assert_eq!((profile(1).rejected, profile(1).critical), (3, 0));
+        assert_eq!((profile(3).rejected, profile(3).critical), (3, 3));
+        let score = |i: usize| ctx.profiles.capacity_score(&idents[i], now);
+        assert!(score(0) > score(1));
+        assert_eq!(score(1), score(2));
+        assert!(score(2) > score(3));
+
+        // The hops that kept rejecting aren't selected for a while
+        for _ in 0..10 {
+            let selected = HopSelector::new(4)
+                .min_length(1)
+                .select(&ris, &us, &ctx.profiles, now, &mut rng)
+                .unwrap();
+            assert_eq!(selected, vec![ris[0].clone()]);
+        }
+    }
+
+    struct TestRouter {
+        ctx: Arc<Context>,
+        netdb: MockNetDb,
+        ib_rx: mpsc::Receiver<(Hash, crate::i2np::Message)>,
+    }
+
+    /// Creates `count` routers that can reach and look up each other. The routers
+    /// support short build records if `format` is [`RecordFormat::Short`].
+    fn routers(peers: &LoopbackPeers, count: usize, format: RecordFormat) -> Vec<TestRouter> {
+        let mut routers: Vec<_> = (0..count)
+            .map(|_| {
+                let (ctx, netdb, ib_rx) = match format {
+                    RecordFormat::Long => loopback_context_and_netdb(peers),
+                    RecordFormat::Short => {
+                        let (ctx, netdb, ib_rx) = loopback_context_and_netdb_with_keys(
+                            peers,
+                            RouterSecretKeys::with_enc_type(EncType::X25519),
+                        );
+                        *ctx.ri.write().unwrap() = RouterInfoBuilder::new(ctx.keys.rid.clone())
+                            .option("router.version", "0.9.51")
+                            .sign(&ctx.keys.signing_private_key);
+                        (ctx, netdb, ib_rx)
+                    }
+                };
+                TestRouter { ctx, netdb, ib_rx }
+            })
+            .collect();
+        let ris: Vec<_> = routers
+            .iter()
+            .map(|router| router.ctx.ri.read().unwrap().clone())
+            .collect();
+        for router in routers.iter_mut() {
+            for ri in &ris {
+                router
+                    .netdb
+                    .store_router_info(ri.router_id.hash(), ri.clone());
+            }
+        }
+        routers
+    }
+
+    /// Starts the tunnel subsystem of `router`, wired up as the router builder does:
+    /// build messages go to a listener, and tunnel messages are routed by tunnel ID
+    /// to a participant, which dispatches the messages that reach our endpoints. The
+    /// types of the messages that the router receives are noted in `received`, and
+    /// the tunnels it agrees to participate in are noted in `participating`.
+    fn start_tunnels(
+        rt: &mut Runtime,
+        router: TestRouter,
+        replies: PendingReplies,
+        transit: Transit,
+        new_own_rx: mpsc::Receiver<(TunnelId, OwnTunnel)>,
+        received: Arc<Mutex<Vec<MessageType>>>,
+        participating: Arc<Mutex<Vec<HopData>>>,
+    ) {
+        let TestRouter { ctx, netdb, ib_rx } = router;
+        let (build_tx, build_rx) = mpsc::channel(16);
+        let (data_tx, data_rx) = mpsc::channel(16);
+        let (accepted_tx, accepted_rx) = mpsc::channel(16);
+        let (participating_tx, participating_rx) = mpsc::channel(16);
+
+        let mut dispatcher = Dispatcher::new();
+        let tunnel_dispatcher = TunnelDispatcher::new(transit.tunnel_ids().clone(), data_tx);
+        dispatcher.register_distributor(MessageType::TunnelData, tunnel_dispatcher.clone());
+        dispatcher.register_distributor(MessageType::TunnelGateway, tunnel_dispatcher);
+        for &msg_type in &[
+            MessageType::VariableTunnelBuild,
+            MessageType::VariableTunnelBuildReply,
+            MessageType::ShortTunnelBuild,
+            MessageType::OutboundTunnelBuildReply,
+        ] {
+            dispatcher.register(msg_type, build_tx.clone());
+        }
+
+        rt.spawn(netdb);
+        rt.spawn(Listener::new(
+            ctx.clone(),
+            replies,
+            transit.clone(),
+            accepted_tx,
+            build_rx,
+        ));
+        rt.spawn(
+            accepted_rx
+                .map(move |(tid, config)| {
+                    participating.lock().unwrap().push(config.hop_data.clone());
+                    (tid, config)
+                })
+                .forward(participating_tx.sink_map_err(|_| ()))
+                .map(|_| ()),
+        );
+        rt.spawn(Participant::new(
+            participating_rx,
+            new_own_rx,
+            data_rx,
+            transit,
+            ctx.netdb.clone(),
+            dispatcher.clone(),
+            ctx.comms.clone(),
+            ctx.msg_ids.clone(),
+        ));
+        rt.spawn(ib_rx.for_each(move |(from, msg)| {
+            received.lock().unwrap().push(msg.message_type());
+            dispatcher.handle(from, msg).map_err(|_| ())
+        }));
+    }
+
+    fn wait_for_len<T>(list: &Mutex<Vec<T>>, len: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while list.lock().unwrap().len() < len {
+            assert!(Instant::now() < deadline, "list did not fill up");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Builds tunnels through `len` other in-process routers, which are sent build
+    /// records of the given format. An outbound and an inbound tunnel are built
+    /// while we have no tunnels to build through, and then one of each through them.
+    fn build_tunnels(len: usize, format: RecordFormat) {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let mut routers = routers(&peers, len + 1, format);
+        let us = routers.remove(0);
+        let our_hash = us.ctx.keys.rid.hash();
+        let our_ri = us.ctx.ri.read().unwrap().clone();
+
+        // The other routers accept build requests, and pass on tunnel messages
+        let mut others = vec![];
+        for router in routers {
+            let transit = Transit::default();
+            let participating = Arc::new(Mutex::new(vec![]));
+            let (_new_own_tx, new_own_rx) = mpsc::channel(1);
+            start_tunnels(
+                &mut rt,
+                router,
+                PendingReplies::default(),
+                transit.clone(),
+                new_own_rx,
+                Arc::new(Mutex::new(vec![])),
+                participating.clone(),
+            );
+            others.push((transit, participating));
+        }
+
+        // Our listener passes build replies to the creator. We note the types of the
+        // messages that the replies arrive in.
+        let replies = PendingReplies::default();
+        let transit = Transit::default();
+        let ids = transit.tunnel_ids().clone();
+        let received = Arc::new(Mutex::new(vec![]));
+        let (new_tunnel_tx, new_tunnel_rx) = mpsc::channel(16);
+        let ctx = us.ctx.clone();
+        start_tunnels(
+            &mut rt,
+            us,
+            replies.clone(),
+            transit,
+            new_tunnel_rx,
+            received.clone(),
+            Arc::new(Mutex::new(vec![])),
+        );
+        let creator = Creator::new(ctx, replies, ids.clone(), new_tunnel_tx);
+        let selector = HopSelector::new(len);
+        let mut build = |role, path| {
+            let built = rt.block_on(creator.build(role, &selector, path)).unwrap();
+            assert_eq!(built.role, role);
+            assert_eq!(built.hops.len(), len);
+            built
+        };
+
+        // With no tunnels to build through, the outbound tunnel's reply comes back
+        // through a zero-hop tunnel, and the inbound tunnel's request is sent directly
+        let outbound = build(TunnelRole::Outbound, BuildPath::Direct);
+        let inbound = build(TunnelRole::Inbound, BuildPath::Direct);
+        for tunnel in &[&outbound, &inbound] {
+            assert!(matches!(
+                ids.state(&tunnel.tid),
+                Some(TunnelIdState::Live(TunnelUse::Own(role), _)) if role == tunnel.role
+            ));
+        }
+
+        // Then the inbound tunnel's request goes out through the outbound tunnel, and
+        // the outbound tunnel's reply comes back through the inbound tunnel
+        let routed_inbound = build(TunnelRole::Inbound, BuildPath::Exploratory(outbound.tid));
+        build(TunnelRole::Outbound, BuildPath::Exploratory(inbound.tid));
+
+        // The outbound endpoints sent their replies to the gateways of our inbound
+        // tunnels, and the inbound endpoints forwarded the requests back to us.
+        let request_type = match format {
+            RecordFormat::Long => MessageType::VariableTunnelBuild,
+            RecordFormat::Short => MessageType::ShortTunnelBuild,
+        };
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(
+                received[..3],
+                [MessageType::TunnelGateway, request_type, request_type]
+            );
+            assert!(received.len() > 3);
+            assert!(received[3..]
+                .iter()
+                .all(|msg_type| *msg_type == MessageType::TunnelData));
+        }
+
+        // Every other router participates in all four tunnels
+        for (transit, participating) in &others {
+            assert_eq!(transit.tunnels(), 4);
+            wait_for_len(participating, 4);
+        }
+        if len == 1 {
+            assert_eq!(
+                *others[0].1.lock().unwrap(),
+                vec![
+                    HopData::OutboundEndpoint(our_hash.clone()),
+                    HopData::InboundGateway((our_ri.clone(), inbound.tid)),
+                    HopData::InboundGateway((our_ri, routed_inbound.tid)),
+                    HopData::OutboundEndpoint(our_hash),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn one_hop_tunnels() {
+        build_tunnels(1, RecordFormat::Long);
+    }
+
+    #[test]
+    fn two_hop_tunnels() {
+        build_tunnels(2, RecordFormat::Long);
+    }
+
+    #[test]
+    fn short_record_tunnels() {
+        // We and three other routers, so that each tunnel has a gateway, a
+        // participant and an endpoint
+        build_tunnels(3, RecordFormat::Short);
+    }
+
+    #[test]
+    fn not_enough_peers() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let mut routers = routers(&peers, 2, RecordFormat::Long);
+        let us = routers.remove(0);
+        rt.spawn(us.netdb);
+
+        let (new_tunnel_tx, _new_tunnel_rx) = mpsc::channel(16);
+        let creator = Creator::new(
+            us.ctx.clone(),
+            PendingReplies::default(),
+            TunnelIds::default(),
+            new_tunnel_tx,
+        );
+        assert_eq!(
+            rt.block_on(creator.build(
+                TunnelRole::Outbound,
+                &HopSelector::new(2),
+                BuildPath::Direct
+            )),
+            Err(BuildError::NotEnoughPeers(1))
+        );
+    }
+
+    #[test]
+    fn unknown_exploratory_tunnel() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let mut routers = routers(&peers, 2, RecordFormat::Long);
+        let us = routers.remove(0);
+        rt.spawn(us.netdb);
+
+        let (new_tunnel_tx, _new_tunnel_rx) = mpsc::channel(16);
+        let creator = Creator::new(
+            us.ctx.clone(),
+            PendingReplies::default(),
+            TunnelIds::default(),
+            new_tunnel_tx,
+        );
+
+        // Builds can only go through tunnels that the creator built, in the direction
+        // that the build needs
+        let outbound = rt
+            .block_on(creator.zero_hop(TunnelRole::Outbound))
+            .unwrap()
+            .tid;
+        for &(role, tid) in &[
+            (TunnelRole::Inbound, TunnelId(5)),
+            (TunnelRole::Outbound, TunnelId(5)),
+            (TunnelRole::Outbound, outbound),
+        ] {
+            assert_eq!(
+                rt.block_on(creator.build(role, &HopSelector::new(1), BuildPath::Exploratory(tid))),
+                Err(BuildError::NoSuchTunnel(tid))
+            );
+        }
+    }
+}
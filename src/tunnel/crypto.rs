@@ -0,0 +1,188 @@
+//! Tunnel encryption operations.
+//!
+//! Each hop of a tunnel adds a layer of encryption to the 1024-byte body of every
+//! [`TunnelData`] message, using its IV and layer keys. The creator of a tunnel knows
+//! the keys of every hop, and removes the layers by decrypting with each of them in
+//! turn: after receiving a message through an inbound tunnel, or before sending a
+//! message into an outbound tunnel, so that the hops' layers cancel out by the time it
+//! reaches the endpoint.
+//!
+//! [`TunnelData`]: crate::i2np::TunnelData
+
+use aes::{cipher::BlockCipherKey, Block, BlockDecrypt, BlockEncrypt, NewBlockCipher};
+
+use crate::crypto::{Aes256, SessionKey};
+
+/// Implements layered encryption and decryption of tunnel messages.
+///
+/// See the ["Participant Processing" section][processing] of the tunnel implementation
+/// documentation for details of the algorithm.
+///
+/// [processing]: https://geti2p.net/en/docs/tunnels/implementation#tunnel.participant
+#[derive(Clone, Debug)]
+pub struct LayerCipher {
+    iv_cipher: aes::Aes256,
+    layer_key: SessionKey,
+}
+
+impl LayerCipher {
+    /// Create a `LayerCipher` for the tunnel hop with the given IV and layer keys.
+    pub fn new(iv_key: &SessionKey, layer_key: SessionKey) -> Self {
+        let iv_key = BlockCipherKey::<aes::Aes256>::from_slice(&iv_key.0);
+        LayerCipher {
+            iv_cipher: aes::Aes256::new(iv_key),
+            layer_key,
+        }
+    }
+
+    /// Encrypt the body of a [`TunnelData`] message using the IV and layer keys for
+    /// this hop.
+    ///
+    /// Used by tunnel participants, including IBGWs and OBEPs that are not the tunnel
+    /// creator.
+    ///
+    /// [`TunnelData`]: crate::i2np::TunnelData
+    pub fn encrypt_layer(&self, data: &mut [u8; 1024]) {
+        // Encrypt the received IV with AES256/ECB using the IV key to determine the current IV
+        self.iv_cipher
+            .encrypt_block(Block::from_mut_slice(&mut data[0..16]));
+
+        // Use that IV with the layer key to encrypt the data
+        let mut cipher = Aes256::new(&self.layer_key, &data[0..16], &[0; 16]);
+        assert_eq!(cipher.encrypt_blocks(&mut data[16..]), Some(1008));
+
+        // Encrypt the current IV with AES256/ECB using the IV key again
+        self.iv_cipher
+            .encrypt_block(Block::from_mut_slice(&mut data[0..16]));
+    }
+
+    /// Decrypt the body of a [`TunnelData`] message using the IV and layer keys for
+    /// this hop.
+    ///
+    /// Used by the tunnel creator to preprocess outgoing `TunnelData` messages, and
+    /// postprocess incoming `TunnelData` messages.
+    ///
+    /// [`TunnelData`]: crate::i2np::TunnelData
+    pub fn decrypt_layer(&self, data: &mut [u8; 1024]) {
+        // Decrypt the received IV with AES256/ECB using the IV key to determine the current IV
+        self.iv_cipher
+            .decrypt_block(Block::from_mut_slice(&mut data[0..16]));
+
+        // Use that IV with the layer key to decrypt the data
+        let mut cipher = Aes256::new(&self.layer_key, &[0; 16], &data[0..16]);
+        assert_eq!(cipher.decrypt_blocks(&mut data[16..]), Some(1008));
+
+        // Decrypt the current IV with AES256/ECB using the IV key again
+        self.iv_cipher
+            .decrypt_block(Block::from_mut_slice(&mut data[0..16]));
+    }
+}
+
+/// Removes the layers of encryption that the given hops add to `data`, where `layers`
+/// are ordered from the gateway to the endpoint.
+///
+/// The endpoint of an inbound tunnel that we built uses this to read the messages it
+/// receives. The gateway of an outbound tunnel that we built uses this to preprocess
+/// the messages it sends, so that they are in plaintext once every hop has added its
+/// layer.
+pub(super) fn remove_layers<'a, I>(layers: I, data: &mut [u8; 1024])
+where
+    I: IntoIterator<Item = &'a LayerCipher>,
+    I::IntoIter: DoubleEndedIterator,
+{
+    for layer in layers.into_iter().rev() {
+        layer.decrypt_layer(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{remove_layers, LayerCipher};
+    use crate::crypto::SessionKey;
+    use crate::data::encoding::hex_decode;
+
+    #[test]
+    fn round_trip() {
+        let iv_key = SessionKey([1; 32]);
+        let layer_key = SessionKey([2; 32]);
+
+        let mut data = [0; 1024];
+
+        let cipher = LayerCipher::new(&iv_key, layer_key);
+
+        cipher.encrypt_layer(&mut data);
+        assert!(data[..] != [0; 1024][..]);
+        cipher.decrypt_layer(&mut data);
+        assert_eq!(&data[..], &[0; 1024][..]);
+
+        cipher.decrypt_layer(&mut data);
+        assert!(data[..] != [0; 1024][..]);
+        cipher.encrypt_layer(&mut data);
+        assert_eq!(&data[..], &[0; 1024][..]);
+    }
+
+    /// The tunnel specifications don't include test vectors, so this was generated by
+    /// applying AES-256 ECB to the IV, then AES-256 CBC to the rest of the message, and
+    /// then ECB to the IV again, using an independent AES implementation.
+    #[test]
+    fn known_answer() {
+        let mut iv_key = [0; 32];
+        let mut layer_key = [0; 32];
+        for (i, (iv, layer)) in iv_key.iter_mut().zip(layer_key.iter_mut()).enumerate() {
+            *iv = i as u8;
+            *layer = 32 + i as u8;
+        }
+        let cipher = LayerCipher::new(&SessionKey(iv_key), SessionKey(layer_key));
+
+        let mut data = [0; 1024];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let plaintext = data;
+
+        cipher.encrypt_layer(&mut data);
+        assert_eq!(
+            &data[0..16],
+            &hex_decode("cdfc2535310bf56b2eb78aa25add7751").unwrap()[..]
+        );
+        assert_eq!(
+            &data[16..32],
+            &hex_decode("b1071a1b8ac7034138d5cb29f2b51ba1").unwrap()[..]
+        );
+        assert_eq!(
+            &data[1008..],
+            &hex_decode("10a44e7e69ee2e5ea5845264f2a13f71").unwrap()[..]
+        );
+
+        cipher.decrypt_layer(&mut data);
+        assert_eq!(&data[..], &plaintext[..]);
+    }
+
+    #[test]
+    fn multiple_hops() {
+        let layers: Vec<_> = (0..5u8)
+            .map(|i| LayerCipher::new(&SessionKey([i; 32]), SessionKey([0x80 | i; 32])))
+            .collect();
+
+        let mut plaintext = [0; 1024];
+        for (i, b) in plaintext.iter_mut().enumerate() {
+            *b = (i * 7) as u8;
+        }
+
+        // An outbound gateway preprocesses the message, and each hop adds its layer
+        let mut data = plaintext;
+        remove_layers(&layers, &mut data);
+        for (i, layer) in layers.iter().enumerate() {
+            assert!(data[..] != plaintext[..], "Plaintext before hop {}", i);
+            layer.encrypt_layer(&mut data);
+        }
+        assert_eq!(&data[..], &plaintext[..]);
+
+        // The endpoint of an inbound tunnel removes the layers that each hop added
+        for layer in &layers {
+            layer.encrypt_layer(&mut data);
+        }
+        remove_layers(&layers, &mut data);
+        assert_eq!(&data[..], &plaintext[..]);
+    }
+}
@@ -0,0 +1,269 @@
+//! Routing of inbound tunnel messages by tunnel ID.
+//!
+//! TunnelData and TunnelGateway messages from the transports are checked against the
+//! [`TunnelIds`] in use at this router before they are queued for the [`Participant`].
+//! Messages for tunnels that have expired or never existed are routine, and are
+//! counted and dropped here without reaching the participant.
+//!
+//! Only the tunnel ID of a message is read. The message itself is handed on as it
+//! arrived, so its body is never copied on the way through.
+//!
+//! [`Participant`]: super::Participant
+
+use futures::future;
+use std::sync::{Arc, Mutex};
+
+use super::{
+    accounting::TunnelUse,
+    registry::{TunnelIdState, TunnelIds},
+    TunnelRole,
+};
+use crate::data::{Hash, TunnelId};
+use crate::i2np::{Message, MessagePayload};
+use crate::router::{
+    types::{self, Distributor},
+    DistributorTx,
+};
+
+/// Counters for the tunnel messages that we have received.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DispatchStats {
+    /// Messages for tunnels in which we are the inbound gateway.
+    pub inbound_gateway: u64,
+    /// Messages for tunnels in which we are an intermediate hop.
+    pub intermediate: u64,
+    /// Messages for tunnels in which we are the outbound endpoint.
+    pub outbound_endpoint: u64,
+    /// Messages for our own inbound tunnels.
+    pub own_inbound: u64,
+    /// Messages for our own outbound tunnels.
+    pub own_outbound: u64,
+    /// Messages for tunnels that were accepted or built, but not yet registered.
+    pub pending: u64,
+    /// Messages that were dropped because their tunnel expired recently.
+    pub expired: u64,
+    /// Messages that were dropped because their tunnel ID is not in use.
+    pub unknown: u64,
+    /// Messages that were dropped because their type doesn't fit their tunnel.
+    pub misrouted: u64,
+}
+
+/// Routes TunnelData and TunnelGateway messages to the [`Participant`], dropping those
+/// that can't be for any of our tunnels.
+///
+/// This is registered with the router's [`Dispatcher`] for both message types.
+///
+/// [`Participant`]: super::Participant
+/// [`Dispatcher`]: crate::router::Dispatcher
+#[derive(Clone)]
+pub struct TunnelDispatcher {
+    ids: TunnelIds,
+    participant: DistributorTx,
+    stats: Arc<Mutex<DispatchStats>>,
+}
+
+impl TunnelDispatcher {
+    pub fn new(ids: TunnelIds, participant: DistributorTx) -> Self {
+        TunnelDispatcher {
+            ids,
+            participant,
+            stats: Arc::new(Mutex::new(DispatchStats::default())),
+        }
+    }
+
+    pub fn stats(&self) -> DispatchStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Returns whether a message for `tid` should be passed on, counting it either way.
+    fn route(&self, tid: TunnelId, gateway: bool) -> bool {
+        let state = self.ids.state(&tid);
+        let mut stats = self.stats.lock().unwrap();
+        let counter = match state {
+            Some(TunnelIdState::Live(usage, _)) => match (usage, gateway) {
+                (TunnelUse::InboundGateway, true) => &mut stats.inbound_gateway,
+                (TunnelUse::Intermediate, false) => &mut stats.intermediate,
+                (TunnelUse::OutboundEndpoint, false) => &mut stats.outbound_endpoint,
+                // The participant checks whether we are also the gateway of a
+                // zero-hop inbound tunnel.
+                (TunnelUse::Own(TunnelRole::Inbound), _) => &mut stats.own_inbound,
+                (TunnelUse::Own(TunnelRole::Outbound), false) => &mut stats.own_outbound,
+                _ => {
+                    debug!(
+                        "Dropping message for tunnel {}: wrong type for our role",
+                        tid
+                    );
+                    stats.misrouted += 1;
+                    return false;
+                }
+            },
+            // The participant may not have registered the tunnel yet
+            Some(TunnelIdState::Reserved(_)) => &mut stats.pending,
+            Some(TunnelIdState::Tombstone(_)) => {
+                self.ids.late_message(&tid);
+                stats.expired += 1;
+                return false;
+            }
+            None => {
+                debug!("Dropping message for tunnel {}: unknown TunnelId", tid);
+                stats.unknown += 1;
+                return false;
+            }
+        };
+        *counter += 1;
+        true
+    }
+}
+
+impl types::Distributor for TunnelDispatcher {
+    fn handle(&self, from: Hash, msg: Message) -> types::DistributorResult {
+        let routed = match &msg.payload {
+            MessagePayload::TunnelData(td) => self.route(td.tid, false),
+            MessagePayload::TunnelGateway(tg) => self.route(tg.tid(), true),
+            _ => {
+                debug!("Dropping non-tunnel message from {}:\n{}", from, msg);
+                self.stats.lock().unwrap().misrouted += 1;
+                false
+            }
+        };
+
+        if routed {
+            self.participant.handle(from, msg)
+        } else {
+            Box::new(future::ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Async, Future, Stream};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{DispatchStats, TunnelDispatcher};
+    use crate::crypto::SessionKey;
+    use crate::data::{Hash, RouterInfo, RouterSecretKeys, TunnelId};
+    use crate::i2np::{Message, MessageIdGenerator, MessagePayload};
+    use crate::router::types::Distributor;
+    use crate::tunnel::{
+        crypto::LayerCipher, registry::Registry, Accounting, HopConfig, HopData, OwnTunnel,
+        TunnelIds, TunnelRole, TUNNEL_LIFETIME,
+    };
+
+    #[test]
+    fn routing() {
+        let ids = TunnelIds::default();
+        let mut registry = Registry::new(Accounting::default(), ids.clone());
+        let now = SystemTime::now();
+        let expires = now + Duration::from_secs(TUNNEL_LIFETIME);
+        let ri = RouterInfo::new(RouterSecretKeys::new().rid);
+
+        // One tunnel of each role
+        let hop = |hop_data| HopConfig {
+            hop_data,
+            layer_cipher: LayerCipher::new(&SessionKey([1; 32]), SessionKey([2; 32])),
+            expires,
+        };
+        let own = |role| OwnTunnel {
+            role,
+            hops: vec![],
+            first_hop: ri.clone(),
+            expires,
+        };
+        registry.register_participating(
+            TunnelId(1),
+            hop(HopData::InboundGateway((ri.clone(), TunnelId(11)))),
+        );
+        registry.register_participating(
+            TunnelId(2),
+            hop(HopData::Intermediate(
+                Hash([1; 32]),
+                (ri.clone(), TunnelId(12)),
+            )),
+        );
+        registry.register_participating(TunnelId(3), hop(HopData::OutboundEndpoint(Hash([1; 32]))));
+        registry.register_own(TunnelId(4), own(TunnelRole::Inbound));
+        registry.register_own(TunnelId(5), own(TunnelRole::Outbound));
+        assert!(ids.reserve(TunnelId(6), now));
+
+        let (participant_tx, mut participant_rx) = mpsc::channel(16);
+        let dispatcher = TunnelDispatcher::new(ids.clone(), participant_tx);
+        let from = Hash([1; 32]);
+        let msg_ids = MessageIdGenerator::default();
+        let data = |tid| Message::tunnel_data(&msg_ids, TunnelId(tid), [tid as u8; 1024]);
+        let gateway =
+            |tid| Message::tunnel_gateway(&msg_ids, TunnelId(tid), &Message::dummy_data());
+
+        let routed = vec![
+            gateway(1),
+            data(2),
+            data(3),
+            data(4),
+            gateway(4),
+            data(5),
+            data(6),
+        ];
+        let routed_ids: Vec<_> = routed.iter().map(|msg| msg.id).collect();
+        let gateway_body = match &routed[0].payload {
+            MessagePayload::TunnelGateway(tg) => tg.data().as_ptr(),
+            _ => unreachable!(),
+        };
+        for msg in routed {
+            dispatcher.handle(from.clone(), msg).wait().unwrap();
+        }
+
+        // Messages of the wrong type for their tunnel, or for IDs that are not in use,
+        // are dropped
+        for msg in vec![data(1), gateway(2), gateway(5), data(7), gateway(8)] {
+            dispatcher.handle(from.clone(), msg).wait().unwrap();
+        }
+        dispatcher
+            .handle(from.clone(), Message::dummy_data())
+            .wait()
+            .unwrap();
+
+        // Messages for tunnels that expired recently are dropped as late
+        registry.expire(expires, Instant::now());
+        dispatcher.handle(from.clone(), data(2)).wait().unwrap();
+        assert_eq!(ids.stats().late_messages, 1);
+
+        // Every routed message reaches the participant, in order
+        let mut received = vec![];
+        while let Ok(Async::Ready(Some((sender, msg)))) = participant_rx.poll() {
+            assert_eq!(sender, from);
+            received.push(msg);
+        }
+        assert_eq!(
+            received.iter().map(|msg| msg.id).collect::<Vec<_>>(),
+            routed_ids
+        );
+
+        // The wrapped message was passed on without being copied
+        match &received[0].payload {
+            MessagePayload::TunnelGateway(tg) => assert_eq!(tg.data().as_ptr(), gateway_body),
+            _ => panic!("Expected a TunnelGateway message"),
+        }
+        match &received[1].payload {
+            MessagePayload::TunnelData(td) => {
+                assert_eq!(td.tid, TunnelId(2));
+                assert_eq!(&td.data()[..], &[2; 1024][..]);
+            }
+            _ => panic!("Expected a TunnelData message"),
+        }
+
+        assert_eq!(
+            dispatcher.stats(),
+            DispatchStats {
+                inbound_gateway: 1,
+                intermediate: 1,
+                outbound_endpoint: 1,
+                own_inbound: 2,
+                own_outbound: 1,
+                pending: 1,
+                expired: 1,
+                unknown: 2,
+                misrouted: 4,
+            }
+        );
+    }
+}
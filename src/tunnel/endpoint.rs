@@ -0,0 +1,766 @@
+//! Processing at the endpoint of a tunnel.
+//!
+//! Once the layered encryption has been removed, the endpoint checks the checksum of
+//! each [`TunnelData`] message, and reassembles the I2NP messages that the gateway
+//! fragmented into it. Fragments of a message may arrive out of order, so they are
+//! held until every fragment has arrived, or the message times out.
+//!
+//! A gateway can send first fragments that it never completes, so the incomplete
+//! messages we hold are capped per tunnel, and across all of our endpoints. Messages
+//! that time out or are dropped are remembered for a while, so that their remaining
+//! fragments are dropped instead of being taken for new messages.
+//!
+//! See the ["Endpoint Processing" section][endpoint] of the tunnel implementation
+//! documentation for details.
+//!
+//! [`TunnelData`]: crate::i2np::TunnelData
+//! [endpoint]: https://geti2p.net/en/docs/tunnels/implementation#tunnel.endpoint
+
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::{Duration, Instant};
+
+use super::{
+    frame::tunnel_message, gateway::MAX_FRAGMENT_NUMBER, TunnelMessageDeliveryInstructions,
+    TunnelMessageDeliveryType,
+};
+use crate::i2np::{frame::message_bytes, Message};
+
+/// How long we wait for the rest of a fragmented message after its first fragment
+/// arrives.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most fragment data we hold for incomplete messages in a single tunnel. When a
+/// fragment would take us over this, the oldest incomplete messages are dropped.
+const MAX_PENDING_BYTES: usize = 256 * 1024;
+
+/// The most incomplete messages we hold in a single tunnel. When a new message would
+/// take us over this, the oldest incomplete message is dropped.
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// The most incomplete messages we hold across all of our endpoints. New messages are
+/// refused while we are at this limit.
+const MAX_TOTAL_PENDING_MESSAGES: usize = 4096;
+
+/// The most dropped messages that we remember in a single tunnel.
+const MAX_DROPPED_MESSAGES: usize = 4 * MAX_PENDING_MESSAGES;
+
+/// Tunnel endpoint errors
+#[derive(Debug, PartialEq)]
+pub enum EndpointError {
+    /// The TunnelData message could not be parsed, or its checksum was invalid.
+    Invalid,
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for EndpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointError::Invalid => "Invalid TunnelData message".fmt(f),
+        }
+    }
+}
+
+/// Counters for the messages reassembled at a tunnel endpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EndpointStats {
+    /// I2NP messages that were reassembled and parsed.
+    pub completed: u64,
+    /// Incomplete messages that timed out waiting for their missing fragments.
+    pub expired: u64,
+    /// Incomplete messages dropped to stay under our memory cap.
+    pub evicted: u64,
+    /// Fragments that we had already received.
+    pub duplicates: u64,
+    /// TunnelData messages that could not be parsed, and messages whose fragments
+    /// were inconsistent.
+    pub invalid: u64,
+    /// New messages refused because too many messages were incomplete at our
+    /// endpoints.
+    pub refused: u64,
+    /// Fragments of messages that had already timed out, or been dropped.
+    pub late: u64,
+    /// Reassembled messages that failed their checksum, or could not be parsed.
+    pub corrupt: u64,
+}
+
+/// The number of incomplete messages held across all of our endpoints.
+///
+/// This is a handle that is shared between the [`Reassembler`] of each endpoint.
+#[derive(Clone, Default)]
+pub(super) struct PendingMessages(Arc<AtomicUsize>);
+
+impl PendingMessages {
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn sub(&self, n: usize) {
+        self.0.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+/// The fragments we have of a message.
+struct PartialMessage {
+    /// Only known once the first fragment has arrived.
+    delivery: Option<TunnelMessageDeliveryType>,
+    fragments: BTreeMap<u8, Vec<u8>>,
+    /// Only known once the last fragment has arrived.
+    last_fragment: Option<u8>,
+    len: usize,
+    started: Instant,
+}
+
+impl PartialMessage {
+    fn new(now: Instant) -> Self {
+        PartialMessage {
+            delivery: None,
+            fragments: BTreeMap::new(),
+            last_fragment: None,
+            len: 0,
+            started: now,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        match (&self.delivery, self.last_fragment) {
+            (Some(_), Some(last)) => self.fragments.len() == usize::from(last) + 1,
+            _ => false,
+        }
+    }
+}
+
+/// Reassembles the I2NP messages sent through a single tunnel.
+#[derive(Default)]
+pub(super) struct Reassembler {
+    pending: HashMap<u32, PartialMessage>,
+    pending_bytes: usize,
+    /// The messages that timed out or were dropped, and when to forget them.
+    dropped: HashMap<u32, Instant>,
+    total: PendingMessages,
+    stats: EndpointStats,
+}
+
+impl Drop for Reassembler {
+    fn drop(&mut self) {
+        self.total.sub(self.pending.len());
+    }
+}
+
+impl Reassembler {
+    /// Creates a reassembler whose incomplete messages count towards `total`.
+    pub(super) fn new(total: PendingMessages) -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+            pending_bytes: 0,
+            dropped: HashMap::new(),
+            total,
+            stats: EndpointStats::default(),
+        }
+    }
+
+    pub(super) fn stats(&self) -> EndpointStats {
+        self.stats
+    }
+
+    /// Returns the number of messages that are waiting for more fragments.
+    pub(super) fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Processes the plaintext of a [`TunnelData`] message, returning the messages that
+    /// it completed along with their delivery instructions.
+    ///
+    /// [`TunnelData`]: crate::i2np::TunnelData
+    pub(super) fn receive(
+        &mut self,
+        data: &[u8; 1024],
+        now: Instant,
+    ) -> Result<Vec<(TunnelMessageDeliveryType, Message)>, EndpointError> {
+        let tm = match tunnel_message(&data[..]) {
+            Ok((_, tm)) => tm,
+            Err(_) => {
+                self.stats.invalid += 1;
+                return Err(EndpointError::Invalid);
+            }
+        };
+
+        let mut completed = vec![];
+        for (tmdi, frag) in tm.0 {
+            let (msg_id, fragment_number, delivery, last) = match tmdi {
+                TunnelMessageDeliveryInstructions::First(di) => match di.msg_id {
+                    Some(msg_id) => (msg_id, 0, Some(di.delivery_type), false),
+                    None => {
+                        // An unfragmented message
+                        if let Some(msg) = self.parse(Bytes::from(frag)) {
+                            completed.push((di.delivery_type, msg));
+                        }
+                        continue;
+                    }
+                },
+                TunnelMessageDeliveryInstructions::FollowOn(di) => {
+                    (di.msg_id, di.fragment_number, None, di.last_fragment)
+                }
+            };
+
+            if let Some((delivery, data)) =
+                self.add_fragment(msg_id, fragment_number, delivery, last, frag, now)
+            {
+                if let Some(msg) = self.parse(Bytes::from(data)) {
+                    completed.push((delivery, msg));
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Stores a fragment, returning the reassembled message if it was the last one
+    /// missing.
+    fn add_fragment(
+        &mut self,
+        msg_id: u32,
+        fragment_number: u8,
+        delivery: Option<TunnelMessageDeliveryType>,
+        last: bool,
+        frag: &[u8],
+        now: Instant,
+    ) -> Option<(TunnelMessageDeliveryType, Vec<u8>)> {
+        if self.dropped.contains_key(&msg_id) {
+            self.stats.late += 1;
+            return None;
+        }
+        if fragment_number > MAX_FRAGMENT_NUMBER {
+            self.drop_message(msg_id, now);
+            self.stats.invalid += 1;
+            return None;
+        }
+
+        match self.pending.get(&msg_id) {
+            Some(pending) if pending.started + REASSEMBLY_TIMEOUT <= now => {
+                // The message timed out since we last expired
+                self.drop_message(msg_id, now);
+                self.stats.expired += 1;
+                self.stats.late += 1;
+                return None;
+            }
+            Some(pending) if pending.fragments.contains_key(&fragment_number) => {
+                self.stats.duplicates += 1;
+                return None;
+            }
+            Some(_) => (),
+            None => {
+                if !self.make_room(now) {
+                    self.stats.refused += 1;
+                    return None;
+                }
+                self.pending.insert(msg_id, PartialMessage::new(now));
+                self.total.add(1);
+            }
+        }
+
+        self.make_space(frag.len(), msg_id, now);
+
+        // The fragment numbers must be consistent with the last fragment
+        let pending = &self.pending[&msg_id];
+        let inconsistent = match pending.last_fragment {
+            Some(last_fragment) => last || fragment_number > last_fragment,
+            None => {
+                last && pending
+                    .fragments
+                    .keys()
+                    .next_back()
+                    .map_or(false, |n| *n > fragment_number)
+            }
+        };
+        if inconsistent {
+            self.drop_message(msg_id, now);
+            self.stats.invalid += 1;
+            return None;
+        }
+
+        let pending = self.pending.get_mut(&msg_id).unwrap();
+        if delivery.is_some() {
+            pending.delivery = delivery;
+        }
+        if last {
+            pending.last_fragment = Some(fragment_number);
+        }
+        pending.fragments.insert(fragment_number, frag.to_vec());
+        pending.len += frag.len();
+        self.pending_bytes += frag.len();
+
+        if !pending.is_complete() {
+            return None;
+        }
+
+        let pending = self.remove(msg_id).unwrap();
+        let mut data = Vec::with_capacity(pending.len);
+        for frag in pending.fragments.values() {
+            data.extend_from_slice(frag);
+        }
+        Some((pending.delivery.unwrap(), data))
+    }
+
+    /// Makes room for a new incomplete message, dropping the oldest one in this tunnel
+    /// if we are at our cap. Returns `false` if all of our endpoints together are at
+    /// their cap.
+    fn make_room(&mut self, now: Instant) -> bool {
+        if self.pending.len() >= MAX_PENDING_MESSAGES {
+            self.evict_oldest(None, now);
+        }
+        self.total.get() < MAX_TOTAL_PENDING_MESSAGES
+    }
+
+    /// Drops the oldest incomplete messages, other than `msg_id`, until `len` more
+    /// bytes fit under our cap.
+    fn make_space(&mut self, len: usize, msg_id: u32, now: Instant) {
+        while self.pending_bytes + len > MAX_PENDING_BYTES {
+            if !self.evict_oldest(Some(msg_id), now) {
+                break;
+            }
+        }
+    }
+
+    /// Drops the oldest incomplete message other than `keep`, returning `false` if
+    /// there was none.
+    fn evict_oldest(&mut self, keep: Option<u32>, now: Instant) -> bool {
+        let oldest = self
+            .pending
+            .iter()
+            .filter(|(id, _)| Some(**id) != keep)
+            .min_by_key(|(_, pending)| pending.started)
+            .map(|(id, _)| *id);
+        match oldest {
+            Some(id) => {
+                self.drop_message(id, now);
+                self.stats.evicted += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops an incomplete message, and remembers it so that its remaining fragments
+    /// are dropped too.
+    fn drop_message(&mut self, msg_id: u32, now: Instant) {
+        if self.remove(msg_id).is_some() && self.dropped.len() < MAX_DROPPED_MESSAGES {
+            self.dropped.insert(msg_id, now + REASSEMBLY_TIMEOUT);
+        }
+    }
+
+    fn remove(&mut self, msg_id: u32) -> Option<PartialMessage> {
+        let pending = self.pending.remove(&msg_id)?;
+        self.pending_bytes -= pending.len;
+        self.total.sub(1);
+        Some(pending)
+    }
+
+    /// Parses a reassembled message. Opaque payloads share `data` instead of copying it.
+    fn parse(&mut self, data: Bytes) -> Option<Message> {
+        match message_bytes(&data, &data) {
+            Ok((_, msg)) => {
+                self.stats.completed += 1;
+                Some(msg)
+            }
+            Err(_) => {
+                self.stats.corrupt += 1;
+                None
+            }
+        }
+    }
+
+    /// Drops incomplete messages that have timed out, returning the number dropped.
+    pub(super) fn expire(&mut self, now: Instant) -> usize {
+        self.dropped.retain(|_, forget| *forget > now);
+
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.started + REASSEMBLY_TIMEOUT <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for msg_id in &expired {
+            self.drop_message(*msg_id, now);
+        }
+
+        self.stats.expired += expired.len() as u64;
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{
+        EndpointError, EndpointStats, PendingMessages, Reassembler, MAX_PENDING_BYTES,
+        MAX_PENDING_MESSAGES, MAX_TOTAL_PENDING_MESSAGES, REASSEMBLY_TIMEOUT,
+    };
+    use crate::crypto::rand::TestRng;
+    use crate::data::{Hash, TunnelId};
+    use crate::i2np::{frame::gen_message, Message, MessageIdGenerator};
+    use crate::tunnel::{
+        frame::gen_tunnel_message, gateway::Fragmenter, FirstFragmentDeliveryInstructions,
+        FollowOnFragmentDeliveryInstructions, TunnelMessage, TunnelMessageDeliveryInstructions,
+        TunnelMessageDeliveryType,
+    };
+    use crate::util::serialize;
+
+    fn message(len: usize) -> Message {
+        Message::data(
+            &MessageIdGenerator::default(),
+            (0..len).map(|i| i as u8).collect::<Vec<_>>(),
+        )
+    }
+
+    fn assert_completed(
+        completed: Vec<(TunnelMessageDeliveryType, Message)>,
+        delivery: TunnelMessageDeliveryType,
+        msg: &Message,
+    ) {
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].0, delivery);
+        assert_eq!(
+            serialize(|input| gen_message(input, &completed[0].1)),
+            serialize(|input| gen_message(input, msg))
+        );
+    }
+
+    /// Fragments `msg` as a gateway would.
+    fn fragments(delivery: TunnelMessageDeliveryType, msg: &Message) -> Vec<[u8; 1024]> {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let mut fragmenter = Fragmenter::default();
+        fragmenter
+            .push(delivery, 1234, serialize(|input| gen_message(input, msg)))
+            .unwrap();
+        let mut payloads = vec![];
+        while let Some(payload) = fragmenter.next_payload(&mut rng) {
+            payloads.push(payload);
+        }
+        payloads
+    }
+
+    /// Returns the plaintext of a TunnelData message carrying a single fragment.
+    fn payload(tmdi: TunnelMessageDeliveryInstructions, frag: &[u8]) -> [u8; 1024] {
+        let mut payload = [0; 1024];
+        let tm = TunnelMessage(vec![(tmdi, frag)]);
+        gen_tunnel_message((&mut payload[..], 0), &[1; 16], &tm).unwrap();
+        payload
+    }
+
+    fn first(msg_id: u32) -> TunnelMessageDeliveryInstructions {
+        TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+            delivery_type: TunnelMessageDeliveryType::Local,
+            msg_id: Some(msg_id),
+        })
+    }
+
+    fn follow_on(
+        msg_id: u32,
+        fragment_number: u8,
+        last: bool,
+    ) -> TunnelMessageDeliveryInstructions {
+        TunnelMessageDeliveryInstructions::FollowOn(FollowOnFragmentDeliveryInstructions {
+            fragment_number,
+            last_fragment: last,
+            msg_id,
+        })
+    }
+
+    #[test]
+    fn unfragmented() {
+        let msg = message(10);
+        let payloads = fragments(TunnelMessageDeliveryType::Router(Hash([7; 32])), &msg);
+        assert_eq!(payloads.len(), 1);
+
+        let mut reassembler = Reassembler::default();
+        assert_completed(
+            reassembler.receive(&payloads[0], Instant::now()).unwrap(),
+            TunnelMessageDeliveryType::Router(Hash([7; 32])),
+            &msg,
+        );
+        assert_eq!(reassembler.stats().completed, 1);
+    }
+
+    #[test]
+    fn reordered_fragments() {
+        let msg = message(2500);
+        let delivery = TunnelMessageDeliveryType::Tunnel(TunnelId(5), Hash([7; 32]));
+        let payloads = fragments(delivery.clone(), &msg);
+        assert_eq!(payloads.len(), 3);
+
+        // The last fragment arrives first, and the first fragment last
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert!(reassembler.receive(&payloads[2], now).unwrap().is_empty());
+        assert!(reassembler.receive(&payloads[1], now).unwrap().is_empty());
+        assert_completed(
+            reassembler.receive(&payloads[0], now).unwrap(),
+            delivery,
+            &msg,
+        );
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.pending_bytes, 0);
+    }
+
+    #[test]
+    fn duplicate_fragments() {
+        let msg = message(2500);
+        let payloads = fragments(TunnelMessageDeliveryType::Local, &msg);
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert!(reassembler.receive(&payloads[0], now).unwrap().is_empty());
+        assert!(reassembler.receive(&payloads[0], now).unwrap().is_empty());
+        assert!(reassembler.receive(&payloads[1], now).unwrap().is_empty());
+        assert_completed(
+            reassembler.receive(&payloads[2], now).unwrap(),
+            TunnelMessageDeliveryType::Local,
+            &msg,
+        );
+        assert_eq!(
+            reassembler.stats(),
+            EndpointStats {
+                completed: 1,
+                duplicates: 1,
+                ..EndpointStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn missing_fragment() {
+        let msg = message(2500);
+        let payloads = fragments(TunnelMessageDeliveryType::Local, &msg);
+
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert!(reassembler.receive(&payloads[0], now).unwrap().is_empty());
+        assert!(reassembler.receive(&payloads[2], now).unwrap().is_empty());
+
+        // The message is kept until it times out
+        assert_eq!(reassembler.expire(now + Duration::from_secs(1)), 0);
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT), 1);
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.pending_bytes, 0);
+        assert_eq!(reassembler.stats().expired, 1);
+    }
+
+    #[test]
+    fn inconsistent_fragments() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        let mut payload = [0; 1024];
+        let frag = [0; 100];
+        let tm = TunnelMessage(vec![
+            (
+                TunnelMessageDeliveryInstructions::FollowOn(FollowOnFragmentDeliveryInstructions {
+                    fragment_number: 3,
+                    last_fragment: false,
+                    msg_id: 1,
+                }),
+                &frag[..],
+            ),
+            (
+                TunnelMessageDeliveryInstructions::FollowOn(FollowOnFragmentDeliveryInstructions {
+                    fragment_number: 2,
+                    last_fragment: true,
+                    msg_id: 1,
+                }),
+                &frag[..],
+            ),
+        ]);
+        gen_tunnel_message((&mut payload[..], 0), &[1; 16], &tm).unwrap();
+
+        // A fragment after the last one invalidates the message
+        assert!(reassembler.receive(&payload, now).unwrap().is_empty());
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.stats().invalid, 1);
+    }
+
+    #[test]
+    fn memory_cap() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+
+        // Send most of the fragments of many large messages
+        let frag = [0; 900];
+        let per_message = 5;
+        let count = MAX_PENDING_BYTES / (per_message * frag.len()) + 4;
+        assert!(count < MAX_PENDING_MESSAGES);
+        for msg_id in 0..count as u32 {
+            let now = now + Duration::from_millis(u64::from(msg_id));
+            reassembler
+                .receive(&payload(first(msg_id), &frag), now)
+                .unwrap();
+            for n in 1..per_message as u8 {
+                reassembler
+                    .receive(&payload(follow_on(msg_id, n, false), &frag), now)
+                    .unwrap();
+            }
+        }
+
+        // The oldest were dropped
+        assert!(reassembler.pending_bytes <= MAX_PENDING_BYTES);
+        assert!(!reassembler.pending.contains_key(&0));
+        assert!(reassembler.pending.contains_key(&(count as u32 - 1)));
+        assert_eq!(
+            reassembler.stats().evicted as usize,
+            count - reassembler.pending.len()
+        );
+    }
+
+    #[test]
+    fn exhaustion() {
+        let total = PendingMessages::default();
+        let now = Instant::now();
+        let frag = [0; 10];
+
+        // A gateway that never completes its messages only displaces its own
+        let mut reassembler = Reassembler::new(total.clone());
+        for msg_id in 0..1000 {
+            let now = now + Duration::from_millis(u64::from(msg_id));
+            reassembler
+                .receive(&payload(first(msg_id), &frag), now)
+                .unwrap();
+        }
+        assert_eq!(reassembler.pending(), MAX_PENDING_MESSAGES);
+        assert_eq!(total.get(), MAX_PENDING_MESSAGES);
+        assert!(reassembler.pending.contains_key(&999));
+        assert_eq!(
+            reassembler.stats().evicted as usize,
+            1000 - MAX_PENDING_MESSAGES
+        );
+
+        // Many gateways together can't exceed the global cap
+        let mut reassemblers: Vec<_> = (1..MAX_TOTAL_PENDING_MESSAGES / MAX_PENDING_MESSAGES)
+            .map(|_| Reassembler::new(total.clone()))
+            .collect();
+        for reassembler in &mut reassemblers {
+            for msg_id in 0..MAX_PENDING_MESSAGES as u32 {
+                reassembler
+                    .receive(&payload(first(msg_id), &frag), now)
+                    .unwrap();
+            }
+        }
+        assert_eq!(total.get(), MAX_TOTAL_PENDING_MESSAGES);
+
+        let mut latecomer = Reassembler::new(total.clone());
+        latecomer.receive(&payload(first(1), &frag), now).unwrap();
+        assert_eq!(latecomer.pending(), 0);
+        assert_eq!(latecomer.stats().refused, 1);
+
+        // Fragments of messages we already hold are still accepted
+        let completed = reassembler
+            .receive(&payload(follow_on(999, 1, true), &frag), now)
+            .unwrap();
+        assert!(completed.is_empty());
+        assert_eq!(reassembler.stats().corrupt, 1);
+
+        // Space is freed once an endpoint goes away
+        drop(reassemblers.pop());
+        latecomer.receive(&payload(first(1), &frag), now).unwrap();
+        assert_eq!(latecomer.pending(), 1);
+        assert_eq!(
+            total.get(),
+            MAX_TOTAL_PENDING_MESSAGES - MAX_PENDING_MESSAGES
+        );
+    }
+
+    #[test]
+    fn late_fragments() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let frag = [0; 100];
+
+        // A fragment that arrives after its message timed out is dropped, even if the
+        // message hasn't been expired yet
+        reassembler.receive(&payload(first(1), &frag), now).unwrap();
+        reassembler
+            .receive(
+                &payload(follow_on(1, 1, false), &frag),
+                now + REASSEMBLY_TIMEOUT,
+            )
+            .unwrap();
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.pending_bytes, 0);
+
+        // Its remaining fragments aren't taken for a new message
+        let later = now + REASSEMBLY_TIMEOUT + Duration::from_secs(1);
+        reassembler
+            .receive(&payload(follow_on(1, 2, true), &frag), later)
+            .unwrap();
+        assert!(reassembler.pending.is_empty());
+
+        // The same holds for messages that were expired
+        reassembler.receive(&payload(first(2), &frag), now).unwrap();
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT), 1);
+        reassembler
+            .receive(&payload(follow_on(2, 1, true), &frag), later)
+            .unwrap();
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(
+            reassembler.stats(),
+            EndpointStats {
+                expired: 2,
+                late: 3,
+                ..EndpointStats::default()
+            }
+        );
+
+        // Dropped messages are eventually forgotten
+        reassembler.expire(later + REASSEMBLY_TIMEOUT);
+        assert!(reassembler.dropped.is_empty());
+    }
+
+    #[test]
+    fn corrupt_message() {
+        let mut msg = serialize(|input| gen_message(input, &message(1500)));
+        let len = msg.len();
+        msg[len - 1] ^= 0xff;
+
+        // The reassembled message fails its checksum, and isn't delivered
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        assert!(reassembler
+            .receive(&payload(first(1), &msg[..900]), now)
+            .unwrap()
+            .is_empty());
+        assert!(reassembler
+            .receive(&payload(follow_on(1, 1, true), &msg[900..]), now)
+            .unwrap()
+            .is_empty());
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(
+            reassembler.stats(),
+            EndpointStats {
+                corrupt: 1,
+                ..EndpointStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        let msg = message(10);
+        let mut payloads = fragments(TunnelMessageDeliveryType::Local, &msg);
+        payloads[0][1000] ^= 0xff;
+
+        let mut reassembler = Reassembler::default();
+        assert!(matches!(
+            reassembler.receive(&payloads[0], Instant::now()),
+            Err(EndpointError::Invalid)
+        ));
+        assert_eq!(reassembler.stats().invalid, 1);
+    }
+}
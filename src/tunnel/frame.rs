@@ -193,7 +193,7 @@ fn gen_tmdi<'a>(
 
 // TunnelMessage
 
-fn tunnel_message(i: &[u8]) -> IResult<&[u8], TunnelMessage> {
+pub(super) fn tunnel_message(i: &[u8]) -> IResult<&[u8], TunnelMessage> {
     let (i, (iv, cs, padding)) = terminated(
         tuple((take(16usize), be_u32, take_until(&b"\x00"[..]))),
         tag(&[0]),
@@ -220,7 +220,7 @@ fn gen_tmdi_fragment_pair<'a>(
     )
 }
 
-fn gen_tunnel_message<'a>(
+pub(super) fn gen_tunnel_message<'a>(
     input: (&'a mut [u8], usize),
     iv: &[u8],
     tm: &TunnelMessage,
@@ -0,0 +1,873 @@
+//! Processing at the gateway of a tunnel.
+//!
+//! The gateway fragments the I2NP messages it is given into the 1003 bytes of content
+//! that fit in each [`TunnelData`] message, batching small messages together. A
+//! message that doesn't fit in the space left is split into a first fragment and
+//! follow-on fragments, numbered from 1 to 63.
+//!
+//! Our outbound gateways hold small messages back for a short delay, so that messages
+//! sent close together share `TunnelData` messages instead of each being padded out to
+//! a whole one.
+//!
+//! See the ["Gateway Processing" section][gateway] of the tunnel implementation
+//! documentation for details.
+//!
+//! [`TunnelData`]: crate::i2np::TunnelData
+//! [gateway]: https://geti2p.net/en/docs/tunnels/implementation#tunnel.gateway
+
+use futures::{future, Future};
+use rand::Rng;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io;
+
+use super::{
+    crypto::LayerCipher, frame::gen_tunnel_message, FirstFragmentDeliveryInstructions,
+    FollowOnFragmentDeliveryInstructions, OwnTunnel, TunnelMessage,
+    TunnelMessageDeliveryInstructions, TunnelMessageDeliveryType, TunnelRole,
+};
+use crate::crypto::rand::{CryptoRng, OsRng};
+use crate::data::{RouterInfo, TunnelId};
+use crate::i2np::{frame::gen_message, Message, MessageIdGenerator};
+use crate::router::{
+    config::{self, Config},
+    types::{CommSystem, Distributor},
+};
+use crate::util::serialize;
+
+type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+/// The space in a `TunnelData` message for fragments and their delivery
+/// instructions.
+const MAX_CONTENT_LEN: usize = 1003;
+
+/// The length of the delivery instructions and size of a follow-on fragment.
+const FOLLOW_ON_OVERHEAD: usize = 5 + 2;
+
+/// Fragment numbers have six bits, so a message has at most this many follow-on
+/// fragments.
+pub(super) const MAX_FRAGMENT_NUMBER: u8 = 63;
+
+/// The largest I2NP message, with its header, that can be sent through a tunnel. The
+/// follow-on fragments alone can hold this much, so the first fragment can be any
+/// size.
+pub(super) const MAX_MESSAGE_LEN: usize =
+    MAX_FRAGMENT_NUMBER as usize * (MAX_CONTENT_LEN - FOLLOW_ON_OVERHEAD);
+
+/// A message is not fragmented into less space than this; the rest of the
+/// `TunnelData` message is padded instead.
+const MIN_FIRST_FRAGMENT_LEN: usize = 64;
+
+/// The default time that our outbound gateways hold small messages back for, in
+/// milliseconds.
+const BATCH_DELAY: u64 = 75;
+
+/// Tunnel gateway errors
+#[derive(Debug, PartialEq)]
+pub enum GatewayError {
+    /// The message is too large to send through a tunnel.
+    TooLarge(usize),
+}
+
+#[cfg_attr(tarpaulin, skip)]
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GatewayError::TooLarge(len) => {
+                write!(f, "Message of {} bytes is too large for a tunnel", len)
+            }
+        }
+    }
+}
+
+/// A message that hasn't been completely fragmented yet.
+struct PendingMessage {
+    delivery: TunnelMessageDeliveryType,
+    msg_id: u32,
+    data: Vec<u8>,
+    /// The start of the next fragment.
+    offset: usize,
+    fragment_number: u8,
+}
+
+/// Fragments serialized I2NP messages into the plaintext of `TunnelData` messages.
+#[derive(Default)]
+pub(super) struct Fragmenter {
+    queue: VecDeque<PendingMessage>,
+}
+
+impl Fragmenter {
+    /// Queues a serialized I2NP message. `msg_id` identifies its fragments, and so
+    /// must be unique among the messages sent through the tunnel.
+    pub(super) fn push(
+        &mut self,
+        delivery: TunnelMessageDeliveryType,
+        msg_id: u32,
+        data: Vec<u8>,
+    ) -> Result<(), GatewayError> {
+        if data.len() > MAX_MESSAGE_LEN {
+            return Err(GatewayError::TooLarge(data.len()));
+        }
+        self.queue.push_back(PendingMessage {
+            delivery,
+            msg_id,
+            data,
+            offset: 0,
+            fragment_number: 0,
+        });
+        Ok(())
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns true if the queued messages don't all fit in one `TunnelData` message.
+    fn is_full(&self) -> bool {
+        let plan = self.plan();
+        match plan.last() {
+            Some((_, end)) => {
+                plan.len() < self.queue.len() || *end < self.queue[plan.len() - 1].data.len()
+            }
+            None => false,
+        }
+    }
+
+    /// Chooses the fragments for the next `TunnelData` message: as many of the queued
+    /// messages as fit, followed by the first fragment of the next one if there is
+    /// enough space left. Entry `i` is the delivery instructions and end of a fragment
+    /// of the `i`th queued message.
+    fn plan(&self) -> Vec<(TunnelMessageDeliveryInstructions, usize)> {
+        let mut space = MAX_CONTENT_LEN;
+        let mut plan = vec![];
+        for pending in &self.queue {
+            let remaining = pending.data.len() - pending.offset;
+            if pending.offset == 0 {
+                let whole =
+                    TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                        delivery_type: pending.delivery.clone(),
+                        msg_id: None,
+                    });
+                let len = whole.byte_len() + 2 + remaining;
+                if len <= space {
+                    space -= len;
+                    plan.push((whole, pending.data.len()));
+                    continue;
+                }
+
+                let first =
+                    TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                        delivery_type: pending.delivery.clone(),
+                        msg_id: Some(pending.msg_id),
+                    });
+                let overhead = first.byte_len() + 2;
+                if space >= overhead + MIN_FIRST_FRAGMENT_LEN {
+                    plan.push((first, space - overhead));
+                }
+                break;
+            } else {
+                // Only the first queued message can have been partly sent
+                let len = remaining.min(space - FOLLOW_ON_OVERHEAD);
+                let last_fragment = len == remaining;
+                plan.push((
+                    TunnelMessageDeliveryInstructions::FollowOn(
+                        FollowOnFragmentDeliveryInstructions {
+                            fragment_number: pending.fragment_number,
+                            last_fragment,
+                            msg_id: pending.msg_id,
+                        },
+                    ),
+                    pending.offset + len,
+                ));
+                space -= FOLLOW_ON_OVERHEAD + len;
+                if !last_fragment || space <= FOLLOW_ON_OVERHEAD {
+                    break;
+                }
+            }
+        }
+        plan
+    }
+
+    /// Returns the plaintext of the next `TunnelData` message, with a random IV, or
+    /// `None` if nothing is queued.
+    pub(super) fn next_payload<R: CryptoRng>(&mut self, rng: &mut R) -> Option<[u8; 1024]> {
+        if self.queue.is_empty() {
+            return None;
+        }
+
+        let plan = self.plan();
+        let ends: Vec<_> = plan.iter().map(|(_, end)| *end).collect();
+
+        let mut iv = [0; 16];
+        rng.fill(&mut iv[..]);
+        let mut payload = [0; 1024];
+        let tm = TunnelMessage(
+            plan.into_iter()
+                .zip(self.queue.iter())
+                .map(|((tmdi, end), pending)| (tmdi, &pending.data[pending.offset..end]))
+                .collect(),
+        );
+        gen_tunnel_message((&mut payload[..], 0), &iv, &tm)
+            .expect("Planned fragments fit in a TunnelData message");
+
+        for (pending, end) in self.queue.iter_mut().zip(ends) {
+            pending.offset = end;
+            pending.fragment_number += 1;
+        }
+        while self
+            .queue
+            .front()
+            .map_or(false, |pending| pending.offset == pending.data.len())
+        {
+            self.queue.pop_front();
+        }
+
+        Some(payload)
+    }
+}
+
+/// Holds messages back for a delay, so that they can be batched with others.
+///
+/// No message is held for longer than the delay. Everything is sent early once the
+/// queued messages fill a `TunnelData` message, or once a priority message or one
+/// that needs fragmenting is queued.
+struct Batcher {
+    fragmenter: Fragmenter,
+    delay: Duration,
+    /// When the oldest held message must be sent.
+    deadline: Option<Instant>,
+    /// Whether everything queued should be sent without waiting.
+    flush: bool,
+}
+
+impl Batcher {
+    fn new(delay: Duration) -> Self {
+        Batcher {
+            fragmenter: Fragmenter::default(),
+            delay,
+            deadline: None,
+            flush: false,
+        }
+    }
+
+    fn push(
+        &mut self,
+        delivery: TunnelMessageDeliveryType,
+        msg_id: u32,
+        data: Vec<u8>,
+        priority: bool,
+        now: Instant,
+    ) -> Result<(), GatewayError> {
+        let whole = TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+            delivery_type: delivery.clone(),
+            msg_id: None,
+        });
+        let fragmented = whole.byte_len() + 2 + data.len() > MAX_CONTENT_LEN;
+
+        self.fragmenter.push(delivery, msg_id, data)?;
+        self.flush |= priority || fragmented;
+        if self.deadline.is_none() {
+            self.deadline = Some(now + self.delay);
+        }
+        Ok(())
+    }
+
+    /// Returns the plaintext of the next `TunnelData` message if it is due to be sent
+    /// by `now`.
+    fn next_due<R: CryptoRng>(&mut self, rng: &mut R, now: Instant) -> Option<[u8; 1024]> {
+        let due = self.flush
+            || self.deadline.map_or(false, |deadline| deadline <= now)
+            || self.fragmenter.is_full();
+        if due {
+            self.next_payload(rng)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the plaintext of the next `TunnelData` message, whether or not it is due.
+    fn next_payload<R: CryptoRng>(&mut self, rng: &mut R) -> Option<[u8; 1024]> {
+        let payload = self.fragmenter.next_payload(rng);
+        if self.fragmenter.is_empty() {
+            self.deadline = None;
+            self.flush = false;
+        }
+        payload
+    }
+}
+
+/// The gateway of one of our outbound tunnels.
+///
+/// Each `TunnelData` message is decrypted in advance with the layer keys of every
+/// hop, so that the endpoint sees the plaintext once each hop has added its layer.
+///
+/// In a zero-hop tunnel we are also the endpoint, so the messages are handed straight
+/// to our own [`Participant`](super::Participant) instead of going through the
+/// transports.
+///
+/// Small messages are held back for up to the batching delay. The gateway must be sent
+/// again by its [`deadline`](OutboundGateway::deadline) for them to go out in time.
+pub struct OutboundGateway {
+    first_hop: RouterInfo,
+    tid: TunnelId,
+    layers: Vec<LayerCipher>,
+    batcher: Batcher,
+    zero_hop: bool,
+    msg_ids: MessageIdGenerator,
+}
+
+impl OutboundGateway {
+    /// Returns the gateway for `tunnel`, which we registered as `tid`, or `None` if it
+    /// is an inbound tunnel.
+    pub fn new(tid: TunnelId, tunnel: &OwnTunnel, msg_ids: MessageIdGenerator) -> Option<Self> {
+        if tunnel.role != TunnelRole::Outbound {
+            return None;
+        }
+        Some(OutboundGateway {
+            first_hop: tunnel.first_hop.clone(),
+            tid: tunnel.hops.first().map_or(tid, |hop| hop.receive_tid),
+            layers: tunnel
+                .hops
+                .iter()
+                .map(|hop| hop.layer_cipher.clone())
+                .collect(),
+            batcher: Batcher::new(Duration::from_millis(BATCH_DELAY)),
+            zero_hop: tunnel.is_zero_hop(),
+            msg_ids,
+        })
+    }
+
+    /// Returns the gateway for `tunnel`, with the batching delay set in `config`.
+    pub fn from_config(
+        tid: TunnelId,
+        tunnel: &OwnTunnel,
+        config: &Config,
+        msg_ids: MessageIdGenerator,
+    ) -> Option<Self> {
+        let delay = config
+            .get_int(config::TUNNEL_GATEWAY_BATCH_DELAY)
+            .map(|v| v as u64)
+            .unwrap_or(BATCH_DELAY);
+        OutboundGateway::new(tid, tunnel, msg_ids)
+            .map(|gateway| gateway.batch_delay(Duration::from_millis(delay)))
+    }
+
+    /// Sets how long small messages are held back for. With no delay, every message
+    /// is sent straight away.
+    pub fn batch_delay(mut self, delay: Duration) -> Self {
+        self.batcher.delay = delay;
+        self
+    }
+
+    /// Queues `msg` to be delivered by the endpoint according to `delivery`. It may be
+    /// held back for up to the batching delay.
+    pub fn push(
+        &mut self,
+        delivery: TunnelMessageDeliveryType,
+        msg: &Message,
+    ) -> Result<(), GatewayError> {
+        self.push_at(delivery, msg, false, Instant::now())
+    }
+
+    /// Queues `msg` to be delivered by the endpoint according to `delivery`, and sent
+    /// without delay along with any messages that are being held back.
+    pub fn push_priority(
+        &mut self,
+        delivery: TunnelMessageDeliveryType,
+        msg: &Message,
+    ) -> Result<(), GatewayError> {
+        self.push_at(delivery, msg, true, Instant::now())
+    }
+
+    fn push_at(
+        &mut self,
+        delivery: TunnelMessageDeliveryType,
+        msg: &Message,
+        priority: bool,
+        now: Instant,
+    ) -> Result<(), GatewayError> {
+        let data = serialize(|input| gen_message(input, msg));
+        self.batcher.push(delivery, msg.id, data, priority, now)
+    }
+
+    /// Returns the time by which messages that are being held back must be sent, or
+    /// `None` if none are.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.batcher.deadline
+    }
+
+    /// Returns TunnelData messages holding everything that is queued, including the
+    /// messages being held back, ready to send to the first hop.
+    pub fn flush(&mut self) -> Vec<Message> {
+        self.flush_with_rng(&mut OsRng)
+    }
+
+    fn flush_with_rng<R: CryptoRng>(&mut self, rng: &mut R) -> Vec<Message> {
+        let mut msgs = vec![];
+        while let Some(data) = self.batcher.next_payload(rng) {
+            msgs.push(self.tunnel_data(data));
+        }
+        msgs
+    }
+
+    /// Returns TunnelData messages holding what is due to be sent by `now`.
+    fn due_with_rng<R: CryptoRng>(&mut self, rng: &mut R, now: Instant) -> Vec<Message> {
+        let mut msgs = vec![];
+        while let Some(data) = self.batcher.next_due(rng, now) {
+            msgs.push(self.tunnel_data(data));
+        }
+        msgs
+    }
+
+    fn tunnel_data(&self, mut data: [u8; 1024]) -> Message {
+        remove_layers(&self.layers, &mut data);
+        Message::tunnel_data(&self.msg_ids, self.tid, data)
+    }
+
+    /// Sends what is due to the first hop. Messages for a zero-hop tunnel are given to
+    /// `local` instead.
+    pub fn send<D: Distributor>(&mut self, comms: &dyn CommSystem, local: &D) -> IoFuture<()> {
+        let msgs = self.due_with_rng(&mut OsRng, Instant::now());
+        if self.zero_hop {
+            let us = self.first_hop.router_id.hash();
+            let sent: Vec<_> = msgs
+                .into_iter()
+                .map(|msg| {
+                    local.handle(us.clone(), msg).map_err(|_| {
+                        io::Error::new(io::ErrorKind::BrokenPipe, "Tunnel subsystem has shut down")
+                    })
+                })
+                .collect();
+            return Box::new(future::join_all(sent).map(|_| ()));
+        }
+
+        let sent: Vec<_> = msgs
+            .into_iter()
+            .map(|msg| match comms.send(self.first_hop.clone(), msg) {
+                Ok(f) => f,
+                Err((ri, _)) => Box::new(future::err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!(
+                        "Could not send to {} over any of our transports",
+                        ri.router_id.hash()
+                    ),
+                ))),
+            })
+            .collect();
+        Box::new(future::join_all(sent).map(|_| ()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::iter;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{Batcher, Fragmenter, GatewayError, OutboundGateway, MAX_MESSAGE_LEN};
+    use crate::crypto::{rand::TestRng, SessionKey};
+    use crate::data::{Hash, RouterInfo, RouterSecretKeys, TunnelId};
+    use crate::i2np::{Message, MessageIdGenerator, MessagePayload};
+    use crate::tunnel::{
+        crypto::LayerCipher, frame::tunnel_message, FirstFragmentDeliveryInstructions, OwnTunnel,
+        TunnelHop, TunnelMessageDeliveryInstructions, TunnelMessageDeliveryType, TunnelRole,
+    };
+
+    /// Lays out a TunnelData plaintext as described in the specification: the IV, the
+    /// checksum, non-zero padding, a zero byte, and then the fragments.
+    fn expected_payload(iv: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::default();
+        hasher.update(content);
+        hasher.update(iv);
+
+        let mut payload = iv.to_vec();
+        payload.extend_from_slice(&hasher.finalize()[0..4]);
+        payload.extend(iter::repeat(1).take(1008 - 4 - 1 - content.len()));
+        payload.push(0);
+        payload.extend_from_slice(content);
+        payload
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    /// Returns the ID of the message that each fragment in `payload` belongs to.
+    /// Messages that weren't fragmented are identified by their first byte.
+    fn message_ids(payload: &[u8; 1024]) -> Vec<u32> {
+        let (_, tm) = tunnel_message(payload).unwrap();
+        tm.0.iter()
+            .map(|(tmdi, data)| match tmdi {
+                TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                    msg_id: Some(msg_id),
+                    ..
+                }) => *msg_id,
+                TunnelMessageDeliveryInstructions::First(_) => u32::from(data[0]),
+                TunnelMessageDeliveryInstructions::FollowOn(fofdi) => fofdi.msg_id,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_fragment() {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let mut fragmenter = Fragmenter::default();
+        fragmenter
+            .push(TunnelMessageDeliveryType::Local, 1234, data(10))
+            .unwrap();
+
+        let payload = fragmenter.next_payload(&mut rng).unwrap();
+        let mut content = vec![0x00, 0x00, 0x0a];
+        content.extend(data(10));
+        assert_eq!(
+            &payload[..],
+            &expected_payload(&payload[0..16], &content)[..]
+        );
+
+        assert!(fragmenter.is_empty());
+        assert!(fragmenter.next_payload(&mut rng).is_none());
+    }
+
+    #[test]
+    fn multiple_fragments() {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let mut fragmenter = Fragmenter::default();
+        let msg = data(1500);
+        fragmenter
+            .push(
+                TunnelMessageDeliveryType::Router(Hash([7; 32])),
+                0x0102_0304,
+                msg.clone(),
+            )
+            .unwrap();
+
+        // The first fragment fills the message, with no padding
+        let payload = fragmenter.next_payload(&mut rng).unwrap();
+        let mut content = vec![0x48];
+        content.extend_from_slice(&[7; 32]);
+        content.extend_from_slice(&[0x01, 0x02, 0x03, 0x04, 0x03, 0xc4]);
+        content.extend_from_slice(&msg[..964]);
+        assert_eq!(content.len(), 1003);
+        assert_eq!(
+            &payload[..],
+            &expected_payload(&payload[0..16], &content)[..]
+        );
+
+        // The follow-on fragment is the last
+        let payload = fragmenter.next_payload(&mut rng).unwrap();
+        let mut content = vec![0x83, 0x01, 0x02, 0x03, 0x04, 0x02, 0x18];
+        content.extend_from_slice(&msg[964..]);
+        assert_eq!(
+            &payload[..],
+            &expected_payload(&payload[0..16], &content)[..]
+        );
+
+        assert!(fragmenter.next_payload(&mut rng).is_none());
+    }
+
+    #[test]
+    fn batched_messages() {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let mut fragmenter = Fragmenter::default();
+        fragmenter
+            .push(TunnelMessageDeliveryType::Local, 1, data(3))
+            .unwrap();
+        fragmenter
+            .push(TunnelMessageDeliveryType::Router(Hash([7; 32])), 2, data(2))
+            .unwrap();
+        fragmenter
+            .push(
+                TunnelMessageDeliveryType::Tunnel(TunnelId(0x0506_0708), Hash([9; 32])),
+                3,
+                data(1),
+            )
+            .unwrap();
+
+        // All three messages fit in one TunnelData message
+        let payload = fragmenter.next_payload(&mut rng).unwrap();
+        let mut content = vec![0x00, 0x00, 0x03, 0x00, 0x01, 0x02];
+        content.push(0x40);
+        content.extend_from_slice(&[7; 32]);
+        content.extend_from_slice(&[0x00, 0x02, 0x00, 0x01]);
+        content.extend_from_slice(&[0x20, 0x05, 0x06, 0x07, 0x08]);
+        content.extend_from_slice(&[9; 32]);
+        content.extend_from_slice(&[0x00, 0x01, 0x00]);
+        assert_eq!(
+            &payload[..],
+            &expected_payload(&payload[0..16], &content)[..]
+        );
+        assert!(fragmenter.next_payload(&mut rng).is_none());
+
+        // A message that doesn't fit in the space left is fragmented into it
+        let big = data(1500);
+        fragmenter
+            .push(TunnelMessageDeliveryType::Local, 4, data(100))
+            .unwrap();
+        fragmenter
+            .push(TunnelMessageDeliveryType::Local, 5, big.clone())
+            .unwrap();
+
+        let payload = fragmenter.next_payload(&mut rng).unwrap();
+        let mut content = vec![0x00, 0x00, 0x64];
+        content.extend(data(100));
+        content.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0x05, 0x03, 0x7d]);
+        content.extend_from_slice(&big[..893]);
+        assert_eq!(content.len(), 1003);
+        assert_eq!(
+            &payload[..],
+            &expected_payload(&payload[0..16], &content)[..]
+        );
+
+        let payload = fragmenter.next_payload(&mut rng).unwrap();
+        let mut content = vec![0x83, 0x00, 0x00, 0x00, 0x05, 0x02, 0x5f];
+        content.extend_from_slice(&big[893..]);
+        assert_eq!(
+            &payload[..],
+            &expected_payload(&payload[0..16], &content)[..]
+        );
+        assert!(fragmenter.is_empty());
+    }
+
+    #[test]
+    fn too_large() {
+        let mut fragmenter = Fragmenter::default();
+        assert_eq!(
+            fragmenter.push(
+                TunnelMessageDeliveryType::Local,
+                1,
+                data(MAX_MESSAGE_LEN + 1)
+            ),
+            Err(GatewayError::TooLarge(MAX_MESSAGE_LEN + 1))
+        );
+
+        // The largest message with the largest first fragment instructions needs
+        // every fragment number
+        let mut rng = TestRng::from_seed([1; 32]);
+        fragmenter
+            .push(
+                TunnelMessageDeliveryType::Tunnel(TunnelId(1), Hash([9; 32])),
+                1,
+                data(MAX_MESSAGE_LEN),
+            )
+            .unwrap();
+        let mut payloads = 0;
+        while fragmenter.next_payload(&mut rng).is_some() {
+            payloads += 1;
+        }
+        assert_eq!(payloads, 64);
+    }
+
+    #[test]
+    fn batching_delay() {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let delay = Duration::from_millis(100);
+        let mut batcher = Batcher::new(delay);
+        let t0 = Instant::now();
+        let ms = Duration::from_millis(1);
+
+        // Small messages wait for others
+        for i in 0..3u8 {
+            let now = t0 + ms * 10 * u32::from(i);
+            batcher
+                .push(
+                    TunnelMessageDeliveryType::Local,
+                    i.into(),
+                    vec![i; 100],
+                    false,
+                    now,
+                )
+                .unwrap();
+            assert!(batcher.next_due(&mut rng, now).is_none());
+        }
+        assert_eq!(batcher.deadline, Some(t0 + delay));
+        assert!(batcher.next_due(&mut rng, t0 + delay - ms).is_none());
+
+        // Until the oldest has waited for the delay, when they share a TunnelData message
+        let payload = batcher.next_due(&mut rng, t0 + delay).unwrap();
+        assert_eq!(message_ids(&payload), vec![0, 1, 2]);
+        assert!(batcher.next_due(&mut rng, t0 + delay).is_none());
+        assert_eq!(batcher.deadline, None);
+
+        // With no delay, messages are sent straight away
+        let mut batcher = Batcher::new(Duration::from_secs(0));
+        batcher
+            .push(TunnelMessageDeliveryType::Local, 3, vec![3; 100], false, t0)
+            .unwrap();
+        let payload = batcher.next_due(&mut rng, t0).unwrap();
+        assert_eq!(message_ids(&payload), vec![3]);
+    }
+
+    #[test]
+    fn early_flush() {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let delay = Duration::from_millis(100);
+        let t0 = Instant::now();
+        let t1 = t0 + Duration::from_millis(10);
+
+        // Once the queued messages fill a TunnelData message, it is sent, and the rest
+        // waits
+        let mut batcher = Batcher::new(delay);
+        for i in 0..7u8 {
+            batcher
+                .push(
+                    TunnelMessageDeliveryType::Local,
+                    i.into(),
+                    vec![i; 150],
+                    false,
+                    t0,
+                )
+                .unwrap();
+        }
+        let payload = batcher.next_due(&mut rng, t0).unwrap();
+        assert_eq!(message_ids(&payload), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert!(batcher.next_due(&mut rng, t0).is_none());
+        let payload = batcher.next_due(&mut rng, t0 + delay).unwrap();
+        assert_eq!(message_ids(&payload), vec![6]);
+
+        // A priority message is sent straight away, along with what was waiting
+        let mut batcher = Batcher::new(delay);
+        batcher
+            .push(TunnelMessageDeliveryType::Local, 0, vec![0; 10], false, t0)
+            .unwrap();
+        batcher
+            .push(TunnelMessageDeliveryType::Local, 1, vec![1; 10], true, t1)
+            .unwrap();
+        let payload = batcher.next_due(&mut rng, t1).unwrap();
+        assert_eq!(message_ids(&payload), vec![0, 1]);
+        assert!(batcher.next_due(&mut rng, t1).is_none());
+        assert_eq!(batcher.deadline, None);
+
+        // So is a message that needs fragmenting, after what was waiting
+        let mut batcher = Batcher::new(delay);
+        batcher
+            .push(TunnelMessageDeliveryType::Local, 0, vec![0; 10], false, t0)
+            .unwrap();
+        batcher
+            .push(
+                TunnelMessageDeliveryType::Local,
+                1,
+                vec![1; 1500],
+                false,
+                t1,
+            )
+            .unwrap();
+        let payload = batcher.next_due(&mut rng, t1).unwrap();
+        assert_eq!(message_ids(&payload), vec![0, 1]);
+        let payload = batcher.next_due(&mut rng, t1).unwrap();
+        assert_eq!(message_ids(&payload), vec![1]);
+        assert!(batcher.next_due(&mut rng, t1).is_none());
+        assert!(batcher.fragmenter.is_empty());
+    }
+
+    #[test]
+    fn latency_bound() {
+        let mut rng = TestRng::from_seed([1; 32]);
+        let delay = Duration::from_millis(50);
+        let mut batcher = Batcher::new(delay);
+        let t0 = Instant::now();
+
+        // Messages of all sizes arrive at random, and the batcher is polled every
+        // millisecond
+        let mut queued = vec![];
+        let mut sent = HashMap::new();
+        for tick in 0..2000u32 {
+            let now = t0 + Duration::from_millis(tick.into());
+            if tick < 1900 && rng.gen_bool(0.1) {
+                let id = queued.len() as u8;
+                let len = if rng.gen_bool(0.1) {
+                    1500
+                } else {
+                    rng.gen_range(1..300)
+                };
+                let priority = rng.gen_bool(0.05);
+                batcher
+                    .push(
+                        TunnelMessageDeliveryType::Local,
+                        id.into(),
+                        vec![id; len],
+                        priority,
+                        now,
+                    )
+                    .unwrap();
+                queued.push(now);
+            }
+            while let Some(payload) = batcher.next_due(&mut rng, now) {
+                for id in message_ids(&payload) {
+                    sent.insert(id, now);
+                }
+            }
+        }
+
+        // Every message was completely sent within the delay
+        assert!(queued.len() > 100 && queued.len() < 256);
+        assert_eq!(sent.len(), queued.len());
+        for (id, last_sent) in sent {
+            assert!(last_sent - queued[id as usize] <= delay);
+        }
+    }
+
+    #[test]
+    fn outbound_layers() {
+        let hops: Vec<_> = (0..3u8)
+            .map(|i| TunnelHop {
+                ident: Hash([i; 32]),
+                receive_tid: TunnelId(u32::from(i) + 10),
+                layer_cipher: LayerCipher::new(&SessionKey([i; 32]), SessionKey([i + 3; 32])),
+            })
+            .collect();
+        let tunnel = OwnTunnel {
+            role: TunnelRole::Outbound,
+            hops,
+            first_hop: RouterInfo::new(RouterSecretKeys::new().rid),
+            expires: SystemTime::now(),
+        };
+
+        let mut gateway =
+            OutboundGateway::new(TunnelId(1), &tunnel, MessageIdGenerator::default()).unwrap();
+        let msg = Message::dummy_data();
+        gateway
+            .push(TunnelMessageDeliveryType::Router(Hash([5; 32])), &msg)
+            .unwrap();
+        let mut rng = TestRng::from_seed([1; 32]);
+        let msgs = gateway.flush_with_rng(&mut rng);
+        assert_eq!(msgs.len(), 1);
+
+        let mut data = match &msgs[0].payload {
+            MessagePayload::TunnelData(td) => {
+                assert_eq!(td.tid, TunnelId(10));
+                *td.data()
+            }
+            _ => panic!("Unexpected message: {}", msgs[0]),
+        };
+
+        // Each hop adds its layer, and the endpoint can read the message
+        for hop in &tunnel.hops {
+            hop.layer_cipher.encrypt_layer(&mut data);
+        }
+        let (_, tm) = tunnel_message(&data).unwrap();
+        assert_eq!(tm.0.len(), 1);
+        assert_eq!(
+            tm.0[0].0,
+            TunnelMessageDeliveryInstructions::First(FirstFragmentDeliveryInstructions {
+                delivery_type: TunnelMessageDeliveryType::Router(Hash([5; 32])),
+                msg_id: None,
+            })
+        );
+        assert_eq!(
+            tm.0[0].1,
+            &crate::util::serialize(|input| crate::i2np::frame::gen_message(input, &msg))[..]
+        );
+
+        // Inbound tunnels have no gateway on our side
+        let inbound = OwnTunnel {
+            role: TunnelRole::Inbound,
+            ..tunnel
+        };
+        assert!(
+            OutboundGateway::new(TunnelId(1), &inbound, MessageIdGenerator::default()).is_none()
+        );
+    }
+}
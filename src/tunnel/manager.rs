@@ -0,0 +1,213 @@
+//! The pools of tunnels that we build for ourselves.
+//!
+//! The exploratory pool carries the router's own traffic: netDb lookups and stores, and
+//! tunnel build messages. Each local destination has a client pool of its own, with
+//! stricter hop selection. Pools never share tunnels, so that a destination's traffic
+//! can't be linked to the router's, or to another destination's.
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::pool::{PoolConfig, TunnelBuilder, TunnelPool};
+use crate::data::Hash;
+use crate::router::config::Config;
+
+/// Maintains the exploratory pool, and a client pool for each local destination.
+///
+/// Like [`TunnelPool`], the manager is driven by calling [`TunnelManager::poll`]
+/// regularly, which must be done from within a task.
+pub struct TunnelManager<B: TunnelBuilder + Clone> {
+    builder: B,
+    exploratory: TunnelPool<B>,
+    client_config: PoolConfig,
+    clients: HashMap<Hash, TunnelPool<B>>,
+}
+
+impl<B: TunnelBuilder + Clone> TunnelManager<B> {
+    pub fn new(builder: B, exploratory: PoolConfig, client: PoolConfig) -> Self {
+        TunnelManager {
+            exploratory: TunnelPool::new(builder.clone(), exploratory),
+            builder,
+            client_config: client,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Creates a manager with the pool settings in `config`.
+    pub fn from_config(builder: B, config: &Config) -> Self {
+        TunnelManager::new(
+            builder,
+            PoolConfig::exploratory(config),
+            PoolConfig::client(config),
+        )
+    }
+
+    /// Returns the pool for the router's own traffic.
+    pub fn exploratory(&mut self) -> &mut TunnelPool<B> {
+        &mut self.exploratory
+    }
+
+    /// Returns the pool for the local destination `dest`, creating it if this is the
+    /// first time it has been asked for.
+    pub fn client(&mut self, dest: &Hash) -> &mut TunnelPool<B> {
+        let (builder, config) = (&self.builder, &self.client_config);
+        self.clients.entry(dest.clone()).or_insert_with(|| {
+            debug!("Creating tunnel pool for {}", dest);
+            TunnelPool::new(builder.clone(), config.clone())
+        })
+    }
+
+    /// Stops building tunnels for `dest`, returning false if it had no pool. Its
+    /// existing tunnels are left to expire.
+    pub fn remove_client(&mut self, dest: &Hash) -> bool {
+        self.clients.remove(dest).is_some()
+    }
+
+    /// Returns the pool that traffic from `dest` should use: its client pool, or the
+    /// exploratory pool for traffic that isn't from a local destination.
+    pub fn pool_for(&mut self, dest: Option<&Hash>) -> &mut TunnelPool<B> {
+        match dest {
+            Some(dest) => self.client(dest),
+            None => self.exploratory(),
+        }
+    }
+
+    /// Polls every pool. Client pools build through the exploratory pool's tunnels;
+    /// see [`TunnelPool::poll_through`].
+    pub fn poll(&mut self, now: SystemTime) {
+        self.exploratory.poll(now);
+        for pool in self.clients.values_mut() {
+            pool.poll_through(&mut self.exploratory, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, Future};
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    use super::TunnelManager;
+    use crate::data::{Bandwidth, Hash, TunnelId};
+    use crate::router::config::{self, Config};
+    use crate::tunnel::{
+        BuildError, BuildPath, BuiltTunnel, HopSelector, PoolConfig, TunnelBuilder, TunnelRole,
+    };
+
+    /// Builds every tunnel immediately, with a new ID and as many hops as the
+    /// selector asks for.
+    #[derive(Clone, Default)]
+    struct MockBuilder {
+        builds: Arc<Mutex<Vec<(TunnelRole, HopSelector, BuildPath)>>>,
+    }
+
+    impl TunnelBuilder for MockBuilder {
+        type Future = Box<dyn Future<Item = BuiltTunnel, Error = BuildError> + Send>;
+
+        fn build(&self, role: TunnelRole, selector: &HopSelector, path: BuildPath) -> Self::Future {
+            let mut builds = self.builds.lock().unwrap();
+            builds.push((role, selector.clone(), path));
+            let n = builds.len();
+            let len = selector.hop_count(&mut rand::thread_rng());
+            Box::new(future::ok(BuiltTunnel {
+                role,
+                tid: TunnelId(n as u32),
+                hops: (0..len).map(|i| Hash([(n * 8 + i) as u8; 32])).collect(),
+            }))
+        }
+
+        fn zero_hop(&self, role: TunnelRole) -> Self::Future {
+            Box::new(future::empty())
+        }
+    }
+
+    #[test]
+    fn settings_from_config() {
+        let mut cfg = Config::default();
+        cfg.set(config::TUNNEL_EXPLORATORY_LENGTH, 1).unwrap();
+        cfg.set(config::TUNNEL_EXPLORATORY_QUANTITY, 3).unwrap();
+        cfg.set(config::TUNNEL_CLIENT_VARIANCE, 1).unwrap();
+        cfg.set(config::TUNNEL_CLIENT_BACKUP, 2).unwrap();
+        cfg.set(config::TUNNEL_CLIENT_MIN_BANDWIDTH, "O").unwrap();
+
+        let exploratory = PoolConfig::exploratory(&cfg);
+        assert_eq!(exploratory.selector, HopSelector::new(1).min_length(1));
+        assert_eq!((exploratory.quantity, exploratory.backup_quantity), (3, 0));
+        assert!(exploratory.zero_hop_fallback);
+
+        let client = PoolConfig::client(&cfg);
+        assert_eq!(
+            client.selector,
+            HopSelector::new(3)
+                .length_variance(1)
+                .exclude_floodfills(true)
+                .high_capacity(true)
+                .min_bandwidth(Bandwidth::O)
+        );
+        assert_eq!((client.quantity, client.backup_quantity), (2, 2));
+        assert!(!client.zero_hop_fallback);
+
+        // Invalid bandwidth classes are ignored
+        cfg.set(config::TUNNEL_CLIENT_MIN_BANDWIDTH, "OX").unwrap();
+        assert_eq!(
+            PoolConfig::client(&cfg).selector,
+            HopSelector::new(3)
+                .length_variance(1)
+                .exclude_floodfills(true)
+                .high_capacity(true)
+        );
+    }
+
+    #[test]
+    fn separate_pools() {
+        let builder = MockBuilder::default();
+        let mut manager = TunnelManager::from_config(builder.clone(), &Config::default());
+        let exploratory_selector = manager.exploratory().config().selector.clone();
+        let client_selector = PoolConfig::client(&Config::default()).selector;
+        let (alice, bob) = (Hash([1; 32]), Hash([2; 32]));
+        let now = SystemTime::now();
+
+        // Each pool builds its own tunnels, with its own settings
+        manager.client(&alice);
+        manager.poll(now);
+        {
+            let builds = builder.builds.lock().unwrap();
+            assert_eq!(builds.len(), 4 + 6);
+            assert!(builds[..4]
+                .iter()
+                .all(|(_, s, _)| *s == exploratory_selector));
+            assert!(builds[4..].iter().all(|(_, s, _)| *s == client_selector));
+
+            // Only the exploratory pool builds directly, while it has no tunnels
+            assert!(builds[..4].iter().all(|(_, _, p)| *p == BuildPath::Direct));
+            assert!(builds[4..].iter().all(|(_, _, p)| *p != BuildPath::Direct));
+        }
+        let stats = manager.client(&alice).stats();
+        assert_eq!((stats.inbound, stats.outbound), (3, 3));
+
+        // Pools don't share tunnels
+        manager.client(&bob);
+        manager.poll(now);
+        let mut seen = vec![];
+        for dest in &[None, Some(&alice), Some(&bob)] {
+            let pool = manager.pool_for(*dest);
+            let mut tids = vec![];
+            for _ in 0..6 {
+                tids.push(pool.select_inbound(now).unwrap().tid);
+                tids.push(pool.select_outbound(now).unwrap().tid);
+            }
+            assert!(tids.iter().all(|tid| !seen.contains(tid)));
+            seen.extend(tids);
+        }
+        let exploratory = manager.exploratory().select_outbound(now).unwrap();
+        let client = manager.client(&alice).select_outbound(now).unwrap();
+        assert_eq!(exploratory.hops.len(), 2);
+        assert_eq!(client.hops.len(), 3);
+
+        // Removed destinations no longer have a pool
+        assert!(manager.remove_client(&bob));
+        assert!(!manager.remove_client(&bob));
+        assert_eq!(manager.clients.len(), 1);
+    }
+}
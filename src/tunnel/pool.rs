@@ -0,0 +1,1142 @@
+//! Pools of tunnels that we keep built.
+//!
+//! A pool tries to always have `quantity + backup_quantity` live tunnels in each
+//! direction. Tunnels only live for ten minutes, so each one is replaced by a new build
+//! that starts [`REBUILD_AHEAD`] seconds before it expires, and stops being selected
+//! [`SELECTION_CUTOFF`] seconds before it expires, so that the messages sent through
+//! it have time to arrive. Failed builds are retried
+//! after a delay that grows with each consecutive failure, and hops that rejected a
+//! build are not used for the next ones.
+//!
+//! If the pool is given a [`ProbeSender`], its tunnels are tested periodically. A
+//! tunnel that fails several tests in a row is no longer selected, and is replaced as
+//! if it were about to expire. It is used again if a later test succeeds.
+//!
+//! A pool can fall back to zero-hop tunnels when it can't build real ones, either
+//! because builds keep failing or because we don't know enough peers yet. Zero-hop
+//! tunnels are only selected while the pool has no other usable tunnels in that
+//! direction, and real builds carry on being retried meanwhile.
+//!
+//! Builds go through exploratory tunnels, as described in [`BuildPath`]. The
+//! exploratory pool builds through its own tunnels, and only builds directly while it
+//! has none. Other pools build through the exploratory pool's tunnels, and wait for
+//! it to have some instead of building directly.
+
+use futures::{Async, Future};
+use rand::{thread_rng, Rng};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+
+use super::{
+    acceptor::TUNNEL_ACCEPT,
+    tester::{ProbeSender, TestConfig, Tester},
+    BuildError, BuildPath, BuildTunnel, BuiltTunnel, Creator, HopSelector, TunnelRole,
+    TUNNEL_LIFETIME,
+};
+use crate::data::{Bandwidth, Hash, TunnelId};
+use crate::router::{
+    config::{self, Config},
+    profiles::Profiles,
+};
+
+/// How long before a tunnel expires that we start building its replacement, in
+/// seconds.
+const REBUILD_AHEAD: u64 = 2 * 60;
+
+/// How long before a tunnel expires that we stop selecting it for new traffic, in
+/// seconds.
+const SELECTION_CUTOFF: u64 = 30;
+
+/// The delay before retrying after a failed build, in seconds. It doubles with each
+/// consecutive failure, up to [`MAX_RETRY_DELAY`].
+const RETRY_DELAY: u64 = 5;
+const MAX_RETRY_DELAY: u64 = 60;
+
+/// The number of peers that rejected builds which we remember, and avoid.
+const MAX_EXCLUDED_PEERS: usize = 16;
+
+/// The number of builds in a row that must fail before a pool falls back to zero-hop
+/// tunnels.
+const ZERO_HOP_AFTER: u32 = 3;
+
+/// The default settings for the exploratory pool.
+const EXPLORATORY_LENGTH: usize = 2;
+const EXPLORATORY_QUANTITY: usize = 2;
+const EXPLORATORY_BACKUP: usize = 0;
+
+/// The default settings for client pools.
+const CLIENT_LENGTH: usize = 3;
+const CLIENT_QUANTITY: usize = 2;
+const CLIENT_BACKUP: usize = 1;
+
+/// Something that builds tunnels for a pool.
+pub trait TunnelBuilder {
+    type Future: Future<Item = BuiltTunnel, Error = BuildError>;
+
+    fn build(&self, role: TunnelRole, selector: &HopSelector, path: BuildPath) -> Self::Future;
+
+    /// Creates a tunnel in which we are both the gateway and the endpoint.
+    fn zero_hop(&self, role: TunnelRole) -> Self::Future;
+}
+
+impl TunnelBuilder for Creator {
+    type Future = BuildTunnel;
+
+    fn build(&self, role: TunnelRole, selector: &HopSelector, path: BuildPath) -> BuildTunnel {
+        Creator::build(self, role, selector, path)
+    }
+
+    fn zero_hop(&self, role: TunnelRole) -> BuildTunnel {
+        Creator::zero_hop(self, role)
+    }
+}
+
+/// How consumers are given a tunnel from a pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selection {
+    /// Each tunnel is used in turn.
+    RoundRobin,
+    /// A tunnel is chosen at random each time.
+    Random,
+}
+
+/// The settings for a pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    /// Chooses the hops of each tunnel, and therefore its length.
+    pub selector: HopSelector,
+    /// The number of tunnels the pool needs in each direction.
+    pub quantity: usize,
+    /// Extra tunnels kept in each direction, in case some of them fail.
+    pub backup_quantity: usize,
+    pub selection: Selection,
+    /// Whether to use zero-hop tunnels when real ones can't be built.
+    pub zero_hop_fallback: bool,
+}
+
+impl PoolConfig {
+    pub fn new(selector: HopSelector, quantity: usize) -> Self {
+        PoolConfig {
+            selector,
+            quantity,
+            backup_quantity: 0,
+            selection: Selection::RoundRobin,
+            zero_hop_fallback: false,
+        }
+    }
+
+    pub fn backup_quantity(mut self, backup_quantity: usize) -> Self {
+        self.backup_quantity = backup_quantity;
+        self
+    }
+
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    pub fn zero_hop_fallback(mut self, zero_hop_fallback: bool) -> Self {
+        self.zero_hop_fallback = zero_hop_fallback;
+        self
+    }
+
+    /// Returns the settings for the exploratory pool from `config`.
+    ///
+    /// Exploratory tunnels carry our netDb traffic and build messages. They may use
+    /// any peers, are shortened when we don't know enough of them, and fall back to
+    /// zero-hop tunnels when they can't be built at all.
+    pub fn exploratory(config: &Config) -> Self {
+        let selector = hop_selector(
+            config,
+            config::TUNNEL_EXPLORATORY_LENGTH,
+            config::TUNNEL_EXPLORATORY_VARIANCE,
+            config::TUNNEL_EXPLORATORY_MIN_BANDWIDTH,
+            EXPLORATORY_LENGTH,
+        )
+        .min_length(1);
+        PoolConfig::new(
+            selector,
+            get(
+                config,
+                config::TUNNEL_EXPLORATORY_QUANTITY,
+                EXPLORATORY_QUANTITY,
+            ),
+        )
+        .backup_quantity(get(
+            config,
+            config::TUNNEL_EXPLORATORY_BACKUP,
+            EXPLORATORY_BACKUP,
+        ))
+        .zero_hop_fallback(true)
+    }
+
+    /// Returns the settings for the pools of our local destinations from `config`.
+    ///
+    /// Client tunnels never use floodfills, so that their traffic can't be linked to
+    /// the floodfills' netDb activity, and prefer peers with high capacity scores.
+    pub fn client(config: &Config) -> Self {
+        let selector = hop_selector(
+            config,
+            config::TUNNEL_CLIENT_LENGTH,
+            config::TUNNEL_CLIENT_VARIANCE,
+            config::TUNNEL_CLIENT_MIN_BANDWIDTH,
+            CLIENT_LENGTH,
+        )
+        .exclude_floodfills(true)
+        .high_capacity(true);
+        PoolConfig::new(
+            selector,
+            get(config, config::TUNNEL_CLIENT_QUANTITY, CLIENT_QUANTITY),
+        )
+        .backup_quantity(get(config, config::TUNNEL_CLIENT_BACKUP, CLIENT_BACKUP))
+    }
+
+    fn target(&self) -> usize {
+        self.quantity + self.backup_quantity
+    }
+}
+
+/// Returns the integer setting at `key`, or `default` if it is unset or out of range.
+fn get<T: TryFrom<i64>>(config: &Config, key: &str, default: T) -> T {
+    config
+        .get_int(key)
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+        .unwrap_or(default)
+}
+
+/// Reads the hop selection settings of one kind of pool.
+fn hop_selector(
+    config: &Config,
+    length_key: &str,
+    variance_key: &str,
+    min_bandwidth_key: &str,
+    default_length: usize,
+) -> HopSelector {
+    let length = get(config, length_key, default_length);
+    let selector = HopSelector::new(length).length_variance(get(config, variance_key, 0));
+    let min_bandwidth = match config.get_str(min_bandwidth_key) {
+        Ok(class) => {
+            let mut chars = class.chars();
+            match (chars.next().and_then(Bandwidth::from_code), chars.next()) {
+                (Some(bandwidth), None) => Some(bandwidth),
+                _ => {
+                    warn!(
+                        "Ignoring invalid bandwidth class {} for {}",
+                        class, min_bandwidth_key
+                    );
+                    None
+                }
+            }
+        }
+        Err(_) => None,
+    };
+    match min_bandwidth {
+        Some(bandwidth) => selector.min_bandwidth(bandwidth),
+        None => selector,
+    }
+}
+
+/// The state of a pool.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    /// Live tunnels in each direction.
+    pub inbound: usize,
+    pub outbound: usize,
+    /// Builds that haven't finished yet.
+    pub building: usize,
+    pub builds_succeeded: u64,
+    pub builds_failed: u64,
+    /// The result of the most recent build to finish.
+    pub last_build: Option<Result<TunnelId, BuildError>>,
+    /// Live tunnels that have failed too many tests to be used.
+    pub failing: usize,
+    pub tests_succeeded: u64,
+    pub tests_failed: u64,
+    /// The round-trip time of the most recent successful test.
+    pub last_test_rtt: Option<Duration>,
+    /// Live zero-hop tunnels, in either direction.
+    pub zero_hop: usize,
+}
+
+struct PoolTunnel {
+    tunnel: BuiltTunnel,
+    expires: SystemTime,
+    /// The number of tests in a row that the tunnel has failed.
+    test_failures: u32,
+    failing: bool,
+}
+
+impl PoolTunnel {
+    fn is_zero_hop(&self) -> bool {
+        self.tunnel.hops.is_empty()
+    }
+}
+
+/// The tunnels of one pool in one direction.
+#[derive(Default)]
+struct Tunnels {
+    tunnels: Vec<PoolTunnel>,
+    next: usize,
+}
+
+impl Tunnels {
+    fn live(&self, now: SystemTime) -> impl Iterator<Item = &PoolTunnel> + '_ {
+        self.tunnels.iter().filter(move |t| t.expires > now)
+    }
+
+    /// Returns the tunnels that aren't about to expire, and haven't failed too many
+    /// tests.
+    fn usable(&self, now: SystemTime) -> impl Iterator<Item = &PoolTunnel> + '_ {
+        let cutoff = now + Duration::from_secs(SELECTION_CUTOFF);
+        self.tunnels
+            .iter()
+            .filter(move |t| t.expires > cutoff && !t.failing)
+    }
+
+    /// Returns the number of tunnels that won't need replacing soon. Zero-hop tunnels
+    /// always need replacing by real ones.
+    fn current(&self, now: SystemTime) -> usize {
+        let replace_after = now + Duration::from_secs(REBUILD_AHEAD);
+        self.tunnels
+            .iter()
+            .filter(|t| t.expires > replace_after && !t.failing && !t.is_zero_hop())
+            .count()
+    }
+
+    /// Records the result of a test of the given tunnel, if it is still in the pool.
+    fn tested(&mut self, tid: TunnelId, succeeded: bool, failure_threshold: u32) {
+        if let Some(t) = self.tunnels.iter_mut().find(|t| t.tunnel.tid == tid) {
+            if succeeded {
+                if t.failing {
+                    debug!("Tunnel {} has recovered", tid);
+                }
+                t.test_failures = 0;
+                t.failing = false;
+            } else {
+                t.test_failures += 1;
+                if !t.failing && t.test_failures >= failure_threshold {
+                    debug!("Tunnel {} has failed {} tests", tid, t.test_failures);
+                    t.failing = true;
+                }
+            }
+        }
+    }
+
+    /// Returns the usable tunnels to select from, which are zero-hop tunnels only if
+    /// there is nothing else.
+    fn candidates(&self, now: SystemTime) -> impl Iterator<Item = &PoolTunnel> + '_ {
+        let zero_hop = self.usable(now).all(|t| t.is_zero_hop());
+        self.usable(now)
+            .filter(move |t| t.is_zero_hop() == zero_hop)
+    }
+
+    fn select(&mut self, selection: Selection, now: SystemTime) -> Option<BuiltTunnel> {
+        let count = self.candidates(now).count();
+        if count == 0 {
+            return None;
+        }
+        let i = match selection {
+            Selection::RoundRobin => {
+                self.next = self.next.wrapping_add(1);
+                self.next % count
+            }
+            Selection::Random => thread_rng().gen_range(0..count),
+        };
+        self.candidates(now).nth(i).map(|t| t.tunnel.clone())
+    }
+}
+
+/// Maintains a set of inbound and outbound tunnels.
+///
+/// The pool is driven by calling [`TunnelPool::poll`] regularly, which must be done
+/// from within a task.
+pub struct TunnelPool<B: TunnelBuilder> {
+    builder: B,
+    config: PoolConfig,
+    inbound: Tunnels,
+    outbound: Tunnels,
+    /// Builds in progress, and whether each is for a zero-hop tunnel.
+    building: Vec<(TunnelRole, bool, B::Future)>,
+    consecutive_failures: u32,
+    retry_at: Option<SystemTime>,
+    excluded: Vec<Hash>,
+    tester: Option<Tester>,
+    stats: PoolStats,
+}
+
+impl<B: TunnelBuilder> TunnelPool<B> {
+    pub fn new(builder: B, config: PoolConfig) -> Self {
+        TunnelPool {
+            builder,
+            config,
+            inbound: Tunnels::default(),
+            outbound: Tunnels::default(),
+            building: vec![],
+            consecutive_failures: 0,
+            retry_at: None,
+            excluded: vec![],
+            tester: None,
+            stats: PoolStats::default(),
+        }
+    }
+
+    /// Tests the pool's tunnels with messages sent by `sender`, recording the results
+    /// in `profiles`.
+    pub fn test_tunnels(
+        mut self,
+        sender: Box<dyn ProbeSender + Send>,
+        config: TestConfig,
+        profiles: Profiles,
+    ) -> Self {
+        self.tester = Some(Tester::new(sender, config, profiles));
+        self
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.stats.clone()
+    }
+
+    fn tunnels(&mut self, role: TunnelRole) -> &mut Tunnels {
+        match role {
+            TunnelRole::Inbound => &mut self.inbound,
+            TunnelRole::Outbound => &mut self.outbound,
+        }
+    }
+
+    /// Returns an inbound tunnel for a consumer to use, if we have one.
+    pub fn select_inbound(&mut self, now: SystemTime) -> Option<BuiltTunnel> {
+        let selection = self.config.selection;
+        self.inbound.select(selection, now)
+    }
+
+    /// Returns an outbound tunnel for a consumer to use, if we have one.
+    pub fn select_outbound(&mut self, now: SystemTime) -> Option<BuiltTunnel> {
+        let selection = self.config.selection;
+        self.outbound.select(selection, now)
+    }
+
+    /// Returns the path for a build of a tunnel with the given role through one of
+    /// this pool's tunnels, if it has one in the direction that the build needs.
+    pub fn build_path(&mut self, role: TunnelRole, now: SystemTime) -> Option<BuildPath> {
+        let tunnel = match role {
+            TunnelRole::Inbound => self.select_outbound(now),
+            TunnelRole::Outbound => self.select_inbound(now),
+        };
+        tunnel.map(|tunnel| BuildPath::Exploratory(tunnel.tid))
+    }
+
+    /// Records that the DeliveryStatus message with the given ID has arrived. Returns
+    /// false if it wasn't sent to test this pool.
+    pub fn probe_received(&mut self, msg_id: u32, now: SystemTime) -> bool {
+        let tester = match self.tester.as_mut() {
+            Some(tester) => tester,
+            None => return false,
+        };
+        let (probe, rtt) = match tester.received(msg_id, now) {
+            Some(res) => res,
+            None => return false,
+        };
+        let threshold = tester.config().failure_threshold;
+
+        self.outbound.tested(probe.outbound.tid, true, threshold);
+        self.inbound.tested(probe.inbound.tid, true, threshold);
+        self.stats.tests_succeeded += 1;
+        self.stats.last_test_rtt = Some(rtt);
+        self.update_stats(now);
+        true
+    }
+
+    /// Drops expired tunnels, tests the pool's tunnels, starts the builds needed to
+    /// maintain the pool, and collects the results of finished builds.
+    ///
+    /// Builds go through the pool's own tunnels, so this is for the exploratory pool.
+    pub fn poll(&mut self, now: SystemTime) {
+        self.update(None, now)
+    }
+
+    /// Like [`TunnelPool::poll`], with builds going through the tunnels of the
+    /// `exploratory` pool.
+    pub fn poll_through(&mut self, exploratory: &mut TunnelPool<B>, now: SystemTime) {
+        self.update(Some(exploratory), now)
+    }
+
+    fn update(&mut self, mut exploratory: Option<&mut TunnelPool<B>>, now: SystemTime) {
+        self.inbound.tunnels.retain(|t| t.expires > now);
+        self.outbound.tunnels.retain(|t| t.expires > now);
+
+        self.test(now);
+
+        if self.retry_at.map_or(true, |retry_at| retry_at <= now) {
+            self.retry_at = None;
+            for &role in &[TunnelRole::Inbound, TunnelRole::Outbound] {
+                self.start_builds(role, exploratory.as_deref_mut(), now);
+            }
+        }
+        if self.config.zero_hop_fallback {
+            self.fall_back(now);
+        }
+
+        let mut i = 0;
+        while i < self.building.len() {
+            match self.building[i].2.poll() {
+                Ok(Async::NotReady) => i += 1,
+                res => {
+                    let (role, zero_hop, _) = self.building.swap_remove(i);
+                    match res {
+                        Ok(Async::Ready(tunnel)) if zero_hop => {
+                            self.built_zero_hop(role, tunnel, now)
+                        }
+                        Ok(Async::Ready(tunnel)) => self.built(role, tunnel, now),
+                        Err(e) if zero_hop => {
+                            error!("Failed to create zero-hop {:?} tunnel: {}", role, e)
+                        }
+                        Err(e) => self.failed(role, e, now),
+                        Ok(Async::NotReady) => unreachable!(),
+                    }
+                }
+            }
+        }
+
+        self.update_stats(now);
+    }
+
+    fn update_stats(&mut self, now: SystemTime) {
+        self.stats.inbound = self.inbound.live(now).count();
+        self.stats.outbound = self.outbound.live(now).count();
+        self.stats.building = self.building.len();
+        self.stats.failing = self.inbound.live(now).filter(|t| t.failing).count()
+            + self.outbound.live(now).filter(|t| t.failing).count();
+        self.stats.zero_hop = self.inbound.live(now).filter(|t| t.is_zero_hop()).count()
+            + self.outbound.live(now).filter(|t| t.is_zero_hop()).count();
+    }
+
+    /// Records the tests that have timed out, and starts new tests if they are due.
+    fn test(&mut self, now: SystemTime) {
+        let tester = match self.tester.as_mut() {
+            Some(tester) => tester,
+            None => return,
+        };
+        let threshold = tester.config().failure_threshold;
+
+        for probe in tester.expire(now) {
+            self.outbound.tested(probe.outbound.tid, false, threshold);
+            self.inbound.tested(probe.inbound.tid, false, threshold);
+            self.stats.tests_failed += 1;
+        }
+
+        // Pair each outbound tunnel with an inbound tunnel, preferring those that are
+        // still passing their tests, so that one failing tunnel doesn't take the others
+        // down with it.
+        let mut inbound: Vec<_> = self.inbound.usable(now).map(|t| &t.tunnel).collect();
+        if inbound.is_empty() {
+            inbound = self.inbound.live(now).map(|t| &t.tunnel).collect();
+        }
+        if inbound.is_empty() || self.outbound.live(now).next().is_none() || !tester.due(now) {
+            return;
+        }
+        for (i, outbound) in self.outbound.live(now).enumerate() {
+            tester.send(&outbound.tunnel, inbound[i % inbound.len()], now);
+        }
+    }
+
+    fn start_builds(
+        &mut self,
+        role: TunnelRole,
+        mut exploratory: Option<&mut TunnelPool<B>>,
+        now: SystemTime,
+    ) {
+        let building = self
+            .building
+            .iter()
+            .filter(|(r, zero_hop, _)| *r == role && !zero_hop)
+            .count();
+        let have = self.tunnels(role).current(now) + building;
+        let target = self.config.target();
+        if have >= target {
+            return;
+        }
+
+        let selector = self.config.selector.clone().exclude(self.excluded.clone());
+        for _ in have..target {
+            let path = match exploratory.as_deref_mut() {
+                Some(pool) => match pool.build_path(role, now) {
+                    Some(path) => path,
+                    None => {
+                        debug!("Waiting for exploratory tunnels to build through");
+                        return;
+                    }
+                },
+                None => self.build_path(role, now).unwrap_or(BuildPath::Direct),
+            };
+            debug!("Building {:?} tunnel via {:?}", role, path);
+            let f = self.builder.build(role, &selector, path);
+            self.building.push((role, false, f));
+        }
+    }
+
+    /// Creates zero-hop tunnels for the directions that have nothing usable, if we
+    /// can't build real tunnels.
+    fn fall_back(&mut self, now: SystemTime) {
+        let cant_build = self.consecutive_failures >= ZERO_HOP_AFTER
+            || matches!(
+                self.stats.last_build,
+                Some(Err(BuildError::NotEnoughPeers(_)))
+            );
+        if !cant_build {
+            return;
+        }
+
+        for &role in &[TunnelRole::Inbound, TunnelRole::Outbound] {
+            let creating = self
+                .building
+                .iter()
+                .any(|(r, zero_hop, _)| *r == role && *zero_hop);
+            if creating || self.tunnels(role).usable(now).next().is_some() {
+                continue;
+            }
+            debug!("Falling back to a zero-hop {:?} tunnel", role);
+            let f = self.builder.zero_hop(role);
+            self.building.push((role, true, f));
+        }
+    }
+
+    fn built(&mut self, role: TunnelRole, tunnel: BuiltTunnel, now: SystemTime) {
+        debug!("Built {:?} tunnel {}", role, tunnel.tid);
+        self.consecutive_failures = 0;
+        self.stats.builds_succeeded += 1;
+        self.stats.last_build = Some(Ok(tunnel.tid));
+        self.tunnels(role).tunnels.push(PoolTunnel {
+            tunnel,
+            expires: now + Duration::from_secs(TUNNEL_LIFETIME),
+            test_failures: 0,
+            failing: false,
+        });
+    }
+
+    /// Adds a zero-hop tunnel to the pool. It doesn't count as a successful build, so
+    /// real builds are still retried as if it didn't exist.
+    fn built_zero_hop(&mut self, role: TunnelRole, tunnel: BuiltTunnel, now: SystemTime) {
+        debug!("Created zero-hop {:?} tunnel {}", role, tunnel.tid);
+        self.tunnels(role).tunnels.push(PoolTunnel {
+            tunnel,
+            expires: now + Duration::from_secs(TUNNEL_LIFETIME),
+            test_failures: 0,
+            failing: false,
+        });
+    }
+
+    fn failed(&mut self, role: TunnelRole, e: BuildError, now: SystemTime) {
+        debug!("Failed to build {:?} tunnel: {}", role, e);
+        if let BuildError::Rejected(replies) = &e {
+            // Try other peers next time
+            for (hop, reply) in replies {
+                if *reply != TUNNEL_ACCEPT && !self.excluded.contains(hop) {
+                    self.excluded.push(hop.clone());
+                }
+            }
+            let len = self.excluded.len();
+            if len > MAX_EXCLUDED_PEERS {
+                self.excluded.drain(..len - MAX_EXCLUDED_PEERS);
+            }
+        }
+
+        let delay = (RETRY_DELAY << self.consecutive_failures.min(8)).min(MAX_RETRY_DELAY);
+        self.consecutive_failures += 1;
+        self.retry_at = Some(now + Duration::from_secs(delay));
+        self.stats.builds_failed += 1;
+        self.stats.last_build = Some(Err(e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, Future};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use super::{
+        PoolConfig, PoolStats, Selection, TunnelBuilder, TunnelPool, MAX_RETRY_DELAY,
+        REBUILD_AHEAD, RETRY_DELAY, SELECTION_CUTOFF,
+    };
+    use crate::data::{Hash, TunnelId};
+    use crate::router::profiles::Profiles;
+    use crate::tunnel::{
+        tester::PROBE_TIMEOUT, BuildError, BuildPath, BuiltTunnel, HopSelector, ProbeSender,
+        TestConfig, TunnelRole, TUNNEL_LIFETIME,
+    };
+
+    /// Builds that finish immediately with scripted results. Once the script runs out,
+    /// builds never finish.
+    #[derive(Clone, Default)]
+    struct MockBuilder {
+        script: Arc<Mutex<VecDeque<Result<(), BuildError>>>>,
+        builds: Arc<Mutex<Vec<(TunnelRole, HopSelector, BuildPath)>>>,
+        zero_hops: Arc<Mutex<Vec<TunnelRole>>>,
+    }
+
+    impl MockBuilder {
+        fn script(&self, results: Vec<Result<(), BuildError>>) {
+            self.script.lock().unwrap().extend(results);
+        }
+
+        fn builds(&self) -> usize {
+            self.builds.lock().unwrap().len()
+        }
+
+        fn last_selector(&self) -> HopSelector {
+            self.builds.lock().unwrap().last().unwrap().1.clone()
+        }
+
+        fn zero_hops(&self) -> usize {
+            self.zero_hops.lock().unwrap().len()
+        }
+    }
+
+    impl TunnelBuilder for MockBuilder {
+        type Future = Box<dyn Future<Item = BuiltTunnel, Error = BuildError> + Send>;
+
+        fn build(&self, role: TunnelRole, selector: &HopSelector, path: BuildPath) -> Self::Future {
+            let mut builds = self.builds.lock().unwrap();
+            builds.push((role, selector.clone(), path));
+            let tunnel = BuiltTunnel {
+                role,
+                tid: TunnelId(builds.len() as u32),
+                hops: vec![Hash([builds.len() as u8; 32])],
+            };
+            match self.script.lock().unwrap().pop_front() {
+                Some(Ok(())) => Box::new(future::ok(tunnel)),
+                Some(Err(e)) => Box::new(future::err(e)),
+                None => Box::new(future::empty()),
+            }
+        }
+
+        /// Zero-hop tunnels are created immediately, with IDs from 100.
+        fn zero_hop(&self, role: TunnelRole) -> Self::Future {
+            let mut zero_hops = self.zero_hops.lock().unwrap();
+            zero_hops.push(role);
+            Box::new(future::ok(BuiltTunnel {
+                role,
+                tid: TunnelId(99 + zero_hops.len() as u32),
+                hops: vec![],
+            }))
+        }
+    }
+
+    /// Records the message IDs of the tests it is asked to send.
+    #[derive(Clone, Default)]
+    struct MockSender(Arc<Mutex<Vec<(TunnelId, TunnelId, u32)>>>);
+
+    impl MockSender {
+        fn sent(&self) -> Vec<(TunnelId, TunnelId, u32)> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl ProbeSender for MockSender {
+        fn send_probe(&self, outbound: &BuiltTunnel, inbound: &BuiltTunnel, msg_id: u32) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((outbound.tid, inbound.tid, msg_id));
+        }
+    }
+
+    fn secs(t0: SystemTime, secs: u64) -> SystemTime {
+        t0 + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn maintains_quantity() {
+        let builder = MockBuilder::default();
+        let config = PoolConfig::new(HopSelector::new(2), 2).backup_quantity(1);
+        let mut pool = TunnelPool::new(builder.clone(), config);
+        let t0 = SystemTime::now();
+
+        // Three builds in each direction, of which four succeed
+        builder.script(vec![Ok(()), Ok(()), Ok(()), Ok(())]);
+        pool.poll(t0);
+        assert_eq!(builder.builds(), 6);
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                inbound: 3,
+                outbound: 1,
+                building: 2,
+                builds_succeeded: 4,
+                builds_failed: 0,
+                last_build: Some(Ok(TunnelId(4))),
+                ..PoolStats::default()
+            }
+        );
+
+        // Nothing more is needed while builds are in progress
+        pool.poll(secs(t0, 10));
+        assert_eq!(builder.builds(), 6);
+    }
+
+    #[test]
+    fn rebuilds_ahead_of_expiry() {
+        let builder = MockBuilder::default();
+        let mut pool = TunnelPool::new(builder.clone(), PoolConfig::new(HopSelector::new(2), 1));
+        let t0 = SystemTime::now();
+
+        builder.script(vec![Ok(()), Ok(())]);
+        pool.poll(t0);
+        assert_eq!(builder.builds(), 2);
+        let first = pool.select_outbound(t0).unwrap();
+
+        // No replacements until the tunnels are about to expire
+        let rebuild_at = TUNNEL_LIFETIME - REBUILD_AHEAD;
+        pool.poll(secs(t0, rebuild_at - 1));
+        assert_eq!(builder.builds(), 2);
+
+        // The replacements take a while, and the old tunnels are still used meanwhile
+        pool.poll(secs(t0, rebuild_at));
+        assert_eq!(builder.builds(), 4);
+        assert_eq!(pool.stats().building, 2);
+        assert_eq!(pool.select_outbound(secs(t0, rebuild_at)), Some(first));
+
+        // Once the old tunnels expire, they can't be selected
+        pool.poll(secs(t0, TUNNEL_LIFETIME));
+        assert_eq!(builder.builds(), 4);
+        assert_eq!(pool.stats().outbound, 0);
+        assert_eq!(pool.select_outbound(secs(t0, TUNNEL_LIFETIME)), None);
+    }
+
+    #[test]
+    fn selection_cutoff() {
+        let builder = MockBuilder::default();
+        let mut pool = TunnelPool::new(builder.clone(), PoolConfig::new(HopSelector::new(2), 1));
+        let t0 = SystemTime::now();
+
+        builder.script(vec![Ok(()), Ok(())]);
+        pool.poll(t0);
+        let cutoff = TUNNEL_LIFETIME - SELECTION_CUTOFF;
+        assert!(pool.select_inbound(secs(t0, cutoff - 1)).is_some());
+        assert!(pool.select_outbound(secs(t0, cutoff - 1)).is_some());
+
+        // Tunnels that are about to expire aren't given new traffic, but are still
+        // live until they expire
+        assert_eq!(pool.select_inbound(secs(t0, cutoff)), None);
+        assert_eq!(pool.select_outbound(secs(t0, cutoff)), None);
+        pool.poll(secs(t0, cutoff));
+        let stats = pool.stats();
+        assert_eq!((stats.inbound, stats.outbound), (1, 1));
+
+        pool.poll(secs(t0, TUNNEL_LIFETIME));
+        let stats = pool.stats();
+        assert_eq!((stats.inbound, stats.outbound), (0, 0));
+    }
+
+    #[test]
+    fn retries_failed_builds() {
+        let builder = MockBuilder::default();
+        let mut pool = TunnelPool::new(builder.clone(), PoolConfig::new(HopSelector::new(2), 1));
+        let t0 = SystemTime::now();
+        let rejecting = Hash([7; 32]);
+
+        // The inbound build is rejected by one hop
+        builder.script(vec![
+            Err(BuildError::Rejected(vec![
+                (Hash([6; 32]), 0),
+                (rejecting.clone(), 30),
+            ])),
+            Ok(()),
+        ]);
+        pool.poll(t0);
+        let stats = pool.stats();
+        assert_eq!((stats.inbound, stats.outbound), (0, 1));
+        assert_eq!(stats.builds_failed, 1);
+        assert!(matches!(
+            stats.last_build,
+            Some(Err(BuildError::Rejected(_)))
+        ));
+
+        // The build is retried after a delay, avoiding the hop that rejected it
+        pool.poll(secs(t0, RETRY_DELAY - 1));
+        assert_eq!(builder.builds(), 2);
+        builder.script(vec![Err(BuildError::TimedOut)]);
+        pool.poll(secs(t0, RETRY_DELAY));
+        assert_eq!(builder.builds(), 3);
+        assert_eq!(
+            builder.last_selector(),
+            HopSelector::new(2).exclude(vec![rejecting])
+        );
+        assert_eq!(pool.stats().last_build, Some(Err(BuildError::TimedOut)));
+
+        // Consecutive failures back off
+        let t1 = secs(t0, RETRY_DELAY);
+        pool.poll(secs(t1, 2 * RETRY_DELAY - 1));
+        assert_eq!(builder.builds(), 3);
+        builder.script(vec![Ok(())]);
+        pool.poll(secs(t1, 2 * RETRY_DELAY));
+        assert_eq!(builder.builds(), 4);
+        let stats = pool.stats();
+        assert_eq!((stats.inbound, stats.outbound), (1, 1));
+        assert_eq!((stats.builds_succeeded, stats.builds_failed), (2, 2));
+        assert_eq!(stats.last_build, Some(Ok(TunnelId(4))));
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let builder = MockBuilder::default();
+        let mut pool = TunnelPool::new(builder.clone(), PoolConfig::new(HopSelector::new(2), 1));
+        let mut now = SystemTime::now();
+
+        let mut delays = vec![];
+        for _ in 0..6 {
+            builder.script(vec![Err(BuildError::NotEnoughPeers(0)); 2]);
+            pool.poll(now);
+            let retry_at = pool.retry_at.unwrap();
+            delays.push(retry_at.duration_since(now).unwrap().as_secs());
+            now = retry_at;
+        }
+        // Both directions fail each round, so the delay doubles twice per round
+        assert_eq!(
+            delays,
+            vec![
+                2 * RETRY_DELAY,
+                8 * RETRY_DELAY,
+                MAX_RETRY_DELAY,
+                MAX_RETRY_DELAY,
+                MAX_RETRY_DELAY,
+                MAX_RETRY_DELAY
+            ]
+        );
+        assert_eq!(pool.stats().builds_failed, 12);
+    }
+
+    #[test]
+    fn selection() {
+        let builder = MockBuilder::default();
+        let config = PoolConfig::new(HopSelector::new(1), 3);
+        let mut pool = TunnelPool::new(builder.clone(), config.clone());
+        let t0 = SystemTime::now();
+
+        assert_eq!(pool.select_inbound(t0), None);
+
+        builder.script(vec![Ok(()); 6]);
+        pool.poll(t0);
+
+        // Round-robin cycles through the tunnels
+        let selected: Vec<_> = (0..6)
+            .map(|_| pool.select_inbound(t0).unwrap().tid)
+            .collect();
+        assert_eq!(selected[..3], selected[3..]);
+        let mut inbound = selected[..3].to_vec();
+        inbound.sort_by_key(|tid| tid.0);
+        assert_eq!(inbound, vec![TunnelId(1), TunnelId(2), TunnelId(3)]);
+
+        // Random selection only returns live tunnels in the right direction
+        let builder = MockBuilder::default();
+        let mut pool = TunnelPool::new(builder.clone(), config.selection(Selection::Random));
+        builder.script(vec![Ok(()); 6]);
+        pool.poll(t0);
+        for _ in 0..20 {
+            assert_eq!(pool.select_outbound(t0).unwrap().role, TunnelRole::Outbound);
+        }
+        assert_eq!(pool.select_outbound(secs(t0, TUNNEL_LIFETIME)), None);
+    }
+
+    #[test]
+    fn tests_tunnels() {
+        let builder = MockBuilder::default();
+        let sender = MockSender::default();
+        let profiles = Profiles::default();
+        let test_config = TestConfig {
+            interval: Duration::from_secs(30),
+            failure_threshold: 2,
+        };
+        let mut pool = TunnelPool::new(builder.clone(), PoolConfig::new(HopSelector::new(1), 1))
+            .test_tunnels(Box::new(sender.clone()), test_config, profiles.clone());
+        let t0 = SystemTime::now();
+
+        // The tunnels are tested as soon as they are built
+        builder.script(vec![Ok(()), Ok(())]);
+        pool.poll(t0);
+        assert_eq!(builder.builds(), 2);
+        assert!(sender.sent().is_empty());
+        pool.poll(secs(t0, 1));
+        assert_eq!(sender.sent().len(), 1);
+        let (outbound, inbound, _) = sender.sent()[0];
+        assert_eq!((outbound, inbound), (TunnelId(2), TunnelId(1)));
+
+        // Each test times out, until the tunnels have failed too many
+        let mut now = secs(t0, 1);
+        for i in 1..=2 {
+            pool.poll(now + Duration::from_secs(PROBE_TIMEOUT));
+            assert_eq!(pool.stats().tests_failed, i);
+            now += test_config.interval;
+            pool.poll(now);
+            assert_eq!(sender.sent().len() as u64, i + 1);
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.failing, 2);
+        assert_eq!((stats.inbound, stats.outbound), (1, 1));
+
+        // Failing tunnels aren't selected, and are replaced
+        assert_eq!(pool.select_outbound(now), None);
+        assert_eq!(pool.select_inbound(now), None);
+        assert_eq!(builder.builds(), 4);
+
+        // The hops in both tunnels were blamed
+        let hops = [Hash([1; 32]), Hash([2; 32])];
+        assert!(hops.iter().all(|hop| !profiles.is_failing(hop, now)));
+        pool.poll(now + Duration::from_secs(PROBE_TIMEOUT));
+        assert!(hops.iter().all(|hop| profiles.is_failing(hop, now)));
+
+        // A successful test brings them back
+        now += test_config.interval;
+        pool.poll(now);
+        let (_, _, msg_id) = *sender.sent().last().unwrap();
+        assert!(!pool.probe_received(msg_id.wrapping_add(1), now));
+        assert!(pool.probe_received(msg_id, secs(now, 2)));
+        assert!(!pool.probe_received(msg_id, secs(now, 2)));
+        let stats = pool.stats();
+        assert_eq!(stats.failing, 0);
+        assert_eq!((stats.tests_succeeded, stats.tests_failed), (1, 3));
+        assert_eq!(stats.last_test_rtt, Some(Duration::from_secs(2)));
+        assert_eq!(pool.select_outbound(now).unwrap().tid, TunnelId(2));
+        assert!(hops.iter().all(|hop| !profiles.is_failing(hop, now)));
+    }
+
+    #[test]
+    fn zero_hop_fallback() {
+        let builder = MockBuilder::default();
+        let config = PoolConfig::new(HopSelector::new(2), 1);
+        let t0 = SystemTime::now();
+
+        // Pools only fall back if they are configured to
+        let mut pool = TunnelPool::new(builder.clone(), config.clone());
+        builder.script(vec![Err(BuildError::NotEnoughPeers(0)); 2]);
+        pool.poll(t0);
+        pool.poll(secs(t0, 1));
+        assert_eq!(builder.zero_hops(), 0);
+        assert_eq!(pool.select_inbound(secs(t0, 1)), None);
+
+        // Without enough peers, both directions fall back to zero-hop tunnels
+        let builder = MockBuilder::default();
+        let mut pool = TunnelPool::new(builder.clone(), config.zero_hop_fallback(true));
+        builder.script(vec![Err(BuildError::NotEnoughPeers(0)); 2]);
+        pool.poll(t0);
+        assert_eq!(builder.zero_hops(), 0);
+        pool.poll(secs(t0, 1));
+        pool.poll(secs(t0, 2));
+        assert_eq!(builder.zero_hops(), 2);
+        let stats = pool.stats();
+        assert_eq!((stats.inbound, stats.outbound, stats.zero_hop), (1, 1, 2));
+        assert_eq!((stats.builds_succeeded, stats.builds_failed), (0, 2));
+        let inbound = pool.select_inbound(secs(t0, 1)).unwrap();
+        assert_eq!((inbound.tid, inbound.hops.len()), (TunnelId(100), 0));
+        let outbound = pool.select_outbound(secs(t0, 1)).unwrap();
+        assert_eq!((outbound.tid, outbound.hops.len()), (TunnelId(101), 0));
+
+        // Real builds are still retried, and preferred once they succeed
+        builder.script(vec![Ok(()), Ok(())]);
+        pool.poll(secs(t0, 2 * RETRY_DELAY));
+        assert_eq!(builder.builds(), 4);
+        let stats = pool.stats();
+        assert_eq!((stats.inbound, stats.outbound, stats.zero_hop), (2, 2, 2));
+        for _ in 0..4 {
+            assert_eq!(pool.select_inbound(secs(t0, 20)).unwrap().hops.len(), 1);
+            assert_eq!(pool.select_outbound(secs(t0, 20)).unwrap().hops.len(), 1);
+        }
+
+        // Zero-hop tunnels expire like any other
+        pool.poll(secs(t0, TUNNEL_LIFETIME + 1));
+        assert_eq!(pool.stats().zero_hop, 0);
+        assert_eq!(builder.zero_hops(), 2);
+    }
+
+    #[test]
+    fn zero_hop_after_failures() {
+        let builder = MockBuilder::default();
+        let config = PoolConfig::new(HopSelector::new(2), 1).zero_hop_fallback(true);
+        let mut pool = TunnelPool::new(builder.clone(), config);
+        let t0 = SystemTime::now();
+
+        // Builds that time out are retried a few times before falling back
+        builder.script(vec![Err(BuildError::TimedOut); 2]);
+        pool.poll(t0);
+        pool.poll(secs(t0, 1));
+        assert_eq!(builder.zero_hops(), 0);
+
+        builder.script(vec![Err(BuildError::TimedOut); 2]);
+        pool.poll(secs(t0, 2 * RETRY_DELAY));
+        assert_eq!(pool.stats().builds_failed, 4);
+        pool.poll(secs(t0, 2 * RETRY_DELAY + 1));
+        assert_eq!(builder.zero_hops(), 2);
+        assert!(pool
+            .select_outbound(secs(t0, 2 * RETRY_DELAY + 1))
+            .is_some());
+
+        // Only one zero-hop tunnel is needed in each direction
+        pool.poll(secs(t0, 2 * RETRY_DELAY + 2));
+        assert_eq!(builder.zero_hops(), 2);
+    }
+
+    #[test]
+    fn builds_through_exploratory() {
+        let builder = MockBuilder::default();
+        let config = PoolConfig::new(HopSelector::new(2), 1);
+        let mut exploratory = TunnelPool::new(builder.clone(), config.clone());
+        let mut client = TunnelPool::new(builder.clone(), config);
+        let t0 = SystemTime::now();
+        let paths = || -> Vec<_> {
+            let builds = builder.builds.lock().unwrap();
+            builds
+                .iter()
+                .map(|(role, _, path)| (*role, *path))
+                .collect()
+        };
+
+        // Client pools wait for exploratory tunnels instead of building directly
+        client.poll_through(&mut exploratory, t0);
+        assert_eq!(builder.builds(), 0);
+        assert_eq!(client.stats().building, 0);
+
+        // The exploratory pool builds directly while it has no tunnels
+        builder.script(vec![Ok(()); 4]);
+        exploratory.poll(t0);
+        assert_eq!(
+            paths(),
+            vec![
+                (TunnelRole::Inbound, BuildPath::Direct),
+                (TunnelRole::Outbound, BuildPath::Direct),
+            ]
+        );
+
+        // Inbound builds go through an outbound tunnel, and outbound builds through an
+        // inbound tunnel
+        client.poll_through(&mut exploratory, t0);
+        assert_eq!(
+            paths()[2..],
+            [
+                (TunnelRole::Inbound, BuildPath::Exploratory(TunnelId(2))),
+                (TunnelRole::Outbound, BuildPath::Exploratory(TunnelId(1))),
+            ]
+        );
+
+        // Once it has tunnels, the exploratory pool builds through them too
+        let rebuild_at = secs(t0, TUNNEL_LIFETIME - REBUILD_AHEAD);
+        exploratory.poll(rebuild_at);
+        assert_eq!(
+            paths()[4..],
+            [
+                (TunnelRole::Inbound, BuildPath::Exploratory(TunnelId(2))),
+                (TunnelRole::Outbound, BuildPath::Exploratory(TunnelId(1))),
+            ]
+        );
+        client.poll_through(&mut exploratory, rebuild_at);
+        assert!(paths()
+            .iter()
+            .skip(2)
+            .all(|(_, path)| *path != BuildPath::Direct));
+    }
+}
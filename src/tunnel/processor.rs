@@ -1,16 +1,31 @@
 //! Fast-path processing of tunnel data in participating tunnels.
 
-use futures::{sync::mpsc, try_ready, Async, Future, Poll, Stream};
-use std::collections::HashMap;
+use futures::{
+    future::{self, Either},
+    sync::mpsc,
+    try_ready, Async, Future, Poll, Stream,
+};
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant, SystemTime};
 use tokio::{io, spawn, timer::Delay};
 use tokio_threadpool::blocking;
 
-use super::{encryption::LayerCipher, HopConfig, HopData, TUNNEL_LIFETIME};
+use super::{
+    accounting::TunnelCounters,
+    crypto::{remove_layers, LayerCipher},
+    endpoint::Reassembler,
+    gateway::Fragmenter,
+    registry::Registry,
+    HopConfig, HopData, OwnTunnel, Transit, TunnelMessageDeliveryType, TunnelRole, TUNNEL_LIFETIME,
+};
+use crate::crypto::rand::OsRng;
 use crate::data::{Hash, RouterInfo, TunnelId};
-use crate::i2np::{Message, MessagePayload, TunnelData};
-use crate::router::types::CommSystem;
+use crate::i2np::{frame, Message, MessageIdGenerator, MessagePayload, TunnelData};
+use crate::netdb::client::Client as NetDbClient;
+use crate::router::{
+    types::{CommSystem, Distributor},
+    Dispatcher,
+};
 use crate::util::DecayingBloomFilter;
 
 type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
@@ -18,6 +33,10 @@ type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
 /// Interval on which we expire tunnels we are participating in.
 const EXPIRE_TUNNELS_INTERVAL: u64 = 10;
 
+/// Maximum time to wait for the RouterInfo of a router that an endpoint is delivering
+/// a message to.
+const MAX_LOOKUP_TIME: u64 = 30;
+
 macro_rules! try_poll {
     ($f:expr, $parent:expr, $state:expr) => {
         match $f {
@@ -46,6 +65,7 @@ enum HopProcessorState {
 struct HopProcessor {
     state: Option<HopProcessorState>,
     comms: Arc<RwLock<dyn CommSystem>>,
+    msg_ids: MessageIdGenerator,
 }
 
 impl HopProcessor {
@@ -54,10 +74,12 @@ impl HopProcessor {
         td: TunnelData,
         layer_cipher: LayerCipher,
         comms: Arc<RwLock<dyn CommSystem>>,
+        msg_ids: MessageIdGenerator,
     ) -> Self {
         HopProcessor {
             state: Some(HopProcessorState::Processing(next_hop, td, layer_cipher)),
             comms,
+            msg_ids,
         }
     }
 }
@@ -73,7 +95,7 @@ impl Future for HopProcessor {
                     // Process the layer
                     try_poll!(
                         blocking(|| {
-                            layer_cipher.encrypt_layer(&mut td);
+                            td.update_data(|data| layer_cipher.encrypt_layer(data));
                         }),
                         self,
                         HopProcessorState::Processing(next_hop, td, layer_cipher)
@@ -84,7 +106,7 @@ impl Future for HopProcessor {
 
                     match self.comms.read().unwrap().send(
                         next_hop.0,
-                        Message::from_payload(MessagePayload::TunnelData(td)),
+                        Message::from_payload(&self.msg_ids, MessagePayload::TunnelData(td)),
                     ) {
                         Ok(f) => HopProcessorState::Sending(f),
                         Err((ri, msg)) => {
@@ -107,40 +129,183 @@ impl Future for HopProcessor {
     }
 }
 
+/// Delivers the messages reassembled at the tunnels we are the endpoint of, according
+/// to their delivery instructions.
+struct Endpoints {
+    netdb: NetDbClient,
+    dispatcher: Dispatcher,
+    comms: Arc<RwLock<dyn CommSystem>>,
+    msg_ids: MessageIdGenerator,
+}
+
+impl Endpoints {
+    /// Reassembles the plaintext of a [`TunnelData`] message, returning the messages that
+    /// it completes.
+    fn receive(
+        &self,
+        endpoint: Option<(&mut Reassembler, &TunnelCounters)>,
+        td: &TunnelData,
+    ) -> Vec<(TunnelMessageDeliveryType, Message)> {
+        let (reassembler, counters) = match endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                debug!("Dropping TunnelData message: tunnel {} has expired", td.tid);
+                return vec![];
+            }
+        };
+        match reassembler.receive(td.data(), Instant::now()) {
+            Ok(completed) => {
+                for (_, msg) in &completed {
+                    counters.sent(msg.size());
+                }
+                completed
+            }
+            Err(e) => {
+                warn!("Dropping TunnelData message: {}", e);
+                vec![]
+            }
+        }
+    }
+
+    fn deliver(&self, from: &Hash, delivery: TunnelMessageDeliveryType, msg: Message) {
+        match delivery {
+            TunnelMessageDeliveryType::Local => {
+                spawn(
+                    self.dispatcher
+                        .handle(from.clone(), msg)
+                        .map_err(|e| error!("Failed to dispatch message from tunnel: {}", e)),
+                );
+            }
+            TunnelMessageDeliveryType::Router(to) => self.send_to(to, msg),
+            TunnelMessageDeliveryType::Tunnel(tid, to) => {
+                self.send_to(to, Message::tunnel_gateway(&self.msg_ids, tid, &msg))
+            }
+        }
+    }
+
+    fn send_to(&self, to: Hash, msg: Message) {
+        let ri = match self.netdb.local_router_info(&to) {
+            Some(ri) => Either::A(future::ok(ri)),
+            None => Either::B(
+                self.netdb
+                    .lookup_router_info(to.clone(), MAX_LOOKUP_TIME * 1000, None, false)
+                    .map_err(move |e| debug!("Could not deliver message to {}: {}", to, e)),
+            ),
+        };
+
+        let comms = self.comms.clone();
+        spawn(
+            ri.and_then(move |ri| match comms.read().unwrap().send(ri, msg) {
+                Ok(f) => Either::A(f.map_err(|e| debug!("Failed to deliver message: {}", e))),
+                Err((ri, msg)) => {
+                    error!(
+                        "Could not send message to {} over any of our transports: {}",
+                        ri.router_id.hash(),
+                        msg
+                    );
+                    Either::B(future::err(()))
+                }
+            }),
+        );
+    }
+}
+
 /// A [`Future`] that handles incoming [`TunnelData`] messages for a single participating
 /// tunnel.
 ///
 /// Each message is spawned into its own task, which uses the [`blocking()`] threadpool
 /// for encryption operations.
 ///
-/// Currently only supports intermediate hops, not IBGWs or OBEPs.
+/// Also tracks the tunnels that we built, so that their messages can be recognised.
+/// Tunnels are dropped from the [`Registry`] on a timer once they expire.
+///
+/// Forwarded messages are counted against our bandwidth share in [`Transit`], which
+/// drops them from the heaviest tunnels first as the share runs out.
+///
+/// Messages sent to us as an IBGW are fragmented into [`TunnelData`] messages for the
+/// rest of the tunnel. As an OBEP, and at the endpoint of our own inbound tunnels, we
+/// reassemble the I2NP messages and deliver them: locally through the [`Dispatcher`],
+/// or to another router or tunnel.
+///
+/// We are both ends of our own zero-hop tunnels, so messages for them are delivered
+/// locally, and messages sent into our zero-hop outbound tunnels are reassembled here.
 pub struct Participant {
     new_participating_rx: mpsc::Receiver<(TunnelId, HopConfig)>,
-    participating: HashMap<TunnelId, HopConfig>,
+    new_own_rx: mpsc::Receiver<(TunnelId, OwnTunnel)>,
+    registry: Registry,
+    endpoints: Endpoints,
     filter: DecayingBloomFilter,
     expire_tunnels_timer: Delay,
     decay_filter_timer: Delay,
     ib_rx: mpsc::Receiver<(Hash, Message)>,
+    transit: Transit,
     comms: Arc<RwLock<dyn CommSystem>>,
+    msg_ids: MessageIdGenerator,
 }
 
 impl Participant {
     pub fn new(
         new_participating_rx: mpsc::Receiver<(TunnelId, HopConfig)>,
+        new_own_rx: mpsc::Receiver<(TunnelId, OwnTunnel)>,
         ib_rx: mpsc::Receiver<(Hash, Message)>,
+        transit: Transit,
+        netdb: NetDbClient,
+        dispatcher: Dispatcher,
         comms: Arc<RwLock<dyn CommSystem>>,
+        msg_ids: MessageIdGenerator,
     ) -> Self {
         Participant {
             new_participating_rx,
-            participating: HashMap::new(),
+            new_own_rx,
+            registry: Registry::new(transit.accounting().clone(), transit.tunnel_ids().clone()),
+            endpoints: Endpoints {
+                netdb,
+                dispatcher,
+                comms: comms.clone(),
+                msg_ids: msg_ids.clone(),
+            },
             filter: DecayingBloomFilter::new(20_000), // TODO: Configure this based on bandwidth
             expire_tunnels_timer: Delay::new(
                 Instant::now() + Duration::from_secs(EXPIRE_TUNNELS_INTERVAL),
             ),
             decay_filter_timer: Delay::new(Instant::now() + Duration::from_secs(TUNNEL_LIFETIME)),
             ib_rx,
+            transit,
             comms,
+            msg_ids,
+        }
+    }
+
+    /// Returns the given tunnel if it is one of our zero-hop inbound tunnels.
+    fn zero_hop_inbound(&self, tid: &TunnelId) -> Option<(&OwnTunnel, &TunnelCounters)> {
+        self.registry
+            .own(tid)
+            .filter(|(tunnel, _)| tunnel.role == TunnelRole::Inbound && tunnel.is_zero_hop())
+    }
+
+    /// Reassembles the plaintext of a [`TunnelData`] message that reached one of our
+    /// endpoints, and delivers the messages that it completes.
+    fn reassemble(&mut self, from: &Hash, td: &TunnelData) {
+        for (delivery, msg) in self.endpoints.receive(self.registry.endpoint(td.tid), td) {
+            self.deliver(from, delivery, msg);
+        }
+    }
+
+    /// Delivers a reassembled message. Messages for one of our zero-hop inbound tunnels
+    /// are delivered locally, instead of being sent to ourselves.
+    fn deliver(&self, from: &Hash, delivery: TunnelMessageDeliveryType, msg: Message) {
+        if let TunnelMessageDeliveryType::Tunnel(tid, to) = &delivery {
+            if let Some((tunnel, counters)) = self.zero_hop_inbound(tid) {
+                if tunnel.first_hop.router_id.hash() == *to {
+                    counters.received(msg.size(), Instant::now());
+                    counters.sent(msg.size());
+                    self.endpoints
+                        .deliver(from, TunnelMessageDeliveryType::Local, msg);
+                    return;
+                }
+            }
         }
+        self.endpoints.deliver(from, delivery, msg);
     }
 }
 
@@ -156,15 +321,23 @@ impl Future for Participant {
                 .poll()
                 .map_err(|e| error!("Error while polling for new participating tunnels: {:?}", e))?
             {
-                self.participating.insert(tid, config);
+                self.registry.register_participating(tid, config);
+            }
+            while let Async::Ready(Some((tid, tunnel))) = self
+                .new_own_rx
+                .poll()
+                .map_err(|e| error!("Error while polling for new tunnels we built: {:?}", e))?
+            {
+                self.registry.register_own(tid, tunnel);
             }
 
             // Handle periodic work
             if let Ok(Async::Ready(())) = self.expire_tunnels_timer.poll() {
-                // Drop expired tunnels
-                let now = SystemTime::now();
-                self.participating
-                    .retain(|_tid, config| config.expires > now);
+                // Drop expired tunnels, and the messages that endpoints have been
+                // waiting on for too long
+                let expired = self.registry.expire(SystemTime::now(), Instant::now());
+                self.transit.expired(expired);
+                debug!("Expired tunnels: {:?}", self.registry.stats());
 
                 // Reset timer
                 self.expire_tunnels_timer =
@@ -184,7 +357,7 @@ impl Future for Participant {
                 match msg.payload {
                     MessagePayload::TunnelData(td) => {
                         // Find the tunnel ID
-                        if let Some(config) = self.participating.get(&td.tid) {
+                        if let Some((config, counters)) = self.registry.participating(&td.tid) {
                             // Checks that the message came from the same previous hop as before.
                             // Does not apply to IBGWs.
                             match &config.hop_data {
@@ -201,27 +374,149 @@ impl Future for Participant {
                             // Check for duplicates by feeding the XOR of the IV and first block
                             // into a decaying Bloom filter.
                             let filter_value: Vec<_> =
-                                (0..16).map(|i| td.data[i] ^ td.data[i + 16]).collect();
+                                (0..16).map(|i| td.data()[i] ^ td.data()[i + 16]).collect();
                             if self.filter.feed(&filter_value) {
                                 warn!("Dropping TunnelData message: duplicate");
                                 continue;
                             }
 
+                            counters.received(td.data().len(), Instant::now());
+                            if !self
+                                .transit
+                                .record(counters, td.data().len(), Instant::now())
+                            {
+                                debug!("Dropping TunnelData message: over our bandwidth share");
+                                continue;
+                            }
+
                             // Okay, we want to process this message
                             match &config.hop_data {
-                                HopData::InboundGateway(_) => unimplemented!(),
+                                HopData::InboundGateway(_) => {
+                                    warn!("Dropping TunnelData message: IBGWs only accept TunnelGateway messages");
+                                }
                                 HopData::Intermediate(_, next_hop) => {
+                                    counters.sent(td.data().len());
                                     spawn(HopProcessor::new(
                                         next_hop.clone(),
                                         td,
                                         config.layer_cipher.clone(),
                                         self.comms.clone(),
+                                        self.msg_ids.clone(),
                                     ));
                                 }
-                                HopData::OutboundEndpoint(_) => unimplemented!(),
+                                HopData::OutboundEndpoint(_) => {
+                                    // Our layer is the last, after which the message is in
+                                    // plaintext
+                                    let mut td = td;
+                                    td.update_data(|data| config.layer_cipher.encrypt_layer(data));
+                                    self.reassemble(&from, &td);
+                                }
                             }
+                        } else if let Some((tunnel, counters)) = self.registry.own(&td.tid) {
+                            let mut td = td;
+                            match tunnel.role {
+                                TunnelRole::Inbound => {
+                                    // Remove the layers that each hop added
+                                    td.update_data(|data| {
+                                        remove_layers(
+                                            tunnel.hops.iter().map(|hop| &hop.layer_cipher),
+                                            data,
+                                        )
+                                    });
+                                }
+                                TunnelRole::Outbound if tunnel.is_zero_hop() => {
+                                    // We are also the OBEP, and only we can send into it
+                                    if from != tunnel.first_hop.router_id.hash() {
+                                        warn!("Dropping TunnelData message: from the wrong peer");
+                                        continue;
+                                    }
+                                }
+                                TunnelRole::Outbound => {
+                                    warn!(
+                                        "Dropping TunnelData message for one of our outbound tunnels"
+                                    );
+                                    continue;
+                                }
+                            }
+                            counters.received(td.data().len(), Instant::now());
+                            self.reassemble(&from, &td);
+                        } else if self.registry.expired_recently(&td.tid) {
+                            debug!("Dropping TunnelData message: tunnel {} expired", td.tid);
                         } else {
-                            warn!("Dropping TunnelData message: unknown TunnelId");
+                            debug!("Dropping TunnelData message: unknown TunnelId");
+                        }
+                    }
+                    MessagePayload::TunnelGateway(tg) => {
+                        if let Some((_, counters)) = self.zero_hop_inbound(&tg.tid()) {
+                            // We are also the endpoint, so there is nothing to fragment
+                            counters.received(tg.data().len(), Instant::now());
+                            match frame::message_bytes(tg.data(), tg.data()) {
+                                Ok((_, msg)) => {
+                                    counters.sent(msg.size());
+                                    self.endpoints.deliver(
+                                        &from,
+                                        TunnelMessageDeliveryType::Local,
+                                        msg,
+                                    );
+                                }
+                                Err(e) => {
+                                    warn!("Dropping TunnelGateway message: {:?}", e);
+                                }
+                            }
+                            continue;
+                        }
+
+                        let (next_hop, layer_cipher, counters) =
+                            match self.registry.participating(&tg.tid()) {
+                                Some((
+                                    HopConfig {
+                                        hop_data: HopData::InboundGateway(next_hop),
+                                        layer_cipher,
+                                        ..
+                                    },
+                                    counters,
+                                )) => (next_hop, layer_cipher, counters),
+                                Some(_) => {
+                                    warn!("Dropping TunnelGateway message: we are not the IBGW");
+                                    continue;
+                                }
+                                None if self.registry.expired_recently(&tg.tid()) => {
+                                    debug!(
+                                        "Dropping TunnelGateway message: tunnel {} expired",
+                                        tg.tid()
+                                    );
+                                    continue;
+                                }
+                                None => {
+                                    debug!("Dropping TunnelGateway message: unknown TunnelId");
+                                    continue;
+                                }
+                            };
+
+                        // Fragment the message into TunnelData messages for the endpoint
+                        counters.received(tg.data().len(), Instant::now());
+                        let mut fragmenter = Fragmenter::default();
+                        if let Err(e) = fragmenter.push(
+                            TunnelMessageDeliveryType::Local,
+                            self.msg_ids.next_id(),
+                            tg.data().to_vec(),
+                        ) {
+                            warn!("Dropping TunnelGateway message: {}", e);
+                            continue;
+                        }
+                        while let Some(data) = fragmenter.next_payload(&mut OsRng) {
+                            if !self.transit.record(counters, data.len(), Instant::now()) {
+                                debug!("Dropping TunnelData message: over our bandwidth share");
+                                break;
+                            }
+                            counters.sent(data.len());
+                            spawn(HopProcessor::new(
+                                next_hop.clone(),
+                                TunnelData::new(tg.tid(), data),
+                                layer_cipher.clone(),
+                                self.comms.clone(),
+                                self.msg_ids.clone(),
+                            ));
                         }
                     }
                     _ => {
@@ -235,3 +530,225 @@ impl Future for Participant {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{sync::mpsc, Future, Sink, Stream};
+    use std::time::{Duration, SystemTime};
+    use tokio::runtime::Runtime;
+
+    use super::Participant;
+    use crate::crypto::{rand::TestRng, SessionKey};
+    use crate::data::TunnelId;
+    use crate::i2np::{frame::gen_message, Message, MessagePayload, MessageType};
+    use crate::router::{
+        mock::{loopback_context_and_netdb, LoopbackPeers},
+        Dispatcher,
+    };
+    use crate::tunnel::{
+        crypto::LayerCipher, gateway::Fragmenter, HopConfig, HopData, OutboundGateway, OwnTunnel,
+        Transit, TunnelMessageDeliveryType, TunnelRole, TUNNEL_LIFETIME,
+    };
+    use crate::util::serialize;
+
+    #[test]
+    fn relays_through_intermediate_hop() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let (a, _a_netdb, _a_rx) = loopback_context_and_netdb(&peers);
+        let (b, _b_netdb, b_rx) = loopback_context_and_netdb(&peers);
+        let (c, _c_netdb, c_rx) = loopback_context_and_netdb(&peers);
+        let b_ri = b.ri.read().unwrap().clone();
+        let c_ri = c.ri.read().unwrap().clone();
+
+        // B is the intermediate hop of a tunnel from A to C
+        let layer_cipher = LayerCipher::new(&SessionKey([1; 32]), SessionKey([2; 32]));
+        let (new_participating_tx, new_participating_rx) = mpsc::channel(1);
+        let (_new_own_tx, new_own_rx) = mpsc::channel(1);
+        rt.block_on(new_participating_tx.send((
+            TunnelId(1),
+            HopConfig {
+                hop_data: HopData::Intermediate(a.keys.rid.hash(), (c_ri, TunnelId(2))),
+                layer_cipher: layer_cipher.clone(),
+                expires: SystemTime::now() + Duration::from_secs(TUNNEL_LIFETIME),
+            },
+        )))
+        .unwrap();
+
+        let data = [7; 1024];
+        let sent = a
+            .comms
+            .read()
+            .unwrap()
+            .send(b_ri, Message::tunnel_data(&a.msg_ids, TunnelId(1), data))
+            .unwrap_or_else(|_| panic!("B is reachable"));
+        rt.block_on(sent).unwrap();
+
+        rt.spawn(Participant::new(
+            new_participating_rx,
+            new_own_rx,
+            b_rx,
+            Transit::default(),
+            b.netdb.clone(),
+            Dispatcher::new(),
+            b.comms.clone(),
+            b.msg_ids.clone(),
+        ));
+
+        // C receives the message with B's layer of encryption
+        let (received, _) = rt.block_on(c_rx.into_future().map_err(|_| ())).unwrap();
+        let (from, msg) = received.unwrap();
+        assert_eq!(from, b.keys.rid.hash());
+
+        let mut expected = data;
+        layer_cipher.encrypt_layer(&mut expected);
+        match &msg.payload {
+            MessagePayload::TunnelData(td) => {
+                assert_eq!(td.tid, TunnelId(2));
+                assert_eq!(&td.data()[..], &expected[..]);
+            }
+            _ => panic!("Unexpected message: {}", msg),
+        }
+    }
+
+    #[test]
+    fn reassembles_at_outbound_endpoint() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let (a, _a_netdb, _a_rx) = loopback_context_and_netdb(&peers);
+        let (b, _b_netdb, b_rx) = loopback_context_and_netdb(&peers);
+        let b_ri = b.ri.read().unwrap().clone();
+
+        // B is the OBEP of a tunnel from A
+        let layer_cipher = LayerCipher::new(&SessionKey([1; 32]), SessionKey([2; 32]));
+        let (new_participating_tx, new_participating_rx) = mpsc::channel(1);
+        let (_new_own_tx, new_own_rx) = mpsc::channel(1);
+        rt.block_on(new_participating_tx.send((
+            TunnelId(1),
+            HopConfig {
+                hop_data: HopData::OutboundEndpoint(a.keys.rid.hash()),
+                layer_cipher: layer_cipher.clone(),
+                expires: SystemTime::now() + Duration::from_secs(TUNNEL_LIFETIME),
+            },
+        )))
+        .unwrap();
+
+        // A sends a message for B to handle itself, that needs two fragments
+        let inner = Message::data(&a.msg_ids, vec![3; 1500]);
+        let mut fragmenter = Fragmenter::default();
+        fragmenter
+            .push(
+                TunnelMessageDeliveryType::Local,
+                1,
+                serialize(|input| gen_message(input, &inner)),
+            )
+            .unwrap();
+        let mut rng = TestRng::from_seed([1; 32]);
+        while let Some(mut data) = fragmenter.next_payload(&mut rng) {
+            layer_cipher.decrypt_layer(&mut data);
+            let sent = a
+                .comms
+                .read()
+                .unwrap()
+                .send(
+                    b_ri.clone(),
+                    Message::tunnel_data(&a.msg_ids, TunnelId(1), data),
+                )
+                .unwrap_or_else(|_| panic!("B is reachable"));
+            rt.block_on(sent).unwrap();
+        }
+
+        let (local_tx, local_rx) = mpsc::channel(1);
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(MessageType::Data, local_tx);
+        rt.spawn(Participant::new(
+            new_participating_rx,
+            new_own_rx,
+            b_rx,
+            Transit::default(),
+            b.netdb.clone(),
+            dispatcher,
+            b.comms.clone(),
+            b.msg_ids.clone(),
+        ));
+
+        // B dispatches the reassembled message locally
+        let (received, _) = rt.block_on(local_rx.into_future().map_err(|_| ())).unwrap();
+        let (from, msg) = received.unwrap();
+        assert_eq!(from, a.keys.rid.hash());
+        assert_eq!(
+            serialize(|input| gen_message(input, &msg)),
+            serialize(|input| gen_message(input, &inner))
+        );
+    }
+
+    #[test]
+    fn zero_hop_tunnels() {
+        let mut rt = Runtime::new().unwrap();
+        let peers = LoopbackPeers::default();
+        let (a, _a_netdb, _a_rx) = loopback_context_and_netdb(&peers);
+        let a_ri = a.ri.read().unwrap().clone();
+        let zero_hop = |role| OwnTunnel {
+            role,
+            hops: vec![],
+            first_hop: a_ri.clone(),
+            expires: SystemTime::now() + Duration::from_secs(TUNNEL_LIFETIME),
+        };
+
+        // A has a zero-hop tunnel in each direction
+        let (_new_participating_tx, new_participating_rx) = mpsc::channel(1);
+        let (new_own_tx, new_own_rx) = mpsc::channel(2);
+        let new_own_tx = rt
+            .block_on(new_own_tx.send((TunnelId(1), zero_hop(TunnelRole::Outbound))))
+            .unwrap();
+        rt.block_on(new_own_tx.send((TunnelId(2), zero_hop(TunnelRole::Inbound))))
+            .unwrap();
+
+        let (ib_tx, ib_rx) = mpsc::channel(16);
+        let mut tunnels = Dispatcher::new();
+        tunnels.register(MessageType::TunnelData, ib_tx.clone());
+        tunnels.register(MessageType::TunnelGateway, ib_tx);
+
+        let (netdb_tx, netdb_rx) = mpsc::channel(1);
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(MessageType::DatabaseStore, netdb_tx);
+        rt.spawn(Participant::new(
+            new_participating_rx,
+            new_own_rx,
+            ib_rx,
+            Transit::default(),
+            a.netdb.clone(),
+            dispatcher,
+            a.comms.clone(),
+            a.msg_ids.clone(),
+        ));
+
+        // A publishes its RouterInfo out through one tunnel and back in through the other,
+        // without the message touching the transports
+        let store = Message::database_store(&a.msg_ids, a_ri.clone(), None);
+        let mut gateway = OutboundGateway::new(
+            TunnelId(1),
+            &zero_hop(TunnelRole::Outbound),
+            a.msg_ids.clone(),
+        )
+        .unwrap();
+        gateway
+            .push_priority(
+                TunnelMessageDeliveryType::Tunnel(TunnelId(2), a.keys.rid.hash()),
+                &store,
+            )
+            .unwrap();
+        let sent = gateway.send(&*a.comms.read().unwrap(), &tunnels);
+        rt.block_on(sent).unwrap();
+        assert_eq!(tunnels.received(MessageType::TunnelData), 1);
+
+        // The local netdb handler receives it
+        let (received, _) = rt.block_on(netdb_rx.into_future().map_err(|_| ())).unwrap();
+        let (from, msg) = received.unwrap();
+        assert_eq!(from, a.keys.rid.hash());
+        assert_eq!(
+            serialize(|input| gen_message(input, &msg)),
+            serialize(|input| gen_message(input, &store))
+        );
+    }
+}
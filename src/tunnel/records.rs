@@ -0,0 +1,759 @@
+//! Encryption and decryption of tunnel build records.
+//!
+//! Each hop's request is encrypted to the encryption key in its RouterInfo:
+//!
+//! - ElGamal hops get a long record holding a [`BuildRequestRecord`] encrypted with
+//!   ElGamal.
+//! - ECIES-X25519 hops get either a long record holding an [`EciesBuildRequestRecord`]
+//!   or a short record holding a [`ShortBuildRequestRecord`], encrypted with the
+//!   Noise N pattern. The keys for a short record are derived from the Noise state.
+//!
+//! A hop processes a build message by replacing its record with its encrypted reply
+//! and encrypting every other record with its reply key: AES-256-CBC for long records,
+//! and ChaCha20 for short ones. The creator undoes this in advance for the records of
+//! later hops, and afterwards for the replies.
+//!
+//! See the [tunnel creation specification][spec] and the specifications for
+//! [ECIES hops][ecies] and [short records][short].
+//!
+//! [spec]: https://geti2p.net/spec/tunnel-creation
+//! [ecies]: https://geti2p.net/spec/tunnel-creation-ecies
+//! [short]: https://geti2p.net/spec/proposals/157-new-tbm
+
+use block_modes::{block_padding::NoPadding, BlockMode, Cbc};
+use rand::Rng;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::build::BuildError;
+use crate::crypto::{
+    chachapoly::{chacha20, noise_nonce, open_in_place, seal_in_place, TAG_LEN},
+    ecies,
+    elgamal::{self, Ephemeral},
+    hkdf,
+    noise::NoiseSymmetricState,
+    pool::Precomputed,
+    rand::CryptoRng,
+    DecryptionKey, EncryptionKey, PublicKey, SessionKey,
+};
+use crate::data::{Hash, Mapping, RouterInfo, TunnelId};
+use crate::i2np::{
+    frame::{
+        build_response_record, ecies_build_request_record, ecies_build_response_record,
+        gen_build_response_record, gen_ecies_build_request_record, gen_ecies_build_response_record,
+        gen_short_build_request_record, short_build_request_record, ECIES_BUILD_REQUEST_RECORD_LEN,
+        SHORT_BUILD_REQUEST_RECORD_LEN,
+    },
+    BuildRequestError, BuildRequestRecord, BuildResponseRecord, EciesBuildRequestRecord,
+    EciesBuildResponseRecord, ParticipantType, ShortBuildRequestRecord,
+};
+
+pub(super) const LONG_RECORD_LEN: usize = 528;
+pub(super) const SHORT_RECORD_LEN: usize = 218;
+
+/// The length of the truncated router hash at the start of each record.
+const TO_PEER_LEN: usize = 16;
+
+/// How long after its request time a build request we send expires.
+const REQUEST_EXPIRATION: u32 = 10 * 60;
+
+/// The first router version that supports short build records.
+const MIN_SHORT_RECORD_VERSION: &[u32] = &[0, 9, 51];
+
+const SHORT_REPLY_KEY_INFO: &[u8] = b"SMTunnelReplyKey";
+const SHORT_LAYER_KEY_INFO: &[u8] = b"SMTunnelLayerKey";
+const SHORT_IV_KEY_INFO: &[u8] = b"TunnelLayerIVKey";
+
+/// The kinds of build record.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum RecordFormat {
+    /// 528-byte records, which every hop supports.
+    Long,
+    /// 218-byte records, which only recent ECIES-X25519 hops support.
+    Short,
+}
+
+impl RecordFormat {
+    pub(super) fn from_len(len: usize) -> Option<Self> {
+        match len {
+            LONG_RECORD_LEN => Some(RecordFormat::Long),
+            SHORT_RECORD_LEN => Some(RecordFormat::Short),
+            _ => None,
+        }
+    }
+
+    pub(super) fn record_len(self) -> usize {
+        match self {
+            RecordFormat::Long => LONG_RECORD_LEN,
+            RecordFormat::Short => SHORT_RECORD_LEN,
+        }
+    }
+
+    /// Returns the format to use for a request to `hops`. Short records are only used
+    /// if every hop has an ECIES-X25519 key and is recent enough to read them.
+    pub(super) fn for_hops(hops: &[RouterInfo]) -> Self {
+        if hops.iter().all(|ri| {
+            matches!(ri.router_id.encryption_key(), EncryptionKey::X25519(_))
+                && ri
+                    .router_version()
+                    .map_or(false, |v| version_at_least(v, MIN_SHORT_RECORD_VERSION))
+        }) {
+            RecordFormat::Short
+        } else {
+            RecordFormat::Long
+        }
+    }
+}
+
+/// Compares a dotted router version against `min`. Unparseable versions are treated
+/// as too old.
+fn version_at_least(version: &str, min: &[u32]) -> bool {
+    version
+        .split('.')
+        .map(|part| part.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_or(false, |parts| parts.as_slice() >= min)
+}
+
+fn minutes_since_epoch(now: SystemTime) -> u32 {
+    (now.duration_since(UNIX_EPOCH)
+        .expect("System time is broken!")
+        .as_secs()
+        / 60) as u32
+}
+
+fn cbc_encrypt(record: &mut [u8], key: &SessionKey, iv: &[u8; 16]) {
+    let cipher: Cbc<aes::Aes256, NoPadding> =
+        Cbc::new_from_slices(&key.0, iv).expect("key and iv are correct length");
+    let len = record.len();
+    cipher.encrypt(record, len).expect("Should not fail!");
+}
+
+fn cbc_decrypt(record: &mut [u8], key: &SessionKey, iv: &[u8; 16]) {
+    let cipher: Cbc<aes::Aes256, NoPadding> =
+        Cbc::new_from_slices(&key.0, iv).expect("key and iv are correct length");
+    cipher.decrypt(record).expect("Should not fail!");
+}
+
+/// Derives 64 bytes of key material from the chaining key of a short record, and
+/// returns the two halves.
+fn short_kdf(ck: &[u8; 32], info: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0; 64];
+    hkdf::derive(ck, &[], info, &mut okm);
+    (*array_ref![okm, 0, 32], *array_ref![okm, 32, 32])
+}
+
+/// Derives the layer key, IV key and reply key for a short record from the Noise state
+/// after the record was encrypted.
+fn short_record_keys(
+    ck: &[u8; 32],
+    hop_type: ParticipantType,
+) -> (SessionKey, SessionKey, [u8; 32]) {
+    let (ck, reply_key) = short_kdf(ck, SHORT_REPLY_KEY_INFO);
+    let (ck, layer_key) = short_kdf(&ck, SHORT_LAYER_KEY_INFO);
+    let iv_key = if hop_type == ParticipantType::OutboundEndpoint {
+        // The OBEP derives further keys (for the garlic-wrapped reply) from the
+        // chaining key.
+        let (_, iv_key) = short_kdf(&ck, SHORT_IV_KEY_INFO);
+        iv_key
+    } else {
+        ck
+    };
+    (SessionKey(layer_key), SessionKey(iv_key), reply_key)
+}
+
+/// The keys a hop uses to encrypt its reply and the other records.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum ReplyKeys {
+    ElGamal {
+        key: SessionKey,
+        iv: [u8; 16],
+    },
+    /// The reply is encrypted with the chaining key from the request's Noise state.
+    EciesLong {
+        key: SessionKey,
+        iv: [u8; 16],
+        ck: [u8; 32],
+        h: [u8; 32],
+    },
+    Short {
+        key: [u8; 32],
+        h: [u8; 32],
+    },
+}
+
+impl ReplyKeys {
+    fn from_long_state(key: SessionKey, iv: [u8; 16], state: &NoiseSymmetricState) -> Self {
+        ReplyKeys::EciesLong {
+            key,
+            iv,
+            ck: *state.chaining_key(),
+            h: *state.handshake_hash(),
+        }
+    }
+
+    /// The key used to detect duplicate requests.
+    pub(super) fn filter_key(&self) -> &[u8] {
+        match self {
+            ReplyKeys::ElGamal { key, .. } | ReplyKeys::EciesLong { key, .. } => &key.0,
+            ReplyKeys::Short { key, .. } => key,
+        }
+    }
+
+    /// Encrypts the record at `index`, which is not this hop's own record.
+    pub(super) fn encrypt_other(&self, index: usize, record: &mut [u8]) {
+        match self {
+            ReplyKeys::ElGamal { key, iv } | ReplyKeys::EciesLong { key, iv, .. } => {
+                cbc_encrypt(record, key, iv)
+            }
+            ReplyKeys::Short { key, .. } => chacha20(key, &noise_nonce(index as u64), record),
+        }
+    }
+
+    /// Reverses [`ReplyKeys::encrypt_other`].
+    pub(super) fn decrypt_other(&self, index: usize, record: &mut [u8]) {
+        match self {
+            ReplyKeys::ElGamal { key, iv } | ReplyKeys::EciesLong { key, iv, .. } => {
+                cbc_decrypt(record, key, iv)
+            }
+            ReplyKeys::Short { key, .. } => chacha20(key, &noise_nonce(index as u64), record),
+        }
+    }
+
+    /// Replaces this hop's own record, at `index`, with its encrypted reply.
+    pub(super) fn seal_reply(&self, index: usize, record: &mut [u8], reply: u8) {
+        match self {
+            ReplyKeys::ElGamal { key, iv } => {
+                gen_build_response_record((&mut *record, 0), &BuildResponseRecord { reply })
+                    .expect("Should not fail!");
+                cbc_encrypt(record, key, iv);
+            }
+            ReplyKeys::EciesLong { ck, h, .. } => {
+                seal_ecies_reply(ck, &noise_nonce(0), h, record, reply)
+            }
+            ReplyKeys::Short { key, h } => {
+                seal_ecies_reply(key, &noise_nonce(index as u64), h, record, reply)
+            }
+        }
+    }
+
+    /// Reads the reply that this hop wrote into the record at `index`.
+    pub(super) fn open_reply(&self, index: usize, record: &mut [u8]) -> Result<u8, BuildError> {
+        match self {
+            ReplyKeys::ElGamal { key, iv } => {
+                cbc_decrypt(record, key, iv);
+                build_response_record(record)
+                    .map(|(_, brr)| brr.reply)
+                    .map_err(|_| BuildError::InvalidReply)
+            }
+            ReplyKeys::EciesLong { ck, h, .. } => open_ecies_reply(ck, &noise_nonce(0), h, record),
+            ReplyKeys::Short { key, h } => {
+                open_ecies_reply(key, &noise_nonce(index as u64), h, record)
+            }
+        }
+    }
+}
+
+fn seal_ecies_reply(key: &[u8; 32], nonce: &[u8; 12], h: &[u8; 32], record: &mut [u8], reply: u8) {
+    let (pt, tag) = record.split_at_mut(record.len() - TAG_LEN);
+    let len = pt.len();
+    gen_ecies_build_response_record(
+        (&mut *pt, 0),
+        &EciesBuildResponseRecord {
+            options: Mapping::default(),
+            reply,
+        },
+        len,
+    )
+    .expect("Should not fail!");
+    tag.copy_from_slice(&seal_in_place(key, nonce, h, pt));
+}
+
+fn open_ecies_reply(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    h: &[u8; 32],
+    record: &mut [u8],
+) -> Result<u8, BuildError> {
+    let (ct, tag) = record.split_at_mut(record.len() - TAG_LEN);
+    open_in_place(key, nonce, h, ct, array_ref![tag, 0, TAG_LEN])
+        .map_err(|_| BuildError::InvalidReply)?;
+    ecies_build_response_record(ct)
+        .map(|(_, brr)| brr.reply)
+        .map_err(|_| BuildError::InvalidReply)
+}
+
+/// The request for one hop of a tunnel we are building.
+#[derive(Clone, Debug)]
+pub(super) struct HopRequest {
+    pub ident: Hash,
+    pub key: EncryptionKey,
+    /// The index of the record that will hold this hop's request.
+    pub record: usize,
+    pub receive_tid: TunnelId,
+    pub next_tid: TunnelId,
+    pub next_ident: Hash,
+    pub hop_type: ParticipantType,
+    pub send_msg_id: u32,
+}
+
+/// The keys we gave one hop of a tunnel we are building.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct HopSecrets {
+    pub ident: Hash,
+    pub record: usize,
+    pub layer_key: SessionKey,
+    pub iv_key: SessionKey,
+    pub reply: ReplyKeys,
+}
+
+/// A build request record that was addressed to us.
+#[derive(Debug, PartialEq)]
+pub(super) struct DecryptedRecord {
+    pub receive_tid: TunnelId,
+    pub next_tid: TunnelId,
+    pub next_ident: Hash,
+    pub hop_type: ParticipantType,
+    pub send_msg_id: u32,
+    /// Time since the epoch, at the precision of the record.
+    pub request_time: Duration,
+    pub layer_key: SessionKey,
+    pub iv_key: SessionKey,
+    pub reply: ReplyKeys,
+}
+
+/// Writes the request for each of `hops` into its record, encrypted so that the hop
+/// can read it after every earlier hop has processed the build message, and returns
+/// the keys we gave each hop. Records not used by a hop are left as they are, so they
+/// should be filled with random data beforehand.
+///
+/// All of `records` must be long records or short records. Panics if a hop's record
+/// index is out of range.
+pub(super) fn encrypt_build_records<T: AsMut<[u8]>, R: CryptoRng>(
+    hops: &[HopRequest],
+    records: &mut [T],
+    ephemerals: &Precomputed<Ephemeral>,
+    rng: &mut R,
+) -> Result<Vec<HopSecrets>, BuildError> {
+    let now = SystemTime::now();
+    let mut secrets: Vec<HopSecrets> = Vec::with_capacity(hops.len());
+    for hop in hops {
+        let record = records[hop.record].as_mut();
+        let format = RecordFormat::from_len(record.len()).expect("Invalid build record length");
+        let hop_secrets = match (format, &hop.key) {
+            (RecordFormat::Long, EncryptionKey::ElGamal(key)) => {
+                encrypt_elgamal(hop, key, record, ephemerals.take(), rng)
+            }
+            (RecordFormat::Long, EncryptionKey::X25519(key)) => {
+                encrypt_ecies_long(hop, key, record, now, rng)
+            }
+            (RecordFormat::Short, EncryptionKey::X25519(key)) => {
+                encrypt_short(hop, key, record, now, rng)
+            }
+            (RecordFormat::Short, EncryptionKey::ElGamal(_)) => None,
+        }
+        .ok_or_else(|| BuildError::UnsupportedHop(hop.ident.clone()))?;
+
+        // Each earlier hop will encrypt this record with its reply key, so undo that
+        // in advance, starting with the hop just before this one.
+        for prev in secrets.iter().rev() {
+            prev.reply.decrypt_other(hop.record, record);
+        }
+        secrets.push(hop_secrets);
+    }
+    Ok(secrets)
+}
+
+fn encrypt_elgamal<R: CryptoRng>(
+    hop: &HopRequest,
+    key: &PublicKey,
+    record: &mut [u8],
+    ephemeral: Ephemeral,
+    rng: &mut R,
+) -> Option<HopSecrets> {
+    let mut brr = BuildRequestRecord::new(
+        hop.receive_tid,
+        hop.ident.clone(),
+        hop.next_tid,
+        hop.next_ident.clone(),
+        hop.hop_type,
+    );
+    brr.send_msg_id = hop.send_msg_id;
+    brr.layer_key = SessionKey::generate(rng);
+    brr.iv_key = SessionKey::generate(rng);
+    brr.reply_key = SessionKey::generate(rng);
+    rng.fill(&mut brr.reply_iv);
+    record.copy_from_slice(&brr.encrypt_with(&elgamal::Encryptor::from(key), ephemeral));
+
+    Some(HopSecrets {
+        ident: hop.ident.clone(),
+        record: hop.record,
+        layer_key: brr.layer_key,
+        iv_key: brr.iv_key,
+        reply: ReplyKeys::ElGamal {
+            key: brr.reply_key,
+            iv: brr.reply_iv,
+        },
+    })
+}
+
+fn encrypt_ecies_long<R: CryptoRng>(
+    hop: &HopRequest,
+    key: &[u8; 32],
+    record: &mut [u8],
+    now: SystemTime,
+    rng: &mut R,
+) -> Option<HopSecrets> {
+    let mut reply_iv = [0; 16];
+    rng.fill(&mut reply_iv);
+    let brr = EciesBuildRequestRecord {
+        receive_tid: hop.receive_tid,
+        next_tid: hop.next_tid,
+        next_ident: hop.next_ident.clone(),
+        layer_key: SessionKey::generate(rng),
+        iv_key: SessionKey::generate(rng),
+        reply_key: SessionKey::generate(rng),
+        reply_iv,
+        hop_type: hop.hop_type,
+        request_time: minutes_since_epoch(now),
+        request_expiration: REQUEST_EXPIRATION,
+        send_msg_id: hop.send_msg_id,
+        options: Mapping::default(),
+    };
+    let mut pt = [0; ECIES_BUILD_REQUEST_RECORD_LEN];
+    gen_ecies_build_request_record((&mut pt, 0), &brr).ok()?;
+    let (ct, state) = ecies::encrypt_with_state(key, &pt, rng).ok()?;
+
+    record[..TO_PEER_LEN].copy_from_slice(&hop.ident.0[..TO_PEER_LEN]);
+    record[TO_PEER_LEN..].copy_from_slice(&ct);
+
+    Some(HopSecrets {
+        ident: hop.ident.clone(),
+        record: hop.record,
+        layer_key: brr.layer_key,
+        iv_key: brr.iv_key,
+        reply: ReplyKeys::from_long_state(brr.reply_key, brr.reply_iv, &state),
+    })
+}
+
+fn encrypt_short<R: CryptoRng>(
+    hop: &HopRequest,
+    key: &[u8; 32],
+    record: &mut [u8],
+    now: SystemTime,
+    rng: &mut R,
+) -> Option<HopSecrets> {
+    let brr = ShortBuildRequestRecord {
+        receive_tid: hop.receive_tid,
+        next_tid: hop.next_tid,
+        next_ident: hop.next_ident.clone(),
+        hop_type: hop.hop_type,
+        layer_enc_type: 0,
+        request_time: minutes_since_epoch(now),
+        request_expiration: REQUEST_EXPIRATION,
+        send_msg_id: hop.send_msg_id,
+        options: Mapping::default(),
+    };
+    let mut pt = [0; SHORT_BUILD_REQUEST_RECORD_LEN];
+    gen_short_build_request_record((&mut pt, 0), &brr).ok()?;
+    let (ct, state) = ecies::encrypt_with_state(key, &pt, rng).ok()?;
+
+    record[..TO_PEER_LEN].copy_from_slice(&hop.ident.0[..TO_PEER_LEN]);
+    record[TO_PEER_LEN..].copy_from_slice(&ct);
+
+    let (layer_key, iv_key, reply_key) = short_record_keys(state.chaining_key(), hop.hop_type);
+    Some(HopSecrets {
+        ident: hop.ident.clone(),
+        record: hop.record,
+        layer_key,
+        iv_key,
+        reply: ReplyKeys::Short {
+            key: reply_key,
+            h: *state.handshake_hash(),
+        },
+    })
+}
+
+/// Reads each hop's reply from the records of a build reply.
+pub(super) fn decrypt_build_replies<T: AsMut<[u8]>>(
+    hops: &[HopSecrets],
+    records: &mut [T],
+) -> Result<Vec<(Hash, u8)>, BuildError> {
+    hops.iter()
+        .enumerate()
+        .map(|(i, hop)| {
+            let record = records
+                .get_mut(hop.record)
+                .ok_or(BuildError::InvalidReply)?
+                .as_mut();
+            // Every later hop encrypted this record after this hop wrote its reply.
+            for later in hops[i + 1..].iter().rev() {
+                later.reply.decrypt_other(hop.record, record);
+            }
+            hop.reply
+                .open_reply(hop.record, record)
+                .map(|reply| (hop.ident.clone(), reply))
+        })
+        .collect()
+}
+
+/// Decrypts a build request record that is addressed to us. The record format is
+/// determined by its length and our encryption key.
+pub(super) fn decrypt_my_record(
+    record: &[u8],
+    key: &DecryptionKey,
+) -> Result<DecryptedRecord, BuildRequestError> {
+    match (RecordFormat::from_len(record.len()), key) {
+        (Some(RecordFormat::Long), DecryptionKey::ElGamal(key)) => {
+            let brr = BuildRequestRecord::decrypt(record, &elgamal::Decryptor::from(key))?;
+            Ok(DecryptedRecord {
+                receive_tid: brr.receive_tid,
+                next_tid: brr.next_tid,
+                next_ident: brr.next_ident,
+                hop_type: brr.hop_type,
+                send_msg_id: brr.send_msg_id,
+                request_time: Duration::from_secs(u64::from(brr.request_time) * 3600),
+                layer_key: brr.layer_key,
+                iv_key: brr.iv_key,
+                reply: ReplyKeys::ElGamal {
+                    key: brr.reply_key,
+                    iv: brr.reply_iv,
+                },
+            })
+        }
+        (Some(RecordFormat::Long), DecryptionKey::X25519(_)) => {
+            let sk = array_ref![key.as_bytes(), 0, 32];
+            let (pt, state) = ecies::decrypt_with_state(sk, &record[TO_PEER_LEN..])?;
+            let (_, brr) = ecies_build_request_record(&pt)?;
+            Ok(DecryptedRecord {
+                receive_tid: brr.receive_tid,
+                next_tid: brr.next_tid,
+                next_ident: brr.next_ident,
+                hop_type: brr.hop_type,
+                send_msg_id: brr.send_msg_id,
+                request_time: Duration::from_secs(u64::from(brr.request_time) * 60),
+                layer_key: brr.layer_key,
+                iv_key: brr.iv_key,
+                reply: ReplyKeys::from_long_state(brr.reply_key, brr.reply_iv, &state),
+            })
+        }
+        (Some(RecordFormat::Short), DecryptionKey::X25519(_)) => {
+            let sk = array_ref![key.as_bytes(), 0, 32];
+            let (pt, state) = ecies::decrypt_with_state(sk, &record[TO_PEER_LEN..])?;
+            let (_, brr) = short_build_request_record(&pt)?;
+            if brr.layer_enc_type != 0 {
+                // We only support AES tunnel layer encryption
+                return Err(BuildRequestError::Invalid);
+            }
+            let (layer_key, iv_key, reply_key) =
+                short_record_keys(state.chaining_key(), brr.hop_type);
+            Ok(DecryptedRecord {
+                receive_tid: brr.receive_tid,
+                next_tid: brr.next_tid,
+                next_ident: brr.next_ident,
+                hop_type: brr.hop_type,
+                send_msg_id: brr.send_msg_id,
+                request_time: Duration::from_secs(u64::from(brr.request_time) * 60),
+                layer_key,
+                iv_key,
+                reply: ReplyKeys::Short {
+                    key: reply_key,
+                    h: *state.handshake_hash(),
+                },
+            })
+        }
+        _ => Err(BuildRequestError::Invalid),
+    }
+}
+
+/// Processes a build message as the hop whose record is at `index`: replaces the
+/// record with our encrypted reply, and encrypts every other record.
+pub(super) fn encrypt_build_reply<T: AsMut<[u8]>>(
+    keys: &ReplyKeys,
+    index: usize,
+    records: &mut [T],
+    reply: u8,
+) {
+    for (i, record) in records.iter_mut().enumerate() {
+        if i == index {
+            keys.seal_reply(i, record.as_mut(), reply);
+        } else {
+            keys.encrypt_other(i, record.as_mut());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+    use crate::crypto::{pool::Pools, rand::TestRng, EncType};
+    use crate::data::{RouterInfoBuilder, RouterSecretKeys};
+
+    /// Requests for a tunnel through hops with the given key types, using the records
+    /// at `positions`.
+    fn requests(
+        enc_types: &[EncType],
+        positions: &[usize],
+    ) -> (Vec<DecryptionKey>, Vec<HopRequest>) {
+        let keys: Vec<_> = enc_types
+            .iter()
+            .map(|&enc_type| DecryptionKey::with_type(enc_type))
+            .collect();
+        let last = keys.len() - 1;
+        let hops = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| HopRequest {
+                ident: Hash([i as u8 + 1; 32]),
+                key: key.public_key(),
+                record: positions[i],
+                receive_tid: TunnelId(i as u32 + 10),
+                next_tid: TunnelId(i as u32 + 11),
+                next_ident: Hash([i as u8 + 2; 32]),
+                hop_type: if i == last {
+                    ParticipantType::OutboundEndpoint
+                } else {
+                    ParticipantType::Intermediate
+                },
+                send_msg_id: i as u32,
+            })
+            .collect();
+        (keys, hops)
+    }
+
+    fn random_records(format: RecordFormat, count: usize) -> Vec<Vec<u8>> {
+        let mut rng = TestRng::from_seed([3; 32]);
+        (0..count)
+            .map(|_| {
+                let mut record = vec![0; format.record_len()];
+                rng.fill(&mut record[..]);
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        use crate::crypto::EncType::{ElGamal2048 as ElG, X25519};
+
+        let pools = Pools::new();
+        for (format, enc_types) in vec![
+            (RecordFormat::Long, vec![ElG]),
+            (RecordFormat::Long, vec![X25519]),
+            (RecordFormat::Long, vec![ElG, X25519, X25519, ElG]),
+            (RecordFormat::Long, vec![X25519, ElG, X25519]),
+            (RecordFormat::Short, vec![X25519, X25519, X25519]),
+        ] {
+            let mut rng = TestRng::from_seed([7; 32]);
+            let positions = [2, 0, 3, 1];
+            let (keys, hops) = requests(&enc_types, &positions);
+            let mut records = random_records(format, 4);
+            let secrets =
+                encrypt_build_records(&hops, &mut records, &pools.elgamal, &mut rng).unwrap();
+            assert_eq!(secrets.len(), hops.len());
+
+            for (i, ((key, hop), hop_secrets)) in
+                keys.iter().zip(hops.iter()).zip(secrets.iter()).enumerate()
+            {
+                assert_eq!(hop_secrets.ident, hop.ident);
+                assert_eq!(hop_secrets.record, hop.record);
+                assert_eq!(&records[hop.record][..16], &hop.ident.0[..16]);
+
+                // Later hops can't read their records until this hop has processed them
+                if let Some(next) = hops.get(i + 1) {
+                    assert!(decrypt_my_record(&records[next.record], &keys[i + 1]).is_err());
+                }
+
+                let brr = decrypt_my_record(&records[hop.record], key).unwrap();
+                assert_eq!(brr.receive_tid, hop.receive_tid);
+                assert_eq!(brr.next_tid, hop.next_tid);
+                assert_eq!(brr.next_ident, hop.next_ident);
+                assert_eq!(brr.hop_type, hop.hop_type);
+                assert_eq!(brr.send_msg_id, hop.send_msg_id);
+                assert_eq!(brr.layer_key, hop_secrets.layer_key);
+                assert_eq!(brr.iv_key, hop_secrets.iv_key);
+                assert_eq!(brr.reply, hop_secrets.reply);
+
+                encrypt_build_reply(&brr.reply, hop.record, &mut records, i as u8 * 10);
+            }
+
+            assert_eq!(
+                decrypt_build_replies(&secrets, &mut records),
+                Ok(hops
+                    .iter()
+                    .enumerate()
+                    .map(|(i, hop)| (hop.ident.clone(), i as u8 * 10))
+                    .collect())
+            );
+        }
+
+        // Each ElGamal record used an ephemeral from the pool
+        assert_eq!(pools.elgamal.stats().misses, 4);
+    }
+
+    #[test]
+    fn unsupported_and_invalid() {
+        let pools = Pools::new();
+        let mut rng = TestRng::from_seed([7; 32]);
+
+        // ElGamal hops can't read short records
+        let (_, hops) = requests(&[EncType::X25519, EncType::ElGamal2048], &[0, 1]);
+        let mut records = random_records(RecordFormat::Short, 4);
+        assert_eq!(
+            encrypt_build_records(&hops, &mut records, &pools.elgamal, &mut rng),
+            Err(BuildError::UnsupportedHop(hops[1].ident.clone()))
+        );
+
+        // Records that weren't processed by the hops can't be read
+        let (keys, hops) = requests(&[EncType::X25519, EncType::X25519], &[3, 1]);
+        let mut records = random_records(RecordFormat::Short, 4);
+        let secrets = encrypt_build_records(&hops, &mut records, &pools.elgamal, &mut rng).unwrap();
+        assert_eq!(
+            decrypt_build_replies(&secrets, &mut records.clone()),
+            Err(BuildError::InvalidReply)
+        );
+        assert_eq!(
+            decrypt_build_replies(&secrets, &mut records[..2]),
+            Err(BuildError::InvalidReply)
+        );
+
+        // Records can't be read with the wrong kind of key, or at the wrong length
+        assert_eq!(
+            decrypt_my_record(&records[3], &DecryptionKey::new()),
+            Err(BuildRequestError::Invalid)
+        );
+        assert!(decrypt_my_record(&records[3][..200], &keys[0]).is_err());
+    }
+
+    #[test]
+    fn record_format() {
+        let hop = |enc_type, version: &str| {
+            let rsk = RouterSecretKeys::with_enc_type(enc_type);
+            RouterInfoBuilder::new(rsk.rid.clone())
+                .option("router.version", version)
+                .sign(&rsk.signing_private_key)
+        };
+        let new = hop(EncType::X25519, "0.9.51");
+        let newer = hop(EncType::X25519, "0.9.58");
+        let old = hop(EncType::X25519, "0.9.50");
+        let unknown = hop(EncType::X25519, "unknown");
+        let elgamal = hop(EncType::ElGamal2048, "0.9.58");
+
+        assert_eq!(
+            RecordFormat::for_hops(&[new.clone(), newer.clone()]),
+            RecordFormat::Short
+        );
+        for other in &[old, unknown, elgamal] {
+            assert_eq!(
+                RecordFormat::for_hops(&[new.clone(), other.clone()]),
+                RecordFormat::Long
+            );
+        }
+
+        assert!(version_at_least("1.0", MIN_SHORT_RECORD_VERSION));
+        assert!(version_at_least("0.9.51.1", MIN_SHORT_RECORD_VERSION));
+        assert!(!version_at_least("0.9.5", MIN_SHORT_RECORD_VERSION));
+        assert!(!version_at_least("0.9.x", MIN_SHORT_RECORD_VERSION));
+    }
+}
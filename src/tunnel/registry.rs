@@ -0,0 +1,569 @@
+//! The tunnels registered at this router.
+//!
+//! Every tunnel that we participate in or built is registered here until it expires,
+//! along with the endpoint state of the tunnels whose messages we reassemble. Tunnels
+//! expire [`TUNNEL_LIFETIME`] seconds after they are built. The registry is expired
+//! periodically, and drops everything it holds for the tunnels that have expired since
+//! the last time. Messages that were still waiting for fragments when their tunnel
+//! expired can never be completed, and are counted as lost.
+//!
+//! Each registered tunnel has its traffic counted in the registry's [`Accounting`].
+//!
+//! The tunnel IDs under which we receive messages are handed out by [`TunnelIds`],
+//! which is shared with the [`Creator`] that picks IDs for our own tunnels and the
+//! [`Listener`] that is asked to take on IDs picked by other routers. An ID is never
+//! used by two tunnels at once, and isn't reused for [`TOMBSTONE_PERIOD`] seconds
+//! after its tunnel expires, so that late messages for the old tunnel are dropped
+//! instead of being misdelivered to a new one.
+//!
+//! [`TUNNEL_LIFETIME`]: super::TUNNEL_LIFETIME
+//! [`Creator`]: super::Creator
+//! [`Listener`]: super::Listener
+
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{
+    accounting::{created, Accounting, TunnelCounters, TunnelUse},
+    endpoint::{PendingMessages, Reassembler},
+    HopConfig, HopData, OwnTunnel,
+};
+use crate::data::TunnelId;
+
+/// How long a tunnel ID is held back from reuse after its tunnel expires, in seconds.
+const TOMBSTONE_PERIOD: u64 = 5 * 60;
+
+/// What a tunnel ID is currently used for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelIdState {
+    /// The ID is reserved for a tunnel that is being built, until the given time.
+    Reserved(SystemTime),
+    /// The ID belongs to a registered tunnel that expires at the given time.
+    Live(TunnelUse, SystemTime),
+    /// The ID belonged to a tunnel that expired, and can be reused after the given
+    /// time.
+    Tombstone(SystemTime),
+}
+
+/// Counters for the tunnel IDs that we have handed out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TunnelIdStats {
+    /// IDs allocated for our own tunnels.
+    pub allocated: u64,
+    /// Random IDs that were already taken, and had to be chosen again.
+    pub collisions: u64,
+    /// IDs picked by other routers that we refused because they were taken.
+    pub refused: u64,
+    /// Messages that arrived for a tunnel after it expired.
+    pub late_messages: u64,
+}
+
+/// The tunnel IDs in use at this router.
+///
+/// This is a handle that can be cloned and shared between subsystems. Lookups only
+/// take a read lock, so they don't hold each other up.
+#[derive(Clone, Default)]
+pub struct TunnelIds {
+    ids: Arc<RwLock<HashMap<TunnelId, TunnelIdState>>>,
+    stats: Arc<Mutex<TunnelIdStats>>,
+}
+
+impl TunnelIds {
+    /// Allocates a random, unused ID for one of our own tunnels. It is reserved until
+    /// the tunnel is registered, or for a tunnel lifetime if it never is.
+    pub fn allocate<R: Rng>(&self, rng: &mut R, now: SystemTime) -> TunnelId {
+        let expires = now + Duration::from_secs(super::TUNNEL_LIFETIME);
+        let mut ids = self.ids.write().unwrap();
+        let mut collisions = 0;
+        let tid = loop {
+            // Zero is not a valid tunnel ID
+            let tid = TunnelId(rng.gen_range(1..=u32::MAX));
+            if is_available(ids.get(&tid), now) {
+                break tid;
+            }
+            collisions += 1;
+        };
+        ids.insert(tid, TunnelIdState::Reserved(expires));
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.allocated += 1;
+        stats.collisions += collisions;
+        tid
+    }
+
+    /// Reserves an ID that another router picked for a tunnel we are asked to
+    /// participate in. Returns `false` if it is taken, or was used too recently.
+    pub fn reserve(&self, tid: TunnelId, now: SystemTime) -> bool {
+        let expires = now + Duration::from_secs(super::TUNNEL_LIFETIME);
+        let mut ids = self.ids.write().unwrap();
+        if tid.0 == 0 || !is_available(ids.get(&tid), now) {
+            self.stats.lock().unwrap().refused += 1;
+            return false;
+        }
+        ids.insert(tid, TunnelIdState::Reserved(expires));
+        true
+    }
+
+    /// Releases a reserved ID that was never used.
+    pub fn release(&self, tid: &TunnelId) {
+        self.ids.write().unwrap().remove(tid);
+    }
+
+    /// Returns what `tid` is currently used for, or `None` if it is free.
+    pub fn state(&self, tid: &TunnelId) -> Option<TunnelIdState> {
+        self.ids.read().unwrap().get(tid).copied()
+    }
+
+    /// Counts a message that arrived for an unregistered tunnel. Returns `true` if its
+    /// tunnel expired recently.
+    pub fn late_message(&self, tid: &TunnelId) -> bool {
+        let late = matches!(self.state(tid), Some(TunnelIdState::Tombstone(_)));
+        if late {
+            self.stats.lock().unwrap().late_messages += 1;
+        }
+        late
+    }
+
+    pub fn stats(&self) -> TunnelIdStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Marks `tid` as used by a registered tunnel that expires at `expires`.
+    fn register(&self, tid: TunnelId, usage: TunnelUse, expires: SystemTime) {
+        self.ids
+            .write()
+            .unwrap()
+            .insert(tid, TunnelIdState::Live(usage, expires));
+    }
+
+    /// Turns the IDs of tunnels that have expired by `now` into tombstones, and frees
+    /// the IDs whose tombstones have lapsed.
+    fn expire(&self, now: SystemTime) {
+        let tombstone = now + Duration::from_secs(TOMBSTONE_PERIOD);
+        self.ids.write().unwrap().retain(|_, state| match *state {
+            TunnelIdState::Reserved(expires) | TunnelIdState::Live(_, expires)
+                if expires <= now =>
+            {
+                *state = TunnelIdState::Tombstone(tombstone);
+                true
+            }
+            TunnelIdState::Reserved(_) | TunnelIdState::Live(_, _) => true,
+            TunnelIdState::Tombstone(until) => until > now,
+        });
+    }
+}
+
+fn is_available(state: Option<&TunnelIdState>, now: SystemTime) -> bool {
+    match state {
+        None => true,
+        Some(TunnelIdState::Reserved(_)) | Some(TunnelIdState::Live(_, _)) => false,
+        Some(TunnelIdState::Tombstone(until)) => *until <= now,
+    }
+}
+
+/// Counters for the tunnels that have expired.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExpiryStats {
+    /// Tunnels we participated in.
+    pub participating: u64,
+    /// Tunnels we built.
+    pub own: u64,
+    /// Tunnels that expired while their endpoint had incomplete messages.
+    pub with_pending: u64,
+    /// Incomplete messages that were dropped because their tunnel expired.
+    pub lost_messages: u64,
+}
+
+/// The tunnels that we are participating in, and the tunnels that we built.
+#[derive(Default)]
+pub(super) struct Registry {
+    participating: HashMap<TunnelId, (HopConfig, Arc<TunnelCounters>)>,
+    own: HashMap<TunnelId, (OwnTunnel, Arc<TunnelCounters>)>,
+    endpoints: HashMap<TunnelId, Reassembler>,
+    pending_messages: PendingMessages,
+    accounting: Accounting,
+    ids: TunnelIds,
+    stats: ExpiryStats,
+}
+
+impl Registry {
+    pub(super) fn new(accounting: Accounting, ids: TunnelIds) -> Self {
+        Registry {
+            participating: HashMap::new(),
+            own: HashMap::new(),
+            endpoints: HashMap::new(),
+            pending_messages: PendingMessages::default(),
+            accounting,
+            ids,
+            stats: ExpiryStats::default(),
+        }
+    }
+
+    pub(super) fn stats(&self) -> ExpiryStats {
+        self.stats
+    }
+
+    pub(super) fn register_participating(&mut self, tid: TunnelId, config: HopConfig) {
+        let usage = match config.hop_data {
+            HopData::InboundGateway(_) => TunnelUse::InboundGateway,
+            HopData::Intermediate(_, _) => TunnelUse::Intermediate,
+            HopData::OutboundEndpoint(_) => TunnelUse::OutboundEndpoint,
+        };
+        let counters = self
+            .accounting
+            .register(tid, usage, created(config.expires));
+        self.ids.register(tid, usage, config.expires);
+        self.participating.insert(tid, (config, counters));
+    }
+
+    pub(super) fn register_own(&mut self, tid: TunnelId, tunnel: OwnTunnel) {
+        let usage = TunnelUse::Own(tunnel.role);
+        let counters = self
+            .accounting
+            .register(tid, usage, created(tunnel.expires));
+        self.ids.register(tid, usage, tunnel.expires);
+        self.own.insert(tid, (tunnel, counters));
+    }
+
+    pub(super) fn participating(&self, tid: &TunnelId) -> Option<(&HopConfig, &TunnelCounters)> {
+        self.participating
+            .get(tid)
+            .map(|(config, counters)| (config, counters.as_ref()))
+    }
+
+    pub(super) fn own(&self, tid: &TunnelId) -> Option<(&OwnTunnel, &TunnelCounters)> {
+        self.own
+            .get(tid)
+            .map(|(tunnel, counters)| (tunnel, counters.as_ref()))
+    }
+
+    /// Returns the endpoint state and counters of the given tunnel, or `None` if it
+    /// isn't registered.
+    pub(super) fn endpoint(
+        &mut self,
+        tid: TunnelId,
+    ) -> Option<(&mut Reassembler, &TunnelCounters)> {
+        let counters = match self.participating.get(&tid) {
+            Some((_, counters)) => counters,
+            None => &self.own.get(&tid)?.1,
+        };
+        let pending_messages = &self.pending_messages;
+        let reassembler = self
+            .endpoints
+            .entry(tid)
+            .or_insert_with(|| Reassembler::new(pending_messages.clone()));
+        Some((reassembler, counters.as_ref()))
+    }
+
+    /// Returns `true` if a message for the unregistered tunnel `tid` arrived after the
+    /// tunnel expired, counting it as late.
+    pub(super) fn expired_recently(&self, tid: &TunnelId) -> bool {
+        self.ids.late_message(tid)
+    }
+
+    /// Drops the tunnels that have expired by `now`, and the messages that endpoints
+    /// have been waiting on for too long. Returns the number of participating tunnels
+    /// that expired.
+    pub(super) fn expire(&mut self, now: SystemTime, instant: Instant) -> usize {
+        let accounting = &self.accounting;
+
+        let participating = self.participating.len();
+        self.participating.retain(|tid, (config, _)| {
+            let keep = config.expires > now;
+            if !keep {
+                accounting.unregister(tid);
+            }
+            keep
+        });
+        let participating = participating - self.participating.len();
+
+        let own = self.own.len();
+        self.own.retain(|tid, (tunnel, _)| {
+            let keep = tunnel.expires > now;
+            if !keep {
+                accounting.unregister(tid);
+            }
+            keep
+        });
+        let own = own - self.own.len();
+
+        self.stats.participating += participating as u64;
+        self.stats.own += own as u64;
+        self.ids.expire(now);
+
+        let (registered, built, stats) = (&self.participating, &self.own, &mut self.stats);
+        self.endpoints.retain(|tid, reassembler| {
+            if registered.contains_key(tid) || built.contains_key(tid) {
+                return true;
+            }
+
+            let pending = reassembler.pending();
+            if pending > 0 {
+                stats.with_pending += 1;
+                stats.lost_messages += pending as u64;
+            }
+            debug!(
+                "Endpoint of expired tunnel {}: {:?}, {} incomplete messages lost",
+                tid,
+                reassembler.stats(),
+                pending
+            );
+            false
+        });
+
+        for (tid, reassembler) in self.endpoints.iter_mut() {
+            let expired = reassembler.expire(instant);
+            if expired > 0 {
+                debug!("Dropped {} incomplete messages in tunnel {}", expired, tid);
+            }
+        }
+
+        participating
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{ExpiryStats, Registry, TunnelIdState, TunnelIdStats, TunnelIds, TOMBSTONE_PERIOD};
+    use crate::crypto::{rand::TestRng, SessionKey};
+    use crate::data::{Hash, RouterInfo, RouterSecretKeys, TunnelId};
+    use crate::i2np::{frame::gen_message, Message, MessageIdGenerator};
+    use crate::tunnel::{
+        crypto::LayerCipher, gateway::Fragmenter, HopConfig, HopData, OwnTunnel,
+        TunnelMessageDeliveryType, TunnelRole, TunnelUse, TUNNEL_LIFETIME,
+    };
+    use crate::util::serialize;
+
+    fn obep(expires: SystemTime) -> HopConfig {
+        HopConfig {
+            hop_data: HopData::OutboundEndpoint(Hash([1; 32])),
+            layer_cipher: LayerCipher::new(&SessionKey([1; 32]), SessionKey([2; 32])),
+            expires,
+        }
+    }
+
+    /// Returns the payloads of a message that needs two fragments.
+    fn fragments() -> Vec<[u8; 1024]> {
+        let msg = Message::data(&MessageIdGenerator::default(), vec![3; 1500]);
+        let mut fragmenter = Fragmenter::default();
+        fragmenter
+            .push(
+                TunnelMessageDeliveryType::Local,
+                1,
+                serialize(|input| gen_message(input, &msg)),
+            )
+            .unwrap();
+        let mut rng = TestRng::from_seed([1; 32]);
+        let mut payloads = vec![];
+        while let Some(data) = fragmenter.next_payload(&mut rng) {
+            payloads.push(data);
+        }
+        assert_eq!(payloads.len(), 2);
+        payloads
+    }
+
+    #[test]
+    fn cleanup() {
+        let mut registry = Registry::default();
+        let t0 = SystemTime::now();
+        let expires = t0 + Duration::from_secs(600);
+
+        registry.register_participating(TunnelId(1), obep(expires));
+        registry.register_participating(TunnelId(2), obep(expires + Duration::from_secs(1)));
+        registry.register_own(
+            TunnelId(3),
+            OwnTunnel {
+                role: TunnelRole::Inbound,
+                hops: vec![],
+                first_hop: RouterInfo::new(RouterSecretKeys::new().rid),
+                expires,
+            },
+        );
+        assert!(registry.endpoint(TunnelId(1)).is_some());
+        assert!(registry.endpoint(TunnelId(4)).is_none());
+
+        // Nothing expires early
+        let instant = Instant::now();
+        assert_eq!(
+            registry.expire(expires - Duration::from_secs(1), instant),
+            0
+        );
+        assert!(registry.participating(&TunnelId(1)).is_some());
+        assert!(registry.own(&TunnelId(3)).is_some());
+
+        // Registrations are dropped once they expire
+        assert_eq!(registry.expire(expires, instant), 1);
+        assert!(registry.participating(&TunnelId(1)).is_none());
+        assert!(registry.participating(&TunnelId(2)).is_some());
+        assert!(registry.own(&TunnelId(3)).is_none());
+        assert!(registry.endpoint(TunnelId(1)).is_none());
+        assert!(registry.endpoints.is_empty());
+        let traffic = registry.accounting.stats(instant);
+        assert_eq!((traffic.participating, traffic.own), (1, 0));
+        assert_eq!(
+            registry.stats(),
+            ExpiryStats {
+                participating: 1,
+                own: 1,
+                with_pending: 0,
+                lost_messages: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn in_flight_at_expiry() {
+        let mut registry = Registry::default();
+        let t0 = SystemTime::now();
+        let expires = t0 + Duration::from_secs(600);
+        let instant = Instant::now();
+        let payloads = fragments();
+
+        // A message that completes before its tunnel expires is delivered
+        registry.register_participating(TunnelId(1), obep(expires));
+        let (reassembler, _) = registry.endpoint(TunnelId(1)).unwrap();
+        assert!(reassembler
+            .receive(&payloads[0], instant)
+            .unwrap()
+            .is_empty());
+        assert_eq!(reassembler.receive(&payloads[1], instant).unwrap().len(), 1);
+
+        // A message whose last fragment is still in flight is lost
+        registry.register_participating(TunnelId(2), obep(expires));
+        let (reassembler, _) = registry.endpoint(TunnelId(2)).unwrap();
+        assert!(reassembler
+            .receive(&payloads[0], instant)
+            .unwrap()
+            .is_empty());
+        assert_eq!(registry.expire(expires, instant), 2);
+        assert_eq!(
+            registry.stats(),
+            ExpiryStats {
+                participating: 2,
+                own: 0,
+                with_pending: 1,
+                lost_messages: 1,
+            }
+        );
+
+        // The late fragment finds no registration
+        assert!(registry.endpoint(TunnelId(2)).is_none());
+    }
+
+    #[test]
+    fn allocation() {
+        let ids = TunnelIds::default();
+        let now = SystemTime::now();
+
+        // Random IDs that are already taken are chosen again
+        let first = ids.allocate(&mut TestRng::from_seed([1; 32]), now);
+        let second = ids.allocate(&mut TestRng::from_seed([1; 32]), now);
+        assert_ne!(first, second);
+        assert_eq!(
+            ids.stats(),
+            TunnelIdStats {
+                allocated: 2,
+                collisions: 1,
+                ..TunnelIdStats::default()
+            }
+        );
+
+        // IDs picked by other routers must be free, and can't be zero
+        assert!(!ids.reserve(first, now));
+        assert!(!ids.reserve(TunnelId(0), now));
+        assert!(ids.reserve(TunnelId(7), now));
+        assert!(!ids.reserve(TunnelId(7), now));
+        assert_eq!(ids.stats().refused, 3);
+
+        // Unused reservations can be released
+        ids.release(&TunnelId(7));
+        assert_eq!(ids.state(&TunnelId(7)), None);
+        assert!(ids.reserve(TunnelId(7), now));
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut registry = Registry::default();
+        let ids = registry.ids.clone();
+        let t0 = SystemTime::now();
+        let expires = t0 + Duration::from_secs(TUNNEL_LIFETIME);
+        let instant = Instant::now();
+
+        assert!(ids.reserve(TunnelId(1), t0));
+        assert_eq!(
+            ids.state(&TunnelId(1)),
+            Some(TunnelIdState::Reserved(expires))
+        );
+        registry.register_participating(TunnelId(1), obep(expires));
+        assert_eq!(
+            ids.state(&TunnelId(1)),
+            Some(TunnelIdState::Live(TunnelUse::OutboundEndpoint, expires))
+        );
+        let unused = ids.allocate(&mut TestRng::from_seed([2; 32]), t0);
+
+        // Expired IDs, including reservations that were never registered, can't be
+        // reused straight away
+        registry.expire(expires, instant);
+        let tombstone = expires + Duration::from_secs(TOMBSTONE_PERIOD);
+        assert_eq!(
+            ids.state(&TunnelId(1)),
+            Some(TunnelIdState::Tombstone(tombstone))
+        );
+        assert_eq!(
+            ids.state(&unused),
+            Some(TunnelIdState::Tombstone(tombstone))
+        );
+        assert!(!ids.reserve(TunnelId(1), expires));
+
+        // Late messages are counted, and dropped
+        assert!(registry.endpoint(TunnelId(1)).is_none());
+        assert!(registry.expired_recently(&TunnelId(1)));
+        assert!(!registry.expired_recently(&TunnelId(2)));
+        assert_eq!(ids.stats().late_messages, 1);
+
+        // Once the tombstone lapses, the ID is free again
+        registry.expire(tombstone, instant);
+        assert_eq!(ids.state(&TunnelId(1)), None);
+        assert!(!registry.expired_recently(&TunnelId(1)));
+        assert!(ids.reserve(TunnelId(1), tombstone));
+    }
+
+    #[test]
+    fn concurrent_allocation() {
+        let ids = TunnelIds::default();
+        let now = SystemTime::now();
+
+        // Every thread draws the same IDs, so they contend for each one
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let ids = ids.clone();
+                thread::spawn(move || {
+                    let mut rng = TestRng::from_seed([3; 32]);
+                    (0..1000)
+                        .map(|_| {
+                            let tid = ids.allocate(&mut rng, now);
+                            assert!(matches!(ids.state(&tid), Some(TunnelIdState::Reserved(_))));
+                            tid
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut allocated = HashSet::new();
+        for thread in threads {
+            for tid in thread.join().unwrap() {
+                assert!(allocated.insert(tid));
+            }
+        }
+        let stats = ids.stats();
+        assert_eq!(stats.allocated, 8000);
+        assert!(stats.collisions >= 1000);
+    }
+}
@@ -0,0 +1,411 @@
+//! Choosing the hops of the tunnels we build.
+//!
+//! Hops are chosen at random from the netDb using the peer selection rules in
+//! [`crate::netdb::select_peers`]: we never use ourselves, a router more than once, two
+//! routers in the same /16 (IPv4) or /48 (IPv6) subnet, or peers whose profiles show
+//! that they are failing, banned, or have rejected several of our builds in a row. The
+//! order of the selected peers is also random, and is used as the order of the hops
+//! from the gateway to the endpoint.
+
+use rand::Rng;
+use std::time::SystemTime;
+
+use super::build::{BuildError, MAX_HOPS};
+use crate::data::{Bandwidth, Hash, RouterInfo};
+use crate::netdb::{select_peers, FloodfillPolicy, PeerCriteria};
+use crate::router::profiles::Profiles;
+
+/// Chooses the hops for a tunnel, according to the settings of the pool it is for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HopSelector {
+    length: usize,
+    length_variance: i8,
+    min_length: usize,
+    exclude_floodfills: bool,
+    high_capacity: bool,
+    min_bandwidth: Option<Bandwidth>,
+    exclude: Vec<Hash>,
+}
+
+impl HopSelector {
+    /// Selects exactly `length` hops, which may include floodfills.
+    pub fn new(length: usize) -> Self {
+        HopSelector {
+            length,
+            length_variance: 0,
+            min_length: length,
+            exclude_floodfills: false,
+            high_capacity: false,
+            min_bandwidth: None,
+            exclude: vec![],
+        }
+    }
+
+    /// Randomizes the length of each tunnel. A positive variance adds between 0 and
+    /// `variance` hops; a negative variance adds between `variance` and `-variance`
+    /// hops. Tunnels always have between 1 and 7 hops.
+    pub fn length_variance(mut self, variance: i8) -> Self {
+        self.length_variance = variance;
+        self
+    }
+
+    /// Allows shorter tunnels to be built when the netDb doesn't have enough suitable
+    /// peers, as long as they have at least `min_length` hops.
+    pub fn min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length.max(1);
+        self
+    }
+
+    /// Controls whether floodfills are excluded. Client tunnels should exclude them,
+    /// so that their traffic can't be linked to the floodfills' netDb activity.
+    pub fn exclude_floodfills(mut self, exclude: bool) -> Self {
+        self.exclude_floodfills = exclude;
+        self
+    }
+
+    /// Prefers the peers with the highest capacity scores, for pools that need fast
+    /// tunnels.
+    pub fn high_capacity(mut self, prefer: bool) -> Self {
+        self.high_capacity = prefer;
+        self
+    }
+
+    /// Only selects peers advertising at least this bandwidth class.
+    pub fn min_bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.min_bandwidth = Some(bandwidth);
+        self
+    }
+
+    /// Never selects any of these peers.
+    pub fn exclude(mut self, peers: Vec<Hash>) -> Self {
+        self.exclude.extend(peers);
+        self
+    }
+
+    /// Returns the number of hops to select for the next tunnel.
+    pub(super) fn hop_count<R: Rng + ?Sized>(&self, rng: &mut R) -> usize {
+        let variance = isize::from(self.length_variance);
+        let delta = if variance > 0 {
+            rng.gen_range(0..=variance)
+        } else if variance < 0 {
+            rng.gen_range(variance..=-variance)
+        } else {
+            0
+        };
+        (self.length as isize + delta).max(1).min(MAX_HOPS as isize) as usize
+    }
+
+    /// Returns the criteria for selecting `count` hops from the netDb.
+    pub(super) fn criteria(&self, count: usize) -> PeerCriteria {
+        let criteria = PeerCriteria::new(count)
+            .floodfills(if self.exclude_floodfills {
+                FloodfillPolicy::Exclude
+            } else {
+                FloodfillPolicy::Allow
+            })
+            .prefer_high_capacity(self.high_capacity)
+            .exclude(self.exclude.clone());
+        match self.min_bandwidth {
+            Some(bandwidth) => criteria.min_bandwidth(bandwidth),
+            None => criteria,
+        }
+    }
+
+    /// Checks that enough of the `count` hops we asked for were selected.
+    pub(super) fn finish(
+        &self,
+        count: usize,
+        peers: Vec<RouterInfo>,
+    ) -> Result<Vec<RouterInfo>, BuildError> {
+        if !peers.is_empty() && peers.len() >= count.min(self.min_length) {
+            Ok(peers)
+        } else {
+            Err(BuildError::NotEnoughPeers(peers.len()))
+        }
+    }
+
+    /// Selects the hops for a tunnel from `candidates`, ordered from the gateway to
+    /// the endpoint.
+    pub fn select<'a, I, R>(
+        &self,
+        candidates: I,
+        us: &Hash,
+        profiles: &Profiles,
+        now: SystemTime,
+        rng: &mut R,
+    ) -> Result<Vec<RouterInfo>, BuildError>
+    where
+        I: IntoIterator<Item = &'a RouterInfo>,
+        R: Rng + ?Sized,
+    {
+        let count = self.hop_count(rng);
+        let peers = select_peers(
+            candidates,
+            &self.criteria(count),
+            us,
+            profiles,
+            |_| false,
+            now,
+            rng,
+        );
+        self.finish(count, peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::HashSet;
+    use std::net::IpAddr;
+    use std::time::{Duration, SystemTime};
+
+    use super::HopSelector;
+    use crate::data::{Hash, RouterCaps, RouterInfo};
+    use crate::router::{
+        mock::mock_reachable_router_info,
+        profiles::{Profiles, Transport},
+    };
+    use crate::tunnel::BuildError;
+
+    struct NetDb {
+        ris: Vec<RouterInfo>,
+        us: Hash,
+        profiles: Profiles,
+        floodfills: HashSet<Hash>,
+        avoided: HashSet<Hash>,
+    }
+
+    /// Creates a netDb with `subnets` routers in distinct subnets, the first two of
+    /// which are floodfills. It also contains a second router in the first subnet,
+    /// a duplicate entry, ourselves, and a failing and a banned router in their own
+    /// subnets.
+    fn netdb(subnets: usize) -> NetDb {
+        let mut ris: Vec<_> = (0..subnets)
+            .map(|i| {
+                mock_reachable_router_info(
+                    RouterCaps::default().floodfill(i < 2),
+                    &format!("10.{}.0.1", i),
+                )
+            })
+            .collect();
+        let floodfills = ris
+            .iter()
+            .filter(|ri| ri.is_floodfill())
+            .map(|ri| ri.router_id.hash())
+            .collect();
+        ris.push(mock_reachable_router_info(
+            RouterCaps::default(),
+            "10.0.200.1",
+        ));
+        ris.push(ris[subnets - 1].clone());
+
+        let now = SystemTime::now();
+        let profiles = Profiles::default();
+        let failing = mock_reachable_router_info(RouterCaps::default(), "192.168.0.1");
+        for _ in 0..3 {
+            profiles.connect_failed(&failing.router_id.hash(), Transport::Ntcp2, now);
+        }
+        let banned = mock_reachable_router_info(RouterCaps::default(), "192.169.0.1");
+        profiles.ban(&banned.router_id.hash(), Duration::from_secs(60), now);
+        let avoided = vec![failing.router_id.hash(), banned.router_id.hash()]
+            .into_iter()
+            .collect();
+        ris.push(failing);
+        ris.push(banned);
+
+        let us = mock_reachable_router_info(RouterCaps::default(), "172.16.0.1");
+        let us_hash = us.router_id.hash();
+        ris.push(us);
+
+        NetDb {
+            ris,
+            us: us_hash,
+            profiles,
+            floodfills,
+            avoided,
+        }
+    }
+
+    fn select(
+        netdb: &NetDb,
+        selector: &HopSelector,
+        seed: u64,
+    ) -> Result<Vec<RouterInfo>, BuildError> {
+        selector.select(
+            &netdb.ris,
+            &netdb.us,
+            &netdb.profiles,
+            SystemTime::now(),
+            &mut StdRng::seed_from_u64(seed),
+        )
+    }
+
+    /// Asserts the constraints that every selection must satisfy.
+    fn check_constraints(netdb: &NetDb, selector: &HopSelector, hops: &[RouterInfo]) {
+        let hashes: Vec<_> = hops.iter().map(|ri| ri.router_id.hash()).collect();
+        let unique: HashSet<_> = hashes.iter().cloned().collect();
+        assert_eq!(unique.len(), hashes.len(), "duplicate hops");
+        assert!(!unique.contains(&netdb.us), "selected ourselves");
+        assert!(
+            unique.is_disjoint(&netdb.avoided),
+            "selected an avoided peer"
+        );
+        assert!(
+            selector.exclude.iter().all(|hash| !unique.contains(hash)),
+            "selected an excluded peer"
+        );
+        if selector.exclude_floodfills {
+            assert!(
+                unique.is_disjoint(&netdb.floodfills),
+                "selected a floodfill"
+            );
+        }
+
+        let mut subnets = HashSet::new();
+        for host in hops.iter().flat_map(|ri| ri.hosts()) {
+            match host {
+                IpAddr::V4(ip) => {
+                    let o = ip.octets();
+                    assert!(subnets.insert((o[0], o[1])), "two hops in one /16");
+                }
+                IpAddr::V6(_) => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn constraints() {
+        let netdb = netdb(10);
+        let excluded = vec![netdb.ris[4].router_id.hash(), netdb.ris[5].router_id.hash()];
+
+        // (selector, allowed lengths)
+        let cases = vec![
+            (HopSelector::new(1), 1..=1),
+            (HopSelector::new(3), 3..=3),
+            (HopSelector::new(3).exclude_floodfills(true), 3..=3),
+            (HopSelector::new(3).high_capacity(true), 3..=3),
+            (HopSelector::new(3).exclude(excluded), 3..=3),
+            (HopSelector::new(2).length_variance(2), 2..=4),
+            (HopSelector::new(2).length_variance(-1), 1..=3),
+            (HopSelector::new(6).length_variance(3), 6..=7),
+            (HopSelector::new(1).length_variance(-3), 1..=4),
+            // Every usable router
+            (HopSelector::new(7).exclude_floodfills(true), 7..=7),
+        ];
+
+        for (selector, lengths) in cases {
+            let mut seen = HashSet::new();
+            for seed in 0..50 {
+                let hops = select(&netdb, &selector, seed).unwrap();
+                assert!(
+                    lengths.contains(&hops.len()),
+                    "{:?} selected {} hops",
+                    selector,
+                    hops.len()
+                );
+                check_constraints(&netdb, &selector, &hops);
+                seen.insert(hops.len());
+            }
+            // Every allowed length is used
+            assert_eq!(seen, lengths.collect::<HashSet<_>>(), "{:?}", selector);
+        }
+    }
+
+    #[test]
+    fn deterministic() {
+        let netdb = netdb(10);
+        let selector = HopSelector::new(3).length_variance(-2);
+        let order = |hops: Vec<RouterInfo>| -> Vec<Hash> {
+            hops.iter().map(|ri| ri.router_id.hash()).collect()
+        };
+
+        for seed in 0..10 {
+            assert_eq!(
+                order(select(&netdb, &selector, seed).unwrap()),
+                order(select(&netdb, &selector, seed).unwrap())
+            );
+        }
+
+        // Different seeds give different tunnels
+        let first = order(select(&netdb, &HopSelector::new(3), 0).unwrap());
+        assert!(
+            (1..10).any(|seed| order(select(&netdb, &HopSelector::new(3), seed).unwrap()) != first)
+        );
+    }
+
+    #[test]
+    fn high_capacity() {
+        let netdb = netdb(10);
+        let now = SystemTime::now();
+        // Half of the usable routers
+        let fast: HashSet<_> = netdb.ris[2..8]
+            .iter()
+            .map(|ri| ri.router_id.hash())
+            .collect();
+        for hash in &fast {
+            for _ in 0..10 {
+                netdb.profiles.tunnel_build_agreed(hash, now);
+            }
+        }
+
+        let selector = HopSelector::new(3).high_capacity(true);
+        for seed in 0..20 {
+            let hops = select(&netdb, &selector, seed).unwrap();
+            check_constraints(&netdb, &selector, &hops);
+            assert!(hops.iter().all(|ri| fast.contains(&ri.router_id.hash())));
+        }
+    }
+
+    #[test]
+    fn degradation() {
+        // Only 3 usable routers, or 2 without floodfills
+        let netdb = netdb(3);
+
+        // (selector, expected result)
+        let cases = vec![
+            (HopSelector::new(3), Ok(3)),
+            (HopSelector::new(4), Err(BuildError::NotEnoughPeers(3))),
+            (HopSelector::new(5).min_length(2), Ok(3)),
+            (
+                HopSelector::new(5).min_length(4),
+                Err(BuildError::NotEnoughPeers(3)),
+            ),
+            (
+                HopSelector::new(3).exclude_floodfills(true),
+                Err(BuildError::NotEnoughPeers(2)),
+            ),
+            (
+                HopSelector::new(3).exclude_floodfills(true).min_length(1),
+                Ok(2),
+            ),
+            // A random length that is shorter than the minimum is allowed
+            (HopSelector::new(1).length_variance(-1).min_length(3), Ok(1)),
+        ];
+
+        for (selector, expected) in cases {
+            for seed in 0..10 {
+                let res = select(&netdb, &selector, seed);
+                if let Ok(hops) = &res {
+                    check_constraints(&netdb, &selector, hops);
+                }
+                match expected.clone() {
+                    Ok(len) if selector.length_variance != 0 => {
+                        assert!(res.unwrap().len() <= 1 + len, "{:?}", selector)
+                    }
+                    Ok(len) => assert_eq!(res.map(|hops| hops.len()), Ok(len), "{:?}", selector),
+                    Err(e) => assert_eq!(res.map(|hops| hops.len()), Err(e), "{:?}", selector),
+                }
+            }
+        }
+
+        // An empty netDb never gives a tunnel
+        let empty = NetDb {
+            ris: vec![],
+            ..netdb
+        };
+        assert_eq!(
+            select(&empty, &HopSelector::new(3).min_length(1), 0),
+            Err(BuildError::NotEnoughPeers(0))
+        );
+    }
+}
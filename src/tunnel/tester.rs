@@ -0,0 +1,180 @@
+//! Testing of the tunnels in a pool.
+//!
+//! Each outbound tunnel in a pool is tested periodically by sending a DeliveryStatus
+//! message out through it, to be delivered back to us through one of the pool's
+//! inbound tunnels. If the message doesn't arrive within [`PROBE_TIMEOUT`], both
+//! tunnels are blamed, because we can't tell which of them lost it. The results are
+//! also recorded in the profiles of every hop in the two tunnels.
+
+use rand::RngCore;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use super::BuiltTunnel;
+use crate::crypto::rand::OsRng;
+use crate::data::Hash;
+use crate::router::{
+    config::{self, Config},
+    profiles::Profiles,
+};
+
+/// The default interval between tests of each tunnel, in seconds.
+const TEST_INTERVAL: u64 = 30;
+
+/// The default number of tests in a row that a tunnel must fail before we stop using
+/// it.
+const FAILURE_THRESHOLD: u32 = 2;
+
+/// How long we wait for a test message to come back, in seconds.
+pub(super) const PROBE_TIMEOUT: u64 = 10;
+
+/// The settings for testing the tunnels in a pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TestConfig {
+    pub interval: Duration,
+    /// The number of tests in a row that a tunnel must fail before we stop using it.
+    pub failure_threshold: u32,
+}
+
+impl Default for TestConfig {
+    fn default() -> Self {
+        TestConfig {
+            interval: Duration::from_secs(TEST_INTERVAL),
+            failure_threshold: FAILURE_THRESHOLD,
+        }
+    }
+}
+
+impl TestConfig {
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = TestConfig::default();
+        TestConfig {
+            interval: config
+                .get_int(config::TUNNEL_TEST_INTERVAL)
+                .map(|v| Duration::from_secs(v as u64))
+                .unwrap_or(defaults.interval),
+            failure_threshold: config
+                .get_int(config::TUNNEL_TEST_FAILURES)
+                .map(|v| v as u32)
+                .unwrap_or(defaults.failure_threshold),
+        }
+    }
+}
+
+/// Something that sends test messages through the tunnels of a pool.
+pub trait ProbeSender {
+    /// Sends a DeliveryStatus message with the given message ID out through
+    /// `outbound`, to be delivered back to us through `inbound`.
+    fn send_probe(&self, outbound: &BuiltTunnel, inbound: &BuiltTunnel, msg_id: u32);
+}
+
+/// A test that is waiting for its message to come back.
+pub(super) struct Probe {
+    pub(super) outbound: BuiltTunnel,
+    pub(super) inbound: BuiltTunnel,
+    sent: SystemTime,
+}
+
+impl Probe {
+    fn hops(&self) -> impl Iterator<Item = &Hash> {
+        self.outbound.hops.iter().chain(self.inbound.hops.iter())
+    }
+}
+
+/// Sends the tests for a pool, and matches up the replies.
+pub(super) struct Tester {
+    sender: Box<dyn ProbeSender + Send>,
+    config: TestConfig,
+    profiles: Profiles,
+    pending: HashMap<u32, Probe>,
+    next_test: Option<SystemTime>,
+}
+
+impl Tester {
+    pub(super) fn new(
+        sender: Box<dyn ProbeSender + Send>,
+        config: TestConfig,
+        profiles: Profiles,
+    ) -> Self {
+        Tester {
+            sender,
+            config,
+            profiles,
+            pending: HashMap::new(),
+            next_test: None,
+        }
+    }
+
+    pub(super) fn config(&self) -> &TestConfig {
+        &self.config
+    }
+
+    /// Returns true if it is time to test the pool's tunnels again.
+    pub(super) fn due(&mut self, now: SystemTime) -> bool {
+        if self.next_test.map_or(true, |next_test| next_test <= now) {
+            self.next_test = Some(now + self.config.interval);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Tests `outbound` and `inbound` together.
+    pub(super) fn send(&mut self, outbound: &BuiltTunnel, inbound: &BuiltTunnel, now: SystemTime) {
+        let msg_id = loop {
+            let msg_id = OsRng.next_u32();
+            if !self.pending.contains_key(&msg_id) {
+                break msg_id;
+            }
+        };
+        debug!(
+            "Testing tunnels {} and {} with message {}",
+            outbound.tid, inbound.tid, msg_id
+        );
+        self.sender.send_probe(outbound, inbound, msg_id);
+        self.pending.insert(
+            msg_id,
+            Probe {
+                outbound: outbound.clone(),
+                inbound: inbound.clone(),
+                sent: now,
+            },
+        );
+    }
+
+    /// Returns the test that the message with the given ID was sent for, along with its
+    /// round-trip time, or `None` if it wasn't one of our tests.
+    pub(super) fn received(&mut self, msg_id: u32, now: SystemTime) -> Option<(Probe, Duration)> {
+        let probe = self.pending.remove(&msg_id)?;
+        let rtt = now.duration_since(probe.sent).unwrap_or_default();
+        for hop in probe.hops() {
+            self.profiles.tunnel_test_succeeded(hop, now);
+        }
+        Some((probe, rtt))
+    }
+
+    /// Returns the tests that have timed out.
+    pub(super) fn expire(&mut self, now: SystemTime) -> Vec<Probe> {
+        let timeout = Duration::from_secs(PROBE_TIMEOUT);
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, probe)| probe.sent + timeout <= now)
+            .map(|(msg_id, _)| *msg_id)
+            .collect();
+
+        let mut failed = Vec::with_capacity(expired.len());
+        for msg_id in expired {
+            let probe = self.pending.remove(&msg_id).unwrap();
+            debug!(
+                "Test of tunnels {} and {} timed out",
+                probe.outbound.tid, probe.inbound.tid
+            );
+            for hop in probe.hops() {
+                self.profiles.tunnel_test_failed(hop, now);
+            }
+            failed.push(probe);
+        }
+        failed
+    }
+}
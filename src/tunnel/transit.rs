@@ -0,0 +1,476 @@
+//! Limits on the tunnels we participate in for other routers.
+//!
+//! Participating tunnels are how the network gets its capacity, but each one costs us
+//! bandwidth. Our share for them is configured directly, or as a percentage of the
+//! router's overall bandwidth limit. Transit traffic is forwarded from a token bucket
+//! that refills at our share, and can burst up to [`BURST`] seconds of it.
+//!
+//! As the bucket empties, we stop forwarding for the tunnels that ask for more than an
+//! even split of our share between the busy tunnels, so that the heaviest tunnels bear
+//! the drops. Traffic is only dropped regardless of its tunnel once the bucket is
+//! empty. Before that happens, we start rejecting new tunnels at random as the recent
+//! rate of our participating tunnels approaches our share, and reject all of them once
+//! it is close.
+
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::{
+    accounting::{Accounting, TunnelCounters},
+    registry::TunnelIds,
+};
+use crate::router::config::{self, Config};
+
+/// The default maximum number of tunnels we participate in at once.
+const MAX_PARTICIPATING_TUNNELS: usize = 2500;
+
+/// The default bandwidth we share with participating tunnels, in bytes per second.
+const SHARE_BANDWIDTH: u64 = 256 * 1024;
+
+/// The default percentage of the router's bandwidth limit that we share with
+/// participating tunnels.
+const SHARE_PERCENTAGE: u64 = 80;
+
+/// Transit traffic can burst up to this many seconds of our share.
+const BURST: u64 = 1;
+
+/// Once forwarding a message would leave less than this percentage of the burst, we
+/// only forward it for tunnels that are within their fair share.
+const CONGESTION_THRESHOLD: u64 = 50;
+
+/// New tunnels are rejected at random once the recent rate of our participating
+/// tunnels reaches this percentage of our share, and more often as it rises.
+const REJECT_THRESHOLD: u64 = 70;
+
+/// New tunnels are always rejected once the recent rate of our participating tunnels
+/// reaches this percentage of our share.
+const ACCEPT_THRESHOLD: u64 = 90;
+
+/// The configured limits on our participating tunnels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransitLimits {
+    /// The most tunnels we will participate in at once.
+    pub max_tunnels: usize,
+    /// The bandwidth we share with participating tunnels, in bytes per second.
+    pub bandwidth: u64,
+}
+
+impl Default for TransitLimits {
+    fn default() -> Self {
+        TransitLimits {
+            max_tunnels: MAX_PARTICIPATING_TUNNELS,
+            bandwidth: SHARE_BANDWIDTH,
+        }
+    }
+}
+
+impl TransitLimits {
+    /// Reads the limits from `config`. If the router has a bandwidth limit, our share
+    /// is at most the configured percentage of it.
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = TransitLimits::default();
+        let percentage = config
+            .get_int(config::TUNNEL_PARTICIPATING_SHARE)
+            .map(|v| v as u64)
+            .unwrap_or(SHARE_PERCENTAGE);
+        let configured = config
+            .get_int(config::TUNNEL_PARTICIPATING_BANDWIDTH)
+            .ok()
+            .map(|v| v as u64);
+        let share = config
+            .get_int(config::ROUTER_BANDWIDTH)
+            .ok()
+            .map(|v| v as u64 * percentage / 100);
+        TransitLimits {
+            max_tunnels: config
+                .get_int(config::TUNNEL_PARTICIPATING_MAX)
+                .map(|v| v as usize)
+                .unwrap_or(defaults.max_tunnels),
+            bandwidth: match (configured, share) {
+                (Some(configured), Some(share)) => configured.min(share),
+                (configured, share) => configured.or(share).unwrap_or(defaults.bandwidth),
+            },
+        }
+    }
+
+    fn burst(&self) -> u64 {
+        self.bandwidth * BURST
+    }
+}
+
+/// Counters for the transit traffic and tunnels that we turned away.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransitStats {
+    /// Messages dropped while we were congested, from tunnels that asked for more than
+    /// their fair share.
+    pub fair_drops: u64,
+    /// Messages dropped because our share was used up.
+    pub hard_drops: u64,
+    /// New tunnels rejected at random as we approached our share.
+    pub probabilistic_rejects: u64,
+}
+
+/// Bytes that we can forward, refilled continuously at a fixed rate.
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: u64,
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, capacity: u64) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: None,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let last = match self.last_refill {
+            Some(last) => last,
+            None => {
+                self.last_refill = Some(now);
+                return;
+            }
+        };
+        let elapsed = now.saturating_duration_since(last).as_micros();
+        let added = elapsed * u128::from(self.rate) / 1_000_000;
+        // Leave the refill time alone until a whole token has accrued, so that
+        // frequent small messages don't lose the fractions.
+        if added > 0 {
+            self.tokens = (u128::from(self.tokens) + added).min(u128::from(self.capacity)) as u64;
+            self.last_refill = Some(now);
+        }
+    }
+
+    /// Returns true if taking `bytes` would leave less than the congestion threshold.
+    fn is_congested_after(&self, bytes: u64) -> bool {
+        (self.tokens - bytes) * 100 < self.capacity * CONGESTION_THRESHOLD
+    }
+}
+
+struct TransitState {
+    limits: TransitLimits,
+    tunnels: usize,
+    bucket: TokenBucket,
+    stats: TransitStats,
+}
+
+/// Our participating tunnels and transit traffic, shared between the
+/// [`Listener`](super::Listener) that accepts new tunnels and the
+/// [`Participant`](super::Participant) that forwards their messages.
+///
+/// Also holds the [`Accounting`] for the traffic of each tunnel, and the [`TunnelIds`]
+/// that are in use.
+#[derive(Clone)]
+pub struct Transit {
+    state: Arc<Mutex<TransitState>>,
+    accounting: Accounting,
+    ids: TunnelIds,
+}
+
+impl Default for Transit {
+    fn default() -> Self {
+        Transit::new(TransitLimits::default())
+    }
+}
+
+impl Transit {
+    pub fn new(limits: TransitLimits) -> Self {
+        Transit {
+            state: Arc::new(Mutex::new(TransitState {
+                limits,
+                tunnels: 0,
+                bucket: TokenBucket::new(limits.bandwidth, limits.burst()),
+                stats: TransitStats::default(),
+            })),
+            accounting: Accounting::default(),
+            ids: TunnelIds::default(),
+        }
+    }
+
+    /// Returns the number of tunnels we are participating in, including ones we have
+    /// accepted that haven't been registered yet.
+    pub fn tunnels(&self) -> usize {
+        self.state.lock().unwrap().tunnels
+    }
+
+    /// Returns the traffic counters of the tunnels registered at this router.
+    pub fn accounting(&self) -> &Accounting {
+        &self.accounting
+    }
+
+    /// Returns the tunnel IDs in use at this router.
+    pub fn tunnel_ids(&self) -> &TunnelIds {
+        &self.ids
+    }
+
+    pub fn stats(&self) -> TransitStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Decides whether to accept a new participating tunnel. If it is accepted, it is
+    /// counted until it is [`expired`](Transit::expired).
+    pub(super) fn admit<R: Rng>(&self, rng: &mut R, now: Instant) -> bool {
+        let rate = self.accounting.participating_rate(now);
+        let mut state = self.state.lock().unwrap();
+        if state.tunnels >= state.limits.max_tunnels {
+            return false;
+        }
+        let bandwidth = state.limits.bandwidth;
+        if rate * 100 >= bandwidth * ACCEPT_THRESHOLD {
+            return false;
+        }
+        if rate * 100 > bandwidth * REJECT_THRESHOLD {
+            // The chance of rejecting rises from nothing to certainty between the
+            // thresholds.
+            let over = rate * 100 - bandwidth * REJECT_THRESHOLD;
+            let range = bandwidth * (ACCEPT_THRESHOLD - REJECT_THRESHOLD);
+            if rng.gen_bool(over as f64 / range as f64) {
+                state.stats.probabilistic_rejects += 1;
+                return false;
+            }
+        }
+        state.tunnels += 1;
+        true
+    }
+
+    /// Stops counting `count` tunnels that have expired.
+    pub(super) fn expired(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.tunnels = state.tunnels.saturating_sub(count);
+    }
+
+    /// Records `bytes` of transit traffic that we want to forward in the tunnel with
+    /// `counters`. Returns `false` if it should be dropped instead, in which case the
+    /// drop is counted against the tunnel.
+    pub(super) fn record(&self, counters: &TunnelCounters, bytes: usize, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.bucket.refill(now);
+        let bytes = bytes as u64;
+
+        let forward = if bytes > state.bucket.tokens {
+            state.stats.hard_drops += 1;
+            false
+        } else if state.bucket.is_congested_after(bytes) {
+            let busy = self.accounting.active_participating(now).max(1) as u64;
+            let fair = counters.demand(now) <= state.limits.bandwidth / busy;
+            if !fair {
+                state.stats.fair_drops += 1;
+            }
+            fair
+        } else {
+            true
+        };
+
+        if forward {
+            state.bucket.tokens -= bytes;
+        } else {
+            counters.dropped();
+        }
+        forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, SystemTime};
+
+    use super::{Transit, TransitLimits, TransitStats};
+    use crate::crypto::rand::TestRng;
+    use crate::data::TunnelId;
+    use crate::router::config::{self, Config};
+    use crate::tunnel::{accounting::TunnelUse, TunnelRole};
+
+    #[test]
+    fn limits_from_config() {
+        let mut cfg = Config::default();
+        assert_eq!(TransitLimits::from_config(&cfg), TransitLimits::default());
+
+        // Our share defaults to a percentage of the router's limit
+        cfg.set(config::ROUTER_BANDWIDTH, 100_000).unwrap();
+        assert_eq!(TransitLimits::from_config(&cfg).bandwidth, 80_000);
+        cfg.set(config::TUNNEL_PARTICIPATING_SHARE, 50).unwrap();
+        assert_eq!(TransitLimits::from_config(&cfg).bandwidth, 50_000);
+
+        // A configured share can only lower that
+        cfg.set(config::TUNNEL_PARTICIPATING_BANDWIDTH, 20_000)
+            .unwrap();
+        assert_eq!(TransitLimits::from_config(&cfg).bandwidth, 20_000);
+        cfg.set(config::TUNNEL_PARTICIPATING_BANDWIDTH, 90_000)
+            .unwrap();
+        assert_eq!(TransitLimits::from_config(&cfg).bandwidth, 50_000);
+    }
+
+    #[test]
+    fn tunnel_limit() {
+        let transit = Transit::new(TransitLimits {
+            max_tunnels: 2,
+            bandwidth: 10_000,
+        });
+        let mut rng = TestRng::from_seed([1; 32]);
+        let now = Instant::now();
+
+        assert!(transit.admit(&mut rng, now));
+        assert!(transit.admit(&mut rng, now));
+        assert!(!transit.admit(&mut rng, now));
+        assert_eq!(transit.tunnels(), 2);
+
+        // Expired tunnels make room for new ones
+        transit.expired(1);
+        assert!(transit.admit(&mut rng, now));
+        assert!(!transit.admit(&mut rng, now));
+    }
+
+    #[test]
+    fn bandwidth_share() {
+        let transit = Transit::new(TransitLimits {
+            max_tunnels: 10,
+            bandwidth: 4096,
+        });
+        let t0 = Instant::now();
+        let second = Duration::from_secs(1);
+        let counters =
+            transit
+                .accounting()
+                .register(TunnelId(1), TunnelUse::Intermediate, SystemTime::now());
+
+        // Traffic over the share is dropped
+        for _ in 0..4 {
+            assert!(transit.record(&counters, 1024, t0));
+        }
+        assert!(!transit.record(&counters, 1024, t0));
+        assert_eq!(transit.stats().hard_drops, 1);
+
+        // The share refills over time
+        assert!(!transit.record(&counters, 1024, t0 + second / 8));
+        assert!(transit.record(&counters, 1024, t0 + second / 4));
+        assert!(transit.record(&counters, 1024, t0 + 5 * second));
+
+        let details = &transit.accounting().stats(t0).tunnels[0];
+        assert_eq!(details.messages_dropped, 2);
+    }
+
+    #[test]
+    fn fair_dropping() {
+        let transit = Transit::new(TransitLimits {
+            max_tunnels: 10,
+            bandwidth: 10_000,
+        });
+        let t0 = Instant::now();
+        let created = SystemTime::now();
+        let accounting = transit.accounting();
+        let tunnels: Vec<_> = (1..=3)
+            .map(|i| {
+                let created = created + Duration::from_secs(i.into());
+                accounting.register(TunnelId(i), TunnelUse::Intermediate, created)
+            })
+            .collect();
+        let mut sent = [0u64; 3];
+
+        // One tunnel asks for 80 kB/s, and two others for 2 kB/s each
+        for tick in 0..200 {
+            let now = t0 + Duration::from_millis(100 * tick);
+            for i in 0..8 {
+                let mut offered = vec![0];
+                if i == 0 && tick % 5 == 0 {
+                    offered.extend(&[1, 2]);
+                }
+                for t in offered {
+                    tunnels[t].received(1024, now);
+                    if transit.record(&tunnels[t], 1024, now) {
+                        sent[t] += 1024;
+                    }
+                }
+            }
+        }
+
+        // The heaviest tunnel bears every drop
+        let stats = accounting.stats(t0 + Duration::from_secs(20));
+        let dropped: Vec<_> = stats.tunnels.iter().map(|d| d.messages_dropped).collect();
+        assert!(dropped[0] > 1000);
+        assert_eq!(&dropped[1..], &[0, 0]);
+        assert_eq!(&sent[1..], &[40 * 1024, 40 * 1024]);
+        assert_eq!(transit.stats().fair_drops, dropped[0]);
+
+        // And still gets what the others leave of our share
+        assert!(sent[0] > 100_000);
+        assert!(sent.iter().sum::<u64>() <= 20 * 10_000 + 10_000);
+    }
+
+    #[test]
+    fn busy_tunnels() {
+        let transit = Transit::new(TransitLimits {
+            max_tunnels: 10,
+            bandwidth: 1000,
+        });
+        let mut rng = TestRng::from_seed([1; 32]);
+        let t0 = Instant::now();
+        let created = SystemTime::now();
+        let later = Duration::from_secs(60);
+
+        // Traffic in our own tunnels doesn't count against the share
+        let accounting = transit.accounting();
+        let own = accounting.register(TunnelId(1), TunnelUse::Own(TunnelRole::Inbound), created);
+        for _ in 0..100 {
+            own.received(1024, t0);
+        }
+        assert!(transit.admit(&mut rng, t0 + later));
+
+        // Participating tunnels using 60% of the share leave room for more
+        let ibgw = accounting.register(TunnelId(2), TunnelUse::InboundGateway, created);
+        let hop = accounting.register(TunnelId(3), TunnelUse::Intermediate, created);
+        let t1 = t0 + later;
+        ibgw.received(3000, t1);
+        hop.received(3000, t1);
+        assert!(transit.admit(&mut rng, t1 + later / 6));
+
+        // Going over the threshold stops us accepting tunnels
+        let t2 = t1 + later / 6;
+        ibgw.received(6000, t2);
+        hop.received(3000, t2);
+        assert!(!transit.admit(&mut rng, t2 + later / 6));
+
+        // Until the busy tunnels expire
+        accounting.unregister(&TunnelId(2));
+        assert!(transit.admit(&mut rng, t2 + later / 6));
+        assert_eq!(transit.tunnels(), 3);
+    }
+
+    #[test]
+    fn probabilistic_rejects() {
+        let transit = Transit::new(TransitLimits {
+            max_tunnels: 1000,
+            bandwidth: 1000,
+        });
+        let mut rng = TestRng::from_seed([1; 32]);
+        let t0 = Instant::now();
+        let later = Duration::from_secs(10);
+        let hop =
+            transit
+                .accounting()
+                .register(TunnelId(1), TunnelUse::Intermediate, SystemTime::now());
+
+        // Below the first threshold, every tunnel is accepted
+        hop.received(6500, t0);
+        assert!((0..100).all(|_| transit.admit(&mut rng, t0 + later)));
+
+        // Between the thresholds, some are rejected
+        hop.received(8000, t0 + later);
+        let accepted = (0..100)
+            .filter(|_| transit.admit(&mut rng, t0 + 2 * later))
+            .count();
+        assert!(accepted > 20 && accepted < 80);
+        assert_eq!(
+            transit.stats(),
+            TransitStats {
+                probabilistic_rejects: 100 - accepted as u64,
+                ..TransitStats::default()
+            }
+        );
+    }
+}
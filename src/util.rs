@@ -3,8 +3,11 @@
 use bloom_filter_rs::{BloomFilter, Murmur3};
 use cookie_factory::GenError;
 use core::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::iter::repeat;
 use std::mem;
+use std::path::Path;
 
 pub fn serialize<S>(serializer: S) -> Vec<u8>
 where
@@ -29,6 +32,67 @@ where
     buf
 }
 
+/// Atomically replaces the file at `path` with `data`, making it readable only by
+/// the current user.
+///
+/// The data is written to a temporary file alongside `path` which is then renamed
+/// over it, so a crash mid-write never leaves a truncated file behind.
+pub(crate) fn write_private_file(path: &str, data: &[u8]) -> io::Result<()> {
+    write_file_atomically(Path::new(path), data, true)
+}
+
+/// Atomically replaces the file at `path` with `data`, in the same way as
+/// [`write_private_file`] but with the default permissions.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    write_file_atomically(path, data, false)
+}
+
+fn write_file_atomically(path: &Path, data: &[u8], private: bool) -> io::Result<()> {
+    let tmp = match path.file_name() {
+        Some(name) => {
+            let mut name = name.to_os_string();
+            name.push(".tmp");
+            path.with_file_name(name)
+        }
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path does not name a file",
+            ))
+        }
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if private {
+            options.mode(0o600);
+        }
+    }
+
+    let res = options
+        .open(&tmp)
+        .and_then(|mut file| {
+            // The mode is only applied to new files, so fix up any stale temporary
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                if private {
+                    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+                }
+            }
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res
+}
+
 /// Format a byte array as a colon-delimited hex string.
 ///
 /// Source: https://github.com/tendermint/signatory